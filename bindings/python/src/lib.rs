@@ -0,0 +1,54 @@
+use anyhow::{bail, Result};
+use llm::vocab::{Encoding, SentenceType};
+
+// Python扩展模块`llm_rs`，需要启用`python` feature
+#[cfg(feature = "python")]
+mod python;
+
+// 与tiktoken的编码名相同，如`gpt2`、`cl100k_base`
+pub fn encoding(name: &str) -> Result<Encoding> {
+    Ok(match name {
+        "gpt2" => Encoding::Gpt2,
+        "r50k_base" => Encoding::R50kBase,
+        "p50k_base" => Encoding::P50kBase,
+        "cl100k_base" => Encoding::Cl100kBase,
+        "o200k_base" => Encoding::O200kBase,
+        _ => bail!("Unknown encoding `{name}`"),
+    })
+}
+
+// 只有中文和中英混合的词表需要从文本构建
+pub fn trainable_sentence_type(name: &str) -> Result<SentenceType> {
+    Ok(match name {
+        "chinese" => SentenceType::Chinese,
+        "mixed" => SentenceType::Mixed,
+        _ => bail!("Cannot build a `{name}` vocabulary from text, use `chinese` or `mixed`"),
+    })
+}
+
+// 与PyTorch的`len(DataLoader)`相同
+pub fn num_batches(len: usize, batch_size: usize, drop_last: bool) -> usize {
+    if drop_last {
+        len / batch_size
+    } else {
+        len.div_ceil(batch_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_helpers() -> Result<()> {
+        assert_eq!(encoding("gpt2")?, Encoding::Gpt2);
+        assert!(encoding("gpt-2").is_err());
+        assert_eq!(trainable_sentence_type("mixed")?, SentenceType::Mixed);
+        assert!(trainable_sentence_type("english").is_err());
+        assert_eq!(
+            (num_batches(10, 4, true), num_batches(10, 4, false)),
+            (2, 3)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+use crate::{encoding, num_batches, trainable_sentence_type};
+use data_loader::{DataLoader, DataLoaderIter, Dataset, GPTDataset, TrainData};
+use llm::vocab::{SentenceType, Vocabulary};
+use pyo3::exceptions::{PyIndexError, PyValueError};
+use pyo3::prelude::*;
+use std::fmt::Display;
+use std::sync::Arc;
+
+type Batch = Vec<TrainData<usize>>;
+type PyBatch = (Vec<Vec<usize>>, Vec<Vec<usize>>);
+
+fn value_error(err: impl Display) -> PyErr {
+    PyValueError::new_err(format!("{err:#}"))
+}
+
+#[pyclass(name = "Vocabulary", module = "llm_rs", frozen)]
+struct PyVocabulary {
+    inner: Vocabulary,
+}
+
+#[pymethods]
+impl PyVocabulary {
+    // 英文词表，`Vocabulary("gpt2")`与书中的`tiktoken.get_encoding("gpt2")`相同
+    #[new]
+    #[pyo3(signature = (encoding_name = "gpt2"))]
+    fn new(encoding_name: &str) -> PyResult<Self> {
+        let vocab = Vocabulary::new("", SentenceType::English)
+            .map_err(value_error)?
+            .with_encoding(encoding(encoding_name).map_err(value_error)?);
+        Ok(PyVocabulary { inner: vocab })
+    }
+
+    // 从文本构建中文或中英混合词表
+    #[staticmethod]
+    #[pyo3(signature = (text, sentence_type = "chinese", min_frequency = None, max_vocab_size = None))]
+    fn train(
+        py: Python<'_>,
+        text: &str,
+        sentence_type: &str,
+        min_frequency: Option<usize>,
+        max_vocab_size: Option<usize>,
+    ) -> PyResult<Self> {
+        let sentence_type = trainable_sentence_type(sentence_type).map_err(value_error)?;
+        let mut vocab = py
+            .allow_threads(|| Vocabulary::new(text, sentence_type))
+            .map_err(value_error)?;
+        if let Some(min_frequency) = min_frequency {
+            vocab = vocab
+                .with_min_frequency(min_frequency)
+                .map_err(value_error)?;
+        }
+        if let Some(max_vocab_size) = max_vocab_size {
+            vocab = vocab
+                .with_max_vocab_size(max_vocab_size)
+                .map_err(value_error)?;
+        }
+        Ok(PyVocabulary { inner: vocab })
+    }
+
+    #[staticmethod]
+    fn load(path: &str) -> PyResult<Self> {
+        let vocab = Vocabulary::load(path).map_err(value_error)?;
+        Ok(PyVocabulary { inner: vocab })
+    }
+
+    fn save(&self, path: &str) -> PyResult<()> {
+        self.inner.save(path).map_err(value_error)
+    }
+
+    fn encode(&self, py: Python<'_>, text: &str) -> PyResult<Vec<usize>> {
+        py.allow_threads(|| self.inner.encode(text))
+            .map_err(value_error)
+    }
+
+    // 多线程编码
+    fn encode_batch(&self, py: Python<'_>, texts: Vec<String>) -> PyResult<Vec<Vec<usize>>> {
+        let texts = texts.iter().map(String::as_str).collect::<Vec<_>>();
+        py.allow_threads(|| self.inner.encode_batch(&texts))
+            .map_err(value_error)
+    }
+
+    fn decode(&self, token_ids: Vec<usize>) -> PyResult<String> {
+        self.inner.decode(&token_ids).map_err(value_error)
+    }
+
+    #[getter]
+    fn vocab_size(&self) -> usize {
+        self.inner.vocab_size()
+    }
+
+    #[getter]
+    fn eos_id(&self) -> Option<usize> {
+        self.inner.eos_id()
+    }
+
+    #[getter]
+    fn pad_id(&self) -> Option<usize> {
+        self.inner.pad_id()
+    }
+
+    fn __len__(&self) -> usize {
+        self.inner.vocab_size()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Vocabulary({:?}, vocab_size={})",
+            self.inner.sentence_type(),
+            self.inner.vocab_size()
+        )
+    }
+}
+
+// 书中的`GPTDatasetV1`
+#[pyclass(name = "GPTDataset", module = "llm_rs", frozen)]
+struct PyGPTDataset {
+    inner: GPTDataset,
+}
+
+#[pymethods]
+impl PyGPTDataset {
+    #[new]
+    fn new(token_ids: Vec<usize>, max_length: usize, stride: usize) -> PyResult<Self> {
+        if max_length == 0 || stride == 0 {
+            return Err(PyValueError::new_err(
+                "max_length and stride must be positive",
+            ));
+        }
+        Ok(PyGPTDataset {
+            inner: GPTDataset::new(token_ids, max_length, stride),
+        })
+    }
+
+    fn __len__(&self) -> usize {
+        self.inner.len()
+    }
+
+    // 返回`(input_ids, target_ids)`，支持负数下标
+    fn __getitem__(&self, index: isize) -> PyResult<(Vec<usize>, Vec<usize>)> {
+        let len = self.inner.len() as isize;
+        let index = if index < 0 { index + len } else { index };
+        if !(0..len).contains(&index) {
+            return Err(PyIndexError::new_err("GPTDataset index out of range"));
+        }
+        let TrainData { feature, label } = self.inner.get(index as usize);
+        Ok((feature, label))
+    }
+}
+
+// 与`torch.utils.data.DataLoader`相同，每次`for`循环是一个新的epoch
+#[pyclass(name = "DataLoader", module = "llm_rs", frozen)]
+struct PyDataLoader {
+    inner: Arc<DataLoader<Batch>>,
+    num_batches: usize,
+}
+
+#[pymethods]
+impl PyDataLoader {
+    // `num_workers = 0`与PyTorch一样表示不额外开线程，这里使用一个工作线程
+    #[new]
+    #[pyo3(signature = (dataset, batch_size = 1, shuffle = false, drop_last = false, num_workers = 0, seed = None))]
+    fn new(
+        dataset: &Bound<'_, PyGPTDataset>,
+        batch_size: usize,
+        shuffle: bool,
+        drop_last: bool,
+        num_workers: usize,
+        seed: Option<u64>,
+    ) -> PyResult<Self> {
+        if batch_size == 0 {
+            return Err(PyValueError::new_err("batch_size must be positive"));
+        }
+        let dataset = dataset.get().inner.clone();
+        let mut builder = DataLoader::builder()
+            .batch_size(batch_size)
+            .shuffle(shuffle)
+            .drop_last(drop_last)
+            .num_workers(num_workers.max(1))
+            .persistent_workers(true);
+        if let Some(seed) = seed {
+            builder = builder.seed(seed);
+        }
+        Ok(PyDataLoader {
+            num_batches: num_batches(dataset.len(), batch_size, drop_last),
+            inner: Arc::new(builder.build(dataset)),
+        })
+    }
+
+    fn __len__(&self) -> usize {
+        self.num_batches
+    }
+
+    fn __iter__(&self) -> PyDataLoaderIter {
+        // 上一个epoch已结束时开始新的epoch
+        PyDataLoaderIter {
+            iter: self.inner.iter_shared(),
+            done: false,
+        }
+    }
+}
+
+#[pyclass(module = "llm_rs")]
+struct PyDataLoaderIter {
+    iter: DataLoaderIter<'static, Batch>,
+    done: bool,
+}
+
+#[pymethods]
+impl PyDataLoaderIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    // 返回`(inputs, targets)`，都是`batch_size`行的嵌套列表，可以直接传给`torch.tensor`
+    fn __next__(mut slf: PyRefMut<'_, Self>, py: Python<'_>) -> PyResult<Option<PyBatch>> {
+        if slf.done {
+            return Ok(None);
+        }
+        let iter = &mut slf.iter;
+        // 等待工作线程时释放GIL
+        let batch = py.allow_threads(|| iter.next());
+        match batch {
+            Some(Ok(batch)) => Ok(Some(
+                batch
+                    .into_iter()
+                    .map(|data| (data.feature, data.label))
+                    .unzip(),
+            )),
+            Some(Err(err)) => {
+                slf.done = true;
+                Err(value_error(err))
+            }
+            None => {
+                slf.done = true;
+                Ok(None)
+            }
+        }
+    }
+}
+
+// 书中第2章的`create_dataloader_v1`，使用GPT-2的tiktoken编码
+#[pyfunction]
+#[pyo3(signature = (txt, batch_size = 4, max_length = 256, stride = 128, shuffle = true, drop_last = true, num_workers = 0))]
+fn create_dataloader_v1(
+    py: Python<'_>,
+    txt: &str,
+    batch_size: usize,
+    max_length: usize,
+    stride: usize,
+    shuffle: bool,
+    drop_last: bool,
+    num_workers: usize,
+) -> PyResult<PyDataLoader> {
+    let vocab = PyVocabulary::new("gpt2")?;
+    let token_ids = vocab.encode(py, txt)?;
+    let dataset = Bound::new(py, PyGPTDataset::new(token_ids, max_length, stride)?)?;
+    PyDataLoader::new(&dataset, batch_size, shuffle, drop_last, num_workers, None)
+}
+
+#[pymodule]
+fn llm_rs(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyVocabulary>()?;
+    m.add_class::<PyGPTDataset>()?;
+    m.add_class::<PyDataLoader>()?;
+    m.add_function(wrap_pyfunction!(create_dataloader_v1, m)?)?;
+    Ok(())
+}
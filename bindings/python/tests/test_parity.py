@@ -0,0 +1,43 @@
+# 与书中第2章的参考实现（tiktoken + GPTDatasetV1）对比，运行：
+#   maturin develop --release && python -m pytest tests
+from pathlib import Path
+
+import tiktoken
+import llm_rs
+
+TEXT = (Path(__file__).parents[3] / "data" / "the-verdict.txt").read_text()
+
+
+def reference_windows(token_ids, max_length, stride):
+    return [
+        (token_ids[i : i + max_length], token_ids[i + 1 : i + max_length + 1])
+        for i in range(0, len(token_ids) - max_length, stride)
+    ]
+
+
+def test_encode_decode():
+    reference = tiktoken.get_encoding("gpt2")
+    vocab = llm_rs.Vocabulary("gpt2")
+    assert vocab.encode(TEXT) == reference.encode(TEXT)
+    assert vocab.decode(vocab.encode(TEXT)) == TEXT
+    assert vocab.vocab_size == reference.n_vocab
+
+
+def test_dataset():
+    token_ids = tiktoken.get_encoding("gpt2").encode(TEXT)
+    dataset = llm_rs.GPTDataset(token_ids, 4, 4)
+    expected = reference_windows(token_ids, 4, 4)
+    assert len(dataset) == len(expected)
+    assert [dataset[i] for i in range(len(dataset))] == expected
+    assert dataset[-1] == expected[-1]
+
+
+def test_dataloader():
+    loader = llm_rs.create_dataloader_v1(TEXT, batch_size=8, max_length=4, stride=4, shuffle=False)
+    token_ids = tiktoken.get_encoding("gpt2").encode(TEXT)
+    expected = reference_windows(token_ids, 4, 4)
+    for _ in range(2):
+        batches = list(loader)
+        assert len(batches) == len(loader) == len(expected) // 8
+        inputs, targets = batches[0]
+        assert list(zip(inputs, targets)) == expected[:8]
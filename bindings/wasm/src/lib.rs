@@ -0,0 +1,223 @@
+use anyhow::{Context, Result};
+use llm::generate::{generate_text_stream, GenerationConfig};
+use llm::vocab::{Encoding, SentenceType, Vocabulary};
+use model::{GPTModel, GptConfig, SamplingConfig};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+// 浏览器中不能读文件，模型、配置和词表都由JS读入内存后传进来
+pub struct Session {
+    model: GPTModel,
+    vocab: Vocabulary,
+}
+
+impl Session {
+    // `config_json`为checkpoint中`model.json`的内容，`vocab_json`为空时使用GPT-2的tiktoken编码
+    pub fn new(model_bytes: &[u8], config_json: &str, vocab_json: &str) -> Result<Self> {
+        let config: GptConfig =
+            serde_json::from_str(config_json).context("Invalid model config")?;
+        let model = GPTModel::from_bytes(model_bytes, &config)?;
+        let vocab = if vocab_json.trim().is_empty() {
+            Vocabulary::new("", SentenceType::English)?.with_encoding(Encoding::Gpt2)
+        } else {
+            Vocabulary::from_json(vocab_json)?
+        };
+        Ok(Session { model, vocab })
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        Ok(self.vocab.encode(text)?)
+    }
+
+    pub fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        Ok(self.vocab.decode(token_ids)?)
+    }
+
+    // 返回prompt加上生成的文本，生成词表的eos时停止。`temperature`为0时贪心解码，`top_k`为0表示不限制
+    pub fn generate(
+        &self,
+        prompt: &str,
+        max_new_tokens: usize,
+        temperature: f32,
+        top_k: usize,
+        seed: u64,
+    ) -> Result<String> {
+        let sampling = SamplingConfig {
+            temperature,
+            top_k: (top_k > 0).then_some(top_k),
+            ..SamplingConfig::default()
+        };
+        let mut rng = StdRng::seed_from_u64(seed);
+        Ok(generate_text_stream(
+            &self.model,
+            &self.vocab,
+            prompt,
+            &GenerationConfig::new(max_new_tokens).with_eos_token_id(self.vocab.eos_id()),
+            &sampling,
+            &mut rng,
+            |_| {},
+        )?)
+    }
+}
+
+// JS通过`llm_alloc`分配输入缓冲区，调用后从`llm_output_ptr`/`llm_output_len`读取UTF-8结果。
+// 返回0表示成功，-1表示失败，此时结果为错误信息
+#[cfg(target_arch = "wasm32")]
+mod ffi {
+    use super::Session;
+    use anyhow::Result;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static SESSION: RefCell<Option<Session>> = const { RefCell::new(None) };
+        static OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+        if len == 0 {
+            return &[];
+        }
+        // SAFETY: JS传入的是`llm_alloc`分配并写入了`len`字节的缓冲区
+        unsafe { std::slice::from_raw_parts(ptr, len) }
+    }
+
+    unsafe fn text<'a>(ptr: *const u8, len: usize) -> Result<&'a str> {
+        Ok(std::str::from_utf8(unsafe { bytes(ptr, len) })?)
+    }
+
+    fn finish(result: Result<String>) -> i32 {
+        let (status, output) = match result {
+            Ok(output) => (0, output),
+            Err(err) => (-1, format!("{err:#}")),
+        };
+        OUTPUT.with(|buffer| *buffer.borrow_mut() = output.into_bytes());
+        status
+    }
+
+    fn with_session(f: impl FnOnce(&Session) -> Result<String>) -> i32 {
+        finish(SESSION.with(|session| match session.borrow().as_ref() {
+            Some(session) => f(session),
+            None => Err(anyhow::anyhow!("Call llm_load first")),
+        }))
+    }
+
+    #[unsafe(no_mangle)]
+    pub extern "C" fn llm_alloc(len: usize) -> *mut u8 {
+        let mut buffer = Vec::<u8>::with_capacity(len);
+        let ptr = buffer.as_mut_ptr();
+        std::mem::forget(buffer);
+        ptr
+    }
+
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn llm_free(ptr: *mut u8, len: usize) {
+        // SAFETY: `ptr`和`len`来自`llm_alloc`
+        drop(unsafe { Vec::from_raw_parts(ptr, 0, len) });
+    }
+
+    #[unsafe(no_mangle)]
+    pub extern "C" fn llm_output_ptr() -> *const u8 {
+        OUTPUT.with(|buffer| buffer.borrow().as_ptr())
+    }
+
+    #[unsafe(no_mangle)]
+    pub extern "C" fn llm_output_len() -> usize {
+        OUTPUT.with(|buffer| buffer.borrow().len())
+    }
+
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn llm_load(
+        model_ptr: *const u8,
+        model_len: usize,
+        config_ptr: *const u8,
+        config_len: usize,
+        vocab_ptr: *const u8,
+        vocab_len: usize,
+    ) -> i32 {
+        let session = unsafe {
+            text(config_ptr, config_len).and_then(|config| {
+                let vocab = text(vocab_ptr, vocab_len)?;
+                Session::new(bytes(model_ptr, model_len), config, vocab)
+            })
+        };
+        finish(session.map(|session| {
+            SESSION.with(|slot| *slot.borrow_mut() = Some(session));
+            String::new()
+        }))
+    }
+
+    // 结果为token id的JSON数组
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn llm_encode(text_ptr: *const u8, text_len: usize) -> i32 {
+        let input = unsafe { text(text_ptr, text_len) };
+        with_session(|session| Ok(serde_json::to_string(&session.encode(input?)?)?))
+    }
+
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn llm_generate(
+        prompt_ptr: *const u8,
+        prompt_len: usize,
+        max_new_tokens: u32,
+        temperature: f32,
+        top_k: u32,
+        seed: u32,
+    ) -> i32 {
+        let prompt = unsafe { text(prompt_ptr, prompt_len) };
+        with_session(|session| {
+            session.generate(
+                prompt?,
+                max_new_tokens as usize,
+                temperature,
+                top_k as usize,
+                seed as u64,
+            )
+        })
+    }
+
+    // 浏览器没有系统随机数。推理只使用固定seed的`StdRng`，不会调用到这里
+    #[unsafe(no_mangle)]
+    unsafe extern "Rust" fn __getrandom_v03_custom(
+        _dest: *mut u8,
+        _len: usize,
+    ) -> Result<(), getrandom::Error> {
+        Err(getrandom::Error::UNSUPPORTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() -> Result<()> {
+        let config = GptConfig {
+            context_length: 16,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 1,
+            ..GptConfig::gpt2_small()
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let path = std::env::temp_dir().join("test_wasm_session.safetensors");
+        model.save(&path)?;
+        let bytes = std::fs::read(&path)?;
+        let config_json = std::fs::read_to_string(path.with_extension("json"))?;
+
+        let session = Session::new(&bytes, &config_json, "")?;
+        let token_ids = session.encode("Every effort moves you")?;
+        assert_eq!(token_ids, [6109, 3626, 6100, 345]);
+        assert_eq!(session.decode(&token_ids)?, "Every effort moves you");
+
+        let text = session.generate("Every effort moves you", 4, 0.0, 0, 0)?;
+        println!("{text}");
+        assert!(text.starts_with("Every effort moves you"));
+        assert_eq!(
+            text,
+            session.generate("Every effort moves you", 4, 0.0, 0, 1)?
+        );
+
+        assert!(Session::new(b"", &config_json, "").is_err());
+        assert!(Session::new(&bytes, "{}", "").is_err());
+        Ok(())
+    }
+}
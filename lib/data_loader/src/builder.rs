@@ -0,0 +1,343 @@
+use crate::sampler::{chunk_batches, shuffle_indices};
+use crate::seed::derive_seed;
+use crate::{
+    Collate, DataLoader, Dataset, DistributedSampler, IterableDataset, LoaderState, StallAction,
+};
+use std::time::Duration;
+
+// 用命名参数配置`DataLoader`，默认值为：
+// batch_size=1, shuffle=false, num_workers=1, drop_last=false, 无seed, 不分片, 非持久化, 无超时检测
+#[derive(Debug, Clone)]
+pub struct DataLoaderBuilder {
+    batch_size: usize,
+    shuffle: bool,
+    num_workers: usize,
+    drop_last: bool,
+    seed: Option<u64>,
+    distributed: Option<(usize, usize)>,
+    persistent_workers: bool,
+    watchdog: Option<(Duration, StallAction)>,
+    resume: Option<LoaderState>,
+}
+
+impl Default for DataLoaderBuilder {
+    fn default() -> Self {
+        DataLoaderBuilder {
+            batch_size: 1,
+            shuffle: false,
+            num_workers: 1,
+            drop_last: false,
+            seed: None,
+            distributed: None,
+            persistent_workers: false,
+            watchdog: None,
+            resume: None,
+        }
+    }
+}
+
+impl DataLoaderBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        assert!(batch_size > 0);
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn shuffle(mut self, shuffle: bool) -> Self {
+        self.shuffle = shuffle;
+        self
+    }
+
+    pub fn num_workers(mut self, num_workers: usize) -> Self {
+        assert!(num_workers > 0);
+        self.num_workers = num_workers;
+        self
+    }
+
+    pub fn drop_last(mut self, drop_last: bool) -> Self {
+        self.drop_last = drop_last;
+        self
+    }
+
+    // 固定打乱顺序，使每次运行得到相同的批次
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    // 只加载第`rank`个分片，未设置`seed`时各进程使用0作为共同的seed
+    pub fn distributed(mut self, rank: usize, world_size: usize) -> Self {
+        assert!(world_size > 0 && rank < world_size);
+        self.distributed = Some((rank, world_size));
+        self
+    }
+
+    // 工作线程在epoch之间保持运行，每次`iter`开始新的epoch时重新打乱。
+    // 设置了`seed`时第`epoch`个epoch使用`seed + epoch`
+    pub fn persistent_workers(mut self, persistent_workers: bool) -> Self {
+        self.persistent_workers = persistent_workers;
+        self
+    }
+
+    // 见`DataLoader::watchdog`
+    pub fn watchdog(mut self, timeout: Duration, action: StallAction) -> Self {
+        self.watchdog = Some((timeout, action));
+        self
+    }
+
+    // 从`DataLoader::state`保存的位置继续，同时恢复其中的`seed`。
+    // 未设置`seed`且打乱时无法还原顺序。只对map式数据集生效
+    pub fn resume(mut self, state: LoaderState) -> Self {
+        if let Some(seed) = state.seed {
+            self.seed = Some(seed);
+        }
+        self.resume = Some(state);
+        self
+    }
+
+    fn with_watchdog<B: Send + 'static>(&self, mut loader: DataLoader<B>) -> DataLoader<B> {
+        if let Some((timeout, action)) = self.watchdog {
+            loader.watchdog(timeout, action);
+        }
+        loader
+    }
+
+    fn batches(&self, len: usize, epoch: u64) -> Vec<Vec<usize>> {
+        let seed = self.seed.map(|seed| seed.wrapping_add(epoch));
+
+        if let Some((rank, world_size)) = self.distributed {
+            let mut sampler = DistributedSampler::new(
+                len,
+                rank,
+                world_size,
+                self.shuffle,
+                self.seed.unwrap_or(0),
+            );
+            sampler.set_epoch(epoch);
+            return sampler.batches(self.batch_size, self.drop_last);
+        }
+
+        let mut indices: Vec<usize> = (0..len).collect();
+        if self.shuffle {
+            shuffle_indices(&mut indices, seed);
+        }
+
+        chunk_batches(&indices, self.batch_size, self.drop_last)
+    }
+
+    pub fn build<D>(&self, dataset: D) -> DataLoader<Vec<D::Item>>
+    where
+        D: Dataset + 'static,
+        D::Item: Send + 'static,
+    {
+        self.build_with(dataset, |batch: Vec<D::Item>| batch)
+    }
+
+    pub fn build_with<D, C>(&self, dataset: D, collate: C) -> DataLoader<C::Output>
+    where
+        D: Dataset + 'static,
+        C: Collate<D::Item> + 'static,
+        C::Output: Send + 'static,
+    {
+        let len = dataset.len();
+        let start = self.resume.unwrap_or_default();
+        // 没有指定seed时从`set_seed`派生，保存在`LoaderState`中，从检查点继续时顺序不变
+        let mut builder = self.clone();
+        if builder.shuffle && builder.seed.is_none() {
+            builder.seed = derive_seed();
+        }
+
+        let mut loader = DataLoader::spawn_batches(dataset, self.num_workers, collate);
+        loader.seed = builder.seed;
+        loader.submit_from(
+            start.epoch,
+            builder.batches(len, start.epoch),
+            start.consumed,
+        );
+
+        // 持久化工作线程：线程在epoch之间保持运行，
+        // 当前epoch的批次全部返回后，再次调用`iter`时提交下一个epoch的批次
+        if self.persistent_workers {
+            loader.plan = Some(Box::new(move |epoch| builder.batches(len, epoch)));
+        }
+
+        self.with_watchdog(loader)
+    }
+
+    // 对可迭代数据集，打乱和分片都以分片为单位，不支持持久化工作线程
+    pub fn build_iterable<D>(&self, dataset: D) -> DataLoader<Vec<D::Item>>
+    where
+        D: IterableDataset + 'static,
+        D::Item: Send + 'static,
+    {
+        self.build_iterable_with(dataset, |batch: Vec<D::Item>| batch)
+    }
+
+    pub fn build_iterable_with<D, C>(&self, dataset: D, collate: C) -> DataLoader<C::Output>
+    where
+        D: IterableDataset + 'static,
+        C: Collate<D::Item> + 'static,
+        C::Output: Send + 'static,
+    {
+        let shards = match self.distributed {
+            Some((rank, world_size)) => {
+                let seed = self.seed.unwrap_or(0);
+                DistributedSampler::new(dataset.num_shards(), rank, world_size, self.shuffle, seed)
+                    .indices()
+            }
+            None => {
+                let mut shards: Vec<usize> = (0..dataset.num_shards()).collect();
+                if self.shuffle {
+                    shuffle_indices(&mut shards, self.seed);
+                }
+                shards
+            }
+        };
+
+        let loader = DataLoader::from_shards_with(
+            dataset,
+            shards,
+            self.batch_size,
+            self.num_workers,
+            self.drop_last,
+            collate,
+        );
+
+        self.with_watchdog(loader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VecDataset;
+
+    fn batches(builder: &DataLoaderBuilder) -> Vec<Vec<i32>> {
+        let loader = builder.build(VecDataset::new((0..25).collect()));
+        let mut batches: Vec<Vec<i32>> = loader.iter().map(Result::unwrap).collect();
+        batches.sort();
+        batches
+    }
+
+    #[test]
+    fn test_builder() {
+        let builder = DataLoader::builder()
+            .batch_size(10)
+            .shuffle(true)
+            .num_workers(4)
+            .seed(42);
+
+        let first = batches(&builder);
+        println!("{:?}", first);
+        assert_eq!(first.len(), 3);
+        assert_eq!(first, batches(&builder));
+
+        assert_eq!(batches(&builder.clone().drop_last(true)).len(), 2);
+
+        let shards: Vec<Vec<i32>> = (0..2)
+            .map(|rank| batches(&builder.clone().distributed(rank, 2)).concat())
+            .collect();
+        assert_eq!((shards[0].len(), shards[1].len()), (13, 13));
+
+        let mut all = shards.concat();
+        all.sort();
+        all.dedup();
+        assert_eq!(all, (0..25).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_persistent_workers() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let epochs = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&epochs);
+
+        let mut loader = DataLoader::builder()
+            .batch_size(4)
+            .shuffle(true)
+            .num_workers(3)
+            .seed(7)
+            .persistent_workers(true)
+            .build(VecDataset::new((0..10).collect::<Vec<i32>>()));
+
+        loader.on_epoch_end(move |stats| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            assert_eq!(stats.samples, 10);
+        });
+        let loader = Arc::new(loader);
+
+        let mut orders = vec![];
+        for epoch in 0..3 {
+            // `iter_shared`与`iter`属于同一个epoch序列
+            let batches: Vec<Vec<i32>> = if epoch == 1 {
+                loader.iter_shared().map(Result::unwrap).collect()
+            } else {
+                loader.iter().map(Result::unwrap).collect()
+            };
+            assert_eq!(loader.epoch(), epoch);
+            assert_eq!(batches.len(), 3);
+
+            let mut items = batches.concat();
+            items.sort();
+            assert_eq!(items, (0..10).collect::<Vec<_>>());
+
+            let mut batches = batches;
+            batches.sort();
+            println!("epoch {epoch}: {:?}", batches);
+            orders.push(batches);
+        }
+
+        assert_eq!(epochs.load(Ordering::SeqCst), 3);
+        assert!(orders[0] != orders[1] || orders[1] != orders[2]);
+    }
+
+    #[test]
+    fn test_resume() {
+        let builder = DataLoader::builder()
+            .batch_size(4)
+            .shuffle(true)
+            .num_workers(3)
+            .seed(7);
+        let dataset = || VecDataset::new((0..30).collect::<Vec<i32>>());
+
+        let loader = builder.build(dataset());
+        let all: Vec<Vec<i32>> = loader.iter().map(Result::unwrap).collect();
+
+        let loader = builder.build(dataset());
+        let mut iter = loader.iter();
+        let mut seen: Vec<Vec<i32>> = (0..3).map(|_| iter.next().unwrap().unwrap()).collect();
+        let state = loader.state();
+        assert_eq!(
+            state,
+            LoaderState {
+                epoch: 0,
+                seed: Some(7),
+                consumed: 12
+            }
+        );
+        drop(loader);
+
+        let loader = DataLoader::builder()
+            .batch_size(4)
+            .shuffle(true)
+            .num_workers(2)
+            .resume(state)
+            .build(dataset());
+        seen.extend(loader.iter().map(Result::unwrap));
+        println!("{:?}", seen);
+        assert_eq!(seen, all);
+        assert_eq!(
+            loader.state(),
+            LoaderState {
+                epoch: 1,
+                seed: Some(7),
+                consumed: 0
+            }
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{DataLoaderError, GPTDataset, TailPolicy};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"GPTC";
+const VERSION: u32 = 1;
+// magic、版本、哈希、`max_length`、`stride`、尾部策略、填充id和token数
+const HEADER_BYTES: u64 = 4 + 4 + 8 + 8 + 8 + 1 + 8 + 8;
+
+// FNV-1a 64位哈希，结果在不同运行和平台之间保持一致
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+impl GPTDataset {
+    // `content_hash`通常是原始文本（以及分词配置）的哈希，
+    // 下次运行时用它判断缓存是否仍然有效，从而跳过分词。
+    // 先写到同名的`.tmp`文件，落盘后再改名，中断时不会留下不完整的缓存
+    pub fn cache_to(
+        &self,
+        path: impl AsRef<Path>,
+        content_hash: u64,
+    ) -> Result<(), DataLoaderError> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        self.write_cache(&mut writer, content_hash)?;
+
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        Ok(fs::rename(&tmp, path)?)
+    }
+
+    fn write_cache(
+        &self,
+        writer: &mut impl Write,
+        content_hash: u64,
+    ) -> Result<(), DataLoaderError> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        write_u64(writer, content_hash)?;
+        write_u64(writer, self.max_length() as u64)?;
+        write_u64(writer, self.stride() as u64)?;
+
+        let (tag, pad_id) = match self.tail() {
+            TailPolicy::Drop => (0, 0),
+            TailPolicy::Pad(pad_id) => (1, pad_id as u64),
+        };
+        writer.write_all(&[tag])?;
+        write_u64(writer, pad_id)?;
+
+        write_u64(writer, self.token_ids().len() as u64)?;
+        for &id in self.token_ids() {
+            let id = u32::try_from(id).map_err(|_| DataLoaderError::TokenIdOverflow(id))?;
+            writer.write_all(&id.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    // 缓存不存在或`content_hash`不一致时返回`None`
+    pub fn load_cached(
+        path: impl AsRef<Path>,
+        content_hash: u64,
+    ) -> Result<Option<Self>, DataLoaderError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC || header[4..] != VERSION.to_le_bytes() {
+            return Err(DataLoaderError::InvalidCache("not a GPTDataset cache file"));
+        }
+
+        if read_u64(&mut reader)? != content_hash {
+            return Ok(None);
+        }
+
+        let max_length = read_u64(&mut reader)? as usize;
+        let stride = read_u64(&mut reader)? as usize;
+        if max_length == 0 || stride == 0 {
+            return Err(DataLoaderError::InvalidCache(
+                "max_length and stride must be greater than 0",
+            ));
+        }
+
+        let mut tag = [0; 1];
+        reader.read_exact(&mut tag)?;
+        let pad_id = read_u64(&mut reader)? as usize;
+        let tail = match tag[0] {
+            0 => TailPolicy::Drop,
+            1 => TailPolicy::Pad(pad_id),
+            _ => return Err(DataLoaderError::InvalidCache("invalid tail policy")),
+        };
+
+        // 损坏的文件头可能给出很大的数量，先与文件大小比较再分配
+        let count = read_u64(&mut reader)?;
+        if Some(file_len)
+            != count
+                .checked_mul(4)
+                .and_then(|n| n.checked_add(HEADER_BYTES))
+        {
+            return Err(DataLoaderError::InvalidCache(
+                "token count does not match file size",
+            ));
+        }
+        let count = count as usize;
+        let mut token_ids = Vec::with_capacity(count);
+        let mut buf = [0; 4];
+        for _ in 0..count {
+            reader.read_exact(&mut buf)?;
+            token_ids.push(u32::from_le_bytes(buf) as usize);
+        }
+
+        Ok(Some(GPTDataset::with_tail(
+            token_ids, max_length, stride, tail,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dataset;
+
+    #[test]
+    fn test_gpt_dataset_cache() {
+        let path = std::env::temp_dir().join("test_gpt_dataset_cache.bin");
+        let hash = content_hash(b"some corpus text");
+        assert_ne!(hash, content_hash(b"some corpus text!"));
+
+        let dataset = GPTDataset::with_tail((0..50).collect(), 8, 4, TailPolicy::Pad(7));
+        dataset.cache_to(&path, hash).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let cached = GPTDataset::load_cached(&path, hash).unwrap().unwrap();
+        assert_eq!(cached.token_ids(), dataset.token_ids());
+        assert_eq!(cached.tail(), TailPolicy::Pad(7));
+        assert_eq!(cached.len(), dataset.len());
+        assert_eq!(cached.get(3).label, dataset.get(3).label);
+
+        assert!(GPTDataset::load_cached(&path, hash + 1).unwrap().is_none());
+
+        // 损坏的文件头返回`InvalidCache`，不会panic或分配过多内存
+        let bytes = std::fs::read(&path).unwrap();
+        let corrupt = |offset: usize, value: u64| {
+            let mut bytes = bytes.clone();
+            bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+            std::fs::write(&path, bytes).unwrap();
+            let err = GPTDataset::load_cached(&path, hash).unwrap_err();
+            println!("{err}");
+            assert!(matches!(err, DataLoaderError::InvalidCache(_)));
+        };
+        corrupt(41, u64::MAX);
+        corrupt(41, 51);
+        corrupt(16, 0);
+        corrupt(24, 0);
+        std::fs::remove_file(&path).unwrap();
+        assert!(GPTDataset::load_cached(&path, hash).unwrap().is_none());
+    }
+}
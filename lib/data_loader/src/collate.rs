@@ -0,0 +1,310 @@
+use crate::{DataLoaderError, TrainData};
+use std::fmt::Debug;
+
+// 在工作线程中把一个批次的样本合并为模型需要的输入格式。
+// 闭包`Fn(Vec<T>) -> B`也实现了该trait。
+pub trait Collate<T>: Send + Sync {
+    type Output;
+
+    fn collate(&self, samples: Vec<T>) -> Self::Output;
+
+    // 工作线程调用该方法，返回的错误交给迭代器
+    fn try_collate(&self, samples: Vec<T>) -> Result<Self::Output, DataLoaderError> {
+        Ok(self.collate(samples))
+    }
+
+    // 批次中的(真实token数, 填充token数)，用于统计填充所占的比例
+    fn token_counts(&self, _batch: &Self::Output) -> Option<(usize, usize)> {
+        None
+    }
+}
+
+impl<T, B, F> Collate<T> for F
+where
+    F: Fn(Vec<T>) -> B + Send + Sync,
+{
+    type Output = B;
+
+    fn collate(&self, samples: Vec<T>) -> B {
+        self(samples)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaddedBatch<T> {
+    pub features: Vec<Vec<T>>,
+    pub labels: Vec<Vec<T>>,
+    // 1表示真实token，0表示填充
+    pub attention_mask: Vec<Vec<u8>>,
+    // 1表示计算loss的标签，0表示填充的标签，传给`masked_cross_entropy`
+    pub loss_mask: Vec<Vec<u8>>,
+}
+
+// 在右侧填充，把批次内的样本补齐到最长的长度
+#[derive(Debug, Clone)]
+pub struct PadCollator<T> {
+    pad_id: T,
+    label_pad_id: T,
+}
+
+impl<T: Clone> PadCollator<T> {
+    pub fn new(pad_id: T) -> Self {
+        PadCollator {
+            label_pad_id: pad_id.clone(),
+            pad_id,
+        }
+    }
+
+    // 标签使用单独的填充值，便于计算损失时忽略
+    pub fn with_label_pad_id(mut self, label_pad_id: T) -> Self {
+        self.label_pad_id = label_pad_id;
+        self
+    }
+}
+
+fn pad_to<T: Clone>(items: &[Vec<T>], pad: &T) -> Vec<Vec<T>> {
+    let max_len = items.iter().map(|item| item.len()).max().unwrap_or(0);
+
+    items
+        .iter()
+        .map(|item| {
+            let mut item = item.clone();
+            item.resize(max_len, pad.clone());
+            item
+        })
+        .collect()
+}
+
+fn length_mask<T>(items: &[Vec<T>]) -> Vec<Vec<u8>> {
+    let max_len = items.iter().map(|item| item.len()).max().unwrap_or(0);
+    items
+        .iter()
+        .map(|item| {
+            let mut mask = vec![1; item.len()];
+            mask.resize(max_len, 0);
+            mask
+        })
+        .collect()
+}
+
+impl<T> Collate<TrainData<T>> for PadCollator<T>
+where
+    T: Clone + Debug + Send + Sync,
+{
+    type Output = PaddedBatch<T>;
+
+    fn collate(&self, samples: Vec<TrainData<T>>) -> PaddedBatch<T> {
+        let (features, labels): (Vec<_>, Vec<_>) = samples
+            .into_iter()
+            .map(|sample| (sample.feature, sample.label))
+            .unzip();
+
+        PaddedBatch {
+            attention_mask: length_mask(&features),
+            loss_mask: length_mask(&labels),
+            features: pad_to(&features, &self.pad_id),
+            labels: pad_to(&labels, &self.label_pad_id),
+        }
+    }
+
+    fn token_counts(&self, batch: &PaddedBatch<T>) -> Option<(usize, usize)> {
+        let total: usize = batch.attention_mask.iter().map(|mask| mask.len()).sum();
+        let real: usize = batch
+            .attention_mask
+            .iter()
+            .flatten()
+            .map(|&m| m as usize)
+            .sum();
+        Some((real, total - real))
+    }
+}
+
+// 按行主序连续存放的`(batch_size, seq_len)`矩阵，可直接交给矩阵运算或转换为张量
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackedBatch {
+    pub features: Vec<u32>,
+    pub labels: Vec<u32>,
+    pub shape: [usize; 2],
+    // 每一行填充前的长度
+    pub lengths: Vec<usize>,
+}
+
+impl StackedBatch {
+    pub fn batch_size(&self) -> usize {
+        self.shape[0]
+    }
+
+    pub fn seq_len(&self) -> usize {
+        self.shape[1]
+    }
+
+    pub fn feature_row(&self, row: usize) -> &[u32] {
+        let seq_len = self.seq_len();
+        &self.features[row * seq_len..(row + 1) * seq_len]
+    }
+
+    pub fn label_row(&self, row: usize) -> &[u32] {
+        let seq_len = self.seq_len();
+        &self.labels[row * seq_len..(row + 1) * seq_len]
+    }
+}
+
+// 把样本堆叠为`StackedBatch`，默认要求批次内样本等长
+#[derive(Debug, Clone, Default)]
+pub struct StackCollator {
+    padding: Option<(u32, u32)>,
+}
+
+impl StackCollator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 允许不等长的样本，在右侧填充到最长的长度
+    pub fn with_padding(mut self, pad_id: u32, label_pad_id: u32) -> Self {
+        self.padding = Some((pad_id, label_pad_id));
+        self
+    }
+}
+
+fn to_u32<T: TryInto<u32> + Clone + Debug>(id: &T) -> Result<u32, DataLoaderError> {
+    id.clone()
+        .try_into()
+        .map_err(|_| DataLoaderError::sample(format!("Token id {id:?} does not fit in u32")))
+}
+
+impl<T> Collate<TrainData<T>> for StackCollator
+where
+    T: Clone + Debug + Send + Sync + TryInto<u32>,
+{
+    type Output = StackedBatch;
+
+    fn collate(&self, samples: Vec<TrainData<T>>) -> StackedBatch {
+        self.try_collate(samples).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    // token id超出`u32`，或者没有填充时样本不等长，返回错误
+    fn try_collate(&self, samples: Vec<TrainData<T>>) -> Result<StackedBatch, DataLoaderError> {
+        let lengths: Vec<usize> = samples.iter().map(|s| s.feature.len()).collect();
+        let seq_len = lengths.iter().copied().max().unwrap_or(0);
+        let (pad_id, label_pad_id) = match self.padding {
+            Some(padding) => padding,
+            None if lengths.iter().all(|&len| len == seq_len) => (0, 0),
+            None => {
+                return Err(DataLoaderError::sample(
+                    "Samples have different lengths, use `StackCollator::with_padding`",
+                ))
+            }
+        };
+
+        let mut features = Vec::with_capacity(samples.len() * seq_len);
+        let mut labels = Vec::with_capacity(samples.len() * seq_len);
+
+        for sample in samples.iter() {
+            for id in sample.feature.iter() {
+                features.push(to_u32(id)?);
+            }
+            features.resize(features.len() + seq_len - sample.feature.len(), pad_id);
+            for id in sample.label.iter() {
+                labels.push(to_u32(id)?);
+            }
+            labels.resize(labels.len() + seq_len - sample.label.len(), label_pad_id);
+        }
+
+        Ok(StackedBatch {
+            features,
+            labels,
+            shape: [samples.len(), seq_len],
+            lengths,
+        })
+    }
+
+    fn token_counts(&self, batch: &StackedBatch) -> Option<(usize, usize)> {
+        let real: usize = batch.lengths.iter().sum();
+        Some((real, batch.features.len() - real))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{batch_indices, DataLoader, Dataset, GPTDataset, VecDataset};
+
+    #[test]
+    fn test_pad_collator() {
+        let samples: Vec<TrainData<usize>> = [3, 1, 4, 2]
+            .iter()
+            .map(|&len| TrainData {
+                feature: (1..=len).collect(),
+                label: (2..=len + 1).collect(),
+            })
+            .collect();
+
+        let collator = PadCollator::new(0).with_label_pad_id(usize::MAX);
+        let batch = collator.collate(samples[..2].to_vec());
+        assert_eq!(batch.features, [vec![1, 2, 3], vec![1, 0, 0]]);
+        assert_eq!(batch.labels[1], [2, usize::MAX, usize::MAX]);
+        assert_eq!(batch.attention_mask, [vec![1, 1, 1], vec![1, 0, 0]]);
+        assert_eq!(batch.loss_mask, batch.attention_mask);
+
+        let dataset = VecDataset::new(samples);
+        let batches = batch_indices(dataset.len(), 2, true, false);
+        let loader = DataLoader::from_batches_with(dataset, batches, 2, collator);
+
+        for batch in loader.iter() {
+            let batch = batch.unwrap();
+            println!("{:?}", batch);
+
+            let max_len = batch.features.iter().map(|f| f.len()).max().unwrap();
+            assert!(batch.features.iter().all(|f| f.len() == max_len));
+        }
+
+        let stats = loader.stats();
+        assert_eq!(stats.real_tokens, 10);
+        println!("padding fraction: {:.2}", stats.padding_fraction());
+    }
+
+    #[test]
+    fn test_stack_collator() {
+        let dataset = GPTDataset::new((0..40).collect(), 4, 4);
+        let loader = DataLoader::builder()
+            .batch_size(3)
+            .build_with(dataset, StackCollator::new());
+
+        for batch in loader.iter() {
+            let batch = batch.unwrap();
+            println!("{:?}", batch);
+            assert_eq!(batch.features.len(), batch.batch_size() * batch.seq_len());
+        }
+        assert_eq!(loader.stats().padding_tokens, 0);
+        // 40个token分成9个窗口，最后3个token被丢弃
+        assert_eq!(loader.stats().dropped_tokens, 3);
+
+        let samples = vec![
+            TrainData {
+                feature: vec![1usize, 2, 3],
+                label: vec![2, 3, 4],
+            },
+            TrainData {
+                feature: vec![5],
+                label: vec![6],
+            },
+        ];
+        assert!(StackCollator::new().try_collate(samples.clone()).is_err());
+        let collator = StackCollator::new().with_padding(0, 9);
+        let batch = collator.collate(samples);
+        assert_eq!(batch.shape, [2, 3]);
+        assert_eq!(batch.feature_row(1), [5, 0, 0]);
+        assert_eq!(batch.label_row(1), [6, 9, 9]);
+        assert_eq!(
+            Collate::<TrainData<usize>>::token_counts(&collator, &batch),
+            Some((4, 2))
+        );
+
+        let overflow = vec![TrainData {
+            feature: vec![u64::MAX],
+            label: vec![1],
+        }];
+        assert!(StackCollator::new().try_collate(overflow).is_err());
+    }
+}
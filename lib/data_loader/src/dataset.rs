@@ -0,0 +1,532 @@
+use crate::DataLoaderError;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::sync::Arc;
+
+// 可按下标随机访问的数据集
+pub trait Dataset: Send + Sync {
+    type Item;
+
+    fn len(&self) -> usize;
+
+    fn get(&self, index: usize) -> Self::Item;
+
+    // `DataLoader`的工作线程调用该方法，返回的错误作为批次的结果交给迭代器。
+    // 读取可能失败的数据集应实现该方法，`get`可以直接panic
+    fn try_get(&self, index: usize) -> Result<Self::Item, DataLoaderError> {
+        Ok(self.get(index))
+    }
+
+    // 工作线程实际调用的方法，`None`表示样本被`filter`去掉，不放进批次
+    fn try_get_filtered(&self, index: usize) -> Result<Option<Self::Item>, DataLoaderError> {
+        self.try_get(index).map(Some)
+    }
+
+    // 没有进入任何样本的token数（如滑动窗口丢弃的末尾），`DataLoader`每个epoch记入`LoaderStats`
+    fn dropped_tokens(&self) -> usize {
+        0
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // 在`get`时才执行变换，使用`DataLoader`时变换在工作线程中完成
+    fn map<F, U>(self, f: F) -> Map<Self, F>
+    where
+        Self: Sized,
+        F: Fn(Self::Item) -> U + Send + Sync,
+    {
+        Map { dataset: self, f }
+    }
+
+    // 与`map`相同在工作线程中取样本时才判断，被去掉的样本不放进批次，批次可能不满。
+    // `len`仍为过滤前的样本数
+    fn filter<P>(self, predicate: P) -> Filter<Self, P>
+    where
+        Self: Sized,
+        P: Fn(&Self::Item) -> bool + Send + Sync,
+    {
+        Filter {
+            dataset: self,
+            predicate,
+        }
+    }
+
+    // 构造时在当前线程对每个样本执行一次判断并记录保留的下标，之后`len`为保留的样本数，批次都是满的。
+    // 上游的`map`在构造时和工作线程中各执行一次
+    fn filter_eager<P>(self, predicate: P) -> EagerFilter<Self>
+    where
+        Self: Sized,
+        P: Fn(&Self::Item) -> bool,
+    {
+        let indices = (0..self.len())
+            .filter(|&i| predicate(&self.get(i)))
+            .collect();
+
+        EagerFilter {
+            dataset: self,
+            indices,
+        }
+    }
+
+    // 按比例随机划分为若干子集（如训练/验证/测试），相同的`seed`得到相同的划分。
+    // 子集只保存下标，共享同一份底层数据。
+    fn split(self, ratios: &[f32], seed: u64) -> Vec<Subset<Self>>
+    where
+        Self: Sized,
+    {
+        assert!(!ratios.is_empty() && ratios.iter().all(|&r| r >= 0.0));
+
+        let mut indices: Vec<usize> = (0..self.len()).collect();
+        indices.shuffle(&mut StdRng::seed_from_u64(seed));
+
+        let dataset = Arc::new(self);
+        let mut start = 0;
+        split_points(indices.len(), ratios)
+            .into_iter()
+            .map(|end| {
+                let subset = Subset {
+                    dataset: Arc::clone(&dataset),
+                    indices: indices[start..end].to_vec(),
+                };
+                start = end;
+                subset
+            })
+            .collect()
+    }
+}
+
+// 每个子集的结束位置：按累计比例取整，用f64计算，样本数超过2^24时也不会越界。
+// 最后一个子集包含取整后剩余的所有样本
+fn split_points(len: usize, ratios: &[f32]) -> Vec<usize> {
+    let total = ratios.iter().map(|&r| r as f64).sum::<f64>();
+    assert!(total > 0.0);
+
+    let mut cumulative = 0.0;
+    let mut points = ratios
+        .iter()
+        .map(|&ratio| {
+            cumulative += ratio as f64;
+            ((len as f64 * cumulative / total).round() as usize).min(len)
+        })
+        .collect::<Vec<_>>();
+    *points.last_mut().unwrap() = len;
+    points
+}
+
+impl<D: Dataset + ?Sized> Dataset for Box<D> {
+    type Item = D::Item;
+
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn get(&self, index: usize) -> D::Item {
+        (**self).get(index)
+    }
+
+    fn try_get(&self, index: usize) -> Result<D::Item, DataLoaderError> {
+        (**self).try_get(index)
+    }
+
+    fn try_get_filtered(&self, index: usize) -> Result<Option<D::Item>, DataLoaderError> {
+        (**self).try_get_filtered(index)
+    }
+
+    fn dropped_tokens(&self) -> usize {
+        (**self).dropped_tokens()
+    }
+}
+
+impl<D: Dataset + ?Sized> Dataset for Arc<D> {
+    type Item = D::Item;
+
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn get(&self, index: usize) -> D::Item {
+        (**self).get(index)
+    }
+
+    fn try_get(&self, index: usize) -> Result<D::Item, DataLoaderError> {
+        (**self).try_get(index)
+    }
+
+    fn try_get_filtered(&self, index: usize) -> Result<Option<D::Item>, DataLoaderError> {
+        (**self).try_get_filtered(index)
+    }
+
+    fn dropped_tokens(&self) -> usize {
+        (**self).dropped_tokens()
+    }
+}
+
+pub struct VecDataset<T> {
+    data: Vec<T>,
+}
+
+impl<T> VecDataset<T> {
+    pub fn new(data: Vec<T>) -> Self {
+        VecDataset { data }
+    }
+}
+
+impl<T: Clone + Send + Sync> Dataset for VecDataset<T> {
+    type Item = T;
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn get(&self, index: usize) -> T {
+        self.data[index].clone()
+    }
+}
+
+// 按分片迭代的数据集，适用于无法一次性载入内存的语料。
+// 每个工作线程领取一个分片，在线程内部生成该分片的样本。
+pub trait IterableDataset: Send + Sync {
+    type Item;
+
+    fn num_shards(&self) -> usize;
+
+    fn iter_shard(&self, shard: usize) -> Box<dyn Iterator<Item = Self::Item> + '_>;
+
+    // 与`Dataset::try_get`相同，工作线程遇到错误时停止并把错误交给迭代器
+    fn try_iter_shard(
+        &self,
+        shard: usize,
+    ) -> Box<dyn Iterator<Item = Result<Self::Item, DataLoaderError>> + '_> {
+        Box::new(self.iter_shard(shard).map(Ok))
+    }
+}
+
+pub struct Map<D, F> {
+    dataset: D,
+    f: F,
+}
+
+impl<D, F, U> Dataset for Map<D, F>
+where
+    D: Dataset,
+    F: Fn(D::Item) -> U + Send + Sync,
+{
+    type Item = U;
+
+    fn len(&self) -> usize {
+        self.dataset.len()
+    }
+
+    fn get(&self, index: usize) -> U {
+        (self.f)(self.dataset.get(index))
+    }
+
+    fn try_get(&self, index: usize) -> Result<U, DataLoaderError> {
+        self.dataset.try_get(index).map(&self.f)
+    }
+
+    // 被上游`filter`去掉的样本不执行变换
+    fn try_get_filtered(&self, index: usize) -> Result<Option<U>, DataLoaderError> {
+        Ok(self.dataset.try_get_filtered(index)?.map(&self.f))
+    }
+
+    fn dropped_tokens(&self) -> usize {
+        self.dataset.dropped_tokens()
+    }
+}
+
+pub struct Filter<D, P> {
+    dataset: D,
+    predicate: P,
+}
+
+impl<D, P> Dataset for Filter<D, P>
+where
+    D: Dataset,
+    P: Fn(&D::Item) -> bool + Send + Sync,
+{
+    type Item = D::Item;
+
+    fn len(&self) -> usize {
+        self.dataset.len()
+    }
+
+    fn get(&self, index: usize) -> D::Item {
+        self.try_get(index).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    // 直接访问被去掉的样本时返回错误
+    fn try_get(&self, index: usize) -> Result<D::Item, DataLoaderError> {
+        self.try_get_filtered(index)?.ok_or_else(|| {
+            DataLoaderError::sample(format!("Sample {index} is removed by the filter"))
+        })
+    }
+
+    fn try_get_filtered(&self, index: usize) -> Result<Option<D::Item>, DataLoaderError> {
+        Ok(self
+            .dataset
+            .try_get_filtered(index)?
+            .filter(|item| (self.predicate)(item)))
+    }
+
+    fn dropped_tokens(&self) -> usize {
+        self.dataset.dropped_tokens()
+    }
+}
+
+pub struct EagerFilter<D> {
+    dataset: D,
+    indices: Vec<usize>,
+}
+
+impl<D: Dataset> Dataset for EagerFilter<D> {
+    type Item = D::Item;
+
+    fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn get(&self, index: usize) -> D::Item {
+        self.dataset.get(self.indices[index])
+    }
+
+    fn try_get(&self, index: usize) -> Result<D::Item, DataLoaderError> {
+        self.dataset.try_get(self.indices[index])
+    }
+
+    fn try_get_filtered(&self, index: usize) -> Result<Option<D::Item>, DataLoaderError> {
+        self.dataset.try_get_filtered(self.indices[index])
+    }
+
+    fn dropped_tokens(&self) -> usize {
+        self.dataset.dropped_tokens()
+    }
+}
+
+pub struct Subset<D> {
+    dataset: Arc<D>,
+    indices: Vec<usize>,
+}
+
+impl<D: Dataset> Subset<D> {
+    pub fn new(dataset: Arc<D>, indices: Vec<usize>) -> Self {
+        assert!(indices.iter().all(|&i| i < dataset.len()));
+        Subset { dataset, indices }
+    }
+
+    pub fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+}
+
+impl<D: Dataset> Dataset for Subset<D> {
+    type Item = D::Item;
+
+    fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn get(&self, index: usize) -> D::Item {
+        self.dataset.get(self.indices[index])
+    }
+
+    fn try_get(&self, index: usize) -> Result<D::Item, DataLoaderError> {
+        self.dataset.try_get(self.indices[index])
+    }
+
+    fn try_get_filtered(&self, index: usize) -> Result<Option<D::Item>, DataLoaderError> {
+        self.dataset.try_get_filtered(self.indices[index])
+    }
+
+    fn dropped_tokens(&self) -> usize {
+        self.dataset.dropped_tokens()
+    }
+}
+
+// 将多个数据集首尾相连，不同类型的数据集可以用`Box<dyn Dataset<Item = T>>`组合
+pub struct ConcatDataset<D> {
+    datasets: Vec<D>,
+    // 每个数据集结束位置的累计下标
+    ends: Vec<usize>,
+}
+
+impl<D: Dataset> ConcatDataset<D> {
+    pub fn new(datasets: Vec<D>) -> Self {
+        let ends = datasets
+            .iter()
+            .scan(0, |end, dataset| {
+                *end += dataset.len();
+                Some(*end)
+            })
+            .collect();
+
+        ConcatDataset { datasets, ends }
+    }
+}
+
+impl<D: Dataset> Dataset for ConcatDataset<D> {
+    type Item = D::Item;
+
+    fn len(&self) -> usize {
+        self.ends.last().copied().unwrap_or(0)
+    }
+
+    fn get(&self, index: usize) -> D::Item {
+        let (which, index) = self.locate(index);
+        self.datasets[which].get(index)
+    }
+
+    fn try_get(&self, index: usize) -> Result<D::Item, DataLoaderError> {
+        let (which, index) = self.locate(index);
+        self.datasets[which].try_get(index)
+    }
+
+    fn try_get_filtered(&self, index: usize) -> Result<Option<D::Item>, DataLoaderError> {
+        let (which, index) = self.locate(index);
+        self.datasets[which].try_get_filtered(index)
+    }
+
+    fn dropped_tokens(&self) -> usize {
+        self.datasets.iter().map(Dataset::dropped_tokens).sum()
+    }
+}
+
+impl<D: Dataset> ConcatDataset<D> {
+    // (数据集的位置, 在该数据集中的下标)
+    fn locate(&self, index: usize) -> (usize, usize) {
+        assert!(index < self.len(), "Index {index} out of range");
+
+        let which = self.ends.partition_point(|&end| end <= index);
+        let start = if which == 0 { 0 } else { self.ends[which - 1] };
+        (which, index - start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DataLoader, GPTDataset};
+
+    #[test]
+    fn test_map_filter() {
+        let dataset = VecDataset::new((0..20).collect::<Vec<i32>>())
+            .filter_eager(|x| x % 2 == 0)
+            .map(|x| x * 10);
+        assert_eq!(dataset.len(), 10);
+        assert_eq!(dataset.get(3), 60);
+
+        let loader = DataLoader::new(dataset, 4, false, 2, false);
+        let mut items = vec![];
+        for (i, batch) in loader.iter().enumerate() {
+            let batch = batch.unwrap();
+            println!("Batch {}: {:?}", i, batch);
+            items.extend(batch);
+        }
+        println!("\n");
+
+        items.sort();
+        assert_eq!(items, (0..10).map(|x| x * 20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_lazy_filter() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let (maps, checks) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let dataset = VecDataset::new((0..20).collect::<Vec<i32>>())
+            .map({
+                let maps = Arc::clone(&maps);
+                move |x| {
+                    maps.fetch_add(1, Ordering::SeqCst);
+                    x
+                }
+            })
+            .filter({
+                let checks = Arc::clone(&checks);
+                move |x| {
+                    checks.fetch_add(1, Ordering::SeqCst);
+                    x % 4 != 0
+                }
+            })
+            .map(|x| x * 10);
+        // 构造时不执行判断和上游的变换
+        assert_eq!(checks.load(Ordering::SeqCst), 0);
+        assert_eq!(maps.load(Ordering::SeqCst), 0);
+        assert_eq!(dataset.len(), 20);
+        assert_eq!(dataset.get(3), 30);
+        assert!(dataset.try_get(4).is_err());
+        assert_eq!(dataset.try_get_filtered(4).unwrap(), None);
+
+        // 第一个批次只剩3个样本，下标12..16的批次只剩3个样本，量都不满
+        let loader = DataLoader::new(dataset, 4, false, 2, false);
+        let batches: Vec<Vec<i32>> = loader.iter().map(Result::unwrap).collect();
+        println!("{batches:?}");
+        assert_eq!(batches[0], [10, 20, 30]);
+        assert_eq!(batches.concat().len(), 15);
+        assert_eq!(loader.stats().filtered, 5);
+        assert_eq!(loader.state().consumed, 0);
+        println!("{:?}", loader.stats());
+        assert_eq!(checks.load(Ordering::SeqCst), 3 + 20);
+        assert_eq!(maps.load(Ordering::SeqCst), 3 + 20);
+
+        // 整个批次都被去掉时跳过该批次
+        let dataset = VecDataset::new((0..12).collect::<Vec<i32>>()).filter(|x| *x < 4 || *x >= 8);
+        let loader = DataLoader::new(dataset, 4, false, 1, false);
+        let batches: Vec<Vec<i32>> = loader.iter().map(Result::unwrap).collect();
+        assert_eq!(batches, [vec![0, 1, 2, 3], vec![8, 9, 10, 11]]);
+    }
+
+    #[test]
+    fn test_split() {
+        let collect = |subset: &Subset<VecDataset<i32>>| -> Vec<i32> {
+            (0..subset.len()).map(|i| subset.get(i)).collect()
+        };
+
+        let splits = VecDataset::new((0..100).collect()).split(&[0.8, 0.1, 0.1], 42);
+        assert_eq!(
+            splits.iter().map(|s| s.len()).collect::<Vec<_>>(),
+            [80, 10, 10]
+        );
+
+        let mut items: Vec<i32> = splits.iter().flat_map(collect).collect();
+        items.sort();
+        assert_eq!(items, (0..100).collect::<Vec<_>>());
+
+        let again = VecDataset::new((0..100).collect()).split(&[0.8, 0.1, 0.1], 42);
+        for (a, b) in splits.iter().zip(again.iter()) {
+            assert_eq!(collect(a), collect(b));
+        }
+
+        // `16777219 as f32`向上取整为16777220，f32计算时会越界
+        let len = (1 << 24) + 3;
+        assert_eq!(split_points(len, &[1.0, 0.0]), [len, len]);
+        assert_eq!(split_points(len, &[0.5, 0.5]), [len / 2 + 1, len]);
+        assert_eq!(split_points(5, &[1.0, 1.0, 1.0]), [2, 3, 5]);
+    }
+
+    #[test]
+    fn test_concat_subset() {
+        let datasets: Vec<Box<dyn Dataset<Item = i32>>> = vec![
+            Box::new(VecDataset::new(vec![0, 1, 2])),
+            Box::new(VecDataset::new(vec![])),
+            Box::new(VecDataset::new((3..10).collect()).map(|x| x * 10)),
+        ];
+
+        let dataset = Arc::new(ConcatDataset::new(datasets));
+        assert_eq!(dataset.len(), 10);
+        assert_eq!(dataset.get(2), 2);
+        assert_eq!(dataset.get(3), 30);
+        assert_eq!(dataset.get(9), 90);
+
+        let subset = Subset::new(Arc::clone(&dataset), vec![9, 0, 4]);
+        let loader = DataLoader::new(subset, 2, false, 1, false);
+        let items: Vec<i32> = loader.iter().flat_map(Result::unwrap).collect();
+        assert_eq!(items, [90, 0, 40]);
+
+        let dataset = GPTDataset::new((0..10).collect(), 4, 4);
+        assert_eq!(Subset::new(Arc::new(dataset), vec![1]).dropped_tokens(), 1);
+    }
+}
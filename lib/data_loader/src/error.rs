@@ -0,0 +1,53 @@
+use crate::WorkerStatus;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DataLoaderError {
+    #[error("DataLoader worker {worker} panicked: {message}")]
+    WorkerPanicked { worker: usize, message: String },
+    // `try_get`、`try_iter_shard`或`try_collate`返回的错误
+    #[error("Failed to load batch: {0}")]
+    Sample(Box<dyn StdError + Send + Sync>),
+    // 超过`timeout`没有收到任何批次
+    #[error(
+        "DataLoader stalled: no batch within {timeout:?}, queue depth {queue_depth}{}",
+        StatusList(workers)
+    )]
+    Stalled {
+        timeout: Duration,
+        queue_depth: usize,
+        workers: Vec<WorkerStatus>,
+    },
+    // 二进制token文件和缓存只能保存`u32`范围内的id
+    #[error("Token id {0} does not fit in u32")]
+    TokenIdOverflow(usize),
+    #[error("File size {0} is not a multiple of 4")]
+    InvalidTokenFile(usize),
+    #[error("max_length {max_length} and stride {stride} must be greater than 0")]
+    InvalidWindow { max_length: usize, stride: usize },
+    #[error("Invalid GPTDataset cache: {0}")]
+    InvalidCache(&'static str),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl DataLoaderError {
+    pub fn sample(err: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        DataLoaderError::Sample(err.into())
+    }
+}
+
+struct StatusList<'a>(&'a [WorkerStatus]);
+
+impl fmt::Display for StatusList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for status in self.0 {
+            write!(f, "; {status}")?;
+        }
+        Ok(())
+    }
+}
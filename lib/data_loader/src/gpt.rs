@@ -0,0 +1,188 @@
+use crate::{Dataset, TrainData};
+
+// 最后一段不足`max_length + 1`个token时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TailPolicy {
+    Drop,
+    // 使用给定的填充id（如`<pad>`）补齐最后一个窗口
+    Pad(usize),
+}
+
+// 与书中`GPTDatasetV1`相同的滑动窗口：
+// 第i个样本为`token_ids[i * stride..][..max_length]`，标签右移一位。
+// 窗口在`get`时生成，不会复制全部样本。
+#[derive(Debug, Clone)]
+pub struct GPTDataset {
+    token_ids: Vec<usize>,
+    max_length: usize,
+    stride: usize,
+    tail: TailPolicy,
+}
+
+impl GPTDataset {
+    pub fn new(token_ids: Vec<usize>, max_length: usize, stride: usize) -> Self {
+        Self::with_tail(token_ids, max_length, stride, TailPolicy::Drop)
+    }
+
+    pub fn with_tail(
+        token_ids: Vec<usize>,
+        max_length: usize,
+        stride: usize,
+        tail: TailPolicy,
+    ) -> Self {
+        assert!(max_length > 0 && stride > 0);
+
+        GPTDataset {
+            token_ids,
+            max_length,
+            stride,
+            tail,
+        }
+    }
+
+    pub fn token_ids(&self) -> &[usize] {
+        &self.token_ids
+    }
+
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn tail(&self) -> TailPolicy {
+        self.tail
+    }
+
+    fn num_full_windows(&self) -> usize {
+        let n = self.token_ids.len();
+        if n <= self.max_length {
+            0
+        } else {
+            (n - self.max_length - 1) / self.stride + 1
+        }
+    }
+
+    fn has_tail_window(&self) -> bool {
+        // 至少还剩一个输入和一个标签
+        matches!(self.tail, TailPolicy::Pad(_))
+            && self.num_full_windows() * self.stride + 1 < self.token_ids.len()
+    }
+}
+
+impl Dataset for GPTDataset {
+    type Item = TrainData<usize>;
+
+    fn len(&self) -> usize {
+        self.num_full_windows() + self.has_tail_window() as usize
+    }
+
+    fn get(&self, index: usize) -> TrainData<usize> {
+        assert!(index < self.len(), "Index {index} out of range");
+
+        let start = index * self.stride;
+        let end = (start + self.max_length).min(self.token_ids.len() - 1);
+
+        let mut feature = self.token_ids[start..end].to_vec();
+        let mut label = self.token_ids[start + 1..end + 1].to_vec();
+
+        if let TailPolicy::Pad(pad_id) = self.tail {
+            feature.resize(self.max_length, pad_id);
+            label.resize(self.max_length, pad_id);
+        }
+
+        TrainData { feature, label }
+    }
+
+    fn dropped_tokens(&self) -> usize {
+        dropped_tokens(
+            self.token_ids.len(),
+            self.max_length,
+            self.stride,
+            self.len(),
+        )
+    }
+}
+
+// `num_windows`个滑动窗口没有覆盖到的token数，包括被丢弃的末尾以及`stride > max_length + 1`时
+// 窗口之间跳过的token。第i个窗口覆盖`[i * stride, i * stride + max_length + 1)`，最后一个截断到末尾
+pub(crate) fn dropped_tokens(
+    num_tokens: usize,
+    max_length: usize,
+    stride: usize,
+    num_windows: usize,
+) -> usize {
+    if num_windows == 0 {
+        return num_tokens;
+    }
+    let last = (num_windows - 1) * stride;
+    let covered =
+        (num_windows - 1) * stride.min(max_length + 1) + (max_length + 1).min(num_tokens - last);
+    num_tokens - covered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpt_dataset() {
+        let token_ids: Vec<usize> = (0..10).collect();
+
+        let dataset = GPTDataset::new(token_ids.clone(), 4, 4);
+        assert_eq!(dataset.len(), 2);
+        assert_eq!(dataset.dropped_tokens(), 1);
+        assert_eq!(dataset.get(1).feature, [4, 5, 6, 7]);
+        assert_eq!(dataset.get(1).label, [5, 6, 7, 8]);
+
+        let dataset = GPTDataset::with_tail(token_ids.clone(), 4, 4, TailPolicy::Pad(99));
+        assert_eq!(dataset.len(), 3);
+        assert_eq!(dataset.dropped_tokens(), 0);
+        assert_eq!(GPTDataset::new(token_ids.clone(), 2, 4).dropped_tokens(), 4);
+        assert_eq!(dataset.get(2).feature, [8, 99, 99, 99]);
+        assert_eq!(dataset.get(2).label, [9, 99, 99, 99]);
+
+        for i in 0..dataset.len() {
+            println!("{:?}", dataset.get(i));
+        }
+
+        // 最后一个完整窗口正好用完所有token时不会产生填充窗口
+        let dataset = GPTDataset::with_tail((0..9).collect(), 4, 4, TailPolicy::Pad(99));
+        assert_eq!(dataset.len(), 2);
+
+        assert_eq!(GPTDataset::new(vec![1, 2, 3], 4, 1).len(), 0);
+        assert_eq!(
+            GPTDataset::with_tail(vec![1, 2, 3], 4, 1, TailPolicy::Pad(0)).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_dropped_tokens() {
+        // 与逐个标记覆盖的token比较
+        for n in 0..30 {
+            for max_length in 1..6 {
+                for stride in 1..9 {
+                    for tail in [TailPolicy::Drop, TailPolicy::Pad(0)] {
+                        let dataset =
+                            GPTDataset::with_tail((0..n).collect(), max_length, stride, tail);
+                        let mut covered = vec![false; n];
+                        for index in 0..dataset.len() {
+                            let start = index * stride;
+                            let end = (start + max_length + 1).min(n);
+                            covered[start..end].iter_mut().for_each(|c| *c = true);
+                        }
+                        let expected = covered.iter().filter(|&&c| !c).count();
+                        assert_eq!(
+                            dataset.dropped_tokens(),
+                            expected,
+                            "{n} {max_length} {stride}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,118 +1,791 @@
-use crossbeam::channel::unbounded;
+mod builder;
+mod cache;
+mod collate;
+mod dataset;
+mod error;
+mod gpt;
+mod mmap;
+mod sampler;
+mod seed;
+mod stats;
+mod watchdog;
+
+pub use builder::DataLoaderBuilder;
+pub use cache::content_hash;
+pub use collate::{Collate, PadCollator, PaddedBatch, StackCollator, StackedBatch};
+pub use dataset::{
+    ConcatDataset, Dataset, EagerFilter, Filter, IterableDataset, Map, Subset, VecDataset,
+};
+pub use error::DataLoaderError;
+pub use gpt::{GPTDataset, TailPolicy};
+pub use mmap::{write_token_file, MmapTokenDataset, TokenFileWriter};
+pub use sampler::{batch_indices, BucketSampler, DistributedSampler};
+pub use seed::{seeded_rng, set_seed, worker_rng};
+pub use stats::{LoaderState, LoaderStats};
+pub use watchdog::{StallAction, WorkerStatus};
+
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
 use rand::seq::SliceRandom;
-use std::collections::VecDeque;
+use stats::Progress;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Debug;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use tracing::Span;
+use watchdog::WorkerState;
+
+type Hook = Box<dyn Fn(&LoaderStats) + Send + Sync>;
+
+// 根据epoch生成该epoch的下标批次
+pub(crate) type EpochPlan = Box<dyn Fn(u64) -> Vec<Vec<usize>> + Send + Sync>;
+
+// (批次在当前epoch中的位置, 样本下标)
+pub(crate) type Job = (usize, Vec<usize>);
 
-pub struct Dataset<T> {
-    data: Vec<T>,
+type Work<B> = Arc<dyn Fn(&WorkerOutput<B>) + Send + Sync>;
+
+pub struct DataLoader<B> {
+    workers: Mutex<Vec<Worker>>,
+    receiver: Receiver<WorkerMessage<B>>,
+    stop: Arc<AtomicBool>,
+    // 用于提交后续epoch的批次，以及重新提交卡住的批次
+    jobs: Option<Sender<Job>>,
+    // 已提交但尚未发送给工作线程的批次，在下一次调用`iter`时发送
+    pending: Mutex<Vec<Job>>,
+    // 可迭代数据集尚未处理的分片
+    shards: Option<Arc<Mutex<VecDeque<usize>>>>,
+    plan: Option<EpochPlan>,
+    // 用于启动替换的工作线程。可迭代数据集为`None`，使工作线程全部退出后通道断开
+    respawn: Option<(Sender<WorkerMessage<B>>, Work<B>)>,
+    watchdog: Option<(Duration, StallAction)>,
+    restarts: AtomicUsize,
+    seed: Option<u64>,
+    // 按批次位置暂存提前到达的批次，使map式数据集按顺序返回
+    reorder: Mutex<BTreeMap<usize, (B, BatchInfo)>>,
+    progress: Mutex<Progress>,
+    // map式数据集的`Dataset::dropped_tokens`，可迭代数据集为0
+    dropped_tokens: usize,
+    // 最近一次调用`iter`时所在的span，工作线程在其下加载批次
+    span: Arc<Mutex<Span>>,
+    batch_hooks: Vec<Hook>,
+    epoch_end_hooks: Vec<Hook>,
 }
 
-impl<T> Dataset<T> {
-    pub fn new(data: Vec<T>) -> Self {
-        Dataset { data }
+impl DataLoader<()> {
+    pub fn builder() -> DataLoaderBuilder {
+        DataLoaderBuilder::new()
     }
+}
 
-    pub fn len(&self) -> usize {
-        self.data.len()
+impl<T: Send + 'static> DataLoader<Vec<T>> {
+    pub fn new<D>(
+        dataset: D,
+        batch_size: usize,
+        shuffle: bool,
+        num_workers: usize,
+        drop_last: bool,
+    ) -> Self
+    where
+        D: Dataset<Item = T> + 'static,
+    {
+        let batches = batch_indices(dataset.len(), batch_size, shuffle, drop_last);
+        Self::from_batches(dataset, batches, num_workers)
     }
 
-    pub fn get(&self, index: usize) -> &T {
-        &self.data[index]
+    // 按给定的下标批次加载数据，批次的划分由调用者（如`BucketSampler`）决定
+    pub fn from_batches<D>(dataset: D, batches: Vec<Vec<usize>>, num_workers: usize) -> Self
+    where
+        D: Dataset<Item = T> + 'static,
+    {
+        Self::from_batches_with(dataset, batches, num_workers, |batch: Vec<T>| batch)
     }
-}
 
-pub struct DataLoader<T> {
-    worker_handles: Vec<thread::JoinHandle<()>>,
-    receiver: crossbeam::channel::Receiver<Vec<T>>,
+    pub fn from_iterable<D>(
+        dataset: D,
+        batch_size: usize,
+        shuffle: bool,
+        num_workers: usize,
+        drop_last: bool,
+    ) -> Self
+    where
+        D: IterableDataset<Item = T> + 'static,
+    {
+        Self::from_iterable_with(
+            dataset,
+            batch_size,
+            shuffle,
+            num_workers,
+            drop_last,
+            |batch: Vec<T>| batch,
+        )
+    }
 }
 
-impl<T: Send + Sync + Clone + 'static> DataLoader<T> {
-    pub fn new(
-        dataset: Dataset<T>,
+impl<B: Send + 'static> DataLoader<B> {
+    // 与`from_batches`相同，但在工作线程中用`collate`把样本合并为批次
+    pub fn from_batches_with<D, C>(
+        dataset: D,
+        batches: Vec<Vec<usize>>,
+        num_workers: usize,
+        collate: C,
+    ) -> Self
+    where
+        D: Dataset + 'static,
+        C: Collate<D::Item, Output = B> + 'static,
+    {
+        let loader = Self::spawn_batches(dataset, num_workers, collate);
+        loader.submit(0, batches);
+        loader
+    }
+
+    fn spawn_batches<D, C>(dataset: D, num_workers: usize, collate: C) -> Self
+    where
+        D: Dataset + 'static,
+        C: Collate<D::Item, Output = B> + 'static,
+    {
+        let (job_sender, jobs) = unbounded::<Job>();
+        let dropped_tokens = dataset.dropped_tokens();
+
+        let mut loader = Self::spawn(num_workers, move |output| {
+            while !output.is_stopped() {
+                let (position, batch_indices) = match jobs.recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                let _span =
+                    tracing::debug_span!(parent: &output.span(), "batch", position).entered();
+
+                output.begin((position, batch_indices.clone()));
+                let mut filtered = 0;
+                let batch: Result<Vec<D::Item>, _> = batch_indices
+                    .into_iter()
+                    .filter_map(|i| {
+                        output.state.set_last_index(i);
+                        dataset.try_get_filtered(i).transpose().or_else(|| {
+                            filtered += 1;
+                            None
+                        })
+                    })
+                    .collect();
+
+                let sent = match batch {
+                    Ok(batch) => output.send(&collate, batch, filtered),
+                    Err(e) => output.fail(e),
+                };
+                if !sent {
+                    break;
+                }
+            }
+        });
+
+        loader.jobs = Some(job_sender);
+        loader.dropped_tokens = dropped_tokens;
+        loader
+    }
+
+    fn submit(&self, epoch: u64, batches: Vec<Vec<usize>>) {
+        self.submit_from(epoch, batches, 0);
+    }
+
+    // 跳过前`consumed`个样本所在的批次，用于从`LoaderState`恢复
+    fn submit_from(&self, epoch: u64, batches: Vec<Vec<usize>>, consumed: usize) {
+        let mut skipped = 0;
+        let batches: Vec<Vec<usize>> = batches
+            .into_iter()
+            .skip_while(|batch| {
+                let skip = skipped + batch.len() <= consumed;
+                if skip {
+                    skipped += batch.len();
+                }
+                skip
+            })
+            .collect();
+
+        tracing::debug!(
+            "epoch {epoch}: submitting {} batches, skipped {skipped} samples",
+            batches.len()
+        );
+        let mut progress = Progress::new(epoch, Some(batches.len()));
+        progress.skipped = skipped;
+        progress.dropped_tokens = self.dropped_tokens;
+        *self.progress.lock().unwrap() = progress;
+        self.reorder.lock().unwrap().clear();
+
+        assert!(self.jobs.is_some(), "DataLoader can not accept new batches");
+        *self.pending.lock().unwrap() = batches.into_iter().enumerate().collect();
+    }
+
+    // 在调用`iter`的span下开始加载，工作线程的日志带上训练循环中的`epoch`等
+    fn send_pending(&self) {
+        let Some(jobs) = self.jobs.as_ref() else {
+            return;
+        };
+        for job in self.pending.lock().unwrap().drain(..) {
+            // 工作线程都已退出时发送失败，迭代时会因通道断开而结束
+            let _ = jobs.send(job);
+        }
+    }
+
+    pub fn from_iterable_with<D, C>(
+        dataset: D,
         batch_size: usize,
         shuffle: bool,
         num_workers: usize,
         drop_last: bool,
-    ) -> Self {
-        let (sender, receiver) = unbounded();
-        let dataset = Arc::new(dataset);
-        let mut indices: Vec<usize> = (0..dataset.len()).collect();
+        collate: C,
+    ) -> Self
+    where
+        D: IterableDataset + 'static,
+        C: Collate<D::Item, Output = B> + 'static,
+    {
+        let mut shards: Vec<usize> = (0..dataset.num_shards()).collect();
 
         if shuffle {
-            indices.shuffle(&mut rand::rng());
+            shards.shuffle(&mut seeded_rng());
         }
 
-        let indices = Arc::new(Mutex::new(VecDeque::from(indices)));
-        let mut worker_handles = Vec::new();
+        Self::from_shards_with(dataset, shards, batch_size, num_workers, drop_last, collate)
+    }
 
-        for _ in 0..num_workers {
-            let dataset = Arc::clone(&dataset);
-            let indices = Arc::clone(&indices);
-            let sender = sender.clone();
-
-            let handle = thread::spawn(move || loop {
-                let batch_indices: Vec<usize> = {
-                    let mut indices = indices.lock().unwrap();
-                    if indices.len() < batch_size {
-                        if drop_last || indices.is_empty() {
-                            break;
+    // 按给定的分片顺序加载可迭代数据集
+    pub(crate) fn from_shards_with<D, C>(
+        dataset: D,
+        shards: Vec<usize>,
+        batch_size: usize,
+        num_workers: usize,
+        drop_last: bool,
+        collate: C,
+    ) -> Self
+    where
+        D: IterableDataset + 'static,
+        C: Collate<D::Item, Output = B> + 'static,
+    {
+        let dataset = Arc::new(dataset);
+        let shards = Arc::new(Mutex::new(VecDeque::from(shards)));
+        let queue = Arc::clone(&shards);
+
+        let mut loader = Self::spawn(num_workers, move |output| {
+            let mut batch = Vec::with_capacity(batch_size);
+
+            while !output.is_stopped() {
+                let shard = match queue.lock().unwrap().pop_front() {
+                    Some(shard) => shard,
+                    None => break,
+                };
+                let _span = tracing::debug_span!(parent: &output.span(), "shard", shard).entered();
+                output.state.busy.store(true, Ordering::SeqCst);
+                output.state.set_last_index(shard);
+
+                for item in dataset.try_iter_shard(shard) {
+                    match item {
+                        Ok(item) => batch.push(item),
+                        Err(e) => {
+                            output.fail(e);
+                            return;
                         }
                     }
 
-                    let len = indices.len();
-                    indices.drain(0..std::cmp::min(batch_size, len)).collect()
-                };
+                    if batch.len() == batch_size {
+                        let full = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+                        if !output.send(&collate, full, 0) {
+                            return;
+                        }
+                    }
+                }
+                output.state.busy.store(false, Ordering::SeqCst);
+            }
 
-                if batch_indices.is_empty() {
-                    break;
+            // 每个工作线程各自处理最后不足一个批次的样本
+            if !batch.is_empty() && !drop_last {
+                output.send(&collate, batch, 0);
+            }
+        });
+
+        loader.shards = Some(shards);
+        loader.respawn = None;
+        loader
+    }
+
+    fn spawn<F>(num_workers: usize, work: F) -> Self
+    where
+        F: Fn(&WorkerOutput<B>) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = unbounded();
+        let work: Work<B> = Arc::new(work);
+
+        let loader = DataLoader {
+            workers: Mutex::new(vec![]),
+            receiver,
+            stop: Arc::new(AtomicBool::new(false)),
+            jobs: None,
+            pending: Mutex::new(vec![]),
+            shards: None,
+            plan: None,
+            respawn: Some((sender, work)),
+            watchdog: None,
+            restarts: AtomicUsize::new(0),
+            seed: None,
+            reorder: Mutex::new(BTreeMap::new()),
+            progress: Mutex::new(Progress::new(0, None)),
+            dropped_tokens: 0,
+            span: Arc::new(Mutex::new(Span::current())),
+            batch_hooks: vec![],
+            epoch_end_hooks: vec![],
+        };
+
+        for _ in 0..num_workers {
+            loader.spawn_worker();
+        }
+        loader
+    }
+
+    // 数据错误由`try_get`等返回，见`WorkerOutput::fail`。
+    // 工作线程中的panic也会被捕获并作为错误发送给迭代器，同时通知其余线程停止。
+    // release配置为`panic = "abort"`时无法捕获，只能作为调试时的后备
+    fn spawn_worker(&self) {
+        let (sender, work) = self
+            .respawn
+            .as_ref()
+            .expect("DataLoader can not spawn new workers");
+        let state = Arc::new(WorkerState::new());
+        let output = WorkerOutput {
+            sender: sender.clone(),
+            stop: Arc::clone(&self.stop),
+            state: Arc::clone(&state),
+            span: Arc::clone(&self.span),
+        };
+        let work = Arc::clone(work);
+
+        let mut workers = self.workers.lock().unwrap();
+        let worker = workers.len();
+        let span = tracing::debug_span!(parent: None, "worker", worker);
+
+        let handle = thread::spawn(move || {
+            let _span = span.entered();
+            tracing::debug!("worker {worker} started");
+            seed::enter_worker(worker);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| work(&output)));
+
+            if let Err(payload) = result {
+                output.stop.store(true, Ordering::SeqCst);
+
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                tracing::error!("worker {worker} panicked: {message}");
+
+                let _ = output
+                    .sender
+                    .send(Err(DataLoaderError::WorkerPanicked { worker, message }));
+            } else {
+                tracing::debug!("worker {worker} exited");
+            }
+        });
+
+        workers.push(Worker {
+            state,
+            handle: Some(handle),
+        });
+    }
+
+    // 超过`timeout`没有收到批次时按`action`处理，而不是一直等待
+    pub fn watchdog(&mut self, timeout: Duration, action: StallAction) {
+        self.watchdog = Some((timeout, action));
+    }
+
+    fn queue_depth(&self) -> usize {
+        match (self.jobs.as_ref(), self.shards.as_ref()) {
+            (Some(jobs), _) => jobs.len(),
+            (None, Some(shards)) => shards.lock().unwrap().len(),
+            (None, None) => 0,
+        }
+    }
+
+    fn recv(&self) -> Option<WorkerMessage<B>> {
+        let Some((timeout, action)) = self.watchdog else {
+            return self.receiver.recv().ok();
+        };
+
+        loop {
+            match self.receiver.recv_timeout(timeout) {
+                Ok(message) => return Some(message),
+                Err(RecvTimeoutError::Disconnected) => return None,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(e) = self.handle_stall(timeout, action) {
+                        return Some(Err(e));
+                    }
                 }
+            }
+        }
+    }
 
-                let batch: Vec<T> = batch_indices
-                    .into_iter()
-                    .map(|i| dataset.get(i).clone())
-                    .collect();
+    // map式数据集的批次按位置依次返回，与工作线程数无关
+    fn recv_in_order(&self) -> Option<WorkerMessage<B>> {
+        let next = self.progress.lock().unwrap().batches;
 
-                sender.send(batch).unwrap();
-            });
+        loop {
+            if let Some(ready) = self.reorder.lock().unwrap().remove(&next) {
+                return Some(Ok(ready));
+            }
 
-            worker_handles.push(handle);
+            match self.recv()? {
+                Ok((batch, info)) => match info.position {
+                    Some(position) if position != next => {
+                        self.reorder.lock().unwrap().insert(position, (batch, info));
+                    }
+                    _ => return Some(Ok((batch, info))),
+                },
+                Err(e) => return Some(Err(e)),
+            }
         }
+    }
 
-        drop(sender);
+    fn handle_stall(&self, timeout: Duration, action: StallAction) -> Result<(), DataLoaderError> {
+        let mut workers = self.workers.lock().unwrap();
 
-        DataLoader {
-            worker_handles,
-            receiver,
+        if let (StallAction::Restart { max_restarts }, Some(jobs)) = (action, self.jobs.as_ref())
+            && self.respawn.is_some()
+        {
+            let stalled: Vec<usize> = (0..workers.len())
+                .filter(|&i| workers[i].handle.is_some() && workers[i].state.job_stalled(timeout))
+                .collect();
+            let restarts = self.restarts.load(Ordering::SeqCst) + stalled.len();
+
+            if !stalled.is_empty() && restarts <= max_restarts {
+                tracing::warn!(
+                    "no batch for {timeout:?}, restarting stalled workers {stalled:?} ({restarts}/{max_restarts} restarts)"
+                );
+                for &i in stalled.iter() {
+                    if let Some(job) = workers[i].retire() {
+                        let _ = jobs.send(job);
+                    }
+                }
+                self.restarts.store(restarts, Ordering::SeqCst);
+                drop(workers);
+
+                for _ in stalled {
+                    self.spawn_worker();
+                }
+                return Ok(());
+            }
         }
+
+        let error = DataLoaderError::Stalled {
+            timeout,
+            queue_depth: self.queue_depth(),
+            workers: workers
+                .iter()
+                .enumerate()
+                .filter(|(_, worker)| worker.handle.is_some())
+                .map(|(i, worker)| worker.state.status(i))
+                .collect(),
+        };
+
+        // 卡住的线程可能永远不会结束，释放时不再等待
+        self.stop.store(true, Ordering::SeqCst);
+        for worker in workers.iter_mut() {
+            if worker.state.busy.load(Ordering::SeqCst) {
+                worker.retire();
+            }
+        }
+
+        Err(error)
     }
 
-    pub fn iter(&self) -> DataLoaderIter<T> {
+    // 持久化模式下若当前epoch已结束，则开始下一个epoch
+    // map式数据集的批次在第一次调用时才开始加载。可迭代数据集创建时即开始加载，
+    // 此后才开始处理的分片在当前span下
+    pub fn iter(&self) -> DataLoaderIter<'_, B> {
+        self.start_epoch();
         DataLoaderIter {
-            receiver: &self.receiver,
+            loader: LoaderRef::Borrowed(self),
         }
     }
+
+    // 与`iter`相同，但持有`Arc`而不是借用，可以保存在需要`'static`的地方，如Python的迭代器
+    pub fn iter_shared(self: &Arc<Self>) -> DataLoaderIter<'static, B> {
+        self.start_epoch();
+        DataLoaderIter {
+            loader: LoaderRef::Shared(Arc::clone(self)),
+        }
+    }
+
+    fn start_epoch(&self) {
+        *self.span.lock().unwrap() = Span::current();
+        if let Some(plan) = self.plan.as_ref() {
+            let next_epoch = {
+                let progress = self.progress.lock().unwrap();
+                (progress.finished && !progress.failed).then_some(progress.epoch + 1)
+            };
+
+            if let Some(epoch) = next_epoch {
+                self.submit(epoch, plan(epoch));
+            }
+        }
+        self.send_pending();
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.progress.lock().unwrap().epoch
+    }
+
+    // 用于保存检查点，之后通过`DataLoaderBuilder::resume`从该位置继续。
+    // 当前epoch已结束时返回下一个epoch的起点
+    pub fn state(&self) -> LoaderState {
+        let progress = self.progress.lock().unwrap();
+
+        if progress.finished {
+            LoaderState {
+                epoch: progress.epoch + 1,
+                seed: self.seed,
+                consumed: 0,
+            }
+        } else {
+            LoaderState {
+                epoch: progress.epoch,
+                seed: self.seed,
+                consumed: progress.skipped + progress.samples + progress.filtered,
+            }
+        }
+    }
+
+    // 当前epoch的统计
+    pub fn stats(&self) -> LoaderStats {
+        self.progress.lock().unwrap().stats()
+    }
+
+    // 钩子在迭代数据的线程中调用，每返回一个批次调用一次
+    pub fn on_batch(&mut self, hook: impl Fn(&LoaderStats) + Send + Sync + 'static) {
+        self.batch_hooks.push(Box::new(hook));
+    }
+
+    // 每个epoch的批次全部返回后调用一次，出现错误时不会调用
+    pub fn on_epoch_end(&mut self, hook: impl Fn(&LoaderStats) + Send + Sync + 'static) {
+        self.epoch_end_hooks.push(Box::new(hook));
+    }
+
+    // 全部样本被去掉的批次也计入`batches`以判断epoch是否结束，但不调用回调
+    fn record_batch(&self, info: BatchInfo) {
+        let empty = info.samples == 0 && info.filtered > 0;
+        let stats = {
+            let mut progress = self.progress.lock().unwrap();
+            progress.batches += 1;
+            progress.samples += info.samples;
+            progress.filtered += info.filtered;
+
+            if let Some((real, padding)) = info.token_counts {
+                progress.real_tokens += real;
+                progress.padding_tokens += padding;
+            }
+            progress.stats()
+        };
+        if empty {
+            return;
+        }
+
+        for hook in self.batch_hooks.iter() {
+            hook(&stats);
+        }
+    }
+
+    fn record_epoch_end(&self) {
+        let stats = {
+            let mut progress = self.progress.lock().unwrap();
+            if progress.finished || progress.failed {
+                return;
+            }
+            progress.finished = true;
+            progress.stats()
+        };
+        tracing::debug!(
+            "epoch {} finished: {} batches, {} samples, {} dropped tokens in {:.3?}",
+            stats.epoch,
+            stats.batches,
+            stats.samples,
+            stats.dropped_tokens,
+            stats.elapsed
+        );
+
+        for hook in self.epoch_end_hooks.iter() {
+            hook(&stats);
+        }
+    }
+
+    // 已知批次数时，收齐所有批次即结束当前epoch
+    fn epoch_done(&self) -> bool {
+        let progress = self.progress.lock().unwrap();
+        progress.finished || progress.failed || progress.expected == Some(progress.batches)
+    }
 }
 
-impl<T> Drop for DataLoader<T> {
+impl<B> Drop for DataLoader<B> {
     fn drop(&mut self) {
-        // 确保所有工作线程完成
-        for handle in self.worker_handles.drain(..) {
-            handle.join().unwrap();
+        // 提前结束时通知工作线程停止，并确保所有工作线程完成
+        self.stop.store(true, Ordering::SeqCst);
+        self.jobs = None;
+        self.respawn = None;
+
+        for worker in self.workers.get_mut().unwrap().drain(..) {
+            if let Some(handle) = worker.handle {
+                let _ = handle.join();
+            }
         }
     }
 }
 
-pub struct DataLoaderIter<'a, T> {
-    receiver: &'a crossbeam::channel::Receiver<Vec<T>>,
+struct Worker {
+    state: Arc<WorkerState>,
+    // 被替换的线程不再等待其结束
+    handle: Option<thread::JoinHandle<()>>,
 }
 
-impl<'a, T> Iterator for DataLoaderIter<'a, T> {
-    type Item = Vec<T>;
+impl Worker {
+    // 返回尚未发送的批次
+    fn retire(&mut self) -> Option<Job> {
+        self.handle = None;
+        let mut job = self.state.job.lock().unwrap();
+        self.state.retired.store(true, Ordering::SeqCst);
+        job.take().map(|(job, _)| job)
+    }
+}
+
+struct BatchInfo {
+    samples: usize,
+    // 被`Dataset::filter`去掉的样本数
+    filtered: usize,
+    token_counts: Option<(usize, usize)>,
+    // 可迭代数据集的批次没有位置，按到达顺序返回
+    position: Option<usize>,
+}
+
+type WorkerMessage<B> = Result<(B, BatchInfo), DataLoaderError>;
+
+struct WorkerOutput<B> {
+    sender: Sender<WorkerMessage<B>>,
+    stop: Arc<AtomicBool>,
+    state: Arc<WorkerState>,
+    span: Arc<Mutex<Span>>,
+}
+
+impl<B> WorkerOutput<B> {
+    fn span(&self) -> Span {
+        self.span.lock().unwrap().clone()
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst) || self.state.retired.load(Ordering::SeqCst)
+    }
+
+    fn begin(&self, job: Job) {
+        *self.state.job.lock().unwrap() = Some((job, Instant::now()));
+        self.state.busy.store(true, Ordering::SeqCst);
+    }
+
+    // 返回`false`表示应停止生成批次。`filtered`为该批次中被`filter`去掉的样本数
+    fn send<T, C>(&self, collate: &C, batch: Vec<T>, filtered: usize) -> bool
+    where
+        C: Collate<T, Output = B>,
+    {
+        if self.is_stopped() {
+            return false;
+        }
+
+        let samples = batch.len();
+        let batch = match collate.try_collate(batch) {
+            Ok(batch) => batch,
+            Err(e) => return self.fail(e),
+        };
+        let mut info = BatchInfo {
+            samples,
+            filtered,
+            token_counts: collate.token_counts(&batch),
+            position: None,
+        };
+
+        // 持有锁发送，保证被替换的线程不会与重新提交的批次重复发送
+        let mut job = self.state.job.lock().unwrap();
+        if self.state.retired.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        if let Some(((position, _), _)) = job.take() {
+            info.position = Some(position);
+            self.state.busy.store(false, Ordering::SeqCst);
+        }
+        let sent = self.sender.send(Ok((batch, info))).is_ok();
+        self.state.batches.fetch_add(1, Ordering::SeqCst);
+        sent
+    }
+
+    // 把数据错误发送给迭代器并通知其余线程停止，总是返回`false`
+    fn fail(&self, error: DataLoaderError) -> bool {
+        if self.is_stopped() {
+            return false;
+        }
+        self.stop.store(true, Ordering::SeqCst);
+        tracing::error!("{error}");
+
+        self.state.job.lock().unwrap().take();
+        self.state.busy.store(false, Ordering::SeqCst);
+        let _ = self.sender.send(Err(error));
+        false
+    }
+}
 
+pub struct DataLoaderIter<'a, B> {
+    loader: LoaderRef<'a, B>,
+}
+
+enum LoaderRef<'a, B> {
+    Borrowed(&'a DataLoader<B>),
+    Shared(Arc<DataLoader<B>>),
+}
+
+impl<B> std::ops::Deref for LoaderRef<'_, B> {
+    type Target = DataLoader<B>;
+
+    fn deref(&self) -> &DataLoader<B> {
+        match self {
+            LoaderRef::Borrowed(loader) => loader,
+            LoaderRef::Shared(loader) => loader,
+        }
+    }
+}
+
+impl<'a, B: Send + 'static> Iterator for DataLoaderIter<'a, B> {
+    type Item = Result<B, DataLoaderError>;
+
+    // 出现错误后不再返回后续批次。样本全部被`filter`去掉的批次不返回
     fn next(&mut self) -> Option<Self::Item> {
-        self.receiver.recv().ok()
+        loop {
+            if self.loader.epoch_done() {
+                self.loader.record_epoch_end();
+                return None;
+            }
+
+            match self.loader.recv_in_order() {
+                Some(Ok((batch, info))) => {
+                    let empty = info.samples == 0 && info.filtered > 0;
+                    self.loader.record_batch(info);
+                    if !empty {
+                        return Some(Ok(batch));
+                    }
+                }
+                Some(Err(e)) => {
+                    self.loader.progress.lock().unwrap().failed = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    self.loader.record_epoch_end();
+                    return None;
+                }
+            }
+        }
     }
 }
 
@@ -122,6 +795,7 @@ pub struct TrainData<T: Clone + Debug> {
     pub label: Vec<T>,
 }
 
+#[deprecated(note = "use `GPTDataset` instead")]
 pub fn gen_rnn_train_data<T>(items: &[T], batch_size: usize, stride: usize) -> Vec<TrainData<T>>
 where
     T: Clone + Debug,
@@ -135,8 +809,8 @@ where
             break;
         }
 
-        let feature: Vec<T> = items[start_pos..end_pos].iter().cloned().collect();
-        let label: Vec<T> = items[start_pos + 1..end_pos + 1].iter().cloned().collect();
+        let feature: Vec<T> = items[start_pos..end_pos].to_vec();
+        let label: Vec<T> = items[start_pos + 1..end_pos + 1].to_vec();
         train_data.push(TrainData { feature, label });
 
         start_pos += stride;
@@ -153,10 +827,11 @@ mod tests {
     #[test]
     fn test_dataloader_nodroplast() {
         let data: Vec<i32> = (0..25).collect();
-        let dataset = Dataset::new(data);
+        let dataset = VecDataset::new(data);
         let loader = DataLoader::new(dataset, 10, true, 4, false);
 
         for (i, batch) in loader.iter().enumerate() {
+            let batch = batch.unwrap();
             println!("Batch {}: {:?}", i, batch);
         }
 
@@ -166,16 +841,270 @@ mod tests {
     #[test]
     fn test_dataloader_droplast() {
         let data: Vec<i32> = (0..25).collect();
-        let dataset = Dataset::new(data);
+        let dataset = VecDataset::new(data);
         let loader = DataLoader::new(dataset, 10, true, 4, true);
 
         for (i, batch) in loader.iter().enumerate() {
+            let batch = batch.unwrap();
             println!("Batch {}: {:?}", i, batch);
         }
         println!("\n");
     }
 
+    struct RangeShards {
+        shards: usize,
+        shard_len: usize,
+    }
+
+    impl IterableDataset for RangeShards {
+        type Item = usize;
+
+        fn num_shards(&self) -> usize {
+            self.shards
+        }
+
+        fn iter_shard(&self, shard: usize) -> Box<dyn Iterator<Item = usize> + '_> {
+            let start = shard * self.shard_len;
+            Box::new(start..start + self.shard_len)
+        }
+    }
+
     #[test]
+    fn test_dataloader_iterable() {
+        let dataset = RangeShards {
+            shards: 5,
+            shard_len: 7,
+        };
+        let loader = DataLoader::from_iterable(dataset, 4, true, 3, false);
+
+        let mut items = vec![];
+        for (i, batch) in loader.iter().enumerate() {
+            let batch = batch.unwrap();
+            println!("Batch {}: {:?}", i, batch);
+            assert!(batch.len() <= 4);
+            items.extend(batch);
+        }
+        println!("\n");
+
+        items.sort();
+        assert_eq!(items, (0..35).collect::<Vec<_>>());
+    }
+
+    struct PanicDataset;
+
+    impl Dataset for PanicDataset {
+        type Item = usize;
+
+        fn len(&self) -> usize {
+            100
+        }
+
+        fn get(&self, index: usize) -> usize {
+            if index == 42 {
+                panic!("bad sample {index}");
+            }
+            index
+        }
+    }
+
+    #[test]
+    fn test_dataloader_worker_panic() {
+        let loader = DataLoader::new(PanicDataset, 5, false, 3, false);
+
+        let results: Vec<_> = loader.iter().collect();
+        let error = results.last().unwrap().as_ref().unwrap_err();
+        println!("{error}");
+
+        assert!(results[..results.len() - 1].iter().all(|r| r.is_ok()));
+        assert!(matches!(
+            error,
+            DataLoaderError::WorkerPanicked { message, .. } if message == "bad sample 42"
+        ));
+    }
+
+    struct FailingDataset;
+
+    impl Dataset for FailingDataset {
+        type Item = usize;
+
+        fn len(&self) -> usize {
+            100
+        }
+
+        fn get(&self, index: usize) -> usize {
+            self.try_get(index).unwrap()
+        }
+
+        fn try_get(&self, index: usize) -> Result<usize, DataLoaderError> {
+            if index == 42 {
+                return Err(DataLoaderError::sample(format!("bad sample {index}")));
+            }
+            Ok(index)
+        }
+    }
+
+    struct FailingShards;
+
+    impl IterableDataset for FailingShards {
+        type Item = usize;
+
+        fn num_shards(&self) -> usize {
+            3
+        }
+
+        fn iter_shard(&self, _shard: usize) -> Box<dyn Iterator<Item = usize> + '_> {
+            unreachable!("DataLoader only calls try_iter_shard")
+        }
+
+        fn try_iter_shard(
+            &self,
+            shard: usize,
+        ) -> Box<dyn Iterator<Item = Result<usize, DataLoaderError>> + '_> {
+            let error = std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid utf-8");
+            match shard {
+                1 => Box::new(std::iter::once(Err(DataLoaderError::sample(error)))),
+                _ => Box::new((0..4).map(Ok)),
+            }
+        }
+    }
+
+    // 数据错误不经过panic，`panic = "abort"`时也能交给迭代器
+    #[test]
+    fn test_dataloader_sample_error() {
+        let loader = DataLoader::new(FailingDataset, 5, false, 3, false);
+        let results: Vec<_> = loader.iter().collect();
+        let error = results.last().unwrap().as_ref().unwrap_err();
+        println!("{error}");
+        assert_eq!(results.len(), 9);
+        assert!(results[..8].iter().all(|r| r.is_ok()));
+        assert!(matches!(error, DataLoaderError::Sample(e) if e.to_string() == "bad sample 42"));
+
+        let loader = DataLoader::from_iterable(FailingShards, 2, false, 1, false);
+        let results: Vec<_> = loader.iter().collect();
+        println!("{:?}", results.last());
+        assert!(matches!(
+            results.last(),
+            Some(Err(DataLoaderError::Sample(_)))
+        ));
+
+        struct FailingCollate;
+        impl Collate<usize> for FailingCollate {
+            type Output = Vec<usize>;
+
+            fn collate(&self, samples: Vec<usize>) -> Vec<usize> {
+                samples
+            }
+
+            fn try_collate(&self, samples: Vec<usize>) -> Result<Vec<usize>, DataLoaderError> {
+                match samples.contains(&7) {
+                    true => Err(DataLoaderError::sample("bad batch")),
+                    false => Ok(samples),
+                }
+            }
+        }
+        let loader = DataLoader::from_batches_with(
+            VecDataset::new((0..10).collect::<Vec<usize>>()),
+            vec![vec![0, 1], vec![6, 7], vec![8, 9]],
+            1,
+            FailingCollate,
+        );
+        let results: Vec<_> = loader.iter().collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok() && results[1].is_err());
+    }
+
+    #[test]
+    fn test_dataloader_hooks() {
+        use std::sync::atomic::AtomicUsize;
+
+        let batches = Arc::new(AtomicUsize::new(0));
+        let epochs = Arc::new(AtomicUsize::new(0));
+
+        let dataset = VecDataset::new((0..25).collect::<Vec<i32>>());
+        let mut loader = DataLoader::new(dataset, 10, true, 2, false);
+
+        let counter = Arc::clone(&batches);
+        loader.on_batch(move |stats| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            println!("{:?}, {:.1} samples/s", stats, stats.samples_per_sec());
+        });
+
+        let counter = Arc::clone(&epochs);
+        loader.on_epoch_end(move |stats| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            assert_eq!((stats.batches, stats.samples), (3, 25));
+        });
+
+        for batch in loader.iter() {
+            batch.unwrap();
+        }
+        assert_eq!(loader.iter().count(), 0);
+
+        assert_eq!(batches.load(Ordering::SeqCst), 3);
+        assert_eq!(epochs.load(Ordering::SeqCst), 1);
+        assert_eq!(loader.stats().samples, 25);
+    }
+
+    // 第一次读取下标7时卡住
+    struct StallDataset {
+        stalled: AtomicBool,
+        always: bool,
+    }
+
+    impl Dataset for StallDataset {
+        type Item = usize;
+
+        fn len(&self) -> usize {
+            20
+        }
+
+        fn get(&self, index: usize) -> usize {
+            if index == 7 && (self.always || !self.stalled.swap(true, Ordering::SeqCst)) {
+                thread::sleep(Duration::from_secs(2));
+            } else {
+                // 超时时另一个线程也在处理批次，但未超时，不应被重启
+                thread::sleep(Duration::from_millis(30));
+            }
+            index
+        }
+    }
+
+    #[test]
+    fn test_dataloader_watchdog() {
+        let dataset = StallDataset {
+            stalled: AtomicBool::new(false),
+            always: false,
+        };
+        let mut loader = DataLoader::new(dataset, 4, false, 2, false);
+        loader.watchdog(
+            Duration::from_millis(200),
+            StallAction::Restart { max_restarts: 1 },
+        );
+
+        let mut items: Vec<usize> = loader.iter().flat_map(Result::unwrap).collect();
+        items.sort();
+        assert_eq!(items, (0..20).collect::<Vec<_>>());
+        assert_eq!(loader.restarts.load(Ordering::SeqCst), 1);
+
+        let dataset = StallDataset {
+            stalled: AtomicBool::new(false),
+            always: true,
+        };
+        let mut loader = DataLoader::new(dataset, 4, false, 2, false);
+        loader.watchdog(Duration::from_millis(200), StallAction::Error);
+
+        let error = loader.iter().find_map(Result::err).unwrap();
+        println!("{error}");
+        match error {
+            DataLoaderError::Stalled { workers, .. } => {
+                assert!(workers.iter().any(|w| w.busy && w.last_index == Some(7)));
+            }
+            _ => panic!("unexpected error: {error}"),
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
     fn test_gen_rnn_train_data() {
         let data: Vec<usize> = (0..26).collect();
 
@@ -186,4 +1115,59 @@ mod tests {
         }
         println!("\n");
     }
+
+    #[test]
+    fn test_dataloader_span() {
+        use tracing_subscriber::registry::{LookupSpan, Registry};
+
+        // 当前span及其所有父span的名称，从外到内
+        fn scope() -> Vec<&'static str> {
+            let Some(id) = Span::current().id() else {
+                return vec![];
+            };
+            tracing::dispatcher::get_default(|dispatch| {
+                let registry = dispatch.downcast_ref::<Registry>().unwrap();
+                let mut names: Vec<_> = registry
+                    .span(&id)
+                    .unwrap()
+                    .scope()
+                    .map(|span| span.name())
+                    .collect();
+                names.reverse();
+                names
+            })
+        }
+
+        tracing::subscriber::set_global_default(Registry::default()).unwrap();
+        let scopes = Arc::new(Mutex::new(vec![]));
+        let recorded = Arc::clone(&scopes);
+        let dataset = VecDataset::new((0..16).collect::<Vec<i32>>()).map(move |x| {
+            recorded.lock().unwrap().push(scope());
+            x
+        });
+        let loader = tracing::info_span!("build").in_scope(|| {
+            DataLoader::builder()
+                .batch_size(2)
+                .num_workers(2)
+                .persistent_workers(true)
+                .build(dataset)
+        });
+
+        for epoch in 0..2 {
+            let _span = tracing::info_span!("epoch", epoch).entered();
+            assert_eq!(
+                loader
+                    .iter()
+                    .map(|batch| batch.unwrap().len())
+                    .sum::<usize>(),
+                16
+            );
+        }
+
+        let scopes = scopes.lock().unwrap();
+        println!("{:?}", scopes);
+        assert_eq!(scopes.len(), 32);
+        // 批次在调用`iter`时才开始加载，而不是在`build`下
+        assert!(scopes.iter().all(|scope| *scope == ["epoch", "batch"]));
+    }
 }
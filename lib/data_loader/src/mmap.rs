@@ -0,0 +1,160 @@
+use crate::{gpt, DataLoaderError, Dataset, TrainData};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const TOKEN_BYTES: usize = std::mem::size_of::<u32>();
+
+// 预先分词好的二进制语料：小端序`u32`的token id依次排列
+pub struct MmapTokenDataset {
+    mmap: Mmap,
+    max_length: usize,
+    stride: usize,
+}
+
+impl MmapTokenDataset {
+    pub fn open(
+        path: impl AsRef<Path>,
+        max_length: usize,
+        stride: usize,
+    ) -> Result<Self, DataLoaderError> {
+        if max_length == 0 || stride == 0 {
+            return Err(DataLoaderError::InvalidWindow { max_length, stride });
+        }
+
+        let file = File::open(path)?;
+
+        // SAFETY: 文件以只读方式映射，使用期间不应被其他进程修改
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        if mmap.len() % TOKEN_BYTES != 0 {
+            return Err(DataLoaderError::InvalidTokenFile(mmap.len()));
+        }
+
+        Ok(MmapTokenDataset {
+            mmap,
+            max_length,
+            stride,
+        })
+    }
+
+    pub fn num_tokens(&self) -> usize {
+        self.mmap.len() / TOKEN_BYTES
+    }
+
+    pub fn token(&self, index: usize) -> u32 {
+        let start = index * TOKEN_BYTES;
+        let bytes = self.mmap[start..start + TOKEN_BYTES].try_into().unwrap();
+        u32::from_le_bytes(bytes)
+    }
+
+    fn tokens(&self, start: usize, len: usize) -> Vec<usize> {
+        (start..start + len)
+            .map(|i| self.token(i) as usize)
+            .collect()
+    }
+}
+
+impl Dataset for MmapTokenDataset {
+    type Item = TrainData<usize>;
+
+    // 与`GPTDataset`的窗口划分一致
+    fn len(&self) -> usize {
+        let num_tokens = self.num_tokens();
+        if num_tokens <= self.max_length {
+            0
+        } else {
+            (num_tokens - self.max_length - 1) / self.stride + 1
+        }
+    }
+
+    fn get(&self, index: usize) -> TrainData<usize> {
+        let start = index * self.stride;
+
+        TrainData {
+            feature: self.tokens(start, self.max_length),
+            label: self.tokens(start + 1, self.max_length),
+        }
+    }
+
+    fn dropped_tokens(&self) -> usize {
+        gpt::dropped_tokens(self.num_tokens(), self.max_length, self.stride, self.len())
+    }
+}
+
+pub struct TokenFileWriter {
+    writer: BufWriter<File>,
+    count: usize,
+}
+
+impl TokenFileWriter {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, DataLoaderError> {
+        Ok(TokenFileWriter {
+            writer: BufWriter::new(File::create(path)?),
+            count: 0,
+        })
+    }
+
+    pub fn write(&mut self, token_ids: &[usize]) -> Result<(), DataLoaderError> {
+        for &id in token_ids {
+            let id = u32::try_from(id).map_err(|_| DataLoaderError::TokenIdOverflow(id))?;
+
+            self.writer.write_all(&id.to_le_bytes())?;
+            self.count += 1;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<usize, DataLoaderError> {
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+pub fn write_token_file(
+    path: impl AsRef<Path>,
+    token_ids: &[usize],
+) -> Result<usize, DataLoaderError> {
+    let mut writer = TokenFileWriter::create(path)?;
+    writer.write(token_ids)?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DataLoader, GPTDataset};
+
+    #[test]
+    fn test_mmap_token_dataset() {
+        let path = std::env::temp_dir().join("test_mmap_token_dataset.bin");
+        let token_ids: Vec<usize> = (0..26).collect();
+        assert_eq!(write_token_file(&path, &token_ids).unwrap(), 26);
+
+        let dataset = MmapTokenDataset::open(&path, 4, 2).unwrap();
+        let expected = GPTDataset::new(token_ids, 4, 2);
+        assert_eq!(dataset.len(), expected.len());
+        assert_eq!(dataset.dropped_tokens(), expected.dropped_tokens());
+
+        for i in 0..expected.len() {
+            let (sample, item) = (dataset.get(i), expected.get(i));
+            assert_eq!(sample.feature, item.feature);
+            assert_eq!(sample.label, item.label);
+        }
+
+        let loader = DataLoader::new(dataset, 4, true, 2, false);
+        for (i, batch) in loader.iter().enumerate() {
+            let batch = batch.unwrap();
+            println!("Batch {}: {:?}", i, batch);
+        }
+        println!("\n");
+
+        assert!(matches!(
+            MmapTokenDataset::open(&path, 0, 2),
+            Err(DataLoaderError::InvalidWindow { .. })
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use crate::seeded_rng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+// 把`0..len`按`batch_size`划分为批次
+pub fn batch_indices(
+    len: usize,
+    batch_size: usize,
+    shuffle: bool,
+    drop_last: bool,
+) -> Vec<Vec<usize>> {
+    assert!(batch_size > 0);
+
+    let mut indices: Vec<usize> = (0..len).collect();
+
+    if shuffle {
+        shuffle_indices(&mut indices, None);
+    }
+
+    chunk_batches(&indices, batch_size, drop_last)
+}
+
+// 没有`seed`时使用`seeded_rng`
+pub(crate) fn shuffle_indices(indices: &mut [usize], seed: Option<u64>) {
+    match seed {
+        Some(seed) => indices.shuffle(&mut StdRng::seed_from_u64(seed)),
+        None => indices.shuffle(&mut seeded_rng()),
+    }
+}
+
+pub(crate) fn chunk_batches(
+    indices: &[usize],
+    batch_size: usize,
+    drop_last: bool,
+) -> Vec<Vec<usize>> {
+    indices
+        .chunks(batch_size)
+        .filter(|batch| !drop_last || batch.len() == batch_size)
+        .map(|batch| batch.to_vec())
+        .collect()
+}
+
+// 按样本长度分桶，同一批次内的样本长度相近，减少填充。
+// `boundaries`为升序的桶上界，长度超过最后一个上界的样本归入额外的一个桶。
+#[derive(Debug, Clone)]
+pub struct BucketSampler {
+    buckets: Vec<Vec<usize>>,
+    batch_size: usize,
+    shuffle: bool,
+    drop_last: bool,
+    seed: Option<u64>,
+}
+
+impl BucketSampler {
+    pub fn new(
+        lengths: &[usize],
+        boundaries: &[usize],
+        batch_size: usize,
+        shuffle: bool,
+        drop_last: bool,
+    ) -> Self {
+        assert!(batch_size > 0);
+        assert!(boundaries.windows(2).all(|w| w[0] < w[1]));
+
+        let mut buckets = vec![vec![]; boundaries.len() + 1];
+        for (index, &len) in lengths.iter().enumerate() {
+            let bucket = boundaries.partition_point(|&bound| bound < len);
+            buckets[bucket].push(index);
+        }
+
+        BucketSampler {
+            buckets,
+            batch_size,
+            shuffle,
+            drop_last,
+            seed: None,
+        }
+    }
+
+    // 固定打乱的种子，每次`batches`返回相同的顺序
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn batches(&self) -> Vec<Vec<usize>> {
+        let mut rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => seeded_rng(),
+        };
+        let mut batches = vec![];
+
+        for bucket in self.buckets.iter() {
+            let mut bucket = bucket.clone();
+            if self.shuffle {
+                bucket.shuffle(&mut rng);
+            }
+
+            batches.extend(chunk_batches(&bucket, self.batch_size, self.drop_last));
+        }
+
+        if self.shuffle {
+            batches.shuffle(&mut rng);
+        }
+
+        batches
+    }
+}
+
+// 多进程数据并行时每个进程只读取自己的分片。
+// 所有进程使用相同的`seed`和`epoch`打乱，因此各分片互不重叠；
+// 样本数不能整除`world_size`时循环补齐，保证每个进程的样本数相同。
+#[derive(Debug, Clone)]
+pub struct DistributedSampler {
+    len: usize,
+    rank: usize,
+    world_size: usize,
+    shuffle: bool,
+    seed: u64,
+    epoch: u64,
+}
+
+impl DistributedSampler {
+    pub fn new(len: usize, rank: usize, world_size: usize, shuffle: bool, seed: u64) -> Self {
+        assert!(world_size > 0 && rank < world_size);
+
+        DistributedSampler {
+            len,
+            rank,
+            world_size,
+            shuffle,
+            seed,
+            epoch: 0,
+        }
+    }
+
+    // 每个epoch开始前调用，使各epoch的打乱顺序不同
+    pub fn set_epoch(&mut self, epoch: u64) {
+        self.epoch = epoch;
+    }
+
+    pub fn num_samples(&self) -> usize {
+        self.len.div_ceil(self.world_size)
+    }
+
+    pub fn indices(&self) -> Vec<usize> {
+        let mut indices: Vec<usize> = (0..self.len).collect();
+
+        if self.shuffle {
+            let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(self.epoch));
+            indices.shuffle(&mut rng);
+        }
+
+        let total = self.num_samples() * self.world_size;
+        for i in 0..total - self.len {
+            indices.push(indices[i % self.len]);
+        }
+
+        indices
+            .into_iter()
+            .skip(self.rank)
+            .step_by(self.world_size)
+            .collect()
+    }
+
+    pub fn batches(&self, batch_size: usize, drop_last: bool) -> Vec<Vec<usize>> {
+        assert!(batch_size > 0);
+        chunk_batches(&self.indices(), batch_size, drop_last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DataLoader, VecDataset};
+
+    #[test]
+    fn test_batch_indices() {
+        assert_eq!(
+            batch_indices(5, 2, false, false),
+            [vec![0, 1], vec![2, 3], vec![4]]
+        );
+        assert_eq!(batch_indices(5, 2, false, true), [vec![0, 1], vec![2, 3]]);
+    }
+
+    #[test]
+    fn test_bucket_sampler() {
+        let samples: Vec<Vec<u32>> = [3, 30, 5, 70, 31, 2, 64, 200, 8, 40]
+            .iter()
+            .map(|&len| vec![0; len])
+            .collect();
+        let lengths: Vec<usize> = samples.iter().map(|s| s.len()).collect();
+
+        let sampler = BucketSampler::new(&lengths, &[8, 32, 64], 2, true, false);
+        let loader = DataLoader::from_batches(VecDataset::new(samples), sampler.batches(), 2);
+
+        let mut total = 0;
+        for batch in loader.iter() {
+            let batch = batch.unwrap();
+            let lengths: Vec<usize> = batch.iter().map(|s| s.len()).collect();
+            println!("{:?}", lengths);
+
+            let bucket = |len: usize| [8, 32, 64].partition_point(|&bound| bound < len);
+            assert!(lengths.iter().all(|&len| bucket(len) == bucket(lengths[0])));
+            total += batch.len();
+        }
+
+        assert_eq!(total, 10);
+    }
+
+    #[test]
+    fn test_distributed_sampler() {
+        let world_size = 3;
+        let mut all = vec![];
+
+        for rank in 0..world_size {
+            let sampler = DistributedSampler::new(10, rank, world_size, true, 7);
+            let indices = sampler.indices();
+            println!("rank {rank}: {:?}", indices);
+
+            assert_eq!(indices.len(), 4);
+            assert_eq!(
+                indices,
+                DistributedSampler::new(10, rank, 3, true, 7).indices()
+            );
+            all.extend(indices);
+        }
+
+        // 12个位置中有2个是补齐的重复样本
+        all.sort();
+        all.dedup();
+        assert_eq!(all, (0..10).collect::<Vec<_>>());
+
+        let mut sampler = DistributedSampler::new(10, 0, world_size, true, 7);
+        let first = sampler.indices();
+        sampler.set_epoch(1);
+        assert_ne!(first, sampler.indices());
+    }
+}
@@ -0,0 +1,102 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+use std::sync::Mutex;
+
+// `set_seed`设置的种子和随机数生成器，进程内所有线程共用，`None`表示使用线程随机数
+static GLOBAL: Mutex<Option<(u64, StdRng)>> = Mutex::new(None);
+
+thread_local! {
+    // `DataLoader`工作线程按编号从种子派生的生成器，不受其它线程调用顺序的影响
+    static WORKER: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+// 与PyTorch的`torch.manual_seed`相同：之后进程内所有没有显式指定种子的随机源
+// （`DataLoader`的打乱顺序、`BucketSampler`，以及用`seeded_rng()`初始化的权重、dropout和采样）都从`seed`派生，
+// 按相同的顺序调用时结果可以复现
+pub fn set_seed(seed: u64) {
+    *GLOBAL.lock().unwrap() = Some((seed, StdRng::seed_from_u64(seed)));
+}
+
+// 从`set_seed`的随机数生成器派生一个新的生成器，可以传给`GPTModel::new`和采样函数。
+// 在`DataLoader`的工作线程中从该线程的`worker_rng`派生。没有调用`set_seed`时使用线程随机数
+pub fn seeded_rng() -> StdRng {
+    with_rng(|rng| match rng {
+        Some(rng) => StdRng::from_rng(rng),
+        None => StdRng::from_rng(&mut rand::rng()),
+    })
+}
+
+// 第`rank`个工作线程的生成器，只由种子和`rank`决定，与线程的调度顺序无关。
+// 没有调用`set_seed`时使用线程随机数
+pub fn worker_rng(rank: usize) -> StdRng {
+    worker_stream(rank).unwrap_or_else(|| StdRng::from_rng(&mut rand::rng()))
+}
+
+// 调用过`set_seed`时派生一个种子，用于需要保存在`LoaderState`中的打乱顺序
+pub(crate) fn derive_seed() -> Option<u64> {
+    with_rng(|rng| rng.map(|rng| rng.random()))
+}
+
+// 工作线程启动时调用，之后该线程的`seeded_rng`和`derive_seed`使用`worker_rng(rank)`
+pub(crate) fn enter_worker(rank: usize) {
+    WORKER.with(|worker| *worker.borrow_mut() = worker_stream(rank));
+}
+
+fn worker_stream(rank: usize) -> Option<StdRng> {
+    let global = GLOBAL.lock().unwrap();
+    let (seed, _) = global.as_ref()?;
+    // 编号乘以黄金比例常数后与种子混合，不同编号得到不相关的流
+    let mixed = seed ^ (rank as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    Some(StdRng::seed_from_u64(mixed))
+}
+
+fn with_rng<T>(f: impl FnOnce(Option<&mut StdRng>) -> T) -> T {
+    WORKER.with(|worker| match worker.borrow_mut().as_mut() {
+        Some(rng) => f(Some(rng)),
+        None => f(GLOBAL.lock().unwrap().as_mut().map(|(_, rng)| rng)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{batch_indices, DataLoader, VecDataset};
+
+    #[test]
+    fn test_set_seed() {
+        // 其它测试并行使用进程内的生成器，在单独的工作线程中检查可复现
+        let run = || {
+            set_seed(123);
+            std::thread::spawn(|| {
+                enter_worker(0);
+                let loader = DataLoader::builder()
+                    .batch_size(4)
+                    .shuffle(true)
+                    .build(VecDataset::new((0..16).collect::<Vec<_>>()));
+                let batches = loader.iter().map(Result::unwrap).collect::<Vec<_>>();
+                let indices = batch_indices(16, 4, true, false);
+                (batches, indices, seeded_rng().random::<u64>())
+            })
+            .join()
+            .unwrap()
+        };
+
+        let (batches, indices, value) = run();
+        println!("{batches:?} {indices:?}");
+        assert_eq!(run(), (batches.clone(), indices, value));
+        assert_ne!(batches.concat(), (0..16).collect::<Vec<_>>());
+
+        // 种子对所有线程可见，不同编号的流不同
+        let streams = (0..2)
+            .map(|rank| std::thread::spawn(move || worker_rng(rank).random::<u64>()))
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_ne!(streams[0], streams[1]);
+        assert_eq!(worker_rng(1).random::<u64>(), streams[1]);
+        assert!(std::thread::spawn(derive_seed).join().unwrap().is_some());
+
+        set_seed(124);
+        assert_ne!(worker_rng(1).random::<u64>(), streams[1]);
+    }
+}
@@ -0,0 +1,93 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoaderStats {
+    pub epoch: u64,
+    pub batches: usize,
+    pub samples: usize,
+    // 被`Dataset::filter`去掉的样本数，不计入`samples`
+    pub filtered: usize,
+    // 仅在整理函数提供token统计（如`PadCollator`）时累计
+    pub real_tokens: usize,
+    pub padding_tokens: usize,
+    // 数据集中没有进入任何样本的token数（`Dataset::dropped_tokens`），每个epoch开始时记录
+    pub dropped_tokens: usize,
+    pub elapsed: Duration,
+}
+
+impl LoaderStats {
+    pub fn padding_fraction(&self) -> f64 {
+        let total = self.real_tokens + self.padding_tokens;
+        if total == 0 {
+            0.0
+        } else {
+            self.padding_tokens as f64 / total as f64
+        }
+    }
+
+    pub fn samples_per_sec(&self) -> f64 {
+        self.samples as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn batches_per_sec(&self) -> f64 {
+        self.batches as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+// 加载进度，用于中断后从同一位置继续训练
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LoaderState {
+    pub epoch: u64,
+    pub seed: Option<u64>,
+    // 当前epoch已返回的样本数
+    pub consumed: usize,
+}
+
+pub(crate) struct Progress {
+    pub epoch: u64,
+    // 可迭代数据集事先不知道批次数
+    pub expected: Option<usize>,
+    pub batches: usize,
+    pub samples: usize,
+    pub filtered: usize,
+    // 恢复时跳过的样本数，不计入统计
+    pub skipped: usize,
+    pub real_tokens: usize,
+    pub padding_tokens: usize,
+    pub dropped_tokens: usize,
+    pub finished: bool,
+    pub failed: bool,
+    start: Instant,
+}
+
+impl Progress {
+    pub fn new(epoch: u64, expected: Option<usize>) -> Self {
+        Progress {
+            epoch,
+            expected,
+            batches: 0,
+            samples: 0,
+            filtered: 0,
+            skipped: 0,
+            real_tokens: 0,
+            padding_tokens: 0,
+            dropped_tokens: 0,
+            finished: false,
+            failed: false,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn stats(&self) -> LoaderStats {
+        LoaderStats {
+            epoch: self.epoch,
+            batches: self.batches,
+            samples: self.samples,
+            filtered: self.filtered,
+            real_tokens: self.real_tokens,
+            padding_tokens: self.padding_tokens,
+            dropped_tokens: self.dropped_tokens,
+            elapsed: self.start.elapsed(),
+        }
+    }
+}
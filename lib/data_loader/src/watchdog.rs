@@ -0,0 +1,83 @@
+use crate::Job;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// 超时未收到批次时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallAction {
+    Error,
+    // 重新提交卡住的批次并启动新的工作线程，总共最多重启`max_restarts`个线程，
+    // 之后仍然超时则报错。可迭代数据集无法重启，总是报错
+    Restart { max_restarts: usize },
+}
+
+// 发生超时时每个工作线程的状态
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStatus {
+    pub worker: usize,
+    pub busy: bool,
+    // 最近读取的样本下标，可迭代数据集为分片下标
+    pub last_index: Option<usize>,
+    pub batches: usize,
+}
+
+impl fmt::Display for WorkerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.busy { "busy" } else { "idle" };
+        write!(f, "worker {} {state}, last index ", self.worker)?;
+        match self.last_index {
+            Some(index) => write!(f, "{index}")?,
+            None => write!(f, "-")?,
+        }
+        write!(f, ", {} batches", self.batches)
+    }
+}
+
+const NO_INDEX: usize = usize::MAX;
+
+pub(crate) struct WorkerState {
+    // 正在处理的批次及其开始时间，发送后清空，重启时据此重新提交
+    pub job: Mutex<Option<(Job, Instant)>>,
+    pub busy: AtomicBool,
+    last_index: AtomicUsize,
+    pub batches: AtomicUsize,
+    // 被替换的线程不再发送批次
+    pub retired: AtomicBool,
+}
+
+impl WorkerState {
+    pub fn new() -> Self {
+        WorkerState {
+            job: Mutex::new(None),
+            busy: AtomicBool::new(false),
+            last_index: AtomicUsize::new(NO_INDEX),
+            batches: AtomicUsize::new(0),
+            retired: AtomicBool::new(false),
+        }
+    }
+
+    // 当前批次已处理超过`timeout`
+    pub fn job_stalled(&self, timeout: Duration) -> bool {
+        self.job
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|(_, started)| started.elapsed() >= timeout)
+    }
+
+    pub fn set_last_index(&self, index: usize) {
+        self.last_index.store(index, Ordering::SeqCst);
+    }
+
+    pub fn status(&self, worker: usize) -> WorkerStatus {
+        let last_index = self.last_index.load(Ordering::SeqCst);
+        WorkerStatus {
+            worker,
+            busy: self.busy.load(Ordering::SeqCst),
+            last_index: (last_index != NO_INDEX).then_some(last_index),
+            batches: self.batches.load(Ordering::SeqCst),
+        }
+    }
+}
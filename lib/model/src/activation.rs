@@ -0,0 +1,142 @@
+use crate::{SavedTensor, Tensor};
+use std::f32::consts::{FRAC_1_SQRT_2, FRAC_2_SQRT_PI};
+
+// 与PyTorch的`approximate`参数相同：`None`为精确的`x·Φ(x)`，`Tanh`为GPT-2和书中使用的近似
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GeluApproximation {
+    None,
+    #[default]
+    Tanh,
+}
+
+// `√(2/π)`
+const SQRT_2_OVER_PI: f32 = FRAC_2_SQRT_PI * FRAC_1_SQRT_2;
+const GELU_COEFF: f32 = 0.044715;
+
+#[derive(Debug, Clone, Default)]
+pub struct Gelu {
+    approximate: GeluApproximation,
+    // 上一次`forward`的输入，`backward`时使用
+    input: Option<SavedTensor>,
+}
+
+impl Gelu {
+    pub fn new(approximate: GeluApproximation) -> Self {
+        Gelu {
+            approximate,
+            input: None,
+        }
+    }
+
+    pub(crate) fn clear_cache(&mut self) {
+        self.input = None;
+    }
+
+    pub(crate) fn saved_bytes(&self) -> usize {
+        self.input.as_ref().map_or(0, SavedTensor::size_in_bytes)
+    }
+
+    pub fn approximate(&self) -> GeluApproximation {
+        self.approximate
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Tensor {
+        let output = self.apply(input);
+        self.input = Some(SavedTensor::new(input));
+        output
+    }
+
+    // 只计算输出，不保存反向传播需要的输入
+    pub fn apply(&self, input: &Tensor) -> Tensor {
+        let mut output = input.clone();
+        output
+            .data_mut()
+            .iter_mut()
+            .for_each(|x| *x = gelu(*x, self.approximate));
+        output
+    }
+
+    pub fn backward(&self, grad_output: &Tensor) -> Tensor {
+        let input = self
+            .input
+            .as_ref()
+            .expect("Gelu::backward called before forward")
+            .tensor();
+        assert_eq!(
+            grad_output.len(),
+            input.len(),
+            "Gradient does not match the last forward input"
+        );
+
+        let mut grad_input = grad_output.clone();
+        for (g, x) in grad_input.data_mut().iter_mut().zip(input.data()) {
+            *g *= gelu_grad(*x, self.approximate);
+        }
+        grad_input
+    }
+}
+
+pub fn gelu(x: f32, approximate: GeluApproximation) -> f32 {
+    match approximate {
+        GeluApproximation::None => 0.5 * x * (1.0 + erf(x * FRAC_1_SQRT_2)),
+        GeluApproximation::Tanh => {
+            0.5 * x * (1.0 + (SQRT_2_OVER_PI * (x + GELU_COEFF * x.powi(3))).tanh())
+        }
+    }
+}
+
+fn gelu_grad(x: f32, approximate: GeluApproximation) -> f32 {
+    match approximate {
+        // Φ(x) + x·φ(x)
+        GeluApproximation::None => {
+            let cdf = 0.5 * (1.0 + erf(x * FRAC_1_SQRT_2));
+            let pdf = (-0.5 * x * x).exp() * FRAC_2_SQRT_PI * FRAC_1_SQRT_2 * 0.5;
+            cdf + x * pdf
+        }
+        GeluApproximation::Tanh => {
+            let tanh = (SQRT_2_OVER_PI * (x + GELU_COEFF * x.powi(3))).tanh();
+            let du = SQRT_2_OVER_PI * (1.0 + 3.0 * GELU_COEFF * x * x);
+            0.5 * (1.0 + tanh) + 0.5 * x * (1.0 - tanh * tanh) * du
+        }
+    }
+}
+
+// 标准库没有`erf`，使用Abramowitz-Stegun 7.1.26的近似，误差小于1.5e-7
+fn erf(x: f32) -> f32 {
+    let t = 1.0 / (1.0 + 0.327_591_1 * x.abs());
+    let poly = t
+        * (0.254_829_6
+            + t * (-0.284_496_7 + t * (1.421_413_7 + t * (-1.453_152 + t * 1.061_405_4))));
+    let y = 1.0 - poly * (-x * x).exp();
+    y.copysign(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gelu() {
+        // 与PyTorch的`F.gelu`对比
+        assert!((gelu(1.0, GeluApproximation::None) - 0.841_344_7).abs() < 1e-6);
+        assert!((gelu(-1.0, GeluApproximation::None) + 0.158_655_3).abs() < 1e-6);
+        assert!((gelu(1.0, GeluApproximation::Tanh) - 0.841_192).abs() < 1e-6);
+        assert!((gelu(-3.0, GeluApproximation::Tanh) + 0.003_637_4).abs() < 1e-6);
+        assert_eq!(gelu(0.0, GeluApproximation::None), 0.0);
+
+        let input = Tensor::new((-6..=6).map(|x| x as f32 * 0.5).collect(), &[13]);
+        for approximate in [GeluApproximation::None, GeluApproximation::Tanh] {
+            let mut activation = Gelu::new(approximate);
+            let output = activation.forward(&input);
+            println!("{approximate:?}: {:?}", output.data());
+
+            let grad = activation.backward(&Tensor::new(vec![1.0; 13], &[13]));
+            let eps = 1e-3;
+            for (x, g) in input.data().iter().zip(grad.data()) {
+                let numeric =
+                    (gelu(x + eps, approximate) - gelu(x - eps, approximate)) / (2.0 * eps);
+                assert!((numeric - g).abs() < 1e-2, "x = {x}: {numeric} vs {g}");
+            }
+        }
+    }
+}
@@ -0,0 +1,1428 @@
+use crate::chunked::{
+    attend_chunked, attend_chunked_backward, ChunkedInputs, Chunking, TileDropout,
+};
+use crate::dropout::apply_mask;
+use crate::error::{invalid, shape_error, Result};
+use crate::param::prefixed;
+use crate::{Dropout, KvCache, Linear, Param, Rope, SavedTensor, Tensor};
+use rand::Rng;
+use std::borrow::Cow;
+use std::ops::Range;
+use tensor::gemm;
+
+// 书中第3章的`SelfAttention_v2`：`softmax(Q·Kᵀ/√d)·V`，没有因果掩码
+#[derive(Debug, Clone)]
+pub struct SelfAttention {
+    w_query: Linear,
+    w_key: Linear,
+    w_value: Linear,
+    // 上一次`forward`的Q、K、V和注意力权重，`backward`时使用
+    cache: Option<AttentionCache>,
+}
+
+#[derive(Debug, Clone)]
+struct AttentionCache {
+    queries: Tensor,
+    keys: Tensor,
+    values: Tensor,
+    weights: Tensor,
+}
+
+impl SelfAttention {
+    pub fn new(d_in: usize, d_out: usize, qkv_bias: bool, rng: &mut impl Rng) -> Self {
+        SelfAttention {
+            w_query: Linear::new(d_in, d_out, qkv_bias, rng),
+            w_key: Linear::new(d_in, d_out, qkv_bias, rng),
+            w_value: Linear::new(d_in, d_out, qkv_bias, rng),
+            cache: None,
+        }
+    }
+
+    pub fn from_linears(w_query: Linear, w_key: Linear, w_value: Linear) -> Self {
+        assert!(
+            w_query.in_features() == w_key.in_features()
+                && w_query.in_features() == w_value.in_features(),
+            "Q, K and V projections must have the same input dim"
+        );
+        assert!(
+            w_query.out_features() == w_key.out_features()
+                && w_query.out_features() == w_value.out_features(),
+            "Q, K and V projections must have the same output dim"
+        );
+
+        SelfAttention {
+            w_query,
+            w_key,
+            w_value,
+            cache: None,
+        }
+    }
+
+    pub fn d_in(&self) -> usize {
+        self.w_query.in_features()
+    }
+
+    pub fn d_out(&self) -> usize {
+        self.w_query.out_features()
+    }
+
+    pub fn w_query(&self) -> &Linear {
+        &self.w_query
+    }
+
+    pub fn w_key(&self) -> &Linear {
+        &self.w_key
+    }
+
+    pub fn w_value(&self) -> &Linear {
+        &self.w_value
+    }
+
+    pub fn w_query_mut(&mut self) -> &mut Linear {
+        &mut self.w_query
+    }
+
+    pub fn w_key_mut(&mut self) -> &mut Linear {
+        &mut self.w_key
+    }
+
+    pub fn w_value_mut(&mut self) -> &mut Linear {
+        &mut self.w_value
+    }
+
+    // 上一次`forward`的注意力权重，形状为`(B, T, T)`
+    pub fn attention_weights(&self) -> Option<&Tensor> {
+        self.cache.as_ref().map(|cache| &cache.weights)
+    }
+
+    // 输入为`(B, T, d_in)`，输出`(B, T, d_out)`
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor> {
+        let [batch, seq_len, _] = *input.shape() else {
+            return Err(shape_error!(
+                "Expected a (B, T, C) input, got {:?}",
+                input.shape()
+            ));
+        };
+
+        let queries = self.w_query.forward(input)?;
+        let keys = self.w_key.forward(input)?;
+        let values = self.w_value.forward(input)?;
+
+        let dim = self.d_out();
+        let (qkv_size, weights_size) = (seq_len * dim, seq_len * seq_len);
+        let mut weights = vec![0.0; batch * weights_size];
+        let mut output = vec![0.0; batch * qkv_size];
+
+        for b in 0..batch {
+            let qkv = b * qkv_size..(b + 1) * qkv_size;
+            attend(
+                &queries.data()[qkv.clone()],
+                &keys.data()[qkv.clone()],
+                &values.data()[qkv.clone()],
+                &mut weights[b * weights_size..(b + 1) * weights_size],
+                &mut output[qkv],
+                seq_len,
+                dim,
+                Mask::None,
+                None,
+            )?;
+        }
+
+        self.cache = Some(AttentionCache {
+            queries,
+            keys,
+            values,
+            weights: Tensor::new(weights, &[batch, seq_len, seq_len]),
+        });
+        Ok(Tensor::new(output, &[batch, seq_len, dim]))
+    }
+
+    // 累加Wq/Wk/Wv的梯度，返回输入的梯度
+    pub fn backward(&mut self, grad_output: &Tensor) -> Result<Tensor> {
+        let cache = self
+            .cache
+            .as_ref()
+            .expect("SelfAttention::backward called before forward");
+        let [batch, seq_len, _] = *cache.weights.shape() else {
+            unreachable!()
+        };
+        let dim = self.d_out();
+        assert_eq!(
+            grad_output.len(),
+            batch * seq_len * dim,
+            "Gradient does not match the last forward input"
+        );
+
+        let (qkv_size, weights_size) = (seq_len * dim, seq_len * seq_len);
+        let mut grad_queries = vec![0.0; batch * qkv_size];
+        let mut grad_keys = vec![0.0; batch * qkv_size];
+        let mut grad_values = vec![0.0; batch * qkv_size];
+
+        for b in 0..batch {
+            let qkv = b * qkv_size..(b + 1) * qkv_size;
+            attend_backward(
+                AttentionInputs {
+                    queries: &cache.queries.data()[qkv.clone()],
+                    keys: &cache.keys.data()[qkv.clone()],
+                    values: &cache.values.data()[qkv.clone()],
+                    weights: &cache.weights.data()[b * weights_size..(b + 1) * weights_size],
+                    dropout_mask: None,
+                },
+                &grad_output.data()[qkv.clone()],
+                &mut grad_queries[qkv.clone()],
+                &mut grad_keys[qkv.clone()],
+                &mut grad_values[qkv],
+                seq_len,
+                dim,
+            )?;
+        }
+
+        let shape = [batch, seq_len, dim];
+        let mut grad_input = self.w_query.backward(&Tensor::new(grad_queries, &shape))?;
+        for grad in [
+            self.w_key.backward(&Tensor::new(grad_keys, &shape))?,
+            self.w_value.backward(&Tensor::new(grad_values, &shape))?,
+        ] {
+            for (g, x) in grad_input.data_mut().iter_mut().zip(grad.data()) {
+                *g += x;
+            }
+        }
+        Ok(grad_input)
+    }
+
+    pub fn zero_grad(&mut self) {
+        self.w_query.zero_grad();
+        self.w_key.zero_grad();
+        self.w_value.zero_grad();
+    }
+}
+
+// 书中第3章的`MultiHeadAttention`：把`d_out`拆成`num_heads`个头分别做因果注意力，
+// 拼接后经过输出投影。注意力权重上可以加dropout
+#[derive(Debug, Clone)]
+pub struct MultiHeadAttention {
+    w_query: Linear,
+    w_key: Linear,
+    w_value: Linear,
+    out_proj: Linear,
+    num_heads: usize,
+    // K和V的头数，每`num_heads / num_kv_heads`个Q头共享一组K和V（GQA），为1时即MQA
+    num_kv_heads: usize,
+    context_length: usize,
+    dropout: Dropout,
+    // 为`None`时不在注意力中编码位置，由模型的位置嵌入提供
+    rope: Option<Rope>,
+    // 滑动窗口注意力，为`None`时可以看到之前所有的位置
+    window: Option<usize>,
+    // 分块计算注意力，不保存`(T, T)`的注意力矩阵
+    chunk_size: Option<usize>,
+    cache: Option<MultiHeadCache>,
+}
+
+#[derive(Debug, Clone)]
+struct MultiHeadCache {
+    // Q按`(B, H, T, head_dim)`排列，K、V按`(B, H_kv, T, head_dim)`，每个头是连续的，Q和K已经旋转过
+    queries: SavedTensor,
+    keys: SavedTensor,
+    values: SavedTensor,
+    batch: usize,
+    seq_len: usize,
+    scores: Scores,
+}
+
+#[derive(Debug, Clone)]
+enum Scores {
+    // `(B, H, T, T)`，dropout之前的注意力权重
+    Full {
+        weights: SavedTensor,
+        dropout_mask: Option<Vec<f32>>,
+    },
+    // 分块计算时只保存每个头的输出`(B, H, T, head_dim)`和每行的logsumexp，反向传播时重新计算权重
+    Chunked {
+        context: SavedTensor,
+        lse: Vec<f32>,
+        dropout: Option<TileDropout>,
+    },
+}
+
+impl MultiHeadAttention {
+    pub fn new(
+        d_in: usize,
+        d_out: usize,
+        context_length: usize,
+        dropout: f32,
+        num_heads: usize,
+        qkv_bias: bool,
+        rng: &mut impl Rng,
+    ) -> Self {
+        assert!(
+            num_heads > 0 && d_out.is_multiple_of(num_heads),
+            "d_out must be divisible by num_heads"
+        );
+
+        MultiHeadAttention {
+            w_query: Linear::new(d_in, d_out, qkv_bias, rng),
+            w_key: Linear::new(d_in, d_out, qkv_bias, rng),
+            w_value: Linear::new(d_in, d_out, qkv_bias, rng),
+            out_proj: Linear::new(d_out, d_out, true, rng),
+            num_heads,
+            num_kv_heads: num_heads,
+            context_length,
+            dropout: Dropout::new(dropout, rng),
+            rope: None,
+            window: None,
+            chunk_size: None,
+            cache: None,
+        }
+    }
+
+    // 分组查询注意力：K和V只有`num_kv_heads`个头，重新初始化K和V的投影。
+    // KV缓存缩小为原来的`num_kv_heads / num_heads`
+    pub fn with_kv_heads(mut self, num_kv_heads: usize, rng: &mut impl Rng) -> Self {
+        assert!(
+            num_kv_heads > 0 && self.num_heads.is_multiple_of(num_kv_heads),
+            "num_heads must be divisible by num_kv_heads"
+        );
+        let (d_in, kv_dim) = (self.d_in(), num_kv_heads * self.head_dim());
+        let qkv_bias = self.w_key.bias().is_some();
+        self.w_key = Linear::new(d_in, kv_dim, qkv_bias, rng);
+        self.w_value = Linear::new(d_in, kv_dim, qkv_bias, rng);
+        self.num_kv_heads = num_kv_heads;
+        self
+    }
+
+    // 使用RoPE编码位置，`theta`通常为10000
+    pub fn with_rope(mut self, theta: f32) -> Self {
+        self.rope = Some(Rope::new(self.head_dim(), self.context_length, theta));
+        self
+    }
+
+    pub fn rope(&self) -> Option<&Rope> {
+        self.rope.as_ref()
+    }
+
+    // 每个token只关注最后`window`个token（包括自己），KV缓存只保留窗口内的K和V
+    pub fn with_sliding_window(mut self, window: usize) -> Self {
+        assert!(window > 0, "Sliding window must be positive");
+        self.window = Some(window);
+        self
+    }
+
+    pub fn window(&self) -> Option<usize> {
+        self.window
+    }
+
+    // 每次只计算`chunk_size`个query对`chunk_size`个key的分数，长序列的内存从O(T²)降到O(T)，
+    // 结果与完整计算相同（dropout的掩码不同）
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "Chunk size must be positive");
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    pub fn chunk_size(&self) -> Option<usize> {
+        self.chunk_size
+    }
+
+    fn chunking(&self, padding: usize, dropout: Option<TileDropout>) -> Option<Chunking> {
+        self.chunk_size.map(|chunk| Chunking {
+            chunk,
+            mask: self.mask(),
+            padding,
+            dropout,
+        })
+    }
+
+    fn mask(&self) -> Mask {
+        match self.window {
+            Some(window) => Mask::SlidingWindow(window),
+            None => Mask::Causal,
+        }
+    }
+
+    pub fn d_in(&self) -> usize {
+        self.w_query.in_features()
+    }
+
+    pub fn d_out(&self) -> usize {
+        self.w_query.out_features()
+    }
+
+    pub fn num_heads(&self) -> usize {
+        self.num_heads
+    }
+
+    pub fn num_kv_heads(&self) -> usize {
+        self.num_kv_heads
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.d_out() / self.num_heads
+    }
+
+    // 第i个Q头（按`(B, H)`编号）使用的K、V头
+    fn kv_head(&self, i: usize) -> usize {
+        let (b, h) = (i / self.num_heads, i % self.num_heads);
+        b * self.num_kv_heads + h / (self.num_heads / self.num_kv_heads)
+    }
+
+    pub fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    pub fn w_query(&self) -> &Linear {
+        &self.w_query
+    }
+
+    pub fn w_key(&self) -> &Linear {
+        &self.w_key
+    }
+
+    pub fn w_value(&self) -> &Linear {
+        &self.w_value
+    }
+
+    pub fn out_proj(&self) -> &Linear {
+        &self.out_proj
+    }
+
+    pub fn w_query_mut(&mut self) -> &mut Linear {
+        &mut self.w_query
+    }
+
+    pub fn w_key_mut(&mut self) -> &mut Linear {
+        &mut self.w_key
+    }
+
+    pub fn w_value_mut(&mut self) -> &mut Linear {
+        &mut self.w_value
+    }
+
+    pub fn out_proj_mut(&mut self) -> &mut Linear {
+        &mut self.out_proj
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.dropout.is_training()
+    }
+
+    // 推理时关闭注意力权重上的dropout
+    pub fn set_training(&mut self, training: bool) {
+        self.dropout.set_training(training);
+    }
+
+    pub fn reseed(&mut self, rng: &mut impl Rng) {
+        self.dropout.reseed(rng);
+    }
+
+    // 上一次`forward`的注意力权重，形状为`(B, H, T, T)`，分块计算时为`None`
+    pub fn attention_weights(&self) -> Option<Cow<'_, Tensor>> {
+        match self.cache.as_ref().map(|cache| &cache.scores) {
+            Some(Scores::Full { weights, .. }) => Some(weights.tensor()),
+            _ => None,
+        }
+    }
+
+    // 输入为`(B, T, d_in)`，输出`(B, T, d_out)`，T不能超过`context_length`
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor> {
+        let [batch, seq_len, _] = *input.shape() else {
+            return Err(shape_error!(
+                "Expected a (B, T, C) input, got {:?}",
+                input.shape()
+            ));
+        };
+        if seq_len > self.context_length {
+            return Err(invalid!(
+                "Sequence length {seq_len} exceeds context length {}",
+                self.context_length
+            ));
+        }
+
+        let (heads, kv_heads, head_dim) = (self.num_heads, self.num_kv_heads, self.head_dim());
+        let mut queries = split_heads(
+            self.w_query.forward(input)?.data(),
+            seq_len,
+            heads,
+            head_dim,
+        );
+        let mut keys = split_heads(
+            self.w_key.forward(input)?.data(),
+            seq_len,
+            kv_heads,
+            head_dim,
+        );
+        if let Some(rope) = self.rope.as_ref() {
+            rope.apply(&mut queries, seq_len, 0);
+            rope.apply(&mut keys, seq_len, 0);
+        }
+        let values = split_heads(
+            self.w_value.forward(input)?.data(),
+            seq_len,
+            kv_heads,
+            head_dim,
+        );
+
+        let head_size = seq_len * head_dim;
+        let mut context = vec![0.0; batch * heads * head_size];
+        let scores = match self.chunk_size {
+            None => {
+                let weights_size = seq_len * seq_len;
+                let dropout_mask = self.dropout.sample_mask(batch * heads * weights_size);
+                let mut weights = vec![0.0; batch * heads * weights_size];
+                for i in 0..batch * heads {
+                    let q = i * head_size..(i + 1) * head_size;
+                    let kv = self.kv_head(i) * head_size..(self.kv_head(i) + 1) * head_size;
+                    let w = i * weights_size..(i + 1) * weights_size;
+                    attend(
+                        &queries[q.clone()],
+                        &keys[kv.clone()],
+                        &values[kv],
+                        &mut weights[w.clone()],
+                        &mut context[q],
+                        seq_len,
+                        head_dim,
+                        self.mask(),
+                        dropout_mask.as_ref().map(|mask| &mask[w]),
+                    )?;
+                }
+                Scores::Full {
+                    weights: SavedTensor::from_tensor(Tensor::new(
+                        weights,
+                        &[batch, heads, seq_len, seq_len],
+                    )),
+                    dropout_mask,
+                }
+            }
+            Some(_) => {
+                let dropout = self
+                    .dropout
+                    .sample_seed()
+                    .map(|seed| TileDropout::new(self.dropout.p(), seed));
+                let mut lse = vec![0.0; batch * heads * seq_len];
+                for i in 0..batch * heads {
+                    let q = i * head_size..(i + 1) * head_size;
+                    let kv = self.kv_head(i) * head_size..(self.kv_head(i) + 1) * head_size;
+                    let chunking = self.chunking(0, dropout.map(|dropout| dropout.for_head(i)));
+                    attend_chunked(
+                        &queries[q.clone()],
+                        &keys[kv.clone()],
+                        &values[kv],
+                        &mut context[q],
+                        &mut lse[i * seq_len..(i + 1) * seq_len],
+                        head_dim,
+                        &chunking.unwrap(),
+                    )?;
+                }
+                Scores::Chunked {
+                    context: SavedTensor::new(&Tensor::new(
+                        context.clone(),
+                        &[batch, heads, seq_len, head_dim],
+                    )),
+                    lse,
+                    dropout,
+                }
+            }
+        };
+
+        let context = merge_heads(&context, seq_len, heads, head_dim);
+        let output = self
+            .out_proj
+            .forward(&Tensor::new(context, &[batch, seq_len, self.d_out()]))?;
+
+        // 半精度时按16位保存，与`gemm`使用的输入相同
+        let save = |data, heads| {
+            SavedTensor::from_tensor(Tensor::new(data, &[batch, heads, seq_len, head_dim]))
+        };
+        self.cache = Some(MultiHeadCache {
+            queries: save(queries, heads),
+            keys: save(keys, kv_heads),
+            values: save(values, kv_heads),
+            batch,
+            seq_len,
+            scores,
+        });
+        Ok(output)
+    }
+
+    // 推理时使用：只计算新输入的Q、K、V，K和V追加到`cache`后与缓存的前缀一起做注意力。
+    // 不使用dropout，也不保存反向传播需要的中间结果
+    pub fn forward_with_cache(&self, input: &Tensor, cache: &mut KvCache) -> Result<Tensor> {
+        self.forward_padded(input, cache, &[])
+    }
+
+    // 批量生成时使用：第b个序列左侧有`padding[b]`个填充token，填充的位置不会被注意到，
+    // 位置编码从填充之后开始计算
+    pub fn forward_padded(
+        &self,
+        input: &Tensor,
+        cache: &mut KvCache,
+        padding: &[usize],
+    ) -> Result<Tensor> {
+        let [batch, seq_len, _] = *input.shape() else {
+            return Err(shape_error!(
+                "Expected a (B, T, C) input, got {:?}",
+                input.shape()
+            ));
+        };
+        if cache.len() + seq_len > self.context_length {
+            return Err(invalid!(
+                "Sequence length {} exceeds context length {}",
+                cache.len() + seq_len,
+                self.context_length
+            ));
+        }
+
+        let (heads, kv_heads, head_dim) = (self.num_heads, self.num_kv_heads, self.head_dim());
+        let mut queries = split_heads(self.w_query.apply(input)?.data(), seq_len, heads, head_dim);
+        let mut keys = split_heads(self.w_key.apply(input)?.data(), seq_len, kv_heads, head_dim);
+        let values = split_heads(
+            self.w_value.apply(input)?.data(),
+            seq_len,
+            kv_heads,
+            head_dim,
+        );
+        // 新token的位置从已缓存的长度开始，缓存中保存旋转后的K
+        if let Some(rope) = self.rope.as_ref() {
+            rope.apply_padded(&mut queries, seq_len, cache.len(), heads, padding);
+            rope.apply_padded(&mut keys, seq_len, cache.len(), kv_heads, padding);
+        }
+        cache.append(&keys, &values, batch * kv_heads, head_dim)?;
+
+        let head_size = seq_len * head_dim;
+        let mut context = vec![0.0; batch * heads * head_size];
+        for i in 0..batch * heads {
+            let q = i * head_size..(i + 1) * head_size;
+            // 滑动窗口可能已经丢弃了一部分填充
+            let evicted = cache.len() - cache.stored_len();
+            let pad = padding.get(i / heads).copied().unwrap_or(0);
+            let (keys, values) = (cache.keys(self.kv_head(i)), cache.values(self.kv_head(i)));
+            match self.chunking(pad.saturating_sub(evicted), None) {
+                Some(chunking) => attend_chunked(
+                    &queries[q.clone()],
+                    keys,
+                    values,
+                    &mut context[q],
+                    &mut vec![0.0; seq_len],
+                    head_dim,
+                    &chunking,
+                )?,
+                None => attend_cached(
+                    &queries[q.clone()],
+                    keys,
+                    values,
+                    &mut context[q],
+                    head_dim,
+                    self.mask(),
+                    pad.saturating_sub(evicted),
+                )?,
+            }
+        }
+        // 滑动窗口之外的K和V不会再被用到，下一个token只需要最后`window - 1`个
+        if let Some(window) = self.window {
+            cache.evict(window - 1);
+        }
+
+        let context = merge_heads(&context, seq_len, heads, head_dim);
+        self.out_proj
+            .apply(&Tensor::new(context, &[batch, seq_len, self.d_out()]))
+    }
+
+    // 累加所有投影的梯度，返回输入的梯度
+    pub fn backward(&mut self, grad_output: &Tensor) -> Result<Tensor> {
+        let grad_context = self.out_proj.backward(grad_output)?;
+        let cache = self
+            .cache
+            .as_ref()
+            .expect("MultiHeadAttention::backward called before forward");
+        let (batch, seq_len) = (cache.batch, cache.seq_len);
+
+        let (heads, kv_heads, head_dim) = (self.num_heads, self.num_kv_heads, self.head_dim());
+        let (head_size, weights_size) = (seq_len * head_dim, seq_len * seq_len);
+        let grad_context = split_heads(grad_context.data(), seq_len, heads, head_dim);
+        let mut grad_queries = vec![0.0; batch * heads * head_size];
+        // 同一组的Q头的梯度累加到共享的K、V头上
+        let mut grad_keys = vec![0.0; batch * kv_heads * head_size];
+        let mut grad_values = vec![0.0; batch * kv_heads * head_size];
+        let (queries, keys, values) = (
+            cache.queries.tensor(),
+            cache.keys.tensor(),
+            cache.values.tensor(),
+        );
+        let saved = match &cache.scores {
+            Scores::Full { weights, .. } => weights.tensor(),
+            Scores::Chunked { context, .. } => context.tensor(),
+        };
+
+        for i in 0..batch * heads {
+            let q = i * head_size..(i + 1) * head_size;
+            let kv = self.kv_head(i) * head_size..(self.kv_head(i) + 1) * head_size;
+            let (queries, keys, values) = (
+                &queries.data()[q.clone()],
+                &keys.data()[kv.clone()],
+                &values.data()[kv.clone()],
+            );
+            match &cache.scores {
+                Scores::Full { dropout_mask, .. } => {
+                    let w = i * weights_size..(i + 1) * weights_size;
+                    attend_backward(
+                        AttentionInputs {
+                            queries,
+                            keys,
+                            values,
+                            weights: &saved.data()[w.clone()],
+                            dropout_mask: dropout_mask.as_ref().map(|mask| &mask[w]),
+                        },
+                        &grad_context[q.clone()],
+                        &mut grad_queries[q],
+                        &mut grad_keys[kv.clone()],
+                        &mut grad_values[kv],
+                        seq_len,
+                        head_dim,
+                    )?;
+                }
+                Scores::Chunked { lse, dropout, .. } => {
+                    let chunking = self.chunking(0, dropout.map(|dropout| dropout.for_head(i)));
+                    attend_chunked_backward(
+                        ChunkedInputs {
+                            queries,
+                            keys,
+                            values,
+                            output: &saved.data()[q.clone()],
+                            lse: &lse[i * seq_len..(i + 1) * seq_len],
+                        },
+                        &grad_context[q.clone()],
+                        &mut grad_queries[q],
+                        &mut grad_keys[kv.clone()],
+                        &mut grad_values[kv],
+                        head_dim,
+                        &chunking.expect("chunk_size changed between forward and backward"),
+                    )?;
+                }
+            }
+        }
+        if let Some(rope) = self.rope.as_ref() {
+            rope.apply_inverse(&mut grad_queries, seq_len, 0);
+            rope.apply_inverse(&mut grad_keys, seq_len, 0);
+        }
+
+        let to_tensor = |grad: Vec<f32>, heads: usize| {
+            let shape = [batch, seq_len, heads * head_dim];
+            Tensor::new(merge_heads(&grad, seq_len, heads, head_dim), &shape)
+        };
+        let mut grad_input = self.w_query.backward(&to_tensor(grad_queries, heads))?;
+        for grad in [
+            self.w_key.backward(&to_tensor(grad_keys, kv_heads))?,
+            self.w_value.backward(&to_tensor(grad_values, kv_heads))?,
+        ] {
+            for (g, x) in grad_input.data_mut().iter_mut().zip(grad.data()) {
+                *g += x;
+            }
+        }
+        Ok(grad_input)
+    }
+
+    pub(crate) fn clear_cache(&mut self) {
+        self.cache = None;
+        self.w_query.clear_cache();
+        self.w_key.clear_cache();
+        self.w_value.clear_cache();
+        self.out_proj.clear_cache();
+    }
+
+    pub(crate) fn saved_bytes(&self) -> usize {
+        let cache = self.cache.as_ref().map_or(0, |cache| {
+            let scores = match &cache.scores {
+                Scores::Full {
+                    weights,
+                    dropout_mask,
+                } => weights.size_in_bytes() + dropout_mask.as_deref().map_or(0, size_of_val),
+                Scores::Chunked { context, lse, .. } => {
+                    context.size_in_bytes() + size_of_val(lse.as_slice())
+                }
+            };
+            cache.queries.size_in_bytes()
+                + cache.keys.size_in_bytes()
+                + cache.values.size_in_bytes()
+                + scores
+        });
+        [&self.w_query, &self.w_key, &self.w_value, &self.out_proj]
+            .iter()
+            .map(|linear| linear.saved_bytes())
+            .sum::<usize>()
+            + cache
+    }
+
+    pub fn zero_grad(&mut self) {
+        self.w_query.zero_grad();
+        self.w_key.zero_grad();
+        self.w_value.zero_grad();
+        self.out_proj.zero_grad();
+    }
+
+    pub fn named_params(&self) -> Vec<(String, &Param)> {
+        let mut params = prefixed("W_query", self.w_query.named_params());
+        params.extend(prefixed("W_key", self.w_key.named_params()));
+        params.extend(prefixed("W_value", self.w_value.named_params()));
+        params.extend(prefixed("out_proj", self.out_proj.named_params()));
+        params
+    }
+
+    pub fn named_params_mut(&mut self) -> Vec<(String, &mut Param)> {
+        let mut params = prefixed("W_query", self.w_query.named_params_mut());
+        params.extend(prefixed("W_key", self.w_key.named_params_mut()));
+        params.extend(prefixed("W_value", self.w_value.named_params_mut()));
+        params.extend(prefixed("out_proj", self.out_proj.named_params_mut()));
+        params
+    }
+
+    pub fn named_linears(&self) -> Vec<(String, &Linear)> {
+        vec![
+            ("W_query".to_string(), &self.w_query),
+            ("W_key".to_string(), &self.w_key),
+            ("W_value".to_string(), &self.w_value),
+            ("out_proj".to_string(), &self.out_proj),
+        ]
+    }
+
+    pub fn named_linears_mut(&mut self) -> Vec<(String, &mut Linear)> {
+        vec![
+            ("W_query".to_string(), &mut self.w_query),
+            ("W_key".to_string(), &mut self.w_key),
+            ("W_value".to_string(), &mut self.w_value),
+            ("out_proj".to_string(), &mut self.out_proj),
+        ]
+    }
+}
+
+// `(B, T, H·d)`转为`(B, H, T, d)`
+fn split_heads(data: &[f32], seq_len: usize, heads: usize, head_dim: usize) -> Vec<f32> {
+    if data.is_empty() {
+        return vec![];
+    }
+    let mut output = vec![0.0; data.len()];
+    for (b, sequence) in data.chunks(seq_len * heads * head_dim).enumerate() {
+        for (t, row) in sequence.chunks(heads * head_dim).enumerate() {
+            for (h, x) in row.chunks(head_dim).enumerate() {
+                let start = ((b * heads + h) * seq_len + t) * head_dim;
+                output[start..start + head_dim].copy_from_slice(x);
+            }
+        }
+    }
+    output
+}
+
+// `split_heads`的逆操作，`(B, H, T, d)`转为`(B, T, H·d)`
+fn merge_heads(data: &[f32], seq_len: usize, heads: usize, head_dim: usize) -> Vec<f32> {
+    if data.is_empty() {
+        return vec![];
+    }
+    let mut output = vec![0.0; data.len()];
+    for (i, head) in data.chunks(seq_len * head_dim).enumerate() {
+        let (b, h) = (i / heads, i % heads);
+        for (t, x) in head.chunks(head_dim).enumerate() {
+            let start = ((b * seq_len + t) * heads + h) * head_dim;
+            output[start..start + head_dim].copy_from_slice(x);
+        }
+    }
+    output
+}
+
+// 单个序列`attend`时保存的输入，都是`(T, d)`，`weights`为dropout之前的`(T, T)`注意力权重
+pub(crate) struct AttentionInputs<'a> {
+    pub queries: &'a [f32],
+    pub keys: &'a [f32],
+    pub values: &'a [f32],
+    pub weights: &'a [f32],
+    pub dropout_mask: Option<&'a [f32]>,
+}
+
+// 第i个位置可以看到的位置
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Mask {
+    None,
+    // 只能看到自己和之前的位置
+    Causal,
+    // 只能看到最后`window`个位置（包括自己）
+    SlidingWindow(usize),
+}
+
+impl Mask {
+    pub(crate) fn visible(self, i: usize, len: usize) -> Range<usize> {
+        match self {
+            Mask::None => 0..len,
+            Mask::Causal => 0..i + 1,
+            Mask::SlidingWindow(window) => (i + 1).saturating_sub(window)..i + 1,
+        }
+    }
+}
+
+// 单个序列的缩放点积注意力，`weights`保存softmax之后、dropout之前的注意力权重
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn attend(
+    queries: &[f32],
+    keys: &[f32],
+    values: &[f32],
+    weights: &mut [f32],
+    output: &mut [f32],
+    seq_len: usize,
+    dim: usize,
+    mask: Mask,
+    dropout_mask: Option<&[f32]>,
+) -> Result<()> {
+    let scale = 1.0 / (dim as f32).sqrt();
+    weights.fill(0.0);
+    gemm(queries, false, keys, true, weights, seq_len, dim, seq_len)?;
+
+    for (i, row) in weights.chunks_mut(seq_len).enumerate() {
+        row.iter_mut().for_each(|x| *x *= scale);
+        let visible = mask.visible(i, seq_len);
+        row[..visible.start].fill(f32::NEG_INFINITY);
+        row[visible.end..].fill(f32::NEG_INFINITY);
+        softmax(row);
+    }
+
+    match dropout_mask {
+        Some(mask) => {
+            let mut dropped = weights.to_vec();
+            apply_mask(&mut dropped, mask);
+            gemm(
+                &dropped, false, values, false, output, seq_len, seq_len, dim,
+            )?;
+        }
+        None => gemm(weights, false, values, false, output, seq_len, seq_len, dim)?,
+    }
+    Ok(())
+}
+
+// `attend`的反向传播，把Q、K、V的梯度累加到`grad_*`。被掩码的位置权重为0，梯度自然为0
+pub(crate) fn attend_backward(
+    inputs: AttentionInputs,
+    grad_output: &[f32],
+    grad_queries: &mut [f32],
+    grad_keys: &mut [f32],
+    grad_values: &mut [f32],
+    seq_len: usize,
+    dim: usize,
+) -> Result<()> {
+    let AttentionInputs {
+        queries,
+        keys,
+        values,
+        weights,
+        dropout_mask,
+    } = inputs;
+    let scale = 1.0 / (dim as f32).sqrt();
+
+    // dV = Aᵀ·dO，dA = dO·Vᵀ，其中A为dropout之后的权重
+    let mut grad_scores = vec![0.0; seq_len * seq_len];
+    gemm(
+        grad_output,
+        false,
+        values,
+        true,
+        &mut grad_scores,
+        seq_len,
+        dim,
+        seq_len,
+    )?;
+    match dropout_mask {
+        Some(mask) => {
+            let mut dropped = weights.to_vec();
+            apply_mask(&mut dropped, mask);
+            gemm(
+                &dropped,
+                true,
+                grad_output,
+                false,
+                grad_values,
+                seq_len,
+                seq_len,
+                dim,
+            )?;
+            apply_mask(&mut grad_scores, mask);
+        }
+        None => gemm(
+            weights,
+            true,
+            grad_output,
+            false,
+            grad_values,
+            seq_len,
+            seq_len,
+            dim,
+        )?,
+    }
+
+    // softmax的反向：dS = A ⊙ (dA - Σ(dA ⊙ A))，再乘上缩放系数
+    for (grad, weight) in grad_scores.chunks_mut(seq_len).zip(weights.chunks(seq_len)) {
+        let dot = grad.iter().zip(weight).map(|(g, a)| g * a).sum::<f32>();
+        for (g, a) in grad.iter_mut().zip(weight) {
+            *g = a * (*g - dot) * scale;
+        }
+    }
+
+    // dQ = dS·K，dK = dSᵀ·Q
+    gemm(
+        &grad_scores,
+        false,
+        keys,
+        false,
+        grad_queries,
+        seq_len,
+        seq_len,
+        dim,
+    )?;
+    gemm(
+        &grad_scores,
+        true,
+        queries,
+        false,
+        grad_keys,
+        seq_len,
+        seq_len,
+        dim,
+    )?;
+    Ok(())
+}
+
+// 新的`q_len`个query对整个缓存（最后`q_len`行是它们自己）做因果注意力，
+// 第i个query对应的绝对位置为`kv_len - q_len + i`
+fn attend_cached(
+    queries: &[f32],
+    keys: &[f32],
+    values: &[f32],
+    output: &mut [f32],
+    dim: usize,
+    mask: Mask,
+    padding: usize,
+) -> Result<()> {
+    let (q_len, kv_len) = (queries.len() / dim, keys.len() / dim);
+    if q_len == 0 {
+        return Ok(());
+    }
+    let past = kv_len - q_len;
+    let scale = 1.0 / (dim as f32).sqrt();
+
+    let mut weights = vec![0.0; q_len * kv_len];
+    gemm(queries, false, keys, true, &mut weights, q_len, dim, kv_len)?;
+    for (i, row) in weights.chunks_mut(kv_len).enumerate() {
+        row.iter_mut().for_each(|x| *x *= scale);
+        let visible = mask.visible(past + i, kv_len);
+        row[..visible.start.max(padding).min(kv_len)].fill(f32::NEG_INFINITY);
+        row[visible.end..].fill(f32::NEG_INFINITY);
+        softmax(row);
+    }
+
+    gemm(&weights, false, values, false, output, q_len, kv_len, dim)?;
+    Ok(())
+}
+
+// 减去最大值避免溢出，全是`-inf`的行（被完全掩码）结果为0
+pub(crate) fn softmax(row: &mut [f32]) {
+    let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        row.fill(0.0);
+        return;
+    }
+
+    let mut sum = 0.0;
+    for x in row.iter_mut() {
+        *x = (*x - max).exp();
+        sum += *x;
+    }
+    row.iter_mut().for_each(|x| *x /= sum);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // 书中第3章的例子：“Your journey starts with one step”
+    fn book_inputs() -> Tensor {
+        let inputs = vec![
+            0.43, 0.15, 0.89, // Your
+            0.55, 0.87, 0.66, // journey
+            0.57, 0.85, 0.64, // starts
+            0.22, 0.58, 0.33, // with
+            0.77, 0.25, 0.10, // one
+            0.05, 0.80, 0.55, // step
+        ];
+        Tensor::new(inputs, &[1, 6, 3])
+    }
+
+    fn weighted_sum(output: &Tensor, weights: &[f32]) -> f32 {
+        output.data().iter().zip(weights).map(|(x, w)| x * w).sum()
+    }
+
+    #[test]
+    fn test_self_attention() {
+        let mut rng = StdRng::seed_from_u64(789);
+        let mut attention = SelfAttention::new(3, 2, false, &mut rng);
+
+        let inputs = book_inputs();
+        let batch = Tensor::new([inputs.data(), inputs.data()].concat(), &[2, 6, 3]);
+        let output = attention.forward(&batch).unwrap();
+        println!("{:?}", output);
+        assert_eq!(output.shape(), [2, 6, 2]);
+        assert_eq!(output.data()[..12], output.data()[12..]);
+
+        // 每一行注意力权重的和为1
+        let weights = attention.attention_weights().unwrap();
+        for row in weights.data().chunks(6) {
+            assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+
+        assert!(attention.forward(&Tensor::zeros(&[6, 3])).is_err());
+        assert!(attention.forward(&Tensor::zeros(&[1, 6, 4])).is_err());
+    }
+
+    #[test]
+    fn test_self_attention_backward() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut attention = SelfAttention::new(3, 4, true, &mut rng);
+        let input = Tensor::randn(&[2, 5, 3], 1.0, &mut rng);
+
+        // loss = Σ output ⊙ r，dLoss/dOutput = r
+        let output = attention.forward(&input).unwrap();
+        let r = Tensor::randn(output.shape(), 1.0, &mut rng);
+        let grad_input = attention.backward(&r).unwrap();
+
+        let eps = 1e-2;
+        for i in 0..input.len() {
+            let mut plus = input.clone();
+            plus.data_mut()[i] += eps;
+            let mut minus = input.clone();
+            minus.data_mut()[i] -= eps;
+
+            let loss_plus = weighted_sum(&attention.forward(&plus).unwrap(), r.data());
+            let loss_minus = weighted_sum(&attention.forward(&minus).unwrap(), r.data());
+            let numeric = (loss_plus - loss_minus) / (2.0 * eps);
+            assert!(
+                (numeric - grad_input.data()[i]).abs() < 1e-2,
+                "input[{i}]: numeric {numeric}, analytic {}",
+                grad_input.data()[i]
+            );
+        }
+
+        let grad_key = attention.w_key().weight().grad().clone();
+        for i in 0..grad_key.len() {
+            attention.w_key_mut().weight_mut().value_mut().data_mut()[i] += eps;
+            let loss_plus = weighted_sum(&attention.forward(&input).unwrap(), r.data());
+            attention.w_key_mut().weight_mut().value_mut().data_mut()[i] -= 2.0 * eps;
+            let loss_minus = weighted_sum(&attention.forward(&input).unwrap(), r.data());
+            attention.w_key_mut().weight_mut().value_mut().data_mut()[i] += eps;
+
+            let numeric = (loss_plus - loss_minus) / (2.0 * eps);
+            assert!(
+                (numeric - grad_key.data()[i]).abs() < 1e-2,
+                "w_key[{i}]: numeric {numeric}, analytic {}",
+                grad_key.data()[i]
+            );
+        }
+
+        attention.zero_grad();
+        assert!(attention
+            .w_value()
+            .weight()
+            .grad()
+            .data()
+            .iter()
+            .all(|g| *g == 0.0));
+    }
+
+    #[test]
+    fn test_multi_head_attention() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut attention = MultiHeadAttention::new(3, 4, 6, 0.0, 2, false, &mut rng);
+
+        let inputs = book_inputs();
+        let batch = Tensor::new([inputs.data(), inputs.data()].concat(), &[2, 6, 3]);
+        let output = attention.forward(&batch).unwrap();
+        println!("{:?}", output);
+        assert_eq!(output.shape(), [2, 6, 4]);
+        assert_eq!(output.data()[..24], output.data()[24..]);
+
+        // 因果掩码：对角线以上的权重为0，每行的和为1
+        let weights = attention.attention_weights().unwrap();
+        assert_eq!(weights.shape(), [2, 2, 6, 6]);
+        for row in weights
+            .data()
+            .chunks(36)
+            .flat_map(|head| head.chunks(6).enumerate())
+        {
+            let (i, row) = row;
+            assert!(row[i + 1..].iter().all(|x| *x == 0.0));
+            assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+
+        // 修改后面的token不影响前面位置的输出
+        let mut changed = inputs.clone();
+        changed.data_mut()[15..].fill(9.0);
+        let changed = attention.forward(&changed).unwrap();
+        assert_eq!(changed.data()[..20], output.data()[..20]);
+        assert_ne!(changed.data()[20..24], output.data()[20..24]);
+
+        assert!(attention.forward(&Tensor::zeros(&[1, 7, 3])).is_err());
+    }
+
+    #[test]
+    fn test_multi_head_attention_backward() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut attention = MultiHeadAttention::new(3, 4, 8, 0.2, 2, true, &mut rng);
+        let input = Tensor::randn(&[2, 5, 3], 1.0, &mut rng);
+        let r = Tensor::randn(&[2, 5, 4], 1.0, &mut rng);
+
+        // 克隆时dropout的随机数状态一起复制，数值梯度和解析梯度使用相同的掩码
+        let initial = attention.clone();
+        let loss = |attention: &MultiHeadAttention, input: &Tensor| {
+            let mut attention = attention.clone();
+            weighted_sum(&attention.forward(input).unwrap(), r.data())
+        };
+
+        attention.forward(&input).unwrap();
+        assert!(matches!(
+            attention.cache.as_ref().unwrap().scores,
+            Scores::Full {
+                dropout_mask: Some(_),
+                ..
+            }
+        ));
+        let grad_input = attention.backward(&r).unwrap();
+
+        let eps = 1e-2;
+        for i in 0..input.len() {
+            let mut plus = input.clone();
+            plus.data_mut()[i] += eps;
+            let mut minus = input.clone();
+            minus.data_mut()[i] -= eps;
+
+            let numeric = (loss(&initial, &plus) - loss(&initial, &minus)) / (2.0 * eps);
+            assert!(
+                (numeric - grad_input.data()[i]).abs() < 1e-2,
+                "input[{i}]: numeric {numeric}, analytic {}",
+                grad_input.data()[i]
+            );
+        }
+
+        let grad_query = attention.w_query().weight().grad();
+        for i in 0..grad_query.len() {
+            let mut plus = initial.clone();
+            plus.w_query_mut().weight_mut().value_mut().data_mut()[i] += eps;
+            let mut minus = initial.clone();
+            minus.w_query_mut().weight_mut().value_mut().data_mut()[i] -= eps;
+
+            let numeric = (loss(&plus, &input) - loss(&minus, &input)) / (2.0 * eps);
+            assert!(
+                (numeric - grad_query.data()[i]).abs() < 1e-2,
+                "w_query[{i}]: numeric {numeric}, analytic {}",
+                grad_query.data()[i]
+            );
+        }
+
+        // 推理模式下没有dropout，两次输出相同
+        attention.set_training(false);
+        let output = attention.forward(&input).unwrap();
+        assert_eq!(attention.forward(&input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_multi_head_attention_kv_cache() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut attention = MultiHeadAttention::new(3, 4, 6, 0.0, 2, true, &mut rng);
+        let input = Tensor::randn(&[2, 6, 3], 1.0, &mut rng);
+        let expected = attention.forward(&input).unwrap();
+
+        // 先输入前4个token，再逐个输入剩下的token，结果与一次性计算相同
+        let mut cache = KvCache::new();
+        let rows = |start: usize, end: usize| {
+            let data = input
+                .data()
+                .chunks(18)
+                .flat_map(|sequence| sequence[start * 3..end * 3].to_vec())
+                .collect();
+            Tensor::new(data, &[2, end - start, 3])
+        };
+        let mut outputs = vec![attention
+            .forward_with_cache(&rows(0, 4), &mut cache)
+            .unwrap()];
+        for t in 4..6 {
+            outputs.push(
+                attention
+                    .forward_with_cache(&rows(t, t + 1), &mut cache)
+                    .unwrap(),
+            );
+        }
+        assert_eq!(cache.len(), 6);
+
+        for b in 0..2 {
+            let cached = outputs
+                .iter()
+                .flat_map(|output| {
+                    let size = output.len() / 2;
+                    output.data()[b * size..(b + 1) * size].to_vec()
+                })
+                .collect::<Vec<_>>();
+            for (x, y) in cached.iter().zip(&expected.data()[b * 24..(b + 1) * 24]) {
+                assert!((x - y).abs() < 1e-5, "{x} vs {y}");
+            }
+        }
+
+        assert!(attention
+            .forward_with_cache(&rows(0, 1), &mut cache)
+            .is_err());
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_multi_head_attention_rope() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut attention =
+            MultiHeadAttention::new(3, 4, 8, 0.0, 2, true, &mut rng).with_rope(10000.0);
+        let input = Tensor::randn(&[1, 5, 3], 1.0, &mut rng);
+        let r = Tensor::randn(&[1, 5, 4], 1.0, &mut rng);
+        let expected = attention.forward(&input).unwrap();
+        let grad_input = attention.backward(&r).unwrap();
+
+        let loss = |input: &Tensor| {
+            let mut attention = attention.clone();
+            weighted_sum(&attention.forward(input).unwrap(), r.data())
+        };
+        let eps = 1e-2;
+        for i in 0..input.len() {
+            let mut plus = input.clone();
+            plus.data_mut()[i] += eps;
+            let mut minus = input.clone();
+            minus.data_mut()[i] -= eps;
+
+            let numeric = (loss(&plus) - loss(&minus)) / (2.0 * eps);
+            assert!(
+                (numeric - grad_input.data()[i]).abs() < 1e-2,
+                "input[{i}]: numeric {numeric}, analytic {}",
+                grad_input.data()[i]
+            );
+        }
+
+        // 逐个token输入时位置从缓存长度开始
+        let mut cache = KvCache::new();
+        let mut cached = vec![];
+        for row in input.data().chunks(3) {
+            let output = attention
+                .forward_with_cache(&Tensor::new(row.to_vec(), &[1, 1, 3]), &mut cache)
+                .unwrap();
+            cached.extend(output.into_data());
+        }
+        for (x, y) in cached.iter().zip(expected.data()) {
+            assert!((x - y).abs() < 1e-5, "{x} vs {y}");
+        }
+    }
+
+    #[test]
+    fn test_multi_head_attention_sliding_window() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut attention =
+            MultiHeadAttention::new(3, 4, 8, 0.0, 2, true, &mut rng).with_sliding_window(3);
+        let input = Tensor::randn(&[1, 6, 3], 1.0, &mut rng);
+        let expected = attention.forward(&input).unwrap();
+
+        // 第i行只有最后3个位置的权重不为0
+        let weights = attention.attention_weights().unwrap();
+        for (i, row) in weights.data()[..36].chunks(6).enumerate() {
+            for (j, w) in row.iter().enumerate() {
+                assert_eq!(*w > 0.0, j <= i && j + 3 > i, "weights[{i}][{j}] = {w}");
+            }
+        }
+
+        // KV缓存只保留窗口内的token，位置继续增加
+        let mut cache = KvCache::new();
+        let mut cached = attention
+            .forward_with_cache(
+                &Tensor::new(input.data()[..6].to_vec(), &[1, 2, 3]),
+                &mut cache,
+            )
+            .unwrap()
+            .into_data();
+        for row in input.data()[6..].chunks(3) {
+            let output = attention
+                .forward_with_cache(&Tensor::new(row.to_vec(), &[1, 1, 3]), &mut cache)
+                .unwrap();
+            cached.extend(output.into_data());
+            assert!(cache.stored_len() <= 2);
+        }
+        assert_eq!(cache.len(), 6);
+        for (x, y) in cached.iter().zip(expected.data()) {
+            assert!((x - y).abs() < 1e-5, "{x} vs {y}");
+        }
+    }
+
+    #[test]
+    fn test_grouped_query_attention() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut attention = MultiHeadAttention::new(3, 8, 8, 0.0, 4, true, &mut rng)
+            .with_kv_heads(2, &mut rng)
+            .with_rope(10000.0);
+        assert_eq!(attention.num_kv_heads(), 2);
+        assert_eq!(attention.w_key().weight().shape(), [4, 3]);
+        let input = Tensor::randn(&[2, 5, 3], 1.0, &mut rng);
+        let r = Tensor::randn(&[2, 5, 8], 1.0, &mut rng);
+        let expected = attention.forward(&input).unwrap();
+        let grad_input = attention.backward(&r).unwrap();
+
+        let loss = |attention: &MultiHeadAttention, input: &Tensor| {
+            let mut attention = attention.clone();
+            weighted_sum(&attention.forward(input).unwrap(), r.data())
+        };
+        let eps = 1e-2;
+        for i in 0..input.len() {
+            let mut plus = input.clone();
+            plus.data_mut()[i] += eps;
+            let mut minus = input.clone();
+            minus.data_mut()[i] -= eps;
+
+            let numeric = (loss(&attention, &plus) - loss(&attention, &minus)) / (2.0 * eps);
+            assert!(
+                (numeric - grad_input.data()[i]).abs() < 1e-2,
+                "input[{i}]: numeric {numeric}, analytic {}",
+                grad_input.data()[i]
+            );
+        }
+
+        // 共享的K投影的梯度来自同一组的两个Q头
+        let grad_key = attention.w_key().weight().grad().clone();
+        for i in 0..grad_key.len() {
+            let mut plus = attention.clone();
+            plus.w_key_mut().weight_mut().value_mut().data_mut()[i] += eps;
+            let mut minus = attention.clone();
+            minus.w_key_mut().weight_mut().value_mut().data_mut()[i] -= eps;
+
+            let numeric = (loss(&plus, &input) - loss(&minus, &input)) / (2.0 * eps);
+            assert!(
+                (numeric - grad_key.data()[i]).abs() < 1e-2,
+                "W_key[{i}]: numeric {numeric}, analytic {}",
+                grad_key.data()[i]
+            );
+        }
+
+        // KV缓存只保存2个头
+        let mut cache = KvCache::new();
+        let mut cached = vec![vec![]; 2];
+        for t in 0..5 {
+            let rows = (0..2)
+                .flat_map(|b| input.data()[(b * 5 + t) * 3..(b * 5 + t + 1) * 3].to_vec())
+                .collect();
+            let output = attention
+                .forward_with_cache(&Tensor::new(rows, &[2, 1, 3]), &mut cache)
+                .unwrap();
+            for (b, row) in output.data().chunks(8).enumerate() {
+                cached[b].extend_from_slice(row);
+            }
+        }
+        assert_eq!(cache.num_heads(), 2 * 2);
+        assert_eq!(cache.keys(0).len(), 5 * 2);
+        for (x, y) in cached.concat().iter().zip(expected.data()) {
+            assert!((x - y).abs() < 1e-5, "{x} vs {y}");
+        }
+    }
+}
@@ -0,0 +1,501 @@
+use crate::attention::Mask;
+use crate::dropout::{apply_mask, bernoulli_mask};
+use crate::error::Result;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::Range;
+use tensor::gemm;
+
+// 分块注意力（FlashAttention的思路）：Q和K按`chunk`分块，每次只计算`(chunk, chunk)`的分数，
+// 用在线softmax累加输出，不需要保存`(T, T)`的注意力矩阵。反向传播时根据每行的logsumexp重新计算权重
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Chunking {
+    pub chunk: usize,
+    pub mask: Mask,
+    // 最前面的`padding`个key被屏蔽，批量生成时使用
+    pub padding: usize,
+    pub dropout: Option<TileDropout>,
+}
+
+// 注意力权重上的dropout，每个`(行块, 列块)`的掩码从`seed`派生，反向传播时重新生成相同的掩码
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TileDropout {
+    p: f32,
+    seed: u64,
+}
+
+impl TileDropout {
+    pub fn new(p: f32, seed: u64) -> Self {
+        TileDropout { p, seed }
+    }
+
+    // 每个头使用不同的掩码
+    pub fn for_head(self, head: usize) -> Self {
+        TileDropout {
+            seed: mix(&(self.seed, head)),
+            ..self
+        }
+    }
+
+    fn mask(self, row_block: usize, col_block: usize, len: usize) -> Vec<f32> {
+        let mut rng = StdRng::seed_from_u64(mix(&(self.seed, row_block, col_block)));
+        bernoulli_mask(&mut rng, self.p, len)
+    }
+}
+
+fn mix(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// 单个头的分块划分，`past`个key在query之前
+struct Blocks<'a> {
+    queries: &'a [f32],
+    keys: &'a [f32],
+    dim: usize,
+    past: usize,
+    chunking: &'a Chunking,
+}
+
+impl Blocks<'_> {
+    fn q_len(&self) -> usize {
+        self.queries.len() / self.dim
+    }
+
+    fn kv_len(&self) -> usize {
+        self.keys.len() / self.dim
+    }
+
+    fn row_blocks(&self) -> impl Iterator<Item = Range<usize>> + use<> {
+        let (q_len, chunk) = (self.q_len(), self.chunking.chunk);
+        (0..q_len)
+            .step_by(chunk)
+            .map(move |start| start..(start + chunk).min(q_len))
+    }
+
+    // 跳过这些行都看不到的列块
+    fn col_blocks(&self, rows: &Range<usize>) -> impl Iterator<Item = Range<usize>> + use<> {
+        let (kv_len, chunk, mask) = (self.kv_len(), self.chunking.chunk, self.chunking.mask);
+        let first = mask.visible(self.past + rows.start, kv_len);
+        let last = mask.visible(self.past + rows.end - 1, kv_len);
+        let (lo, hi) = (first.start.max(self.chunking.padding), last.end.min(kv_len));
+        (0..kv_len)
+            .step_by(chunk)
+            .map(move |start| start..(start + chunk).min(kv_len))
+            .filter(move |cols| cols.end > lo && cols.start < hi)
+    }
+
+    // 缩放后的分数`(rows, cols)`，看不到的位置为`-inf`
+    fn scores(&self, rows: &Range<usize>, cols: &Range<usize>) -> Result<Vec<f32>> {
+        let (dim, n) = (self.dim, cols.len());
+        let mut scores = vec![0.0; rows.len() * n];
+        gemm(
+            &self.queries[rows.start * dim..rows.end * dim],
+            false,
+            &self.keys[cols.start * dim..cols.end * dim],
+            true,
+            &mut scores,
+            rows.len(),
+            dim,
+            n,
+        )?;
+
+        let scale = 1.0 / (dim as f32).sqrt();
+        for (r, row) in scores.chunks_mut(n).enumerate() {
+            let visible = self
+                .chunking
+                .mask
+                .visible(self.past + rows.start + r, self.kv_len());
+            let visible = visible.start.max(self.chunking.padding)..visible.end;
+            for (j, x) in cols.clone().zip(row.iter_mut()) {
+                *x = if visible.contains(&j) {
+                    *x * scale
+                } else {
+                    f32::NEG_INFINITY
+                };
+            }
+        }
+        Ok(scores)
+    }
+
+    fn tile_mask(&self, rows: &Range<usize>, cols: &Range<usize>) -> Option<Vec<f32>> {
+        let chunk = self.chunking.chunk;
+        self.chunking.dropout.map(|dropout| {
+            dropout.mask(
+                rows.start / chunk,
+                cols.start / chunk,
+                rows.len() * cols.len(),
+            )
+        })
+    }
+}
+
+// 单个头的注意力，最后`q_len`个key是query自己。`output`为`(q_len, dim)`，
+// `lse`保存每行的`log Σ exp(score)`，完全被屏蔽的行输出0，`lse`为`-inf`
+pub(crate) fn attend_chunked(
+    queries: &[f32],
+    keys: &[f32],
+    values: &[f32],
+    output: &mut [f32],
+    lse: &mut [f32],
+    dim: usize,
+    chunking: &Chunking,
+) -> Result<()> {
+    let (q_len, kv_len) = (queries.len() / dim, keys.len() / dim);
+    let blocks = Blocks {
+        queries,
+        keys,
+        dim,
+        past: kv_len - q_len,
+        chunking,
+    };
+
+    output.fill(0.0);
+    for rows in blocks.row_blocks() {
+        let mut max = vec![f32::NEG_INFINITY; rows.len()];
+        let mut sum = vec![0.0; rows.len()];
+        let acc = &mut output[rows.start * dim..rows.end * dim];
+
+        for cols in blocks.col_blocks(&rows) {
+            let mut weights = blocks.scores(&rows, &cols)?;
+            // 在线softmax：最大值变大时，之前累加的结果乘上`exp(旧最大值 - 新最大值)`
+            for (r, row) in weights.chunks_mut(cols.len()).enumerate() {
+                let new_max = row.iter().copied().fold(max[r], f32::max);
+                if new_max == f32::NEG_INFINITY {
+                    row.fill(0.0);
+                    continue;
+                }
+                let correction = (max[r] - new_max).exp();
+                sum[r] *= correction;
+                acc[r * dim..(r + 1) * dim]
+                    .iter_mut()
+                    .for_each(|x| *x *= correction);
+                for x in row.iter_mut() {
+                    *x = (*x - new_max).exp();
+                    sum[r] += *x;
+                }
+                max[r] = new_max;
+            }
+
+            // 归一化使用dropout之前的权重
+            if let Some(mask) = blocks.tile_mask(&rows, &cols) {
+                apply_mask(&mut weights, &mask);
+            }
+            gemm(
+                &weights,
+                false,
+                &values[cols.start * dim..cols.end * dim],
+                false,
+                acc,
+                rows.len(),
+                cols.len(),
+                dim,
+            )?;
+        }
+
+        for (r, row) in acc.chunks_mut(dim).enumerate() {
+            if sum[r] > 0.0 {
+                row.iter_mut().for_each(|x| *x /= sum[r]);
+                lse[rows.start + r] = max[r] + sum[r].ln();
+            } else {
+                lse[rows.start + r] = f32::NEG_INFINITY;
+            }
+        }
+    }
+    Ok(())
+}
+
+// `attend_chunked`保存的结果，`output`和`lse`是前向计算的输出
+pub(crate) struct ChunkedInputs<'a> {
+    pub queries: &'a [f32],
+    pub keys: &'a [f32],
+    pub values: &'a [f32],
+    pub output: &'a [f32],
+    pub lse: &'a [f32],
+}
+
+// `attend_chunked`的反向传播，把梯度累加到`grad_*`。
+// dS = P ⊙ (dP - D)，其中`D_i = dO_i·O_i`，不需要完整的注意力矩阵
+pub(crate) fn attend_chunked_backward(
+    inputs: ChunkedInputs,
+    grad_output: &[f32],
+    grad_queries: &mut [f32],
+    grad_keys: &mut [f32],
+    grad_values: &mut [f32],
+    dim: usize,
+    chunking: &Chunking,
+) -> Result<()> {
+    let ChunkedInputs {
+        queries,
+        keys,
+        values,
+        output,
+        lse,
+    } = inputs;
+    let (q_len, kv_len) = (queries.len() / dim, keys.len() / dim);
+    let blocks = Blocks {
+        queries,
+        keys,
+        dim,
+        past: kv_len - q_len,
+        chunking,
+    };
+    let scale = 1.0 / (dim as f32).sqrt();
+
+    for rows in blocks.row_blocks() {
+        let row_range = rows.start * dim..rows.end * dim;
+        let grad_out = &grad_output[row_range.clone()];
+        let delta = grad_out
+            .chunks(dim)
+            .zip(output[row_range.clone()].chunks(dim))
+            .map(|(g, o)| g.iter().zip(o).map(|(g, o)| g * o).sum::<f32>())
+            .collect::<Vec<_>>();
+
+        for cols in blocks.col_blocks(&rows) {
+            let (n, col_range) = (cols.len(), cols.start * dim..cols.end * dim);
+            let mut weights = blocks.scores(&rows, &cols)?;
+            for (r, row) in weights.chunks_mut(n).enumerate() {
+                let lse = lse[rows.start + r];
+                for x in row.iter_mut() {
+                    *x = if lse == f32::NEG_INFINITY {
+                        0.0
+                    } else {
+                        (*x - lse).exp()
+                    };
+                }
+            }
+
+            // dV = (P ⊙ M)ᵀ·dO，dP = (dO·Vᵀ) ⊙ M
+            let mut grad_scores = vec![0.0; rows.len() * n];
+            gemm(
+                grad_out,
+                false,
+                &values[col_range.clone()],
+                true,
+                &mut grad_scores,
+                rows.len(),
+                dim,
+                n,
+            )?;
+            let mask = blocks.tile_mask(&rows, &cols);
+            let mut dropped = weights.clone();
+            if let Some(mask) = mask.as_ref() {
+                apply_mask(&mut dropped, mask);
+                apply_mask(&mut grad_scores, mask);
+            }
+            gemm(
+                &dropped,
+                true,
+                grad_out,
+                false,
+                &mut grad_values[col_range.clone()],
+                n,
+                rows.len(),
+                dim,
+            )?;
+
+            for ((r, grad), weight) in grad_scores.chunks_mut(n).enumerate().zip(weights.chunks(n))
+            {
+                for (g, a) in grad.iter_mut().zip(weight) {
+                    *g = a * (*g - delta[r]) * scale;
+                }
+            }
+
+            // dQ = dS·K，dK = dSᵀ·Q
+            gemm(
+                &grad_scores,
+                false,
+                &keys[col_range.clone()],
+                false,
+                &mut grad_queries[row_range.clone()],
+                rows.len(),
+                n,
+                dim,
+            )?;
+            gemm(
+                &grad_scores,
+                true,
+                &queries[row_range.clone()],
+                false,
+                &mut grad_keys[col_range],
+                n,
+                rows.len(),
+                dim,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attention::{attend, attend_backward, AttentionInputs};
+    use crate::Tensor;
+
+    #[test]
+    fn test_chunked_attention() {
+        let (seq_len, dim) = (7, 4);
+        let mut rng = StdRng::seed_from_u64(123);
+        let randn = |rng: &mut StdRng| Tensor::randn(&[seq_len, dim], 1.0, rng).into_data();
+        let (queries, keys, values) = (randn(&mut rng), randn(&mut rng), randn(&mut rng));
+        let grad_output = randn(&mut rng);
+
+        for mask in [Mask::None, Mask::Causal, Mask::SlidingWindow(3)] {
+            let mut weights = vec![0.0; seq_len * seq_len];
+            let mut expected = vec![0.0; seq_len * dim];
+            attend(
+                &queries,
+                &keys,
+                &values,
+                &mut weights,
+                &mut expected,
+                seq_len,
+                dim,
+                mask,
+                None,
+            )
+            .unwrap();
+            let mut expected_grads = vec![vec![0.0; seq_len * dim]; 3];
+            let [dq, dk, dv] = &mut expected_grads[..] else {
+                unreachable!()
+            };
+            attend_backward(
+                AttentionInputs {
+                    queries: &queries,
+                    keys: &keys,
+                    values: &values,
+                    weights: &weights,
+                    dropout_mask: None,
+                },
+                &grad_output,
+                dq,
+                dk,
+                dv,
+                seq_len,
+                dim,
+            )
+            .unwrap();
+
+            // 块大小不整除序列长度时最后一块较小
+            for chunk in [1, 3, 7, 16] {
+                let chunking = Chunking {
+                    chunk,
+                    mask,
+                    padding: 0,
+                    dropout: None,
+                };
+                let mut output = vec![0.0; seq_len * dim];
+                let mut lse = vec![0.0; seq_len];
+                attend_chunked(
+                    &queries,
+                    &keys,
+                    &values,
+                    &mut output,
+                    &mut lse,
+                    dim,
+                    &chunking,
+                )
+                .unwrap();
+                for (x, y) in output.iter().zip(&expected) {
+                    assert!((x - y).abs() < 1e-5, "{mask:?} chunk {chunk}: {x} vs {y}");
+                }
+
+                let mut grads = vec![vec![0.0; seq_len * dim]; 3];
+                let [dq, dk, dv] = &mut grads[..] else {
+                    unreachable!()
+                };
+                attend_chunked_backward(
+                    ChunkedInputs {
+                        queries: &queries,
+                        keys: &keys,
+                        values: &values,
+                        output: &output,
+                        lse: &lse,
+                    },
+                    &grad_output,
+                    dq,
+                    dk,
+                    dv,
+                    dim,
+                    &chunking,
+                )
+                .unwrap();
+                for (x, y) in grads.concat().iter().zip(expected_grads.concat()) {
+                    assert!((x - y).abs() < 1e-4, "{mask:?} chunk {chunk}: {x} vs {y}");
+                }
+            }
+        }
+
+        // dropout的掩码在反向传播时重新生成，与数值梯度一致
+        let chunking = Chunking {
+            chunk: 3,
+            mask: Mask::Causal,
+            padding: 1,
+            dropout: Some(TileDropout::new(0.3, 42)),
+        };
+        let forward = |queries: &[f32]| {
+            let mut output = vec![0.0; seq_len * dim];
+            let mut lse = vec![0.0; seq_len];
+            attend_chunked(
+                queries,
+                &keys,
+                &values,
+                &mut output,
+                &mut lse,
+                dim,
+                &chunking,
+            )
+            .unwrap();
+            (output, lse)
+        };
+        let (output, lse) = forward(&queries);
+        // 第一个token只能看到被屏蔽的填充位置
+        assert!(output[..dim].iter().all(|x| *x == 0.0));
+        assert_eq!(lse[0], f32::NEG_INFINITY);
+
+        let mut grads = vec![vec![0.0; seq_len * dim]; 3];
+        let [dq, dk, dv] = &mut grads[..] else {
+            unreachable!()
+        };
+        attend_chunked_backward(
+            ChunkedInputs {
+                queries: &queries,
+                keys: &keys,
+                values: &values,
+                output: &output,
+                lse: &lse,
+            },
+            &grad_output,
+            dq,
+            dk,
+            dv,
+            dim,
+            &chunking,
+        )
+        .unwrap();
+        let loss = |queries: &[f32]| {
+            let (output, _) = forward(queries);
+            output
+                .iter()
+                .zip(&grad_output)
+                .map(|(x, w)| x * w)
+                .sum::<f32>()
+        };
+        let eps = 1e-2;
+        for i in 0..queries.len() {
+            let mut plus = queries.clone();
+            plus[i] += eps;
+            let mut minus = queries.clone();
+            minus[i] -= eps;
+            let numeric = (loss(&plus) - loss(&minus)) / (2.0 * eps);
+            assert!(
+                (numeric - grads[0][i]).abs() < 1e-2,
+                "queries[{i}]: numeric {numeric}, analytic {}",
+                grads[0][i]
+            );
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::Tensor;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// 训练时以概率`p`把元素置0，其余元素乘以`1/(1-p)`保持期望不变；推理时直接返回输入
+#[derive(Debug, Clone)]
+pub struct Dropout {
+    p: f32,
+    training: bool,
+    rng: StdRng,
+    // 上一次`forward`的掩码，`None`表示没有丢弃
+    mask: Option<Vec<f32>>,
+}
+
+impl Dropout {
+    // 掩码的随机数生成器从`rng`派生，相同的种子得到相同的掩码
+    pub fn new(p: f32, rng: &mut impl Rng) -> Self {
+        assert!(
+            (0.0..1.0).contains(&p),
+            "Dropout probability must be in [0, 1)"
+        );
+        Dropout {
+            p,
+            training: true,
+            rng: StdRng::from_rng(rng),
+            mask: None,
+        }
+    }
+
+    pub fn p(&self) -> f32 {
+        self.p
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    pub fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    pub(crate) fn clear_cache(&mut self) {
+        self.mask = None;
+    }
+
+    pub(crate) fn saved_bytes(&self) -> usize {
+        self.mask.as_deref().map_or(0, size_of_val)
+    }
+
+    // 从掩码的随机数生成器取一个种子，即使推理模式也会推进随机数
+    pub(crate) fn next_seed(&mut self) -> u64 {
+        self.rng.random()
+    }
+
+    // 重新派生掩码的随机数生成器，用于复现训练过程
+    pub fn reseed(&mut self, rng: &mut impl Rng) {
+        self.rng = StdRng::from_rng(rng);
+    }
+
+    // 生成长度为`len`的掩码，元素为0或`1/(1-p)`。推理模式或`p`为0时返回`None`
+    pub(crate) fn sample_mask(&mut self, len: usize) -> Option<Vec<f32>> {
+        if !self.training || self.p == 0.0 {
+            return None;
+        }
+
+        Some(bernoulli_mask(&mut self.rng, self.p, len))
+    }
+
+    // 分块注意力不保存掩码，只保存生成掩码的种子。推理模式或`p`为0时返回`None`
+    pub(crate) fn sample_seed(&mut self) -> Option<u64> {
+        (self.training && self.p > 0.0).then(|| self.rng.random())
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Tensor {
+        self.mask = self.sample_mask(input.len());
+        let mut output = input.clone();
+        if let Some(mask) = self.mask.as_ref() {
+            apply_mask(output.data_mut(), mask);
+        }
+        output
+    }
+
+    pub fn backward(&self, grad_output: &Tensor) -> Tensor {
+        let mut grad_input = grad_output.clone();
+        if let Some(mask) = self.mask.as_ref() {
+            assert_eq!(
+                mask.len(),
+                grad_output.len(),
+                "Gradient does not match the last forward input"
+            );
+            apply_mask(grad_input.data_mut(), mask);
+        }
+        grad_input
+    }
+}
+
+// 以概率`p`为0，否则为`1/(1-p)`
+pub(crate) fn bernoulli_mask(rng: &mut impl Rng, p: f32, len: usize) -> Vec<f32> {
+    let scale = 1.0 / (1.0 - p);
+    (0..len)
+        .map(|_| if rng.random::<f32>() < p { 0.0 } else { scale })
+        .collect()
+}
+
+pub(crate) fn apply_mask(data: &mut [f32], mask: &[f32]) {
+    for (x, m) in data.iter_mut().zip(mask) {
+        *x *= m;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dropout() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut dropout = Dropout::new(0.5, &mut rng);
+
+        // 书中第3章：大约一半的元素被置0，其余变为2
+        let input = Tensor::new(vec![1.0; 1000], &[10, 100]);
+        let output = dropout.forward(&input);
+        let zeros = output.data().iter().filter(|x| **x == 0.0).count();
+        println!("zeros: {zeros}");
+        assert!(output.data().iter().all(|x| *x == 0.0 || *x == 2.0));
+        assert!((400..600).contains(&zeros));
+
+        let grad = dropout.backward(&input);
+        assert_eq!(grad, output);
+
+        dropout.set_training(false);
+        assert_eq!(dropout.forward(&input), input);
+        assert_eq!(dropout.backward(&input), input);
+
+        let mask = Dropout::new(0.1, &mut StdRng::seed_from_u64(1)).sample_mask(16);
+        let same = Dropout::new(0.1, &mut StdRng::seed_from_u64(1)).sample_mask(16);
+        assert_eq!(mask, same);
+
+        let mut dropout = Dropout::new(0.1, &mut rng);
+        dropout.reseed(&mut StdRng::seed_from_u64(1));
+        assert_eq!(dropout.sample_mask(16), mask);
+    }
+}
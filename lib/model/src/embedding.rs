@@ -0,0 +1,147 @@
+use crate::error::{invalid, shape_error, Result};
+use crate::{Param, Tensor};
+use rand::Rng;
+
+// 词嵌入层：`vocab_size × embed_dim`的矩阵，按token id取出对应的行
+#[derive(Debug, Clone)]
+pub struct Embedding {
+    weight: Param,
+    // 上一次`forward`的输入，`backward`时使用
+    token_ids: Vec<usize>,
+}
+
+impl Embedding {
+    // 与PyTorch的`nn.Embedding`相同，权重初始化为标准正态分布
+    pub fn new(vocab_size: usize, embed_dim: usize, rng: &mut impl Rng) -> Self {
+        Self::from_weight(Tensor::randn(&[vocab_size, embed_dim], 1.0, rng))
+    }
+
+    pub fn from_weight(weight: Tensor) -> Self {
+        assert_eq!(weight.shape().len(), 2, "Embedding weight must be 2D");
+        Embedding {
+            weight: Param::new(weight),
+            token_ids: vec![],
+        }
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.weight.shape()[0]
+    }
+
+    pub fn embed_dim(&self) -> usize {
+        self.weight.shape()[1]
+    }
+
+    pub fn weight(&self) -> &Tensor {
+        self.weight.value()
+    }
+
+    pub fn weight_mut(&mut self) -> &mut Tensor {
+        self.weight.value_mut()
+    }
+
+    pub fn grad(&self) -> &Tensor {
+        self.weight.grad()
+    }
+
+    pub fn param(&self) -> &Param {
+        &self.weight
+    }
+
+    pub fn param_mut(&mut self) -> &mut Param {
+        &mut self.weight
+    }
+
+    // 输入为`(B, T)`的token id，输出`(B, T, C)`
+    pub fn forward(&mut self, token_ids: &[Vec<usize>]) -> Result<Tensor> {
+        let output = self.apply(token_ids)?;
+        self.token_ids = token_ids.iter().flatten().copied().collect();
+        Ok(output)
+    }
+
+    // 只计算输出，不保存反向传播需要的输入
+    pub fn apply(&self, token_ids: &[Vec<usize>]) -> Result<Tensor> {
+        let batch = token_ids.len();
+        let seq_len = token_ids.first().map_or(0, |ids| ids.len());
+        if token_ids.iter().any(|ids| ids.len() != seq_len) {
+            return Err(shape_error!(
+                "All sequences in a batch must have the same length"
+            ));
+        }
+
+        let vocab_size = self.vocab_size();
+        let dim = self.embed_dim();
+        let mut output = Vec::with_capacity(batch * seq_len * dim);
+
+        for &id in token_ids.iter().flatten() {
+            if id >= vocab_size {
+                return Err(invalid!(
+                    "Token id {id} out of range for vocab size {vocab_size}"
+                ));
+            }
+            output.extend_from_slice(&self.weight.value().data()[id * dim..(id + 1) * dim]);
+        }
+
+        Ok(Tensor::new(output, &[batch, seq_len, dim]))
+    }
+
+    // 把输出的梯度累加到对应的行，同一个token出现多次时梯度相加。
+    // 多次调用会继续累加，直到`zero_grad`
+    pub fn backward(&mut self, grad_output: &Tensor) {
+        let dim = self.embed_dim();
+        assert_eq!(
+            grad_output.len(),
+            self.token_ids.len() * dim,
+            "Gradient does not match the last forward input"
+        );
+
+        let grad = self.weight.grad_mut().data_mut();
+        for (i, &id) in self.token_ids.iter().enumerate() {
+            let rows = &grad_output.data()[i * dim..(i + 1) * dim];
+            for (g, x) in grad[id * dim..(id + 1) * dim].iter_mut().zip(rows) {
+                *g += x;
+            }
+        }
+    }
+
+    pub fn zero_grad(&mut self) {
+        self.weight.zero_grad();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_embedding() {
+        let weight = Tensor::new((0..12).map(|x| x as f32).collect(), &[4, 3]);
+        let mut embedding = Embedding::from_weight(weight);
+
+        let output = embedding.forward(&[vec![2, 0], vec![2, 3]]).unwrap();
+        assert_eq!(output.shape(), [2, 2, 3]);
+        assert_eq!(
+            output.data(),
+            [6.0, 7.0, 8.0, 0.0, 1.0, 2.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0]
+        );
+
+        // id 2出现两次，梯度相加
+        embedding.backward(&Tensor::new(vec![1.0; 12], &[2, 2, 3]));
+        embedding.backward(&Tensor::new(vec![1.0; 12], &[2, 2, 3]));
+        println!("{:?}", embedding.grad());
+        assert_eq!(&embedding.grad().data()[6..9], [4.0, 4.0, 4.0]);
+        assert_eq!(&embedding.grad().data()[3..6], [0.0, 0.0, 0.0]);
+
+        embedding.zero_grad();
+        assert!(embedding.grad().data().iter().all(|g| *g == 0.0));
+
+        assert!(embedding.forward(&[vec![4]]).is_err());
+        assert!(embedding.forward(&[vec![0, 1], vec![0]]).is_err());
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let embedding = Embedding::new(50257, 8, &mut rng);
+        assert_eq!(embedding.weight().shape(), [50257, 8]);
+    }
+}
@@ -0,0 +1,129 @@
+use safetensors::SafeTensorError;
+use std::io;
+use std::path::PathBuf;
+use tensor::TensorError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ModelError {
+    #[error("Failed to read {}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("Failed to write {}", path.display())]
+    Write { path: PathBuf, source: io::Error },
+
+    // 文件能读取但内容无效，`kind`如`safetensors file`、`config`
+    #[error("Invalid {kind} {}", path.display())]
+    InvalidFile {
+        kind: &'static str,
+        path: PathBuf,
+        source: Box<ModelError>,
+    },
+
+    // `GptConfig`等超参数无效
+    #[error("{0}")]
+    Config(String),
+
+    // 输入或权重的形状与模型不匹配
+    #[error("{0}")]
+    Shape(String),
+
+    // 当前的设备、编译选项或模型结构不支持该操作
+    #[error("{0}")]
+    Unsupported(String),
+
+    #[error("{0}")]
+    Invalid(String),
+
+    // 矩阵乘法在GPU上出错
+    #[error(transparent)]
+    Tensor(#[from] TensorError),
+
+    #[error(transparent)]
+    Safetensors(#[from] SafeTensorError),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T, E = ModelError> = std::result::Result<T, E>;
+
+// 格式化为`ModelError`的各个变体，代替anyhow的`bail!`
+macro_rules! invalid {
+    ($($arg:tt)+) => {
+        $crate::error::ModelError::Invalid(format!($($arg)+))
+    };
+}
+pub(crate) use invalid;
+
+macro_rules! config_error {
+    ($($arg:tt)+) => {
+        $crate::error::ModelError::Config(format!($($arg)+))
+    };
+}
+pub(crate) use config_error;
+
+macro_rules! shape_error {
+    ($($arg:tt)+) => {
+        $crate::error::ModelError::Shape(format!($($arg)+))
+    };
+}
+pub(crate) use shape_error;
+
+macro_rules! unsupported {
+    ($($arg:tt)+) => {
+        $crate::error::ModelError::Unsupported(format!($($arg)+))
+    };
+}
+pub(crate) use unsupported;
+
+// 用于`map_err`，错误信息带上文件路径
+pub(crate) fn read_error(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> ModelError {
+    let path = path.into();
+    move |source| ModelError::Read { path, source }
+}
+
+pub(crate) fn write_error(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> ModelError {
+    let path = path.into();
+    move |source| ModelError::Write { path, source }
+}
+
+pub(crate) fn invalid_file<E: Into<ModelError>>(
+    kind: &'static str,
+    path: impl Into<PathBuf>,
+) -> impl FnOnce(E) -> ModelError {
+    let path = path.into();
+    move |source| ModelError::InvalidFile {
+        kind,
+        path,
+        source: Box::new(source.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as StdError;
+
+    #[test]
+    fn test_model_error() {
+        let err = io::Error::new(io::ErrorKind::NotFound, "missing");
+        let err = read_error("model.safetensors")(err);
+        println!("{err}");
+        assert_eq!(err.to_string(), "Failed to read model.safetensors");
+        assert!(StdError::source(&err).is_some());
+
+        let err = invalid_file::<ModelError>("config", "config.json")(config_error!(
+            "dropout must be in [0, 1), got {}",
+            1.5
+        ));
+        assert_eq!(err.to_string(), "Invalid config config.json");
+        assert_eq!(
+            StdError::source(&err).unwrap().to_string(),
+            "dropout must be in [0, 1), got 1.5"
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use crate::error::Result;
+use crate::param::prefixed;
+use crate::{Gelu, GeluApproximation, Linear, Param, Tensor};
+use rand::Rng;
+
+// 书中第4章的`FeedForward`：先扩展到4倍维度，经过GELU后再投影回来
+#[derive(Debug, Clone)]
+pub struct FeedForward {
+    fc1: Linear,
+    gelu: Gelu,
+    fc2: Linear,
+}
+
+impl FeedForward {
+    pub fn new(emb_dim: usize, rng: &mut impl Rng) -> Self {
+        Self::from_linears(
+            Linear::new(emb_dim, 4 * emb_dim, true, rng),
+            Linear::new(4 * emb_dim, emb_dim, true, rng),
+        )
+    }
+
+    pub fn from_linears(fc1: Linear, fc2: Linear) -> Self {
+        assert_eq!(
+            fc1.out_features(),
+            fc2.in_features(),
+            "Hidden dims of the two layers must match"
+        );
+        FeedForward {
+            fc1,
+            gelu: Gelu::new(GeluApproximation::Tanh),
+            fc2,
+        }
+    }
+
+    pub fn fc1(&self) -> &Linear {
+        &self.fc1
+    }
+
+    pub fn fc2(&self) -> &Linear {
+        &self.fc2
+    }
+
+    pub fn fc1_mut(&mut self) -> &mut Linear {
+        &mut self.fc1
+    }
+
+    pub fn fc2_mut(&mut self) -> &mut Linear {
+        &mut self.fc2
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor> {
+        let hidden = self.fc1.forward(input)?;
+        let hidden = self.gelu.forward(&hidden);
+        self.fc2.forward(&hidden)
+    }
+
+    // 只计算输出，不保存反向传播需要的中间结果
+    pub fn apply(&self, input: &Tensor) -> Result<Tensor> {
+        let hidden = self.fc1.apply(input)?;
+        let hidden = self.gelu.apply(&hidden);
+        self.fc2.apply(&hidden)
+    }
+
+    pub fn backward(&mut self, grad_output: &Tensor) -> Result<Tensor> {
+        let grad = self.fc2.backward(grad_output)?;
+        let grad = self.gelu.backward(&grad);
+        self.fc1.backward(&grad)
+    }
+
+    pub(crate) fn clear_cache(&mut self) {
+        self.fc1.clear_cache();
+        self.gelu.clear_cache();
+        self.fc2.clear_cache();
+    }
+
+    pub(crate) fn saved_bytes(&self) -> usize {
+        self.fc1.saved_bytes() + self.gelu.saved_bytes() + self.fc2.saved_bytes()
+    }
+
+    pub fn zero_grad(&mut self) {
+        self.fc1.zero_grad();
+        self.fc2.zero_grad();
+    }
+
+    // 书中用`nn.Sequential`实现，GELU是第1层
+    pub fn named_params(&self) -> Vec<(String, &Param)> {
+        let mut params = prefixed("layers.0", self.fc1.named_params());
+        params.extend(prefixed("layers.2", self.fc2.named_params()));
+        params
+    }
+
+    pub fn named_params_mut(&mut self) -> Vec<(String, &mut Param)> {
+        let mut params = prefixed("layers.0", self.fc1.named_params_mut());
+        params.extend(prefixed("layers.2", self.fc2.named_params_mut()));
+        params
+    }
+
+    pub fn named_linears(&self) -> Vec<(String, &Linear)> {
+        vec![
+            ("layers.0".to_string(), &self.fc1),
+            ("layers.2".to_string(), &self.fc2),
+        ]
+    }
+
+    pub fn named_linears_mut(&mut self) -> Vec<(String, &mut Linear)> {
+        vec![
+            ("layers.0".to_string(), &mut self.fc1),
+            ("layers.2".to_string(), &mut self.fc2),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_feed_forward() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut ff = FeedForward::new(768, &mut rng);
+        let output = ff
+            .forward(&Tensor::randn(&[2, 3, 768], 1.0, &mut rng))
+            .unwrap();
+        assert_eq!(output.shape(), [2, 3, 768]);
+        assert_eq!(ff.fc1().weight().shape(), [3072, 768]);
+
+        let mut ff = FeedForward::new(3, &mut rng);
+        let input = Tensor::randn(&[2, 3], 1.0, &mut rng);
+        let r = Tensor::randn(&[2, 3], 1.0, &mut rng);
+        let loss = |ff: &mut FeedForward, input: &Tensor| {
+            let output = ff.forward(input).unwrap();
+            output
+                .data()
+                .iter()
+                .zip(r.data())
+                .map(|(x, w)| x * w)
+                .sum::<f32>()
+        };
+
+        loss(&mut ff, &input);
+        let grad_input = ff.backward(&r).unwrap();
+        let eps = 1e-2;
+        for i in 0..input.len() {
+            let mut plus = input.clone();
+            plus.data_mut()[i] += eps;
+            let mut minus = input.clone();
+            minus.data_mut()[i] -= eps;
+
+            let numeric = (loss(&mut ff, &plus) - loss(&mut ff, &minus)) / (2.0 * eps);
+            println!("input[{i}]: {numeric} vs {}", grad_input.data()[i]);
+            assert!((numeric - grad_input.data()[i]).abs() < 1e-2);
+        }
+    }
+}
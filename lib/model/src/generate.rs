@@ -0,0 +1,79 @@
+use crate::error::{invalid, Result};
+use crate::{argmax, GPTModel};
+
+// 书中第4章的`generate_text_simple`：每一步只保留最后`context_size`个token，
+// 重新计算整个上下文后取最后一个位置logits最大的token
+pub fn generate_text_simple(
+    model: &GPTModel,
+    token_ids: &[Vec<usize>],
+    max_new_tokens: usize,
+    context_size: usize,
+) -> Result<Vec<Vec<usize>>> {
+    if context_size == 0 {
+        return Err(invalid!("context_size must be positive"));
+    }
+
+    let vocab_size = model.config().vocab_size;
+    let mut token_ids = token_ids.to_vec();
+    for _ in 0..max_new_tokens {
+        let context = token_ids
+            .iter()
+            .map(|ids| ids[ids.len().saturating_sub(context_size)..].to_vec())
+            .collect::<Vec<_>>();
+        let seq_len = context.first().map_or(0, |ids| ids.len());
+        if seq_len == 0 {
+            return Err(invalid!("Prompt must contain at least one token"));
+        }
+        let logits = model.forward_with_cache(&context, &mut model.new_kv_cache())?;
+        for (ids, sequence) in token_ids
+            .iter_mut()
+            .zip(logits.data().chunks(seq_len * vocab_size))
+        {
+            ids.push(argmax(&sequence[(seq_len - 1) * vocab_size..]));
+        }
+    }
+
+    Ok(token_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GptConfig, PosEncoding, SamplingConfig};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_generate_text_simple() {
+        let config = GptConfig {
+            vocab_size: 13,
+            context_length: 4,
+            emb_dim: 8,
+            n_heads: 2,
+            n_layers: 1,
+            dropout: 0.0,
+            qkv_bias: false,
+            pos_encoding: PosEncoding::Learned,
+            tie_weights: false,
+            sliding_window: None,
+            sliding_window_layers: vec![],
+            n_kv_heads: None,
+            attention_chunk_size: None,
+        };
+        let mut rng = StdRng::seed_from_u64(123);
+        let model = GPTModel::new(&config, &mut rng).unwrap();
+
+        let output = generate_text_simple(&model, &[vec![1, 2, 3], vec![4, 5, 6]], 6, 4).unwrap();
+        println!("{output:?}");
+        assert!(output.iter().all(|ids| ids.len() == 9));
+
+        // 与使用KV缓存的贪心解码结果相同
+        let greedy = model
+            .generate(&[4, 5, 6], 6, &SamplingConfig::greedy(), &mut rng)
+            .unwrap();
+        assert_eq!(output[1], greedy);
+
+        assert!(generate_text_simple(&model, &[vec![]], 1, 4).is_err());
+        assert!(generate_text_simple(&model, &[vec![1]], 1, 0).is_err());
+    }
+}
@@ -0,0 +1,553 @@
+use crate::error::{invalid, unsupported, write_error, ModelError, Result};
+use crate::{GPTModel, Linear, PosEncoding, Precision, Tensor};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+const GGUF_VERSION: u32 = 3;
+// 张量数据的对齐，与`general.alignment`的默认值相同
+const ALIGNMENT: usize = 32;
+// Q8_0和Q4_0每块32个数，共用一个f16的缩放系数
+const BLOCK_SIZE: usize = 32;
+
+// 导出时张量的类型，LayerNorm、偏置和位置嵌入始终为f32
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GgufType {
+    #[default]
+    F32,
+    F16,
+    Q8_0,
+    Q4_0,
+}
+
+impl GgufType {
+    // ggml_type
+    fn id(self) -> u32 {
+        match self {
+            GgufType::F32 => 0,
+            GgufType::F16 => 1,
+            GgufType::Q4_0 => 2,
+            GgufType::Q8_0 => 8,
+        }
+    }
+
+    // llama_ftype，对应`general.file_type`
+    fn file_type(self) -> u32 {
+        match self {
+            GgufType::F32 => 0,
+            GgufType::F16 => 1,
+            GgufType::Q4_0 => 2,
+            GgufType::Q8_0 => 7,
+        }
+    }
+
+    fn encode(self, data: &[f32]) -> Vec<u8> {
+        match self {
+            GgufType::F32 => data.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            GgufType::F16 => data
+                .iter()
+                .flat_map(|x| Precision::F16.to_bits(*x).to_le_bytes())
+                .collect(),
+            GgufType::Q8_0 => data.chunks(BLOCK_SIZE).flat_map(quantize_q8_0).collect(),
+            GgufType::Q4_0 => data.chunks(BLOCK_SIZE).flat_map(quantize_q4_0).collect(),
+        }
+    }
+}
+
+impl FromStr for GgufType {
+    type Err = ModelError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "f32" => Ok(GgufType::F32),
+            "f16" => Ok(GgufType::F16),
+            "q8_0" => Ok(GgufType::Q8_0),
+            "q4_0" => Ok(GgufType::Q4_0),
+            _ => Err(invalid!(
+                "Unknown GGUF type `{s}`, expected f32, f16, q8_0 or q4_0"
+            )),
+        }
+    }
+}
+
+// GGUF的metadata值，数组的元素类型由第一个元素决定
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+}
+
+impl GgufValue {
+    // gguf_type
+    fn type_id(&self) -> u32 {
+        match self {
+            GgufValue::U32(_) => 4,
+            GgufValue::I32(_) => 5,
+            GgufValue::F32(_) => 6,
+            GgufValue::Bool(_) => 7,
+            GgufValue::String(_) => 8,
+            GgufValue::Array(_) => 9,
+        }
+    }
+
+    fn write(&self, out: &mut impl Write) -> Result<()> {
+        match self {
+            GgufValue::U32(x) => out.write_all(&x.to_le_bytes())?,
+            GgufValue::I32(x) => out.write_all(&x.to_le_bytes())?,
+            GgufValue::F32(x) => out.write_all(&x.to_le_bytes())?,
+            GgufValue::Bool(x) => out.write_all(&[*x as u8])?,
+            GgufValue::String(s) => write_string(out, s)?,
+            GgufValue::Array(items) => {
+                let type_id = items
+                    .first()
+                    .map_or(GgufValue::U32(0).type_id(), Self::type_id);
+                if items.iter().any(|item| item.type_id() != type_id) {
+                    return Err(invalid!("GGUF arrays must have elements of the same type"));
+                }
+                out.write_all(&type_id.to_le_bytes())?;
+                out.write_all(&(items.len() as u64).to_le_bytes())?;
+                for item in items {
+                    item.write(out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct GgufTensor {
+    name: String,
+    tensor: Tensor,
+    dtype: GgufType,
+}
+
+// 按llama.cpp的`gpt2`架构导出，可以用llama.cpp/ollama推理。`metadata`为分词器等额外的键值，
+// 与架构相关的键由这里写入。线性层和词嵌入按`dtype`保存，行长度不是32的倍数时量化类型退回f16
+pub fn export_gguf(
+    model: &GPTModel,
+    path: impl AsRef<Path>,
+    dtype: GgufType,
+    metadata: &[(String, GgufValue)],
+) -> Result<()> {
+    let config = model.config();
+    if config.pos_encoding != PosEncoding::Learned
+        || config.kv_heads() != config.n_heads
+        || config.sliding_window.is_some()
+    {
+        return Err(unsupported!(
+            "GGUF export supports the GPT-2 architecture only (learned positions, full MHA)"
+        ));
+    }
+    if model.is_quantized() {
+        return Err(unsupported!(
+            "Export the f32 model, GGUF quantization is done during export"
+        ));
+    }
+    // LoRA合并后再导出，不修改原来的模型
+    let merged;
+    let model = match model.has_lora() {
+        true => {
+            let mut clone = model.clone();
+            clone.merge_lora();
+            merged = clone;
+            &merged
+        }
+        false => model,
+    };
+
+    let tensors = gpt2_tensors(model, dtype)?;
+    let norm_eps = model.final_norm().eps();
+    let mut kv = vec![
+        (
+            "general.architecture".to_string(),
+            GgufValue::String("gpt2".to_string()),
+        ),
+        (
+            "general.file_type".to_string(),
+            GgufValue::U32(dtype.file_type()),
+        ),
+        (
+            "general.alignment".to_string(),
+            GgufValue::U32(ALIGNMENT as u32),
+        ),
+        (
+            "gpt2.context_length".to_string(),
+            GgufValue::U32(config.context_length as u32),
+        ),
+        (
+            "gpt2.embedding_length".to_string(),
+            GgufValue::U32(config.emb_dim as u32),
+        ),
+        (
+            "gpt2.feed_forward_length".to_string(),
+            GgufValue::U32(4 * config.emb_dim as u32),
+        ),
+        (
+            "gpt2.block_count".to_string(),
+            GgufValue::U32(config.n_layers as u32),
+        ),
+        (
+            "gpt2.attention.head_count".to_string(),
+            GgufValue::U32(config.n_heads as u32),
+        ),
+        (
+            "gpt2.attention.layer_norm_epsilon".to_string(),
+            GgufValue::F32(norm_eps),
+        ),
+    ];
+    for (key, value) in metadata {
+        match kv.iter_mut().find(|(k, _)| k == key) {
+            Some((_, old)) => *old = value.clone(),
+            None => kv.push((key.clone(), value.clone())),
+        }
+    }
+
+    let mut header = b"GGUF".to_vec();
+    header.write_all(&GGUF_VERSION.to_le_bytes())?;
+    header.write_all(&(tensors.len() as u64).to_le_bytes())?;
+    header.write_all(&(kv.len() as u64).to_le_bytes())?;
+    for (key, value) in &kv {
+        write_string(&mut header, key)?;
+        header.write_all(&value.type_id().to_le_bytes())?;
+        value.write(&mut header)?;
+    }
+
+    // 维度从最内层开始，与PyTorch的形状相反。偏移相对于张量数据的开头
+    let data = tensors
+        .iter()
+        .map(|t| t.dtype.encode(t.tensor.data()))
+        .collect::<Vec<_>>();
+    let mut offset = 0;
+    for (t, bytes) in tensors.iter().zip(&data) {
+        write_string(&mut header, &t.name)?;
+        header.write_all(&(t.tensor.shape().len() as u32).to_le_bytes())?;
+        for dim in t.tensor.shape().iter().rev() {
+            header.write_all(&(*dim as u64).to_le_bytes())?;
+        }
+        header.write_all(&t.dtype.id().to_le_bytes())?;
+        header.write_all(&(offset as u64).to_le_bytes())?;
+        offset += bytes.len().next_multiple_of(ALIGNMENT);
+    }
+    header.resize(header.len().next_multiple_of(ALIGNMENT), 0);
+
+    let path = path.as_ref();
+    let write = || -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&header)?;
+        for bytes in &data {
+            out.write_all(bytes)?;
+            out.write_all(&vec![
+                0;
+                bytes.len().next_multiple_of(ALIGNMENT) - bytes.len()
+            ])?;
+        }
+        out.flush()
+    };
+    write().map_err(write_error(path))
+}
+
+// llama.cpp中`gpt2`的张量名，Q、K、V按行拼成`attn_qkv`
+fn gpt2_tensors(model: &GPTModel, dtype: GgufType) -> Result<Vec<GgufTensor>> {
+    let matrix = |name: String, tensor: Tensor| {
+        let row_len = tensor.shape().last().copied().unwrap_or(0);
+        let dtype = match dtype {
+            GgufType::Q8_0 | GgufType::Q4_0 if row_len % BLOCK_SIZE != 0 => GgufType::F16,
+            dtype => dtype,
+        };
+        GgufTensor {
+            name,
+            tensor,
+            dtype,
+        }
+    };
+    let vector = |name: String, tensor: Tensor| GgufTensor {
+        name,
+        tensor,
+        dtype: GgufType::F32,
+    };
+    // 没有偏置的层写入全0的偏置
+    let bias = |linear: &Linear| match linear.bias() {
+        Some(bias) => bias.value().clone(),
+        None => Tensor::zeros(&[linear.weight().shape()[0]]),
+    };
+    let Some(pos_emb) = model.pos_emb() else {
+        return Err(unsupported!(
+            "GGUF export requires learned position embeddings"
+        ));
+    };
+
+    let tok_emb = model.tok_emb().weight().clone();
+    let mut tensors = vec![
+        matrix("token_embd.weight".to_string(), tok_emb.clone()),
+        vector("position_embd.weight".to_string(), pos_emb.weight().clone()),
+    ];
+    for (i, block) in model.trf_blocks().iter().enumerate() {
+        let (att, ff) = (block.att(), block.ff());
+        let (q, k, v) = (att.w_query(), att.w_key(), att.w_value());
+        let concat = |tensors: [Tensor; 3]| {
+            let mut shape = tensors[0].shape().to_vec();
+            shape[0] *= 3;
+            let data = tensors.iter().flat_map(|t| t.data().to_vec()).collect();
+            Tensor::new(data, &shape)
+        };
+        let qkv_weight = concat([q, k, v].map(|linear| linear.weight().value().clone()));
+        let qkv_bias = concat([q, k, v].map(bias));
+
+        let blk = |name: &str| format!("blk.{i}.{name}");
+        tensors.extend([
+            vector(
+                blk("attn_norm.weight"),
+                block.norm1().scale().value().clone(),
+            ),
+            vector(blk("attn_norm.bias"), block.norm1().shift().value().clone()),
+            matrix(blk("attn_qkv.weight"), qkv_weight),
+            vector(blk("attn_qkv.bias"), qkv_bias),
+            matrix(
+                blk("attn_output.weight"),
+                att.out_proj().weight().value().clone(),
+            ),
+            vector(blk("attn_output.bias"), bias(att.out_proj())),
+            vector(
+                blk("ffn_norm.weight"),
+                block.norm2().scale().value().clone(),
+            ),
+            vector(blk("ffn_norm.bias"), block.norm2().shift().value().clone()),
+            matrix(blk("ffn_up.weight"), ff.fc1().weight().value().clone()),
+            vector(blk("ffn_up.bias"), bias(ff.fc1())),
+            matrix(blk("ffn_down.weight"), ff.fc2().weight().value().clone()),
+            vector(blk("ffn_down.bias"), bias(ff.fc2())),
+        ]);
+    }
+
+    // 共享权重时输出层也写入词嵌入，旧版本的llama.cpp要求有`output.weight`
+    let out_head = match model.out_head() {
+        Some(out_head) => out_head.weight().value().clone(),
+        None => tok_emb,
+    };
+    tensors.extend([
+        vector(
+            "output_norm.weight".to_string(),
+            model.final_norm().scale().value().clone(),
+        ),
+        vector(
+            "output_norm.bias".to_string(),
+            model.final_norm().shift().value().clone(),
+        ),
+        matrix("output.weight".to_string(), out_head),
+    ]);
+    Ok(tensors)
+}
+
+fn write_string(out: &mut impl Write, s: &str) -> Result<()> {
+    out.write_all(&(s.len() as u64).to_le_bytes())?;
+    out.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+// 与ggml的`quantize_row_q8_0`相同：`d = max|x| / 127`，`q = round(x / d)`
+fn quantize_q8_0(block: &[f32]) -> Vec<u8> {
+    let amax = block.iter().fold(0f32, |max, x| max.max(x.abs()));
+    let d = amax / 127.0;
+    let inv = if d != 0.0 { 1.0 / d } else { 0.0 };
+    let mut bytes = Precision::F16.to_bits(d).to_le_bytes().to_vec();
+    bytes.extend(block.iter().map(|x| (x * inv).round() as i8 as u8));
+    bytes
+}
+
+// 与ggml的`quantize_row_q4_0`相同：绝对值最大的数映射到-8，每个字节的低4位为前16个数，高4位为后16个数
+fn quantize_q4_0(block: &[f32]) -> Vec<u8> {
+    let max = block
+        .iter()
+        .copied()
+        .fold(0f32, |max, x| if x.abs() > max.abs() { x } else { max });
+    let d = max / -8.0;
+    let inv = if d != 0.0 { 1.0 / d } else { 0.0 };
+    let q = |x: f32| ((x * inv + 8.5) as u8).min(15);
+    let mut bytes = Precision::F16.to_bits(d).to_le_bytes().to_vec();
+    bytes.extend((0..BLOCK_SIZE / 2).map(|j| q(block[j]) | (q(block[j + BLOCK_SIZE / 2]) << 4)));
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GptConfig;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashMap;
+
+    struct Reader<'a> {
+        bytes: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> Reader<'a> {
+        fn take(&mut self, n: usize) -> &'a [u8] {
+            self.pos += n;
+            &self.bytes[self.pos - n..self.pos]
+        }
+
+        fn u32(&mut self) -> u32 {
+            u32::from_le_bytes(self.take(4).try_into().unwrap())
+        }
+
+        fn u64(&mut self) -> usize {
+            u64::from_le_bytes(self.take(8).try_into().unwrap()) as usize
+        }
+
+        fn string(&mut self) -> String {
+            let n = self.u64();
+            String::from_utf8(self.take(n).to_vec()).unwrap()
+        }
+
+        fn skip_value(&mut self, type_id: u32) {
+            match type_id {
+                4..=6 => self.pos += 4,
+                7 => self.pos += 1,
+                8 => {
+                    self.string();
+                }
+                9 => {
+                    let item_type = self.u32();
+                    for _ in 0..self.u64() {
+                        self.skip_value(item_type);
+                    }
+                }
+                _ => panic!("unexpected type {type_id}"),
+            }
+        }
+    }
+
+    // 张量名 -> (维度, 反量化后的数据)
+    type GgufTensors = HashMap<String, (Vec<usize>, Vec<f32>)>;
+
+    // 读回导出的文件：metadata的键和所有张量
+    fn read_gguf(bytes: &[u8]) -> (Vec<String>, GgufTensors) {
+        let mut reader = Reader { bytes, pos: 0 };
+        assert_eq!(reader.take(4), b"GGUF");
+        assert_eq!(reader.u32(), GGUF_VERSION);
+        let (n_tensors, n_kv) = (reader.u64(), reader.u64());
+
+        let mut keys = vec![];
+        for _ in 0..n_kv {
+            keys.push(reader.string());
+            let type_id = reader.u32();
+            reader.skip_value(type_id);
+        }
+
+        let mut infos = vec![];
+        for _ in 0..n_tensors {
+            let name = reader.string();
+            let n_dims = reader.u32();
+            let dims = (0..n_dims).map(|_| reader.u64()).collect::<Vec<_>>();
+            let type_id = reader.u32();
+            infos.push((name, dims, type_id, reader.u64()));
+        }
+        let start = reader.pos.next_multiple_of(ALIGNMENT);
+
+        let mut tensors = HashMap::new();
+        for (name, dims, type_id, offset) in infos {
+            assert_eq!(offset % ALIGNMENT, 0);
+            let len = dims.iter().product::<usize>();
+            let data = &bytes[start + offset..];
+            let values = match type_id {
+                0 => data[..4 * len]
+                    .chunks(4)
+                    .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                    .collect(),
+                1 => data[..2 * len]
+                    .chunks(2)
+                    .map(|b| Precision::F16.from_bits(u16::from_le_bytes([b[0], b[1]])))
+                    .collect(),
+                8 => data[..len / 32 * 34]
+                    .chunks(34)
+                    .flat_map(|b| {
+                        let d = Precision::F16.from_bits(u16::from_le_bytes([b[0], b[1]]));
+                        b[2..].iter().map(move |q| *q as i8 as f32 * d)
+                    })
+                    .collect(),
+                2 => data[..len / 32 * 18]
+                    .chunks(18)
+                    .flat_map(|b| {
+                        let d = Precision::F16.from_bits(u16::from_le_bytes([b[0], b[1]]));
+                        let low = b[2..].iter().map(move |q| ((q & 0xf) as f32 - 8.0) * d);
+                        let high = b[2..].iter().map(move |q| ((q >> 4) as f32 - 8.0) * d);
+                        low.chain(high).collect::<Vec<_>>()
+                    })
+                    .collect(),
+                _ => panic!("unexpected tensor type {type_id}"),
+            };
+            tensors.insert(name, (dims, values));
+        }
+        (keys, tensors)
+    }
+
+    #[test]
+    fn test_export_gguf() -> Result<()> {
+        let config = GptConfig {
+            vocab_size: 40,
+            context_length: 8,
+            emb_dim: 32,
+            n_heads: 2,
+            n_layers: 2,
+            dropout: 0.0,
+            ..GptConfig::gpt2_small()
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let metadata = [(
+            "general.name".to_string(),
+            GgufValue::String("tiny".to_string()),
+        )];
+        let path = std::env::temp_dir().join("test_export.gguf");
+
+        for (dtype, tolerance) in [
+            (GgufType::F32, 0.0),
+            (GgufType::F16, 1e-3),
+            (GgufType::Q8_0, 1e-2),
+            (GgufType::Q4_0, 0.1),
+        ] {
+            export_gguf(&model, &path, dtype, &metadata)?;
+            let (keys, tensors) = read_gguf(&std::fs::read(&path)?);
+            assert!(keys.contains(&"gpt2.block_count".to_string()));
+            assert!(keys.contains(&"general.name".to_string()));
+            assert_eq!(tensors.len(), 2 + 12 * 2 + 3);
+
+            // `attn_qkv`的前`emb_dim`行为Q，未写入偏置的层为0
+            let (dims, qkv) = &tensors["blk.0.attn_qkv.weight"];
+            assert_eq!(dims, &[32, 96]);
+            let att = model.trf_blocks()[0].att();
+            let expected = [att.w_query(), att.w_key(), att.w_value()]
+                .iter()
+                .flat_map(|linear| linear.weight().value().data().to_vec())
+                .collect::<Vec<_>>();
+            let error = qkv
+                .iter()
+                .zip(&expected)
+                .map(|(x, y)| (x - y).abs())
+                .fold(0f32, f32::max);
+            println!("{dtype:?}: max error {error}");
+            assert!(error <= tolerance, "{dtype:?}: {error}");
+            assert!(tensors["blk.1.attn_qkv.bias"].1.iter().all(|x| *x == 0.0));
+            assert_eq!(tensors["position_embd.weight"].0, [32, 8]);
+            assert_eq!(
+                tensors["output_norm.weight"].1,
+                model.final_norm().scale().value().data()
+            );
+        }
+
+        assert_eq!("Q8_0".parse::<GgufType>()?, GgufType::Q8_0);
+        assert!("q5_k".parse::<GgufType>().is_err());
+        let rope = GptConfig {
+            pos_encoding: PosEncoding::Rope { theta: 10000.0 },
+            ..config
+        };
+        let model = GPTModel::new(&rope, &mut StdRng::seed_from_u64(123))?;
+        assert!(export_gguf(&model, &path, GgufType::F32, &[]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,1172 @@
+use crate::error::{config_error, invalid, unsupported, ModelError, Result};
+use crate::linear::{linear, linear_backward};
+use crate::param::prefixed;
+use crate::{
+    Dropout, Embedding, KvCache, LayerNorm, Linear, Param, PositionalEmbedding, SamplingConfig,
+    SavedTensor, Tensor, TransformerBlock,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use tensor::{with_device, Device};
+
+// 位置编码方式
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PosEncoding {
+    // GPT-2：可学习的绝对位置嵌入
+    #[default]
+    Learned,
+    // Llama：在注意力中旋转Q和K，`theta`通常为10000
+    Rope {
+        theta: f32,
+    },
+}
+
+// 对应书中的`GPT_CONFIG_124M`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GptConfig {
+    pub vocab_size: usize,
+    pub context_length: usize,
+    pub emb_dim: usize,
+    pub n_heads: usize,
+    pub n_layers: usize,
+    pub dropout: f32,
+    pub qkv_bias: bool,
+    // 旧的配置文件没有该字段，默认使用可学习的位置嵌入
+    #[serde(default)]
+    pub pos_encoding: PosEncoding,
+    // 输出层与词嵌入共享权重，与原始的GPT-2相同，124M模型少约38M参数
+    #[serde(default)]
+    pub tie_weights: bool,
+    // 滑动窗口注意力：每个token只关注最后`sliding_window`个token，KV缓存的大小不超过窗口
+    #[serde(default)]
+    pub sliding_window: Option<usize>,
+    // 使用滑动窗口的层，为空时所有层都使用。例如Gemma-2只在偶数层使用
+    #[serde(default)]
+    pub sliding_window_layers: Vec<usize>,
+    // K和V的头数，为`None`时与`n_heads`相同。Llama 2 70B等模型使用GQA，为1时即MQA
+    #[serde(default)]
+    pub n_kv_heads: Option<usize>,
+    // 分块计算注意力，每块`attention_chunk_size`个token，不保存`(T, T)`的注意力矩阵，
+    // 可以在CPU上使用2k~4k的上下文
+    #[serde(default)]
+    pub attention_chunk_size: Option<usize>,
+}
+
+impl GptConfig {
+    // 124M
+    pub fn gpt2_small() -> Self {
+        GptConfig {
+            vocab_size: 50257,
+            context_length: 1024,
+            emb_dim: 768,
+            n_heads: 12,
+            n_layers: 12,
+            dropout: 0.1,
+            qkv_bias: false,
+            pos_encoding: PosEncoding::Learned,
+            tie_weights: false,
+            sliding_window: None,
+            sliding_window_layers: vec![],
+            n_kv_heads: None,
+            attention_chunk_size: None,
+        }
+    }
+
+    // 355M
+    pub fn gpt2_medium() -> Self {
+        GptConfig {
+            emb_dim: 1024,
+            n_heads: 16,
+            n_layers: 24,
+            ..Self::gpt2_small()
+        }
+    }
+
+    // 774M
+    pub fn gpt2_large() -> Self {
+        GptConfig {
+            emb_dim: 1280,
+            n_heads: 20,
+            n_layers: 36,
+            ..Self::gpt2_small()
+        }
+    }
+
+    pub fn uses_sliding_window(&self, layer: usize) -> bool {
+        self.sliding_window.is_some()
+            && (self.sliding_window_layers.is_empty()
+                || self.sliding_window_layers.contains(&layer))
+    }
+
+    pub fn kv_heads(&self) -> usize {
+        self.n_kv_heads.unwrap_or(self.n_heads)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.vocab_size == 0 || self.context_length == 0 || self.emb_dim == 0 {
+            return Err(config_error!(
+                "vocab_size, context_length and emb_dim must be positive"
+            ));
+        }
+        if self.n_heads == 0 || !self.emb_dim.is_multiple_of(self.n_heads) {
+            return Err(config_error!(
+                "emb_dim {} must be divisible by n_heads {}",
+                self.emb_dim,
+                self.n_heads
+            ));
+        }
+        if self.kv_heads() == 0 || !self.n_heads.is_multiple_of(self.kv_heads()) {
+            return Err(config_error!(
+                "n_heads {} must be divisible by n_kv_heads {}",
+                self.n_heads,
+                self.kv_heads()
+            ));
+        }
+        if !(0.0..1.0).contains(&self.dropout) {
+            return Err(config_error!(
+                "dropout must be in [0, 1), got {}",
+                self.dropout
+            ));
+        }
+        if self.attention_chunk_size == Some(0) {
+            return Err(config_error!("attention_chunk_size must be positive"));
+        }
+        if self.sliding_window == Some(0) {
+            return Err(config_error!("sliding_window must be positive"));
+        }
+        if let Some(layer) = self
+            .sliding_window_layers
+            .iter()
+            .find(|layer| **layer >= self.n_layers)
+        {
+            return Err(config_error!(
+                "Sliding window layer {layer} out of range for {} layers",
+                self.n_layers
+            ));
+        }
+        if let PosEncoding::Rope { theta } = self.pos_encoding {
+            if !(self.emb_dim / self.n_heads).is_multiple_of(2) {
+                return Err(config_error!(
+                    "RoPE needs an even head dim, got {}",
+                    self.emb_dim / self.n_heads
+                ));
+            }
+            if theta <= 0.0 {
+                return Err(config_error!("RoPE theta must be positive, got {theta}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for GptConfig {
+    fn default() -> Self {
+        Self::gpt2_small()
+    }
+}
+
+// 书中第4章的`GPTModel`：词嵌入 + 位置嵌入 -> N个`TransformerBlock` -> LayerNorm -> 输出层
+#[derive(Debug, Clone)]
+pub struct GPTModel {
+    config: GptConfig,
+    tok_emb: Embedding,
+    // 使用RoPE时为`None`
+    pos_emb: Option<PositionalEmbedding>,
+    drop_emb: Dropout,
+    trf_blocks: Vec<TransformerBlock>,
+    final_norm: LayerNorm,
+    // 绑定权重时为`None`，logits使用`tok_emb`的权重计算
+    out_head: Option<Linear>,
+    // 绑定权重时上一次`forward`输出层的输入，`backward`时使用
+    head_input: Option<SavedTensor>,
+    // `forward`、`backward`和生成时矩阵乘法使用的设备
+    device: Device,
+}
+
+impl GPTModel {
+    pub fn new(config: &GptConfig, rng: &mut impl Rng) -> Result<Self> {
+        config.validate()?;
+
+        let tok_emb = Embedding::new(config.vocab_size, config.emb_dim, rng);
+        let pos_emb = (config.pos_encoding == PosEncoding::Learned)
+            .then(|| PositionalEmbedding::new(config.context_length, config.emb_dim, rng));
+        let drop_emb = Dropout::new(config.dropout, rng);
+        let trf_blocks = (0..config.n_layers)
+            .map(|layer| {
+                let block = TransformerBlock::new(
+                    config.emb_dim,
+                    config.context_length,
+                    config.n_heads,
+                    config.dropout,
+                    config.qkv_bias,
+                    rng,
+                );
+                let block = match config.n_kv_heads {
+                    Some(n_kv_heads) => block.with_kv_heads(n_kv_heads, rng),
+                    None => block,
+                };
+                let block = match config.attention_chunk_size {
+                    Some(chunk_size) => block.with_chunk_size(chunk_size),
+                    None => block,
+                };
+                let block = match config.pos_encoding {
+                    PosEncoding::Learned => block,
+                    PosEncoding::Rope { theta } => block.with_rope(theta),
+                };
+                match config.sliding_window {
+                    Some(window) if config.uses_sliding_window(layer) => {
+                        block.with_sliding_window(window)
+                    }
+                    _ => block,
+                }
+            })
+            .collect();
+
+        Ok(GPTModel {
+            config: config.clone(),
+            tok_emb,
+            pos_emb,
+            drop_emb,
+            trf_blocks,
+            final_norm: LayerNorm::new(config.emb_dim),
+            out_head: (!config.tie_weights)
+                .then(|| Linear::new(config.emb_dim, config.vocab_size, false, rng)),
+            head_input: None,
+            device: Device::Cpu,
+        })
+    }
+
+    pub fn config(&self) -> &GptConfig {
+        &self.config
+    }
+
+    pub fn device(&self) -> Device {
+        self.device
+    }
+
+    // 之后的训练和生成都在`device`上计算矩阵乘法，参数仍然保存在内存中
+    pub fn to_device(&mut self, device: Device) -> Result<()> {
+        if !device.is_available() {
+            return Err(unsupported!(
+                "{device:?} is not available, build with the cuda or metal feature"
+            ));
+        }
+        self.device = device;
+        Ok(())
+    }
+
+    pub fn tok_emb(&self) -> &Embedding {
+        &self.tok_emb
+    }
+
+    pub fn pos_emb(&self) -> Option<&PositionalEmbedding> {
+        self.pos_emb.as_ref()
+    }
+
+    pub fn trf_blocks(&self) -> &[TransformerBlock] {
+        &self.trf_blocks
+    }
+
+    pub fn final_norm(&self) -> &LayerNorm {
+        &self.final_norm
+    }
+
+    pub fn out_head(&self) -> Option<&Linear> {
+        self.out_head.as_ref()
+    }
+
+    pub fn tok_emb_mut(&mut self) -> &mut Embedding {
+        &mut self.tok_emb
+    }
+
+    pub fn pos_emb_mut(&mut self) -> Option<&mut PositionalEmbedding> {
+        self.pos_emb.as_mut()
+    }
+
+    pub fn trf_blocks_mut(&mut self) -> &mut [TransformerBlock] {
+        &mut self.trf_blocks
+    }
+
+    pub fn final_norm_mut(&mut self) -> &mut LayerNorm {
+        &mut self.final_norm
+    }
+
+    pub fn out_head_mut(&mut self) -> Option<&mut Linear> {
+        self.out_head.as_mut()
+    }
+
+    // 与PyTorch的`model.train()`相同：打开词嵌入、注意力权重和残差连接上的dropout
+    pub fn train(&mut self) {
+        self.set_training(true);
+    }
+
+    // 与PyTorch的`model.eval()`相同：关闭所有dropout
+    pub fn eval(&mut self) {
+        self.set_training(false);
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.drop_emb.is_training()
+    }
+
+    pub fn set_training(&mut self, training: bool) {
+        self.drop_emb.set_training(training);
+        self.trf_blocks
+            .iter_mut()
+            .for_each(|block| block.set_training(training));
+    }
+
+    // 每个`TransformerBlock`只保存输入，反向传播时重新计算block内的中间结果。
+    // 训练更深的模型或更长的上下文时使用，每步多一次前向传播
+    pub fn set_gradient_checkpointing(&mut self, checkpointing: bool) {
+        self.trf_blocks
+            .iter_mut()
+            .for_each(|block| block.set_checkpointing(checkpointing));
+    }
+
+    pub fn gradient_checkpointing(&self) -> bool {
+        self.trf_blocks
+            .iter()
+            .any(TransformerBlock::is_checkpointing)
+    }
+
+    // 所有dropout的掩码从`seed`重新派生，相同的种子得到相同的训练过程
+    pub fn seed_dropout(&mut self, seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        self.drop_emb.reseed(&mut rng);
+        self.trf_blocks
+            .iter_mut()
+            .for_each(|block| block.reseed(&mut rng));
+    }
+
+    // 输入为`(B, T)`的token id，返回`(B, T, vocab_size)`的logits
+    pub fn forward(&mut self, token_ids: &[Vec<usize>]) -> Result<Tensor> {
+        with_device(self.device, || {
+            let mut x = self.tok_emb.forward(token_ids)?;
+            if let Some(pos_emb) = self.pos_emb.as_mut() {
+                x = pos_emb.forward(&x)?;
+            }
+            let mut x = self.drop_emb.forward(&x);
+            for block in self.trf_blocks.iter_mut() {
+                x = block.forward(&x)?;
+            }
+            let x = self.final_norm.forward(&x)?;
+            match self.out_head.as_mut() {
+                Some(out_head) => out_head.forward(&x),
+                None => {
+                    let logits = linear(&x, self.tok_emb.param(), None)?;
+                    self.head_input = Some(SavedTensor::from_tensor(x));
+                    Ok(logits)
+                }
+            }
+        })
+    }
+
+    // 每一层一个空的KV缓存，用于`forward_with_cache`
+    pub fn new_kv_cache(&self) -> Vec<KvCache> {
+        vec![KvCache::new(); self.trf_blocks.len()]
+    }
+
+    // 推理时使用：第一次传入完整的prompt，之后每次只传入新生成的token，
+    // 位置从已缓存的长度开始。不使用dropout，也不保存反向传播需要的中间结果
+    pub fn forward_with_cache(
+        &self,
+        token_ids: &[Vec<usize>],
+        cache: &mut [KvCache],
+    ) -> Result<Tensor> {
+        self.forward_padded(token_ids, cache, &[])
+    }
+
+    // 批量生成时使用：第b个序列左侧有`padding[b]`个填充token，填充的位置不参与注意力，
+    // 位置从填充之后开始计算，结果与单独计算每个序列相同
+    pub fn forward_padded(
+        &self,
+        token_ids: &[Vec<usize>],
+        cache: &mut [KvCache],
+        padding: &[usize],
+    ) -> Result<Tensor> {
+        with_device(self.device, || {
+            if cache.len() != self.trf_blocks.len() {
+                return Err(invalid!(
+                    "Expected {} layer caches, got {}",
+                    self.trf_blocks.len(),
+                    cache.len()
+                ));
+            }
+
+            let start = cache.first().map_or(0, |cache| cache.len());
+            let mut x = self.tok_emb.apply(token_ids)?;
+            if let Some(pos_emb) = self.pos_emb.as_ref() {
+                x = pos_emb.apply_padded(&x, start, padding)?;
+            }
+            for (block, cache) in self.trf_blocks.iter().zip(cache.iter_mut()) {
+                x = block.forward_padded(&x, cache, padding)?;
+            }
+            let x = self.final_norm.apply(&x)?;
+            match self.out_head.as_ref() {
+                Some(out_head) => out_head.apply(&x),
+                None => linear(&x, self.tok_emb.param(), None),
+            }
+        })
+    }
+
+    // 书中第5章的`generate`：每次取最后一个位置的logits，按`sampling`采样下一个token，
+    // 返回prompt加上新生成的token。使用KV缓存，超过`context_length`时只保留最后`context_length`个token重新计算
+    pub fn generate(
+        &self,
+        token_ids: &[usize],
+        max_new_tokens: usize,
+        sampling: &SamplingConfig,
+        rng: &mut impl Rng,
+    ) -> Result<Vec<usize>> {
+        self.generate_stream(token_ids, max_new_tokens, sampling, rng, |_| {
+            Ok::<_, ModelError>(true)
+        })
+    }
+
+    // 与`generate`相同，每采样一个token就调用一次`on_token`，用于边生成边输出。
+    // `on_token`返回false时停止，返回的序列包括这个token；返回错误时生成失败，
+    // 错误类型由回调决定，需要能从`ModelError`转换
+    pub fn generate_stream<E: From<ModelError>>(
+        &self,
+        token_ids: &[usize],
+        max_new_tokens: usize,
+        sampling: &SamplingConfig,
+        rng: &mut impl Rng,
+        mut on_token: impl FnMut(usize) -> Result<bool, E>,
+    ) -> Result<Vec<usize>, E> {
+        if token_ids.is_empty() {
+            return Err(invalid!("Prompt must contain at least one token").into());
+        }
+
+        let (prompt_len, context_length) = (token_ids.len(), self.config.context_length);
+        let mut token_ids = token_ids.to_vec();
+        let mut cache = self.new_kv_cache();
+        let start = token_ids.len().saturating_sub(context_length);
+        let mut logits = self.forward_with_cache(&[token_ids[start..].to_vec()], &mut cache)?;
+
+        for _ in 0..max_new_tokens {
+            let vocab_size = self.config.vocab_size;
+            let last = &logits.data()[logits.len() - vocab_size..];
+            let next = sampling.sample(last, &token_ids[prompt_len..], rng);
+            token_ids.push(next);
+            if !on_token(next)? {
+                break;
+            }
+
+            logits = if cache[0].len() < context_length {
+                self.forward_with_cache(&[vec![next]], &mut cache)?
+            } else {
+                cache.iter_mut().for_each(KvCache::clear);
+                let start = token_ids.len() - context_length;
+                self.forward_with_cache(&[token_ids[start..].to_vec()], &mut cache)?
+            };
+        }
+
+        Ok(token_ids)
+    }
+
+    // 批量生成：prompt长度不同时左侧填充，一次前向计算所有序列。每个序列生成`stop_token`后停止，
+    // 返回的序列包括`stop_token`。结果与对每个prompt单独调用`generate`相同（随机采样时随机数的顺序不同）
+    pub fn generate_batch(
+        &self,
+        prompts: &[Vec<usize>],
+        max_new_tokens: usize,
+        sampling: &SamplingConfig,
+        stop_token: Option<usize>,
+        rng: &mut impl Rng,
+    ) -> Result<Vec<Vec<usize>>> {
+        if prompts.is_empty() || prompts.iter().any(|prompt| prompt.is_empty()) {
+            return Err(invalid!("Every prompt must contain at least one token"));
+        }
+
+        // 左侧用0填充，填充的位置被注意力掩码屏蔽，用什么token都可以
+        let longest = prompts.iter().map(Vec::len).max().unwrap_or(0);
+        let full_padding = prompts
+            .iter()
+            .map(|prompt| longest - prompt.len())
+            .collect::<Vec<_>>();
+        let mut slots = prompts
+            .iter()
+            .zip(&full_padding)
+            .map(|(prompt, pad)| [vec![0; *pad], prompt.clone()].concat())
+            .collect::<Vec<_>>();
+        let mut outputs = prompts.to_vec();
+        let mut finished = vec![false; prompts.len()];
+
+        let context_length = self.config.context_length;
+        let vocab_size = self.config.vocab_size;
+        let mut cache = self.new_kv_cache();
+        // 只保留最后`context_length`个位置，返回裁剪后的序列和剩下的填充长度
+        let crop = |slots: &[Vec<usize>]| {
+            let start = slots[0].len().saturating_sub(context_length);
+            let context = slots
+                .iter()
+                .map(|slot| slot[start..].to_vec())
+                .collect::<Vec<_>>();
+            let padding = full_padding
+                .iter()
+                .map(|pad| pad.saturating_sub(start))
+                .collect::<Vec<_>>();
+            (context, padding)
+        };
+        let (context, mut padding) = crop(&slots);
+        let mut logits = self.forward_padded(&context, &mut cache, &padding)?;
+
+        for _ in 0..max_new_tokens {
+            let seq_len = logits.shape()[1];
+            let mut next_tokens = vec![];
+            for (b, sequence) in logits.data().chunks(seq_len * vocab_size).enumerate() {
+                let last = &sequence[(seq_len - 1) * vocab_size..];
+                // 已经停止的序列继续输入`stop_token`，结果不使用
+                let next = match (finished[b], stop_token) {
+                    (true, Some(stop)) => stop,
+                    _ => {
+                        let next = sampling.sample(last, &outputs[b][prompts[b].len()..], rng);
+                        outputs[b].push(next);
+                        finished[b] = stop_token == Some(next);
+                        next
+                    }
+                };
+                slots[b].push(next);
+                next_tokens.push(vec![next]);
+            }
+            if finished.iter().all(|done| *done) {
+                break;
+            }
+
+            logits = if cache[0].len() < context_length {
+                self.forward_padded(&next_tokens, &mut cache, &padding)?
+            } else {
+                cache.iter_mut().for_each(KvCache::clear);
+                let context;
+                (context, padding) = crop(&slots);
+                self.forward_padded(&context, &mut cache, &padding)?
+            };
+        }
+
+        Ok(outputs)
+    }
+
+    // 上一次`forward`为反向传播保存的激活的字节数，不包括返回的logits。
+    // 在`with_precision`中`forward`时线性层和注意力的输入、注意力权重和GELU的输入存为16位
+    pub fn saved_bytes(&self) -> usize {
+        let head = match self.out_head.as_ref() {
+            Some(out_head) => out_head.saved_bytes(),
+            None => self
+                .head_input
+                .as_ref()
+                .map_or(0, SavedTensor::size_in_bytes),
+        };
+        self.drop_emb.saved_bytes()
+            + self
+                .trf_blocks
+                .iter()
+                .map(TransformerBlock::saved_bytes)
+                .sum::<usize>()
+            + self.final_norm.saved_bytes()
+            + head
+    }
+
+    // 输入为logits的梯度，累加所有参数的梯度
+    pub fn backward(&mut self, grad_logits: &Tensor) -> Result<()> {
+        with_device(self.device, || {
+            // 绑定权重时，输出层和词嵌入的梯度累加到同一个参数
+            let grad = match self.out_head.as_mut() {
+                Some(out_head) => out_head.backward(grad_logits)?,
+                None => {
+                    let input = self
+                        .head_input
+                        .as_ref()
+                        .expect("GPTModel::backward called before forward")
+                        .tensor();
+                    linear_backward(&input, grad_logits, self.tok_emb.param_mut(), None)?
+                }
+            };
+            let mut grad = self.final_norm.backward(&grad);
+            for block in self.trf_blocks.iter_mut().rev() {
+                grad = block.backward(&grad)?;
+            }
+            let grad = self.drop_emb.backward(&grad);
+            if let Some(pos_emb) = self.pos_emb.as_mut() {
+                pos_emb.backward(&grad);
+            }
+            self.tok_emb.backward(&grad);
+            Ok(())
+        })
+    }
+
+    pub fn zero_grad(&mut self) {
+        self.tok_emb.zero_grad();
+        if let Some(pos_emb) = self.pos_emb.as_mut() {
+            pos_emb.zero_grad();
+        }
+        self.trf_blocks
+            .iter_mut()
+            .for_each(|block| block.zero_grad());
+        self.final_norm.zero_grad();
+        if let Some(out_head) = self.out_head.as_mut() {
+            out_head.zero_grad();
+        }
+    }
+
+    // 所有可训练参数，名字与书中PyTorch模型的`state_dict`相同
+    pub fn named_params(&self) -> Vec<(String, &Param)> {
+        let mut params = vec![("tok_emb.weight".to_string(), self.tok_emb.param())];
+        if let Some(pos_emb) = self.pos_emb.as_ref() {
+            params.push(("pos_emb.weight".to_string(), pos_emb.param()));
+        }
+        for (i, block) in self.trf_blocks.iter().enumerate() {
+            params.extend(prefixed(&format!("trf_blocks.{i}"), block.named_params()));
+        }
+        params.extend(prefixed("final_norm", self.final_norm.named_params()));
+        if let Some(out_head) = self.out_head.as_ref() {
+            params.extend(prefixed("out_head", out_head.named_params()));
+        }
+        params
+    }
+
+    pub fn named_params_mut(&mut self) -> Vec<(String, &mut Param)> {
+        let mut params = vec![("tok_emb.weight".to_string(), self.tok_emb.param_mut())];
+        if let Some(pos_emb) = self.pos_emb.as_mut() {
+            params.push(("pos_emb.weight".to_string(), pos_emb.param_mut()));
+        }
+        for (i, block) in self.trf_blocks.iter_mut().enumerate() {
+            params.extend(prefixed(
+                &format!("trf_blocks.{i}"),
+                block.named_params_mut(),
+            ));
+        }
+        params.extend(prefixed("final_norm", self.final_norm.named_params_mut()));
+        if let Some(out_head) = self.out_head.as_mut() {
+            params.extend(prefixed("out_head", out_head.named_params_mut()));
+        }
+        params
+    }
+
+    // 所有线性层，名字与`named_params`的前缀相同
+    pub fn named_linears(&self) -> Vec<(String, &Linear)> {
+        let mut linears = vec![];
+        for (i, block) in self.trf_blocks.iter().enumerate() {
+            linears.extend(prefixed(&format!("trf_blocks.{i}"), block.named_linears()));
+        }
+        linears.extend(
+            self.out_head
+                .iter()
+                .map(|out_head| ("out_head".to_string(), out_head)),
+        );
+        linears
+    }
+
+    pub fn named_linears_mut(&mut self) -> Vec<(String, &mut Linear)> {
+        let mut linears = vec![];
+        for (i, block) in self.trf_blocks.iter_mut().enumerate() {
+            linears.extend(prefixed(
+                &format!("trf_blocks.{i}"),
+                block.named_linears_mut(),
+            ));
+        }
+        linears.extend(
+            self.out_head
+                .iter_mut()
+                .map(|out_head| ("out_head".to_string(), out_head)),
+        );
+        linears
+    }
+
+    // 训练后量化：所有线性层的权重转为int8，词嵌入和LayerNorm保持f32。
+    // 之后只能用于推理，`backward`会panic
+    pub fn quantize(&mut self) {
+        self.named_linears_mut()
+            .into_iter()
+            .for_each(|(_, linear)| linear.quantize());
+    }
+
+    pub fn is_quantized(&self) -> bool {
+        self.named_linears()
+            .iter()
+            .any(|(_, linear)| linear.is_quantized())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiny_config() -> GptConfig {
+        GptConfig {
+            vocab_size: 11,
+            context_length: 6,
+            emb_dim: 8,
+            n_heads: 2,
+            n_layers: 2,
+            dropout: 0.0,
+            qkv_bias: true,
+            pos_encoding: PosEncoding::Learned,
+            tie_weights: false,
+            sliding_window: None,
+            sliding_window_layers: vec![],
+            n_kv_heads: None,
+            attention_chunk_size: None,
+        }
+    }
+
+    #[test]
+    fn test_gpt_config() {
+        let config = GptConfig::gpt2_large();
+        assert_eq!(
+            (config.emb_dim, config.n_heads, config.n_layers),
+            (1280, 20, 36)
+        );
+        assert_eq!(config.vocab_size, GptConfig::default().vocab_size);
+        assert!(config.validate().is_ok());
+
+        let config = GptConfig {
+            n_heads: 5,
+            ..tiny_config()
+        };
+        assert!(config.validate().is_err());
+        assert!(GPTModel::new(&config, &mut StdRng::seed_from_u64(0)).is_err());
+
+        for n_kv_heads in [0, 3] {
+            let config = GptConfig {
+                n_kv_heads: Some(n_kv_heads),
+                ..tiny_config()
+            };
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_gpt_model() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let config = tiny_config();
+        let mut model = GPTModel::new(&config, &mut rng).unwrap();
+
+        let batch = [vec![1, 2, 3, 4], vec![5, 6, 7, 8]];
+        let logits = model.forward(&batch).unwrap();
+        println!("{:?}", logits.shape());
+        assert_eq!(logits.shape(), [2, 4, 11]);
+        assert_eq!(model.trf_blocks().len(), 2);
+
+        assert_eq!(model.device(), Device::Cpu);
+        assert!(model.to_device(Device::Cpu).is_ok());
+        if !Device::Cuda(0).is_available() {
+            assert!(model.to_device(Device::Cuda(0)).is_err());
+            assert_eq!(model.device(), Device::Cpu);
+        }
+
+        assert!(model.forward(&[vec![11]]).is_err());
+        assert!(model.forward(&[vec![0; 7]]).is_err());
+
+        // 对token 1的词嵌入做数值梯度检查，token 0没有出现，梯度为0，loss = Σ logits ⊙ r
+        let r = Tensor::randn(logits.shape(), 1.0, &mut rng);
+        let loss = |model: &mut GPTModel| {
+            let logits = model.forward(&batch).unwrap();
+            logits
+                .data()
+                .iter()
+                .zip(r.data())
+                .map(|(x, w)| x * w)
+                .sum::<f32>()
+        };
+
+        loss(&mut model);
+        model.backward(&r).unwrap();
+        let grad = model.tok_emb().grad().clone();
+        assert!(grad.data()[..8].iter().all(|g| *g == 0.0));
+
+        let eps = 1e-2;
+        for i in 8..16 {
+            model.tok_emb_mut().weight_mut().data_mut()[i] += eps;
+            let loss_plus = loss(&mut model);
+            model.tok_emb_mut().weight_mut().data_mut()[i] -= 2.0 * eps;
+            let loss_minus = loss(&mut model);
+            model.tok_emb_mut().weight_mut().data_mut()[i] += eps;
+
+            let numeric = (loss_plus - loss_minus) / (2.0 * eps);
+            assert!(
+                (numeric - grad.data()[i]).abs() < 5e-2,
+                "tok_emb[{i}]: numeric {numeric}, analytic {}",
+                grad.data()[i]
+            );
+        }
+
+        model.zero_grad();
+        assert!(model
+            .out_head()
+            .unwrap()
+            .weight()
+            .grad()
+            .data()
+            .iter()
+            .all(|g| *g == 0.0));
+    }
+
+    #[test]
+    fn test_gpt_model_kv_cache() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut model = GPTModel::new(&tiny_config(), &mut rng).unwrap();
+        let tokens = vec![3, 1, 4, 1, 5, 9];
+        let expected = model.forward(std::slice::from_ref(&tokens)).unwrap();
+
+        // prompt一次输入，之后逐个token输入
+        let mut cache = model.new_kv_cache();
+        let mut logits = model
+            .forward_with_cache(&[tokens[..3].to_vec()], &mut cache)
+            .unwrap()
+            .into_data();
+        for &token in &tokens[3..] {
+            let next = model
+                .forward_with_cache(&[vec![token]], &mut cache)
+                .unwrap();
+            assert_eq!(next.shape(), [1, 1, 11]);
+            logits.extend(next.into_data());
+        }
+
+        for (x, y) in logits.iter().zip(expected.data()) {
+            assert!((x - y).abs() < 1e-4, "{x} vs {y}");
+        }
+
+        // 超过`context_length`
+        assert!(model.forward_with_cache(&[vec![0]], &mut cache).is_err());
+        assert!(model.forward_with_cache(&[vec![0]], &mut []).is_err());
+    }
+
+    #[test]
+    fn test_gpt_model_generate() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let model = GPTModel::new(&tiny_config(), &mut rng).unwrap();
+
+        // 生成的长度超过`context_length`时裁剪上下文
+        let greedy = model
+            .generate(&[1, 2, 3, 4], 5, &SamplingConfig::greedy(), &mut rng)
+            .unwrap();
+        println!("{greedy:?}");
+        assert_eq!(greedy.len(), 9);
+        assert_eq!(greedy[..4], [1, 2, 3, 4]);
+        assert_eq!(
+            model
+                .generate(&[1, 2, 3, 4], 5, &SamplingConfig::greedy(), &mut rng)
+                .unwrap(),
+            greedy
+        );
+
+        // 与不使用缓存、每次重新计算整个上下文的结果相同
+        let mut uncached = model.clone();
+        let mut token_ids = vec![1, 2, 3, 4];
+        for _ in 0..5 {
+            let start = token_ids.len().saturating_sub(6);
+            let logits = uncached.forward(&[token_ids[start..].to_vec()]).unwrap();
+            token_ids.push(crate::argmax(&logits.data()[logits.len() - 11..]));
+        }
+        assert_eq!(token_ids, greedy);
+
+        // 流式生成按顺序给出每个新token，回调返回false时提前停止
+        let mut streamed = vec![];
+        let greedy_config = SamplingConfig::greedy();
+        model
+            .generate_stream(&[1, 2, 3, 4], 5, &greedy_config, &mut rng, |token| {
+                streamed.push(token);
+                Ok::<_, ModelError>(true)
+            })
+            .unwrap();
+        assert_eq!(streamed, greedy[4..]);
+        let stopped = model
+            .generate_stream(&[1, 2, 3, 4], 5, &greedy_config, &mut rng, |_| {
+                Ok::<_, ModelError>(false)
+            })
+            .unwrap();
+        assert_eq!(stopped, greedy[..5]);
+        let result = model.generate_stream(&[1, 2, 3, 4], 5, &greedy_config, &mut rng, |_| {
+            Err(invalid!("stop"))
+        });
+        assert!(result.is_err());
+
+        let sampling = SamplingConfig {
+            temperature: 1.4,
+            top_k: Some(5),
+            top_p: Some(0.9),
+            repetition_penalty: 1.2,
+            ..SamplingConfig::default()
+        };
+        let sample = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            model.generate(&[1], 8, &sampling, &mut rng).unwrap()
+        };
+        assert_eq!(sample(7), sample(7));
+
+        let greedy = SamplingConfig::greedy();
+        assert!(model.generate(&[], 1, &greedy, &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_gpt_model_rope() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let config = GptConfig {
+            pos_encoding: PosEncoding::Rope { theta: 10000.0 },
+            ..tiny_config()
+        };
+        let mut model = GPTModel::new(&config, &mut rng).unwrap();
+        assert!(model.pos_emb().is_none());
+        assert!(model.trf_blocks()[0].att().rope().is_some());
+        assert!(model
+            .named_params()
+            .iter()
+            .all(|(name, _)| !name.starts_with("pos_emb")));
+
+        let tokens = vec![3, 1, 4, 1, 5, 9];
+        let expected = model.forward(std::slice::from_ref(&tokens)).unwrap();
+        let mut cache = model.new_kv_cache();
+        let mut logits = vec![];
+        for &token in &tokens {
+            let next = model
+                .forward_with_cache(&[vec![token]], &mut cache)
+                .unwrap();
+            logits.extend(next.into_data());
+        }
+        for (x, y) in logits.iter().zip(expected.data()) {
+            assert!((x - y).abs() < 1e-4, "{x} vs {y}");
+        }
+        assert!(model.forward(&[vec![0; 7]]).is_err());
+
+        // 没有位置嵌入时，相同的token在不同位置的输出也不同
+        let logits = model.forward(&[vec![2, 2]]).unwrap();
+        assert_ne!(logits.data()[..11], logits.data()[11..]);
+
+        let config = GptConfig {
+            emb_dim: 6,
+            n_heads: 2,
+            ..config
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_gpt_model_train_eval() {
+        let config = GptConfig {
+            dropout: 0.5,
+            ..tiny_config()
+        };
+        let mut model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123)).unwrap();
+        let batch = [vec![1, 2, 3, 4]];
+        assert!(model.is_training());
+
+        // 训练模式下每次的dropout掩码不同
+        let first = model.forward(&batch).unwrap();
+        assert_ne!(model.forward(&batch).unwrap(), first);
+
+        // 相同的种子得到相同的掩码
+        model.seed_dropout(42);
+        let seeded = model.forward(&batch).unwrap();
+        model.seed_dropout(42);
+        assert_eq!(model.forward(&batch).unwrap(), seeded);
+
+        // 推理模式下与不使用dropout的`forward_with_cache`结果相同
+        model.eval();
+        assert!(!model.is_training());
+        assert!(!model.trf_blocks()[1].att().is_training());
+        let logits = model.forward(&batch).unwrap();
+        let cached = model
+            .forward_with_cache(&batch, &mut model.new_kv_cache())
+            .unwrap();
+        for (x, y) in logits.data().iter().zip(cached.data()) {
+            assert!((x - y).abs() < 1e-5, "{x} vs {y}");
+        }
+
+        model.train();
+        assert!(model.trf_blocks()[0].is_training());
+    }
+
+    #[test]
+    fn test_gpt_model_tie_weights() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let config = GptConfig {
+            tie_weights: true,
+            ..tiny_config()
+        };
+        let mut model = GPTModel::new(&config, &mut rng).unwrap();
+        assert!(model.out_head().is_none());
+        assert!(model
+            .named_params()
+            .iter()
+            .all(|(name, _)| !name.starts_with("out_head")));
+
+        // token 0没有出现在输入中，只有输出层的梯度
+        let batch = [vec![1, 2, 3, 1]];
+        let logits = model.forward(&batch).unwrap();
+        let r = Tensor::randn(logits.shape(), 1.0, &mut rng);
+        let loss = |model: &mut GPTModel| {
+            let logits = model.forward(&batch).unwrap();
+            logits
+                .data()
+                .iter()
+                .zip(r.data())
+                .map(|(x, w)| x * w)
+                .sum::<f32>()
+        };
+
+        model.backward(&r).unwrap();
+        let grad = model.tok_emb().grad().clone();
+        let eps = 1e-2;
+        for i in 0..16 {
+            model.tok_emb_mut().weight_mut().data_mut()[i] += eps;
+            let loss_plus = loss(&mut model);
+            model.tok_emb_mut().weight_mut().data_mut()[i] -= 2.0 * eps;
+            let loss_minus = loss(&mut model);
+            model.tok_emb_mut().weight_mut().data_mut()[i] += eps;
+
+            let numeric = (loss_plus - loss_minus) / (2.0 * eps);
+            assert!(
+                (numeric - grad.data()[i]).abs() < 5e-2,
+                "tok_emb[{i}]: numeric {numeric}, analytic {}",
+                grad.data()[i]
+            );
+        }
+        assert!(grad.data()[..8].iter().any(|g| *g != 0.0));
+
+        // 推理与训练使用相同的输出层
+        let cached = model
+            .forward_with_cache(&batch, &mut model.new_kv_cache())
+            .unwrap();
+        for (x, y) in cached
+            .data()
+            .iter()
+            .zip(model.forward(&batch).unwrap().data())
+        {
+            assert!((x - y).abs() < 1e-5, "{x} vs {y}");
+        }
+    }
+
+    #[test]
+    fn test_gpt_model_sliding_window() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let config = GptConfig {
+            sliding_window: Some(2),
+            sliding_window_layers: vec![0],
+            ..tiny_config()
+        };
+        let mut model = GPTModel::new(&config, &mut rng).unwrap();
+        assert_eq!(model.trf_blocks()[0].att().window(), Some(2));
+        assert_eq!(model.trf_blocks()[1].att().window(), None);
+
+        let tokens = vec![3, 1, 4, 1, 5, 9];
+        let expected = model.forward(std::slice::from_ref(&tokens)).unwrap();
+        let mut cache = model.new_kv_cache();
+        let mut logits = vec![];
+        for &token in &tokens {
+            let next = model
+                .forward_with_cache(&[vec![token]], &mut cache)
+                .unwrap();
+            logits.extend(next.into_data());
+        }
+        for (x, y) in logits.iter().zip(expected.data()) {
+            assert!((x - y).abs() < 1e-4, "{x} vs {y}");
+        }
+        assert_eq!((cache[0].stored_len(), cache[1].stored_len()), (1, 6));
+
+        let config = GptConfig {
+            sliding_window_layers: vec![2],
+            ..config
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_gpt_model_generate_batch() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let prompts = [vec![1, 2, 3], vec![7], vec![4, 5, 6, 7, 8]];
+        for pos_encoding in [PosEncoding::Learned, PosEncoding::Rope { theta: 10000.0 }] {
+            let config = GptConfig {
+                pos_encoding,
+                ..tiny_config()
+            };
+            let model = GPTModel::new(&config, &mut rng).unwrap();
+
+            // 超过`context_length`时裁剪，与单独生成每个序列的结果相同
+            let greedy = SamplingConfig::greedy();
+            let outputs = model
+                .generate_batch(&prompts, 4, &greedy, None, &mut rng)
+                .unwrap();
+            println!("{outputs:?}");
+            for (prompt, output) in prompts.iter().zip(&outputs) {
+                let expected = model.generate(prompt, 4, &greedy, &mut rng).unwrap();
+                assert_eq!(output, &expected);
+            }
+
+            // 生成`stop_token`的序列提前停止，其余序列继续
+            let stop = outputs[0][3];
+            let stopped = model
+                .generate_batch(&prompts, 4, &greedy, Some(stop), &mut rng)
+                .unwrap();
+            for (output, full) in stopped.iter().zip(&outputs) {
+                assert!(full.starts_with(output), "{output:?} vs {full:?}");
+                assert!(output == full || output.last() == Some(&stop));
+            }
+            assert_eq!(stopped[0].len(), 4);
+        }
+
+        let model = GPTModel::new(&tiny_config(), &mut rng).unwrap();
+        let sampling = SamplingConfig::default();
+        assert!(model
+            .generate_batch(&[vec![1], vec![]], 1, &sampling, None, &mut rng)
+            .is_err());
+        assert!(model
+            .generate_batch(&[], 1, &sampling, None, &mut rng)
+            .is_err());
+    }
+
+    #[test]
+    fn test_gpt_model_chunked_attention() {
+        let config = GptConfig {
+            pos_encoding: PosEncoding::Rope { theta: 10000.0 },
+            sliding_window: Some(4),
+            n_kv_heads: Some(1),
+            ..tiny_config()
+        };
+        let chunked_config = GptConfig {
+            attention_chunk_size: Some(2),
+            ..config.clone()
+        };
+        // 分块不使用随机数，两个模型的权重相同
+        let mut model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123)).unwrap();
+        let mut chunked = GPTModel::new(&chunked_config, &mut StdRng::seed_from_u64(123)).unwrap();
+
+        let batch = [vec![1, 2, 3, 4, 5], vec![6, 7, 8, 9, 10]];
+        let expected = model.forward(&batch).unwrap();
+        let logits = chunked.forward(&batch).unwrap();
+        for (x, y) in logits.data().iter().zip(expected.data()) {
+            assert!((x - y).abs() < 1e-5, "{x} vs {y}");
+        }
+        assert!(chunked.trf_blocks()[0].att().attention_weights().is_none());
+
+        let r = Tensor::randn(logits.shape(), 1.0, &mut StdRng::seed_from_u64(1));
+        model.backward(&r).unwrap();
+        chunked.backward(&r).unwrap();
+        for ((name, x), (_, y)) in model.named_params().iter().zip(chunked.named_params()) {
+            for (a, b) in x.grad().data().iter().zip(y.grad().data()) {
+                assert!((a - b).abs() < 1e-4, "{name}: {a} vs {b}");
+            }
+        }
+
+        let prompts = [vec![1, 2, 3], vec![7], vec![4, 5, 6, 7, 8]];
+        let greedy = SamplingConfig::greedy();
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(
+            chunked
+                .generate_batch(&prompts, 4, &greedy, None, &mut rng)
+                .unwrap(),
+            model
+                .generate_batch(&prompts, 4, &greedy, None, &mut rng)
+                .unwrap()
+        );
+
+        let config = GptConfig {
+            attention_chunk_size: Some(0),
+            ..tiny_config()
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::Param;
+use serde::{Deserialize, Serialize};
+
+// `torch.cuda.amp.GradScaler`：f16训练时把loss乘上`scale`再反向传播，避免小梯度下溢为0。
+// 更新参数前把梯度除回`scale`，出现inf/nan时跳过这一步并缩小`scale`，
+// 连续`growth_interval`步没有溢出时放大`scale`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GradScaler {
+    scale: f32,
+    growth_factor: f32,
+    backoff_factor: f32,
+    growth_interval: usize,
+    // 距离上一次溢出或放大`scale`的步数
+    good_steps: usize,
+}
+
+impl Default for GradScaler {
+    fn default() -> Self {
+        GradScaler::new()
+    }
+}
+
+impl GradScaler {
+    // 与PyTorch的默认值相同：init_scale = 2^16，growth_factor = 2，backoff_factor = 0.5，growth_interval = 2000
+    pub fn new() -> Self {
+        GradScaler {
+            scale: 65536.0,
+            growth_factor: 2.0,
+            backoff_factor: 0.5,
+            growth_interval: 2000,
+            good_steps: 0,
+        }
+    }
+
+    pub fn with_init_scale(mut self, scale: f32) -> Self {
+        assert!(scale > 0.0, "Scale must be positive");
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_growth_interval(mut self, growth_interval: usize) -> Self {
+        assert!(growth_interval > 0, "Growth interval must be positive");
+        self.growth_interval = growth_interval;
+        self
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    // 梯度除以`scale`并更新`scale`。梯度中有inf/nan时返回false，调用方应跳过`optimizer.step`
+    pub fn unscale<'a>(
+        &mut self,
+        params: impl IntoIterator<Item = (String, &'a mut Param)>,
+    ) -> bool {
+        let inv_scale = 1.0 / self.scale;
+        let mut finite = true;
+        for (_, param) in params {
+            if !param.requires_grad() {
+                continue;
+            }
+            for g in param.grad_mut().data_mut() {
+                *g *= inv_scale;
+                finite &= g.is_finite();
+            }
+        }
+
+        if finite {
+            self.good_steps += 1;
+            if self.good_steps == self.growth_interval {
+                self.scale *= self.growth_factor;
+                self.good_steps = 0;
+            }
+        } else {
+            self.scale *= self.backoff_factor;
+            self.good_steps = 0;
+        }
+        finite
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Tensor;
+
+    #[test]
+    fn test_grad_scaler() {
+        let mut param = Param::new(Tensor::new(vec![0.0; 2], &[2]));
+        let mut scaler = GradScaler::new()
+            .with_init_scale(8.0)
+            .with_growth_interval(2);
+
+        param.grad_mut().data_mut().copy_from_slice(&[8.0, -4.0]);
+        assert!(scaler.unscale([("w".to_string(), &mut param)]));
+        assert_eq!(param.grad().data(), &[1.0, -0.5]);
+        assert_eq!(scaler.scale(), 8.0);
+
+        // 连续两步没有溢出，scale翻倍
+        assert!(scaler.unscale([("w".to_string(), &mut param)]));
+        assert_eq!(scaler.scale(), 16.0);
+
+        param.grad_mut().data_mut()[0] = f32::INFINITY;
+        assert!(!scaler.unscale([("w".to_string(), &mut param)]));
+        println!("{scaler:?}");
+        assert_eq!(scaler.scale(), 8.0);
+    }
+}
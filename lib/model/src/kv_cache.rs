@@ -0,0 +1,105 @@
+use crate::error::{shape_error, Result};
+
+// 一层注意力已经计算过的K和V。每个`(batch, head)`单独存储`(T, head_dim)`，
+// 生成新token时只需要追加一行，不需要重新计算前缀
+#[derive(Debug, Clone, Default)]
+pub struct KvCache {
+    keys: Vec<Vec<f32>>,
+    values: Vec<Vec<f32>>,
+    head_dim: usize,
+    // 已经处理过的token数，滑动窗口丢弃旧的K和V之后也不变，新token的位置从这里开始
+    len: usize,
+}
+
+impl KvCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 已处理的token数
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // 实际保存的token数，使用滑动窗口时不超过窗口大小
+    pub fn stored_len(&self) -> usize {
+        match self.keys.first() {
+            Some(keys) if self.head_dim > 0 => keys.len() / self.head_dim,
+            _ => 0,
+        }
+    }
+
+    // 保存的`(batch, head)`数，使用GQA时为`B·n_kv_heads`
+    pub fn num_heads(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // 开始新的序列前调用
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.values.clear();
+        self.head_dim = 0;
+        self.len = 0;
+    }
+
+    // `keys`和`values`按`(B·H, T, head_dim)`排列
+    pub(crate) fn append(
+        &mut self,
+        keys: &[f32],
+        values: &[f32],
+        heads: usize,
+        head_dim: usize,
+    ) -> Result<()> {
+        if self.keys.is_empty() {
+            self.keys = vec![vec![]; heads];
+            self.values = vec![vec![]; heads];
+            self.head_dim = head_dim;
+        } else if self.keys.len() != heads || self.head_dim != head_dim {
+            return Err(shape_error!(
+                "KV cache holds {} heads of dim {}, got {heads} heads of dim {head_dim}",
+                self.keys.len(),
+                self.head_dim
+            ));
+        }
+
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let size = keys.len() / heads;
+        for (cache, new) in self.keys.iter_mut().zip(keys.chunks(size)) {
+            cache.extend_from_slice(new);
+        }
+        for (cache, new) in self.values.iter_mut().zip(values.chunks(size)) {
+            cache.extend_from_slice(new);
+        }
+
+        self.len += size / head_dim;
+        Ok(())
+    }
+
+    // 只保留最后`keep`个token的K和V
+    pub(crate) fn evict(&mut self, keep: usize) {
+        let stored = self.stored_len();
+        if stored <= keep {
+            return;
+        }
+
+        let drop = (stored - keep) * self.head_dim;
+        for cache in self.keys.iter_mut().chain(self.values.iter_mut()) {
+            cache.drain(..drop);
+        }
+    }
+
+    pub(crate) fn keys(&self, head: usize) -> &[f32] {
+        &self.keys[head]
+    }
+
+    pub(crate) fn values(&self, head: usize) -> &[f32] {
+        &self.values[head]
+    }
+}
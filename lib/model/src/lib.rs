@@ -0,0 +1,58 @@
+mod activation;
+mod attention;
+mod chunked;
+mod dropout;
+mod embedding;
+mod error;
+mod feed_forward;
+mod generate;
+mod gguf;
+mod gpt;
+mod grad_scaler;
+mod kv_cache;
+mod linear;
+mod lora;
+mod loss;
+mod norm;
+mod optim;
+mod param;
+mod positional;
+mod pretrained;
+mod quantize;
+mod rope;
+mod sampling;
+mod scheduler;
+mod serialize;
+mod summary;
+mod transformer;
+
+pub use activation::{gelu, Gelu, GeluApproximation};
+pub use attention::{MultiHeadAttention, SelfAttention};
+pub use dropout::Dropout;
+pub use embedding::Embedding;
+pub use error::{ModelError, Result};
+pub use feed_forward::FeedForward;
+pub use generate::generate_text_simple;
+pub use gguf::{export_gguf, GgufType, GgufValue};
+pub use gpt::{GPTModel, GptConfig, PosEncoding};
+pub use grad_scaler::GradScaler;
+pub use kv_cache::KvCache;
+pub use linear::Linear;
+pub use loss::{
+    cross_entropy, cross_entropy_with_grad, masked_cross_entropy, masked_cross_entropy_with_grad,
+    perplexity,
+};
+pub use norm::LayerNorm;
+pub use optim::{clip_grad_norm, AdamW};
+pub use param::Param;
+pub use positional::PositionalEmbedding;
+pub use pretrained::load_gpt2_safetensors;
+pub use quantize::QuantizedTensor;
+pub use rope::Rope;
+pub use sampling::{
+    apply_penalties, argmax, multinomial, sample_next, softmax, top_k_filter, top_p_filter,
+    SamplingConfig,
+};
+pub use scheduler::{LrScheduler, StepDecay, WarmupCosine};
+pub use tensor::{with_precision, Device, Precision, SavedTensor, Tensor};
+pub use transformer::TransformerBlock;
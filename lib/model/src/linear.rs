@@ -0,0 +1,372 @@
+use crate::error::{shape_error, Result};
+use crate::lora::Lora;
+use crate::{Param, QuantizedTensor, SavedTensor, Tensor};
+use rand::Rng;
+use tensor::gemm;
+
+// 全连接层`y = x·Wᵀ + b`，权重按PyTorch的`(out, in)`存储，加载预训练权重时不需要转置
+#[derive(Debug, Clone)]
+pub struct Linear {
+    weight: Weight,
+    bias: Option<Param>,
+    lora: Option<Lora>,
+    // 上一次`forward`的输入，`backward`时使用
+    input: Option<SavedTensor>,
+}
+
+#[derive(Debug, Clone)]
+enum Weight {
+    F32(Param),
+    // `quantize`之后只能用于推理
+    Int8(QuantizedTensor),
+}
+
+impl Linear {
+    // 与PyTorch的`nn.Linear`相同，权重和偏置从`U(-1/√in, 1/√in)`初始化
+    pub fn new(in_features: usize, out_features: usize, bias: bool, rng: &mut impl Rng) -> Self {
+        let bound = 1.0 / (in_features as f32).sqrt();
+        let weight = Tensor::uniform(&[out_features, in_features], -bound, bound, rng);
+        let bias = bias.then(|| Tensor::uniform(&[out_features], -bound, bound, rng));
+        Self::from_weights(weight, bias)
+    }
+
+    pub fn from_weights(weight: Tensor, bias: Option<Tensor>) -> Self {
+        assert_eq!(weight.shape().len(), 2, "Linear weight must be 2D");
+        if let Some(bias) = bias.as_ref() {
+            assert_eq!(
+                bias.shape(),
+                [weight.shape()[0]],
+                "Bias does not match weight"
+            );
+        }
+
+        Linear {
+            weight: Weight::F32(Param::new(weight)),
+            bias: bias.map(Param::new),
+            lora: None,
+            input: None,
+        }
+    }
+
+    pub fn in_features(&self) -> usize {
+        self.weight_shape()[1]
+    }
+
+    pub fn out_features(&self) -> usize {
+        self.weight_shape()[0]
+    }
+
+    fn weight_shape(&self) -> &[usize] {
+        match &self.weight {
+            Weight::F32(weight) => weight.shape(),
+            Weight::Int8(weight) => weight.shape(),
+        }
+    }
+
+    // 量化之后没有f32的权重，使用`quantized_weight`
+    pub fn weight(&self) -> &Param {
+        match &self.weight {
+            Weight::F32(weight) => weight,
+            Weight::Int8(_) => panic!("Linear weight is quantized"),
+        }
+    }
+
+    pub fn weight_mut(&mut self) -> &mut Param {
+        match &mut self.weight {
+            Weight::F32(weight) => weight,
+            Weight::Int8(_) => panic!("Linear weight is quantized"),
+        }
+    }
+
+    pub fn is_quantized(&self) -> bool {
+        matches!(self.weight, Weight::Int8(_))
+    }
+
+    pub fn quantized_weight(&self) -> Option<&QuantizedTensor> {
+        match &self.weight {
+            Weight::F32(_) => None,
+            Weight::Int8(weight) => Some(weight),
+        }
+    }
+
+    pub fn quantized_weight_mut(&mut self) -> Option<&mut QuantizedTensor> {
+        match &mut self.weight {
+            Weight::F32(_) => None,
+            Weight::Int8(weight) => Some(weight),
+        }
+    }
+
+    // 训练后量化：权重按输出通道转为int8，偏置保持f32。之后不能再`backward`
+    pub fn quantize(&mut self) {
+        if let Weight::F32(weight) = &self.weight {
+            self.weight = Weight::Int8(QuantizedTensor::quantize(weight.value()));
+            self.input = None;
+        }
+    }
+
+    // 加上rank为`rank`的LoRA，已有的LoRA会被替换。不会冻结权重，见`GPTModel::add_lora`
+    pub fn add_lora(&mut self, rank: usize, alpha: f32, rng: &mut impl Rng) {
+        let [out_features, in_features] = *self.weight_shape() else {
+            unreachable!()
+        };
+        self.lora = Some(Lora::new(in_features, out_features, rank, alpha, rng));
+    }
+
+    pub(crate) fn lora(&self) -> Option<&Lora> {
+        self.lora.as_ref()
+    }
+
+    // 把LoRA合并到权重中
+    pub fn merge_lora(&mut self) {
+        if let Some(lora) = self.lora.take() {
+            let delta = lora.delta_weight();
+            let weight = self.weight_mut().value_mut().data_mut();
+            for (w, d) in weight.iter_mut().zip(delta.data()) {
+                *w += d;
+            }
+        }
+    }
+
+    pub fn bias(&self) -> Option<&Param> {
+        self.bias.as_ref()
+    }
+
+    pub fn bias_mut(&mut self) -> Option<&mut Param> {
+        self.bias.as_mut()
+    }
+
+    // 输入的最后一维为`in_features`，其余维度保持不变
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor> {
+        let mut output = self.apply_base(input)?;
+        if let Some(lora) = self.lora.as_mut() {
+            lora.forward(input, &mut output)?;
+        }
+        self.input = Some(SavedTensor::new(input));
+        Ok(output)
+    }
+
+    // 只计算输出，不保存反向传播需要的输入
+    pub fn apply(&self, input: &Tensor) -> Result<Tensor> {
+        let mut output = self.apply_base(input)?;
+        if let Some(lora) = self.lora.as_ref() {
+            lora.add_to(input, &mut output)?;
+        }
+        Ok(output)
+    }
+
+    fn apply_base(&self, input: &Tensor) -> Result<Tensor> {
+        match &self.weight {
+            Weight::F32(weight) => linear(input, weight, self.bias.as_ref()),
+            Weight::Int8(weight) => affine(
+                input,
+                weight.shape(),
+                self.bias.as_ref(),
+                |input, output, rows| {
+                    weight.matmul_transposed(input, output, rows);
+                    Ok(())
+                },
+            ),
+        }
+    }
+
+    // 累加权重和偏置的梯度，返回输入的梯度
+    pub fn backward(&mut self, grad_output: &Tensor) -> Result<Tensor> {
+        let input = self
+            .input
+            .as_ref()
+            .expect("Linear::backward called before forward")
+            .tensor();
+        let Weight::F32(weight) = &mut self.weight else {
+            panic!("Quantized Linear can only be used for inference");
+        };
+        let mut grad_input = linear_backward(&input, grad_output, weight, self.bias.as_mut())?;
+        if let Some(lora) = self.lora.as_mut() {
+            lora.backward(&input, grad_output, &mut grad_input)?;
+        }
+        Ok(grad_input)
+    }
+
+    // 丢弃`forward`保存的输入
+    pub(crate) fn clear_cache(&mut self) {
+        self.input = None;
+        if let Some(lora) = self.lora.as_mut() {
+            lora.clear_cache();
+        }
+    }
+
+    // `forward`为反向传播保存的字节数
+    pub(crate) fn saved_bytes(&self) -> usize {
+        let lora = self.lora.as_ref().map_or(0, Lora::saved_bytes);
+        self.input.as_ref().map_or(0, SavedTensor::size_in_bytes) + lora
+    }
+
+    pub fn zero_grad(&mut self) {
+        if let Weight::F32(weight) = &mut self.weight {
+            weight.zero_grad();
+        }
+        if let Some(bias) = self.bias.as_mut() {
+            bias.zero_grad();
+        }
+        if let Some(lora) = self.lora.as_mut() {
+            lora.zero_grad();
+        }
+    }
+
+    // 与PyTorch的`state_dict`同名，量化后的权重不是`Param`，不包括在内
+    pub fn named_params(&self) -> Vec<(String, &Param)> {
+        let mut params = vec![];
+        if let Weight::F32(weight) = &self.weight {
+            params.push(("weight".to_string(), weight));
+        }
+        params.extend(self.bias.iter().map(|bias| ("bias".to_string(), bias)));
+        params.extend(self.lora.iter().flat_map(Lora::named_params));
+        params
+    }
+
+    pub fn named_params_mut(&mut self) -> Vec<(String, &mut Param)> {
+        let mut params = vec![];
+        if let Weight::F32(weight) = &mut self.weight {
+            params.push(("weight".to_string(), weight));
+        }
+        params.extend(self.bias.iter_mut().map(|bias| ("bias".to_string(), bias)));
+        params.extend(self.lora.iter_mut().flat_map(Lora::named_params_mut));
+        params
+    }
+}
+
+// `weight`按`(out, in)`存储。共享权重的层（例如与词嵌入绑定的输出层）也使用这两个函数
+pub(crate) fn linear(input: &Tensor, weight: &Param, bias: Option<&Param>) -> Result<Tensor> {
+    affine(input, weight.shape(), bias, |input, output, rows| {
+        let [out_features, in_features] = *weight.shape() else {
+            unreachable!()
+        };
+        Ok(gemm(
+            input,
+            false,
+            weight.value().data(),
+            true,
+            output,
+            rows,
+            in_features,
+            out_features,
+        )?)
+    })
+}
+
+// 检查形状、用偏置初始化输出，`matmul`计算`output += input·Wᵀ`
+fn affine(
+    input: &Tensor,
+    weight_shape: &[usize],
+    bias: Option<&Param>,
+    matmul: impl FnOnce(&[f32], &mut [f32], usize) -> Result<()>,
+) -> Result<Tensor> {
+    let [out_features, in_features] = *weight_shape else {
+        return Err(shape_error!(
+            "Linear weight must be 2D, got {weight_shape:?}"
+        ));
+    };
+    let Some((&last, dims)) = input.shape().split_last() else {
+        return Err(shape_error!(
+            "Linear input must have at least one dimension"
+        ));
+    };
+    if last != in_features {
+        return Err(shape_error!(
+            "Expected {in_features} input features, got {last}"
+        ));
+    }
+
+    let rows = input.len() / in_features;
+    let mut output = vec![0.0; rows * out_features];
+    if let Some(bias) = bias {
+        for row in output.chunks_mut(out_features) {
+            row.copy_from_slice(bias.value().data());
+        }
+    }
+    matmul(input.data(), &mut output, rows)?;
+
+    let mut shape = dims.to_vec();
+    shape.push(out_features);
+    Ok(Tensor::new(output, &shape))
+}
+
+pub(crate) fn linear_backward(
+    input: &Tensor,
+    grad_output: &Tensor,
+    weight: &mut Param,
+    bias: Option<&mut Param>,
+) -> Result<Tensor> {
+    let [out_features, in_features] = *weight.shape() else {
+        unreachable!()
+    };
+    let rows = input.len() / in_features;
+    assert_eq!(
+        grad_output.len(),
+        rows * out_features,
+        "Gradient does not match the last forward input"
+    );
+
+    // 冻结的参数不累加梯度
+    if weight.requires_grad() {
+        gemm(
+            grad_output.data(),
+            true,
+            input.data(),
+            false,
+            weight.grad_mut().data_mut(),
+            out_features,
+            rows,
+            in_features,
+        )?;
+    }
+    if let Some(bias) = bias.filter(|bias| bias.requires_grad()) {
+        let grad = bias.grad_mut().data_mut();
+        for row in grad_output.data().chunks(out_features) {
+            for (g, x) in grad.iter_mut().zip(row) {
+                *g += x;
+            }
+        }
+    }
+
+    let mut grad_input = vec![0.0; rows * in_features];
+    gemm(
+        grad_output.data(),
+        false,
+        weight.value().data(),
+        false,
+        &mut grad_input,
+        rows,
+        out_features,
+        in_features,
+    )?;
+    Ok(Tensor::new(grad_input, input.shape()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linear() {
+        let weight = Tensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        let bias = Tensor::new(vec![0.5, -0.5], &[2]);
+        let mut linear = Linear::from_weights(weight, Some(bias));
+
+        let input = Tensor::new(vec![1.0, 0.0, 1.0, 0.0, 1.0, 0.0], &[1, 2, 3]);
+        let output = linear.forward(&input).unwrap();
+        assert_eq!(output.shape(), [1, 2, 2]);
+        assert_eq!(output.data(), [4.5, 9.5, 2.5, 4.5]);
+
+        let grad_input = linear
+            .backward(&Tensor::new(vec![1.0, 0.0, 0.0, 1.0], &[1, 2, 2]))
+            .unwrap();
+        assert_eq!(grad_input.data(), [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(
+            linear.weight().grad().data(),
+            [1.0, 0.0, 1.0, 0.0, 1.0, 0.0]
+        );
+        assert_eq!(linear.bias().unwrap().grad().data(), [1.0, 1.0]);
+
+        assert!(linear.forward(&Tensor::zeros(&[2, 4])).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use crate::error::{invalid, invalid_file, read_error, write_error, Result};
+use crate::linear::{linear, linear_backward};
+use crate::pretrained::{assign, read_tensor};
+use crate::{GPTModel, Param, SavedTensor, Tensor};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use safetensors::tensor::TensorView;
+use safetensors::{Dtype, SafeTensors};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// 附录E的LoRA：线性层的输出加上`alpha / rank × x·Aᵀ·Bᵀ`。A为`(rank, in)`，与`nn.Linear`相同的初始化，
+// B为`(out, rank)`，初始化为0，所以刚加上时模型的输出不变。参数名与PEFT相同：`lora_A.weight`和`lora_B.weight`
+#[derive(Debug, Clone)]
+pub(crate) struct Lora {
+    a: Param,
+    b: Param,
+    alpha: f32,
+    // 上一次`forward`的`x·Aᵀ`，`backward`时使用
+    hidden: Option<SavedTensor>,
+}
+
+impl Lora {
+    pub(crate) fn new(
+        in_features: usize,
+        out_features: usize,
+        rank: usize,
+        alpha: f32,
+        rng: &mut impl Rng,
+    ) -> Self {
+        assert!(rank > 0, "LoRA rank must be greater than 0");
+        let bound = 1.0 / (in_features as f32).sqrt();
+        Lora {
+            a: Param::new(Tensor::uniform(&[rank, in_features], -bound, bound, rng)),
+            b: Param::new(Tensor::zeros(&[out_features, rank])),
+            alpha,
+            hidden: None,
+        }
+    }
+
+    pub(crate) fn clear_cache(&mut self) {
+        self.hidden = None;
+    }
+
+    pub(crate) fn saved_bytes(&self) -> usize {
+        self.hidden.as_ref().map_or(0, SavedTensor::size_in_bytes)
+    }
+
+    pub(crate) fn rank(&self) -> usize {
+        self.a.shape()[0]
+    }
+
+    pub(crate) fn alpha(&self) -> f32 {
+        self.alpha
+    }
+
+    fn scale(&self) -> f32 {
+        self.alpha / self.rank() as f32
+    }
+
+    // `output += scale × x·Aᵀ·Bᵀ`，返回`x·Aᵀ`
+    pub(crate) fn add_to(&self, input: &Tensor, output: &mut Tensor) -> Result<Tensor> {
+        let hidden = linear(input, &self.a, None)?;
+        let delta = linear(&hidden, &self.b, None)?;
+        let scale = self.scale();
+        for (y, d) in output.data_mut().iter_mut().zip(delta.data()) {
+            *y += scale * d;
+        }
+        Ok(hidden)
+    }
+
+    pub(crate) fn forward(&mut self, input: &Tensor, output: &mut Tensor) -> Result<()> {
+        self.hidden = Some(SavedTensor::from_tensor(self.add_to(input, output)?));
+        Ok(())
+    }
+
+    // 累加A和B的梯度，把输入的梯度加到`grad_input`上
+    pub(crate) fn backward(
+        &mut self,
+        input: &Tensor,
+        grad_output: &Tensor,
+        grad_input: &mut Tensor,
+    ) -> Result<()> {
+        let hidden = self
+            .hidden
+            .as_ref()
+            .expect("LoRA backward called before forward")
+            .tensor();
+        let scale = self.scale();
+        let mut grad_scaled = grad_output.clone();
+        grad_scaled.data_mut().iter_mut().for_each(|g| *g *= scale);
+
+        let grad_hidden = linear_backward(&hidden, &grad_scaled, &mut self.b, None)?;
+        let grad = linear_backward(input, &grad_hidden, &mut self.a, None)?;
+        for (g, d) in grad_input.data_mut().iter_mut().zip(grad.data()) {
+            *g += d;
+        }
+        Ok(())
+    }
+
+    // `scale × B·A`，形状为`(out, in)`
+    pub(crate) fn delta_weight(&self) -> Tensor {
+        let [rank, in_features] = *self.a.shape() else {
+            unreachable!()
+        };
+        let out_features = self.b.shape()[0];
+        let (a, b) = (self.a.value().data(), self.b.value().data());
+        let mut delta = vec![0.0; out_features * in_features];
+        for (o, row) in delta.chunks_mut(in_features).enumerate() {
+            for r in 0..rank {
+                let factor = self.scale() * b[o * rank + r];
+                for (d, x) in row
+                    .iter_mut()
+                    .zip(&a[r * in_features..(r + 1) * in_features])
+                {
+                    *d += factor * x;
+                }
+            }
+        }
+        Tensor::new(delta, &[out_features, in_features])
+    }
+
+    pub(crate) fn zero_grad(&mut self) {
+        self.a.zero_grad();
+        self.b.zero_grad();
+    }
+
+    pub(crate) fn named_params(&self) -> Vec<(String, &Param)> {
+        vec![
+            ("lora_A.weight".to_string(), &self.a),
+            ("lora_B.weight".to_string(), &self.b),
+        ]
+    }
+
+    pub(crate) fn named_params_mut(&mut self) -> Vec<(String, &mut Param)> {
+        vec![
+            ("lora_A.weight".to_string(), &mut self.a),
+            ("lora_B.weight".to_string(), &mut self.b),
+        ]
+    }
+}
+
+// LoRA参数的名字，`GPTModel::save`不保存这些参数
+pub(crate) fn is_lora_param(name: &str) -> bool {
+    name.contains(".lora_A.") || name.contains(".lora_B.")
+}
+
+impl GPTModel {
+    // 冻结所有参数，给每个线性层加上LoRA，之后只训练A和B。与词嵌入绑定的输出层不加
+    pub fn add_lora(&mut self, rank: usize, alpha: f32, rng: &mut impl Rng) {
+        self.named_params_mut()
+            .into_iter()
+            .for_each(|(_, param)| param.set_requires_grad(false));
+        self.named_linears_mut()
+            .into_iter()
+            .for_each(|(_, linear)| linear.add_lora(rank, alpha, rng));
+    }
+
+    pub fn has_lora(&self) -> bool {
+        self.named_linears()
+            .iter()
+            .any(|(_, linear)| linear.lora().is_some())
+    }
+
+    // 把`scale × B·A`加到权重上并去掉LoRA，解冻所有参数。之后推理没有额外的开销
+    pub fn merge_lora(&mut self) {
+        self.named_linears_mut()
+            .into_iter()
+            .for_each(|(_, linear)| linear.merge_lora());
+        self.named_params_mut()
+            .into_iter()
+            .for_each(|(_, param)| param.set_requires_grad(true));
+    }
+
+    // 只保存LoRA的参数，rank和alpha保存在metadata中，与基础模型的checkpoint分开
+    pub fn save_lora(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let linears = self.named_linears();
+        let Some(lora) = linears.iter().find_map(|(_, linear)| linear.lora()) else {
+            return Err(invalid!("Model has no LoRA adapters"));
+        };
+
+        let tensors = self
+            .named_params()
+            .into_iter()
+            .filter(|(name, _)| is_lora_param(name))
+            .map(|(name, param)| {
+                let bytes = param.value().data().iter().flat_map(|x| x.to_le_bytes());
+                (name, param.shape().to_vec(), bytes.collect::<Vec<u8>>())
+            })
+            .collect::<Vec<_>>();
+        let views = tensors
+            .iter()
+            .map(|(name, shape, bytes)| {
+                let view = TensorView::new(Dtype::F32, shape.clone(), bytes)?;
+                Ok((name.as_str(), view))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let metadata = HashMap::from([
+            ("lora_rank".to_string(), lora.rank().to_string()),
+            ("lora_alpha".to_string(), lora.alpha().to_string()),
+        ]);
+        let bytes = safetensors::serialize(views, Some(metadata))?;
+        fs::write(path, bytes).map_err(write_error(path))
+    }
+
+    // 给基础模型加上`save_lora`保存的LoRA，与`add_lora`相同会冻结其余参数
+    pub fn load_lora(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(read_error(path))?;
+        let tensors =
+            SafeTensors::deserialize(&bytes).map_err(invalid_file("safetensors file", path))?;
+        let (_, metadata) = SafeTensors::read_metadata(&bytes)?;
+        let metadata = metadata.metadata().clone().unwrap_or_default();
+        let value = |key: &str| {
+            metadata
+                .get(key)
+                .ok_or_else(|| invalid!("Missing LoRA metadata {key}"))
+        };
+        let rank = value("lora_rank")?;
+        let rank = rank
+            .parse()
+            .map_err(|_| invalid!("Invalid lora_rank {rank}"))?;
+        let alpha = value("lora_alpha")?;
+        let alpha = alpha
+            .parse()
+            .map_err(|_| invalid!("Invalid lora_alpha {alpha}"))?;
+
+        if self.has_lora() {
+            return Err(invalid!("Model already has LoRA adapters"));
+        }
+        // A和B都会被覆盖，随机初始化不影响结果
+        self.add_lora(rank, alpha, &mut StdRng::seed_from_u64(0));
+        for (name, param) in self.named_params_mut() {
+            if is_lora_param(&name) {
+                assign(param, read_tensor(&tensors, &name)?, &name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cross_entropy, cross_entropy_with_grad, AdamW, GptConfig, Linear};
+
+    #[test]
+    fn test_lora() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut linear = Linear::new(4, 3, true, &mut rng);
+        let input = Tensor::randn(&[2, 4], 1.0, &mut rng);
+        let base = linear.apply(&input)?;
+        linear.add_lora(2, 4.0, &mut rng);
+        // B初始化为0，输出不变
+        assert_eq!(linear.apply(&input)?, base);
+
+        // 数值梯度检查：loss = Σ output × g
+        *linear.named_params_mut()[3].1.value_mut() = Tensor::randn(&[3, 2], 1.0, &mut rng);
+        linear.weight_mut().set_requires_grad(false);
+        let grad_output = Tensor::randn(&[2, 3], 1.0, &mut rng);
+        linear.forward(&input)?;
+        linear.backward(&grad_output)?;
+        assert!(linear.weight().grad().data().iter().all(|g| *g == 0.0));
+
+        let loss = |linear: &Linear| -> Result<f32> {
+            let output = linear.apply(&input)?;
+            Ok(output
+                .data()
+                .iter()
+                .zip(grad_output.data())
+                .map(|(y, g)| y * g)
+                .sum())
+        };
+        let eps = 1e-2;
+        for p in 2..4 {
+            let (name, grad) = {
+                let params = linear.named_params();
+                (params[p].0.clone(), params[p].1.grad().clone())
+            };
+            for i in 0..grad.len() {
+                let mut plus = linear.clone();
+                plus.named_params_mut()[p].1.value_mut().data_mut()[i] += eps;
+                let mut minus = linear.clone();
+                minus.named_params_mut()[p].1.value_mut().data_mut()[i] -= eps;
+                let numeric = (loss(&plus)? - loss(&minus)?) / (2.0 * eps);
+                assert!(
+                    (numeric - grad.data()[i]).abs() < 1e-2,
+                    "{name}[{i}]: numeric {numeric}, analytic {}",
+                    grad.data()[i]
+                );
+            }
+        }
+
+        // 合并后的输出与合并前相同
+        let output = linear.apply(&input)?;
+        linear.merge_lora();
+        assert!(linear.lora().is_none());
+        for (x, y) in linear.apply(&input)?.data().iter().zip(output.data()) {
+            assert!((x - y).abs() < 1e-5);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_gpt_lora() -> Result<()> {
+        let config = GptConfig {
+            vocab_size: 16,
+            context_length: 8,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 2,
+            dropout: 0.0,
+            tie_weights: false,
+            ..GptConfig::gpt2_small()
+        };
+        let base = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let inputs = vec![vec![1, 2, 3, 4, 5, 6]];
+        let targets = vec![vec![2, 3, 4, 5, 6, 7]];
+        let logits = base.forward_with_cache(&inputs, &mut base.new_kv_cache())?;
+
+        let mut model = base.clone();
+        model.add_lora(4, 8.0, &mut StdRng::seed_from_u64(1));
+        assert!(model.has_lora());
+        assert_eq!(
+            model.forward_with_cache(&inputs, &mut model.new_kv_cache())?,
+            logits
+        );
+
+        // 每层4个注意力投影、2个前馈层，加上输出层
+        let trainable = model
+            .named_params()
+            .into_iter()
+            .filter(|(_, param)| param.requires_grad())
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        println!("{:?}", &trainable[..2]);
+        assert_eq!(trainable.len(), 2 * (2 * 6 + 1));
+        assert!(trainable.iter().all(|name| is_lora_param(name)));
+        assert!(trainable.contains(&"trf_blocks.0.att.W_query.lora_A.weight".to_string()));
+
+        let mut optimizer = AdamW::new(1e-2, 0.0);
+        let initial = cross_entropy(&logits, &targets, None)?;
+        for _ in 0..20 {
+            model.zero_grad();
+            let logits = model.forward(&inputs)?;
+            let (_, grad) = cross_entropy_with_grad(&logits, &targets, None)?;
+            model.backward(&grad)?;
+            optimizer.step(model.named_params_mut());
+        }
+        let logits = model.forward_with_cache(&inputs, &mut model.new_kv_cache())?;
+        let loss = cross_entropy(&logits, &targets, None)?;
+        println!("{initial} -> {loss}");
+        assert!(loss < initial);
+        // 基础模型的参数没有变化
+        let params = model.named_params();
+        let base_params = params.iter().filter(|(name, _)| !is_lora_param(name));
+        for ((name, a), (_, b)) in base.named_params().iter().zip(base_params) {
+            assert_eq!(a.value(), b.value(), "{name}");
+        }
+
+        // LoRA与基础模型分开保存，加载到基础模型上得到相同的输出
+        let path = std::env::temp_dir().join("test_gpt_lora.safetensors");
+        model.save_lora(&path)?;
+        let mut loaded = base.clone();
+        loaded.load_lora(&path)?;
+        assert_eq!(
+            loaded.forward_with_cache(&inputs, &mut loaded.new_kv_cache())?,
+            logits
+        );
+        assert!(loaded.load_lora(&path).is_err());
+        assert!(base.save_lora(&path).is_err());
+
+        let base_path = std::env::temp_dir().join("test_gpt_lora_base.safetensors");
+        model.save(&base_path)?;
+        assert!(!GPTModel::load(&base_path)?.has_lora());
+
+        model.merge_lora();
+        assert!(!model.has_lora());
+        assert!(model
+            .named_params()
+            .iter()
+            .all(|(_, param)| param.requires_grad()));
+        let merged = model.forward_with_cache(&inputs, &mut model.new_kv_cache())?;
+        for (x, y) in merged.data().iter().zip(logits.data()) {
+            assert!((x - y).abs() < 1e-4);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use crate::error::{invalid, shape_error, Result};
+use crate::Tensor;
+
+// 书中第5章的`torch.nn.functional.cross_entropy`：`(B, T, vocab_size)`的logits与`(B, T)`的目标token，
+// 返回所有位置的平均负对数似然。目标为`ignore_index`的位置不计入平均（如填充）
+pub fn cross_entropy(
+    logits: &Tensor,
+    targets: &[Vec<usize>],
+    ignore_index: Option<usize>,
+) -> Result<f32> {
+    let (sum, count) = negative_log_likelihood(logits, targets, None, ignore_index, None)?;
+    Ok(sum / count as f32)
+}
+
+// 同时返回loss对logits的梯度`(softmax - onehot) / N`，直接传给`GPTModel::backward`
+pub fn cross_entropy_with_grad(
+    logits: &Tensor,
+    targets: &[Vec<usize>],
+    ignore_index: Option<usize>,
+) -> Result<(f32, Tensor)> {
+    masked_cross_entropy_with_grad(logits, targets, None, ignore_index)
+}
+
+// `loss_mask`与`targets`形状相同，为0的位置（如填充、指令微调中的prompt）不计入loss，也没有梯度。
+// 与`ignore_index`同时使用时两者都会被忽略
+pub fn masked_cross_entropy(
+    logits: &Tensor,
+    targets: &[Vec<usize>],
+    loss_mask: Option<&[Vec<u8>]>,
+    ignore_index: Option<usize>,
+) -> Result<f32> {
+    let (sum, count) = negative_log_likelihood(logits, targets, loss_mask, ignore_index, None)?;
+    Ok(sum / count as f32)
+}
+
+pub fn masked_cross_entropy_with_grad(
+    logits: &Tensor,
+    targets: &[Vec<usize>],
+    loss_mask: Option<&[Vec<u8>]>,
+    ignore_index: Option<usize>,
+) -> Result<(f32, Tensor)> {
+    let mut grad = vec![0.0; logits.len()];
+    let (sum, count) =
+        negative_log_likelihood(logits, targets, loss_mask, ignore_index, Some(&mut grad))?;
+    grad.iter_mut().for_each(|g| *g /= count as f32);
+    Ok((sum / count as f32, Tensor::new(grad, logits.shape())))
+}
+
+// 困惑度为平均负对数似然的exp，可以理解为模型在每个位置平均从多少个token中犹豫
+pub fn perplexity(loss: f32) -> f32 {
+    loss.exp()
+}
+
+// 返回(负对数似然之和, 参与计算的位置数)。`grad`不为`None`时写入未归一化的梯度
+fn negative_log_likelihood(
+    logits: &Tensor,
+    targets: &[Vec<usize>],
+    loss_mask: Option<&[Vec<u8>]>,
+    ignore_index: Option<usize>,
+    mut grad: Option<&mut [f32]>,
+) -> Result<(f32, usize)> {
+    let [batch, seq_len, vocab_size] = *logits.shape() else {
+        return Err(shape_error!(
+            "Expected (B, T, vocab_size) logits, got {:?}",
+            logits.shape()
+        ));
+    };
+    if targets.len() != batch || targets.iter().any(|row| row.len() != seq_len) {
+        return Err(shape_error!(
+            "Targets do not match logits of shape {:?}",
+            logits.shape()
+        ));
+    }
+    if let Some(loss_mask) = loss_mask
+        && (loss_mask.len() != batch || loss_mask.iter().any(|row| row.len() != seq_len))
+    {
+        return Err(shape_error!(
+            "Loss mask does not match logits of shape {:?}",
+            logits.shape()
+        ));
+    }
+
+    let (mut sum, mut count) = (0.0, 0);
+    let rows = logits.data().chunks(vocab_size.max(1));
+    for (i, (row, &target)) in rows.zip(targets.iter().flatten()).enumerate() {
+        let masked = loss_mask.is_some_and(|mask| mask[i / seq_len][i % seq_len] == 0);
+        if masked || ignore_index == Some(target) {
+            continue;
+        }
+        if target >= vocab_size {
+            return Err(invalid!(
+                "Target {target} out of range for vocab size {vocab_size}"
+            ));
+        }
+
+        // log Σ exp(x) = max + log Σ exp(x - max)
+        let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exp_sum = row.iter().map(|x| (x - max).exp()).sum::<f32>();
+        let log_sum_exp = max + exp_sum.ln();
+        sum += log_sum_exp - row[target];
+        count += 1;
+
+        if let Some(grad) = grad.as_deref_mut() {
+            let grad = &mut grad[i * vocab_size..(i + 1) * vocab_size];
+            for (g, x) in grad.iter_mut().zip(row) {
+                *g = (x - log_sum_exp).exp();
+            }
+            grad[target] -= 1.0;
+        }
+    }
+
+    if count == 0 {
+        return Err(invalid!("All targets are ignored"));
+    }
+    Ok((sum, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_cross_entropy() -> Result<()> {
+        // 均匀分布时loss为ln(vocab_size)，困惑度为vocab_size
+        let logits = Tensor::new(vec![0.0; 2 * 3 * 5], &[2, 3, 5]);
+        let targets = vec![vec![0, 1, 2], vec![3, 4, 0]];
+        let loss = cross_entropy(&logits, &targets, None)?;
+        println!("{loss} {}", perplexity(loss));
+        assert!((loss - 5f32.ln()).abs() < 1e-6);
+        assert!((perplexity(loss) - 5.0).abs() < 1e-4);
+
+        let logits = Tensor::randn(&[2, 3, 5], 2.0, &mut StdRng::seed_from_u64(123));
+        let (loss, grad) = cross_entropy_with_grad(&logits, &targets, Some(4))?;
+        assert_eq!(loss, cross_entropy(&logits, &targets, Some(4))?);
+        // 被忽略的位置梯度为0，每个位置的梯度之和为0
+        assert!(grad.data()[20..25].iter().all(|g| *g == 0.0));
+        for row in grad.data().chunks(5) {
+            assert!(row.iter().sum::<f32>().abs() < 1e-6);
+        }
+
+        let eps = 1e-2;
+        for i in 0..logits.len() {
+            let mut plus = logits.clone();
+            plus.data_mut()[i] += eps;
+            let mut minus = logits.clone();
+            minus.data_mut()[i] -= eps;
+
+            let numeric = (cross_entropy(&plus, &targets, Some(4))?
+                - cross_entropy(&minus, &targets, Some(4))?)
+                / (2.0 * eps);
+            assert!(
+                (numeric - grad.data()[i]).abs() < 1e-3,
+                "logits[{i}]: numeric {numeric}, analytic {}",
+                grad.data()[i]
+            );
+        }
+
+        assert!(cross_entropy(&logits, &[vec![0, 1, 2]], None).is_err());
+        assert!(cross_entropy(&logits, &[vec![0, 1, 5], vec![0; 3]], None).is_err());
+        assert!(cross_entropy(&logits, &[vec![1; 3], vec![1; 3]], Some(1)).is_err());
+
+        // 掩码为0的位置与`ignore_index`的效果相同
+        let mask = vec![vec![1, 1, 1], vec![1, 0, 1]];
+        let (masked_loss, masked_grad) =
+            masked_cross_entropy_with_grad(&logits, &targets, Some(&mask), None)?;
+        assert_eq!((masked_loss, &masked_grad), (loss, &grad));
+        let mask = vec![vec![0, 1, 1], vec![1, 1, 1]];
+        let loss = masked_cross_entropy(&logits, &targets, Some(&mask), Some(4))?;
+        let expected = cross_entropy(&logits, &[vec![4, 1, 2], vec![3, 4, 0]], Some(4))?;
+        assert_eq!(loss, expected);
+        assert!(masked_cross_entropy(&logits, &targets, Some(&mask[..1]), None).is_err());
+        assert!(
+            masked_cross_entropy(&logits, &targets, Some(&[vec![0; 3], vec![0; 3]]), None).is_err()
+        );
+        Ok(())
+    }
+}
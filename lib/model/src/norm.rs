@@ -0,0 +1,257 @@
+use crate::error::{shape_error, Result};
+use crate::{Param, Tensor};
+
+// 书中第4章的`LayerNorm`：对最后一维做归一化，再乘以可学习的`scale`、加上`shift`。
+// 与GPT-2相同使用有偏方差（除以n）
+#[derive(Debug, Clone)]
+pub struct LayerNorm {
+    scale: Param,
+    shift: Param,
+    eps: f32,
+    // 上一次`forward`归一化之后的值和每一行的`1/σ`，`backward`时使用
+    normalized: Option<Tensor>,
+    inv_std: Vec<f32>,
+}
+
+impl LayerNorm {
+    pub fn new(emb_dim: usize) -> Self {
+        Self::with_eps(emb_dim, 1e-5)
+    }
+
+    pub fn with_eps(emb_dim: usize, eps: f32) -> Self {
+        Self::from_weights(
+            Tensor::new(vec![1.0; emb_dim], &[emb_dim]),
+            Tensor::zeros(&[emb_dim]),
+            eps,
+        )
+    }
+
+    pub fn from_weights(scale: Tensor, shift: Tensor, eps: f32) -> Self {
+        assert_eq!(scale.shape().len(), 1, "LayerNorm scale must be 1D");
+        assert_eq!(scale.shape(), shift.shape(), "Scale does not match shift");
+
+        LayerNorm {
+            scale: Param::new(scale),
+            shift: Param::new(shift),
+            eps,
+            normalized: None,
+            inv_std: vec![],
+        }
+    }
+
+    pub fn emb_dim(&self) -> usize {
+        self.scale.shape()[0]
+    }
+
+    pub fn eps(&self) -> f32 {
+        self.eps
+    }
+
+    pub fn scale(&self) -> &Param {
+        &self.scale
+    }
+
+    pub fn scale_mut(&mut self) -> &mut Param {
+        &mut self.scale
+    }
+
+    pub fn shift(&self) -> &Param {
+        &self.shift
+    }
+
+    pub fn shift_mut(&mut self) -> &mut Param {
+        &mut self.shift
+    }
+
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor> {
+        let (normalized, inv_std) = self.normalize(input)?;
+        let output = self.affine(&normalized);
+        self.normalized = Some(normalized);
+        self.inv_std = inv_std;
+        Ok(output)
+    }
+
+    // 只计算输出，不保存反向传播需要的中间结果
+    pub fn apply(&self, input: &Tensor) -> Result<Tensor> {
+        let (normalized, _) = self.normalize(input)?;
+        Ok(self.affine(&normalized))
+    }
+
+    fn normalize(&self, input: &Tensor) -> Result<(Tensor, Vec<f32>)> {
+        let dim = self.emb_dim();
+        if input.shape().last() != Some(&dim) {
+            return Err(shape_error!(
+                "Expected last dim {dim}, got {:?}",
+                input.shape()
+            ));
+        }
+
+        let mut normalized = input.clone();
+        let mut inv_stds = Vec::with_capacity(input.len() / dim.max(1));
+        for row in normalized.data_mut().chunks_mut(dim) {
+            let mean = row.iter().sum::<f32>() / dim as f32;
+            let var = row.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / dim as f32;
+            let inv_std = 1.0 / (var + self.eps).sqrt();
+            row.iter_mut().for_each(|x| *x = (*x - mean) * inv_std);
+            inv_stds.push(inv_std);
+        }
+        Ok((normalized, inv_stds))
+    }
+
+    fn affine(&self, normalized: &Tensor) -> Tensor {
+        let mut output = normalized.clone();
+        let (scale, shift) = (self.scale.value().data(), self.shift.value().data());
+        for row in output.data_mut().chunks_mut(self.emb_dim()) {
+            for ((x, s), b) in row.iter_mut().zip(scale).zip(shift) {
+                *x = *x * s + b;
+            }
+        }
+        output
+    }
+
+    // `dx = (dx̂ - mean(dx̂) - x̂·mean(dx̂·x̂)) / σ`，其中`dx̂ = dy·scale`
+    pub fn backward(&mut self, grad_output: &Tensor) -> Tensor {
+        let normalized = self
+            .normalized
+            .as_ref()
+            .expect("LayerNorm::backward called before forward");
+        assert_eq!(
+            grad_output.len(),
+            normalized.len(),
+            "Gradient does not match the last forward input"
+        );
+
+        let dim = self.emb_dim();
+        let mut grad_input = grad_output.clone();
+        let scale = self.scale.value().data().to_vec();
+        let grad_scale = self.scale.grad_mut().data_mut();
+
+        for (((grad, out), x_hat), inv_std) in grad_input
+            .data_mut()
+            .chunks_mut(dim)
+            .zip(grad_output.data().chunks(dim))
+            .zip(normalized.data().chunks(dim))
+            .zip(&self.inv_std)
+        {
+            for ((g, dy), x) in grad_scale.iter_mut().zip(out).zip(x_hat) {
+                *g += dy * x;
+            }
+
+            let mut mean = 0.0;
+            let mut mean_dot = 0.0;
+            for ((g, s), x) in grad.iter_mut().zip(&scale).zip(x_hat) {
+                *g *= s;
+                mean += *g;
+                mean_dot += *g * x;
+            }
+            let (mean, mean_dot) = (mean / dim as f32, mean_dot / dim as f32);
+            for (g, x) in grad.iter_mut().zip(x_hat) {
+                *g = (*g - mean - x * mean_dot) * inv_std;
+            }
+        }
+
+        let grad_shift = self.shift.grad_mut().data_mut();
+        for row in grad_output.data().chunks(dim) {
+            for (g, dy) in grad_shift.iter_mut().zip(row) {
+                *g += dy;
+            }
+        }
+
+        grad_input
+    }
+
+    pub(crate) fn clear_cache(&mut self) {
+        self.normalized = None;
+        self.inv_std = vec![];
+    }
+
+    // 归一化在f32下计算，半精度时也按f32保存
+    pub(crate) fn saved_bytes(&self) -> usize {
+        self.normalized
+            .as_ref()
+            .map_or(0, |x| size_of_val(x.data()))
+            + size_of_val(self.inv_std.as_slice())
+    }
+
+    pub fn zero_grad(&mut self) {
+        self.scale.zero_grad();
+        self.shift.zero_grad();
+    }
+
+    pub fn named_params(&self) -> Vec<(String, &Param)> {
+        vec![
+            ("scale".to_string(), &self.scale),
+            ("shift".to_string(), &self.shift),
+        ]
+    }
+
+    pub fn named_params_mut(&mut self) -> Vec<(String, &mut Param)> {
+        vec![
+            ("scale".to_string(), &mut self.scale),
+            ("shift".to_string(), &mut self.shift),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_layer_norm() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let input = Tensor::randn(&[2, 5], 1.0, &mut rng);
+        let mut norm = LayerNorm::new(5);
+
+        // 书中第4章：归一化之后每一行均值为0、方差为1
+        let output = norm.forward(&input).unwrap();
+        println!("{:?}", output);
+        for row in output.data().chunks(5) {
+            let mean = row.iter().sum::<f32>() / 5.0;
+            let var = row.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / 5.0;
+            assert!(mean.abs() < 1e-5);
+            assert!((var - 1.0).abs() < 1e-3);
+        }
+
+        // 数值梯度检查，loss = Σ output ⊙ r
+        let mut norm = LayerNorm::from_weights(
+            Tensor::randn(&[5], 1.0, &mut rng),
+            Tensor::randn(&[5], 1.0, &mut rng),
+            1e-5,
+        );
+        let r = Tensor::randn(&[2, 5], 1.0, &mut rng);
+        let loss = |norm: &mut LayerNorm, input: &Tensor| {
+            let output = norm.forward(input).unwrap();
+            output
+                .data()
+                .iter()
+                .zip(r.data())
+                .map(|(x, w)| x * w)
+                .sum::<f32>()
+        };
+
+        loss(&mut norm, &input);
+        let grad_input = norm.backward(&r);
+        let eps = 1e-2;
+        for i in 0..input.len() {
+            let mut plus = input.clone();
+            plus.data_mut()[i] += eps;
+            let mut minus = input.clone();
+            minus.data_mut()[i] -= eps;
+
+            let numeric = (loss(&mut norm, &plus) - loss(&mut norm, &minus)) / (2.0 * eps);
+            assert!(
+                (numeric - grad_input.data()[i]).abs() < 1e-2,
+                "input[{i}]: numeric {numeric}, analytic {}",
+                grad_input.data()[i]
+            );
+        }
+
+        let expected_shift = [0, 1, 2, 3, 4].map(|i| r.data()[i] + r.data()[i + 5]);
+        assert_eq!(norm.shift().grad().data(), expected_shift);
+
+        assert!(norm.forward(&Tensor::zeros(&[2, 4])).is_err());
+    }
+}
@@ -0,0 +1,343 @@
+use crate::error::{invalid, invalid_file, read_error, shape_error, write_error, Result};
+use crate::pretrained::read_tensor;
+use crate::{Param, Tensor};
+use safetensors::tensor::TensorView;
+use safetensors::{Dtype, SafeTensors};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// 保存在safetensors的metadata中的超参数
+const HYPERPARAMS: [&str; 6] = ["lr", "beta1", "beta2", "eps", "weight_decay", "steps"];
+
+// 书中第5章使用的`torch.optim.AdamW`。权重衰减与梯度解耦：直接把参数乘上`1 - lr × weight_decay`，
+// 不经过一阶和二阶矩。每个参数的矩按参数名保存，传入`model.named_params_mut()`即可
+#[derive(Debug, Clone)]
+pub struct AdamW {
+    lr: f32,
+    betas: (f32, f32),
+    eps: f32,
+    weight_decay: f32,
+    // 已经执行的`step`次数，用于偏差修正
+    steps: u64,
+    moments: HashMap<String, Moments>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Moments {
+    m: Tensor,
+    v: Tensor,
+}
+
+impl AdamW {
+    // 其余参数与PyTorch的默认值相同：betas = (0.9, 0.999)，eps = 1e-8
+    pub fn new(lr: f32, weight_decay: f32) -> Self {
+        AdamW {
+            lr,
+            betas: (0.9, 0.999),
+            eps: 1e-8,
+            weight_decay,
+            steps: 0,
+            moments: HashMap::new(),
+        }
+    }
+
+    pub fn with_betas(mut self, beta1: f32, beta2: f32) -> Self {
+        assert!(
+            (0.0..1.0).contains(&beta1) && (0.0..1.0).contains(&beta2),
+            "Betas must be in [0, 1)"
+        );
+        self.betas = (beta1, beta2);
+        self
+    }
+
+    pub fn with_eps(mut self, eps: f32) -> Self {
+        self.eps = eps;
+        self
+    }
+
+    pub fn lr(&self) -> f32 {
+        self.lr
+    }
+
+    // 学习率调度器每一步调用
+    pub fn set_lr(&mut self, lr: f32) {
+        self.lr = lr;
+    }
+
+    pub fn betas(&self) -> (f32, f32) {
+        self.betas
+    }
+
+    pub fn eps(&self) -> f32 {
+        self.eps
+    }
+
+    pub fn weight_decay(&self) -> f32 {
+        self.weight_decay
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // 用累加的梯度更新一次参数，梯度不会被清零。冻结的参数不更新，也没有矩
+    pub fn step<'a>(&mut self, params: impl IntoIterator<Item = (String, &'a mut Param)>) {
+        self.steps += 1;
+        let (beta1, beta2) = self.betas;
+        let bias_correction1 = 1.0 - beta1.powi(self.steps as i32);
+        let bias_correction2 = 1.0 - beta2.powi(self.steps as i32);
+
+        for (name, param) in params {
+            if !param.requires_grad() {
+                continue;
+            }
+            let moments = self.moments.entry(name).or_insert_with(|| Moments {
+                m: Tensor::zeros(param.shape()),
+                v: Tensor::zeros(param.shape()),
+            });
+            assert_eq!(
+                moments.m.shape(),
+                param.shape(),
+                "Parameter shape changed between steps"
+            );
+
+            let decay = 1.0 - self.lr * self.weight_decay;
+            let (values, grad) = param.value_and_grad();
+            let values = values.data_mut();
+            let (m, v) = (moments.m.data_mut(), moments.v.data_mut());
+            for (i, g) in grad.data().iter().enumerate() {
+                m[i] = beta1 * m[i] + (1.0 - beta1) * g;
+                v[i] = beta2 * v[i] + (1.0 - beta2) * g * g;
+                let m_hat = m[i] / bias_correction1;
+                let v_hat = v[i] / bias_correction2;
+                values[i] = values[i] * decay - self.lr * m_hat / (v_hat.sqrt() + self.eps);
+            }
+        }
+    }
+
+    // 与PyTorch的`optimizer.state_dict()`对应，矩保存为`{name}.exp_avg`和`{name}.exp_avg_sq`，
+    // 超参数和步数保存在metadata中，用于从检查点继续训练
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let f32_bytes = |data: &[f32]| data.iter().flat_map(|x| x.to_le_bytes()).collect();
+
+        let mut tensors: Vec<(String, Vec<usize>, Vec<u8>)> = vec![];
+        for (name, moments) in &self.moments {
+            let shape = moments.m.shape().to_vec();
+            tensors.push((
+                format!("{name}.exp_avg"),
+                shape.clone(),
+                f32_bytes(moments.m.data()),
+            ));
+            tensors.push((
+                format!("{name}.exp_avg_sq"),
+                shape,
+                f32_bytes(moments.v.data()),
+            ));
+        }
+        let views = tensors
+            .iter()
+            .map(|(name, shape, bytes)| {
+                let view = TensorView::new(Dtype::F32, shape.clone(), bytes)?;
+                Ok((name.as_str(), view))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let values = [
+            self.lr.to_string(),
+            self.betas.0.to_string(),
+            self.betas.1.to_string(),
+            self.eps.to_string(),
+            self.weight_decay.to_string(),
+            self.steps.to_string(),
+        ];
+        let metadata = HYPERPARAMS
+            .iter()
+            .map(|key| key.to_string())
+            .zip(values)
+            .collect::<HashMap<_, _>>();
+        let bytes = safetensors::serialize(views, Some(metadata))?;
+        fs::write(path, bytes).map_err(write_error(path))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<AdamW> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(read_error(path))?;
+        let tensors =
+            SafeTensors::deserialize(&bytes).map_err(invalid_file("safetensors file", path))?;
+        let (_, metadata) = SafeTensors::read_metadata(&bytes)?;
+
+        let metadata = metadata.metadata().clone().unwrap_or_default();
+        let hyperparam = |key: &str| -> Result<&str> {
+            metadata
+                .get(key)
+                .map(String::as_str)
+                .ok_or_else(|| invalid!("Missing optimizer metadata {key}"))
+        };
+        let float = |key: &str| -> Result<f32> {
+            hyperparam(key)?
+                .parse()
+                .map_err(|_| invalid!("Invalid optimizer metadata {key}"))
+        };
+
+        let mut moments = HashMap::new();
+        for name in tensors.names() {
+            let Some(param) = name.strip_suffix(".exp_avg") else {
+                continue;
+            };
+            let m = read_tensor(&tensors, name)?;
+            let v = read_tensor(&tensors, &format!("{param}.exp_avg_sq"))?;
+            if m.shape() != v.shape() {
+                return Err(shape_error!(
+                    "{param}: exp_avg and exp_avg_sq have different shapes"
+                ));
+            }
+            moments.insert(param.to_string(), Moments { m, v });
+        }
+
+        Ok(AdamW {
+            lr: float("lr")?,
+            betas: (float("beta1")?, float("beta2")?),
+            eps: float("eps")?,
+            weight_decay: float("weight_decay")?,
+            steps: hyperparam("steps")?
+                .parse()
+                .map_err(|_| invalid!("Invalid optimizer metadata steps"))?,
+            moments,
+        })
+    }
+
+    pub fn zero_grad<'a>(&self, params: impl IntoIterator<Item = (String, &'a mut Param)>) {
+        params.into_iter().for_each(|(_, param)| param.zero_grad());
+    }
+}
+
+// `torch.nn.utils.clip_grad_norm_`：所有未冻结参数的梯度拼在一起计算L2范数，超过`max_norm`时整体缩放到`max_norm`。
+// 返回缩放前的范数，便于在训练日志中观察
+pub fn clip_grad_norm<'a>(
+    params: impl IntoIterator<Item = (String, &'a mut Param)>,
+    max_norm: f32,
+) -> f32 {
+    let mut params = params
+        .into_iter()
+        .map(|(_, param)| param)
+        .filter(|param| param.requires_grad())
+        .collect::<Vec<_>>();
+    let total_norm = params
+        .iter()
+        .flat_map(|param| param.grad().data())
+        .map(|g| (*g as f64) * (*g as f64))
+        .sum::<f64>()
+        .sqrt() as f32;
+
+    let scale = max_norm / (total_norm + 1e-6);
+    if scale < 1.0 {
+        for param in params.iter_mut() {
+            param
+                .grad_mut()
+                .data_mut()
+                .iter_mut()
+                .for_each(|g| *g *= scale);
+        }
+    }
+    total_norm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GPTModel, GptConfig};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_adamw() {
+        // 第一步的更新量约为`lr × sign(grad)`
+        let mut param = Param::new(Tensor::new(vec![1.0, -2.0, 3.0], &[3]));
+        *param.grad_mut() = Tensor::new(vec![0.5, -0.1, 0.0], &[3]);
+        let mut optimizer = AdamW::new(0.1, 0.0);
+        optimizer.step([("w".to_string(), &mut param)]);
+        println!("{:?}", param.value());
+        let expected = [0.9, -1.9, 3.0];
+        for (x, y) in param.value().data().iter().zip(expected) {
+            assert!((x - y).abs() < 1e-5, "{x} vs {y}");
+        }
+
+        // 梯度为0时只有权重衰减，与学习率成正比，与梯度的大小无关
+        let mut param = Param::new(Tensor::new(vec![2.0], &[1]));
+        let mut optimizer = AdamW::new(0.1, 0.5);
+        optimizer.step([("w".to_string(), &mut param)]);
+        assert!((param.value().data()[0] - 2.0 * (1.0 - 0.05)).abs() < 1e-6);
+        assert_eq!(optimizer.steps(), 1);
+
+        // 最小化`Σ(x - 3)²`
+        let mut param = Param::new(Tensor::zeros(&[4]));
+        let mut optimizer = AdamW::new(0.1, 0.0).with_betas(0.9, 0.99);
+        for _ in 0..300 {
+            let grad = param.value().data().iter().map(|x| 2.0 * (x - 3.0));
+            *param.grad_mut() = Tensor::new(grad.collect(), &[4]);
+            optimizer.step([("x".to_string(), &mut param)]);
+            optimizer.zero_grad([("x".to_string(), &mut param)]);
+        }
+        assert!(param.grad().data().iter().all(|g| *g == 0.0));
+        for x in param.value().data() {
+            assert!((x - 3.0).abs() < 1e-2, "{x}");
+        }
+
+        // 对整个模型的参数更新，之后每个参数都有自己的矩
+        let config = GptConfig {
+            vocab_size: 11,
+            context_length: 4,
+            emb_dim: 8,
+            n_heads: 2,
+            n_layers: 1,
+            dropout: 0.0,
+            ..GptConfig::gpt2_small()
+        };
+        let mut model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123)).unwrap();
+        let logits = model.forward(&[vec![1, 2, 3]]).unwrap();
+        model.backward(&logits).unwrap();
+        let before = model.clone();
+        let mut optimizer = AdamW::new(4e-4, 0.1);
+        optimizer.step(model.named_params_mut());
+        assert_eq!(optimizer.moments.len(), model.named_params().len());
+        assert_ne!(
+            before.tok_emb().param().value(),
+            model.tok_emb().param().value()
+        );
+
+        // 从保存的状态继续更新，结果与不中断时相同
+        let path = std::env::temp_dir().join("test_adamw.safetensors");
+        optimizer.save(&path).unwrap();
+        let mut loaded = AdamW::load(&path).unwrap();
+        assert_eq!(loaded.steps(), 1);
+        assert_eq!(loaded.moments, optimizer.moments);
+        let mut resumed = model.clone();
+        optimizer.step(model.named_params_mut());
+        loaded.step(resumed.named_params_mut());
+        assert_eq!(model.named_params(), resumed.named_params());
+        assert!(AdamW::load(std::env::temp_dir().join("missing.safetensors")).is_err());
+    }
+
+    #[test]
+    fn test_clip_grad_norm() {
+        let mut a = Param::new(Tensor::zeros(&[2]));
+        let mut b = Param::new(Tensor::zeros(&[1]));
+        *a.grad_mut() = Tensor::new(vec![3.0, 0.0], &[2]);
+        *b.grad_mut() = Tensor::new(vec![-4.0], &[1]);
+
+        // 范数未超过`max_norm`时梯度不变
+        let norm = clip_grad_norm([("a".to_string(), &mut a), ("b".to_string(), &mut b)], 10.0);
+        assert!((norm - 5.0).abs() < 1e-6);
+        assert_eq!(a.grad().data(), &[3.0, 0.0]);
+
+        let norm = clip_grad_norm([("a".to_string(), &mut a), ("b".to_string(), &mut b)], 1.0);
+        println!("{norm} {:?} {:?}", a.grad(), b.grad());
+        assert!((norm - 5.0).abs() < 1e-6);
+        assert!((a.grad().data()[0] - 0.6).abs() < 1e-5);
+        assert!((b.grad().data()[0] + 0.8).abs() < 1e-5);
+        let norm = clip_grad_norm([("a".to_string(), &mut a), ("b".to_string(), &mut b)], 1.0);
+        assert!((norm - 1.0).abs() < 1e-5);
+    }
+}
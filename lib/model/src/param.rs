@@ -0,0 +1,65 @@
+use crate::Tensor;
+
+// 可训练的参数及其累加的梯度
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    value: Tensor,
+    grad: Tensor,
+    // 为false时参数被冻结：线性层不再累加其梯度，优化器和梯度裁剪跳过该参数
+    requires_grad: bool,
+}
+
+impl Param {
+    pub fn new(value: Tensor) -> Self {
+        Param {
+            grad: Tensor::zeros(value.shape()),
+            value,
+            requires_grad: true,
+        }
+    }
+
+    pub fn value(&self) -> &Tensor {
+        &self.value
+    }
+
+    pub fn value_mut(&mut self) -> &mut Tensor {
+        &mut self.value
+    }
+
+    pub fn grad(&self) -> &Tensor {
+        &self.grad
+    }
+
+    pub fn grad_mut(&mut self) -> &mut Tensor {
+        &mut self.grad
+    }
+
+    // 优化器更新参数时同时读取梯度
+    pub(crate) fn value_and_grad(&mut self) -> (&mut Tensor, &Tensor) {
+        (&mut self.value, &self.grad)
+    }
+
+    pub fn requires_grad(&self) -> bool {
+        self.requires_grad
+    }
+
+    pub fn set_requires_grad(&mut self, requires_grad: bool) {
+        self.requires_grad = requires_grad;
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        self.value.shape()
+    }
+
+    pub fn zero_grad(&mut self) {
+        self.grad.fill(0.0);
+    }
+}
+
+// 给子模块的参数名加上前缀，例如`weight` -> `out_head.weight`
+pub(crate) fn prefixed<P>(prefix: &str, params: Vec<(String, P)>) -> Vec<(String, P)> {
+    params
+        .into_iter()
+        .map(|(name, param)| (format!("{prefix}.{name}"), param))
+        .collect()
+}
@@ -0,0 +1,177 @@
+use crate::error::{invalid, shape_error, Result};
+use crate::{Param, Tensor};
+use rand::Rng;
+
+// 可学习的绝对位置嵌入：`context_length × embed_dim`的矩阵，第t个位置加上第t行
+#[derive(Debug, Clone)]
+pub struct PositionalEmbedding {
+    weight: Param,
+    // 上一次`forward`的序列长度
+    seq_len: usize,
+}
+
+impl PositionalEmbedding {
+    pub fn new(context_length: usize, embed_dim: usize, rng: &mut impl Rng) -> Self {
+        Self::from_weight(Tensor::randn(&[context_length, embed_dim], 1.0, rng))
+    }
+
+    pub fn from_weight(weight: Tensor) -> Self {
+        assert_eq!(
+            weight.shape().len(),
+            2,
+            "Positional embedding weight must be 2D"
+        );
+        PositionalEmbedding {
+            weight: Param::new(weight),
+            seq_len: 0,
+        }
+    }
+
+    pub fn context_length(&self) -> usize {
+        self.weight.shape()[0]
+    }
+
+    pub fn embed_dim(&self) -> usize {
+        self.weight.shape()[1]
+    }
+
+    pub fn weight(&self) -> &Tensor {
+        self.weight.value()
+    }
+
+    pub fn weight_mut(&mut self) -> &mut Tensor {
+        self.weight.value_mut()
+    }
+
+    pub fn grad(&self) -> &Tensor {
+        self.weight.grad()
+    }
+
+    pub fn param(&self) -> &Param {
+        &self.weight
+    }
+
+    pub fn param_mut(&mut self) -> &mut Param {
+        &mut self.weight
+    }
+
+    // 输入为`(B, T, C)`的词嵌入，只使用前T个位置，T不能超过`context_length`
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor> {
+        let output = self.apply(input, 0)?;
+        self.seq_len = input.shape()[1];
+        Ok(output)
+    }
+
+    // 从第`start`个位置开始加上位置嵌入，使用KV缓存生成时`start`为已缓存的长度。
+    // 只计算输出，不保存反向传播需要的状态
+    pub fn apply(&self, input: &Tensor, start: usize) -> Result<Tensor> {
+        self.apply_padded(input, start, &[])
+    }
+
+    // 批量生成时序列左侧有`padding[b]`个填充token，第b个序列的位置从填充之后开始计算
+    pub(crate) fn apply_padded(
+        &self,
+        input: &Tensor,
+        start: usize,
+        padding: &[usize],
+    ) -> Result<Tensor> {
+        let [_, seq_len, dim] = *input.shape() else {
+            return Err(shape_error!(
+                "Expected a (B, T, C) input, got {:?}",
+                input.shape()
+            ));
+        };
+        if dim != self.embed_dim() {
+            return Err(shape_error!(
+                "Expected embedding dim {}, got {dim}",
+                self.embed_dim()
+            ));
+        }
+        if start + seq_len > self.context_length() {
+            return Err(invalid!(
+                "Sequence length {} exceeds context length {}",
+                start + seq_len,
+                self.context_length()
+            ));
+        }
+
+        let weight = self.weight.value().data();
+        let mut output = input.clone();
+        if output.is_empty() {
+            return Ok(output);
+        }
+        for (b, sequence) in output.data_mut().chunks_mut(seq_len * dim).enumerate() {
+            let pad = padding.get(b).copied().unwrap_or(0);
+            for (t, row) in sequence.chunks_mut(dim).enumerate() {
+                let pos = (start + t).saturating_sub(pad);
+                for (x, p) in row.iter_mut().zip(&weight[pos * dim..(pos + 1) * dim]) {
+                    *x += p;
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    // 对批次求和后累加到前T行。输入的梯度与`grad_output`相同，不需要返回
+    pub fn backward(&mut self, grad_output: &Tensor) {
+        let size = self.seq_len * self.embed_dim();
+        if size == 0 {
+            return;
+        }
+        assert_eq!(
+            grad_output.len() % size,
+            0,
+            "Gradient does not match the last forward input"
+        );
+
+        let grad = &mut self.weight.grad_mut().data_mut()[..size];
+        for row in grad_output.data().chunks(size) {
+            for (g, x) in grad.iter_mut().zip(row) {
+                *g += x;
+            }
+        }
+    }
+
+    pub fn zero_grad(&mut self) {
+        self.weight.zero_grad();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Embedding;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_positional_embedding() {
+        let weight = Tensor::new((0..8).map(|x| x as f32).collect(), &[4, 2]);
+        let mut positional = PositionalEmbedding::from_weight(weight);
+
+        // 序列比`context_length`短时只加前两个位置
+        let input = Tensor::new(vec![1.0; 8], &[2, 2, 2]);
+        let output = positional.forward(&input).unwrap();
+        assert_eq!(output.data(), [1.0, 2.0, 3.0, 4.0, 1.0, 2.0, 3.0, 4.0]);
+
+        positional.backward(&Tensor::new(vec![1.0; 8], &[2, 2, 2]));
+        assert_eq!(
+            positional.grad().data(),
+            [2.0, 2.0, 2.0, 2.0, 0.0, 0.0, 0.0, 0.0]
+        );
+
+        assert!(positional.forward(&Tensor::zeros(&[1, 5, 2])).is_err());
+        assert!(positional.forward(&Tensor::zeros(&[1, 2, 3])).is_err());
+
+        // 书中第2章：词嵌入加上位置嵌入作为模型的输入
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut token_embedding = Embedding::new(4000, 256, &mut rng);
+        let mut positional = PositionalEmbedding::new(4, 256, &mut rng);
+        let embeddings = token_embedding
+            .forward(&[vec![40, 367, 2885, 1464], vec![1807, 3619, 402, 271]])
+            .unwrap();
+        let input = positional.forward(&embeddings).unwrap();
+        println!("{:?}", input.shape());
+        assert_eq!(input.shape(), [2, 4, 256]);
+    }
+}
@@ -0,0 +1,355 @@
+use crate::error::{invalid, invalid_file, read_error, shape_error, unsupported, Result};
+use crate::{GPTModel, GptConfig, Linear, Param, Tensor};
+use memmap2::Mmap;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use safetensors::{Dtype, SafeTensors};
+use std::fs::File;
+use std::path::Path;
+
+// 书中第5.5节：加载OpenAI/Hugging Face的GPT-2权重（`model.safetensors`）。
+// GPT-2使用`Conv1D`，权重按`(in, out)`存储，需要转置；`c_attn`把Q、K、V拼在一起，需要拆开
+pub fn load_gpt2_safetensors(path: impl AsRef<Path>, config: &GptConfig) -> Result<GPTModel> {
+    if !config.qkv_bias {
+        return Err(unsupported!(
+            "GPT-2 checkpoints have qkv bias, set qkv_bias = true"
+        ));
+    }
+    if config.kv_heads() != config.n_heads {
+        return Err(unsupported!(
+            "GPT-2 checkpoints use multi-head attention, n_kv_heads must be None"
+        ));
+    }
+
+    let path = path.as_ref();
+    let file = File::open(path).map_err(read_error(path))?;
+
+    // SAFETY: 文件以只读方式映射，使用期间不应被其他进程修改
+    let mmap = unsafe { Mmap::map(&file).map_err(read_error(path))? };
+    let tensors =
+        SafeTensors::deserialize(&mmap).map_err(invalid_file("safetensors file", path))?;
+
+    // `GPT2LMHeadModel`保存的名字带有`transformer.`前缀
+    let prefix = if tensors
+        .names()
+        .iter()
+        .any(|name| name.starts_with("transformer."))
+    {
+        "transformer."
+    } else {
+        ""
+    };
+    let get = |name: &str| read_tensor(&tensors, &format!("{prefix}{name}"));
+
+    // 随机初始化的权重会被全部覆盖
+    let mut model = GPTModel::new(config, &mut StdRng::seed_from_u64(0))?;
+    let wte = get("wte.weight")?;
+    assign(model.tok_emb_mut().param_mut(), wte.clone(), "wte.weight")?;
+    let pos_emb = model
+        .pos_emb_mut()
+        .ok_or_else(|| unsupported!("GPT-2 uses learned positional embeddings"))?;
+    assign(pos_emb.param_mut(), get("wpe.weight")?, "wpe.weight")?;
+
+    let emb_dim = config.emb_dim;
+    for (i, block) in model.trf_blocks_mut().iter_mut().enumerate() {
+        let name = |suffix: &str| format!("h.{i}.{suffix}");
+        let (ln_1, ln_2) = (name("ln_1"), name("ln_2"));
+        assign(
+            block.norm1_mut().scale_mut(),
+            get(&format!("{ln_1}.weight"))?,
+            &ln_1,
+        )?;
+        assign(
+            block.norm1_mut().shift_mut(),
+            get(&format!("{ln_1}.bias"))?,
+            &ln_1,
+        )?;
+        assign(
+            block.norm2_mut().scale_mut(),
+            get(&format!("{ln_2}.weight"))?,
+            &ln_2,
+        )?;
+        assign(
+            block.norm2_mut().shift_mut(),
+            get(&format!("{ln_2}.bias"))?,
+            &ln_2,
+        )?;
+
+        // 转置后为`(3·C, C)`，按行拆成Q、K、V
+        let c_attn = name("attn.c_attn");
+        let weight = get(&format!("{c_attn}.weight"))?;
+        let bias = get(&format!("{c_attn}.bias"))?;
+        if weight.shape() != [emb_dim, 3 * emb_dim] || bias.shape() != [3 * emb_dim] {
+            return Err(shape_error!(
+                "{c_attn}: expected shape [{emb_dim}, {}], got {:?}",
+                3 * emb_dim,
+                weight.shape()
+            ));
+        }
+        let weight = weight.transpose();
+        let mut qkv = weight
+            .data()
+            .chunks(emb_dim * emb_dim)
+            .zip(bias.data().chunks(emb_dim))
+            .map(|(w, b)| {
+                (
+                    Tensor::new(w.to_vec(), &[emb_dim, emb_dim]),
+                    Tensor::new(b.to_vec(), &[emb_dim]),
+                )
+            });
+        let mut next = || qkv.next().unwrap();
+        let att = block.att_mut();
+        let ((q_weight, q_bias), (k_weight, k_bias)) = (next(), next());
+        let (v_weight, v_bias) = next();
+        assign_linear(att.w_query_mut(), q_weight, q_bias, &c_attn)?;
+        assign_linear(att.w_key_mut(), k_weight, k_bias, &c_attn)?;
+        assign_linear(att.w_value_mut(), v_weight, v_bias, &c_attn)?;
+
+        let conv1d = |suffix: &str| -> Result<(Tensor, Tensor, String)> {
+            let conv = name(suffix);
+            let weight = get(&format!("{conv}.weight"))?.transpose();
+            Ok((weight, get(&format!("{conv}.bias"))?, conv))
+        };
+        let (weight, bias, conv) = conv1d("attn.c_proj")?;
+        assign_linear(att.out_proj_mut(), weight, bias, &conv)?;
+        let (weight, bias, conv) = conv1d("mlp.c_fc")?;
+        assign_linear(block.ff_mut().fc1_mut(), weight, bias, &conv)?;
+        let (weight, bias, conv) = conv1d("mlp.c_proj")?;
+        assign_linear(block.ff_mut().fc2_mut(), weight, bias, &conv)?;
+    }
+
+    assign(
+        model.final_norm_mut().scale_mut(),
+        get("ln_f.weight")?,
+        "ln_f",
+    )?;
+    assign(
+        model.final_norm_mut().shift_mut(),
+        get("ln_f.bias")?,
+        "ln_f",
+    )?;
+
+    // GPT-2的输出层与词嵌入共享权重，有的checkpoint额外保存了`lm_head.weight`。
+    // 不绑定权重时复制一份
+    if let Some(out_head) = model.out_head_mut() {
+        let lm_head = match tensors.tensor("lm_head.weight") {
+            Ok(_) => read_tensor(&tensors, "lm_head.weight")?,
+            Err(_) => wte,
+        };
+        assign(out_head.weight_mut(), lm_head, "lm_head")?;
+    }
+
+    Ok(model)
+}
+
+pub(crate) fn read_tensor(tensors: &SafeTensors, name: &str) -> Result<Tensor> {
+    let view = tensors
+        .tensor(name)
+        .map_err(|_| invalid!("Missing tensor {name}"))?;
+    if view.dtype() != Dtype::F32 {
+        return Err(invalid!(
+            "{name}: only F32 tensors are supported, got {:?}",
+            view.dtype()
+        ));
+    }
+
+    let data = view
+        .data()
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+        .collect();
+    Ok(Tensor::new(data, view.shape()))
+}
+
+// `Linear`的权重按PyTorch的`(out, in)`存储，`weight`需要已经转置
+fn assign_linear(linear: &mut Linear, weight: Tensor, bias: Tensor, name: &str) -> Result<()> {
+    assign(linear.weight_mut(), weight, name)?;
+    match linear.bias_mut() {
+        Some(param) => assign(param, bias, name),
+        None => Err(invalid!("{name}: layer was built without bias")),
+    }
+}
+
+// 形状不一致时说明`config`与checkpoint不匹配
+pub(crate) fn assign(param: &mut Param, tensor: Tensor, name: &str) -> Result<()> {
+    if param.shape() != tensor.shape() {
+        return Err(shape_error!(
+            "{name}: expected shape {:?}, got {:?}",
+            param.shape(),
+            tensor.shape()
+        ));
+    }
+    *param.value_mut() = tensor;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PosEncoding;
+    use safetensors::tensor::TensorView;
+
+    // 按Hugging Face `GPT2LMHeadModel`的格式保存，模拟下载的checkpoint
+    fn save_hf_gpt2(model: &GPTModel, path: &Path) {
+        let concat = |tensors: [&Tensor; 3]| {
+            let data = tensors
+                .iter()
+                .flat_map(|t| t.data().to_vec())
+                .collect::<Vec<_>>();
+            let mut shape = tensors[0].shape().to_vec();
+            shape[0] *= 3;
+            Tensor::new(data, &shape)
+        };
+
+        let mut tensors = vec![
+            ("wte.weight".to_string(), model.tok_emb().weight().clone()),
+            (
+                "wpe.weight".to_string(),
+                model.pos_emb().unwrap().weight().clone(),
+            ),
+            (
+                "ln_f.weight".to_string(),
+                model.final_norm().scale().value().clone(),
+            ),
+            (
+                "ln_f.bias".to_string(),
+                model.final_norm().shift().value().clone(),
+            ),
+        ];
+        for (i, block) in model.trf_blocks().iter().enumerate() {
+            let att = block.att();
+            let (q, k, v) = (att.w_query(), att.w_key(), att.w_value());
+            let bias = |linear: &Linear| linear.bias().unwrap().value().clone();
+            let layers = [
+                ("ln_1.weight", block.norm1().scale().value().clone()),
+                ("ln_1.bias", block.norm1().shift().value().clone()),
+                ("ln_2.weight", block.norm2().scale().value().clone()),
+                ("ln_2.bias", block.norm2().shift().value().clone()),
+                (
+                    "attn.c_attn.weight",
+                    concat([q.weight().value(), k.weight().value(), v.weight().value()])
+                        .transpose(),
+                ),
+                ("attn.c_attn.bias", concat([&bias(q), &bias(k), &bias(v)])),
+                (
+                    "attn.c_proj.weight",
+                    att.out_proj().weight().value().transpose(),
+                ),
+                ("attn.c_proj.bias", bias(att.out_proj())),
+                (
+                    "mlp.c_fc.weight",
+                    block.ff().fc1().weight().value().transpose(),
+                ),
+                ("mlp.c_fc.bias", bias(block.ff().fc1())),
+                (
+                    "mlp.c_proj.weight",
+                    block.ff().fc2().weight().value().transpose(),
+                ),
+                ("mlp.c_proj.bias", bias(block.ff().fc2())),
+            ];
+            tensors.extend(layers.map(|(name, tensor)| (format!("h.{i}.{name}"), tensor)));
+        }
+
+        let bytes = tensors
+            .iter()
+            .map(|(name, tensor)| {
+                let bytes = tensor.data().iter().flat_map(|x| x.to_le_bytes()).collect();
+                (
+                    format!("transformer.{name}"),
+                    tensor.shape().to_vec(),
+                    bytes,
+                )
+            })
+            .collect::<Vec<(String, Vec<usize>, Vec<u8>)>>();
+        let views = bytes.iter().map(|(name, shape, bytes)| {
+            (
+                name,
+                TensorView::new(Dtype::F32, shape.clone(), bytes).unwrap(),
+            )
+        });
+        std::fs::write(path, safetensors::serialize(views, None).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_load_gpt2_safetensors() -> Result<()> {
+        let config = GptConfig {
+            vocab_size: 13,
+            context_length: 8,
+            emb_dim: 8,
+            n_heads: 2,
+            n_layers: 2,
+            dropout: 0.0,
+            qkv_bias: true,
+            pos_encoding: PosEncoding::Learned,
+            tie_weights: false,
+            sliding_window: None,
+            sliding_window_layers: vec![],
+            n_kv_heads: None,
+            attention_chunk_size: None,
+        };
+        let mut model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        // GPT-2的输出层与词嵌入共享权重
+        let wte = model.tok_emb().weight().clone();
+        *model.out_head_mut().unwrap().weight_mut().value_mut() = wte;
+
+        let path = std::env::temp_dir().join("test_gpt2.safetensors");
+        save_hf_gpt2(&model, &path);
+
+        let loaded = load_gpt2_safetensors(&path, &config)?;
+        let batch = [vec![1, 5, 3, 12]];
+        let expected = model.forward(&batch)?;
+        let logits = loaded.forward_with_cache(&batch, &mut loaded.new_kv_cache())?;
+        for (x, y) in logits.data().iter().zip(expected.data()) {
+            assert!((x - y).abs() < 1e-5, "{x} vs {y}");
+        }
+
+        let tied = load_gpt2_safetensors(
+            &path,
+            &GptConfig {
+                tie_weights: true,
+                ..config.clone()
+            },
+        )?;
+        assert!(tied.out_head().is_none());
+        let logits = tied.forward_with_cache(&batch, &mut tied.new_kv_cache())?;
+        for (x, y) in logits.data().iter().zip(expected.data()) {
+            assert!((x - y).abs() < 1e-5, "{x} vs {y}");
+        }
+
+        // 配置与checkpoint不匹配
+        let err = load_gpt2_safetensors(
+            &path,
+            &GptConfig {
+                emb_dim: 4,
+                ..config.clone()
+            },
+        );
+        println!("{:?}", err.as_ref().err());
+        assert!(err.is_err());
+        assert!(load_gpt2_safetensors(
+            &path,
+            &GptConfig {
+                n_layers: 3,
+                ..config.clone()
+            }
+        )
+        .is_err());
+        assert!(load_gpt2_safetensors(
+            &path,
+            &GptConfig {
+                qkv_bias: false,
+                ..config.clone()
+            }
+        )
+        .is_err());
+        assert!(load_gpt2_safetensors(
+            &path,
+            &GptConfig {
+                pos_encoding: PosEncoding::Rope { theta: 10000.0 },
+                ..config
+            }
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use crate::error::{shape_error, Result};
+use crate::Tensor;
+
+// 按行（输出通道）对称量化的int8矩阵：`w[i][j] ≈ data[i][j] × scales[i]`，
+// 每行的scale为`max|w[i]| / 127`，内存约为f32的1/4
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedTensor {
+    data: Vec<i8>,
+    scales: Vec<f32>,
+    shape: [usize; 2],
+}
+
+impl QuantizedTensor {
+    pub fn quantize(tensor: &Tensor) -> Self {
+        let [rows, cols] = *tensor.shape() else {
+            panic!("Only 2D tensors can be quantized, got {:?}", tensor.shape());
+        };
+
+        let mut data = Vec::with_capacity(rows * cols);
+        let mut scales = Vec::with_capacity(rows);
+        for row in tensor.data().chunks(cols.max(1)).take(rows) {
+            let max = row.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+            let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+            data.extend(
+                row.iter()
+                    .map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8),
+            );
+            scales.push(scale);
+        }
+
+        QuantizedTensor {
+            data,
+            scales,
+            shape: [rows, cols],
+        }
+    }
+
+    // 从checkpoint中读取的数据恢复
+    pub fn from_parts(data: Vec<i8>, scales: Vec<f32>, shape: [usize; 2]) -> Result<Self> {
+        if data.len() != shape[0] * shape[1] || scales.len() != shape[0] {
+            return Err(shape_error!(
+                "{} values and {} scales do not match shape {shape:?}",
+                data.len(),
+                scales.len()
+            ));
+        }
+        Ok(QuantizedTensor {
+            data,
+            scales,
+            shape,
+        })
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn data(&self) -> &[i8] {
+        &self.data
+    }
+
+    pub fn scales(&self) -> &[f32] {
+        &self.scales
+    }
+
+    pub fn dequantize(&self) -> Tensor {
+        let cols = self.shape[1];
+        let data = self
+            .data
+            .chunks(cols.max(1))
+            .zip(&self.scales)
+            .flat_map(|(row, scale)| row.iter().map(move |x| *x as f32 * scale))
+            .collect();
+        Tensor::new(data, &self.shape)
+    }
+
+    // `output += input·Wᵀ`，`input`为`(rows, in)`。每次只反量化一行权重，不需要额外的f32副本
+    pub(crate) fn matmul_transposed(&self, input: &[f32], output: &mut [f32], rows: usize) {
+        let [out_features, in_features] = self.shape;
+        let mut weight = vec![0.0; in_features];
+        for (j, (row, scale)) in self
+            .data
+            .chunks(in_features.max(1))
+            .zip(&self.scales)
+            .enumerate()
+        {
+            for (w, q) in weight.iter_mut().zip(row) {
+                *w = *q as f32 * scale;
+            }
+            for r in 0..rows {
+                let x = &input[r * in_features..(r + 1) * in_features];
+                output[r * out_features + j] +=
+                    x.iter().zip(&weight).map(|(x, w)| x * w).sum::<f32>();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_quantize() {
+        let tensor = Tensor::new(vec![1.0, -0.5, 0.25, 0.0, 0.0, 0.0], &[2, 3]);
+        let quantized = QuantizedTensor::quantize(&tensor);
+        assert_eq!(quantized.data(), [127, -64, 32, 0, 0, 0]);
+        assert_eq!(quantized.scales()[1], 1.0);
+
+        // 误差不超过半个量化步长
+        let tensor = Tensor::randn(&[4, 16], 1.0, &mut StdRng::seed_from_u64(123));
+        let quantized = QuantizedTensor::quantize(&tensor);
+        let dequantized = quantized.dequantize();
+        for (i, (x, y)) in tensor.data().iter().zip(dequantized.data()).enumerate() {
+            let scale = quantized.scales()[i / 16];
+            assert!((x - y).abs() <= scale / 2.0 + 1e-6, "{x} vs {y}");
+        }
+
+        let input = [0.5, -1.0, 2.0, 0.0].repeat(4);
+        let mut output = vec![0.0; 4];
+        quantized.matmul_transposed(&input, &mut output, 1);
+        for (j, y) in output.iter().enumerate() {
+            let row = &dequantized.data()[j * 16..(j + 1) * 16];
+            let expected = row.iter().zip(&input).map(|(w, x)| w * x).sum::<f32>();
+            assert!((y - expected).abs() < 1e-4, "{y} vs {expected}");
+        }
+
+        assert!(QuantizedTensor::from_parts(vec![0; 5], vec![1.0; 2], [2, 3]).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+// 旋转位置编码（RoPE），Llama使用的位置编码：把每个头的Q和K按位置旋转，
+// 旋转后的点积只与两个位置的距离有关。与Hugging Face的Llama相同，前一半维度和后一半维度组成一对
+#[derive(Debug, Clone)]
+pub struct Rope {
+    head_dim: usize,
+    theta: f32,
+    // `(context_length, head_dim / 2)`
+    cos: Vec<f32>,
+    sin: Vec<f32>,
+}
+
+impl Rope {
+    // 第i对维度的频率为`theta^(-2i/head_dim)`
+    pub fn new(head_dim: usize, context_length: usize, theta: f32) -> Self {
+        assert!(
+            head_dim > 0 && head_dim.is_multiple_of(2),
+            "RoPE needs an even head_dim"
+        );
+
+        let half = head_dim / 2;
+        let (mut cos, mut sin) = (vec![], vec![]);
+        for pos in 0..context_length {
+            for i in 0..half {
+                let freq = theta.powf(-2.0 * i as f32 / head_dim as f32);
+                let angle = pos as f32 * freq;
+                cos.push(angle.cos());
+                sin.push(angle.sin());
+            }
+        }
+
+        Rope {
+            head_dim,
+            theta,
+            cos,
+            sin,
+        }
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.head_dim
+    }
+
+    pub fn theta(&self) -> f32 {
+        self.theta
+    }
+
+    pub fn context_length(&self) -> usize {
+        self.cos.len() / (self.head_dim / 2)
+    }
+
+    // `data`按`(B·H, T, head_dim)`排列，第t行的位置为`start + t`
+    pub fn apply(&self, data: &mut [f32], seq_len: usize, start: usize) {
+        self.rotate(data, seq_len, start, 1.0, |_| 0);
+    }
+
+    // 旋转矩阵是正交的，反向传播时反向旋转梯度
+    pub fn apply_inverse(&self, data: &mut [f32], seq_len: usize, start: usize) {
+        self.rotate(data, seq_len, start, -1.0, |_| 0);
+    }
+
+    // 每个序列有`heads`个头，第b个序列左侧有`padding[b]`个填充token，位置从填充之后开始计算
+    pub(crate) fn apply_padded(
+        &self,
+        data: &mut [f32],
+        seq_len: usize,
+        start: usize,
+        heads: usize,
+        padding: &[usize],
+    ) {
+        self.rotate(data, seq_len, start, 1.0, |head| {
+            padding.get(head / heads).copied().unwrap_or(0)
+        });
+    }
+
+    fn rotate(
+        &self,
+        data: &mut [f32],
+        seq_len: usize,
+        start: usize,
+        sign: f32,
+        padding: impl Fn(usize) -> usize,
+    ) {
+        if data.is_empty() {
+            return;
+        }
+        assert!(
+            start + seq_len <= self.context_length(),
+            "Position {} exceeds RoPE context length {}",
+            start + seq_len,
+            self.context_length()
+        );
+
+        let half = self.head_dim / 2;
+        for (h, head) in data.chunks_mut(seq_len * self.head_dim).enumerate() {
+            let pad = padding(h);
+            for (t, row) in head.chunks_mut(self.head_dim).enumerate() {
+                let offset = (start + t).saturating_sub(pad) * half;
+                let (x1, x2) = row.split_at_mut(half);
+                for i in 0..half {
+                    let (cos, sin) = (self.cos[offset + i], sign * self.sin[offset + i]);
+                    let (a, b) = (x1[i], x2[i]);
+                    x1[i] = a * cos - b * sin;
+                    x2[i] = b * cos + a * sin;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rope() {
+        let rope = Rope::new(4, 16, 10000.0);
+        assert_eq!(rope.context_length(), 16);
+
+        let q = [0.3, -1.2, 0.8, 0.5];
+        let k = [1.1, 0.4, -0.7, 0.9];
+        let dot_at = |m: usize, n: usize| {
+            let (mut q, mut k) = (q, k);
+            rope.apply(&mut q, 1, m);
+            rope.apply(&mut k, 1, n);
+            q.iter().zip(&k).map(|(a, b)| a * b).sum::<f32>()
+        };
+
+        // 点积只与相对位置有关
+        println!("{} {}", dot_at(3, 1), dot_at(10, 8));
+        assert!((dot_at(3, 1) - dot_at(10, 8)).abs() < 1e-4);
+        assert!((dot_at(0, 0) - dot_at(7, 7)).abs() < 1e-4);
+
+        // 位置0不旋转，旋转不改变长度，可以还原
+        let mut x = q;
+        rope.apply(&mut x, 1, 0);
+        assert_eq!(x, q);
+        rope.apply(&mut x, 1, 5);
+        let norm = |x: &[f32]| x.iter().map(|v| v * v).sum::<f32>();
+        assert!((norm(&x) - norm(&q)).abs() < 1e-5);
+        rope.apply_inverse(&mut x, 1, 5);
+        for (a, b) in x.iter().zip(&q) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+}
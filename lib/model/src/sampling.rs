@@ -0,0 +1,265 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// 解码参数，CLI通过它配置采样。处理顺序：惩罚 -> temperature -> top_k -> top_p
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SamplingConfig {
+    // 为0时使用贪心解码
+    pub temperature: f32,
+    pub top_k: Option<usize>,
+    // 只保留累计概率达到`top_p`的最小token集合
+    pub top_p: Option<f32>,
+    // 与CTRL相同：已生成token的正logits除以该值，负logits乘以该值，1.0表示不惩罚
+    pub repetition_penalty: f32,
+    // 已生成token的logits减去`出现次数 × frequency_penalty`，0表示不惩罚
+    pub frequency_penalty: f32,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig {
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+            repetition_penalty: 1.0,
+            frequency_penalty: 0.0,
+        }
+    }
+}
+
+impl SamplingConfig {
+    pub fn greedy() -> Self {
+        SamplingConfig {
+            temperature: 0.0,
+            ..Self::default()
+        }
+    }
+
+    // `generated`为已经生成的token，用于计算重复惩罚
+    pub fn sample(&self, logits: &[f32], generated: &[usize], rng: &mut impl Rng) -> usize {
+        let mut logits = logits.to_vec();
+        apply_penalties(
+            &mut logits,
+            generated,
+            self.repetition_penalty,
+            self.frequency_penalty,
+        );
+
+        if self.temperature <= 0.0 {
+            return argmax(&logits);
+        }
+
+        logits.iter_mut().for_each(|x| *x /= self.temperature);
+        if let Some(k) = self.top_k {
+            top_k_filter(&mut logits, k);
+        }
+        if let Some(p) = self.top_p {
+            top_p_filter(&mut logits, p);
+        }
+
+        let probs = softmax(&logits);
+        multinomial(&probs, rng)
+    }
+}
+
+// 书中第5章的解码策略：`top_k`只保留概率最大的k个token，`temperature`缩放logits后按概率采样。
+// `temperature`为0时退化为贪心解码
+pub fn sample_next(
+    logits: &[f32],
+    temperature: f32,
+    top_k: Option<usize>,
+    rng: &mut impl Rng,
+) -> usize {
+    let config = SamplingConfig {
+        temperature,
+        top_k,
+        ..SamplingConfig::default()
+    };
+    config.sample(logits, &[], rng)
+}
+
+pub fn apply_penalties(
+    logits: &mut [f32],
+    generated: &[usize],
+    repetition_penalty: f32,
+    frequency_penalty: f32,
+) {
+    if repetition_penalty == 1.0 && frequency_penalty == 0.0 {
+        return;
+    }
+
+    let mut counts = HashMap::new();
+    for &id in generated.iter().filter(|id| **id < logits.len()) {
+        *counts.entry(id).or_insert(0usize) += 1;
+    }
+
+    for (id, count) in counts {
+        let x = &mut logits[id];
+        *x = if *x > 0.0 {
+            *x / repetition_penalty
+        } else {
+            *x * repetition_penalty
+        };
+        *x -= frequency_penalty * count as f32;
+    }
+}
+
+// 按概率从大到小累加，达到`p`之后的token设为`-inf`，至少保留概率最大的token
+pub fn top_p_filter(logits: &mut [f32], p: f32) {
+    if p >= 1.0 {
+        return;
+    }
+
+    let probs = softmax(logits);
+    let mut order = (0..logits.len()).collect::<Vec<_>>();
+    order.sort_unstable_by(|a, b| probs[*b].total_cmp(&probs[*a]));
+
+    let mut cumulative = 0.0;
+    for (rank, &id) in order.iter().enumerate() {
+        if rank > 0 && cumulative >= p {
+            logits[id] = f32::NEG_INFINITY;
+        }
+        cumulative += probs[id];
+    }
+}
+
+// 第一个最大值的下标，与`torch.argmax`相同
+pub fn argmax(logits: &[f32]) -> usize {
+    logits
+        .iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |(best, max), (i, &x)| {
+            if x > max {
+                (i, x)
+            } else {
+                (best, max)
+            }
+        })
+        .0
+}
+
+// 小于第k大的值设为`-inf`，相同的值都会保留
+pub fn top_k_filter(logits: &mut [f32], k: usize) {
+    if k == 0 || k >= logits.len() {
+        return;
+    }
+
+    let mut sorted = logits.to_vec();
+    sorted.sort_unstable_by(|a, b| b.total_cmp(a));
+    let min = sorted[k - 1];
+    logits
+        .iter_mut()
+        .filter(|x| **x < min)
+        .for_each(|x| *x = f32::NEG_INFINITY);
+}
+
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp = logits.iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+    let sum = exp.iter().sum::<f32>();
+    exp.into_iter().map(|x| x / sum).collect()
+}
+
+// 按概率抽取一个下标
+pub fn multinomial(probs: &[f32], rng: &mut impl Rng) -> usize {
+    let mut threshold = rng.random::<f32>() * probs.iter().sum::<f32>();
+    for (i, p) in probs.iter().enumerate() {
+        if *p > 0.0 && threshold < *p {
+            return i;
+        }
+        threshold -= p;
+    }
+
+    // 浮点误差导致没有选中时，返回最后一个概率不为0的下标
+    probs.iter().rposition(|p| *p > 0.0).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_sampling() {
+        // 书中第5章的例子，6.28不是`TAU`
+        #[allow(clippy::approx_constant)]
+        let logits = [4.51, 0.89, -1.90, 6.75, 1.63, -1.62, -1.89, 6.28, 1.79];
+        assert_eq!(argmax(&logits), 3);
+
+        let mut top_k = logits.to_vec();
+        top_k_filter(&mut top_k, 3);
+        println!("{top_k:?}");
+        let kept = (0..9).filter(|i| top_k[*i].is_finite()).collect::<Vec<_>>();
+        assert_eq!(kept, [0, 3, 7]);
+
+        let mut rng = StdRng::seed_from_u64(123);
+        assert_eq!(sample_next(&logits, 0.0, None, &mut rng), 3);
+
+        // top_k为1时与贪心解码相同
+        for _ in 0..10 {
+            assert_eq!(sample_next(&logits, 5.0, Some(1), &mut rng), 3);
+        }
+
+        let mut counts = [0; 9];
+        for _ in 0..1000 {
+            counts[sample_next(&logits, 1.0, Some(3), &mut rng)] += 1;
+        }
+        println!("{counts:?}");
+        assert_eq!(
+            counts.iter().sum::<usize>(),
+            counts[0] + counts[3] + counts[7]
+        );
+        assert!(counts[3] > counts[7] && counts[7] > counts[0]);
+
+        let sample = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20)
+                .map(|_| sample_next(&logits, 1.5, None, &mut rng))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sample(42), sample(42));
+    }
+
+    #[test]
+    fn test_sampling_config() {
+        // 概率为[0.5, 0.3, 0.15, 0.05]
+        let logits = [0.5f32, 0.3, 0.15, 0.05].map(|p| p.ln());
+        let mut top_p = logits.to_vec();
+        top_p_filter(&mut top_p, 0.75);
+        let kept = (0..4).filter(|i| top_p[*i].is_finite()).collect::<Vec<_>>();
+        assert_eq!(kept, [0, 1]);
+
+        // 最大的概率已经超过`top_p`时只保留它
+        let mut top_p = logits.to_vec();
+        top_p_filter(&mut top_p, 0.1);
+        assert_eq!(top_p.iter().filter(|x| x.is_finite()).count(), 1);
+
+        let mut penalized = [2.0, -2.0, 1.0];
+        apply_penalties(&mut penalized, &[0, 1, 1, 5], 2.0, 0.5);
+        assert_eq!(penalized, [0.5, -5.0, 1.0]);
+
+        let greedy = SamplingConfig::greedy();
+        let mut rng = StdRng::seed_from_u64(123);
+        assert_eq!(greedy.sample(&[1.0, 3.0, 2.0], &[], &mut rng), 1);
+
+        // 重复惩罚让贪心解码不再选择已经生成的token
+        let config = SamplingConfig {
+            repetition_penalty: 2.0,
+            ..SamplingConfig::greedy()
+        };
+        assert_eq!(config.sample(&[1.0, 3.0, 2.0], &[1], &mut rng), 2);
+
+        let config = SamplingConfig {
+            temperature: 0.8,
+            top_k: Some(3),
+            top_p: Some(0.75),
+            ..SamplingConfig::default()
+        };
+        for _ in 0..100 {
+            assert!(config.sample(&logits, &[], &mut rng) < 2);
+        }
+        println!("{config:?}");
+    }
+}
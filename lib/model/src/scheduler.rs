@@ -0,0 +1,125 @@
+use crate::AdamW;
+use std::f32::consts::PI;
+
+// 附录D的学习率调度：学习率只由全局步数决定。`step`读取优化器已经执行的更新次数并设置本次更新的学习率，
+// 在每次`optimizer.step`之前调用，从检查点恢复的优化器会接着原来的进度继续
+pub trait LrScheduler {
+    // 第`step`次更新（从0开始）使用的学习率
+    fn lr_at(&self, step: u64) -> f32;
+
+    fn step(&self, optimizer: &mut AdamW) -> f32 {
+        let lr = self.lr_at(optimizer.steps());
+        optimizer.set_lr(lr);
+        lr
+    }
+}
+
+// 前`warmup_steps`步从`initial_lr`线性增加到`peak_lr`，之后按余弦曲线在`total_steps`时降到`min_lr`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WarmupCosine {
+    initial_lr: f32,
+    peak_lr: f32,
+    min_lr: f32,
+    warmup_steps: u64,
+    total_steps: u64,
+}
+
+impl WarmupCosine {
+    pub fn new(peak_lr: f32, warmup_steps: u64, total_steps: u64) -> Self {
+        assert!(
+            warmup_steps <= total_steps,
+            "Warmup steps must not exceed total steps"
+        );
+        WarmupCosine {
+            initial_lr: 0.0,
+            peak_lr,
+            min_lr: 0.0,
+            warmup_steps,
+            total_steps,
+        }
+    }
+
+    pub fn with_initial_lr(mut self, initial_lr: f32) -> Self {
+        self.initial_lr = initial_lr;
+        self
+    }
+
+    pub fn with_min_lr(mut self, min_lr: f32) -> Self {
+        self.min_lr = min_lr;
+        self
+    }
+}
+
+impl LrScheduler for WarmupCosine {
+    fn lr_at(&self, step: u64) -> f32 {
+        if step < self.warmup_steps {
+            let increment = (self.peak_lr - self.initial_lr) / self.warmup_steps as f32;
+            return self.initial_lr + step as f32 * increment;
+        }
+
+        // 超过`total_steps`后保持`min_lr`
+        let decay_steps = (self.total_steps - self.warmup_steps).max(1);
+        let progress = ((step - self.warmup_steps) as f32 / decay_steps as f32).min(1.0);
+        self.min_lr + (self.peak_lr - self.min_lr) * 0.5 * (1.0 + (PI * progress).cos())
+    }
+}
+
+// `torch.optim.lr_scheduler.StepLR`：每`step_size`步学习率乘以`gamma`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepDecay {
+    initial_lr: f32,
+    step_size: u64,
+    gamma: f32,
+}
+
+impl StepDecay {
+    pub fn new(initial_lr: f32, step_size: u64, gamma: f32) -> Self {
+        assert!(step_size > 0, "Step size must be greater than 0");
+        StepDecay {
+            initial_lr,
+            step_size,
+            gamma,
+        }
+    }
+}
+
+impl LrScheduler for StepDecay {
+    fn lr_at(&self, step: u64) -> f32 {
+        self.initial_lr * self.gamma.powi((step / self.step_size) as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Param, Tensor};
+
+    #[test]
+    fn test_lr_scheduler() {
+        let scheduler = WarmupCosine::new(1e-3, 10, 110)
+            .with_initial_lr(1e-4)
+            .with_min_lr(1e-5);
+        let lrs = (0..120).map(|i| scheduler.lr_at(i)).collect::<Vec<_>>();
+        println!("{:?}", &lrs[..12]);
+        assert!((lrs[0] - 1e-4).abs() < 1e-9);
+        assert!((lrs[5] - 5.5e-4).abs() < 1e-8);
+        assert!((lrs[10] - 1e-3).abs() < 1e-9);
+        assert!((lrs[60] - (1e-5 + 0.5 * (1e-3 - 1e-5))).abs() < 1e-8);
+        assert!(lrs[..=10].windows(2).all(|w| w[0] < w[1]));
+        assert!(lrs[10..=110].windows(2).all(|w| w[0] > w[1]));
+        assert!(lrs[110..].iter().all(|lr| (lr - 1e-5).abs() < 1e-9));
+
+        let scheduler = StepDecay::new(0.1, 3, 0.5);
+        let lrs = (0..7).map(|i| scheduler.lr_at(i)).collect::<Vec<_>>();
+        assert_eq!(lrs, [0.1, 0.1, 0.1, 0.05, 0.05, 0.05, 0.025]);
+
+        // 学习率由优化器的步数决定
+        let mut param = Param::new(Tensor::zeros(&[2]));
+        let mut optimizer = AdamW::new(1.0, 0.0);
+        for lr in lrs {
+            assert_eq!(scheduler.step(&mut optimizer), lr);
+            assert_eq!(optimizer.lr(), lr);
+            optimizer.step([("w".to_string(), &mut param)]);
+        }
+    }
+}
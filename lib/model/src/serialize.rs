@@ -0,0 +1,249 @@
+use crate::error::{invalid, invalid_file, read_error, shape_error, write_error, Result};
+use crate::lora::is_lora_param;
+use crate::pretrained::{assign, read_tensor};
+use crate::{GPTModel, GptConfig, QuantizedTensor};
+use memmap2::Mmap;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use safetensors::tensor::TensorView;
+use safetensors::{Dtype, SafeTensors};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+// 量化后的checkpoint在metadata中标记，int8权重保存为`{name}.weight`，每行的scale保存为`{name}.weight_scale`
+const QUANTIZATION: &str = "quantization";
+const INT8: &str = "int8";
+
+// 权重保存为safetensors，参数名与书中PyTorch模型的`state_dict`相同，
+// 可以直接用`safetensors.torch.load_file`读取。`GptConfig`保存在同名的`.json`文件中
+impl GPTModel {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let f32_bytes = |data: &[f32]| data.iter().flat_map(|x| x.to_le_bytes()).collect();
+
+        let mut tensors: Vec<(String, Dtype, Vec<usize>, Vec<u8>)> = vec![];
+        // LoRA用`save_lora`单独保存
+        for (name, param) in self.named_params() {
+            if is_lora_param(&name) {
+                continue;
+            }
+            let bytes = f32_bytes(param.value().data());
+            tensors.push((name, Dtype::F32, param.shape().to_vec(), bytes));
+        }
+        for (name, linear) in self.named_linears() {
+            if let Some(weight) = linear.quantized_weight() {
+                let bytes = weight.data().iter().map(|x| *x as u8).collect();
+                tensors.push((
+                    format!("{name}.weight"),
+                    Dtype::I8,
+                    weight.shape().to_vec(),
+                    bytes,
+                ));
+                let scales = f32_bytes(weight.scales());
+                let shape = vec![weight.scales().len()];
+                tensors.push((format!("{name}.weight_scale"), Dtype::F32, shape, scales));
+            }
+        }
+        let views = tensors
+            .iter()
+            .map(|(name, dtype, shape, bytes)| {
+                let view = TensorView::new(*dtype, shape.clone(), bytes)?;
+                Ok((name.as_str(), view))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Hugging Face的工具通过`format`判断使用哪个框架加载
+        let mut metadata = HashMap::from([("format".to_string(), "pt".to_string())]);
+        if self.is_quantized() {
+            metadata.insert(QUANTIZATION.to_string(), INT8.to_string());
+        }
+        let bytes = safetensors::serialize(views, Some(metadata))?;
+        fs::write(path, bytes).map_err(write_error(path))?;
+
+        let config_path = config_path(path);
+        let config = serde_json::to_string_pretty(self.config())?;
+        fs::write(&config_path, config).map_err(write_error(&config_path))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<GPTModel> {
+        let path = path.as_ref();
+        let config_path = config_path(path);
+        let config = fs::read_to_string(&config_path).map_err(read_error(&config_path))?;
+        let config: GptConfig =
+            serde_json::from_str(&config).map_err(invalid_file("config", &config_path))?;
+
+        let file = File::open(path).map_err(read_error(path))?;
+
+        // SAFETY: 文件以只读方式映射，使用期间不应被其他进程修改
+        let mmap = unsafe { Mmap::map(&file).map_err(read_error(path))? };
+        Self::from_bytes(&mmap, &config).map_err(invalid_file("checkpoint", path))
+    }
+
+    // 从内存中的safetensors数据加载，不需要文件系统（如在浏览器中），`config`为同名`.json`文件的内容
+    pub fn from_bytes(bytes: &[u8], config: &GptConfig) -> Result<GPTModel> {
+        let tensors = SafeTensors::deserialize(bytes)?;
+
+        let (_, metadata) = SafeTensors::read_metadata(bytes)?;
+        let quantization = metadata
+            .metadata()
+            .as_ref()
+            .and_then(|metadata| metadata.get(QUANTIZATION));
+
+        // 随机初始化的权重会被全部覆盖
+        let mut model = GPTModel::new(config, &mut StdRng::seed_from_u64(0))?;
+        match quantization.map(String::as_str) {
+            None => {}
+            Some(INT8) => model.quantize(),
+            Some(other) => return Err(invalid!("Unsupported quantization {other}")),
+        }
+
+        for (name, param) in model.named_params_mut() {
+            assign(param, read_tensor(&tensors, &name)?, &name)?;
+        }
+        for (name, linear) in model.named_linears_mut() {
+            if let Some(weight) = linear.quantized_weight_mut() {
+                let loaded = read_int8(&tensors, &name)?;
+                if loaded.shape() != weight.shape() {
+                    return Err(shape_error!(
+                        "{name}: expected shape {:?}, got {:?}",
+                        weight.shape(),
+                        loaded.shape()
+                    ));
+                }
+                *weight = loaded;
+            }
+        }
+        Ok(model)
+    }
+}
+
+fn read_int8(tensors: &SafeTensors, name: &str) -> Result<QuantizedTensor> {
+    let weight_name = format!("{name}.weight");
+    let view = tensors
+        .tensor(&weight_name)
+        .map_err(|_| invalid!("Missing tensor {weight_name}"))?;
+    let [rows, cols] = *view.shape() else {
+        return Err(shape_error!(
+            "{weight_name}: expected a 2D tensor, got {:?}",
+            view.shape()
+        ));
+    };
+    if view.dtype() != Dtype::I8 {
+        return Err(invalid!(
+            "{weight_name}: expected I8, got {:?}",
+            view.dtype()
+        ));
+    }
+
+    let data = view.data().iter().map(|x| *x as i8).collect();
+    let scales = read_tensor(tensors, &format!("{name}.weight_scale"))?.into_data();
+    QuantizedTensor::from_parts(data, scales, [rows, cols])
+}
+
+// `model.safetensors` -> `model.json`
+fn config_path(path: &Path) -> PathBuf {
+    path.with_extension("json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PosEncoding;
+
+    #[test]
+    fn test_save_load() -> Result<()> {
+        let config = GptConfig {
+            vocab_size: 13,
+            context_length: 8,
+            emb_dim: 8,
+            n_heads: 2,
+            n_layers: 2,
+            dropout: 0.1,
+            qkv_bias: false,
+            pos_encoding: PosEncoding::Learned,
+            tie_weights: false,
+            sliding_window: None,
+            sliding_window_layers: vec![],
+            n_kv_heads: None,
+            attention_chunk_size: None,
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let path = std::env::temp_dir().join("test_save_load.safetensors");
+        model.save(&path)?;
+        assert!(path.with_extension("json").exists());
+
+        let loaded = GPTModel::load(&path)?;
+        assert_eq!(loaded.config(), &config);
+        for ((name, x), (_, y)) in model.named_params().iter().zip(loaded.named_params()) {
+            assert_eq!(x.value(), y.value(), "{name}");
+        }
+
+        let names = model
+            .named_params()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        println!("{names:?}");
+        assert!(names.contains(&"trf_blocks.1.att.W_query.weight".to_string()));
+        assert!(names.contains(&"trf_blocks.0.ff.layers.2.bias".to_string()));
+        assert!(!names.contains(&"trf_blocks.0.att.W_query.bias".to_string()));
+
+        let from_bytes = GPTModel::from_bytes(&fs::read(&path)?, &config)?;
+        assert_eq!(
+            from_bytes.named_params()[0].1.value(),
+            model.named_params()[0].1.value()
+        );
+        assert!(GPTModel::from_bytes(b"not safetensors", &config).is_err());
+
+        assert!(GPTModel::load(std::env::temp_dir().join("missing.safetensors")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_load_quantized() -> Result<()> {
+        let config = GptConfig {
+            vocab_size: 13,
+            context_length: 8,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 2,
+            dropout: 0.0,
+            qkv_bias: true,
+            pos_encoding: PosEncoding::Learned,
+            tie_weights: false,
+            sliding_window: None,
+            sliding_window_layers: vec![],
+            n_kv_heads: None,
+            attention_chunk_size: None,
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let batch = [vec![1, 5, 3, 12]];
+        let expected = model.forward_with_cache(&batch, &mut model.new_kv_cache())?;
+
+        let mut quantized = model.clone();
+        quantized.quantize();
+        assert!(quantized.is_quantized());
+        assert_eq!(quantized.num_parameters(), model.num_parameters());
+        // 线性层的权重占大部分内存
+        println!(
+            "{} -> {} bytes",
+            model.parameter_bytes(),
+            quantized.parameter_bytes()
+        );
+        assert!(quantized.parameter_bytes() * 2 < model.parameter_bytes());
+
+        let logits = quantized.forward_with_cache(&batch, &mut quantized.new_kv_cache())?;
+        for (x, y) in logits.data().iter().zip(expected.data()) {
+            assert!((x - y).abs() < 5e-2, "{x} vs {y}");
+        }
+
+        let path = std::env::temp_dir().join("test_save_load_quantized.safetensors");
+        quantized.save(&path)?;
+        let loaded = GPTModel::load(&path)?;
+        assert!(loaded.is_quantized());
+        let reloaded = loaded.forward_with_cache(&batch, &mut loaded.new_kv_cache())?;
+        assert_eq!(reloaded, logits);
+        Ok(())
+    }
+}
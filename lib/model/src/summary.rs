@@ -0,0 +1,199 @@
+use crate::{GPTModel, GptConfig, Linear, Param, PosEncoding};
+use std::fmt::Write;
+
+const MB: f64 = 1024.0 * 1024.0;
+
+impl GptConfig {
+    // 不创建模型直接计算参数量，用于估算实验规模。124M模型不绑定权重时为163,009,536
+    pub fn num_parameters(&self) -> usize {
+        let (v, c) = (self.vocab_size, self.emb_dim);
+        let pos_emb = match self.pos_encoding {
+            PosEncoding::Learned => self.context_length * c,
+            PosEncoding::Rope { .. } => 0,
+        };
+        // 使用GQA时K和V的投影更小
+        let kv = self.kv_heads() * (c / self.n_heads);
+        let qkv_bias = if self.qkv_bias { c + 2 * kv } else { 0 };
+        // Q、K、V和输出投影，4倍扩展的前馈网络，两个LayerNorm
+        let block = 2 * c * c + 2 * c * kv + qkv_bias + c + 8 * c * c + 5 * c + 4 * c;
+        let out_head = if self.tie_weights { 0 } else { v * c };
+        v * c + pos_emb + self.n_layers * block + 2 * c + out_head
+    }
+}
+
+impl GPTModel {
+    // 量化后的权重也计算在内
+    pub fn num_parameters(&self) -> usize {
+        count(self.named_params(), self.named_linears())
+    }
+
+    // 书中第4章的练习：每个模块的参数量，以及`(batch_size, seq_len)`的输入训练一步需要的内存
+    pub fn summary(&self, batch_size: usize, seq_len: usize) -> String {
+        let config = self.config();
+        let mut out = String::new();
+        let mut line = |name: &str, params: usize| {
+            _ = writeln!(out, "{name:<24}{:>16}", thousands(params));
+        };
+
+        line("tok_emb", self.tok_emb().param().value().len());
+        if let Some(pos_emb) = self.pos_emb() {
+            line("pos_emb", pos_emb.param().value().len());
+        }
+        if let Some(block) = self.trf_blocks().first() {
+            let n_layers = self.trf_blocks().len();
+            line(
+                &format!("trf_blocks ({n_layers} ×)"),
+                n_layers * count(block.named_params(), block.named_linears()),
+            );
+            let att = block.att();
+            line("  att", count(att.named_params(), att.named_linears()));
+            line(
+                "  ff",
+                count(block.ff().named_params(), block.ff().named_linears()),
+            );
+            line(
+                "  norm1 + norm2",
+                count(block.norm1().named_params(), vec![])
+                    + count(block.norm2().named_params(), vec![]),
+            );
+        }
+        line(
+            "final_norm",
+            count(self.final_norm().named_params(), vec![]),
+        );
+        match self.out_head() {
+            Some(out_head) => line(
+                "out_head",
+                count(out_head.named_params(), vec![(String::new(), out_head)]),
+            ),
+            None => line("out_head (tied)", 0),
+        }
+
+        let total = self.num_parameters();
+        let params_mb = self.parameter_bytes() as f64 / MB;
+        let activations_mb =
+            activation_floats(config, batch_size, seq_len) as f64 * size_of::<f32>() as f64 / MB;
+        _ = writeln!(out, "{:<24}{:>16}", "total", thousands(total));
+        _ = writeln!(out, "parameters:  {params_mb:.2} MB");
+        // 量化后只能推理，不需要梯度
+        let grads_mb = if self.is_quantized() { 0.0 } else { params_mb };
+        _ = writeln!(out, "gradients:   {grads_mb:.2} MB");
+        _ = writeln!(
+            out,
+            "activations: {activations_mb:.2} MB (batch {batch_size}, seq_len {seq_len})"
+        );
+        _ = write!(
+            out,
+            "total:       {:.2} MB",
+            params_mb + grads_mb + activations_mb
+        );
+        out
+    }
+
+    // f32参数4字节，int8权重1字节加上每行一个f32的scale
+    pub fn parameter_bytes(&self) -> usize {
+        let params = self
+            .named_params()
+            .iter()
+            .map(|(_, param)| size_of_val(param.value().data()))
+            .sum::<usize>();
+        let quantized = self
+            .named_linears()
+            .iter()
+            .filter_map(|(_, linear)| linear.quantized_weight())
+            .map(|weight| size_of_val(weight.data()) + size_of_val(weight.scales()))
+            .sum::<usize>();
+        params + quantized
+    }
+}
+
+fn count(params: Vec<(String, &Param)>, linears: Vec<(String, &Linear)>) -> usize {
+    let quantized = linears
+        .iter()
+        .filter_map(|(_, linear)| linear.quantized_weight())
+        .map(|weight| weight.data().len())
+        .sum::<usize>();
+    params
+        .iter()
+        .map(|(_, param)| param.value().len())
+        .sum::<usize>()
+        + quantized
+}
+
+// 按`forward`为反向传播保存的中间结果估算，每个block大约保存20份`(B, T, C)`，
+// 以及注意力权重和它的dropout掩码两份`(B, H, T, T)`。分块计算时只保存每行的logsumexp
+fn activation_floats(config: &GptConfig, batch_size: usize, seq_len: usize) -> usize {
+    let btc = batch_size * seq_len * config.emb_dim;
+    let attention = match config.attention_chunk_size {
+        Some(_) => batch_size * config.n_heads * seq_len,
+        None => 2 * batch_size * config.n_heads * seq_len * seq_len,
+    };
+    let block = 20 * btc + attention;
+    let logits = batch_size * seq_len * config.vocab_size;
+    config.n_layers * block + 3 * btc + logits
+}
+
+// 163009536 -> 163,009,536
+fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_summary() {
+        // 书中第4章：163,009,536个参数，绑定权重后为124,412,160
+        let config = GptConfig::gpt2_small();
+        assert_eq!(config.num_parameters(), 163_009_536);
+        let tied = GptConfig {
+            tie_weights: true,
+            ..config
+        };
+        assert_eq!(tied.num_parameters(), 124_412_160);
+        assert_eq!(thousands(163_009_536), "163,009,536");
+        assert_eq!(thousands(768), "768");
+
+        let config = GptConfig {
+            vocab_size: 13,
+            context_length: 8,
+            emb_dim: 8,
+            n_heads: 2,
+            n_layers: 2,
+            dropout: 0.0,
+            qkv_bias: true,
+            pos_encoding: PosEncoding::Rope { theta: 10000.0 },
+            tie_weights: true,
+            sliding_window: None,
+            sliding_window_layers: vec![],
+            n_kv_heads: None,
+            attention_chunk_size: None,
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(0)).unwrap();
+        assert_eq!(model.num_parameters(), config.num_parameters());
+        let grouped = GptConfig {
+            n_kv_heads: Some(1),
+            ..config.clone()
+        };
+        let grouped_model = GPTModel::new(&grouped, &mut StdRng::seed_from_u64(0)).unwrap();
+        assert_eq!(grouped_model.num_parameters(), grouped.num_parameters());
+        assert!(grouped.num_parameters() < config.num_parameters());
+
+        let summary = model.summary(2, 8);
+        println!("{summary}");
+        assert!(summary.contains("trf_blocks (2 ×)"));
+        assert!(summary.contains("out_head (tied)"));
+        assert!(!summary.contains("pos_emb"));
+    }
+}
@@ -0,0 +1,362 @@
+use crate::error::Result;
+use crate::param::prefixed;
+use crate::{Dropout, FeedForward, KvCache, LayerNorm, Linear, MultiHeadAttention, Param, Tensor};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// 书中第4章的`TransformerBlock`，Pre-LN结构：
+// `x = x + drop(att(norm1(x)))`，`x = x + drop(ff(norm2(x)))`
+#[derive(Debug, Clone)]
+pub struct TransformerBlock {
+    norm1: LayerNorm,
+    att: MultiHeadAttention,
+    drop_att: Dropout,
+    norm2: LayerNorm,
+    ff: FeedForward,
+    drop_ff: Dropout,
+    // 梯度检查点：`forward`只保存输入和dropout的种子，`backward`时重新计算中间结果
+    checkpointing: bool,
+    checkpoint: Option<(Tensor, u64)>,
+}
+
+impl TransformerBlock {
+    pub fn new(
+        emb_dim: usize,
+        context_length: usize,
+        n_heads: usize,
+        drop_rate: f32,
+        qkv_bias: bool,
+        rng: &mut impl Rng,
+    ) -> Self {
+        TransformerBlock {
+            norm1: LayerNorm::new(emb_dim),
+            att: MultiHeadAttention::new(
+                emb_dim,
+                emb_dim,
+                context_length,
+                drop_rate,
+                n_heads,
+                qkv_bias,
+                rng,
+            ),
+            drop_att: Dropout::new(drop_rate, rng),
+            norm2: LayerNorm::new(emb_dim),
+            ff: FeedForward::new(emb_dim, rng),
+            drop_ff: Dropout::new(drop_rate, rng),
+            checkpointing: false,
+            checkpoint: None,
+        }
+    }
+
+    // 注意力的K和V只有`n_kv_heads`个头
+    pub fn with_kv_heads(mut self, n_kv_heads: usize, rng: &mut impl Rng) -> Self {
+        self.att = self.att.with_kv_heads(n_kv_heads, rng);
+        self
+    }
+
+    // 分块计算注意力
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.att = self.att.with_chunk_size(chunk_size);
+        self
+    }
+
+    // 注意力使用RoPE编码位置
+    pub fn with_rope(mut self, theta: f32) -> Self {
+        self.att = self.att.with_rope(theta);
+        self
+    }
+
+    // 注意力只关注最后`window`个token
+    pub fn with_sliding_window(mut self, window: usize) -> Self {
+        self.att = self.att.with_sliding_window(window);
+        self
+    }
+
+    pub fn norm1(&self) -> &LayerNorm {
+        &self.norm1
+    }
+
+    pub fn att(&self) -> &MultiHeadAttention {
+        &self.att
+    }
+
+    pub fn norm2(&self) -> &LayerNorm {
+        &self.norm2
+    }
+
+    pub fn ff(&self) -> &FeedForward {
+        &self.ff
+    }
+
+    pub fn norm1_mut(&mut self) -> &mut LayerNorm {
+        &mut self.norm1
+    }
+
+    pub fn att_mut(&mut self) -> &mut MultiHeadAttention {
+        &mut self.att
+    }
+
+    pub fn norm2_mut(&mut self) -> &mut LayerNorm {
+        &mut self.norm2
+    }
+
+    pub fn ff_mut(&mut self) -> &mut FeedForward {
+        &mut self.ff
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.drop_att.is_training()
+    }
+
+    // 与PyTorch的`torch.utils.checkpoint`相同，用多一次前向传播的计算换取内存
+    pub fn set_checkpointing(&mut self, checkpointing: bool) {
+        self.checkpointing = checkpointing;
+        self.checkpoint = None;
+    }
+
+    pub fn is_checkpointing(&self) -> bool {
+        self.checkpointing
+    }
+
+    pub fn set_training(&mut self, training: bool) {
+        self.att.set_training(training);
+        self.drop_att.set_training(training);
+        self.drop_ff.set_training(training);
+    }
+
+    pub fn reseed(&mut self, rng: &mut impl Rng) {
+        self.att.reseed(rng);
+        self.drop_att.reseed(rng);
+        self.drop_ff.reseed(rng);
+    }
+
+    // 输入输出都是`(B, T, emb_dim)`
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor> {
+        if !self.checkpointing {
+            return self.forward_cached(input);
+        }
+
+        // 用新的种子重新派生dropout的随机数，重新计算时得到相同的掩码
+        let seed = self.drop_att.next_seed();
+        self.reseed(&mut StdRng::seed_from_u64(seed));
+        let output = self.forward_cached(input)?;
+        self.clear_cache();
+        self.checkpoint = Some((input.clone(), seed));
+        Ok(output)
+    }
+
+    fn forward_cached(&mut self, input: &Tensor) -> Result<Tensor> {
+        let x = self.norm1.forward(input)?;
+        let x = self.att.forward(&x)?;
+        let mut x = self.drop_att.forward(&x);
+        add_assign(&mut x, input);
+
+        let shortcut = x.clone();
+        let x = self.norm2.forward(&x)?;
+        let x = self.ff.forward(&x)?;
+        let mut x = self.drop_ff.forward(&x);
+        add_assign(&mut x, &shortcut);
+        Ok(x)
+    }
+
+    // 推理时使用，注意力的K和V追加到`cache`。不使用dropout
+    pub fn forward_with_cache(&self, input: &Tensor, cache: &mut KvCache) -> Result<Tensor> {
+        self.forward_padded(input, cache, &[])
+    }
+
+    // 第b个序列左侧有`padding[b]`个填充token
+    pub fn forward_padded(
+        &self,
+        input: &Tensor,
+        cache: &mut KvCache,
+        padding: &[usize],
+    ) -> Result<Tensor> {
+        let x = self.norm1.apply(input)?;
+        let mut x = self.att.forward_padded(&x, cache, padding)?;
+        add_assign(&mut x, input);
+
+        let shortcut = x.clone();
+        let x = self.norm2.apply(&x)?;
+        let mut x = self.ff.apply(&x)?;
+        add_assign(&mut x, &shortcut);
+        Ok(x)
+    }
+
+    pub fn backward(&mut self, grad_output: &Tensor) -> Result<Tensor> {
+        let Some((input, seed)) = self.checkpoint.take() else {
+            return self.backward_cached(grad_output);
+        };
+
+        self.reseed(&mut StdRng::seed_from_u64(seed));
+        self.forward_cached(&input)?;
+        let grad_input = self.backward_cached(grad_output);
+        self.clear_cache();
+        grad_input
+    }
+
+    // 残差连接的梯度直接加到分支的梯度上
+    fn backward_cached(&mut self, grad_output: &Tensor) -> Result<Tensor> {
+        let grad = self.drop_ff.backward(grad_output);
+        let grad = self.ff.backward(&grad)?;
+        let mut grad_shortcut = self.norm2.backward(&grad);
+        add_assign(&mut grad_shortcut, grad_output);
+
+        let grad = self.drop_att.backward(&grad_shortcut);
+        let grad = self.att.backward(&grad)?;
+        let mut grad_input = self.norm1.backward(&grad);
+        add_assign(&mut grad_input, &grad_shortcut);
+        Ok(grad_input)
+    }
+
+    fn clear_cache(&mut self) {
+        self.norm1.clear_cache();
+        self.att.clear_cache();
+        self.drop_att.clear_cache();
+        self.norm2.clear_cache();
+        self.ff.clear_cache();
+        self.drop_ff.clear_cache();
+    }
+
+    // 梯度检查点只保存输入
+    pub(crate) fn saved_bytes(&self) -> usize {
+        let checkpoint = self
+            .checkpoint
+            .as_ref()
+            .map_or(0, |(input, _)| size_of_val(input.data()));
+        self.norm1.saved_bytes()
+            + self.att.saved_bytes()
+            + self.drop_att.saved_bytes()
+            + self.norm2.saved_bytes()
+            + self.ff.saved_bytes()
+            + self.drop_ff.saved_bytes()
+            + checkpoint
+    }
+
+    pub fn zero_grad(&mut self) {
+        self.norm1.zero_grad();
+        self.att.zero_grad();
+        self.norm2.zero_grad();
+        self.ff.zero_grad();
+    }
+
+    pub fn named_params(&self) -> Vec<(String, &Param)> {
+        let mut params = prefixed("att", self.att.named_params());
+        params.extend(prefixed("ff", self.ff.named_params()));
+        params.extend(prefixed("norm1", self.norm1.named_params()));
+        params.extend(prefixed("norm2", self.norm2.named_params()));
+        params
+    }
+
+    pub fn named_params_mut(&mut self) -> Vec<(String, &mut Param)> {
+        let mut params = prefixed("att", self.att.named_params_mut());
+        params.extend(prefixed("ff", self.ff.named_params_mut()));
+        params.extend(prefixed("norm1", self.norm1.named_params_mut()));
+        params.extend(prefixed("norm2", self.norm2.named_params_mut()));
+        params
+    }
+
+    pub fn named_linears(&self) -> Vec<(String, &Linear)> {
+        let mut linears = prefixed("att", self.att.named_linears());
+        linears.extend(prefixed("ff", self.ff.named_linears()));
+        linears
+    }
+
+    pub fn named_linears_mut(&mut self) -> Vec<(String, &mut Linear)> {
+        let mut linears = prefixed("att", self.att.named_linears_mut());
+        linears.extend(prefixed("ff", self.ff.named_linears_mut()));
+        linears
+    }
+}
+
+fn add_assign(x: &mut Tensor, other: &Tensor) {
+    for (a, b) in x.data_mut().iter_mut().zip(other.data()) {
+        *a += b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_transformer_block() {
+        // 书中第4章：GPT-2 small的一个block，输入输出形状相同
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut block = TransformerBlock::new(768, 1024, 12, 0.1, false, &mut rng);
+        let input = Tensor::uniform(&[2, 4, 768], 0.0, 1.0, &mut rng);
+        let output = block.forward(&input).unwrap();
+        println!("{:?}", output.shape());
+        assert_eq!(output.shape(), input.shape());
+
+        // 推理模式下输出是确定的
+        block.set_training(false);
+        assert!(!block.is_training());
+        let output = block.forward(&input).unwrap();
+        assert_eq!(block.forward(&input).unwrap(), output);
+
+        let mut block = TransformerBlock::new(4, 8, 2, 0.0, true, &mut rng);
+        let input = Tensor::randn(&[2, 3, 4], 1.0, &mut rng);
+        let r = Tensor::randn(&[2, 3, 4], 1.0, &mut rng);
+        let loss = |block: &mut TransformerBlock, input: &Tensor| {
+            let output = block.forward(input).unwrap();
+            output
+                .data()
+                .iter()
+                .zip(r.data())
+                .map(|(x, w)| x * w)
+                .sum::<f32>()
+        };
+
+        loss(&mut block, &input);
+        let grad_input = block.backward(&r).unwrap();
+        let eps = 1e-2;
+        for i in 0..input.len() {
+            let mut plus = input.clone();
+            plus.data_mut()[i] += eps;
+            let mut minus = input.clone();
+            minus.data_mut()[i] -= eps;
+
+            let numeric = (loss(&mut block, &plus) - loss(&mut block, &minus)) / (2.0 * eps);
+            assert!(
+                (numeric - grad_input.data()[i]).abs() < 2e-2,
+                "input[{i}]: numeric {numeric}, analytic {}",
+                grad_input.data()[i]
+            );
+        }
+    }
+
+    #[test]
+    fn test_gradient_checkpointing() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let block = TransformerBlock::new(8, 8, 2, 0.2, true, &mut rng);
+        let input = Tensor::randn(&[2, 4, 8], 1.0, &mut rng);
+        let grad_output = Tensor::randn(&[2, 4, 8], 1.0, &mut rng);
+
+        // 不使用检查点、但按相同方式派生dropout种子的block得到相同的输出和梯度
+        let mut expected = block.clone();
+        let seed = expected.drop_att.next_seed();
+        expected.reseed(&mut StdRng::seed_from_u64(seed));
+        let expected_output = expected.forward(&input).unwrap();
+        let expected_grad = expected.backward(&grad_output).unwrap();
+
+        let mut checkpointed = block.clone();
+        checkpointed.set_checkpointing(true);
+        let output = checkpointed.forward(&input).unwrap();
+        assert_eq!(output, expected_output);
+        assert!(checkpointed.checkpoint.is_some());
+
+        let grad = checkpointed.backward(&grad_output).unwrap();
+        assert_eq!(grad, expected_grad);
+        assert!(checkpointed.checkpoint.is_none());
+        for ((name, a), (_, b)) in checkpointed
+            .named_params()
+            .into_iter()
+            .zip(expected.named_params())
+        {
+            println!("{name}: {:?}", &a.grad().data()[..2]);
+            assert_eq!(a.grad(), b.grad(), "{name}");
+        }
+    }
+}
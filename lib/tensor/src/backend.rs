@@ -0,0 +1,222 @@
+use crate::{Device, Precision, TensorError};
+use std::borrow::Cow;
+
+// 当前使用的矩阵乘法后端
+#[cfg(not(feature = "ndarray"))]
+pub const BACKEND: &str = "cpu";
+#[cfg(feature = "ndarray")]
+pub const BACKEND: &str = "ndarray";
+
+// `c(m×n) += op(a)(m×k) · op(b)(k×n)`。`trans_a`为true时`a`按`(k, m)`存储，
+// `trans_b`为true时`b`按`(n, k)`存储，反向传播时不需要显式转置。
+// 只有在GPU上计算时才会出错
+#[allow(clippy::too_many_arguments)]
+pub fn gemm(
+    a: &[f32],
+    trans_a: bool,
+    b: &[f32],
+    trans_b: bool,
+    c: &mut [f32],
+    m: usize,
+    k: usize,
+    n: usize,
+) -> Result<(), TensorError> {
+    assert_eq!(a.len(), m * k, "Left operand does not match ({m}, {k})");
+    assert_eq!(b.len(), k * n, "Right operand does not match ({k}, {n})");
+    assert_eq!(c.len(), m * n, "Output does not match ({m}, {n})");
+
+    // 小矩阵上传到GPU的开销比计算本身大，仍然在CPU上计算
+    let device = Device::current();
+    let precision = Precision::current();
+    if !device.is_cpu() && m * k * n >= GPU_MIN_SIZE {
+        #[cfg(any(feature = "cuda", feature = "metal"))]
+        return gemm_candle(device, precision, a, trans_a, b, trans_b, c, m, k, n);
+        #[cfg(not(any(feature = "cuda", feature = "metal")))]
+        return Err(TensorError::DeviceUnavailable(device));
+    }
+
+    // 输入舍入到半精度的值后仍然存为f32（需要额外复制一份），乘积用f32累加
+    let round = |x: &[f32]| x.iter().map(|x| precision.round(*x)).collect::<Vec<_>>();
+    let (a, b) = match precision {
+        Precision::F32 => (Cow::Borrowed(a), Cow::Borrowed(b)),
+        _ => (Cow::Owned(round(a)), Cow::Owned(round(b))),
+    };
+    let (a, b) = (a.as_ref(), b.as_ref());
+
+    #[cfg(not(feature = "ndarray"))]
+    gemm_cpu(a, trans_a, b, trans_b, c, m, k, n);
+    #[cfg(feature = "ndarray")]
+    gemm_ndarray(a, trans_a, b, trans_b, c, m, k, n);
+    Ok(())
+}
+
+const GPU_MIN_SIZE: usize = 1 << 18;
+
+#[cfg(any(feature = "cuda", feature = "metal"))]
+#[allow(clippy::too_many_arguments)]
+fn gemm_candle(
+    device: Device,
+    precision: Precision,
+    a: &[f32],
+    trans_a: bool,
+    b: &[f32],
+    trans_b: bool,
+    c: &mut [f32],
+    m: usize,
+    k: usize,
+    n: usize,
+) -> Result<(), TensorError> {
+    use candle_core::{DType, Tensor};
+
+    let dtype = match precision {
+        Precision::F32 => DType::F32,
+        Precision::Bf16 => DType::BF16,
+        Precision::F16 => DType::F16,
+    };
+
+    let product = || -> candle_core::Result<Vec<f32>> {
+        let candle_device = candle_device(device)?;
+        let a = if trans_a {
+            Tensor::from_slice(a, (k, m), &candle_device)?.t()?
+        } else {
+            Tensor::from_slice(a, (m, k), &candle_device)?
+        };
+        let b = if trans_b {
+            Tensor::from_slice(b, (n, k), &candle_device)?.t()?
+        } else {
+            Tensor::from_slice(b, (k, n), &candle_device)?
+        };
+        let (a, b) = (a.to_dtype(dtype)?, b.to_dtype(dtype)?);
+        a.matmul(&b)?.to_dtype(DType::F32)?.flatten_all()?.to_vec1()
+    };
+    let product = product().map_err(|err| TensorError::Gemm {
+        device,
+        message: err.to_string(),
+    })?;
+    for (y, x) in c.iter_mut().zip(product) {
+        *y += x;
+    }
+    Ok(())
+}
+
+// 创建设备需要初始化CUDA上下文或Metal命令队列，每个线程只创建一次
+#[cfg(any(feature = "cuda", feature = "metal"))]
+fn candle_device(device: Device) -> candle_core::Result<candle_core::Device> {
+    use std::cell::RefCell;
+
+    thread_local! {
+        static DEVICES: RefCell<Vec<(Device, candle_core::Device)>> = const { RefCell::new(vec![]) };
+    }
+
+    DEVICES.with(|devices| {
+        if let Some((_, created)) = devices.borrow().iter().find(|(key, _)| *key == device) {
+            return Ok(created.clone());
+        }
+        let created = match device {
+            Device::Cpu => candle_core::Device::Cpu,
+            Device::Cuda(ordinal) => candle_core::Device::new_cuda(ordinal)?,
+            Device::Metal(ordinal) => candle_core::Device::new_metal(ordinal)?,
+        };
+        devices.borrow_mut().push((device, created.clone()));
+        Ok(created)
+    })
+}
+
+#[cfg(not(feature = "ndarray"))]
+#[allow(clippy::too_many_arguments)]
+fn gemm_cpu(
+    a: &[f32],
+    trans_a: bool,
+    b: &[f32],
+    trans_b: bool,
+    c: &mut [f32],
+    m: usize,
+    k: usize,
+    n: usize,
+) {
+    for i in 0..m {
+        let row = &mut c[i * n..(i + 1) * n];
+        for p in 0..k {
+            let x = if trans_a { a[p * m + i] } else { a[i * k + p] };
+            if x == 0.0 {
+                continue;
+            }
+            if trans_b {
+                for (j, y) in row.iter_mut().enumerate() {
+                    *y += x * b[j * k + p];
+                }
+            } else {
+                for (y, w) in row.iter_mut().zip(&b[p * n..(p + 1) * n]) {
+                    *y += x * w;
+                }
+            }
+        }
+    }
+}
+
+// 转置只是交换视图的步长，不会复制数据
+#[cfg(feature = "ndarray")]
+#[allow(clippy::too_many_arguments)]
+fn gemm_ndarray(
+    a: &[f32],
+    trans_a: bool,
+    b: &[f32],
+    trans_b: bool,
+    c: &mut [f32],
+    m: usize,
+    k: usize,
+    n: usize,
+) {
+    use ndarray::linalg::general_mat_mul;
+    use ndarray::{ArrayView2, ArrayViewMut2};
+
+    let a = if trans_a {
+        ArrayView2::from_shape((k, m), a).unwrap().reversed_axes()
+    } else {
+        ArrayView2::from_shape((m, k), a).unwrap()
+    };
+    let b = if trans_b {
+        ArrayView2::from_shape((n, k), b).unwrap().reversed_axes()
+    } else {
+        ArrayView2::from_shape((k, n), b).unwrap()
+    };
+    let mut c = ArrayViewMut2::from_shape((m, n), c).unwrap();
+    general_mat_mul(1.0, &a, &b, 1.0, &mut c);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::with_device;
+
+    #[test]
+    fn test_gemm() {
+        println!("backend: {BACKEND}");
+
+        // a: 2×3, b: 3×2
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let b = [1.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+        let mut c = [0.0; 4];
+        gemm(&a, false, &b, false, &mut c, 2, 3, 2).unwrap();
+        assert_eq!(c, [4.0, 5.0, 10.0, 11.0]);
+
+        let a_t = [1.0, 4.0, 2.0, 5.0, 3.0, 6.0];
+        let b_t = [1.0, 0.0, 1.0, 0.0, 1.0, 1.0];
+        let mut c_t = [0.0; 4];
+        gemm(&a_t, true, &b_t, true, &mut c_t, 2, 3, 2).unwrap();
+        assert_eq!(c_t, c);
+
+        // 结果累加到`c`上
+        gemm(&a, false, &b, false, &mut c, 2, 3, 2).unwrap();
+        assert_eq!(c, [8.0, 10.0, 20.0, 22.0]);
+
+        // 不可用的设备返回错误，不会panic
+        let a = vec![1.0; 64 * 64];
+        let mut c = vec![0.0; 64 * 64];
+        let result = with_device(Device::Cuda(0), || {
+            gemm(&a, false, &a, false, &mut c, 64, 64, 64)
+        });
+        println!("{result:?}");
+        assert_eq!(result.is_ok(), Device::Cuda(0).is_available());
+    }
+}
@@ -0,0 +1,109 @@
+use crate::TensorError;
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+
+// 矩阵乘法在哪个设备上计算。GPU需要打开`cuda`或`metal` feature，通过candle调用cuBLAS/MPS，
+// 数据仍然保存在`Tensor`中，每次乘法前上传、计算后取回
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Device {
+    #[default]
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+thread_local! {
+    static CURRENT: Cell<Device> = const { Cell::new(Device::Cpu) };
+}
+
+impl Device {
+    // 打开了对应的feature，并且可以创建设备
+    pub fn is_available(self) -> bool {
+        match self {
+            Device::Cpu => true,
+            #[cfg(feature = "cuda")]
+            Device::Cuda(ordinal) => candle_core::Device::new_cuda(ordinal).is_ok(),
+            #[cfg(feature = "metal")]
+            Device::Metal(ordinal) => candle_core::Device::new_metal(ordinal).is_ok(),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    pub fn is_cpu(self) -> bool {
+        self == Device::Cpu
+    }
+
+    // 当前线程`gemm`使用的设备
+    pub fn current() -> Device {
+        CURRENT.with(Cell::get)
+    }
+}
+
+// 形如`cpu`、`cuda`、`cuda:1`、`metal:0`，省略序号时为0
+impl FromStr for Device {
+    type Err = TensorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TensorError::InvalidDevice(s.to_string());
+        let lower = s.to_ascii_lowercase();
+        let (name, ordinal) = match lower.split_once(':') {
+            Some((name, ordinal)) => (name, Some(ordinal.parse().map_err(|_| invalid())?)),
+            None => (lower.as_str(), None),
+        };
+        match (name, ordinal) {
+            ("cpu", None) => Ok(Device::Cpu),
+            ("cuda", ordinal) => Ok(Device::Cuda(ordinal.unwrap_or(0))),
+            ("metal", ordinal) => Ok(Device::Metal(ordinal.unwrap_or(0))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Device::Cpu => write!(f, "cpu"),
+            Device::Cuda(ordinal) => write!(f, "cuda:{ordinal}"),
+            Device::Metal(ordinal) => write!(f, "metal:{ordinal}"),
+        }
+    }
+}
+
+// 在`device`上执行`f`中所有的`gemm`，结束后恢复之前的设备
+pub fn with_device<T>(device: Device, f: impl FnOnce() -> T) -> T {
+    struct Restore(Device);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.with(|current| current.set(self.0));
+        }
+    }
+
+    let _restore = Restore(CURRENT.with(|current| current.replace(device)));
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device() {
+        assert!(Device::Cpu.is_available());
+        assert_eq!(Device::current(), Device::Cpu);
+        let inner = with_device(Device::Cuda(0), || {
+            with_device(Device::Metal(1), Device::current)
+        });
+        assert_eq!(inner, Device::Metal(1));
+        assert_eq!(Device::current(), Device::Cpu);
+        println!("cuda: {}", Device::Cuda(0).is_available());
+
+        for device in [Device::Cpu, Device::Cuda(1), Device::Metal(0)] {
+            assert_eq!(device.to_string().parse::<Device>().unwrap(), device);
+        }
+        assert_eq!("CUDA".parse::<Device>().unwrap(), Device::Cuda(0));
+        assert!("cpu:0".parse::<Device>().is_err());
+        assert!("tpu".parse::<Device>().is_err());
+    }
+}
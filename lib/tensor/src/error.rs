@@ -0,0 +1,15 @@
+use crate::Device;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TensorError {
+    #[error("{0:?} needs the cuda or metal feature")]
+    DeviceUnavailable(Device),
+
+    // candle返回的错误，如显存不足、驱动出错
+    #[error("gemm on {device:?} failed: {message}")]
+    Gemm { device: Device, message: String },
+
+    #[error("Unknown device `{0}`, expected cpu, cuda[:N] or metal[:N]")]
+    InvalidDevice(String),
+}
@@ -0,0 +1,13 @@
+mod backend;
+mod device;
+mod error;
+mod precision;
+mod saved;
+mod tensor;
+
+pub use backend::{gemm, BACKEND};
+pub use device::{with_device, Device};
+pub use error::TensorError;
+pub use precision::{with_precision, Precision};
+pub use saved::SavedTensor;
+pub use tensor::Tensor;
@@ -0,0 +1,164 @@
+use std::cell::Cell;
+
+// 矩阵乘法输入的精度，与PyTorch的autocast相同：只有矩阵乘法的输入转为半精度，累加、softmax和LayerNorm仍然使用f32。
+// CPU上把输入舍入到对应精度后用f32计算，GPU上由candle使用bf16/f16的矩阵乘法。
+// 反向传播保存的激活按`SavedTensor`存为16位，参数和梯度仍然是f32
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Precision {
+    #[default]
+    F32,
+    Bf16,
+    // 可表示的范围只有±65504，梯度容易下溢，训练时需要loss scaling
+    F16,
+}
+
+thread_local! {
+    static CURRENT: Cell<Precision> = const { Cell::new(Precision::F32) };
+}
+
+impl Precision {
+    // 舍入到最近的可表示值，距离相同时取偶数
+    pub fn round(self, x: f32) -> f32 {
+        match self {
+            Precision::F32 => x,
+            Precision::Bf16 => round_bf16(x),
+            Precision::F16 => round_f16(x),
+        }
+    }
+
+    // 半精度的16位编码，先舍入到最近的可表示值。`F32`时没有16位编码
+    pub fn to_bits(self, x: f32) -> u16 {
+        match self {
+            Precision::F32 => panic!("F32 has no 16-bit encoding"),
+            Precision::Bf16 => (round_bf16(x).to_bits() >> 16) as u16,
+            Precision::F16 => f16_bits(x),
+        }
+    }
+
+    pub fn from_bits(self, bits: u16) -> f32 {
+        match self {
+            Precision::F32 => panic!("F32 has no 16-bit encoding"),
+            Precision::Bf16 => f32::from_bits((bits as u32) << 16),
+            Precision::F16 => f16_to_f32(bits),
+        }
+    }
+
+    // 当前线程`gemm`使用的精度
+    pub fn current() -> Precision {
+        CURRENT.with(Cell::get)
+    }
+}
+
+// 在`precision`下执行`f`中所有的`gemm`，结束后恢复之前的精度
+pub fn with_precision<T>(precision: Precision, f: impl FnOnce() -> T) -> T {
+    struct Restore(Precision);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.with(|current| current.set(self.0));
+        }
+    }
+
+    let _restore = Restore(CURRENT.with(|current| current.replace(precision)));
+    f()
+}
+
+// bf16为f32的高16位
+fn round_bf16(x: f32) -> f32 {
+    if x.is_nan() {
+        return x;
+    }
+    let bits = x.to_bits();
+    let rounded = bits.wrapping_add(0x7fff + ((bits >> 16) & 1));
+    f32::from_bits(rounded & 0xffff_0000)
+}
+
+// f16有10位尾数，指数范围为[-14, 15]，更小的数为间隔`2^-24`的非规格化数
+fn round_f16(x: f32) -> f32 {
+    if !x.is_finite() {
+        return x;
+    }
+    let abs = x.abs();
+    // 65504和下一个值65536的中点
+    if abs >= 65520.0 {
+        return f32::INFINITY.copysign(x);
+    }
+
+    let exponent = ((abs.to_bits() >> 23) as i32 - 127).max(-14);
+    let quantum = 2f32.powi(exponent - 10);
+    ((abs / quantum).round_ties_even() * quantum).copysign(x)
+}
+
+// 先舍入到f16可表示的值，再取出符号、指数和尾数
+fn f16_bits(x: f32) -> u16 {
+    let sign = ((x.to_bits() >> 16) & 0x8000) as u16;
+    if x.is_nan() {
+        return sign | 0x7e00;
+    }
+    let abs = round_f16(x).abs();
+    if abs.is_infinite() {
+        return sign | 0x7c00;
+    }
+    if abs < 2f32.powi(-14) {
+        // 非规格化数：尾数为`abs / 2^-24`
+        return sign | (abs * 2f32.powi(24)) as u16;
+    }
+    let bits = abs.to_bits();
+    let exponent = ((bits >> 23) as i32 - 127 + 15) as u16;
+    sign | (exponent << 10) | ((bits >> 13) & 0x3ff) as u16
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = ((bits & 0x8000) as u32) << 16;
+    let (exponent, mantissa) = (((bits >> 10) & 0x1f) as u32, (bits & 0x3ff) as u32);
+    match exponent {
+        0 => f32::from_bits(sign | (mantissa as f32 * 2f32.powi(-24)).to_bits()),
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 127 - 15) << 23) | (mantissa << 13)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precision() {
+        let bf16 = Precision::Bf16;
+        assert_eq!(bf16.round(1.0), 1.0);
+        // bf16只有7位尾数
+        assert_eq!(bf16.round(1.0 + 1.0 / 256.0), 1.0);
+        assert_eq!(bf16.round(1.0 + 3.0 / 256.0), 1.0 + 4.0 / 256.0);
+        assert_eq!(bf16.round(-3.140625), -3.140625);
+        assert_eq!(bf16.round(1e38), f32::from_bits(0x7e96_0000));
+        assert!(bf16.round(f32::NAN).is_nan());
+
+        let f16 = Precision::F16;
+        assert_eq!(f16.round(1.0 + 1.0 / 2048.0), 1.0);
+        assert_eq!(f16.round(1.0 + 3.0 / 2048.0), 1.0 + 2.0 / 1024.0);
+        assert_eq!(f16.round(65504.0), 65504.0);
+        assert_eq!(f16.round(65519.0), 65504.0);
+        assert_eq!(f16.round(-70000.0), f32::NEG_INFINITY);
+        assert_eq!(f16.round(2f32.powi(-24)), 2f32.powi(-24));
+        assert_eq!(f16.round(2f32.powi(-26)), 0.0);
+        assert_eq!(f16.round(0.1), 0.099975586);
+
+        for (x, bits) in [
+            (1.0, 0x3c00),
+            (-2.0, 0xc000),
+            (65504.0, 0x7bff),
+            (1e-7, 0x0002),
+            (-70000.0, 0xfc00),
+        ] {
+            assert_eq!(f16.to_bits(x), bits);
+            assert_eq!(f16.from_bits(bits), f16.round(x));
+        }
+        assert!(f16.from_bits(f16.to_bits(f32::NAN)).is_nan());
+        assert_eq!(bf16.to_bits(-3.140625), 0xc049);
+        assert_eq!(bf16.from_bits(bf16.to_bits(0.1)), bf16.round(0.1));
+
+        assert_eq!(Precision::current(), Precision::F32);
+        let inner = with_precision(Precision::Bf16, Precision::current);
+        assert_eq!(inner, Precision::Bf16);
+        assert_eq!(Precision::current(), Precision::F32);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{Precision, Tensor};
+use std::borrow::Cow;
+
+// 反向传播保存的激活。`Precision::current()`为半精度时存为16位，只占f32的一半内存，
+// 使用时再转回f32；f32时原样保存
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedTensor(Saved);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Saved {
+    F32(Tensor),
+    Half {
+        precision: Precision,
+        shape: Vec<usize>,
+        bits: Vec<u16>,
+    },
+}
+
+impl SavedTensor {
+    pub fn new(tensor: &Tensor) -> Self {
+        match Precision::current() {
+            Precision::F32 => SavedTensor(Saved::F32(tensor.clone())),
+            precision => Self::half(precision, tensor.data(), tensor.shape()),
+        }
+    }
+
+    // 不需要复制f32的数据
+    pub fn from_tensor(tensor: Tensor) -> Self {
+        match Precision::current() {
+            Precision::F32 => SavedTensor(Saved::F32(tensor)),
+            precision => Self::half(precision, tensor.data(), tensor.shape()),
+        }
+    }
+
+    fn half(precision: Precision, data: &[f32], shape: &[usize]) -> Self {
+        SavedTensor(Saved::Half {
+            precision,
+            shape: shape.to_vec(),
+            bits: data.iter().map(|x| precision.to_bits(*x)).collect(),
+        })
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        match &self.0 {
+            Saved::F32(tensor) => tensor.shape(),
+            Saved::Half { shape, .. } => shape,
+        }
+    }
+
+    pub fn precision(&self) -> Precision {
+        match &self.0 {
+            Saved::F32(_) => Precision::F32,
+            Saved::Half { precision, .. } => *precision,
+        }
+    }
+
+    // 半精度时解码出一份f32的张量
+    pub fn tensor(&self) -> Cow<'_, Tensor> {
+        match &self.0 {
+            Saved::F32(tensor) => Cow::Borrowed(tensor),
+            Saved::Half {
+                precision,
+                shape,
+                bits,
+            } => {
+                let data = bits.iter().map(|bits| precision.from_bits(*bits)).collect();
+                Cow::Owned(Tensor::new(data, shape))
+            }
+        }
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        match &self.0 {
+            Saved::F32(tensor) => size_of_val(tensor.data()),
+            Saved::Half { bits, .. } => size_of_val(bits.as_slice()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::with_precision;
+
+    #[test]
+    fn test_saved_tensor() {
+        let tensor = Tensor::new(vec![1.0, -0.1, 3.3, 65504.0, 1e-7, 0.0], &[2, 3]);
+        let saved = SavedTensor::new(&tensor);
+        assert_eq!(saved.precision(), Precision::F32);
+        assert_eq!(*saved.tensor(), tensor);
+        assert_eq!(saved.size_in_bytes(), 6 * 4);
+
+        for precision in [Precision::Bf16, Precision::F16] {
+            let saved = with_precision(precision, || SavedTensor::from_tensor(tensor.clone()));
+            println!("{precision:?}: {:?}", saved.tensor().data());
+            assert_eq!(saved.shape(), [2, 3]);
+            assert_eq!(saved.size_in_bytes(), 6 * 2);
+            // 解码出舍入后的值，与`gemm`使用的输入相同
+            let rounded = tensor.data().iter().map(|x| precision.round(*x));
+            assert!(saved.tensor().data().iter().copied().eq(rounded));
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use rand::Rng;
+use std::f32::consts::PI;
+
+// 按行优先存储的多维数组
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tensor {
+    shape: Vec<usize>,
+    data: Vec<f32>,
+}
+
+impl Tensor {
+    pub fn new(data: Vec<f32>, shape: &[usize]) -> Self {
+        assert_eq!(
+            data.len(),
+            shape.iter().product::<usize>(),
+            "Data length does not match shape {shape:?}"
+        );
+        Tensor {
+            shape: shape.to_vec(),
+            data,
+        }
+    }
+
+    pub fn zeros(shape: &[usize]) -> Self {
+        Self::new(vec![0.0; shape.iter().product()], shape)
+    }
+
+    // 均值为0、标准差为`std`的正态分布，使用Box-Muller变换生成
+    pub fn randn(shape: &[usize], std: f32, rng: &mut impl Rng) -> Self {
+        let len = shape.iter().product();
+        let mut data = Vec::with_capacity(len);
+
+        while data.len() < len {
+            let u1 = rng.random::<f32>().max(f32::MIN_POSITIVE);
+            let u2 = rng.random::<f32>();
+            let radius = (-2.0 * u1.ln()).sqrt() * std;
+            data.push(radius * (2.0 * PI * u2).cos());
+            if data.len() < len {
+                data.push(radius * (2.0 * PI * u2).sin());
+            }
+        }
+
+        Self::new(data, shape)
+    }
+
+    pub fn uniform(shape: &[usize], low: f32, high: f32, rng: &mut impl Rng) -> Self {
+        let data = (0..shape.iter().product())
+            .map(|_| rng.random_range(low..high))
+            .collect();
+        Self::new(data, shape)
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    pub fn into_data(self) -> Vec<f32> {
+        self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn fill(&mut self, value: f32) {
+        self.data.fill(value);
+    }
+
+    // 二维矩阵的转置，返回新的`(cols, rows)`张量
+    pub fn transpose(&self) -> Self {
+        let [rows, cols] = *self.shape else {
+            panic!("transpose expects a 2D tensor, got {:?}", self.shape);
+        };
+
+        let mut data = vec![0.0; self.data.len()];
+        for (i, row) in self.data.chunks(cols.max(1)).enumerate() {
+            for (j, x) in row.iter().enumerate() {
+                data[j * rows + i] = *x;
+            }
+        }
+        Self::new(data, &[cols, rows])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_tensor_transpose() {
+        let tensor = Tensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        let transposed = tensor.transpose();
+        assert_eq!(transposed.shape(), [3, 2]);
+        assert_eq!(transposed.data(), [1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+        assert_eq!(transposed.transpose(), tensor);
+    }
+
+    #[test]
+    fn test_tensor_randn() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let tensor = Tensor::randn(&[100, 101], 2.0, &mut rng);
+        assert_eq!(tensor.shape(), [100, 101]);
+
+        let n = tensor.len() as f32;
+        let mean = tensor.data().iter().sum::<f32>() / n;
+        let var = tensor
+            .data()
+            .iter()
+            .map(|x| (x - mean).powi(2))
+            .sum::<f32>()
+            / n;
+        println!("mean: {mean}, std: {}", var.sqrt());
+        assert!(mean.abs() < 0.1);
+        assert!((var.sqrt() - 2.0).abs() < 0.1);
+
+        let tensor = Tensor::randn(&[2, 3], 1.0, &mut StdRng::seed_from_u64(42));
+        let same = Tensor::randn(&[2, 3], 1.0, &mut StdRng::seed_from_u64(42));
+        assert_eq!(tensor, same);
+    }
+}
@@ -0,0 +1,295 @@
+use crate::error::BenchError;
+use crate::tokenizer::Tokenizer;
+use data_loader::{DataLoader, GPTDataset};
+use model::{cross_entropy_with_grad, generate_text_simple, GPTModel, GptConfig, SamplingConfig};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::time::Instant;
+
+type Result<T, E = BenchError> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    pub name: String,
+    pub value: f64,
+    pub unit: &'static str,
+}
+
+// `llm bench`的结果，每行一项，便于比较不同版本的输出
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BenchReport {
+    pub results: Vec<BenchResult>,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self
+            .results
+            .iter()
+            .map(|result| result.name.len())
+            .max()
+            .unwrap_or(0);
+        for result in &self.results {
+            writeln!(
+                f,
+                "{:<width$}  {:>12.2} {}",
+                result.name, result.value, result.unit
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchConfig {
+    pub model: GptConfig,
+    pub batch_size: usize,
+    // 每项重复的次数，取平均值
+    pub iterations: usize,
+    // 依次测试的DataLoader工作线程数
+    pub num_workers: Vec<usize>,
+    pub prompt_len: usize,
+    pub new_tokens: usize,
+    pub seed: u64,
+}
+
+impl Default for BenchConfig {
+    // 与`llm train`的默认模型相同
+    fn default() -> Self {
+        BenchConfig {
+            model: GptConfig {
+                context_length: 256,
+                ..GptConfig::gpt2_small()
+            },
+            batch_size: 2,
+            iterations: 3,
+            num_workers: vec![1, 2, 4],
+            prompt_len: 16,
+            new_tokens: 32,
+            seed: 123,
+        }
+    }
+}
+
+// 依次测试分词、DataLoader、模型的前向/反向传播和生成，`text`为分词和DataLoader使用的文本
+pub fn run_bench(
+    config: &BenchConfig,
+    tokenizer: &impl Tokenizer,
+    text: &str,
+) -> Result<BenchReport> {
+    if config.iterations == 0 {
+        return Err(BenchError::Invalid(
+            "iterations must be positive".to_string(),
+        ));
+    }
+    let mut results = vec![bench_tokenizer(tokenizer, text, config.iterations)?];
+
+    let token_ids = tokenizer.encode(text)?;
+    for &num_workers in &config.num_workers {
+        results.push(bench_data_loader(
+            &token_ids,
+            config.model.context_length,
+            config.batch_size,
+            num_workers,
+            config.iterations,
+        )?);
+    }
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut model = GPTModel::new(&config.model, &mut rng)?;
+    results.extend(bench_model(
+        &mut model,
+        config.batch_size,
+        config.iterations,
+        &mut rng,
+    )?);
+    results.extend(bench_generation(
+        &model,
+        config.prompt_len,
+        config.new_tokens,
+        config.iterations,
+        &mut rng,
+    )?);
+    Ok(BenchReport { results })
+}
+
+// 先执行一次预热，再返回`iterations`次的平均秒数
+fn time<T, E>(iterations: usize, mut f: impl FnMut() -> Result<T, E>) -> Result<f64, E> {
+    f()?;
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f()?;
+    }
+    Ok(start.elapsed().as_secs_f64() / iterations.max(1) as f64)
+}
+
+pub fn bench_tokenizer(
+    tokenizer: &impl Tokenizer,
+    text: &str,
+    iterations: usize,
+) -> Result<BenchResult> {
+    let seconds = time(iterations, || tokenizer.encode(text))?;
+    Ok(BenchResult {
+        name: "tokenizer encode".to_string(),
+        value: text.len() as f64 / 1e6 / seconds,
+        unit: "MB/s",
+    })
+}
+
+// 每次迭代遍历一个完整的epoch
+pub fn bench_data_loader(
+    token_ids: &[usize],
+    context_length: usize,
+    batch_size: usize,
+    num_workers: usize,
+    iterations: usize,
+) -> Result<BenchResult> {
+    let dataset = GPTDataset::new(token_ids.to_vec(), context_length, context_length);
+    // 持久化的工作线程，每次`iter`开始新的epoch
+    let loader = DataLoader::builder()
+        .batch_size(batch_size)
+        .shuffle(true)
+        .num_workers(num_workers)
+        .drop_last(true)
+        .persistent_workers(true)
+        .build(dataset);
+    let mut num_batches = 0;
+    let seconds = time(iterations, || -> Result<()> {
+        num_batches = loader.iter().collect::<Result<Vec<_>, _>>()?.len();
+        Ok(())
+    })?;
+    if num_batches == 0 {
+        return Err(BenchError::Invalid(format!(
+            "Text is too short for one batch of {batch_size} x {context_length} tokens"
+        )));
+    }
+    Ok(BenchResult {
+        name: format!("data loader ({num_workers} workers)"),
+        value: num_batches as f64 / seconds,
+        unit: "batches/s",
+    })
+}
+
+// 随机的`(batch_size, context_length)`批次，分别测前向传播和前向+反向传播
+pub fn bench_model(
+    model: &mut GPTModel,
+    batch_size: usize,
+    iterations: usize,
+    rng: &mut impl Rng,
+) -> Result<[BenchResult; 2]> {
+    let config = model.config().clone();
+    let batch = (0..batch_size)
+        .map(|_| {
+            (0..config.context_length + 1)
+                .map(|_| rng.random_range(0..config.vocab_size))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let inputs = batch
+        .iter()
+        .map(|ids| ids[..ids.len() - 1].to_vec())
+        .collect::<Vec<_>>();
+    let targets = batch
+        .iter()
+        .map(|ids| ids[1..].to_vec())
+        .collect::<Vec<_>>();
+    let tokens = (batch_size * config.context_length) as f64;
+
+    let forward = time(iterations, || {
+        model.forward_with_cache(&inputs, &mut model.new_kv_cache())
+    })?;
+    let backward = time(iterations, || -> model::Result<()> {
+        let logits = model.forward(&inputs)?;
+        let (_, grad) = cross_entropy_with_grad(&logits, &targets, None)?;
+        model.backward(&grad)?;
+        model.zero_grad();
+        Ok(())
+    })?;
+    Ok([
+        BenchResult {
+            name: "model forward".to_string(),
+            value: tokens / forward,
+            unit: "tokens/s",
+        },
+        BenchResult {
+            name: "model forward+backward".to_string(),
+            value: tokens / backward,
+            unit: "tokens/s",
+        },
+    ])
+}
+
+// 贪心生成`new_tokens`个token：使用KV缓存的`GPTModel::generate`与每一步重新计算整个上下文的`generate_text_simple`
+pub fn bench_generation(
+    model: &GPTModel,
+    prompt_len: usize,
+    new_tokens: usize,
+    iterations: usize,
+    rng: &mut impl Rng,
+) -> Result<[BenchResult; 2]> {
+    let config = model.config();
+    let prompt = (0..prompt_len.max(1))
+        .map(|_| rng.random_range(0..config.vocab_size))
+        .collect::<Vec<_>>();
+    let greedy = SamplingConfig::greedy();
+    let mut sample_rng = StdRng::seed_from_u64(0);
+
+    let cached = time(iterations, || {
+        model.generate(&prompt, new_tokens, &greedy, &mut sample_rng)
+    })?;
+    let uncached = time(iterations, || {
+        generate_text_simple(
+            model,
+            std::slice::from_ref(&prompt),
+            new_tokens,
+            config.context_length,
+        )
+    })?;
+    Ok([
+        BenchResult {
+            name: "generate (kv cache)".to_string(),
+            value: new_tokens as f64 / cached,
+            unit: "tokens/s",
+        },
+        BenchResult {
+            name: "generate (no cache)".to_string(),
+            value: new_tokens as f64 / uncached,
+            unit: "tokens/s",
+        },
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TestResult;
+    use crate::vocab::{Encoding, SentenceType, Vocabulary};
+
+    #[test]
+    fn test_run_bench() -> TestResult {
+        let config = BenchConfig {
+            model: GptConfig {
+                context_length: 16,
+                emb_dim: 16,
+                n_heads: 2,
+                n_layers: 1,
+                ..GptConfig::gpt2_small()
+            },
+            iterations: 1,
+            num_workers: vec![1, 2],
+            new_tokens: 4,
+            ..BenchConfig::default()
+        };
+        let vocab = Vocabulary::new("", SentenceType::English)?.with_encoding(Encoding::Gpt2);
+        let text = "Every effort moves you forward. ".repeat(20);
+        let report = run_bench(&config, &vocab, &text)?;
+        println!("{report}");
+
+        assert_eq!(report.results.len(), 1 + 2 + 2 + 2);
+        assert!(report.results.iter().all(|result| result.value > 0.0));
+        assert!(report.to_string().contains("data loader (2 workers)"));
+        assert!(run_bench(&config, &vocab, "Too short").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::{bail, Context, Result};
+use data_loader::TokenFileWriter;
+use llm::config::Config;
+use llm::stats::{detect_language, CorpusStats, Language};
+use llm::vocab::{Encoding, SentenceType, Vocabulary};
+use serde::de::{value::StrDeserializer, IntoDeserializer};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+// 每块约1MB文本，每个线程一块，攒够后用`encode_batch`并行分词，
+// 避免把整个语料读入内存
+const CHUNK_SIZE: usize = 1 << 20;
+
+const USAGE: &str = "<input.txt> <output.bin> [--languages zh,en] [--encoding Gpt2] \
+                     [--config config.toml] [--vocab vocab.json]";
+
+// 用法见`USAGE`。文档以空行分隔，`--languages`只保留指定语言（zh、en、other）的文档。
+// 英文使用`--encoding`指定的tiktoken编码，默认与训练配置（`--config`或默认配置）的
+// `tokenizer.encoding`相同。`--vocab`为中文或混合词表，中文文档用它编码；
+// 混合词表与tiktoken共用id空间，其余文档也用它编码。
+// 统计报告写入与输出文件同名的`.stats.json`
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len().is_multiple_of(2) {
+        bail!("Usage: {} {USAGE}", args[0]);
+    }
+
+    let (mut languages, mut encoding, mut config, mut vocab_path) = (None, None, None, None);
+    for flag in args[3..].chunks(2) {
+        match flag[0].as_str() {
+            "--languages" => languages = Some(parse_languages(&flag[1])?),
+            "--encoding" => encoding = Some(parse_encoding(&flag[1])?),
+            "--config" => config = Some(Config::load(&flag[1], &[])?),
+            "--vocab" => vocab_path = Some(&flag[1]),
+            _ => bail!("Unknown option {}. Usage: {} {USAGE}", flag[0], args[0]),
+        }
+    }
+    let encoding = encoding.unwrap_or_else(|| config.unwrap_or_default().tokenizer.encoding);
+
+    let vocab = Vocabulary::new("", SentenceType::English)?.with_encoding(encoding);
+    let zh_vocab = match vocab_path {
+        Some(path) => Some(load_zh_vocab(path, encoding, languages.as_deref())?),
+        None => None,
+    };
+    let mut reader = BufReader::new(File::open(&args[1])?);
+    let mut writer = TokenFileWriter::create(&args[2])?;
+    let mut stats = CorpusStats::new();
+    let mut docs: Vec<(Language, String)> = vec![];
+    let mut docs_len = 0;
+    let mut doc = String::new();
+    let mut has_text = false;
+    let mut in_gap = false;
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        let blank = line.trim().is_empty();
+
+        // 文档带上其后的空行，保证拼接后与原文一致
+        if (n == 0 && !doc.is_empty()) || (in_gap && !blank) {
+            let language = detect_language(&doc);
+            if languages.as_ref().is_none_or(|l| l.contains(&language)) {
+                stats.add_document(&doc);
+                docs_len += doc.len();
+                docs.push((language, std::mem::take(&mut doc)));
+            } else {
+                stats.add_filtered_document(language);
+                doc.clear();
+            }
+            has_text = false;
+        }
+
+        doc.push_str(&line);
+        has_text |= !blank;
+        in_gap = has_text && blank;
+
+        if n == 0 || docs_len >= CHUNK_SIZE * rayon::current_num_threads() {
+            let tokenizer = |language: Language| match (&zh_vocab, language) {
+                (Some(zh_vocab), Language::Zh) => zh_vocab,
+                (Some(zh_vocab), _) if zh_vocab.sentence_type() == &SentenceType::Mixed => zh_vocab,
+                _ => &vocab,
+            };
+            // 按语言分组批量编码，再按原顺序写入
+            let mut encoded = vec![vec![]; docs.len()];
+            for language in [Language::Zh, Language::En, Language::Other] {
+                let (indices, texts): (Vec<usize>, Vec<&str>) = docs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (l, _))| *l == language)
+                    .map(|(i, (_, doc))| (i, doc.as_str()))
+                    .unzip();
+                let token_ids = tokenizer(language).encode_batch(&texts)?;
+                for (i, token_ids) in indices.into_iter().zip(token_ids) {
+                    encoded[i] = token_ids;
+                }
+            }
+            for ((language, _), token_ids) in docs.iter().zip(encoded) {
+                stats.add_tokens(token_ids.len());
+                stats.add_language_document(*language, token_ids.len());
+                writer.write(&token_ids)?;
+            }
+            docs.clear();
+            docs_len = 0;
+        }
+
+        if n == 0 {
+            break;
+        }
+    }
+
+    let count = writer.finish()?;
+    println!("Wrote {count} tokens to {}", args[2]);
+
+    let report_path = Path::new(&args[2]).with_extension("stats.json");
+    serde_json::to_writer_pretty(File::create(&report_path)?, &stats.report())?;
+    println!("Wrote corpus statistics to {}", report_path.display());
+
+    Ok(())
+}
+
+fn parse_languages(names: &str) -> Result<Vec<Language>> {
+    names
+        .split(',')
+        .map(|name| {
+            Language::parse(name.trim()).with_context(|| format!("Unknown language {name}"))
+        })
+        .collect()
+}
+
+fn parse_encoding(name: &str) -> Result<Encoding> {
+    let deserializer: StrDeserializer<serde::de::value::Error> = name.into_deserializer();
+    Encoding::deserialize(deserializer).with_context(|| format!("Unknown encoding {name}"))
+}
+
+// 中文词表与tiktoken的id重叠，只能用于只保留中文的语料；
+// 混合词表自带tiktoken编码，需要与`encoding`一致
+fn load_zh_vocab(
+    path: &str,
+    encoding: Encoding,
+    languages: Option<&[Language]>,
+) -> Result<Vocabulary> {
+    let vocab = Vocabulary::load(path)?;
+    match vocab.sentence_type() {
+        SentenceType::Chinese if languages != Some(&[Language::Zh]) => bail!(
+            "A Chinese vocabulary shares ids with tiktoken, use --languages zh or a mixed vocabulary"
+        ),
+        SentenceType::Chinese => {}
+        SentenceType::Mixed if vocab.encoding() != encoding => bail!(
+            "The mixed vocabulary uses {:?}, but the encoding is {encoding:?}",
+            vocab.encoding()
+        ),
+        SentenceType::Mixed => {}
+        sentence_type => bail!("Expected a Chinese or mixed vocabulary, found {sentence_type:?}"),
+    }
+    Ok(vocab)
+}
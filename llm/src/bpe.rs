@@ -0,0 +1,350 @@
+use crate::error::{invalid, invalid_file, read_error, write_error, Result, TokenizerError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+const BYTE_TOKENS: usize = 256;
+
+// 从语料中学习字节级BPE合并规则。
+// id依次为：256个字节、特殊token、按学习顺序排列的合并结果
+#[derive(Debug, Clone)]
+pub struct BpeTrainer {
+    vocab_size: usize,
+    min_frequency: usize,
+    special_tokens: Vec<String>,
+}
+
+impl BpeTrainer {
+    pub fn new(vocab_size: usize) -> Self {
+        BpeTrainer {
+            vocab_size,
+            min_frequency: 2,
+            special_tokens: vec![],
+        }
+    }
+
+    // 出现次数少于`min_frequency`的字节对不再合并
+    pub fn min_frequency(mut self, min_frequency: usize) -> Self {
+        self.min_frequency = min_frequency.max(1);
+        self
+    }
+
+    pub fn special_tokens(mut self, special_tokens: &[&str]) -> Self {
+        self.special_tokens = special_tokens.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn train(&self, text: &str) -> BpeTokenizer {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for word in split_words(text) {
+            *counts.entry(word).or_default() += 1;
+        }
+
+        // 按单词排序，使训练结果与哈希表的遍历顺序无关
+        let mut words: Vec<(Vec<u32>, usize)> = counts
+            .into_iter()
+            .map(|(word, count)| (word.bytes().map(|b| b as u32).collect(), count))
+            .collect();
+        words.sort();
+
+        let mut tokenizer = BpeTokenizer::with_merges(vec![], self.special_tokens.clone());
+
+        while tokenizer.len() < self.vocab_size {
+            let mut pairs: HashMap<(u32, u32), usize> = HashMap::new();
+            for (symbols, count) in words.iter() {
+                for pair in symbols.windows(2) {
+                    *pairs.entry((pair[0], pair[1])).or_default() += count;
+                }
+            }
+
+            // 频率相同时选择id较小的字节对，保证结果确定
+            let best = pairs
+                .into_iter()
+                .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)));
+
+            let (pair, count) = match best {
+                Some(best) => best,
+                None => break,
+            };
+            if count < self.min_frequency {
+                break;
+            }
+
+            let id = tokenizer.push_merge(pair);
+            for (symbols, _) in words.iter_mut() {
+                merge_pair(symbols, pair, id);
+            }
+        }
+
+        tokenizer
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BpeFile {
+    special_tokens: Vec<String>,
+    merges: Vec<(u32, u32)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BpeTokenizer {
+    merges: Vec<(u32, u32)>,
+    ranks: HashMap<(u32, u32), u32>,
+    special_tokens: Vec<String>,
+    // 每个id对应的字节，特殊token为其UTF-8字节
+    id_to_bytes: Vec<Vec<u8>>,
+}
+
+impl BpeTokenizer {
+    fn with_merges(merges: Vec<(u32, u32)>, special_tokens: Vec<String>) -> Self {
+        let mut id_to_bytes: Vec<Vec<u8>> = (0..BYTE_TOKENS).map(|b| vec![b as u8]).collect();
+        id_to_bytes.extend(special_tokens.iter().map(|s| s.as_bytes().to_vec()));
+
+        let mut tokenizer = BpeTokenizer {
+            merges: vec![],
+            ranks: HashMap::new(),
+            special_tokens,
+            id_to_bytes,
+        };
+
+        for pair in merges {
+            tokenizer.push_merge(pair);
+        }
+        tokenizer
+    }
+
+    fn push_merge(&mut self, pair: (u32, u32)) -> u32 {
+        let id = self.id_to_bytes.len() as u32;
+        let mut bytes = self.id_to_bytes[pair.0 as usize].clone();
+        bytes.extend_from_slice(&self.id_to_bytes[pair.1 as usize]);
+
+        self.ranks.insert(pair, self.merges.len() as u32);
+        self.merges.push(pair);
+        self.id_to_bytes.push(bytes);
+        id
+    }
+
+    pub fn len(&self) -> usize {
+        self.id_to_bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.id_to_bytes.is_empty()
+    }
+
+    pub fn merges(&self) -> &[(u32, u32)] {
+        &self.merges
+    }
+
+    // 依次从`256`开始编号
+    pub fn special_tokens(&self) -> &[String] {
+        &self.special_tokens
+    }
+
+    pub fn special_token_id(&self, token: &str) -> Option<usize> {
+        self.special_tokens
+            .iter()
+            .position(|s| s == token)
+            .map(|i| BYTE_TOKENS + i)
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<usize> {
+        let mut token_ids = vec![];
+        let mut rest = text;
+
+        // 先切出特殊token，其余部分按单词分别合并
+        while !rest.is_empty() {
+            let special = self
+                .special_tokens
+                .iter()
+                .enumerate()
+                .filter_map(|(i, token)| rest.find(token.as_str()).map(|pos| (pos, i)))
+                .min_by_key(|&(pos, i)| (pos, usize::MAX - self.special_tokens[i].len()));
+
+            let (plain, next) = match special {
+                Some((pos, i)) => (&rest[..pos], Some(i)),
+                None => (rest, None),
+            };
+
+            for word in split_words(plain) {
+                self.encode_word(word, &mut token_ids);
+            }
+
+            match next {
+                Some(i) => {
+                    token_ids.push(BYTE_TOKENS + i);
+                    rest = &rest[plain.len() + self.special_tokens[i].len()..];
+                }
+                None => break,
+            }
+        }
+
+        token_ids
+    }
+
+    fn encode_word(&self, word: &str, token_ids: &mut Vec<usize>) {
+        let mut symbols: Vec<u32> = word.bytes().map(|b| b as u32).collect();
+
+        // 每次合并优先级最高（最早学到）的字节对
+        loop {
+            let best = symbols
+                .windows(2)
+                .filter_map(|pair| self.ranks.get(&(pair[0], pair[1])))
+                .min();
+
+            let rank = match best {
+                Some(&rank) => rank as usize,
+                None => break,
+            };
+
+            let id = (BYTE_TOKENS + self.special_tokens.len() + rank) as u32;
+            merge_pair(&mut symbols, self.merges[rank], id);
+        }
+
+        token_ids.extend(symbols.into_iter().map(|id| id as usize));
+    }
+
+    pub fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        let mut bytes = vec![];
+        for &id in token_ids {
+            let token = self
+                .id_to_bytes
+                .get(id)
+                .ok_or(TokenizerError::UnknownId(id))?;
+            bytes.extend_from_slice(token);
+        }
+
+        Ok(String::from_utf8(bytes)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = BpeFile {
+            special_tokens: self.special_tokens.clone(),
+            merges: self.merges.clone(),
+        };
+
+        let path = path.as_ref();
+        fs::write(path, serde_json::to_string(&file)?).map_err(write_error(path))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(read_error(path))?;
+        let file: BpeFile = serde_json::from_str(&text).map_err(invalid_file("BPE file", path))?;
+
+        let offset = BYTE_TOKENS + file.special_tokens.len();
+        for (rank, &(a, b)) in file.merges.iter().enumerate() {
+            if a as usize >= offset + rank || b as usize >= offset + rank {
+                return Err(invalid!("Invalid merge {rank} in {}", path.display()));
+            }
+        }
+
+        Ok(Self::with_merges(file.merges, file.special_tokens))
+    }
+}
+
+fn merge_pair(symbols: &mut Vec<u32>, pair: (u32, u32), id: u32) {
+    let mut i = 0;
+    let mut merged = Vec::with_capacity(symbols.len());
+
+    while i < symbols.len() {
+        if i + 1 < symbols.len() && (symbols[i], symbols[i + 1]) == pair {
+            merged.push(id);
+            i += 2;
+        } else {
+            merged.push(symbols[i]);
+            i += 1;
+        }
+    }
+
+    *symbols = merged;
+}
+
+#[derive(PartialEq)]
+enum CharClass {
+    Word,
+    Space,
+    Other,
+}
+
+fn char_class(c: char) -> CharClass {
+    if c.is_alphanumeric() {
+        CharClass::Word
+    } else if c.is_whitespace() {
+        CharClass::Space
+    } else {
+        CharClass::Other
+    }
+}
+
+// 与GPT-2类似的预分词：单词带上前面的一个空格，合并不会跨越单词边界
+fn split_words(text: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut start = 0;
+    let mut prev: Option<(char, CharClass)> = None;
+
+    for (pos, c) in text.char_indices() {
+        let class = char_class(c);
+
+        let boundary = match prev {
+            None => false,
+            Some(_) if c == ' ' => true,
+            Some((' ', _)) => class == CharClass::Space,
+            Some((_, ref prev_class)) => *prev_class != class,
+        };
+
+        if boundary {
+            words.push(&text[start..pos]);
+            start = pos;
+        }
+
+        prev = Some((c, class));
+    }
+
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bpe() {
+        let text = "low lower lowest newer newest 学习语言模型，学习分词。<eof>";
+        let tokenizer = BpeTrainer::new(300)
+            .min_frequency(2)
+            .special_tokens(&["<eof>"])
+            .train(text);
+
+        println!("vocab size: {}", tokenizer.len());
+        assert!(tokenizer.len() > 257 && tokenizer.len() <= 300);
+
+        let token_ids = tokenizer.encode(text);
+        println!("{:?}", token_ids);
+        assert!(token_ids.len() < text.len());
+        assert_eq!(token_ids.last(), Some(&256));
+        assert_eq!(tokenizer.decode(&token_ids).unwrap(), text);
+
+        let path = std::env::temp_dir().join("test_bpe.json");
+        tokenizer.save(&path).unwrap();
+        let loaded = BpeTokenizer::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            loaded.encode("the lowest 学习"),
+            tokenizer.encode("the lowest 学习")
+        );
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            split_words("Hello,  world!\n\n 你好"),
+            ["Hello", ",", " ", " world", "!", "\n\n", " 你好"]
+        );
+    }
+}
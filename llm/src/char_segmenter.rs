@@ -0,0 +1,55 @@
+use crate::error::{Result, TokenizerError};
+use std::io::BufRead;
+
+// 不启用`jieba` feature时（如编译为wasm）代替`jieba_rs::Jieba`，避免把几MB的词典编译进去。
+// 连续的ASCII字母和数字作为一个词，其余字符（包括每个汉字和空白字符）各自成为一个词。
+// 切分结果与jieba不同，用jieba构建的中文词表需要启用`jieba` feature
+#[derive(Debug, Clone, Default)]
+pub struct Jieba;
+
+impl Jieba {
+    pub fn new() -> Self {
+        Jieba
+    }
+
+    pub fn cut<'a>(&self, sentence: &'a str, _hmm: bool) -> Vec<&'a str> {
+        let mut words = vec![];
+        let mut start = 0;
+        for (i, c) in sentence.char_indices() {
+            let end = i + c.len_utf8();
+            let next_is_word = sentence[end..]
+                .chars()
+                .next()
+                .is_some_and(|next| next.is_ascii_alphanumeric());
+            if !(c.is_ascii_alphanumeric() && next_is_word) {
+                words.push(&sentence[start..end]);
+                start = end;
+            }
+        }
+        words
+    }
+
+    pub fn cut_all<'a>(&self, sentence: &'a str) -> Vec<&'a str> {
+        self.cut(sentence, false)
+    }
+
+    pub fn load_dict<R: BufRead>(&mut self, _dict: &mut R) -> Result<()> {
+        Err(TokenizerError::Unsupported(
+            "User dictionaries require the `jieba` feature".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_char_segmenter() {
+        let words = Jieba::new().cut("我爱Rust 2024，ok.", false);
+        println!("{words:?}");
+        assert_eq!(words, ["我", "爱", "Rust", " ", "2024", "，", "ok", "."]);
+        assert_eq!(words.concat(), "我爱Rust 2024，ok.");
+        assert!(Jieba::new().load_dict(&mut "词 1".as_bytes()).is_err());
+    }
+}
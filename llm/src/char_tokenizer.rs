@@ -0,0 +1,175 @@
+use crate::error::{invalid_file, read_error, write_error, Result, TokenizerError};
+use crate::vocab::{split_special, Segment, UNKNOWN_TOKEN};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize)]
+struct CharTokenizerFile {
+    special_tokens: Vec<String>,
+    chars: String,
+}
+
+// 字符级分词器，每个Unicode字符一个id，适合nanoGPT式的小模型实验。
+// 特殊token排在前面，语料中的字符按码位排序后依次编号
+#[derive(Debug, Clone)]
+pub struct CharTokenizer {
+    chars: Vec<char>,
+    char_to_id: HashMap<char, usize>,
+    special_tokens: Vec<(String, usize)>,
+}
+
+impl CharTokenizer {
+    pub fn new(text: &str) -> Self {
+        Self::with_special_tokens(text, &[])
+    }
+
+    // 包含`<unk>`时语料中没有的字符编码为`<unk>`，否则编码报错
+    pub fn with_special_tokens(text: &str, special_tokens: &[&str]) -> Self {
+        let chars: BTreeSet<char> = text.chars().collect();
+        let mut names: Vec<String> = vec![];
+        for token in special_tokens {
+            if !names.iter().any(|name| name == token) {
+                names.push(token.to_string());
+            }
+        }
+
+        Self::from_parts(names, chars.into_iter().collect())
+    }
+
+    fn from_parts(special_tokens: Vec<String>, chars: Vec<char>) -> Self {
+        let offset = special_tokens.len();
+        let char_to_id = chars
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (*c, offset + i))
+            .collect();
+        let special_tokens = special_tokens
+            .into_iter()
+            .enumerate()
+            .map(|(id, token)| (token, id))
+            .collect();
+
+        CharTokenizer {
+            chars,
+            char_to_id,
+            special_tokens,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.special_tokens.len() + self.chars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn special_token_id(&self, token: &str) -> Option<usize> {
+        self.special_tokens
+            .iter()
+            .find(|(special, _)| special == token)
+            .map(|(_, id)| *id)
+    }
+
+    pub fn special_tokens(&self) -> &[(String, usize)] {
+        &self.special_tokens
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        let unknown = self.special_token_id(UNKNOWN_TOKEN);
+        let mut token_ids = vec![];
+
+        for segment in split_special(text, &self.special_tokens) {
+            match segment {
+                Segment::Special(id) => token_ids.push(id),
+                Segment::Text(text) => {
+                    for c in text.chars() {
+                        match (self.char_to_id.get(&c), unknown) {
+                            (Some(&id), _) => token_ids.push(id),
+                            (None, Some(id)) => token_ids.push(id),
+                            (None, None) => {
+                                return Err(TokenizerError::UnknownToken(c.to_string()))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(token_ids)
+    }
+
+    pub fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        let offset = self.special_tokens.len();
+        let mut text = String::new();
+
+        for &id in token_ids {
+            match id.checked_sub(offset) {
+                None => text.push_str(&self.special_tokens[id].0),
+                Some(index) => {
+                    text.push(*self.chars.get(index).ok_or(TokenizerError::UnknownId(id))?)
+                }
+            }
+        }
+
+        Ok(text)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = CharTokenizerFile {
+            special_tokens: self.special_tokens.iter().map(|(t, _)| t.clone()).collect(),
+            chars: self.chars.iter().collect(),
+        };
+
+        let path = path.as_ref();
+        fs::write(path, serde_json::to_string(&file)?).map_err(write_error(path))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(read_error(path))?;
+        let file: CharTokenizerFile =
+            serde_json::from_str(&text).map_err(invalid_file("character vocabulary", path))?;
+
+        Ok(Self::from_parts(
+            file.special_tokens,
+            file.chars.chars().collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vocab::EOF_TOKEN;
+
+    #[test]
+    fn test_char_tokenizer() {
+        let text = "First Citizen:\nBefore we proceed any further, hear me speak.\n你好";
+        let tokenizer = CharTokenizer::new(text);
+        println!("vocab size: {}", tokenizer.len());
+        assert_eq!(tokenizer.encode("\n").unwrap(), [0]);
+
+        let token_ids = tokenizer.encode(text).unwrap();
+        assert_eq!(token_ids.len(), text.chars().count());
+        assert_eq!(tokenizer.decode(&token_ids).unwrap(), text);
+        assert!(tokenizer.encode("xyz").is_err());
+
+        let tokenizer = CharTokenizer::with_special_tokens(text, &[UNKNOWN_TOKEN, EOF_TOKEN]);
+        let token_ids = tokenizer.encode("hex<eof>").unwrap();
+        assert_eq!(token_ids[2], 0);
+        assert_eq!(token_ids.last(), Some(&1));
+        assert_eq!(tokenizer.decode(&token_ids).unwrap(), "he<unk><eof>");
+
+        let path = std::env::temp_dir().join("test_char_tokenizer.json");
+        tokenizer.save(&path).unwrap();
+        let loaded = CharTokenizer::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            loaded.encode(text).unwrap(),
+            tokenizer.encode(text).unwrap()
+        );
+    }
+}
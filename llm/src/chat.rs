@@ -0,0 +1,480 @@
+use crate::error::{file_error, GenerateError, TokenizerError};
+use crate::generate::{GenerationConfig, StopMatcher, TextStreamer};
+use crate::vocab::Vocabulary;
+use model::{GPTModel, SamplingConfig};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+type Result<T, E = GenerateError> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: Role::System,
+            content: content.into(),
+        }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: Role::User,
+            content: content.into(),
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: Role::Assistant,
+            content: content.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoleMarker {
+    pub prefix: String,
+    pub suffix: String,
+}
+
+// 把多轮对话拼接为一个序列，每条消息为`{prefix}{content}{suffix}`，
+// 助手的回复后面再加上`eos`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatTemplate {
+    system: RoleMarker,
+    user: RoleMarker,
+    assistant: RoleMarker,
+    bos: Option<String>,
+    eos: Option<String>,
+    // 对话中没有系统消息时使用
+    default_system: Option<String>,
+    // 模板中需要作为整体编码的标记
+    special_tokens: Vec<String>,
+}
+
+impl ChatTemplate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // `<|im_start|>user\n你好<|im_end|>\n`
+    pub fn chatml() -> Self {
+        let marker = |role: &str| RoleMarker {
+            prefix: format!("<|im_start|>{role}\n"),
+            suffix: "<|im_end|>\n".to_string(),
+        };
+
+        ChatTemplate {
+            system: marker("system"),
+            user: marker("user"),
+            assistant: marker("assistant"),
+            special_tokens: vec!["<|im_start|>".to_string(), "<|im_end|>".to_string()],
+            ..Default::default()
+        }
+    }
+
+    // 书中第7章指令微调使用的Alpaca格式
+    pub fn alpaca() -> Self {
+        ChatTemplate::new()
+            .marker(Role::System, "", "\n\n")
+            .marker(Role::User, "### Instruction:\n", "\n\n")
+            .marker(Role::Assistant, "### Response:\n", "")
+            .default_system(
+                "Below is an instruction that describes a task. \
+                 Write a response that appropriately completes the request.",
+            )
+    }
+
+    pub fn marker(mut self, role: Role, prefix: &str, suffix: &str) -> Self {
+        let marker = RoleMarker {
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+        };
+        match role {
+            Role::System => self.system = marker,
+            Role::User => self.user = marker,
+            Role::Assistant => self.assistant = marker,
+        }
+        self
+    }
+
+    pub fn bos(mut self, token: &str) -> Self {
+        self.bos = Some(token.to_string());
+        self.add_special(token);
+        self
+    }
+
+    pub fn eos(mut self, token: &str) -> Self {
+        self.eos = Some(token.to_string());
+        self.add_special(token);
+        self
+    }
+
+    pub fn default_system(mut self, content: &str) -> Self {
+        self.default_system = Some(content.to_string());
+        self
+    }
+
+    pub fn special_token(mut self, token: &str) -> Self {
+        self.add_special(token);
+        self
+    }
+
+    pub fn special_tokens(&self) -> &[String] {
+        &self.special_tokens
+    }
+
+    fn add_special(&mut self, token: &str) {
+        if !self.special_tokens.iter().any(|special| special == token) {
+            self.special_tokens.push(token.to_string());
+        }
+    }
+
+    pub fn role_marker(&self, role: Role) -> &RoleMarker {
+        match role {
+            Role::System => &self.system,
+            Role::User => &self.user,
+            Role::Assistant => &self.assistant,
+        }
+    }
+
+    // `add_generation_prompt`为true时在最后加上助手的前缀，用于推理时让模型生成回复
+    pub fn render(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> String {
+        let mut text = self.bos.clone().unwrap_or_default();
+
+        let has_system = messages.iter().any(|message| message.role == Role::System);
+        if let Some(content) = self.default_system.as_ref()
+            && !has_system
+        {
+            self.push_message(&mut text, Role::System, content);
+        }
+
+        for message in messages {
+            self.push_message(&mut text, message.role, &message.content);
+        }
+
+        if add_generation_prompt {
+            text.push_str(&self.assistant.prefix);
+        }
+        text
+    }
+
+    fn push_message(&self, text: &mut String, role: Role, content: &str) {
+        let marker = self.role_marker(role);
+        text.push_str(&marker.prefix);
+        text.push_str(content);
+        text.push_str(&marker.suffix);
+
+        if let (Role::Assistant, Some(eos)) = (role, self.eos.as_ref()) {
+            text.push_str(eos);
+        }
+    }
+
+    // 生成回复时遇到这些文本说明助手的回合已经结束：助手的后缀、用户的前缀和`eos`
+    pub fn stop_strings(&self) -> Vec<String> {
+        [&self.assistant.suffix, &self.user.prefix]
+            .into_iter()
+            .map(|marker| marker.trim().to_string())
+            .chain(self.eos.clone())
+            .filter(|stop| !stop.is_empty())
+            .collect()
+    }
+
+    // 模板的特殊token不在词表中时先加入词表
+    pub fn encode(
+        &self,
+        vocab: &mut Vocabulary,
+        messages: &[ChatMessage],
+        add_generation_prompt: bool,
+    ) -> Result<Vec<usize>, TokenizerError> {
+        for token in self.special_tokens.iter() {
+            vocab.add_special_token(token);
+        }
+        vocab.encode(&self.render(messages, add_generation_prompt))
+    }
+}
+
+// `llm chat`的多轮对话：每次回复前按模板渲染整个对话，超过上下文窗口时从最早的一轮开始丢弃，
+// 系统消息始终保留
+pub struct ChatSession {
+    template: ChatTemplate,
+    system: Option<String>,
+    messages: Vec<ChatMessage>,
+}
+
+impl ChatSession {
+    pub fn new(template: ChatTemplate) -> Self {
+        ChatSession {
+            template,
+            system: None,
+            messages: vec![],
+        }
+    }
+
+    // 为空时使用模板的默认系统消息
+    pub fn set_system(&mut self, content: &str) {
+        self.system = (!content.is_empty()).then(|| content.to_string());
+    }
+
+    // 清空对话，保留系统消息
+    pub fn reset(&mut self) {
+        self.messages.clear();
+    }
+
+    pub fn messages(&self) -> Vec<ChatMessage> {
+        self.system
+            .iter()
+            .map(ChatMessage::system)
+            .chain(self.messages.iter().cloned())
+            .collect()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, serde_json::to_string_pretty(&self.messages())?)
+            .map_err(file_error("write", path))?;
+        Ok(())
+    }
+
+    // 生成回复并加入对话，每解码出一段文本调用一次`on_text`。生成`eos`（`eos_token_id`为空时
+    // 使用词表的`eos_id`）、模板的`stop_strings`或`stop_sequences`时停止，返回的回复去掉了首尾的空白。
+    // 出错时对话保持不变
+    #[allow(clippy::too_many_arguments)]
+    pub fn reply(
+        &mut self,
+        model: &GPTModel,
+        vocab: &mut Vocabulary,
+        content: &str,
+        generation: &GenerationConfig,
+        sampling: &SamplingConfig,
+        rng: &mut impl Rng,
+        on_text: impl FnMut(&str),
+    ) -> Result<String> {
+        self.messages.push(ChatMessage::user(content));
+        match self.generate_reply(model, vocab, generation, sampling, rng, on_text) {
+            Ok(reply) => {
+                self.messages.push(ChatMessage::assistant(reply.clone()));
+                Ok(reply)
+            }
+            Err(err) => {
+                self.messages.pop();
+                Err(err)
+            }
+        }
+    }
+
+    fn generate_reply(
+        &mut self,
+        model: &GPTModel,
+        vocab: &mut Vocabulary,
+        generation: &GenerationConfig,
+        sampling: &SamplingConfig,
+        rng: &mut impl Rng,
+        mut on_text: impl FnMut(&str),
+    ) -> Result<String> {
+        let max_new_tokens = generation.max_new_tokens;
+        let budget = model.config().context_length.saturating_sub(max_new_tokens);
+        let prompt = self.prompt(vocab, budget.max(1))?;
+
+        let mut stops = self.template.stop_strings();
+        stops.extend(generation.stop_sequences.iter().cloned());
+        let mut stops = StopMatcher::new(stops);
+        let eos = generation.eos_token_id.or(vocab.eos_id());
+        let mut streamer = TextStreamer::new(&*vocab);
+        model.generate_stream(
+            &prompt,
+            max_new_tokens,
+            sampling,
+            rng,
+            |token| -> Result<bool> {
+                if Some(token) == eos {
+                    return Ok(false);
+                }
+                let piece = stops.push(&streamer.push(token)?);
+                if !piece.is_empty() {
+                    on_text(piece);
+                }
+                Ok(!stops.is_stopped())
+            },
+        )?;
+
+        // 保留的部分去掉结尾的空白后输出
+        let (text, emitted) = (stops.text(), stops.emitted());
+        let end = text.trim_end().len().max(emitted);
+        if end > emitted {
+            on_text(&text[emitted..end]);
+        }
+        Ok(text.trim().to_string())
+    }
+
+    // 渲染到助手的前缀为止，超过`max_len`个token时整轮（用户和助手的消息）丢弃最早的对话，
+    // 最后一条用户消息始终保留。系统消息不在`self.messages`中，不会被丢弃
+    fn prompt(
+        &mut self,
+        vocab: &mut Vocabulary,
+        max_len: usize,
+    ) -> Result<Vec<usize>, TokenizerError> {
+        loop {
+            let token_ids = self.template.encode(vocab, &self.messages(), true)?;
+            if token_ids.len() <= max_len || self.messages.len() <= 1 {
+                return Ok(token_ids);
+            }
+            let pair = (self.messages[0].role, self.messages[1].role);
+            let turn = if pair == (Role::User, Role::Assistant) {
+                2
+            } else {
+                1
+            };
+            self.messages.drain(..turn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TestResult;
+    use crate::vocab::{Encoding, SentenceType, EOF_TOKEN};
+    use model::GptConfig;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_chat_template() {
+        let messages = [
+            ChatMessage::system("你是一个助手。"),
+            ChatMessage::user("你好"),
+            ChatMessage::assistant("你好，有什么可以帮你？"),
+        ];
+
+        let template = ChatTemplate::chatml();
+        let text = template.render(&messages[1..2], true);
+        println!("{text}");
+        assert_eq!(
+            text,
+            "<|im_start|>user\n你好<|im_end|>\n<|im_start|>assistant\n"
+        );
+
+        let mut vocab =
+            Vocabulary::new("你是一个助手。你好，有什么可以帮你？", SentenceType::Mixed).unwrap();
+        let template = template.eos(EOF_TOKEN);
+        let token_ids = template.encode(&mut vocab, &messages, false).unwrap();
+        println!("{:?}", token_ids);
+        let im_start = vocab.special_token_id("<|im_start|>").unwrap();
+        let eof = vocab.special_token_id(EOF_TOKEN).unwrap();
+        assert_eq!(token_ids.iter().filter(|id| **id == im_start).count(), 3);
+        assert_eq!(token_ids.last(), Some(&eof));
+        assert_eq!(
+            vocab.decode(&token_ids).unwrap(),
+            template.render(&messages, false)
+        );
+
+        let text = ChatTemplate::alpaca().render(&messages[1..], false);
+        assert!(text.starts_with("Below is an instruction"));
+        assert!(text.ends_with("### Response:\n你好，有什么可以帮你？"));
+    }
+
+    #[test]
+    fn test_chat_session() -> TestResult {
+        let template = ChatTemplate::chatml();
+        assert_eq!(template.stop_strings(), ["<|im_end|>", "<|im_start|>user"]);
+
+        let config = GptConfig {
+            context_length: 64,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 1,
+            dropout: 0.0,
+            ..GptConfig::gpt2_small()
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let mut vocab = Vocabulary::new("", SentenceType::English)?.with_encoding(Encoding::Gpt2);
+        let mut session = ChatSession::new(ChatTemplate::alpaca());
+        session.set_system("You are a helpful assistant.");
+
+        let mut rng = StdRng::seed_from_u64(123);
+        let greedy = SamplingConfig::greedy();
+        let mut streamed = String::new();
+        let generation = GenerationConfig::new(8);
+        let reply = session.reply(
+            &model,
+            &mut vocab,
+            "Hello",
+            &generation,
+            &greedy,
+            &mut rng,
+            |piece| streamed.push_str(piece),
+        )?;
+        println!("{reply:?}");
+        assert_eq!(streamed.trim(), reply);
+        assert_eq!(session.messages().len(), 3);
+
+        // 超过上下文窗口时丢弃最早的一轮对话，系统消息保留
+        for _ in 0..3 {
+            session.reply(
+                &model,
+                &mut vocab,
+                "Tell me more about it",
+                &generation,
+                &greedy,
+                &mut rng,
+                |_| {},
+            )?;
+        }
+        let messages = session.messages();
+        println!("{messages:#?}");
+        assert_eq!(messages[0].role, Role::System);
+        assert!(messages.len() < 1 + 2 * 4);
+        // 按轮丢弃，剩下的对话仍然从用户的消息开始
+        assert_eq!(messages[1].role, Role::User);
+        assert_eq!(messages.len() % 2, 1);
+        let prompt = ChatTemplate::alpaca().render(&messages[..messages.len() - 1], true);
+        assert!(vocab.encode(&prompt)?.len() <= 64 - 8);
+
+        let path = std::env::temp_dir().join("test_chat_session.json");
+        session.save(&path)?;
+        let saved: Vec<ChatMessage> = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        assert_eq!(saved, messages);
+        session.reset();
+        assert_eq!(
+            session.messages(),
+            [ChatMessage::system("You are a helpful assistant.")]
+        );
+
+        // 生成出错时不留下没有回复的用户消息：GPT-2的token id超出小词表的模型
+        let small = GPTModel::new(
+            &GptConfig {
+                vocab_size: 100,
+                ..config
+            },
+            &mut StdRng::seed_from_u64(123),
+        )?;
+        let result = session.reply(
+            &small,
+            &mut vocab,
+            "Hello",
+            &generation,
+            &greedy,
+            &mut rng,
+            |_| {},
+        );
+        assert!(result.is_err());
+        assert_eq!(session.messages().len(), 1);
+        Ok(())
+    }
+}
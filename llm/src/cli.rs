@@ -0,0 +1,815 @@
+use crate::bench::{run_bench, BenchConfig};
+use crate::chat::{ChatSession, ChatTemplate, Role};
+use crate::config::Config;
+use crate::corpus::{self, CORPORA};
+use crate::error::{error_chain, file_error, CliError, TrainError};
+use crate::eval::{evaluate_cloze, evaluate_multiple_choice, load_hellaswag, load_lambada};
+use crate::generate::{generate_text_stream, GenerationConfig};
+use crate::gguf::tokenizer_metadata;
+use crate::loss::evaluate;
+use crate::train::{run_state_dir, RunState, TrainConfig, TrainSample, Trainer};
+use crate::vocab::{Encoding, SentenceType, Vocabulary};
+use clap::builder::RangedU64ValueParser;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use data_loader::{DataLoader, Dataset, GPTDataset};
+use model::{
+    export_gguf, AdamW, Device, GPTModel, GgufType, GgufValue, SamplingConfig, WarmupCosine,
+};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::convert::Infallible;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+type Result<T, E = CliError> = std::result::Result<T, E>;
+
+const CHAT_HELP: &str = "Commands:
+  /system <text>  set the system prompt and start a new conversation
+  /reset          clear the conversation
+  /save <path>    save the conversation as JSON
+  /quit           exit";
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "llm",
+    about = "Train, evaluate and run GPT models built from scratch"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum Command {
+    #[command(
+        about = "Train a model from a config file",
+        after_help = "Any `--section.key value` overrides the config, e.g. `--training.lr 1e-3`"
+    )]
+    Train {
+        // 从运行状态继续时默认使用其中的`config.json`
+        #[arg(
+            long,
+            required_unless_present = "resume",
+            help = "config.toml|config.yaml|config.json"
+        )]
+        config: Option<PathBuf>,
+        // `--section.key value`形式的配置覆盖，如`--training.lr 1e-3`，由`parse_args`在clap之前取出
+        #[arg(skip)]
+        overrides: Vec<(String, String)>,
+        #[arg(long, help = "Continue from a run state directory")]
+        resume: Option<PathBuf>,
+        // 覆盖`training.device`
+        #[arg(long, help = "cpu|cuda[:N]|metal[:N]")]
+        device: Option<Device>,
+    },
+    #[command(about = "Generate text from a prompt")]
+    Generate {
+        #[arg(long, help = "dir|model.safetensors")]
+        checkpoint: PathBuf,
+        #[arg(long)]
+        prompt: String,
+        #[arg(long, default_value_t = 50)]
+        max_new_tokens: usize,
+        #[arg(long, default_value_t = Device::Cpu, help = "cpu|cuda[:N]|metal[:N]")]
+        device: Device,
+        #[command(flatten)]
+        decode: DecodeArgs,
+    },
+    #[command(about = "Multi-turn chat with a fine-tuned model")]
+    Chat {
+        #[arg(long, help = "dir|model.safetensors")]
+        checkpoint: PathBuf,
+        #[arg(long, default_value = "alpaca", help = "alpaca|chatml")]
+        template: String,
+        #[arg(long, default_value_t = 256)]
+        max_new_tokens: usize,
+        #[arg(long, default_value_t = Device::Cpu, help = "cpu|cuda[:N]|metal[:N]")]
+        device: Device,
+        #[command(flatten)]
+        decode: DecodeArgs,
+    },
+    #[command(about = "Evaluate perplexity or benchmark accuracy")]
+    Eval {
+        #[arg(long, help = "dir|model.safetensors")]
+        checkpoint: PathBuf,
+        #[arg(long, help = "val.txt|hellaswag|lambada")]
+        data: PathBuf,
+        #[arg(
+            long,
+            default_value = "perplexity",
+            help = "perplexity|hellaswag|lambada"
+        )]
+        task: String,
+        #[arg(long, default_value_t = 8)]
+        batch_size: usize,
+        // 只评估前`limit`条样本，用于快速比较检查点
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    #[command(about = "Export a checkpoint to GGUF")]
+    Export {
+        #[arg(long, help = "dir|model.safetensors")]
+        checkpoint: PathBuf,
+        #[arg(long)]
+        output: PathBuf,
+        #[arg(long, default_value = "f32", help = "f32|f16|q8_0|q4_0")]
+        quantize: GgufType,
+    },
+    #[command(about = "Measure tokenizer, DataLoader, training and generation throughput")]
+    Bench {
+        // 模型、批次大小和文本来自配置文件，没有时使用默认配置
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(long, default_value_t = 3)]
+        iterations: usize,
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_values_t = [1, 2, 4],
+            value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        workers: Vec<usize>,
+        #[arg(long, default_value_t = 32)]
+        new_tokens: usize,
+    },
+    #[command(subcommand, about = "Download or list the known datasets")]
+    Data(DataCommand),
+    #[command(about = "Print the token ids of a text")]
+    Tokenize {
+        #[arg(long)]
+        text: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum DataCommand {
+    #[command(about = "Download and verify a dataset")]
+    Fetch {
+        name: String,
+        // 默认为`corpus::data_dir()`
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+    #[command(about = "List the known datasets")]
+    List,
+}
+
+// `generate`和`chat`共用的解码选项
+#[derive(Debug, Clone, PartialEq, Args)]
+pub struct DecodeArgs {
+    // 停止文本中的`\n`、`\t`和`\\`转换为对应的字符，方便在命令行中输入
+    #[arg(long = "stop", value_parser = parse_stop, help = "Stop text, can be repeated")]
+    pub stop_sequences: Vec<String>,
+    // 为空时使用分词器的`eos_id`
+    #[arg(long)]
+    pub eos_token_id: Option<usize>,
+    #[arg(long, default_value_t = 0.0)]
+    pub temperature: f32,
+    #[arg(long)]
+    pub top_k: Option<usize>,
+    #[arg(long, default_value_t = 123)]
+    pub seed: u64,
+}
+
+impl DecodeArgs {
+    pub fn generation(&self, max_new_tokens: usize) -> GenerationConfig {
+        GenerationConfig::new(max_new_tokens)
+            .with_stop_sequences(self.stop_sequences.clone())
+            .with_eos_token_id(self.eos_token_id)
+    }
+
+    pub fn sampling(&self) -> SamplingConfig {
+        SamplingConfig {
+            temperature: self.temperature,
+            top_k: self.top_k,
+            ..SamplingConfig::default()
+        }
+    }
+}
+
+// `args`不包括程序名，形如`["generate", "--prompt", "..."]`。
+// `train`的`--section.key value`不是固定的选项，先取出来再交给clap
+pub fn parse_args(args: &[String]) -> Result<Command, clap::Error> {
+    let mut rest = vec!["llm".to_string()];
+    let mut overrides = vec![];
+    let mut args = args.iter();
+    let is_train = args
+        .as_slice()
+        .first()
+        .is_some_and(|command| command == "train");
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--") {
+            Some(name) if is_train && name.contains('.') => {
+                let Some(value) = args.next() else {
+                    return Err(Cli::command().error(
+                        ErrorKind::InvalidValue,
+                        format!("Missing value for `--{name}`"),
+                    ));
+                };
+                if overrides.iter().any(|(key, _)| key == name) {
+                    return Err(Cli::command().error(
+                        ErrorKind::ArgumentConflict,
+                        format!("Duplicate option `--{name}`"),
+                    ));
+                }
+                overrides.push((name.to_string(), value.clone()));
+            }
+            _ => rest.push(arg.clone()),
+        }
+    }
+    overrides.sort();
+
+    let mut command = Cli::try_parse_from(rest)?.command;
+    if let Command::Train {
+        overrides: parsed, ..
+    } = &mut command
+    {
+        *parsed = overrides;
+    }
+    Ok(command)
+}
+
+fn parse_stop(text: &str) -> Result<String, Infallible> {
+    Ok(unescape(text))
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => result.push('\n'),
+            ('\\', Some('t')) => result.push('\t'),
+            ('\\', Some('\\')) => result.push('\\'),
+            _ => {
+                result.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    result
+}
+
+pub fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Train {
+            config,
+            overrides,
+            resume,
+            device,
+        } => {
+            let config = match (config, &resume) {
+                (Some(config), _) => config,
+                (None, Some(resume)) => run_state_dir(resume).join("config.json"),
+                (None, None) => return Err(CliError::MissingConfig),
+            };
+            let mut config = Config::load(config, &overrides)?;
+            if let Some(device) = device {
+                config.training.device = device;
+            }
+            train(&config, resume.as_deref())
+        }
+        Command::Generate {
+            checkpoint,
+            prompt,
+            max_new_tokens,
+            device,
+            decode,
+        } => {
+            let mut model = load_checkpoint(&checkpoint)?;
+            model.to_device(device)?;
+            let tokenizer = checkpoint_tokenizer(&checkpoint)?;
+            let generation = decode.generation(max_new_tokens);
+            let eos_token_id = generation.eos_token_id.or(tokenizer.eos_id());
+            let generation = generation.with_eos_token_id(eos_token_id);
+            let sampling = decode.sampling();
+            let mut rng = StdRng::seed_from_u64(decode.seed);
+            // 边生成边输出
+            print!("{prompt}");
+            generate_text_stream(
+                &model,
+                &tokenizer,
+                &prompt,
+                &generation,
+                &sampling,
+                &mut rng,
+                |piece| {
+                    print!("{piece}");
+                    let _ = io::stdout().flush();
+                },
+            )?;
+            println!();
+            Ok(())
+        }
+        Command::Chat {
+            checkpoint,
+            template,
+            max_new_tokens,
+            device,
+            decode,
+        } => {
+            let mut model = load_checkpoint(&checkpoint)?;
+            model.to_device(device)?;
+            let mut tokenizer = checkpoint_tokenizer(&checkpoint)?;
+            let mut session = ChatSession::new(chat_template(&template)?);
+            let (generation, sampling) = (decode.generation(max_new_tokens), decode.sampling());
+            let mut rng = StdRng::seed_from_u64(decode.seed);
+            println!("{CHAT_HELP}");
+            let stdin = io::stdin();
+            loop {
+                print!("> ");
+                io::stdout().flush()?;
+                let mut line = String::new();
+                if stdin.read_line(&mut line)? == 0 {
+                    break;
+                }
+                let line = line.trim();
+                let result: Result<()> = match line.split_once(' ').unwrap_or((line, "")) {
+                    ("", _) => Ok(()),
+                    ("/quit" | "/exit", _) => break,
+                    ("/reset", _) => {
+                        session.reset();
+                        Ok(())
+                    }
+                    ("/system", content) => {
+                        session.set_system(content.trim());
+                        session.reset();
+                        Ok(())
+                    }
+                    ("/save", path) if !path.trim().is_empty() => {
+                        session.save(path.trim()).map_err(Into::into)
+                    }
+                    ("/help", _) => {
+                        println!("{CHAT_HELP}");
+                        Ok(())
+                    }
+                    (command, _) if command.starts_with('/') => Err(CliError::Invalid(format!(
+                        "Unknown command `{line}`\n{CHAT_HELP}"
+                    ))),
+                    _ => session
+                        .reply(
+                            &model,
+                            &mut tokenizer,
+                            line,
+                            &generation,
+                            &sampling,
+                            &mut rng,
+                            |piece| {
+                                print!("{piece}");
+                                let _ = io::stdout().flush();
+                            },
+                        )
+                        .map(|_| println!())
+                        .map_err(Into::into),
+                };
+                // 出错时只打印错误，对话继续
+                if let Err(err) = result {
+                    eprintln!("Error: {}", error_chain(&err));
+                }
+            }
+            Ok(())
+        }
+        Command::Eval {
+            checkpoint,
+            data,
+            task,
+            batch_size,
+            limit,
+        } => {
+            let model = load_checkpoint(&checkpoint)?;
+            let tokenizer = checkpoint_tokenizer(&checkpoint)?;
+            let data = corpus::resolve(&data)?;
+            let limit = limit.unwrap_or(usize::MAX);
+            let result = match task.as_str() {
+                "perplexity" => {
+                    let text = fs::read_to_string(&data).map_err(file_error("read", &data))?;
+                    let context_length = model.config().context_length;
+                    let token_ids = tokenizer.encode(&text)?;
+                    let dataset = GPTDataset::new(token_ids, context_length, context_length);
+                    let loader = DataLoader::new(dataset, batch_size, false, 1, false);
+                    let batches = loader.iter().collect::<Result<Vec<_>, _>>()?;
+                    let evaluation = evaluate(&model, &batches, Some(limit), None)?;
+                    println!(
+                        "loss: {:.4}, perplexity: {:.2}, tokens: {}",
+                        evaluation.loss, evaluation.perplexity, evaluation.tokens
+                    );
+                    return Ok(());
+                }
+                "hellaswag" => {
+                    let mut examples = load_hellaswag(&data)?;
+                    examples.truncate(limit);
+                    evaluate_multiple_choice(&model, &tokenizer, &examples)?
+                }
+                "lambada" => {
+                    let mut examples = load_lambada(&data)?;
+                    examples.truncate(limit);
+                    evaluate_cloze(&model, &tokenizer, &examples)?
+                }
+                _ => {
+                    return Err(CliError::Invalid(format!(
+                        "Unknown eval task `{task}`, expected perplexity, hellaswag or lambada"
+                    )))
+                }
+            };
+            print!(
+                "{task}: examples: {}, accuracy: {:.4}",
+                result.examples, result.accuracy
+            );
+            if let Some(accuracy_norm) = result.accuracy_norm {
+                print!(", accuracy_norm: {accuracy_norm:.4}");
+            }
+            println!(", perplexity: {:.2}", result.perplexity);
+            Ok(())
+        }
+        Command::Export {
+            checkpoint,
+            output,
+            quantize,
+        } => {
+            let model = load_checkpoint(&checkpoint)?;
+            let tokenizer = checkpoint_tokenizer(&checkpoint)?;
+            let mut metadata = tokenizer_metadata(&tokenizer, model.config().vocab_size)?;
+            let name = checkpoint.file_stem().unwrap_or_default().to_string_lossy();
+            metadata.push((
+                "general.name".to_string(),
+                GgufValue::String(name.to_string()),
+            ));
+            export_gguf(&model, &output, quantize, &metadata)?;
+            println!("Exported {quantize:?} GGUF to {}", output.display());
+            Ok(())
+        }
+        Command::Bench {
+            config,
+            iterations,
+            workers,
+            new_tokens,
+        } => {
+            let config = match config {
+                Some(path) => Config::load(path, &[])?,
+                None => Config::default(),
+            };
+            let tokenizer = new_tokenizer(config.tokenizer.encoding)?;
+            let path = corpus::resolve(&config.data.path)?;
+            let text = fs::read_to_string(&path).map_err(file_error("read", &path))?;
+            let bench_config = BenchConfig {
+                model: config.model,
+                batch_size: config.data.batch_size,
+                iterations,
+                num_workers: workers,
+                new_tokens,
+                seed: config.training.seed,
+                ..BenchConfig::default()
+            };
+            print!("{}", run_bench(&bench_config, &tokenizer, &text)?);
+            Ok(())
+        }
+        Command::Data(DataCommand::Fetch { name, dir }) => {
+            let Some(corpus) = corpus::find(&name) else {
+                return Err(CliError::Invalid(format!(
+                    "Unknown dataset `{name}`, see `llm data list`"
+                )));
+            };
+            let path = corpus::fetch(corpus, dir.unwrap_or_else(corpus::data_dir))?;
+            println!("{}", path.display());
+            Ok(())
+        }
+        Command::Data(DataCommand::List) => {
+            for corpus in CORPORA {
+                println!("{:<20} {}", corpus.name, corpus.url);
+            }
+            Ok(())
+        }
+        Command::Tokenize { text } => {
+            let tokenizer = new_tokenizer(Config::default().tokenizer.encoding)?;
+            let token_ids = tokenizer.encode(&text)?;
+            println!("{token_ids:?}");
+            println!("{} tokens", token_ids.len());
+            Ok(())
+        }
+    }
+}
+
+// `save_checkpoint`的目录或单独的`model.safetensors`，目录中有`lora.safetensors`时一起加载
+fn load_checkpoint(path: &Path) -> Result<GPTModel> {
+    if !path.is_dir() {
+        return Ok(GPTModel::load(path)?);
+    }
+    let mut model = GPTModel::load(path.join("model.safetensors"))?;
+    let lora_path = path.join("lora.safetensors");
+    if lora_path.exists() {
+        model.load_lora(lora_path)?;
+    }
+    Ok(model)
+}
+
+// 书中第7章微调使用Alpaca格式，回合之间用空行分隔
+fn chat_template(name: &str) -> Result<ChatTemplate> {
+    match name {
+        "alpaca" => Ok(ChatTemplate::alpaca().marker(Role::Assistant, "### Response:\n", "\n\n")),
+        "chatml" => Ok(ChatTemplate::chatml()),
+        _ => Err(CliError::Invalid(format!(
+            "Unknown chat template `{name}`, expected alpaca or chatml"
+        ))),
+    }
+}
+
+fn new_tokenizer(encoding: Encoding) -> Result<Vocabulary> {
+    Ok(Vocabulary::new("", SentenceType::English)?.with_encoding(encoding))
+}
+
+// 检查点目录中`config.json`记录的分词器，没有时使用默认的GPT-2词表
+fn checkpoint_tokenizer(path: &Path) -> Result<Vocabulary> {
+    let config_path = path.join("config.json");
+    let config = match path.is_dir() && config_path.exists() {
+        true => Config::load(config_path, &[])?,
+        false => Config::default(),
+    };
+    new_tokenizer(config.tokenizer.encoding)
+}
+
+// `resume`为`Trainer::save_run_state`写入的目录，从其中的位置继续训练。没有配置`training.run_state`时之后的运行状态仍写到该目录
+fn train(config: &Config, resume: Option<&Path>) -> Result<()> {
+    let tokenizer = new_tokenizer(config.tokenizer.encoding)?;
+    let (data, training) = (&config.data, &config.training);
+    let path = corpus::resolve(&data.path)?;
+    let text = fs::read_to_string(&path).map_err(file_error("read", &path))?;
+    let start = Instant::now();
+    let token_ids = tokenizer.encode(&text)?;
+    tracing::info!(
+        "Tokenized {} bytes into {} tokens in {:.3?}",
+        text.len(),
+        token_ids.len(),
+        start.elapsed()
+    );
+    let split = ((1.0 - data.val_ratio) * token_ids.len() as f32) as usize;
+    let (train_ids, val_ids) = token_ids.split_at(split.min(token_ids.len()));
+    let context_length = config.model.context_length;
+    let stride = data.stride.unwrap_or(context_length);
+
+    let train_dataset = GPTDataset::new(train_ids.to_vec(), context_length, stride);
+    let steps_per_epoch =
+        train_dataset.len() / (data.batch_size * training.accumulation_steps).max(1);
+    let total_steps = (steps_per_epoch * training.num_epochs) as u64;
+    if let Some(warmup_steps) = training.warmup_steps
+        && warmup_steps > total_steps
+    {
+        return Err(CliError::Invalid(format!(
+            "`training.warmup_steps` ({warmup_steps}) exceeds the total number of steps ({total_steps})"
+        )));
+    }
+    let mut builder = DataLoader::builder()
+        .batch_size(data.batch_size)
+        .shuffle(true)
+        .seed(training.seed)
+        .drop_last(true)
+        .persistent_workers(true);
+    if let Some(resume) = resume {
+        let state = RunState::load(resume)?;
+        tracing::info!(
+            "Resuming from step {} ({} samples into epoch {})",
+            state.global_step,
+            state.consumed,
+            state.epoch
+        );
+        builder = builder.resume(state.loader_state());
+    }
+    let train_loader = builder.build(train_dataset);
+    let val_loader = DataLoader::new(
+        GPTDataset::new(val_ids.to_vec(), context_length, stride),
+        data.batch_size,
+        false,
+        1,
+        false,
+    );
+    let val_batches = val_loader.iter().collect::<Result<Vec<_>, _>>()?;
+
+    let mut model = GPTModel::new(&config.model, &mut StdRng::seed_from_u64(training.seed))?;
+    model.to_device(training.device)?;
+    let train_config = TrainConfig {
+        num_epochs: training.num_epochs,
+        accumulation_steps: training.accumulation_steps,
+        max_grad_norm: training.max_grad_norm,
+        eval_freq: training.eval_freq,
+        eval_iter: training.eval_iter,
+        start_context: training.start_context.clone(),
+        sample_tokens: training.sample_tokens,
+        sample_freq: training.sample_freq,
+        sampling: config.sampling.clone(),
+        seed: training.seed,
+        run_state_freq: training.run_state_freq,
+        ..TrainConfig::default()
+    };
+    let mut trainer = Trainer::new(
+        model,
+        AdamW::new(training.lr, training.weight_decay),
+        train_config,
+    )
+    .with_sample_callback(|sample: &TrainSample| {
+        println!(
+            "Ep {} (Step {:06}): {}",
+            sample.epoch, sample.step, sample.text
+        )
+    });
+    if let Some(warmup_steps) = training.warmup_steps {
+        trainer = trainer.with_scheduler(WarmupCosine::new(training.lr, warmup_steps, total_steps));
+    }
+    if let Some(path) = training.run_state.as_deref().or(resume) {
+        let config = config.clone();
+        trainer = trainer.with_run_state(path, move |dir| {
+            config
+                .save(dir.join("config.json"))
+                .map_err(TrainError::callback)
+        });
+    }
+    if let Some(resume) = resume {
+        trainer.resume_from(resume)?;
+    }
+
+    let history = trainer.train(&train_loader, &val_batches, &tokenizer)?;
+    for (i, (train_loss, val_loss)) in history
+        .train_losses
+        .iter()
+        .zip(&history.val_losses)
+        .enumerate()
+    {
+        println!("Eval {i}: train loss {train_loss:.3}, val loss {val_loss:.3}");
+    }
+    trainer.save_checkpoint(&training.output)?;
+    config.save(training.output.join("config.json"))?;
+    println!("Saved checkpoint to {}", training.output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::error::TestResult;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_args() -> TestResult {
+        assert_eq!(
+            parse_args(&args(
+                "train --training.lr 1e-3 --config cfg.toml --data.batch_size 4"
+            ))?,
+            Command::Train {
+                config: Some("cfg.toml".into()),
+                overrides: vec![
+                    ("data.batch_size".to_string(), "4".to_string()),
+                    ("training.lr".to_string(), "1e-3".to_string()),
+                ],
+                resume: None,
+                device: None,
+            }
+        );
+        assert_eq!(
+            parse_args(&args("train --resume run_state --device cuda:1"))?,
+            Command::Train {
+                config: None,
+                overrides: vec![],
+                resume: Some("run_state".into()),
+                device: Some(Device::Cuda(1)),
+            }
+        );
+        assert!(parse_args(&args("train --training.lr 1e-3")).is_err());
+        let mut generate = args("generate --checkpoint ckpt --temperature 0.7 --top-k 5 --prompt");
+        generate.push("Every effort moves".to_string());
+        let Command::Generate {
+            prompt,
+            device,
+            decode,
+            ..
+        } = parse_args(&generate)?
+        else {
+            panic!("Expected generate");
+        };
+        assert_eq!(prompt, "Every effort moves");
+        assert_eq!(device, Device::Cpu);
+        assert!(parse_args(&args("generate --checkpoint ckpt --prompt Hi --device tpu")).is_err());
+        let sampling = decode.sampling();
+        assert_eq!((sampling.temperature, sampling.top_k), (0.7, Some(5)));
+        assert_eq!(
+            parse_args(&args("eval --data val.txt --checkpoint ckpt"))?,
+            Command::Eval {
+                checkpoint: "ckpt".into(),
+                data: "val.txt".into(),
+                task: "perplexity".to_string(),
+                batch_size: 8,
+                limit: None,
+            }
+        );
+        assert_eq!(
+            parse_args(&args(
+                "eval --checkpoint ckpt --data hellaswag --task hellaswag --limit 100"
+            ))?,
+            Command::Eval {
+                checkpoint: "ckpt".into(),
+                data: "hellaswag".into(),
+                task: "hellaswag".to_string(),
+                batch_size: 8,
+                limit: Some(100),
+            }
+        );
+
+        let Command::Chat {
+            template,
+            max_new_tokens,
+            decode,
+            ..
+        } = parse_args(&args("chat --checkpoint ckpt --template chatml"))?
+        else {
+            panic!("Expected chat");
+        };
+        assert_eq!(
+            (template.as_str(), decode.generation(max_new_tokens)),
+            ("chatml", GenerationConfig::new(256))
+        );
+        assert_eq!(decode.seed, 123);
+
+        // `--stop`可以重复，其它选项重复时报错
+        let Command::Generate {
+            max_new_tokens,
+            decode,
+            ..
+        } = parse_args(
+            &[
+                "generate",
+                "--checkpoint",
+                "ckpt",
+                "--prompt",
+                "Hi",
+                "--stop",
+                "\\n\\n",
+                "--stop",
+                "###",
+                "--eos-token-id",
+                "50256",
+            ]
+            .map(String::from),
+        )?
+        else {
+            panic!("Expected generate");
+        };
+        assert_eq!(
+            decode.generation(max_new_tokens),
+            GenerationConfig::new(50)
+                .with_stop_sequences(vec!["\n\n".to_string(), "###".to_string()])
+                .with_eos_token_id(Some(50256))
+        );
+        assert!(parse_args(&args("generate --checkpoint a --checkpoint b --prompt Hi")).is_err());
+        assert!(chat_template(&template).is_ok() && chat_template("llama").is_err());
+
+        assert_eq!(
+            parse_args(&args("bench --workers 1,8 --iterations 5"))?,
+            Command::Bench {
+                config: None,
+                iterations: 5,
+                workers: vec![1, 8],
+                new_tokens: 32,
+            }
+        );
+
+        assert_eq!(
+            parse_args(&args("data fetch tiny-shakespeare --dir corpora"))?,
+            Command::Data(DataCommand::Fetch {
+                name: "tiny-shakespeare".to_string(),
+                dir: Some("corpora".into()),
+            })
+        );
+        assert_eq!(
+            parse_args(&args("data list"))?,
+            Command::Data(DataCommand::List)
+        );
+
+        for bad in [
+            "",
+            "serve",
+            "eval --data val.txt",
+            "tokenize --text a --text b",
+            "tokenize --text a --verbose 1",
+            "generate --checkpoint ckpt --prompt a --top-k x",
+            "export --checkpoint ckpt --output m.gguf --quantize q3",
+            "tokenize text",
+            "bench --workers 1,0",
+            "data fetch",
+            "data pull the-verdict",
+            "eval --data val.txt --checkpoint ckpt --training.lr 1",
+            "train --config cfg.toml --training.lr 1 --training.lr 2",
+            "train --config cfg.toml --training.lr",
+            "--help",
+        ] {
+            let err = parse_args(&args(bad)).unwrap_err();
+            println!("{bad}: {err}");
+        }
+
+        Ok(())
+    }
+}
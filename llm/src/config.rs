@@ -0,0 +1,439 @@
+use crate::error::{file_error, ConfigError};
+use crate::vocab::Encoding;
+use model::{Device, GptConfig, SamplingConfig};
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// 一次实验的全部配置，可以从TOML、YAML或JSON文件加载，文件中没有给出的字段使用默认值。
+// 训练结束后解析完的配置写入检查点目录的`config.json`，`generate`和`eval`从中恢复分词器
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    pub model: GptConfig,
+    pub tokenizer: TokenizerSection,
+    pub data: DataSection,
+    pub training: TrainingSection,
+    pub sampling: SamplingConfig,
+}
+
+impl Default for Config {
+    // 与书中第5章相同：124M的模型，上下文长度256，每个批次2个样本
+    fn default() -> Self {
+        Config {
+            model: GptConfig {
+                context_length: 256,
+                ..GptConfig::gpt2_small()
+            },
+            tokenizer: TokenizerSection::default(),
+            data: DataSection::default(),
+            training: TrainingSection::default(),
+            sampling: SamplingConfig::greedy(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenizerSection {
+    pub encoding: Encoding,
+}
+
+impl Default for TokenizerSection {
+    // 与加载GPT-2预训练权重时相同
+    fn default() -> Self {
+        TokenizerSection {
+            encoding: Encoding::Gpt2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DataSection {
+    // 训练文本，前`1 - val_ratio`用于训练，其余用于验证
+    pub path: PathBuf,
+    pub val_ratio: f32,
+    pub batch_size: usize,
+    // 滑动窗口的步长，`None`时与`model.context_length`相同
+    pub stride: Option<usize>,
+}
+
+impl Default for DataSection {
+    fn default() -> Self {
+        DataSection {
+            path: PathBuf::from("data/the-verdict.txt"),
+            val_ratio: 0.1,
+            batch_size: 2,
+            stride: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrainingSection {
+    pub lr: f32,
+    pub weight_decay: f32,
+    // 学习率预热的步数，`None`时使用固定的学习率
+    pub warmup_steps: Option<u64>,
+    pub num_epochs: usize,
+    pub accumulation_steps: usize,
+    pub max_grad_norm: Option<f32>,
+    pub eval_freq: usize,
+    pub eval_iter: Option<usize>,
+    pub start_context: Option<String>,
+    pub sample_tokens: usize,
+    pub sample_freq: usize,
+    pub seed: u64,
+    // 检查点目录，训练结束后写入`save_checkpoint`的所有文件和`config.json`
+    pub output: PathBuf,
+    // 运行状态目录，每`run_state_freq`次优化器更新和每个epoch结束时写入，
+    // 中断后用`llm train --resume <dir>`从epoch中间继续。`None`时不写
+    pub run_state: Option<PathBuf>,
+    pub run_state_freq: usize,
+    // 矩阵乘法使用的设备，如`cpu`、`cuda:0`，可以用`llm train --device`覆盖
+    #[serde(with = "device_name")]
+    pub device: Device,
+}
+
+impl Default for TrainingSection {
+    fn default() -> Self {
+        TrainingSection {
+            lr: 4e-4,
+            weight_decay: 0.1,
+            warmup_steps: None,
+            num_epochs: 10,
+            accumulation_steps: 1,
+            max_grad_norm: None,
+            eval_freq: 5,
+            eval_iter: Some(5),
+            start_context: Some("Every effort moves you".to_string()),
+            sample_tokens: 50,
+            sample_freq: 0,
+            seed: 123,
+            output: PathBuf::from("checkpoints"),
+            run_state: None,
+            run_state_freq: 100,
+            device: Device::Cpu,
+        }
+    }
+}
+
+// `Device`在配置文件中写为`Device::from_str`接受的字符串
+mod device_name {
+    use model::Device;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(device: &Device, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(device)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Device, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Config {
+    // 按扩展名解析：`.toml`、`.yaml`/`.yml`或`.json`，同一个表中的键重复时返回错误。
+    // `overrides`为`("training.lr", "1e-3")`形式的覆盖，值按TOML解析，不是合法的TOML值时作为字符串
+    pub fn load(
+        path: impl AsRef<Path>,
+        overrides: &[(String, String)],
+    ) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(file_error("read", path))?;
+        let invalid = |source: Box<dyn std::error::Error + Send + Sync>| ConfigError::Invalid {
+            path: path.to_path_buf(),
+            source,
+        };
+        let file = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(|err| invalid(err.into())),
+            Some("json") => serde_json::from_str(&text).map_err(|err| invalid(err.into())),
+            Some("yaml" | "yml") => serde_yaml::from_str(&text).map_err(|err| invalid(err.into())),
+            _ => return Err(ConfigError::UnknownFormat(path.to_path_buf())),
+        }
+        .map(|Document(value)| value)?;
+        // 空的YAML文件为`null`
+        let file = match file {
+            Value::Null => Value::Object(Map::new()),
+            file => file,
+        };
+
+        let mut value = serde_json::to_value(Config::default())?;
+        merge(&mut value, file);
+        for (key, raw) in overrides {
+            if value
+                .pointer(&format!("/{}", key.replace('.', "/")))
+                .is_none()
+            {
+                return Err(ConfigError::UnknownKey(key.clone()));
+            }
+            let override_value = Document::deserialize(toml::de::ValueDeserializer::new(raw))
+                .map(|Document(value)| value)
+                .unwrap_or_else(|_| Value::String(raw.clone()));
+            insert(&mut value, key, override_value)?;
+        }
+        serde_json::from_value(value).map_err(|err| invalid(err.into()))
+    }
+
+    // 写入解析完的配置，任何默认值的变化都不影响已有实验的复现
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ConfigError> {
+        let path = path.as_ref();
+        fs::write(path, serde_json::to_string_pretty(self)?).map_err(file_error("write", path))?;
+        Ok(())
+    }
+}
+
+// 表逐个字段合并，其他值直接覆盖
+fn merge(base: &mut Value, value: Value) {
+    match (base, value) {
+        (Value::Object(base), Value::Object(map)) => {
+            for (key, value) in map {
+                match base.get_mut(&key) {
+                    Some(old) => merge(old, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, value) => *base = value,
+    }
+}
+
+// 按`a.b.c`设置嵌套的值，中间的表不存在时创建
+fn insert(root: &mut Value, key: &str, value: Value) -> Result<(), ConfigError> {
+    let mut parts = key.split('.').collect::<Vec<_>>();
+    let last = parts.pop().unwrap_or_default();
+    let mut table = root;
+    for part in parts {
+        table = match table {
+            Value::Object(map) => map
+                .entry(part.to_string())
+                .or_insert_with(|| Value::Object(Map::new())),
+            _ => return Err(ConfigError::NotTable(key.to_string())),
+        };
+    }
+    match table {
+        Value::Object(map) => {
+            map.insert(last.to_string(), value);
+            Ok(())
+        }
+        _ => Err(ConfigError::NotTable(key.to_string())),
+    }
+}
+
+// 配置文件解析的结果。与直接解析为`serde_json::Value`相同，但同一个表中的键重复时返回错误，
+// 而不是保留最后一个
+struct Document(Value);
+
+impl<'de> Deserialize<'de> for Document {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DocumentVisitor).map(Document)
+    }
+}
+
+struct DocumentVisitor;
+
+impl<'de> Visitor<'de> for DocumentVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a config value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::from(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+        Ok(Value::from(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+        Ok(Value::from(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Value, E> {
+        Ok(Value::String(v))
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Value, D::Error> {
+        d.deserialize_any(self)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut items = vec![];
+        while let Some(Document(value)) = seq.next_element()? {
+            items.push(value);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut table = Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if table.contains_key(&key) {
+                return Err(de::Error::custom(format!("duplicate key `{key}`")));
+            }
+            let Document(value) = map.next_value()?;
+            table.insert(key, value);
+        }
+        Ok(Value::Object(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{error_chain, TestResult};
+    use model::PosEncoding;
+
+    #[test]
+    fn test_config() -> TestResult {
+        let text = r#"
+# 小模型
+[model]
+vocab_size = 50_257
+context_length = 64
+emb_dim = 128
+n_heads = 4
+n_layers = 2
+pos_encoding = { rope = { theta = 10000.0 } }
+sliding_window_layers = [0, 2]
+
+[data]
+path = "data/the-verdict.txt"  # 训练文本，或`llm data list`中的数据集名
+batch_size = 4
+
+[training]
+lr = 1e-3
+start_context = 'Every "effort"'
+max_grad_norm = 1
+
+[sampling]
+temperature = 0.7
+top_k = 40
+"#;
+        let path = std::env::temp_dir().join("test_config.toml");
+        fs::write(&path, text)?;
+        let overrides = [
+            ("training.num_epochs".to_string(), "3".to_string()),
+            ("training.output".to_string(), "runs/a".to_string()),
+            ("training.device".to_string(), "cuda:1".to_string()),
+        ];
+        let config = Config::load(&path, &overrides)?;
+        println!("{config:#?}");
+
+        let model = &config.model;
+        assert_eq!(
+            (model.vocab_size, model.emb_dim, model.n_layers),
+            (50257, 128, 2)
+        );
+        assert_eq!(model.pos_encoding, PosEncoding::Rope { theta: 10000.0 });
+        assert_eq!(model.sliding_window_layers, [0, 2]);
+        assert_eq!(config.data.batch_size, 4);
+        assert_eq!(config.data.val_ratio, DataSection::default().val_ratio);
+        assert_eq!(model.dropout, 0.1);
+        assert_eq!(config.training.lr, 1e-3);
+        assert_eq!(config.training.max_grad_norm, Some(1.0));
+        assert_eq!(
+            config.training.start_context.as_deref(),
+            Some("Every \"effort\"")
+        );
+        assert_eq!(config.training.num_epochs, 3);
+        assert_eq!(config.training.output, PathBuf::from("runs/a"));
+        assert_eq!(config.training.device, Device::Cuda(1));
+        assert_eq!(
+            (config.sampling.temperature, config.sampling.top_k),
+            (0.7, Some(40))
+        );
+
+        // 保存的配置可以原样加载
+        let saved = std::env::temp_dir().join("test_config.json");
+        config.save(&saved)?;
+        assert_eq!(Config::load(&saved, &[])?, config);
+
+        for bad in [
+            "a = ",
+            "a = [1, 2",
+            "a = \"x",
+            "= 1",
+            "a = 1 2",
+            "a = 1\na = 2",
+        ] {
+            let err = toml::from_str::<Document>(bad).err().unwrap();
+            println!("{bad}: {err}");
+        }
+
+        // YAML与TOML等价，任何格式中重复的键都是错误
+        let yaml = r#"
+model:
+  emb_dim: 128
+  n_heads: 4
+  pos_encoding:
+    rope:
+      theta: 10000.0
+training:
+  lr: 1.0e-3
+  start_context: ~
+"#;
+        let path = std::env::temp_dir().join("test_config.yaml");
+        fs::write(&path, yaml)?;
+        let config = Config::load(&path, &[])?;
+        assert_eq!(config.model.emb_dim, 128);
+        assert_eq!(
+            config.model.pos_encoding,
+            PosEncoding::Rope { theta: 10000.0 }
+        );
+        assert_eq!(config.training.start_context, None);
+        assert_eq!(
+            config.training.num_epochs,
+            TrainingSection::default().num_epochs
+        );
+        fs::write(&path, "")?;
+        assert_eq!(Config::load(&path, &[])?, Config::default());
+
+        for (name, text) in [
+            (
+                "test_config_dup.toml",
+                "[model]\nemb_dim = 1\nemb_dim = 2\n",
+            ),
+            (
+                "test_config_dup.yaml",
+                "model:\n  emb_dim: 1\n  emb_dim: 2\n",
+            ),
+            (
+                "test_config_dup.json",
+                r#"{"model": {"emb_dim": 1, "emb_dim": 2}}"#,
+            ),
+        ] {
+            let path = std::env::temp_dir().join(name);
+            fs::write(&path, text)?;
+            let err = Config::load(&path, &[]).unwrap_err();
+            println!("{name}: {}", error_chain(&err));
+            assert!(error_chain(&err).contains("emb_dim"));
+        }
+        assert!(Config::load(&path, &[("model.emb_dim".to_string(), "x".to_string())]).is_err());
+        assert!(Config::load(&path, &[("training.lrr".to_string(), "1".to_string())]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+// 检查评估数据与训练语料之间的n-gram重叠，输入为分词后的token id
+pub struct ContaminationChecker {
+    n: usize,
+    // 只保存n-gram的哈希，避免大语料占用过多内存
+    train_ngrams: HashSet<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContaminationReport {
+    // 每个评估样本中出现在训练语料里的n-gram比例
+    pub overlaps: Vec<f64>,
+    pub mean_overlap: f64,
+    // 重叠比例不低于阈值的样本下标
+    pub contaminated: Vec<usize>,
+}
+
+impl ContaminationReport {
+    pub fn contaminated_ratio(&self) -> f64 {
+        if self.overlaps.is_empty() {
+            0.0
+        } else {
+            self.contaminated.len() as f64 / self.overlaps.len() as f64
+        }
+    }
+}
+
+fn ngram_hash(ngram: &[usize]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    ngram.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl ContaminationChecker {
+    pub fn new(n: usize) -> Self {
+        assert!(n > 0);
+        ContaminationChecker {
+            n,
+            train_ngrams: HashSet::new(),
+        }
+    }
+
+    pub fn add_train(&mut self, token_ids: &[usize]) {
+        for ngram in token_ids.windows(self.n) {
+            self.train_ngrams.insert(ngram_hash(ngram));
+        }
+    }
+
+    // 不足n个token的样本重叠比例为0
+    pub fn overlap(&self, token_ids: &[usize]) -> f64 {
+        let total = token_ids.windows(self.n).count();
+        if total == 0 {
+            return 0.0;
+        }
+
+        let hits = token_ids
+            .windows(self.n)
+            .filter(|ngram| self.train_ngrams.contains(&ngram_hash(ngram)))
+            .count();
+        hits as f64 / total as f64
+    }
+
+    pub fn report(&self, items: &[Vec<usize>], threshold: f64) -> ContaminationReport {
+        let overlaps: Vec<f64> = items.iter().map(|item| self.overlap(item)).collect();
+        let contaminated = overlaps
+            .iter()
+            .enumerate()
+            .filter(|(_, overlap)| **overlap >= threshold)
+            .map(|(i, _)| i)
+            .collect();
+        let mean_overlap = if overlaps.is_empty() {
+            0.0
+        } else {
+            overlaps.iter().sum::<f64>() / overlaps.len() as f64
+        };
+
+        ContaminationReport {
+            overlaps,
+            mean_overlap,
+            contaminated,
+        }
+    }
+
+    // 去掉重叠比例不低于阈值的评估样本
+    pub fn filter<T>(&self, items: Vec<(T, Vec<usize>)>, threshold: f64) -> Vec<(T, Vec<usize>)> {
+        items
+            .into_iter()
+            .filter(|(_, token_ids)| self.overlap(token_ids) < threshold)
+            .collect()
+    }
+}
+
+// 不重复的n-gram占全部n-gram的比例（distinct-n），衡量词汇多样性
+pub fn distinct_n(token_ids: &[usize], n: usize) -> f64 {
+    assert!(n > 0);
+
+    let ngrams: Vec<&[usize]> = token_ids.windows(n).collect();
+    if ngrams.is_empty() {
+        return 0.0;
+    }
+
+    let unique: HashSet<&[usize]> = ngrams.iter().copied().collect();
+    unique.len() as f64 / ngrams.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contamination() {
+        let mut checker = ContaminationChecker::new(3);
+        checker.add_train(&[1, 2, 3, 4, 5, 6, 7]);
+
+        let items = vec![
+            vec![2, 3, 4, 5],
+            vec![9, 8, 7, 6],
+            vec![5, 6, 7, 8],
+            vec![1],
+        ];
+        let report = checker.report(&items, 0.5);
+        println!("{:?}", report);
+
+        assert_eq!(report.overlaps, [1.0, 0.0, 0.5, 0.0]);
+        assert_eq!(report.contaminated, [0, 2]);
+        assert_eq!(report.contaminated_ratio(), 0.5);
+
+        let items = items.into_iter().enumerate().collect();
+        let clean: Vec<usize> = checker
+            .filter(items, 0.5)
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(clean, [1, 3]);
+
+        assert_eq!(distinct_n(&[1, 2, 1, 2, 1], 2), 0.5);
+        assert_eq!(distinct_n(&[1, 2, 3], 1), 1.0);
+    }
+}
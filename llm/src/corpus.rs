@@ -0,0 +1,314 @@
+use crate::error::{file_error, CorpusError};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// 可以按名字下载的数据集，新增数据集只需在`CORPORA`中加一行
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corpus {
+    pub name: &'static str,
+    pub url: &'static str,
+    // 缓存目录中的文件名
+    pub file_name: &'static str,
+    // 下载的是zip时，要取出的文件
+    pub zip_entry: Option<&'static str>,
+    // 文件内容（zip为取出的文件）的SHA-256。`None`表示还没有固定，`fetch`下载后返回错误并给出实际的值
+    pub sha256: Option<&'static str>,
+}
+
+pub const CORPORA: &[Corpus] = &[
+    Corpus {
+        name: "the-verdict",
+        url: "https://raw.githubusercontent.com/rasbt/LLMs-from-scratch/main/ch02/01_main-chapter-code/the-verdict.txt",
+        file_name: "the-verdict.txt",
+        zip_entry: None,
+        sha256: Some("b41e41a68f0398a3154ae69e2e4c0e2694e17fe0d66730536837f1b01935b31f"),
+    },
+    Corpus {
+        name: "tiny-shakespeare",
+        url: "https://raw.githubusercontent.com/karpathy/char-rnn/master/data/tinyshakespeare/input.txt",
+        file_name: "tiny-shakespeare.txt",
+        zip_entry: None,
+        sha256: None,
+    },
+    Corpus {
+        name: "sms-spam",
+        url: "https://archive.ics.uci.edu/static/public/228/sms+spam+collection.zip",
+        file_name: "sms-spam.tsv",
+        zip_entry: Some("SMSSpamCollection"),
+        sha256: None,
+    },
+    Corpus {
+        name: "instruction-data",
+        url: "https://raw.githubusercontent.com/rasbt/LLMs-from-scratch/main/ch07/01_main-chapter-code/instruction-data.json",
+        file_name: "instruction-data.json",
+        zip_entry: None,
+        sha256: None,
+    },
+    Corpus {
+        name: "hellaswag",
+        url: "https://raw.githubusercontent.com/rowanz/hellaswag/master/data/hellaswag_val.jsonl",
+        file_name: "hellaswag_val.jsonl",
+        zip_entry: None,
+        sha256: None,
+    },
+    Corpus {
+        name: "lambada",
+        url: "https://openaipublic.blob.core.windows.net/gpt-2/data/lambada_test.jsonl",
+        file_name: "lambada_test.jsonl",
+        zip_entry: None,
+        sha256: None,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Corpus> {
+    CORPORA.iter().find(|corpus| corpus.name == name)
+}
+
+// 默认的缓存目录，可用`LLM_DATA_DIR`环境变量修改
+pub fn data_dir() -> PathBuf {
+    std::env::var_os("LLM_DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("data"))
+}
+
+// 已存在的路径原样返回，否则把`path`当作数据集名下载到`data_dir()`
+pub fn resolve(path: &Path) -> Result<PathBuf, CorpusError> {
+    if path.exists() {
+        return Ok(path.to_path_buf());
+    }
+    match path.to_str().and_then(find) {
+        Some(corpus) => fetch(corpus, data_dir()),
+        None => Err(CorpusError::Unknown {
+            path: path.to_path_buf(),
+            known: names(),
+        }),
+    }
+}
+
+fn names() -> String {
+    CORPORA
+        .iter()
+        .map(|corpus| corpus.name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// 下载到`dir`并校验。已缓存且校验通过时不再下载，校验失败的缓存文件会重新下载
+pub fn fetch(corpus: &Corpus, dir: impl AsRef<Path>) -> Result<PathBuf, CorpusError> {
+    let dir = dir.as_ref();
+    let path = dir.join(corpus.file_name);
+    if path.exists() {
+        match verify(corpus, &path) {
+            Ok(_) => return Ok(path),
+            Err(err) => tracing::warn!("{err}, downloading again"),
+        }
+    }
+
+    fs::create_dir_all(dir).map_err(file_error("create", dir))?;
+    // 先下载到临时文件，校验通过后再重命名，中断的下载不会留下损坏的缓存
+    let part = dir.join(format!("{}.part", corpus.file_name));
+    let result = download(corpus, &part).and_then(|_| verify(corpus, &part));
+    if let Err(err) = result {
+        let _ = fs::remove_file(&part);
+        return Err(err);
+    }
+    fs::rename(&part, &path).map_err(file_error("rename", &part))?;
+    Ok(path)
+}
+
+// 工作区没有HTTP客户端依赖，需要系统中有`curl`和`unzip`（只有zip数据集用到）
+fn download(corpus: &Corpus, path: &Path) -> Result<(), CorpusError> {
+    let archive = path.with_extension("zip");
+    let target = if corpus.zip_entry.is_some() {
+        &archive
+    } else {
+        path
+    };
+    let status = Command::new("curl")
+        .args([
+            "--fail",
+            "--location",
+            "--silent",
+            "--show-error",
+            "--output",
+        ])
+        .arg(target)
+        .arg(corpus.url)
+        .status()
+        .map_err(|source| CorpusError::Spawn {
+            program: "curl",
+            source,
+        })?;
+    if !status.success() {
+        return Err(CorpusError::Download {
+            name: corpus.name,
+            url: corpus.url,
+        });
+    }
+
+    if let Some(entry) = corpus.zip_entry {
+        let output = Command::new("unzip")
+            .arg("-p")
+            .arg(&archive)
+            .arg(entry)
+            .output()
+            .map_err(|source| CorpusError::Spawn {
+                program: "unzip",
+                source,
+            });
+        let _ = fs::remove_file(&archive);
+        let output = output?;
+        if !output.status.success() || output.stdout.is_empty() {
+            return Err(CorpusError::Extract {
+                entry,
+                url: corpus.url,
+            });
+        }
+        fs::write(path, output.stdout).map_err(file_error("write", path))?;
+    }
+    Ok(())
+}
+
+// 没有固定SHA-256的数据集也返回错误
+fn verify(corpus: &Corpus, path: &Path) -> Result<(), CorpusError> {
+    let bytes = fs::read(path).map_err(file_error("read", path))?;
+    let digest = sha256_hex(&bytes);
+    match corpus.sha256 {
+        Some(expected) if digest == expected => Ok(()),
+        Some(expected) => Err(CorpusError::Checksum {
+            path: path.to_path_buf(),
+            expected,
+            actual: digest,
+        }),
+        None => Err(CorpusError::Unpinned {
+            name: corpus.name,
+            actual: digest,
+        }),
+    }
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// FIPS 180-4，数据集只有几MB，不需要流式计算
+pub fn sha256_hex(data: &[u8]) -> String {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    h.iter().map(|v| format!("{v:08x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TestResult;
+
+    #[test]
+    fn test_corpus() -> TestResult {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_hex(&[b'a'; 1000]),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+
+        // 仓库中自带的文件校验通过，不需要下载
+        let data = concat!(env!("CARGO_MANIFEST_DIR"), "/../data");
+        let verdict = find("the-verdict").unwrap();
+        assert_eq!(
+            fetch(verdict, data)?,
+            Path::new(data).join("the-verdict.txt")
+        );
+        assert_eq!(resolve(Path::new(data))?, Path::new(data));
+        assert!(resolve(Path::new("no-such-dataset")).is_err());
+
+        // 用`file://`地址代替网络下载
+        let dir = std::env::temp_dir().join("test_corpus");
+        let _ = fs::remove_dir_all(&dir);
+        let source = dir.join("source.txt");
+        fs::create_dir_all(&dir)?;
+        fs::write(&source, "abc")?;
+        let url = format!("file://{}", source.display()).leak();
+        let mut corpus = Corpus {
+            name: "test",
+            url,
+            file_name: "test.txt",
+            zip_entry: None,
+            sha256: Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+        };
+        let path = fetch(&corpus, &dir)?;
+        assert_eq!(fs::read_to_string(&path)?, "abc");
+
+        corpus.sha256 = Some("0000");
+        let err = fetch(&corpus, &dir).unwrap_err();
+        println!("{err}");
+        assert!(err.to_string().contains("Checksum mismatch"));
+        assert!(!dir.join("test.txt.part").exists());
+
+        // 没有固定SHA-256时不保留下载的文件
+        corpus.sha256 = None;
+        let err = fetch(&corpus, &dir).unwrap_err();
+        println!("{err}");
+        assert!(err.to_string().contains(&sha256_hex(b"abc")));
+        assert!(!dir.join("test.txt.part").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use crate::error::{file_error, DatasetError};
+use crate::tokenizer::Tokenizer;
+use crate::vocab::Vocabulary;
+use data_loader::{DataLoaderError, Dataset, GPTDataset, IterableDataset, TrainData};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+// 边读边分词的语料数据集。
+// 构造时只扫描一遍文件记录每个分块的字节范围，分词和滑动窗口在`DataLoader`的工作线程中完成。
+// 分块在换行处切分，滑动窗口不会跨越分块边界。
+#[derive(Debug, Clone)]
+pub struct StreamingTextDataset<T: Tokenizer = Vocabulary> {
+    path: PathBuf,
+    chunks: Vec<(u64, u64)>,
+    vocab: T,
+    max_length: usize,
+    stride: usize,
+}
+
+impl<T: Tokenizer> StreamingTextDataset<T> {
+    pub fn new(
+        path: impl AsRef<Path>,
+        vocab: T,
+        chunk_size: usize,
+        max_length: usize,
+        stride: usize,
+    ) -> Result<Self, DatasetError> {
+        let path = path.as_ref().to_path_buf();
+        let chunks = Self::scan_chunks(&path, chunk_size).map_err(file_error("read", &path))?;
+
+        Ok(StreamingTextDataset {
+            path,
+            chunks,
+            vocab,
+            max_length,
+            stride,
+        })
+    }
+
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    fn scan_chunks(path: &Path, chunk_size: usize) -> io::Result<Vec<(u64, u64)>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut chunks = vec![];
+        let mut line = vec![];
+        let (mut start, mut len) = (0u64, 0u64);
+
+        loop {
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line)? as u64;
+            if n == 0 {
+                break;
+            }
+
+            len += n;
+            if len >= chunk_size as u64 {
+                chunks.push((start, len));
+                start += len;
+                len = 0;
+            }
+        }
+
+        if len > 0 {
+            chunks.push((start, len));
+        }
+
+        Ok(chunks)
+    }
+
+    fn read_chunk(&self, chunk: usize) -> io::Result<String> {
+        let (start, len) = self.chunks[chunk];
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+
+        let mut buf = Vec::with_capacity(len as usize);
+        file.take(len).read_to_end(&mut buf)?;
+        String::from_utf8(buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn chunk_samples(&self, chunk: usize) -> Result<Vec<TrainData<usize>>, DatasetError> {
+        let text = self
+            .read_chunk(chunk)
+            .map_err(|source| DatasetError::Chunk {
+                chunk,
+                path: self.path.clone(),
+                source,
+            })?;
+        let token_ids = self.vocab.encode(&text)?;
+        let windows = GPTDataset::new(token_ids, self.max_length, self.stride);
+
+        Ok((0..windows.len()).map(|i| windows.get(i)).collect())
+    }
+}
+
+impl<T: Tokenizer> IterableDataset for StreamingTextDataset<T> {
+    type Item = TrainData<usize>;
+
+    fn num_shards(&self) -> usize {
+        self.chunks.len()
+    }
+
+    fn iter_shard(&self, shard: usize) -> Box<dyn Iterator<Item = Self::Item> + '_> {
+        Box::new(self.try_iter_shard(shard).map(|item| item.unwrap()))
+    }
+
+    // 读取或分词失败时`DataLoader`返回`DataLoaderError::Sample`
+    fn try_iter_shard(
+        &self,
+        shard: usize,
+    ) -> Box<dyn Iterator<Item = Result<Self::Item, DataLoaderError>> + '_> {
+        match self.chunk_samples(shard) {
+            Ok(samples) => Box::new(samples.into_iter().map(Ok)),
+            Err(e) => Box::new(std::iter::once(Err(e.into()))),
+        }
+    }
+}
+
+// 保存原始文本，在`get`时才分词，配合`DataLoader`即可在工作线程中并行分词。
+// 返回每条文本的token id，可再用`map`转换为训练样本。
+#[derive(Debug, Clone)]
+pub struct TextDataset<T: Tokenizer = Vocabulary> {
+    texts: Vec<String>,
+    vocab: T,
+}
+
+impl<T: Tokenizer> TextDataset<T> {
+    pub fn new(texts: Vec<String>, vocab: T) -> Self {
+        TextDataset { texts, vocab }
+    }
+}
+
+impl<T: Tokenizer> Dataset for TextDataset<T> {
+    type Item = Vec<usize>;
+
+    fn len(&self) -> usize {
+        self.texts.len()
+    }
+
+    fn get(&self, index: usize) -> Vec<usize> {
+        self.try_get(index).unwrap()
+    }
+
+    fn try_get(&self, index: usize) -> Result<Vec<usize>, DataLoaderError> {
+        self.vocab
+            .encode(&self.texts[index])
+            .map_err(|source| DatasetError::Encode { index, source }.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vocab::SentenceType;
+    use data_loader::{DataLoader, PadCollator};
+
+    #[test]
+    fn test_streaming_text_dataset() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/the-verdict.txt");
+        let vocab = Vocabulary::new("", SentenceType::English).unwrap();
+        let dataset = StreamingTextDataset::new(path, vocab, 4096, 32, 32).unwrap();
+        println!("chunks: {}", dataset.num_chunks());
+        assert!(dataset.num_chunks() > 1);
+
+        let loader = DataLoader::from_iterable(dataset, 8, true, 4, false);
+
+        let mut samples = 0;
+        for batch in loader.iter() {
+            let batch = batch.unwrap();
+            for item in batch.iter() {
+                assert_eq!(item.feature.len(), 32);
+                assert_eq!(item.feature[1..], item.label[..31]);
+            }
+            samples += batch.len();
+        }
+
+        println!("samples: {samples}");
+        assert!(samples > 0);
+
+        // 文件在扫描后被改成无效的UTF-8，错误交给迭代器而不是panic
+        let path = std::env::temp_dir().join("test_streaming_text_dataset.txt");
+        std::fs::write(&path, "some text\nmore text\n").unwrap();
+        let vocab = Vocabulary::new("", SentenceType::English).unwrap();
+        let dataset = StreamingTextDataset::new(&path, vocab, 4, 2, 2).unwrap();
+        std::fs::write(&path, [0xff; 20]).unwrap();
+        let loader = DataLoader::from_iterable(dataset, 2, false, 1, false);
+        let err = loader.iter().find_map(Result::err).unwrap();
+        println!("{err}");
+        assert!(matches!(err, DataLoaderError::Sample(_)));
+        assert!(err.to_string().contains("Failed to load chunk"));
+    }
+
+    #[test]
+    fn test_text_dataset() {
+        let texts = ["这是一个例子。", "这是另一个更长的例子。", "例子"];
+        let vocab = Vocabulary::new(&texts.concat(), SentenceType::Chinese).unwrap();
+        let texts: Vec<String> = texts.iter().map(|s| s.to_string()).collect();
+
+        let expected: Vec<Vec<usize>> = texts
+            .iter()
+            .map(|text| vocab.encode(text).unwrap())
+            .collect();
+
+        let dataset = TextDataset::new(texts, vocab).map(|token_ids| TrainData {
+            feature: token_ids[..token_ids.len() - 1].to_vec(),
+            label: token_ids[1..].to_vec(),
+        });
+
+        let loader = DataLoader::builder()
+            .batch_size(3)
+            .num_workers(2)
+            .build_with(dataset, PadCollator::new(1));
+
+        let batch = loader.iter().next().unwrap().unwrap();
+        println!("{:?}", batch);
+
+        for (i, token_ids) in expected.iter().enumerate() {
+            let len = token_ids.len() - 1;
+            assert_eq!(batch.features[i][..len], token_ids[..len]);
+            assert_eq!(
+                batch.attention_mask[i].iter().filter(|&&m| m == 1).count(),
+                len
+            );
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use model::{GPTModel, Result};
+use std::path::{Path, PathBuf};
+
+// 验证loss连续`patience`次评估没有比最好的结果低`min_delta`以上时停止训练，
+// 同时保留最好的模型：设置了`checkpoint`时保存为该safetensors文件，否则在内存中保留一份拷贝
+#[derive(Debug, Clone)]
+pub struct EarlyStopping {
+    patience: usize,
+    min_delta: f32,
+    checkpoint: Option<PathBuf>,
+    best_loss: f32,
+    best_step: Option<u64>,
+    // 上次改进以来的评估次数
+    wait: usize,
+    best_model: Option<GPTModel>,
+}
+
+impl EarlyStopping {
+    pub fn new(patience: usize, min_delta: f32) -> Self {
+        assert!(min_delta >= 0.0, "min_delta must not be negative");
+        EarlyStopping {
+            patience,
+            min_delta,
+            checkpoint: None,
+            best_loss: f32::INFINITY,
+            best_step: None,
+            wait: 0,
+            best_model: None,
+        }
+    }
+
+    pub fn with_checkpoint(mut self, path: impl AsRef<Path>) -> Self {
+        self.checkpoint = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    // 记录第`step`次更新后的验证loss，有改进时保存`model`并返回true
+    pub fn update(&mut self, val_loss: f32, step: u64, model: &GPTModel) -> Result<bool> {
+        if val_loss >= self.best_loss - self.min_delta {
+            self.wait += 1;
+            return Ok(false);
+        }
+
+        self.best_loss = val_loss;
+        self.best_step = Some(step);
+        self.wait = 0;
+        match self.checkpoint.as_ref() {
+            Some(path) => model.save(path)?,
+            None => self.best_model = Some(model.clone()),
+        }
+        Ok(true)
+    }
+
+    pub fn should_stop(&self) -> bool {
+        self.best_step.is_some() && self.wait >= self.patience
+    }
+
+    pub fn best_loss(&self) -> f32 {
+        self.best_loss
+    }
+
+    pub fn best_step(&self) -> Option<u64> {
+        self.best_step
+    }
+
+    // 设置了`checkpoint`时为`None`，用`GPTModel::load`读取
+    pub fn best_model(&self) -> Option<&GPTModel> {
+        self.best_model.as_ref()
+    }
+
+    pub fn checkpoint(&self) -> Option<&Path> {
+        self.checkpoint.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::GptConfig;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_early_stopping() -> Result<()> {
+        let config = GptConfig {
+            vocab_size: 8,
+            context_length: 4,
+            emb_dim: 8,
+            n_heads: 2,
+            n_layers: 1,
+            ..GptConfig::gpt2_small()
+        };
+        let models = (0..6)
+            .map(|seed| GPTModel::new(&config, &mut StdRng::seed_from_u64(seed)))
+            .collect::<Result<Vec<_>>>()?;
+
+        // 第3次评估后的改进小于`min_delta`，不算改进
+        let mut early_stopping = EarlyStopping::new(2, 0.05);
+        let losses = [3.0, 2.5, 2.48, 2.6, 2.2, 2.3];
+        let mut improved = vec![];
+        for (step, (loss, model)) in losses.iter().zip(&models).enumerate() {
+            improved.push(early_stopping.update(*loss, step as u64, model)?);
+            if early_stopping.should_stop() {
+                break;
+            }
+        }
+        println!("{improved:?}");
+        assert_eq!(improved, [true, true, false, false]);
+        assert!(early_stopping.should_stop());
+        assert_eq!(early_stopping.best_step(), Some(1));
+        assert_eq!(early_stopping.best_loss(), 2.5);
+        assert_eq!(
+            early_stopping.best_model().unwrap().named_params(),
+            models[1].named_params()
+        );
+
+        let path = std::env::temp_dir().join("test_early_stopping.safetensors");
+        let mut early_stopping = EarlyStopping::new(1, 0.0).with_checkpoint(&path);
+        assert!(!early_stopping.should_stop());
+        early_stopping.update(2.0, 10, &models[4])?;
+        early_stopping.update(2.0, 20, &models[5])?;
+        assert!(early_stopping.should_stop());
+        assert!(early_stopping.best_model().is_none());
+        assert_eq!(
+            GPTModel::load(early_stopping.checkpoint().unwrap())?.named_params(),
+            models[4].named_params()
+        );
+        Ok(())
+    }
+}
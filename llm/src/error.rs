@@ -0,0 +1,477 @@
+use data_loader::DataLoaderError;
+use model::ModelError;
+use std::error::Error as StdError;
+use std::io;
+use std::path::PathBuf;
+use std::string::FromUtf8Error;
+use thiserror::Error;
+
+// 分词器的公开接口返回该错误。其余模块各有自己的错误类型，只有`main.rs`和`bin/prepare.rs`使用anyhow
+#[derive(Debug, Error)]
+pub enum TokenizerError {
+    #[error("Failed to read {}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("Failed to write {}", path.display())]
+    Write { path: PathBuf, source: io::Error },
+
+    // 文件能读取但内容无效，`kind`如`vocabulary file`、`BPE file`
+    #[error("Invalid {kind} {}", path.display())]
+    InvalidFile {
+        kind: &'static str,
+        path: PathBuf,
+        source: Box<TokenizerError>,
+    },
+
+    #[error("Unknown token id {0}")]
+    UnknownId(usize),
+
+    #[error("{0:?} not in vocabulary")]
+    UnknownToken(String),
+
+    #[error("Decoded bytes are not valid UTF-8")]
+    InvalidUtf8(#[from] FromUtf8Error),
+
+    // 当前的分词器或编译选项不支持该操作
+    #[error("{0}")]
+    Unsupported(String),
+
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error("{0}")]
+    Tiktoken(String),
+
+    // `fancy_regex::Error`较大，装箱避免所有`Result`都变大
+    #[error(transparent)]
+    Regex(Box<fancy_regex::Error>),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T, E = TokenizerError> = std::result::Result<T, E>;
+
+// 读写、创建、改名或fsync文件失败，`action`如`read`、`rename`
+#[derive(Debug, Error)]
+#[error("Failed to {action} {}", path.display())]
+pub struct FileError {
+    pub action: &'static str,
+    pub path: PathBuf,
+    pub source: io::Error,
+}
+
+// `Config::load`和`Config::save`
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Unknown config format {}", .0.display())]
+    UnknownFormat(PathBuf),
+
+    // `source`为toml、serde_yaml或serde_json的错误
+    #[error("Invalid config {}", path.display())]
+    Invalid {
+        path: PathBuf,
+        source: Box<dyn StdError + Send + Sync>,
+    },
+
+    #[error("Unknown config key `{0}`")]
+    UnknownKey(String),
+
+    #[error("`{0}` is not a table")]
+    NotTable(String),
+
+    #[error(transparent)]
+    File(#[from] FileError),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+// 数据集的下载和校验
+#[derive(Debug, Error)]
+pub enum CorpusError {
+    #[error("{} does not exist and is not a known dataset ({known})", path.display())]
+    Unknown { path: PathBuf, known: String },
+
+    #[error("Failed to run {program}")]
+    Spawn {
+        program: &'static str,
+        source: io::Error,
+    },
+
+    #[error("Failed to download {name} from {url}")]
+    Download {
+        name: &'static str,
+        url: &'static str,
+    },
+
+    #[error("Failed to extract {entry} from {url}")]
+    Extract {
+        entry: &'static str,
+        url: &'static str,
+    },
+
+    #[error("Checksum mismatch for {}: expected {expected}, got {actual}", path.display())]
+    Checksum {
+        path: PathBuf,
+        expected: &'static str,
+        actual: String,
+    },
+
+    #[error("{name} has no pinned sha256 in CORPORA, the downloaded file has {actual}")]
+    Unpinned { name: &'static str, actual: String },
+
+    #[error(transparent)]
+    File(#[from] FileError),
+}
+
+// `TextDataset`等在工作线程中加载样本，经`DataLoaderError::Sample`返回给迭代器
+#[derive(Debug, Error)]
+pub enum DatasetError {
+    #[error("Failed to load chunk {chunk} of {}", path.display())]
+    Chunk {
+        chunk: usize,
+        path: PathBuf,
+        source: io::Error,
+    },
+
+    #[error("Failed to encode text {index}")]
+    Encode {
+        index: usize,
+        source: TokenizerError,
+    },
+
+    #[error(transparent)]
+    Tokenizer(#[from] TokenizerError),
+
+    #[error(transparent)]
+    File(#[from] FileError),
+}
+
+impl From<DatasetError> for DataLoaderError {
+    fn from(err: DatasetError) -> Self {
+        DataLoaderError::sample(err)
+    }
+}
+
+impl From<TokenizerError> for DataLoaderError {
+    fn from(err: TokenizerError) -> Self {
+        DataLoaderError::sample(err)
+    }
+}
+
+// `MetricsLogger`和TensorBoard的事件文件
+#[derive(Debug, Error)]
+pub enum MetricsError {
+    #[error(transparent)]
+    File(#[from] FileError),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+// `DataParallel::train_step`
+#[derive(Debug, Error)]
+pub enum ParallelError {
+    #[error("Expected {expected} shards, got {actual}")]
+    Shards { expected: usize, actual: usize },
+
+    #[error("No tokens to train on")]
+    NoTokens,
+
+    #[error("Data parallel worker {rank} panicked: {message}")]
+    WorkerPanicked { rank: usize, message: String },
+
+    #[error(transparent)]
+    Model(#[from] ModelError),
+}
+
+// `Trainer`、`train_step`和loss的计算
+#[derive(Debug, Error)]
+pub enum TrainError {
+    #[error("No tokens to train on")]
+    NoTokens,
+
+    #[error("No tokens to evaluate")]
+    NoEvalTokens,
+
+    #[error("Validation set is empty")]
+    EmptyValidation,
+
+    #[error("Train loader returned no batches in epoch {0}")]
+    NoBatches(usize),
+
+    // `kind`如`run state`、`trainer state`
+    #[error("Invalid {kind} {}", path.display())]
+    InvalidState {
+        kind: &'static str,
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    // `with_run_state`和`save_run_state`的`on_save`返回的错误
+    #[error(transparent)]
+    Callback(Box<dyn StdError + Send + Sync>),
+
+    #[error(transparent)]
+    Model(#[from] ModelError),
+
+    #[error(transparent)]
+    DataLoader(#[from] DataLoaderError),
+
+    #[error(transparent)]
+    Tokenizer(#[from] TokenizerError),
+
+    #[error(transparent)]
+    Parallel(#[from] ParallelError),
+
+    #[error(transparent)]
+    Metrics(#[from] MetricsError),
+
+    #[error(transparent)]
+    File(#[from] FileError),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl TrainError {
+    pub fn callback(err: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        TrainError::Callback(err.into())
+    }
+}
+
+// 指令数据的加载和微调
+#[derive(Debug, Error)]
+pub enum InstructionError {
+    // `line`为JSON Lines文件中的行号
+    #[error("Invalid instruction data {}{}", path.display(), line.map(|line| format!(":{line}")).unwrap_or_default())]
+    Invalid {
+        path: PathBuf,
+        line: Option<usize>,
+        source: serde_json::Error,
+    },
+
+    #[error("Tokenizer has no end-of-text token, set FinetuneConfig::pad_id")]
+    NoPadToken,
+
+    #[error("Instruction fine-tuning requires ignore_index = IGNORE_INDEX")]
+    IgnoreIndex,
+
+    #[error(transparent)]
+    Train(#[from] TrainError),
+
+    #[error(transparent)]
+    Model(#[from] ModelError),
+
+    #[error(transparent)]
+    DataLoader(#[from] DataLoaderError),
+
+    #[error(transparent)]
+    Tokenizer(#[from] TokenizerError),
+
+    #[error(transparent)]
+    File(#[from] FileError),
+}
+
+// HellaSwag、LAMBADA等基准的加载和评估
+#[derive(Debug, Error)]
+pub enum EvalError {
+    #[error("Invalid example {}:{line}", path.display())]
+    InvalidExample {
+        path: PathBuf,
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    #[error("LAMBADA example without a context in {}", .0.display())]
+    MissingContext(PathBuf),
+
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error(transparent)]
+    Model(#[from] ModelError),
+
+    #[error(transparent)]
+    Tokenizer(#[from] TokenizerError),
+
+    #[error(transparent)]
+    File(#[from] FileError),
+}
+
+// 文本生成和对话
+#[derive(Debug, Error)]
+pub enum GenerateError {
+    #[error(transparent)]
+    Model(#[from] ModelError),
+
+    #[error(transparent)]
+    Tokenizer(#[from] TokenizerError),
+
+    #[error(transparent)]
+    File(#[from] FileError),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum BenchError {
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error(transparent)]
+    Model(#[from] ModelError),
+
+    #[error(transparent)]
+    DataLoader(#[from] DataLoaderError),
+
+    #[error(transparent)]
+    Tokenizer(#[from] TokenizerError),
+}
+
+// 命令行的各个子命令，汇总各模块的错误
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("Missing option `--config`")]
+    MissingConfig,
+
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error(transparent)]
+    Corpus(#[from] CorpusError),
+
+    #[error(transparent)]
+    Train(#[from] TrainError),
+
+    #[error(transparent)]
+    Eval(#[from] EvalError),
+
+    #[error(transparent)]
+    Generate(#[from] GenerateError),
+
+    #[error(transparent)]
+    Bench(#[from] BenchError),
+
+    #[error(transparent)]
+    Metrics(#[from] MetricsError),
+
+    #[error(transparent)]
+    Model(#[from] ModelError),
+
+    #[error(transparent)]
+    DataLoader(#[from] DataLoaderError),
+
+    #[error(transparent)]
+    Tokenizer(#[from] TokenizerError),
+
+    #[error(transparent)]
+    File(#[from] FileError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<fancy_regex::Error> for TokenizerError {
+    fn from(err: fancy_regex::Error) -> Self {
+        TokenizerError::Regex(Box::new(err))
+    }
+}
+
+// 格式化为`TokenizerError::Invalid`，代替anyhow的`anyhow!`
+macro_rules! invalid {
+    ($($arg:tt)+) => {
+        $crate::error::TokenizerError::Invalid(format!($($arg)+))
+    };
+}
+pub(crate) use invalid;
+
+macro_rules! unsupported {
+    ($($arg:tt)+) => {
+        $crate::error::TokenizerError::Unsupported(format!($($arg)+))
+    };
+}
+pub(crate) use unsupported;
+
+// 用于`map_err`，错误信息带上文件路径。`FileError`可以转换为各模块的错误
+pub(crate) fn file_error(
+    action: &'static str,
+    path: impl Into<PathBuf>,
+) -> impl FnOnce(io::Error) -> FileError {
+    let path = path.into();
+    move |source| FileError {
+        action,
+        path,
+        source,
+    }
+}
+
+pub(crate) fn read_error(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> TokenizerError {
+    let path = path.into();
+    move |source| TokenizerError::Read { path, source }
+}
+
+pub(crate) fn write_error(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> TokenizerError {
+    let path = path.into();
+    move |source| TokenizerError::Write { path, source }
+}
+
+pub(crate) fn invalid_file<E: Into<TokenizerError>>(
+    kind: &'static str,
+    path: impl Into<PathBuf>,
+) -> impl FnOnce(E) -> TokenizerError {
+    let path = path.into();
+    move |source| TokenizerError::InvalidFile {
+        kind,
+        path,
+        source: Box::new(source.into()),
+    }
+}
+
+// 测试中混用各模块的错误
+#[cfg(test)]
+pub(crate) type TestResult = std::result::Result<(), Box<dyn StdError>>;
+
+// 与anyhow的`{:#}`相同，把`source`链拼在一起
+pub(crate) fn error_chain(err: &dyn StdError) -> String {
+    std::iter::successors(Some(err), |&err| err.source())
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenizer_error() {
+        let err = io::Error::new(io::ErrorKind::NotFound, "missing");
+        let err = read_error("vocab.json")(err);
+        println!("{err}");
+        assert_eq!(err.to_string(), "Failed to read vocab.json");
+        assert!(std::error::Error::source(&err).is_some());
+
+        let err =
+            invalid_file::<TokenizerError>("BPE file", "bpe.json")(invalid!("Invalid merge {}", 3));
+        assert_eq!(err.to_string(), "Invalid BPE file bpe.json");
+        assert_eq!(
+            std::error::Error::source(&err).unwrap().to_string(),
+            "Invalid merge 3"
+        );
+    }
+}
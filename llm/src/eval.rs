@@ -0,0 +1,334 @@
+use crate::error::{file_error, EvalError};
+use crate::tokenizer::Tokenizer;
+use model::{perplexity, GPTModel};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+type Result<T, E = EvalError> = std::result::Result<T, E>;
+
+// 多选题，如HellaSwag：`choices`中哪一个最可能接在`context`后面
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultipleChoice {
+    pub context: String,
+    // 直接拼接在`context`后面，通常以空格开头
+    pub choices: Vec<String>,
+    pub label: usize,
+}
+
+// 预测最后一个词，如LAMBADA：`target`的每个token都是概率最大的token才算正确
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cloze {
+    pub context: String,
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ContinuationScore {
+    // 续写部分每个token的对数概率之和
+    pub log_likelihood: f64,
+    pub tokens: usize,
+    // 每个token都是贪心解码的结果
+    pub greedy: bool,
+}
+
+impl ContinuationScore {
+    fn add(&mut self, logits: &[f32], target: usize) -> Result<()> {
+        let Some(&logit) = logits.get(target) else {
+            return Err(EvalError::Invalid(format!(
+                "Token id {target} out of range for vocab size {}",
+                logits.len()
+            )));
+        };
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let sum = logits.iter().map(|x| ((x - max) as f64).exp()).sum::<f64>();
+        self.log_likelihood += (logit - max) as f64 - sum.ln();
+        self.greedy &= logit >= max;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EvalResult {
+    pub examples: usize,
+    pub accuracy: f32,
+    // 按续写的token数取平均后比较的准确率（HellaSwag的acc_norm），完形填空没有该项
+    pub accuracy_norm: Option<f32>,
+    // 正确续写部分的困惑度
+    pub perplexity: f32,
+}
+
+// 对同一个上下文的多个续写打分。上下文只计算一次，之后复制KV缓存分别计算每个续写。
+// 超过上下文窗口时从左侧截断上下文
+pub fn score_continuations(
+    model: &GPTModel,
+    context_ids: &[usize],
+    continuations: &[Vec<usize>],
+) -> Result<Vec<ContinuationScore>> {
+    let context_length = model.config().context_length;
+    let longest = continuations.iter().map(Vec::len).max().unwrap_or(0);
+    if context_ids.is_empty() || continuations.iter().any(Vec::is_empty) {
+        return Err(EvalError::Invalid(
+            "Context and continuations must not be empty".to_string(),
+        ));
+    }
+    if longest >= context_length {
+        return Err(EvalError::Invalid(format!(
+            "Continuation of {longest} tokens does not fit in context length {context_length}"
+        )));
+    }
+    // 最长的续写去掉最后一个token后与上下文一起不超过上下文窗口
+    let keep = context_length + 1 - longest;
+    let context_ids = &context_ids[context_ids.len().saturating_sub(keep)..];
+
+    let mut cache = model.new_kv_cache();
+    let logits = model.forward_with_cache(&[context_ids.to_vec()], &mut cache)?;
+    let vocab_size = logits.shape()[2];
+    let last = &logits.data()[logits.len() - vocab_size..];
+
+    continuations
+        .iter()
+        .map(|continuation| {
+            let mut score = ContinuationScore {
+                tokens: continuation.len(),
+                greedy: true,
+                ..ContinuationScore::default()
+            };
+            score.add(last, continuation[0])?;
+            if continuation.len() > 1 {
+                let inputs = continuation[..continuation.len() - 1].to_vec();
+                let logits = model.forward_with_cache(&[inputs], &mut cache.clone())?;
+                for (row, &target) in logits.data().chunks(vocab_size).zip(&continuation[1..]) {
+                    score.add(row, target)?;
+                }
+            }
+            Ok(score)
+        })
+        .collect()
+}
+
+// 选对数似然最大的续写计算`accuracy`，选平均每个token对数似然最大的计算`accuracy_norm`
+pub fn evaluate_multiple_choice(
+    model: &GPTModel,
+    tokenizer: &impl Tokenizer,
+    examples: &[MultipleChoice],
+) -> Result<EvalResult> {
+    let (mut correct, mut correct_norm) = (0, 0);
+    let (mut log_likelihood, mut tokens) = (0.0, 0);
+    for (i, example) in examples.iter().enumerate() {
+        if example.label >= example.choices.len() {
+            return Err(EvalError::Invalid(format!(
+                "Label {} out of range for {} choices",
+                example.label,
+                example.choices.len()
+            )));
+        }
+        let context_ids = tokenizer.encode(&example.context)?;
+        let continuations = example
+            .choices
+            .iter()
+            .map(|choice| tokenizer.encode(choice))
+            .collect::<Result<Vec<_>, _>>()?;
+        let scores = score_continuations(model, &context_ids, &continuations)?;
+
+        let best = |key: &dyn Fn(&ContinuationScore) -> f64| {
+            (0..scores.len())
+                .max_by(|&a, &b| key(&scores[a]).total_cmp(&key(&scores[b])))
+                .unwrap_or_default()
+        };
+        correct += (best(&|s| s.log_likelihood) == example.label) as usize;
+        correct_norm += (best(&|s| s.log_likelihood / s.tokens as f64) == example.label) as usize;
+        log_likelihood += scores[example.label].log_likelihood;
+        tokens += scores[example.label].tokens;
+        log_progress(i, examples.len(), correct);
+    }
+    result(
+        examples.len(),
+        correct,
+        Some(correct_norm),
+        log_likelihood,
+        tokens,
+    )
+}
+
+pub fn evaluate_cloze(
+    model: &GPTModel,
+    tokenizer: &impl Tokenizer,
+    examples: &[Cloze],
+) -> Result<EvalResult> {
+    let mut correct = 0;
+    let (mut log_likelihood, mut tokens) = (0.0, 0);
+    for (i, example) in examples.iter().enumerate() {
+        let context_ids = tokenizer.encode(&example.context)?;
+        let target_ids = tokenizer.encode(&example.target)?;
+        let score = score_continuations(model, &context_ids, &[target_ids])?[0];
+        correct += score.greedy as usize;
+        log_likelihood += score.log_likelihood;
+        tokens += score.tokens;
+        log_progress(i, examples.len(), correct);
+    }
+    result(examples.len(), correct, None, log_likelihood, tokens)
+}
+
+fn log_progress(i: usize, total: usize, correct: usize) {
+    if (i + 1).is_multiple_of(100) {
+        tracing::info!(
+            "{}/{total} examples, accuracy {:.4}",
+            i + 1,
+            correct as f32 / (i + 1) as f32
+        );
+    }
+}
+
+fn result(
+    examples: usize,
+    correct: usize,
+    correct_norm: Option<usize>,
+    log_likelihood: f64,
+    tokens: usize,
+) -> Result<EvalResult> {
+    if examples == 0 {
+        return Err(EvalError::Invalid("No examples to evaluate".to_string()));
+    }
+    Ok(EvalResult {
+        examples,
+        accuracy: correct as f32 / examples as f32,
+        accuracy_norm: correct_norm.map(|correct| correct as f32 / examples as f32),
+        perplexity: perplexity((-log_likelihood / tokens as f64) as f32),
+    })
+}
+
+#[derive(Deserialize)]
+struct HellaSwagLine {
+    ctx: String,
+    endings: Vec<String>,
+    label: usize,
+}
+
+#[derive(Deserialize)]
+struct LambadaLine {
+    text: String,
+}
+
+fn load_jsonl<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Vec<T>> {
+    let text = fs::read_to_string(path).map_err(file_error("read", path))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|source| EvalError::InvalidExample {
+                path: path.to_path_buf(),
+                line: i + 1,
+                source,
+            })
+        })
+        .collect()
+}
+
+// HellaSwag的`hellaswag_val.jsonl`，每个候选结尾前加一个空格
+pub fn load_hellaswag(path: impl AsRef<Path>) -> Result<Vec<MultipleChoice>> {
+    Ok(load_jsonl::<HellaSwagLine>(path.as_ref())?
+        .into_iter()
+        .map(|line| MultipleChoice {
+            context: line.ctx,
+            choices: line
+                .endings
+                .iter()
+                .map(|ending| format!(" {ending}"))
+                .collect(),
+            label: line.label,
+        })
+        .collect())
+}
+
+// OpenAI的`lambada_test.jsonl`，最后一个词（包括前面的空格）作为`target`
+pub fn load_lambada(path: impl AsRef<Path>) -> Result<Vec<Cloze>> {
+    let path = path.as_ref();
+    load_jsonl::<LambadaLine>(path)?
+        .into_iter()
+        .map(|line| {
+            let text = line.text.trim_end();
+            let Some(split) = text.rfind(char::is_whitespace) else {
+                return Err(EvalError::MissingContext(path.to_path_buf()));
+            };
+            Ok(Cloze {
+                context: text[..split].to_string(),
+                target: text[split..].to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TestResult;
+    use crate::vocab::{Encoding, SentenceType, Vocabulary};
+    use model::{cross_entropy, GptConfig};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_eval() -> TestResult {
+        let config = GptConfig {
+            context_length: 16,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 2,
+            dropout: 0.0,
+            ..GptConfig::gpt2_small()
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+
+        // 与完整序列一次前向的交叉熵相同
+        let (context, continuation) = (vec![3, 1, 4, 1, 5], vec![9, 2, 6]);
+        let score = score_continuations(&model, &context, std::slice::from_ref(&continuation))?[0];
+        println!("{score:?}");
+        let inputs = [context.clone(), continuation[..2].to_vec()].concat();
+        let targets = [vec![usize::MAX; 4], continuation.clone()].concat();
+        let logits = model.forward_with_cache(&[inputs], &mut model.new_kv_cache())?;
+        let loss = cross_entropy(&logits, &[targets], Some(usize::MAX))?;
+        assert!((score.log_likelihood + loss as f64 * 3.0).abs() < 1e-3);
+
+        // 上下文过长时从左侧截断
+        let long = (0..40).collect::<Vec<_>>();
+        let scores = score_continuations(&model, &long, &[vec![7], vec![8, 9, 10]])?;
+        assert_eq!(scores.len(), 2);
+        assert!(score_continuations(&model, &long, &[vec![1; 16]]).is_err());
+        assert!(score_continuations(&model, &[], &[vec![1]]).is_err());
+
+        let dir = std::env::temp_dir().join("test_eval");
+        fs::create_dir_all(&dir)?;
+        let hellaswag = dir.join("hellaswag.jsonl");
+        fs::write(
+            &hellaswag,
+            r#"{"ctx": "A man is sitting on a roof. he", "endings": ["is using wrap.", "is ripping level tiles off.", "is holding a rubik's cube.", "starts pulling up roofing."], "label": 3}
+{"ctx": "The cat", "endings": ["sat on the mat.", "flew away."], "label": 0}"#,
+        )?;
+        let examples = load_hellaswag(&hellaswag)?;
+        assert_eq!(examples[0].choices[3], " starts pulling up roofing.");
+        let tokenizer = Vocabulary::new("", SentenceType::English)?.with_encoding(Encoding::Gpt2);
+        let result = evaluate_multiple_choice(&model, &tokenizer, &examples)?;
+        println!("{result:?}");
+        assert_eq!(result.examples, 2);
+        assert!(result.accuracy_norm.is_some() && result.perplexity > 1.0);
+
+        let lambada = dir.join("lambada.jsonl");
+        fs::write(
+            &lambada,
+            "{\"text\": \"He opened the door and saw his dog\"}\n",
+        )?;
+        let examples = load_lambada(&lambada)?;
+        assert_eq!(
+            examples[0],
+            Cloze {
+                context: "He opened the door and saw his".to_string(),
+                target: " dog".to_string(),
+            }
+        );
+        let result = evaluate_cloze(&model, &tokenizer, &examples)?;
+        assert!(result.accuracy_norm.is_none() && [0.0, 1.0].contains(&result.accuracy));
+        assert!(evaluate_cloze(&model, &tokenizer, &[]).is_err());
+        Ok(())
+    }
+}
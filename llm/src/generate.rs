@@ -0,0 +1,389 @@
+use crate::error::{GenerateError, TokenizerError};
+use crate::tokenizer::Tokenizer;
+use model::{generate_text_simple, GPTModel, SamplingConfig};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+type Result<T, E = GenerateError> = std::result::Result<T, E>;
+
+// 书中第5章的`text_to_token_ids`，返回`(1, T)`的批次
+pub fn text_to_token_ids(
+    text: &str,
+    tokenizer: &impl Tokenizer,
+) -> Result<Vec<Vec<usize>>, TokenizerError> {
+    Ok(vec![tokenizer.encode(text)?])
+}
+
+pub fn token_ids_to_text(
+    token_ids: &[Vec<usize>],
+    tokenizer: &impl Tokenizer,
+) -> Result<String, TokenizerError> {
+    tokenizer.decode(&token_ids.concat())
+}
+
+// 从prompt贪心生成`max_new_tokens`个token，返回包含prompt的完整文本
+pub fn generate_text(
+    model: &GPTModel,
+    tokenizer: &impl Tokenizer,
+    prompt: &str,
+    max_new_tokens: usize,
+) -> Result<String> {
+    let token_ids = text_to_token_ids(prompt, tokenizer)?;
+    let context_size = model.config().context_length;
+    let token_ids = generate_text_simple(model, &token_ids, max_new_tokens, context_size)?;
+    Ok(token_ids_to_text(&token_ids, tokenizer)?)
+}
+
+// 把逐个生成的token解码成文本片段。字节级BPE的一个token可能只是UTF-8字符的一部分，
+// 解码失败或以`�`结尾时先不输出，等后面的token补全
+pub struct TextStreamer<'a, T: Tokenizer> {
+    tokenizer: &'a T,
+    token_ids: Vec<usize>,
+    emitted: usize,
+    // 上一次成功解码之后的token数，一个字符最多4个字节
+    pending: usize,
+}
+
+impl<'a, T: Tokenizer> TextStreamer<'a, T> {
+    pub fn new(tokenizer: &'a T) -> Self {
+        TextStreamer {
+            tokenizer,
+            token_ids: vec![],
+            emitted: 0,
+            pending: 0,
+        }
+    }
+
+    // 返回新增的文本，没有可以输出的完整字符时为空
+    pub fn push(&mut self, token_id: usize) -> Result<String, TokenizerError> {
+        self.token_ids.push(token_id);
+        self.pending += 1;
+        let text = match self.tokenizer.decode(&self.token_ids) {
+            Ok(text) => text,
+            Err(_) if self.pending < 4 => return Ok(String::new()),
+            Err(err) => return Err(err),
+        };
+        if text.ends_with('\u{FFFD}') {
+            return Ok(String::new());
+        }
+        // 加入新的token后前面的文本可能变短或改变，已输出的位置不再是字符边界
+        let Some(piece) = text.get(self.emitted..).map(str::to_string) else {
+            return Ok(String::new());
+        };
+        self.emitted = text.len();
+        self.pending = 0;
+        Ok(piece)
+    }
+
+    // 生成结束时输出剩下的文本，最后的字符不完整时与`decode`相同返回错误
+    pub fn finish(&mut self) -> Result<String, TokenizerError> {
+        let text = self.tokenizer.decode(&self.token_ids)?;
+        let piece = text.get(self.emitted..).unwrap_or_default().to_string();
+        self.emitted = text.len();
+        Ok(piece)
+    }
+}
+
+// 生成的长度和停止条件，采样方式由`SamplingConfig`配置
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerationConfig {
+    pub max_new_tokens: usize,
+    // 生成的文本中出现其中任意一个时停止，结果不包括停止文本
+    pub stop_sequences: Vec<String>,
+    // 生成该token时停止，结果不包括该token
+    pub eos_token_id: Option<usize>,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        GenerationConfig {
+            max_new_tokens: 50,
+            stop_sequences: vec![],
+            eos_token_id: None,
+        }
+    }
+}
+
+impl GenerationConfig {
+    pub fn new(max_new_tokens: usize) -> Self {
+        GenerationConfig {
+            max_new_tokens,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.stop_sequences = stop_sequences;
+        self
+    }
+
+    pub fn with_eos_token_id(mut self, eos_token_id: Option<usize>) -> Self {
+        self.eos_token_id = eos_token_id;
+        self
+    }
+}
+
+// 在逐段解码出的文本中查找停止文本。停止文本可能跨多个token，
+// 结尾可能是停止文本开头的部分先不输出，确定不是停止文本后再输出
+#[derive(Debug, Clone, Default)]
+pub struct StopMatcher {
+    stops: Vec<String>,
+    text: String,
+    emitted: usize,
+    stopped: bool,
+}
+
+impl StopMatcher {
+    pub fn new(stops: Vec<String>) -> Self {
+        StopMatcher {
+            stops: stops.into_iter().filter(|stop| !stop.is_empty()).collect(),
+            ..Self::default()
+        }
+    }
+
+    // 加入新的文本，返回可以输出的部分。遇到停止文本时截断，之后的文本都被忽略，
+    // 停止文本之前还没输出的部分由`finish`返回
+    pub fn push(&mut self, piece: &str) -> &str {
+        if self.stopped {
+            return "";
+        }
+        // 已输出的部分不包含停止文本，只需要从保留的部分开始查找
+        let start = self.emitted;
+        self.text.push_str(piece);
+        let stop = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.text[start..].find(stop.as_str()))
+            .min();
+        if let Some(end) = stop {
+            self.text.truncate(start + end);
+            self.stopped = true;
+            return "";
+        }
+
+        let hold = self
+            .stops
+            .iter()
+            .map(|stop| partial_match(&self.text, stop))
+            .max()
+            .unwrap_or(0);
+        self.emitted = self.emitted.max(self.text.len() - hold);
+        &self.text[start..self.emitted]
+    }
+
+    // 生成结束时返回还没输出的部分
+    pub fn finish(&mut self) -> &str {
+        let start = self.emitted;
+        self.emitted = self.text.len();
+        &self.text[start..]
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // 到目前为止的文本，不包括停止文本
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn emitted(&self) -> usize {
+        self.emitted
+    }
+}
+
+// `text`的结尾与`stop`开头重合的最大字节数，不包括完整的`stop`
+pub(crate) fn partial_match(text: &str, stop: &str) -> usize {
+    (1..stop.len())
+        .rev()
+        .find(|&len| stop.is_char_boundary(len) && text.ends_with(&stop[..len]))
+        .unwrap_or(0)
+}
+
+// 按`sampling`生成，每解码出一段文本就调用`on_text`，生成`eos_token_id`、停止文本或
+// `max_new_tokens`个token后停止，返回包含prompt的完整文本
+pub fn generate_text_stream(
+    model: &GPTModel,
+    tokenizer: &impl Tokenizer,
+    prompt: &str,
+    generation: &GenerationConfig,
+    sampling: &SamplingConfig,
+    rng: &mut impl Rng,
+    mut on_text: impl FnMut(&str),
+) -> Result<String> {
+    let token_ids = tokenizer.encode(prompt)?;
+    let mut streamer = TextStreamer::new(tokenizer);
+    let mut stops = StopMatcher::new(generation.stop_sequences.clone());
+    model.generate_stream(
+        &token_ids,
+        generation.max_new_tokens,
+        sampling,
+        rng,
+        |token| -> Result<bool> {
+            if generation.eos_token_id == Some(token) {
+                return Ok(false);
+            }
+            let piece = stops.push(&streamer.push(token)?);
+            if !piece.is_empty() {
+                on_text(piece);
+            }
+            Ok(!stops.is_stopped())
+        },
+    )?;
+    // 达到`max_new_tokens`时最后一个字符可能不完整，丢弃这部分字节
+    if !stops.is_stopped() {
+        match streamer.finish() {
+            Ok(piece) => {
+                let piece = stops.push(&piece);
+                if !piece.is_empty() {
+                    on_text(piece);
+                }
+            }
+            Err(err) => tracing::debug!("Dropping incomplete text at the end: {err}"),
+        }
+    }
+    let rest = stops.finish();
+    if !rest.is_empty() {
+        on_text(rest);
+    }
+    Ok(tokenizer.decode(&token_ids)? + stops.text())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TestResult;
+    use crate::vocab::{Encoding, SentenceType, Vocabulary};
+    use model::GptConfig;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_generate_text() -> TestResult {
+        let vocab = Vocabulary::new("", SentenceType::English)?.with_encoding(Encoding::Gpt2);
+        let token_ids = text_to_token_ids("Hello, I am", &vocab)?;
+        assert_eq!(token_ids, [vec![15496, 11, 314, 716]]);
+
+        // 书中第4章：未训练的模型会生成没有意义的文本
+        let config = GptConfig {
+            context_length: 16,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 2,
+            dropout: 0.0,
+            ..GptConfig::gpt2_small()
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let text = generate_text(&model, &vocab, "Hello, I am", 6)?;
+        println!("{text}");
+        assert!(text.starts_with("Hello, I am"));
+
+        let output = generate_text_simple(&model, &token_ids, 6, 16)?;
+        assert_eq!(output[0].len(), 10);
+        assert_eq!(token_ids_to_text(&output, &vocab)?, text);
+
+        // 流式输出的片段拼起来与一次性解码的结果相同
+        let mut pieces = vec![];
+        let streamed = generate_text_stream(
+            &model,
+            &vocab,
+            "Hello, I am",
+            &GenerationConfig::new(6),
+            &SamplingConfig::greedy(),
+            &mut StdRng::seed_from_u64(123),
+            |piece| pieces.push(piece.to_string()),
+        )?;
+        println!("{pieces:?}");
+        assert_eq!(streamed, text);
+        assert_eq!(format!("Hello, I am{}", pieces.concat()), text);
+
+        // 用生成的第3个token作为eos，或者用第2、3个token的文本作为停止文本，都只保留前两个token
+        let generated = &output[0][4..];
+        let expected = token_ids_to_text(&[output[0][..6].to_vec()], &vocab)?;
+        let stop = vocab.decode(&generated[2..4])?;
+        for generation in [
+            GenerationConfig::new(6).with_eos_token_id(Some(generated[2])),
+            GenerationConfig::new(6).with_stop_sequences(vec![stop]),
+        ] {
+            let mut pieces = vec![];
+            let streamed = generate_text_stream(
+                &model,
+                &vocab,
+                "Hello, I am",
+                &generation,
+                &SamplingConfig::greedy(),
+                &mut StdRng::seed_from_u64(123),
+                |piece| pieces.push(piece.to_string()),
+            )?;
+            println!("{generation:?} {streamed:?}");
+            assert_eq!(streamed, expected);
+            assert_eq!(format!("Hello, I am{}", pieces.concat()), expected);
+        }
+
+        // "你"在GPT-2的词表中是3个字节级token，前两个token不输出
+        let ids = vocab.encode("你")?;
+        let mut streamer = TextStreamer::new(&vocab);
+        let pieces = ids
+            .iter()
+            .map(|id| streamer.push(*id))
+            .collect::<Result<Vec<_>, _>>()?;
+        println!("{ids:?} {pieces:?}");
+        assert_eq!(pieces.concat(), "你");
+        assert!(pieces[..pieces.len() - 1].iter().all(String::is_empty));
+
+        // 解码结果改变后已输出的位置落在字符中间，不会panic
+        struct Rewriting;
+        impl Tokenizer for Rewriting {
+            fn encode(&self, _: &str) -> Result<Vec<usize>, TokenizerError> {
+                Ok(vec![])
+            }
+            fn decode(&self, token_ids: &[usize]) -> Result<String, TokenizerError> {
+                Ok(["ab", "中", "ab中"][token_ids.len() - 1].to_string())
+            }
+            fn vocab_size(&self) -> usize {
+                1
+            }
+            fn special_ids(&self) -> Vec<usize> {
+                vec![]
+            }
+        }
+        let mut streamer = TextStreamer::new(&Rewriting);
+        let pieces = (0..3)
+            .map(|_| streamer.push(0))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(pieces, ["ab", "", "中"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_matcher() {
+        assert_eq!(partial_match("Hello<|im", "<|im_end|>"), 4);
+        assert_eq!(partial_match("Hello", "<|im_end|>"), 0);
+        assert_eq!(partial_match("你好", "好的"), 3);
+
+        // 停止文本跨多个片段，可能是停止文本开头的部分先不输出
+        let mut stops = StopMatcher::new(vec!["<|im_end|>".to_string(), String::new()]);
+        assert_eq!(stops.push("Hi <"), "Hi ");
+        assert_eq!(stops.push("|im"), "");
+        assert_eq!(stops.push("_end|>more"), "");
+        assert!(stops.is_stopped());
+        assert_eq!(stops.push("ignored"), "");
+        assert_eq!(stops.finish(), "");
+        assert_eq!(stops.text(), "Hi ");
+
+        // 不是停止文本时保留的部分之后输出
+        let mut stops = StopMatcher::new(vec!["\n\n".to_string(), "###".to_string()]);
+        assert_eq!(stops.push("a\n"), "a");
+        assert_eq!(stops.push("b#"), "\nb");
+        assert_eq!(stops.push("#"), "");
+        assert_eq!(stops.finish(), "##");
+        assert!(!stops.is_stopped());
+
+        let mut stops = StopMatcher::new(vec!["\n\n".to_string()]);
+        assert_eq!(stops.push("line\n"), "line");
+        assert_eq!(stops.push("\nnext"), "");
+        assert_eq!(stops.finish(), "");
+        assert_eq!((stops.text(), stops.emitted()), ("line", 4));
+    }
+}
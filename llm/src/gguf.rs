@@ -0,0 +1,240 @@
+use crate::error::{invalid, unsupported, TokenizerError};
+use crate::vocab::{Encoding, SentenceType, Vocabulary};
+use model::GgufValue;
+use std::collections::HashMap;
+
+// llama.cpp的token类型
+const TOKEN_NORMAL: i32 = 1;
+const TOKEN_CONTROL: i32 = 3;
+const TOKEN_UNUSED: i32 = 5;
+
+// `export_gguf`需要的分词器metadata，llama.cpp按`tokenizer.ggml.model = "gpt2"`重建字节级BPE。
+// tiktoken只保存了每个token的rank，merges按rank从小到大重新推导。
+// 只支持英文词表，`vocab_size`小于模型的嵌入行数时用不会出现的占位token补齐
+pub fn tokenizer_metadata(
+    vocab: &Vocabulary,
+    vocab_size: usize,
+) -> Result<Vec<(String, GgufValue)>, TokenizerError> {
+    if *vocab.sentence_type() != SentenceType::English {
+        return Err(unsupported!(
+            "GGUF export supports tiktoken (English) vocabularies only"
+        ));
+    }
+    if vocab.vocab_size() > vocab_size {
+        return Err(invalid!(
+            "Tokenizer has {} tokens, model vocab size is {vocab_size}",
+            vocab.vocab_size()
+        ));
+    }
+
+    let encoding = vocab.encoding();
+    let bpe = encoding.bpe();
+    let mut specials = bpe
+        .special_tokens()
+        .into_iter()
+        .map(|token| {
+            (
+                bpe.encode_with_special_tokens(token)[0] as usize,
+                token.to_string(),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    specials.extend(
+        vocab
+            .special_tokens()
+            .iter()
+            .map(|(token, id)| (*id, token.clone())),
+    );
+
+    let byte_chars = bytes_to_unicode();
+    let mut tokens = vec![];
+    let mut token_types = vec![];
+    let mut ranks = HashMap::new();
+    for id in 0..vocab_size {
+        if let Some(token) = specials.get(&id) {
+            tokens.push(token.clone());
+            token_types.push(TOKEN_CONTROL);
+            continue;
+        }
+        match token_bytes(encoding, id) {
+            Some(bytes) => {
+                tokens.push(bytes.iter().map(|b| byte_chars[*b as usize]).collect());
+                token_types.push(TOKEN_NORMAL);
+                ranks.insert(bytes, id);
+            }
+            None => {
+                tokens.push(format!("[PAD{id}]"));
+                token_types.push(TOKEN_UNUSED);
+            }
+        }
+    }
+
+    let mut merges = ranks
+        .iter()
+        .filter(|(bytes, _)| bytes.len() > 1)
+        .filter_map(|(bytes, rank)| Some((*rank, split_token(bytes, *rank, &ranks)?)))
+        .collect::<Vec<_>>();
+    merges.sort_unstable_by_key(|(rank, _)| *rank);
+    let merges = merges
+        .into_iter()
+        .map(|(_, (left, right))| {
+            let text = |bytes: &[u8]| {
+                bytes
+                    .iter()
+                    .map(|b| byte_chars[*b as usize])
+                    .collect::<String>()
+            };
+            GgufValue::String(format!("{} {}", text(left), text(right)))
+        })
+        .collect();
+
+    // llama.cpp中与tiktoken预分词正则相同的名字
+    let pre = match encoding {
+        Encoding::Gpt2 | Encoding::R50kBase | Encoding::P50kBase => "gpt-2",
+        Encoding::Cl100kBase => "llama-bpe",
+        Encoding::O200kBase => "gpt-4o",
+    };
+    let string = |s: &str| GgufValue::String(s.to_string());
+    let mut metadata = vec![
+        ("tokenizer.ggml.model".to_string(), string("gpt2")),
+        ("tokenizer.ggml.pre".to_string(), string(pre)),
+        (
+            "tokenizer.ggml.tokens".to_string(),
+            GgufValue::Array(tokens.into_iter().map(GgufValue::String).collect()),
+        ),
+        (
+            "tokenizer.ggml.token_type".to_string(),
+            GgufValue::Array(token_types.into_iter().map(GgufValue::I32).collect()),
+        ),
+        (
+            "tokenizer.ggml.merges".to_string(),
+            GgufValue::Array(merges),
+        ),
+    ];
+    if let Some(eos) = vocab.eos_id() {
+        // GPT-2没有单独的bos，与Hugging Face相同使用`<|endoftext|>`
+        metadata.push((
+            "tokenizer.ggml.bos_token_id".to_string(),
+            GgufValue::U32(eos as u32),
+        ));
+        metadata.push((
+            "tokenizer.ggml.eos_token_id".to_string(),
+            GgufValue::U32(eos as u32),
+        ));
+    }
+    if let Some(pad) = vocab.pad_id() {
+        metadata.push((
+            "tokenizer.ggml.padding_token_id".to_string(),
+            GgufValue::U32(pad as u32),
+        ));
+    }
+    Ok(metadata)
+}
+
+// 普通token的字节，编码中不存在的id（如cl100k_base特殊token之间的空位）返回`None`
+fn token_bytes(encoding: Encoding, id: usize) -> Option<Vec<u8>> {
+    let bpe = encoding.bpe();
+    match bpe.decode(vec![id as u32]) {
+        Ok(text) => Some(text.into_bytes()),
+        // 只是UTF-8字符的一部分，id存在，可以直接取字节
+        Err(err) if err.to_string().contains("valid UTF-8") => {
+            bpe._decode_native_and_split(vec![id as u32]).next()
+        }
+        Err(_) => None,
+    }
+}
+
+// 只用rank更小的token对`bytes`做BPE，最后剩下的两部分就是合并出该token的merge
+fn split_token<'a>(
+    bytes: &'a [u8],
+    rank: usize,
+    ranks: &HashMap<Vec<u8>, usize>,
+) -> Option<(&'a [u8], &'a [u8])> {
+    let mut parts = (0..bytes.len())
+        .map(|i| &bytes[i..i + 1])
+        .collect::<Vec<_>>();
+    loop {
+        let best = (0..parts.len().saturating_sub(1))
+            .filter_map(|i| {
+                let start = parts[i].as_ptr() as usize - bytes.as_ptr() as usize;
+                let merged = &bytes[start..start + parts[i].len() + parts[i + 1].len()];
+                let merged_rank = *ranks.get(merged)?;
+                (merged_rank < rank).then_some((merged_rank, i, merged))
+            })
+            .min();
+        let Some((_, i, merged)) = best else {
+            break;
+        };
+        parts[i] = merged;
+        parts.remove(i + 1);
+    }
+    match parts[..] {
+        [left, right] => Some((left, right)),
+        _ => None,
+    }
+}
+
+// GPT-2的`bytes_to_unicode`：可打印的字节保持不变，其余字节映射到256之后的字符，
+// 使每个token都能写成不含空白和控制字符的字符串
+fn bytes_to_unicode() -> Vec<char> {
+    let printable = |b: u32| matches!(b, 0x21..=0x7e | 0xa1..=0xac | 0xae..=0xff);
+    let mut next = 256;
+    (0..256u32)
+        .map(|b| {
+            let c = if printable(b) {
+                b
+            } else {
+                next += 1;
+                next - 1
+            };
+            char::from_u32(c).unwrap()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TestResult;
+
+    #[test]
+    fn test_tokenizer_metadata() -> TestResult {
+        let vocab = Vocabulary::new("", SentenceType::English)?.with_encoding(Encoding::Gpt2);
+        let metadata = tokenizer_metadata(&vocab, 50260)?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let array = |key: &str| match &metadata[key] {
+            GgufValue::Array(items) => items.clone(),
+            other => panic!("{key}: {other:?}"),
+        };
+
+        // 与Hugging Face的`vocab.json`和`merges.txt`相同
+        let tokens = array("tokenizer.ggml.tokens");
+        assert_eq!(tokens.len(), 50260);
+        assert_eq!(tokens[0], GgufValue::String("!".to_string()));
+        assert_eq!(tokens[262], GgufValue::String("Ġthe".to_string()));
+        assert_eq!(
+            tokens[50256],
+            GgufValue::String("<|endoftext|>".to_string())
+        );
+        assert_eq!(tokens[50259], GgufValue::String("[PAD50259]".to_string()));
+        let types = array("tokenizer.ggml.token_type");
+        assert_eq!(
+            (&types[50256], &types[50257]),
+            (&GgufValue::I32(3), &GgufValue::I32(5))
+        );
+
+        let merges = array("tokenizer.ggml.merges");
+        println!("{} merges: {:?}", merges.len(), &merges[..4]);
+        assert_eq!(merges.len(), 50000);
+        let expected = ["Ġ t", "Ġ a", "h e", "i n"].map(|m| GgufValue::String(m.to_string()));
+        assert_eq!(merges[..4], expected);
+        assert_eq!(
+            metadata["tokenizer.ggml.eos_token_id"],
+            GgufValue::U32(50256)
+        );
+
+        assert!(tokenizer_metadata(&vocab, 1000).is_err());
+        Ok(())
+    }
+}
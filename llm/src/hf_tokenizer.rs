@@ -0,0 +1,812 @@
+use crate::error::{invalid, Result, TokenizerError};
+use crate::vocab::byte_token;
+use fancy_regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+// GPT-2的预分词正则，`ByteLevel`的`use_regex`为true时使用
+const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+// GPT-2把每个字节映射为一个可见字符，空格对应`Ġ`
+static BYTE_CHARS: LazyLock<[char; 256]> = LazyLock::new(|| {
+    let mut chars = ['\0'; 256];
+    let mut n = 0;
+    for byte in 0..=u8::MAX {
+        chars[byte as usize] = if matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF) {
+            byte as char
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+    }
+    chars
+});
+
+static CHAR_BYTES: LazyLock<HashMap<char, u8>> = LazyLock::new(|| {
+    BYTE_CHARS
+        .iter()
+        .enumerate()
+        .map(|(byte, c)| (*c, byte as u8))
+        .collect()
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Normalizer {
+    Prepend(String),
+    Replace { pattern: String, content: String },
+    Lowercase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum SplitBehavior {
+    Removed,
+    Isolated,
+    MergedWithPrevious,
+    MergedWithNext,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum PrependScheme {
+    Always,
+    First,
+    Never,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum PreTokenizer {
+    ByteLevel {
+        add_prefix_space: bool,
+        use_regex: bool,
+    },
+    // `pattern`为正则表达式，普通字符串在解析时已经转义
+    Split {
+        pattern: String,
+        behavior: SplitBehavior,
+        invert: bool,
+    },
+    Metaspace {
+        replacement: char,
+        prepend_scheme: PrependScheme,
+        split: bool,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Decoder {
+    ByteLevel,
+    Replace {
+        pattern: String,
+        content: String,
+    },
+    ByteFallback,
+    Fuse,
+    Strip {
+        content: char,
+        start: usize,
+        stop: usize,
+    },
+    Metaspace {
+        replacement: char,
+        prepend: bool,
+    },
+}
+
+// Hugging Face `tokenizer.json`中BPE模型的切分规则，token本身保存在`Vocabulary`中。
+// 不执行`post_processor`，需要`<s>`等开头token时由调用者添加
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HfModel {
+    normalizers: Vec<Normalizer>,
+    pre_tokenizers: Vec<PreTokenizer>,
+    decoders: Vec<Decoder>,
+    merges: Vec<(String, String)>,
+    byte_fallback: bool,
+    ignore_merges: bool,
+    fuse_unk: bool,
+    unknown_id: Option<usize>,
+    // (左id, 右id) -> (优先级, 合并后的id)
+    #[serde(skip)]
+    ranks: HashMap<(usize, usize), (usize, usize)>,
+    // 与`pre_tokenizers`一一对应
+    #[serde(skip)]
+    regexes: Vec<Option<Regex>>,
+}
+
+pub(crate) struct HfTokenizerFile {
+    pub tokens: Vec<String>,
+    pub special_tokens: Vec<(String, usize)>,
+    pub model: HfModel,
+}
+
+impl HfModel {
+    // 加载后根据词表重建合并规则和正则
+    pub(crate) fn compile(&mut self, vocab: &HashMap<String, usize>) -> Result<()> {
+        self.ranks = self
+            .merges
+            .iter()
+            .enumerate()
+            .filter_map(|(rank, (a, b))| {
+                let merged = vocab.get(&format!("{a}{b}"))?;
+                Some(((*vocab.get(a)?, *vocab.get(b)?), (rank, *merged)))
+            })
+            .collect();
+
+        self.regexes = self
+            .pre_tokenizers
+            .iter()
+            .map(|pre_tokenizer| {
+                let pattern = match pre_tokenizer {
+                    PreTokenizer::ByteLevel {
+                        use_regex: true, ..
+                    } => GPT2_PATTERN.to_string(),
+                    PreTokenizer::Split { pattern, .. } => pattern.clone(),
+                    PreTokenizer::Metaspace {
+                        replacement,
+                        split: true,
+                        ..
+                    } => fancy_regex::escape(&replacement.to_string()).into_owned(),
+                    _ => return Ok(None),
+                };
+                Regex::new(&pattern)
+                    .map(Some)
+                    .map_err(|err| invalid!("Invalid pre-tokenizer pattern {pattern}: {err}"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(())
+    }
+
+    // 与Hugging Face相同，每段普通文本（特殊token之间的部分）单独规范化和预分词
+    pub(crate) fn encode(
+        &self,
+        text: &str,
+        at_start: bool,
+        vocab: &HashMap<String, usize>,
+        token_ids: &mut Vec<usize>,
+    ) -> Result<()> {
+        for word in self.pre_tokenize(&self.normalize(text), at_start)? {
+            self.encode_word(&word, vocab, token_ids)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decode(&self, tokens: Vec<String>) -> String {
+        let mut tokens = tokens;
+        for decoder in self.decoders.iter() {
+            tokens = decoder.decode(tokens);
+        }
+        tokens.concat()
+    }
+
+    fn normalize(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for normalizer in self.normalizers.iter() {
+            text = match normalizer {
+                Normalizer::Prepend(prefix) if !text.is_empty() => format!("{prefix}{text}"),
+                Normalizer::Prepend(_) => text,
+                Normalizer::Replace { pattern, content } => text.replace(pattern, content),
+                Normalizer::Lowercase => text.to_lowercase(),
+            };
+        }
+        text
+    }
+
+    fn pre_tokenize(&self, text: &str, at_start: bool) -> Result<Vec<String>> {
+        let mut words = vec![text.to_string()];
+
+        for (pre_tokenizer, regex) in self.pre_tokenizers.iter().zip(self.regexes.iter()) {
+            let mut next = vec![];
+
+            for (i, word) in words.into_iter().enumerate() {
+                match pre_tokenizer {
+                    PreTokenizer::ByteLevel {
+                        add_prefix_space, ..
+                    } => {
+                        let word = if *add_prefix_space && !word.starts_with(' ') {
+                            format!(" {word}")
+                        } else {
+                            word
+                        };
+                        let parts = match regex {
+                            Some(regex) => split(regex, &word, SplitBehavior::Isolated, false)?,
+                            None => vec![word],
+                        };
+                        next.extend(
+                            parts
+                                .into_iter()
+                                .map(|part| part.bytes().map(|b| BYTE_CHARS[b as usize]).collect()),
+                        );
+                    }
+                    PreTokenizer::Split {
+                        behavior, invert, ..
+                    } => next.extend(split(regex.as_ref().unwrap(), &word, *behavior, *invert)?),
+                    PreTokenizer::Metaspace {
+                        replacement,
+                        prepend_scheme,
+                        ..
+                    } => {
+                        let mut word = word.replace(' ', &replacement.to_string());
+                        let prepend = match prepend_scheme {
+                            PrependScheme::Always => true,
+                            PrependScheme::First => at_start && i == 0,
+                            PrependScheme::Never => false,
+                        };
+                        if prepend && !word.starts_with(*replacement) {
+                            word.insert(0, *replacement);
+                        }
+
+                        match regex {
+                            Some(regex) => next.extend(split(
+                                regex,
+                                &word,
+                                SplitBehavior::MergedWithNext,
+                                false,
+                            )?),
+                            None => next.push(word),
+                        }
+                    }
+                }
+            }
+
+            words = next;
+        }
+
+        Ok(words)
+    }
+
+    // 每次合并优先级最高（`merges`中最靠前）的相邻片段
+    fn encode_word(
+        &self,
+        word: &str,
+        vocab: &HashMap<String, usize>,
+        token_ids: &mut Vec<usize>,
+    ) -> Result<()> {
+        if self.ignore_merges
+            && let Some(&id) = vocab.get(word)
+        {
+            token_ids.push(id);
+            return Ok(());
+        }
+
+        // (起始字节, 结束字节, id)，不在词表中的字符id为None
+        let mut symbols: Vec<(usize, usize, Option<usize>)> = word
+            .char_indices()
+            .map(|(pos, c)| {
+                let end = pos + c.len_utf8();
+                (pos, end, vocab.get(&word[pos..end]).copied())
+            })
+            .collect();
+
+        loop {
+            let best = symbols
+                .windows(2)
+                .enumerate()
+                .filter_map(|(i, pair)| {
+                    let (rank, merged) = self.ranks.get(&(pair[0].2?, pair[1].2?))?;
+                    Some((*rank, i, *merged))
+                })
+                .min();
+
+            let Some((_, i, merged)) = best else {
+                break;
+            };
+            symbols[i] = (symbols[i].0, symbols[i + 1].1, Some(merged));
+            symbols.remove(i + 1);
+        }
+
+        let mut prev_unknown = false;
+        for (start, end, id) in symbols {
+            if let Some(id) = id {
+                token_ids.push(id);
+                prev_unknown = false;
+                continue;
+            }
+
+            let piece = &word[start..end];
+            let bytes: Option<Vec<usize>> = piece
+                .bytes()
+                .map(|b| vocab.get(&byte_token(b)).copied())
+                .collect();
+            match (self.byte_fallback, bytes, self.unknown_id) {
+                (true, Some(bytes), _) => {
+                    token_ids.extend(bytes);
+                    prev_unknown = false;
+                }
+                (_, _, Some(unknown)) => {
+                    if !(self.fuse_unk && prev_unknown) {
+                        token_ids.push(unknown);
+                    }
+                    prev_unknown = true;
+                }
+                _ => return Err(TokenizerError::UnknownToken(piece.to_string())),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Decoder {
+    fn decode(&self, tokens: Vec<String>) -> Vec<String> {
+        match self {
+            Decoder::ByteLevel => {
+                let bytes: Vec<u8> = tokens
+                    .concat()
+                    .chars()
+                    .flat_map(|c| match CHAR_BYTES.get(&c) {
+                        Some(&b) => vec![b],
+                        None => c.to_string().into_bytes(),
+                    })
+                    .collect();
+                vec![String::from_utf8_lossy(&bytes).into_owned()]
+            }
+            Decoder::Replace { pattern, content } => tokens
+                .into_iter()
+                .map(|token| token.replace(pattern, content))
+                .collect(),
+            Decoder::ByteFallback => byte_fallback(tokens),
+            Decoder::Fuse => vec![tokens.concat()],
+            Decoder::Strip {
+                content,
+                start,
+                stop,
+            } => tokens
+                .into_iter()
+                .map(|token| {
+                    let chars: Vec<char> = token.chars().collect();
+                    let from = chars
+                        .iter()
+                        .take(*start)
+                        .take_while(|c| *c == content)
+                        .count();
+                    let skip_end = chars[from..]
+                        .iter()
+                        .rev()
+                        .take(*stop)
+                        .take_while(|c| *c == content)
+                        .count();
+                    chars[from..chars.len() - skip_end].iter().collect()
+                })
+                .collect(),
+            Decoder::Metaspace {
+                replacement,
+                prepend,
+            } => tokens
+                .into_iter()
+                .enumerate()
+                .map(|(i, token)| {
+                    let token = token.replace(*replacement, " ");
+                    match token.strip_prefix(' ') {
+                        Some(rest) if *prepend && i == 0 => rest.to_string(),
+                        _ => token,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+// 连续的`<0xNN>`token合并为字节后按UTF-8解码，无效时每个字节解码为`�`
+fn byte_fallback(tokens: Vec<String>) -> Vec<String> {
+    let mut result = vec![];
+    let mut bytes = vec![];
+
+    let flush = |bytes: &mut Vec<u8>, result: &mut Vec<String>| {
+        if bytes.is_empty() {
+            return;
+        }
+        match String::from_utf8(std::mem::take(bytes)) {
+            Ok(text) => result.push(text),
+            Err(e) => result.extend(e.as_bytes().iter().map(|_| "\u{FFFD}".to_string())),
+        }
+    };
+
+    for token in tokens {
+        let byte = token
+            .strip_prefix("<0x")
+            .and_then(|s| s.strip_suffix('>'))
+            .filter(|hex| hex.len() == 2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match byte {
+            Some(byte) => bytes.push(byte),
+            None => {
+                flush(&mut bytes, &mut result);
+                result.push(token);
+            }
+        }
+    }
+
+    flush(&mut bytes, &mut result);
+    result
+}
+
+fn split(regex: &Regex, text: &str, behavior: SplitBehavior, invert: bool) -> Result<Vec<String>> {
+    // (片段, 是否为匹配的分隔符)
+    let mut parts: Vec<(&str, bool)> = vec![];
+    let mut last = 0;
+    for m in regex.find_iter(text) {
+        let m = m?;
+        if m.start() > last {
+            parts.push((&text[last..m.start()], invert));
+        }
+        if m.end() > m.start() {
+            parts.push((m.as_str(), !invert));
+        }
+        last = m.end();
+    }
+    if last < text.len() {
+        parts.push((&text[last..], invert));
+    }
+
+    let mut words: Vec<String> = vec![];
+    let mut pending_next = String::new();
+    for (part, delimiter) in parts {
+        match (behavior, delimiter) {
+            (SplitBehavior::Removed, true) => {}
+            (SplitBehavior::MergedWithPrevious, true) if !words.is_empty() => {
+                words.last_mut().unwrap().push_str(part)
+            }
+            (SplitBehavior::MergedWithNext, true) => pending_next.push_str(part),
+            _ => words.push(std::mem::take(&mut pending_next) + part),
+        }
+    }
+    if !pending_next.is_empty() {
+        words.push(pending_next);
+    }
+
+    Ok(words)
+}
+
+// 解析`tokenizer.json`，目前只支持BPE模型（GPT-2、Llama、Qwen等）
+pub(crate) fn parse_tokenizer(text: &str) -> Result<HfTokenizerFile> {
+    let json: Value = serde_json::from_str(text)?;
+    let model_json = &json["model"];
+
+    match model_json["type"].as_str() {
+        Some("BPE") => {}
+        other => {
+            return Err(invalid!(
+                "Unsupported tokenizer model {other:?}, only BPE is supported"
+            ))
+        }
+    }
+    for key in ["continuing_subword_prefix", "end_of_word_suffix"] {
+        if model_json[key].as_str().is_some_and(|s| !s.is_empty()) {
+            return Err(invalid!("Unsupported BPE option {key}"));
+        }
+    }
+
+    let mut ids: Vec<(String, usize)> = model_json["vocab"]
+        .as_object()
+        .ok_or_else(|| invalid!("No vocab in tokenizer model"))?
+        .iter()
+        .map(|(token, id)| {
+            Ok((
+                token.clone(),
+                id.as_u64().ok_or_else(|| invalid!("Invalid token id"))? as usize,
+            ))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut special_tokens = vec![];
+    for added in json["added_tokens"].as_array().into_iter().flatten() {
+        let token = str_field(added, "content")?.to_string();
+        let id = added["id"]
+            .as_u64()
+            .ok_or_else(|| invalid!("Invalid added token id"))? as usize;
+        if !ids.iter().any(|(_, i)| *i == id) {
+            ids.push((token.clone(), id));
+        }
+        special_tokens.push((token, id));
+    }
+
+    ids.sort_by_key(|(_, id)| *id);
+    if ids.iter().enumerate().any(|(i, (_, id))| i != *id) {
+        return Err(invalid!("Token ids are not contiguous"));
+    }
+    let tokens: Vec<String> = ids.into_iter().map(|(token, _)| token).collect();
+
+    let merges = model_json["merges"]
+        .as_array()
+        .ok_or_else(|| invalid!("No merges in tokenizer model"))?
+        .iter()
+        .map(|merge| match merge {
+            Value::String(merge) => merge
+                .split_once(' ')
+                .map(|(a, b)| (a.to_string(), b.to_string()))
+                .ok_or_else(|| invalid!("Invalid merge")),
+            Value::Array(pair) if pair.len() == 2 => Ok((
+                pair[0]
+                    .as_str()
+                    .ok_or_else(|| invalid!("Invalid merge"))?
+                    .to_string(),
+                pair[1]
+                    .as_str()
+                    .ok_or_else(|| invalid!("Invalid merge"))?
+                    .to_string(),
+            )),
+            _ => Err(invalid!("Invalid merge")),
+        })
+        .collect::<Result<_>>()?;
+
+    let unknown_id = match model_json["unk_token"].as_str() {
+        Some(unk) => Some(
+            tokens
+                .iter()
+                .position(|token| token == unk)
+                .ok_or_else(|| invalid!("Unknown token not in vocab"))?,
+        ),
+        None => None,
+    };
+
+    let mut model = HfModel {
+        normalizers: flatten(&json["normalizer"], "normalizers")
+            .map(parse_normalizer)
+            .collect::<Result<_>>()?,
+        pre_tokenizers: flatten(&json["pre_tokenizer"], "pretokenizers")
+            .map(parse_pre_tokenizer)
+            .collect::<Result<_>>()?,
+        decoders: flatten(&json["decoder"], "decoders")
+            .map(parse_decoder)
+            .collect::<Result<_>>()?,
+        merges,
+        byte_fallback: bool_field(model_json, "byte_fallback", false),
+        ignore_merges: bool_field(model_json, "ignore_merges", false),
+        fuse_unk: bool_field(model_json, "fuse_unk", false),
+        unknown_id,
+        ..Default::default()
+    };
+
+    let vocab: HashMap<String, usize> = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.clone(), id))
+        .collect();
+    model.compile(&vocab)?;
+
+    Ok(HfTokenizerFile {
+        tokens,
+        special_tokens,
+        model,
+    })
+}
+
+// 展开`Sequence`，null表示没有
+fn flatten<'a>(json: &'a Value, key: &'a str) -> Box<dyn Iterator<Item = &'a Value> + 'a> {
+    match json["type"].as_str() {
+        _ if json.is_null() => Box::new(std::iter::empty()),
+        Some("Sequence") => Box::new(
+            json[key]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .flat_map(move |item| flatten(item, key)),
+        ),
+        _ => Box::new(std::iter::once(json)),
+    }
+}
+
+fn str_field<'a>(json: &'a Value, key: &str) -> Result<&'a str> {
+    json[key]
+        .as_str()
+        .ok_or_else(|| invalid!("Missing field {key}"))
+}
+
+fn bool_field(json: &Value, key: &str, default: bool) -> bool {
+    json[key].as_bool().unwrap_or(default)
+}
+
+fn char_field(json: &Value, key: &str) -> Result<char> {
+    let mut chars = str_field(json, key)?.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(invalid!("Field {key} is not a single character")),
+    }
+}
+
+// `{"String": "..."}`按字面匹配，`{"Regex": "..."}`为正则
+fn pattern_field(json: &Value) -> Result<(String, bool)> {
+    let pattern = &json["pattern"];
+    if let Some(s) = pattern["String"].as_str() {
+        Ok((s.to_string(), false))
+    } else if let Some(s) = pattern["Regex"].as_str() {
+        Ok((s.to_string(), true))
+    } else {
+        Err(invalid!("Invalid pattern {pattern}"))
+    }
+}
+
+fn prepend_scheme(json: &Value) -> Result<PrependScheme> {
+    // 旧版本用`add_prefix_space`表示总是补`▁`
+    match json["prepend_scheme"].as_str() {
+        Some("always") => Ok(PrependScheme::Always),
+        Some("first") => Ok(PrependScheme::First),
+        Some("never") => Ok(PrependScheme::Never),
+        None if bool_field(json, "add_prefix_space", true) => Ok(PrependScheme::Always),
+        None => Ok(PrependScheme::Never),
+        Some(other) => Err(invalid!("Unknown prepend scheme {other}")),
+    }
+}
+
+fn parse_normalizer(json: &Value) -> Result<Normalizer> {
+    match str_field(json, "type")? {
+        "Prepend" => Ok(Normalizer::Prepend(str_field(json, "prepend")?.to_string())),
+        "Replace" => match pattern_field(json)? {
+            (pattern, false) => Ok(Normalizer::Replace {
+                pattern,
+                content: str_field(json, "content")?.to_string(),
+            }),
+            (_, true) => Err(invalid!("Regex Replace normalizer is not supported")),
+        },
+        "Lowercase" => Ok(Normalizer::Lowercase),
+        other => Err(invalid!("Unsupported normalizer {other}")),
+    }
+}
+
+fn parse_pre_tokenizer(json: &Value) -> Result<PreTokenizer> {
+    match str_field(json, "type")? {
+        "ByteLevel" => Ok(PreTokenizer::ByteLevel {
+            add_prefix_space: bool_field(json, "add_prefix_space", true),
+            use_regex: bool_field(json, "use_regex", true),
+        }),
+        "Split" => {
+            let (pattern, regex) = pattern_field(json)?;
+            let behavior = match str_field(json, "behavior")? {
+                "Removed" => SplitBehavior::Removed,
+                "Isolated" => SplitBehavior::Isolated,
+                "MergedWithPrevious" => SplitBehavior::MergedWithPrevious,
+                "MergedWithNext" => SplitBehavior::MergedWithNext,
+                other => return Err(invalid!("Unsupported split behavior {other}")),
+            };
+            Ok(PreTokenizer::Split {
+                pattern: if regex {
+                    pattern
+                } else {
+                    fancy_regex::escape(&pattern).into_owned()
+                },
+                behavior,
+                invert: bool_field(json, "invert", false),
+            })
+        }
+        "Metaspace" => Ok(PreTokenizer::Metaspace {
+            replacement: char_field(json, "replacement")?,
+            prepend_scheme: prepend_scheme(json)?,
+            split: bool_field(json, "split", true),
+        }),
+        other => Err(invalid!("Unsupported pre-tokenizer {other}")),
+    }
+}
+
+fn parse_decoder(json: &Value) -> Result<Decoder> {
+    match str_field(json, "type")? {
+        "ByteLevel" => Ok(Decoder::ByteLevel),
+        "Replace" => match pattern_field(json)? {
+            (pattern, false) => Ok(Decoder::Replace {
+                pattern,
+                content: str_field(json, "content")?.to_string(),
+            }),
+            (_, true) => Err(invalid!("Regex Replace decoder is not supported")),
+        },
+        "ByteFallback" => Ok(Decoder::ByteFallback),
+        "Fuse" => Ok(Decoder::Fuse),
+        "Strip" => Ok(Decoder::Strip {
+            content: char_field(json, "content")?,
+            start: json["start"].as_u64().unwrap_or(0) as usize,
+            stop: json["stop"].as_u64().unwrap_or(0) as usize,
+        }),
+        "Metaspace" => Ok(Decoder::Metaspace {
+            replacement: char_field(json, "replacement")?,
+            prepend: prepend_scheme(json)? != PrependScheme::Never,
+        }),
+        other => Err(invalid!("Unsupported decoder {other}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vocab::{byte_token, Vocabulary};
+    use serde_json::json;
+
+    fn load(name: &str, tokenizer: serde_json::Value) -> Vocabulary {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, tokenizer.to_string()).unwrap();
+        let vocab = Vocabulary::from_hf_tokenizer_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        vocab
+    }
+
+    #[test]
+    fn test_hf_byte_level() {
+        let vocab = json!({
+            "l": 0, "o": 1, "w": 2, "Ġ": 3, "e": 4, "r": 5, "!": 6,
+            "lo": 7, "low": 8, "Ġlow": 9, "er": 10, "Ġlower": 11,
+        });
+        let vocab = load(
+            "test_hf_byte_level.json",
+            json!({
+                "added_tokens": [{"id": 12, "content": "<|endoftext|>", "special": true}],
+                "normalizer": null,
+                "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false, "use_regex": true},
+                "decoder": {"type": "ByteLevel"},
+                "model": {
+                    "type": "BPE",
+                    "vocab": vocab,
+                    "merges": ["l o", "lo w", "Ġ low", ["e", "r"], "Ġlow er"],
+                },
+            }),
+        );
+        assert_eq!(vocab.len(), 13);
+
+        let text = "low lower!<|endoftext|>";
+        let token_ids = vocab.encode(text).unwrap();
+        println!("{:?}", token_ids);
+        assert_eq!(token_ids, [8, 11, 6, 12]);
+        assert_eq!(vocab.decode(&token_ids).unwrap(), text);
+        assert_eq!(vocab.decode_skip_special(&token_ids).unwrap(), "low lower!");
+        assert!(vocab.encode("x").is_err());
+
+        let path = std::env::temp_dir().join("test_hf_byte_level_vocab.json");
+        vocab.save(&path).unwrap();
+        let loaded = Vocabulary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.encode(text).unwrap(), token_ids);
+    }
+
+    #[test]
+    fn test_hf_sentencepiece_bpe() {
+        // 与Llama的`tokenizer.json`结构相同
+        let mut tokens = vec!["<unk>".to_string(), "<s>".to_string(), "</s>".to_string()];
+        tokens.extend((0..=u8::MAX).map(byte_token));
+        tokens.extend(["▁", "h", "i", "▁h", "▁hi"].map(String::from));
+        let offset = 3 + 256;
+        let vocab: serde_json::Map<String, serde_json::Value> = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.clone(), json!(id)))
+            .collect();
+
+        let vocab = load(
+            "test_hf_sentencepiece_bpe.json",
+            json!({
+                "added_tokens": [
+                    {"id": 0, "content": "<unk>", "special": true},
+                    {"id": 1, "content": "<s>", "special": true},
+                ],
+                "normalizer": {"type": "Sequence", "normalizers": [
+                    {"type": "Prepend", "prepend": "▁"},
+                    {"type": "Replace", "pattern": {"String": " "}, "content": "▁"},
+                ]},
+                "pre_tokenizer": null,
+                "decoder": {"type": "Sequence", "decoders": [
+                    {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+                    {"type": "ByteFallback"},
+                    {"type": "Fuse"},
+                    {"type": "Strip", "content": " ", "start": 1, "stop": 0},
+                ]},
+                "model": {
+                    "type": "BPE",
+                    "vocab": vocab,
+                    "merges": ["▁ h", "▁h i"],
+                    "byte_fallback": true,
+                    "fuse_unk": true,
+                    "unk_token": "<unk>",
+                },
+            }),
+        );
+
+        let text = "hi hi好";
+        let token_ids = vocab.encode(text).unwrap();
+        println!("{:?}", token_ids);
+        let mut expected = vec![offset + 4, offset + 4];
+        expected.extend("好".bytes().map(|b| 3 + b as usize));
+        assert_eq!(token_ids, expected);
+        assert_eq!(vocab.decode(&token_ids).unwrap(), text);
+
+        let token_ids = vocab.encode("<s>hi").unwrap();
+        assert_eq!(token_ids, [1, offset + 4]);
+        assert_eq!(vocab.decode_skip_special(&token_ids).unwrap(), "hi");
+    }
+}
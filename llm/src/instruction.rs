@@ -0,0 +1,424 @@
+use crate::error::{file_error, InstructionError, TokenizerError};
+use crate::tokenizer::Tokenizer;
+use crate::train::{TrainConfig, Trainer};
+use data_loader::{Collate, DataLoader, Dataset, TrainData};
+use model::{AdamW, GPTModel};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+type Result<T, E = InstructionError> = std::result::Result<T, E>;
+
+// 计算loss时忽略的目标，对应PyTorch的`ignore_index=-100`
+pub const IGNORE_INDEX: usize = usize::MAX;
+
+// 书中第7章`instruction-data.json`的一条数据，`input`可以为空
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstructionExample {
+    pub instruction: String,
+    #[serde(default)]
+    pub input: String,
+    pub output: String,
+}
+
+impl InstructionExample {
+    // 书中的`format_input`：Alpaca格式的prompt，以`### Response:\n`结尾时模型接着生成回复
+    pub fn prompt(&self) -> String {
+        let mut prompt = format!(
+            "Below is an instruction that describes a task. \
+             Write a response that appropriately completes the request.\
+             \n\n### Instruction:\n{}",
+            self.instruction
+        );
+        if !self.input.is_empty() {
+            prompt.push_str(&format!("\n\n### Input:\n{}", self.input));
+        }
+        prompt.push_str("\n\n### Response:\n");
+        prompt
+    }
+}
+
+// 读取JSON数组或每行一条的JSONL
+pub fn load_instruction_data(path: impl AsRef<Path>) -> Result<Vec<InstructionExample>> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).map_err(file_error("read", path))?;
+    let invalid = |line| {
+        move |source| InstructionError::Invalid {
+            path: path.to_path_buf(),
+            line,
+            source,
+        }
+    };
+
+    if text.trim_start().starts_with('[') {
+        return serde_json::from_str(&text).map_err(invalid(None));
+    }
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| serde_json::from_str(line).map_err(invalid(Some(i + 1))))
+        .collect()
+}
+
+// 分词后的prompt和回复，`prompt_len`之后的token为回复
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionSample {
+    pub token_ids: Vec<usize>,
+    pub prompt_len: usize,
+}
+
+// 书中的`InstructionDataset`：构造时分词，`get`返回`InstructionSample`
+#[derive(Debug, Clone)]
+pub struct InstructionDataset {
+    samples: Vec<InstructionSample>,
+}
+
+impl InstructionDataset {
+    pub fn new(
+        examples: &[InstructionExample],
+        tokenizer: &impl Tokenizer,
+    ) -> Result<Self, TokenizerError> {
+        let samples = examples
+            .iter()
+            .map(|example| {
+                let mut token_ids = tokenizer.encode(&example.prompt())?;
+                let prompt_len = token_ids.len();
+                token_ids.extend(tokenizer.encode(&example.output)?);
+                Ok(InstructionSample {
+                    token_ids,
+                    prompt_len,
+                })
+            })
+            .collect::<Result<Vec<_>, TokenizerError>>()?;
+        Ok(InstructionDataset { samples })
+    }
+}
+
+impl Dataset for InstructionDataset {
+    type Item = InstructionSample;
+
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn get(&self, index: usize) -> InstructionSample {
+        self.samples[index].clone()
+    }
+}
+
+// 书中的`custom_collate_fn`：每个样本末尾加一个`pad_id`（即`<|endoftext|>`）后右侧填充到批次内最长的长度，
+// 目标中除第一个填充外都替换为`IGNORE_INDEX`。`mask_prompt`为true时prompt部分的目标也被忽略，只在回复上计算loss
+#[derive(Debug, Clone)]
+pub struct InstructionCollator {
+    pad_id: usize,
+    mask_prompt: bool,
+    // 超过该长度的样本被截断，一般为模型的`context_length`
+    allowed_max_length: Option<usize>,
+}
+
+impl InstructionCollator {
+    pub fn new(pad_id: usize) -> Self {
+        InstructionCollator {
+            pad_id,
+            mask_prompt: true,
+            allowed_max_length: None,
+        }
+    }
+
+    pub fn with_mask_prompt(mut self, mask_prompt: bool) -> Self {
+        self.mask_prompt = mask_prompt;
+        self
+    }
+
+    pub fn with_allowed_max_length(mut self, allowed_max_length: usize) -> Self {
+        self.allowed_max_length = Some(allowed_max_length);
+        self
+    }
+
+    // 整理后至少有一个目标不是`IGNORE_INDEX`。prompt超过`allowed_max_length`时回复全部被截掉，
+    // 这样的样本没有loss
+    pub fn has_targets(&self, sample: &InstructionSample) -> bool {
+        let start = if self.mask_prompt {
+            sample.prompt_len.saturating_sub(1)
+        } else {
+            0
+        };
+        let end = sample
+            .token_ids
+            .len()
+            .min(self.allowed_max_length.unwrap_or(usize::MAX));
+        start < end
+    }
+}
+
+impl Collate<InstructionSample> for InstructionCollator {
+    type Output = Vec<TrainData<usize>>;
+
+    fn collate(&self, samples: Vec<InstructionSample>) -> Vec<TrainData<usize>> {
+        let max_len = samples
+            .iter()
+            .map(|sample| sample.token_ids.len() + 1)
+            .max()
+            .unwrap_or(0);
+
+        samples
+            .into_iter()
+            .map(|sample| {
+                let len = sample.token_ids.len();
+                let mut token_ids = sample.token_ids;
+                token_ids.resize(max_len, self.pad_id);
+
+                let mut feature = token_ids[..max_len - 1].to_vec();
+                let mut label = token_ids[1..].to_vec();
+                // `label[len - 1]`是第一个填充，作为回复结束的标记保留
+                label[len..].fill(IGNORE_INDEX);
+                if self.mask_prompt {
+                    let prompt_targets = sample.prompt_len.saturating_sub(1).min(label.len());
+                    label[..prompt_targets].fill(IGNORE_INDEX);
+                }
+
+                if let Some(max_length) = self.allowed_max_length {
+                    feature.truncate(max_length);
+                    label.truncate(max_length);
+                }
+                TrainData { feature, label }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FinetuneConfig {
+    pub batch_size: usize,
+    pub lr: f32,
+    pub weight_decay: f32,
+    pub mask_prompt: bool,
+    // 为`None`时使用分词器的`eos_id`
+    pub pad_id: Option<usize>,
+    pub seed: u64,
+    pub train: TrainConfig,
+}
+
+// 书中第7章的设置：batch_size = 8，AdamW(lr = 5e-5, weight_decay = 0.1)，训练2个epoch
+impl Default for FinetuneConfig {
+    fn default() -> Self {
+        FinetuneConfig {
+            batch_size: 8,
+            lr: 5e-5,
+            weight_decay: 0.1,
+            mask_prompt: true,
+            pad_id: None,
+            seed: 123,
+            train: TrainConfig {
+                num_epochs: 2,
+                ignore_index: Some(IGNORE_INDEX),
+                ..TrainConfig::default()
+            },
+        }
+    }
+}
+
+// 指令微调：在`train`上训练，在`val`上评估，训练结束后把模型保存到`output`。
+// 返回的`Trainer`包含微调后的模型和loss曲线
+pub fn finetune_instructions(
+    model: GPTModel,
+    train: &[InstructionExample],
+    val: &[InstructionExample],
+    tokenizer: &impl Tokenizer,
+    config: &FinetuneConfig,
+    output: impl AsRef<Path>,
+) -> Result<Trainer> {
+    let Some(pad_id) = config.pad_id.or(tokenizer.eos_id()) else {
+        return Err(InstructionError::NoPadToken);
+    };
+    if config.train.ignore_index != Some(IGNORE_INDEX) {
+        return Err(InstructionError::IgnoreIndex);
+    }
+
+    let collator = InstructionCollator::new(pad_id)
+        .with_mask_prompt(config.mask_prompt)
+        .with_allowed_max_length(model.config().context_length);
+    // 截断后没有目标的样本被去掉，计入`LoaderStats::filtered`，全部被去掉的批次不参与训练
+    let has_targets = {
+        let collator = collator.clone();
+        move |sample: &InstructionSample| collator.has_targets(sample)
+    };
+    let train_loader = DataLoader::builder()
+        .batch_size(config.batch_size)
+        .shuffle(true)
+        .seed(config.seed)
+        .drop_last(true)
+        .persistent_workers(true)
+        .build_with(
+            InstructionDataset::new(train, tokenizer)?.filter(has_targets.clone()),
+            collator.clone(),
+        );
+    let val_loader = DataLoader::builder()
+        .batch_size(config.batch_size)
+        .build_with(
+            InstructionDataset::new(val, tokenizer)?.filter(has_targets),
+            collator,
+        );
+    let val_batches = val_loader.iter().collect::<Result<Vec<_>, _>>()?;
+
+    let optimizer = AdamW::new(config.lr, config.weight_decay);
+    let mut trainer = Trainer::new(model, optimizer, config.train.clone());
+    trainer.train(&train_loader, &val_batches, tokenizer)?;
+    let filtered = train_loader.stats().filtered;
+    if filtered > 0 {
+        tracing::warn!("Skipped {filtered} examples per epoch whose response was truncated away");
+    }
+    trainer.model().save(output)?;
+    Ok(trainer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::char_tokenizer::CharTokenizer;
+    use crate::error::TestResult;
+    use model::GptConfig;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_instruction_finetuning() -> TestResult {
+        let examples = [
+            InstructionExample {
+                instruction: "Rewrite the sentence using a simile.".to_string(),
+                input: "The car is very fast.".to_string(),
+                output: "The car is as fast as lightning.".to_string(),
+            },
+            InstructionExample {
+                instruction: "Name a color.".to_string(),
+                input: String::new(),
+                output: "Red.".to_string(),
+            },
+        ];
+        let prompt = examples[1].prompt();
+        println!("{prompt}");
+        assert!(prompt.starts_with("Below is an instruction"));
+        assert!(prompt.ends_with("### Instruction:\nName a color.\n\n### Response:\n"));
+        assert!(examples[0]
+            .prompt()
+            .contains("\n\n### Input:\nThe car is very fast."));
+
+        let path = std::env::temp_dir().join("test_instruction_data.jsonl");
+        let jsonl = examples
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        fs::write(&path, jsonl.join("\n"))?;
+        assert_eq!(load_instruction_data(&path)?, examples);
+        fs::write(
+            &path,
+            r#"[{"instruction": "Name a color.", "output": "Red."}]"#,
+        )?;
+        assert_eq!(load_instruction_data(&path)?, examples[1..]);
+
+        // 样本[1, 2, 3]的prompt为前两个token
+        let collator = InstructionCollator::new(0);
+        let samples = vec![
+            InstructionSample {
+                token_ids: vec![5, 6, 7, 8, 9],
+                prompt_len: 3,
+            },
+            InstructionSample {
+                token_ids: vec![1, 2, 3],
+                prompt_len: 2,
+            },
+        ];
+        let batch = collator.collate(samples.clone());
+        assert_eq!(batch[0].feature, [5, 6, 7, 8, 9]);
+        assert_eq!(batch[0].label, [IGNORE_INDEX, IGNORE_INDEX, 8, 9, 0]);
+        assert_eq!(batch[1].feature, [1, 2, 3, 0, 0]);
+        assert_eq!(
+            batch[1].label,
+            [IGNORE_INDEX, 3, 0, IGNORE_INDEX, IGNORE_INDEX]
+        );
+        let batch = collator
+            .clone()
+            .with_mask_prompt(false)
+            .with_allowed_max_length(3)
+            .collate(samples.clone());
+        assert_eq!(batch[0].label, [6, 7, 8]);
+        assert_eq!(batch[1].feature, [1, 2, 3]);
+
+        // 截断到2个token后第一个样本的目标都属于prompt
+        let truncated = collator.clone().with_allowed_max_length(2);
+        assert!(!truncated.has_targets(&samples[0]));
+        assert!(truncated.has_targets(&samples[1]));
+        let batch = truncated.collate(samples.clone());
+        assert!(batch[0].label.iter().all(|&id| id == IGNORE_INDEX));
+        assert!(collator.has_targets(&samples[0]));
+
+        let text = examples
+            .iter()
+            .map(|example| example.prompt() + &example.output)
+            .collect::<String>();
+        let tokenizer = CharTokenizer::new(&text);
+        let config = GptConfig {
+            vocab_size: tokenizer.vocab_size(),
+            context_length: 256,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 1,
+            ..GptConfig::gpt2_small()
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let finetune_config = FinetuneConfig {
+            batch_size: 2,
+            lr: 1e-2,
+            pad_id: Some(0),
+            train: TrainConfig {
+                num_epochs: 3,
+                eval_freq: 1,
+                ..FinetuneConfig::default().train
+            },
+            ..FinetuneConfig::default()
+        };
+        let output = std::env::temp_dir().join("test_instruction_model.safetensors");
+        let trainer = finetune_instructions(
+            model,
+            &examples,
+            &examples,
+            &tokenizer,
+            &finetune_config,
+            &output,
+        )?;
+        let history = trainer.history();
+        println!("{:?}", history.val_losses);
+        assert_eq!(history.val_losses.len(), 3);
+        assert!(history.val_losses[2] < history.val_losses[0]);
+        let loaded = GPTModel::load(&output)?;
+        let mut params = loaded
+            .named_params()
+            .into_iter()
+            .zip(trainer.model().named_params());
+        assert!(params.all(|((_, a), (_, b))| a.value() == b.value()));
+
+        // prompt比上下文窗口长的样本被跳过，不会因为批次中没有目标而报错
+        let long = InstructionExample {
+            instruction: "a".repeat(config.context_length),
+            ..examples[1].clone()
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let one_by_one = FinetuneConfig {
+            batch_size: 1,
+            ..finetune_config.clone()
+        };
+        let train = [long, examples[0].clone()];
+        finetune_instructions(model, &train, &examples, &tokenizer, &one_by_one, &output)?;
+
+        let no_eos = FinetuneConfig {
+            pad_id: None,
+            ..finetune_config
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        assert!(
+            finetune_instructions(model, &examples, &examples, &tokenizer, &no_eos, &output)
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+pub mod bench;
+pub mod bpe;
+#[cfg(not(feature = "jieba"))]
+mod char_segmenter;
+pub mod char_tokenizer;
+pub mod chat;
+pub mod cli;
+pub mod config;
+pub mod contamination;
+pub mod corpus;
+pub mod dataset;
+pub mod early_stopping;
+pub mod error;
+pub mod eval;
+pub mod generate;
+pub mod gguf;
+pub mod hf_tokenizer;
+pub mod instruction;
+pub mod logging;
+pub mod loss;
+pub mod metrics;
+pub mod mmap_vocab;
+pub mod normalize;
+pub mod parallel;
+pub mod sentencepiece;
+pub mod simple_tokenizer;
+pub mod stats;
+#[cfg(feature = "tensorboard")]
+pub mod tensorboard;
+pub mod tokenizer;
+pub mod train;
+pub mod vocab;
+pub mod wordpiece;
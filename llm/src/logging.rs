@@ -0,0 +1,38 @@
+use std::io::IsTerminal;
+use tracing_subscriber::filter::ParseError;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+// `RUST_LOG`为tracing-subscriber的`EnvFilter`语法，如`info,data_loader=debug,llm::train=trace`，
+// 也可以按span过滤（`llm[epoch]=debug`）。为空时默认为`info`
+pub fn filter(spec: &str) -> Result<EnvFilter, ParseError> {
+    let spec = if spec.trim().is_empty() { "info" } else { spec };
+    EnvFilter::try_new(spec)
+}
+
+// 按`RUST_LOG`安装全局subscriber，只有第一次调用生效。
+// 写到stderr，不影响stdout上的训练进度和生成结果；span关闭时记录耗时
+pub fn init() -> Result<(), ParseError> {
+    let filter = filter(&std::env::var("RUST_LOG").unwrap_or_default())?;
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_span_events(FmtSpan::CLOSE)
+        .try_init();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logging() -> Result<(), ParseError> {
+        let filter = filter("warn,data_loader=debug,llm::train[epoch]=trace")?;
+        println!("{filter}");
+        assert_eq!(super::filter("")?.to_string(), "info");
+        assert!(super::filter("llm=loud").is_err());
+        Ok(())
+    }
+}
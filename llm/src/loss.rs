@@ -0,0 +1,247 @@
+use crate::error::TrainError;
+use data_loader::{DataLoader, PaddedBatch, TrainData};
+use model::{
+    cross_entropy, cross_entropy_with_grad, masked_cross_entropy, masked_cross_entropy_with_grad,
+    perplexity, GPTModel, Result,
+};
+
+// `DataLoader`的一个批次拆成模型的输入和目标
+pub(crate) fn split_batch(batch: &[TrainData<usize>]) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+    batch
+        .iter()
+        .map(|sample| (sample.feature.clone(), sample.label.clone()))
+        .unzip()
+}
+
+// 参与计算loss的目标token数
+pub(crate) fn count_tokens(batch: &[TrainData<usize>], ignore_index: Option<usize>) -> usize {
+    batch
+        .iter()
+        .flat_map(|sample| &sample.label)
+        .filter(|target| ignore_index != Some(**target))
+        .count()
+}
+
+// 书中第5章的`calc_loss_batch`：不保存反向传播需要的中间结果，也不使用dropout
+pub fn calc_loss_batch(
+    model: &GPTModel,
+    batch: &[TrainData<usize>],
+    ignore_index: Option<usize>,
+) -> Result<f32> {
+    let (inputs, targets) = split_batch(batch);
+    let logits = model.forward_with_cache(&inputs, &mut model.new_kv_cache())?;
+    cross_entropy(&logits, &targets, ignore_index)
+}
+
+// 训练时使用：计算一个批次的loss并反向传播，梯度累加到模型的参数上
+pub fn backward_batch(
+    model: &mut GPTModel,
+    batch: &[TrainData<usize>],
+    ignore_index: Option<usize>,
+) -> Result<f32> {
+    let (inputs, targets) = split_batch(batch);
+    let logits = model.forward(&inputs)?;
+    let (loss, grad) = cross_entropy_with_grad(&logits, &targets, ignore_index)?;
+    model.backward(&grad)?;
+    Ok(loss)
+}
+
+// `PadCollator`的批次：`loss_mask`为0的位置（填充的标签）不计入loss。
+// 模型是因果的，右侧的填充不影响真实token的logits
+pub fn calc_loss_padded(
+    model: &GPTModel,
+    batch: &PaddedBatch<usize>,
+    ignore_index: Option<usize>,
+) -> Result<f32> {
+    let logits = model.forward_with_cache(&batch.features, &mut model.new_kv_cache())?;
+    masked_cross_entropy(&logits, &batch.labels, Some(&batch.loss_mask), ignore_index)
+}
+
+// 填充位置没有梯度，不会让模型学习输出`<pad>`
+pub fn backward_padded(
+    model: &mut GPTModel,
+    batch: &PaddedBatch<usize>,
+    ignore_index: Option<usize>,
+) -> Result<f32> {
+    let logits = model.forward(&batch.features)?;
+    let (loss, grad) = masked_cross_entropy_with_grad(
+        &logits,
+        &batch.labels,
+        Some(&batch.loss_mask),
+        ignore_index,
+    )?;
+    model.backward(&grad)?;
+    Ok(loss)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Evaluation {
+    // 按token数加权的平均loss
+    pub loss: f32,
+    pub perplexity: f32,
+    // 参与计算的目标token数
+    pub tokens: usize,
+}
+
+// 在多个批次上评估模型，`num_batches`为`Some(n)`时只使用前`n`个批次。
+// 与`calc_loss_batch`相同，不使用dropout，不影响模型的训练状态
+pub fn evaluate<B: AsRef<[TrainData<usize>]>>(
+    model: &GPTModel,
+    batches: impl IntoIterator<Item = B>,
+    num_batches: Option<usize>,
+    ignore_index: Option<usize>,
+) -> Result<Evaluation, TrainError> {
+    let (mut sum, mut count) = (0.0, 0);
+    for batch in batches.into_iter().take(num_batches.unwrap_or(usize::MAX)) {
+        let batch = batch.as_ref();
+        let tokens = count_tokens(batch, ignore_index);
+        if tokens == 0 {
+            continue;
+        }
+        sum += calc_loss_batch(model, batch, ignore_index)? as f64 * tokens as f64;
+        count += tokens;
+    }
+
+    if count == 0 {
+        return Err(TrainError::NoEvalTokens);
+    }
+    let loss = (sum / count as f64) as f32;
+    Ok(Evaluation {
+        loss,
+        perplexity: perplexity(loss),
+        tokens: count,
+    })
+}
+
+// 书中第5章的`calc_loss_loader`：取`loader`的前`num_batches`个批次评估。
+// 持久化的`loader`没有取完的批次会在下一次`iter`时继续返回
+pub fn calc_loss_loader(
+    model: &GPTModel,
+    loader: &DataLoader<Vec<TrainData<usize>>>,
+    num_batches: Option<usize>,
+    ignore_index: Option<usize>,
+) -> Result<Evaluation, TrainError> {
+    let batches = loader
+        .iter()
+        .take(num_batches.unwrap_or(usize::MAX))
+        .collect::<Result<Vec<_>, _>>()?;
+    evaluate(model, batches, None, ignore_index)
+}
+
+// 多个批次的困惑度，每个批次按参与计算的token数加权
+pub fn calc_perplexity<B: AsRef<[TrainData<usize>]>>(
+    model: &GPTModel,
+    batches: impl IntoIterator<Item = B>,
+    ignore_index: Option<usize>,
+) -> Result<f32, TrainError> {
+    Ok(evaluate(model, batches, None, ignore_index)?.perplexity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TestResult;
+    use data_loader::{Collate, GPTDataset, PadCollator};
+    use model::GptConfig;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_calc_loss_batch() -> TestResult {
+        let config = GptConfig {
+            vocab_size: 16,
+            context_length: 8,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 2,
+            dropout: 0.0,
+            ..GptConfig::gpt2_small()
+        };
+        let mut model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let token_ids = (0..64).map(|i| (i * 7) % 16).collect::<Vec<_>>();
+        let loader = DataLoader::new(GPTDataset::new(token_ids.clone(), 4, 4), 2, false, 1, false);
+        let batches = loader.iter().collect::<Result<Vec<_>, _>>()?;
+
+        // 未训练的模型的loss接近ln(16)
+        let loss = calc_loss_batch(&model, &batches[0], None)?;
+        println!("loss: {loss}");
+        assert!((loss - 16f32.ln()).abs() < 1.0);
+
+        assert!((backward_batch(&mut model, &batches[0], None)? - loss).abs() < 1e-5);
+        let grad = model.tok_emb().grad();
+        assert!(grad.data().iter().any(|g| *g != 0.0));
+
+        let ppl = calc_perplexity(&model, &batches, None)?;
+        println!("perplexity: {ppl}");
+        assert!(ppl > 1.0 && ppl < 64.0);
+        assert!((calc_perplexity(&model, &batches[..1], None)? - perplexity(loss)).abs() < 1e-4);
+        assert!(calc_perplexity(&model, Vec::<Vec<TrainData<usize>>>::new(), None).is_err());
+
+        // 只评估前两个批次
+        let loader = DataLoader::new(GPTDataset::new(token_ids, 4, 4), 2, false, 1, false);
+        let evaluation = calc_loss_loader(&model, &loader, Some(2), None)?;
+        println!("{evaluation:?}");
+        let expected = evaluate(&model, &batches[..2], None, None)?;
+        assert_eq!(evaluation, expected);
+        assert_eq!(evaluation.tokens, 2 * 2 * 4);
+        assert!((evaluation.perplexity - evaluation.loss.exp()).abs() < 1e-4);
+        assert_eq!(evaluate(&model, &batches, Some(2), None)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_padded_loss() -> TestResult {
+        let config = GptConfig {
+            vocab_size: 16,
+            context_length: 8,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 2,
+            dropout: 0.0,
+            ..GptConfig::gpt2_small()
+        };
+        let mut model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let samples = [5, 2, 4]
+            .iter()
+            .map(|&len| TrainData {
+                feature: (0..len).map(|i| (i * 3 + len) % 15).collect(),
+                // 第三个样本的prompt部分用`ignore_index`屏蔽
+                label: (0..len)
+                    .map(|i| {
+                        if len == 4 && i < 2 {
+                            15
+                        } else {
+                            (i + len) % 15
+                        }
+                    })
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        let batch = PadCollator::new(0)
+            .with_label_pad_id(usize::MAX)
+            .collate(samples.clone());
+
+        // 与逐个样本计算、按token数加权的结果相同
+        let expected = evaluate(&model, samples.chunks(1), None, Some(15))?;
+        let loss = calc_loss_padded(&model, &batch, Some(15))?;
+        println!("padded loss: {loss} {}", expected.loss);
+        assert!((loss - expected.loss).abs() < 1e-5);
+
+        assert!((backward_padded(&mut model, &batch, Some(15))? - loss).abs() < 1e-5);
+        let padded_grad = model.tok_emb().grad().clone();
+        model.zero_grad();
+        for sample in &samples {
+            let tokens = count_tokens(std::slice::from_ref(sample), Some(15)) as f32;
+            let (inputs, targets) = split_batch(std::slice::from_ref(sample));
+            let logits = model.forward(&inputs)?;
+            let (_, mut grad) = cross_entropy_with_grad(&logits, &targets, Some(15))?;
+            let weight = tokens / expected.tokens as f32;
+            grad.data_mut().iter_mut().for_each(|g| *g *= weight);
+            model.backward(&grad)?;
+        }
+        for (a, b) in padded_grad.data().iter().zip(model.tok_emb().grad().data()) {
+            assert!((a - b).abs() < 1e-5, "{a} vs {b}");
+        }
+        Ok(())
+    }
+}
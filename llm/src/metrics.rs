@@ -0,0 +1,179 @@
+use crate::error::{file_error, MetricsError};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[cfg(feature = "tensorboard")]
+use crate::tensorboard::EventWriter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricsFormat {
+    Csv,
+    Jsonl,
+}
+
+// 一次优化器更新后的指标，`val_loss`只在评估的那一步有值
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Metrics {
+    pub step: u64,
+    pub epoch: usize,
+    pub train_loss: f32,
+    pub val_loss: Option<f32>,
+    pub lr: f32,
+    pub tokens_per_sec: f64,
+    // 训练集`DataLoader`在当前epoch的`LoaderStats`，填充token为到这一步为止的累计值
+    pub padding_tokens: usize,
+    pub dropped_tokens: usize,
+}
+
+const CSV_HEADER: &str =
+    "step,epoch,train_loss,val_loss,lr,tokens_per_sec,padding_tokens,dropped_tokens";
+
+// 把训练指标逐行写入CSV或JSONL文件，便于比较和绘制不同的训练。
+// 打开`tensorboard` feature后可以同时写入TensorBoard的事件文件
+pub struct MetricsLogger {
+    writer: BufWriter<File>,
+    format: MetricsFormat,
+    #[cfg(feature = "tensorboard")]
+    tensorboard: Option<EventWriter>,
+}
+
+impl MetricsLogger {
+    // 覆盖已有的文件，CSV格式先写入表头
+    pub fn create(path: impl AsRef<Path>, format: MetricsFormat) -> Result<Self, MetricsError> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(file_error("create", path))?;
+        let mut writer = BufWriter::new(file);
+        if format == MetricsFormat::Csv {
+            writeln!(writer, "{CSV_HEADER}")?;
+        }
+
+        Ok(MetricsLogger {
+            writer,
+            format,
+            #[cfg(feature = "tensorboard")]
+            tensorboard: None,
+        })
+    }
+
+    // 在`dir`中创建`events.out.tfevents.*`文件，用`tensorboard --logdir`查看
+    #[cfg(feature = "tensorboard")]
+    pub fn with_tensorboard(mut self, dir: impl AsRef<Path>) -> Result<Self, MetricsError> {
+        self.tensorboard = Some(EventWriter::create(dir)?);
+        Ok(self)
+    }
+
+    pub fn format(&self) -> MetricsFormat {
+        self.format
+    }
+
+    pub fn log(&mut self, metrics: &Metrics) -> Result<(), MetricsError> {
+        match self.format {
+            MetricsFormat::Csv => {
+                let val_loss = metrics.val_loss.map(|loss| loss.to_string());
+                writeln!(
+                    self.writer,
+                    "{},{},{},{},{},{},{},{}",
+                    metrics.step,
+                    metrics.epoch,
+                    metrics.train_loss,
+                    val_loss.unwrap_or_default(),
+                    metrics.lr,
+                    metrics.tokens_per_sec,
+                    metrics.padding_tokens,
+                    metrics.dropped_tokens
+                )?;
+            }
+            MetricsFormat::Jsonl => {
+                serde_json::to_writer(&mut self.writer, metrics)?;
+                writeln!(self.writer)?;
+            }
+        }
+
+        #[cfg(feature = "tensorboard")]
+        if let Some(tensorboard) = self.tensorboard.as_mut() {
+            let step = metrics.step as i64;
+            tensorboard.add_scalar("loss/train", metrics.train_loss, step)?;
+            if let Some(val_loss) = metrics.val_loss {
+                tensorboard.add_scalar("loss/val", val_loss, step)?;
+            }
+            tensorboard.add_scalar("lr", metrics.lr, step)?;
+            tensorboard.add_scalar("tokens_per_sec", metrics.tokens_per_sec as f32, step)?;
+            tensorboard.add_scalar("data/padding_tokens", metrics.padding_tokens as f32, step)?;
+            tensorboard.add_scalar("data/dropped_tokens", metrics.dropped_tokens as f32, step)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), MetricsError> {
+        self.writer.flush()?;
+        #[cfg(feature = "tensorboard")]
+        if let Some(tensorboard) = self.tensorboard.as_mut() {
+            tensorboard.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_metrics_logger() -> Result<(), MetricsError> {
+        let metrics = [
+            Metrics {
+                step: 1,
+                epoch: 0,
+                train_loss: 2.5,
+                val_loss: Some(2.75),
+                lr: 1e-4,
+                tokens_per_sec: 1000.0,
+                padding_tokens: 0,
+                dropped_tokens: 3,
+            },
+            Metrics {
+                step: 2,
+                epoch: 0,
+                train_loss: 2.25,
+                val_loss: None,
+                lr: 2e-4,
+                tokens_per_sec: 1200.5,
+                padding_tokens: 7,
+                dropped_tokens: 3,
+            },
+        ];
+
+        let path = std::env::temp_dir().join("test_metrics.csv");
+        let mut logger = MetricsLogger::create(&path, MetricsFormat::Csv)?;
+        metrics.iter().try_for_each(|metrics| logger.log(metrics))?;
+        logger.flush()?;
+        let csv = fs::read_to_string(&path)?;
+        println!("{csv}");
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            [
+                CSV_HEADER,
+                "1,0,2.5,2.75,0.0001,1000,0,3",
+                "2,0,2.25,,0.0002,1200.5,7,3"
+            ]
+        );
+
+        let path = std::env::temp_dir().join("test_metrics.jsonl");
+        let mut logger = MetricsLogger::create(&path, MetricsFormat::Jsonl)?;
+        metrics.iter().try_for_each(|metrics| logger.log(metrics))?;
+        drop(logger);
+        let lines = fs::read_to_string(&path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["val_loss"], 2.75);
+        assert!(lines[1]["val_loss"].is_null());
+        assert_eq!(lines[1]["tokens_per_sec"], 1200.5);
+        assert_eq!(lines[1]["dropped_tokens"], 3);
+        Ok(())
+    }
+}
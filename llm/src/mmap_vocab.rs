@@ -0,0 +1,377 @@
+use crate::error::{invalid, read_error, unsupported, write_error, Result};
+use crate::vocab::{
+    byte_token, decode_tiktoken, decode_tokens, decode_with_special, encode_tiktoken,
+    split_special, tokenize_chinese, CutMode, Encoding, Segment, SentenceType, Vocabulary,
+    UNKNOWN_TOKEN,
+};
+use memmap2::Mmap;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"VOCB";
+const VERSION: u32 = 2;
+const HEADER_BYTES: usize = 32;
+
+// 文件中保存编码在该数组中的下标，旧文件的0字节对应默认的`Cl100kBase`
+const ENCODINGS: [Encoding; 5] = [
+    Encoding::Cl100kBase,
+    Encoding::Gpt2,
+    Encoding::R50kBase,
+    Encoding::P50kBase,
+    Encoding::O200kBase,
+];
+
+// 二进制词表格式（小端序）：
+// header: magic, version(u32), sentence_type(u8), byte_fallback(u8), encoding(u8, 填充到8字节),
+//         max_id(u64), count(u64)
+// offsets: count + 1个u64，第id个token在blob中的范围为offsets[id]..offsets[id + 1]
+// sorted: count个u32，按token字节序排列的id，编码时二分查找
+// blob: 按id依次拼接的UTF-8 token
+// specials（版本2）: count(u64)，之后每个特殊token为id(u64), len(u64), UTF-8字节
+impl Vocabulary {
+    pub fn save_binary(&self, path: impl AsRef<Path>) -> Result<()> {
+        if self.cut_mode() != CutMode::Exact || self.has_user_dict() {
+            return Err(unsupported!(
+                "Binary format only supports the default jieba dictionary and exact cut mode"
+            ));
+        }
+        if !self.normalizer().is_identity() {
+            return Err(unsupported!(
+                "Binary format does not support text normalization"
+            ));
+        }
+
+        let path = path.as_ref();
+        let file = File::create(path).map_err(write_error(path))?;
+        let mut writer = BufWriter::new(file);
+
+        let tokens = self.tokens();
+        let sentence_type = match self.sentence_type() {
+            SentenceType::English => 0u8,
+            SentenceType::Chinese => 1u8,
+            SentenceType::SentencePiece | SentenceType::HuggingFace | SentenceType::Mixed => {
+                return Err(unsupported!(
+                    "Binary format does not support {:?} vocabularies",
+                    self.sentence_type()
+                ))
+            }
+        };
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        let byte_fallback = self.byte_fallback() as u8;
+        let encoding = ENCODINGS
+            .iter()
+            .position(|&e| e == self.encoding())
+            .unwrap() as u8;
+        writer.write_all(&[sentence_type, byte_fallback, encoding, 0, 0, 0, 0, 0])?;
+        writer.write_all(&(self.len() as u64).to_le_bytes())?;
+        writer.write_all(&(tokens.len() as u64).to_le_bytes())?;
+
+        let mut offset = 0u64;
+        writer.write_all(&offset.to_le_bytes())?;
+        for token in tokens {
+            offset += token.len() as u64;
+            writer.write_all(&offset.to_le_bytes())?;
+        }
+
+        let mut sorted: Vec<u32> = (0..tokens.len() as u32).collect();
+        sorted.sort_by(|&a, &b| {
+            tokens[a as usize]
+                .as_bytes()
+                .cmp(tokens[b as usize].as_bytes())
+        });
+        for id in sorted {
+            writer.write_all(&id.to_le_bytes())?;
+        }
+
+        for token in tokens {
+            writer.write_all(token.as_bytes())?;
+        }
+
+        let specials = self.special_tokens();
+        writer.write_all(&(specials.len() as u64).to_le_bytes())?;
+        for (token, id) in specials {
+            writer.write_all(&(*id as u64).to_le_bytes())?;
+            writer.write_all(&(token.len() as u64).to_le_bytes())?;
+            writer.write_all(token.as_bytes())?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// 直接在映射的文件上编解码，打开时不需要反序列化或重建哈希表
+pub struct MmapVocabulary {
+    mmap: Mmap,
+    sentence_type: SentenceType,
+    encoding: Encoding,
+    max_id: usize,
+    count: usize,
+    unknown_id: Option<usize>,
+    byte_start: Option<usize>,
+    special_tokens: Vec<(String, usize)>,
+}
+
+impl MmapVocabulary {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(read_error(path))?;
+
+        // SAFETY: 文件以只读方式映射，使用期间不应被其他进程修改
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        if mmap.len() < HEADER_BYTES || &mmap[..4] != MAGIC {
+            return Err(invalid!("Not a binary vocabulary file: {}", path.display()));
+        }
+        let version = u32::from_le_bytes(mmap[4..8].try_into().unwrap());
+        if version == 0 || version > VERSION {
+            return Err(invalid!(
+                "Unsupported vocabulary version in {}",
+                path.display()
+            ));
+        }
+
+        let sentence_type = match mmap[8] {
+            0 => SentenceType::English,
+            1 => SentenceType::Chinese,
+            _ => return Err(invalid!("Invalid sentence type in {}", path.display())),
+        };
+
+        let encoding = *ENCODINGS
+            .get(mmap[10] as usize)
+            .ok_or_else(|| invalid!("Invalid encoding in {}", path.display()))?;
+
+        let mut vocab = MmapVocabulary {
+            max_id: read_u64(&mmap, 16) as usize,
+            count: read_u64(&mmap, 24) as usize,
+            mmap,
+            sentence_type,
+            encoding,
+            unknown_id: None,
+            byte_start: None,
+            special_tokens: vec![],
+        };
+
+        let blob_end = vocab.blob_start() + vocab.offset(vocab.count);
+        if version == 1 && vocab.mmap.len() != blob_end {
+            return Err(invalid!("Truncated vocabulary file: {}", path.display()));
+        }
+        if version >= 2 {
+            vocab.special_tokens = read_specials(&vocab.mmap, blob_end)
+                .ok_or_else(|| invalid!("Truncated vocabulary file: {}", path.display()))?;
+        }
+
+        // 旧版本保存的英文词表`max_id`是最后一次编码时的最大id
+        if vocab.sentence_type == SentenceType::English {
+            vocab.max_id = vocab.encoding.n_vocab() + vocab.special_tokens.len();
+        }
+        vocab.unknown_id = vocab.get_id(UNKNOWN_TOKEN);
+        if vocab.mmap[9] == 1 {
+            vocab.byte_start = vocab.get_id(&byte_token(0));
+            if vocab.byte_start.is_none() {
+                return Err(invalid!("Byte tokens not in {}", path.display()));
+            }
+        }
+        if vocab.sentence_type == SentenceType::Chinese && vocab.unknown_id.is_none() {
+            return Err(invalid!("Unknown token not in {}", path.display()));
+        }
+
+        Ok(vocab)
+    }
+
+    pub fn len(&self) -> usize {
+        self.max_id
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max_id == 0
+    }
+
+    pub fn sentence_type(&self) -> &SentenceType {
+        &self.sentence_type
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn special_tokens(&self) -> &[(String, usize)] {
+        &self.special_tokens
+    }
+
+    pub fn encode(&self, sentence: &str) -> Result<Vec<usize>> {
+        let mut token_ids = vec![];
+
+        for segment in split_special(sentence, &self.special_tokens) {
+            let text = match segment {
+                Segment::Special(id) => {
+                    token_ids.push(id);
+                    continue;
+                }
+                Segment::Text(text) => text,
+            };
+
+            match self.sentence_type {
+                SentenceType::English => token_ids.extend(encode_tiktoken(self.encoding, text)?),
+                SentenceType::Chinese => {
+                    for token in tokenize_chinese(text) {
+                        match (self.get_id(token), self.byte_start) {
+                            (Some(id), _) => token_ids.push(id),
+                            (None, Some(start)) => {
+                                token_ids.extend(token.bytes().map(|b| start + b as usize));
+                            }
+                            (None, None) => token_ids.push(self.unknown_id.unwrap()),
+                        }
+                    }
+                }
+                SentenceType::SentencePiece | SentenceType::HuggingFace | SentenceType::Mixed => {
+                    unreachable!()
+                }
+            }
+        }
+
+        Ok(token_ids)
+    }
+
+    pub fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        self.decode_with(token_ids, false)
+    }
+
+    pub fn decode_skip_special(&self, token_ids: &[usize]) -> Result<String> {
+        self.decode_with(token_ids, true)
+    }
+
+    fn decode_with(&self, token_ids: &[usize], skip_special: bool) -> Result<String> {
+        match self.sentence_type {
+            SentenceType::English => {
+                decode_with_special(token_ids, &self.special_tokens, skip_special, |ids| {
+                    decode_tiktoken(self.encoding, ids)
+                })
+            }
+            SentenceType::Chinese => {
+                let token_ids: Vec<usize> = token_ids
+                    .iter()
+                    .copied()
+                    .filter(|&id| {
+                        !(skip_special && self.special_tokens.iter().any(|(_, s)| *s == id))
+                    })
+                    .collect();
+                Ok(decode_tokens(&token_ids, self.byte_start, |id| {
+                    self.get_token(id)
+                }))
+            }
+            SentenceType::SentencePiece | SentenceType::HuggingFace | SentenceType::Mixed => {
+                unreachable!()
+            }
+        }
+    }
+
+    pub fn get_token(&self, id: usize) -> Option<&str> {
+        if id >= self.count {
+            return None;
+        }
+
+        let start = self.blob_start() + self.offset(id);
+        let end = self.blob_start() + self.offset(id + 1);
+        std::str::from_utf8(&self.mmap[start..end]).ok()
+    }
+
+    pub fn get_id(&self, token: &str) -> Option<usize> {
+        let (mut low, mut high) = (0, self.count);
+
+        while low < high {
+            let mid = (low + high) / 2;
+            let id = self.sorted_id(mid);
+            let candidate = self.get_token(id).unwrap_or_default();
+
+            match candidate.as_bytes().cmp(token.as_bytes()) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Some(id),
+            }
+        }
+
+        None
+    }
+
+    fn offset(&self, index: usize) -> usize {
+        read_u64(&self.mmap, HEADER_BYTES + index * 8) as usize
+    }
+
+    fn sorted_id(&self, index: usize) -> usize {
+        let start = HEADER_BYTES + (self.count + 1) * 8 + index * 4;
+        u32::from_le_bytes(self.mmap[start..start + 4].try_into().unwrap()) as usize
+    }
+
+    fn blob_start(&self) -> usize {
+        HEADER_BYTES + (self.count + 1) * 8 + self.count * 4
+    }
+}
+
+fn read_u64(bytes: &[u8], start: usize) -> u64 {
+    u64::from_le_bytes(bytes[start..start + 8].try_into().unwrap())
+}
+
+fn read_specials(bytes: &[u8], start: usize) -> Option<Vec<(String, usize)>> {
+    let read = |pos: usize| -> Option<u64> {
+        let bytes = bytes.get(pos..pos + 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+    };
+
+    let count = read(start)? as usize;
+    let mut pos = start + 8;
+    let mut specials = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let id = read(pos)? as usize;
+        let len = read(pos + 8)? as usize;
+        let token = std::str::from_utf8(bytes.get(pos + 16..pos + 16 + len)?).ok()?;
+        specials.push((token.to_string(), id));
+        pos += 16 + len;
+    }
+
+    (pos == bytes.len()).then_some(specials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mmap_vocab() {
+        let text = "这是一个例子。那是另一个例子。";
+        let mut vocab = Vocabulary::new(text, SentenceType::Chinese)
+            .unwrap()
+            .with_byte_fallback();
+        let im_start = vocab.add_special_token("<|im_start|>");
+
+        let path = std::env::temp_dir().join("test_mmap_vocab.bin");
+        vocab.save_binary(&path).unwrap();
+        let mmap_vocab = MmapVocabulary::open(&path).unwrap();
+
+        let token_ids = mmap_vocab.encode(text).unwrap();
+        println!("{:?}", token_ids);
+        assert_eq!(token_ids, vocab.encode(text).unwrap());
+        assert_eq!(mmap_vocab.decode(&token_ids).unwrap(), text);
+        assert_eq!(mmap_vocab.len(), vocab.len());
+        assert_eq!(mmap_vocab.get_id(UNKNOWN_TOKEN), Some(0));
+        assert_eq!(mmap_vocab.get_id("不存在的词"), None);
+
+        let unknown = "苹果和香蕉";
+        let token_ids = mmap_vocab.encode(unknown).unwrap();
+        assert_eq!(token_ids, vocab.encode(unknown).unwrap());
+        assert_eq!(mmap_vocab.decode(&token_ids).unwrap(), unknown);
+
+        let prompt = "<|im_start|>这是<eof>";
+        let token_ids = mmap_vocab.encode(prompt).unwrap();
+        assert_eq!(token_ids[0], im_start);
+        assert_eq!(token_ids, vocab.encode(prompt).unwrap());
+        assert_eq!(mmap_vocab.decode_skip_special(&token_ids).unwrap(), "这是");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum UnicodeForm {
+    #[default]
+    None,
+    Nfc,
+    // 兼容分解后再组合，例如`ﬁ`变为`fi`、全角字母变为半角
+    Nfkc,
+}
+
+// 分词前的文本规范化，依次执行：Unicode规范化、全角转半角、小写、合并空白。
+// 保存在词表文件中，训练和推理时的编码保持一致
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextNormalizer {
+    unicode: UnicodeForm,
+    full_width_to_half_width: bool,
+    lowercase: bool,
+    collapse_whitespace: bool,
+}
+
+impl TextNormalizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn unicode(mut self, form: UnicodeForm) -> Self {
+        self.unicode = form;
+        self
+    }
+
+    // 全角ASCII字符（如`Ａ`、`１`、`，`）和全角空格转为半角，`。`、`、`等中文标点保持不变
+    pub fn full_width_to_half_width(mut self, enable: bool) -> Self {
+        self.full_width_to_half_width = enable;
+        self
+    }
+
+    pub fn lowercase(mut self, enable: bool) -> Self {
+        self.lowercase = enable;
+        self
+    }
+
+    // 连续的空白合并为一个，包含换行时保留为`\n`，否则为空格
+    pub fn collapse_whitespace(mut self, enable: bool) -> Self {
+        self.collapse_whitespace = enable;
+        self
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn normalize<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.is_identity() {
+            return Cow::Borrowed(text);
+        }
+
+        let mut text: String = match self.unicode {
+            UnicodeForm::None => text.to_string(),
+            UnicodeForm::Nfc => text.nfc().collect(),
+            UnicodeForm::Nfkc => text.nfkc().collect(),
+        };
+
+        if self.full_width_to_half_width {
+            text = text.chars().map(half_width).collect();
+        }
+        if self.lowercase {
+            text = text.to_lowercase();
+        }
+        if self.collapse_whitespace {
+            text = collapse_whitespace(&text);
+        }
+
+        Cow::Owned(text)
+    }
+}
+
+fn half_width(c: char) -> char {
+    match c as u32 {
+        0x3000 => ' ',
+        code @ 0xFF01..=0xFF5E => char::from_u32(code - 0xFEE0).unwrap(),
+        _ => c,
+    }
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut run: Option<bool> = None;
+
+    for c in text.chars() {
+        if c.is_whitespace() {
+            run = Some(run.unwrap_or(false) || c == '\n');
+            continue;
+        }
+        if let Some(newline) = run.take() {
+            result.push(if newline { '\n' } else { ' ' });
+        }
+        result.push(c);
+    }
+    if let Some(newline) = run {
+        result.push(if newline { '\n' } else { ' ' });
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_normalizer() {
+        let normalizer = TextNormalizer::new();
+        assert!(normalizer.is_identity());
+        assert!(matches!(
+            normalizer.normalize("ＡＢ"),
+            Cow::Borrowed("ＡＢ")
+        ));
+
+        let normalizer = TextNormalizer::new()
+            .full_width_to_half_width(true)
+            .lowercase(true)
+            .collapse_whitespace(true);
+        assert_eq!(
+            normalizer.normalize("Ｈｅｌｌｏ，　ＷＯＲＬＤ！  你好。\n\n 再见"),
+            "hello, world! 你好。\n再见"
+        );
+
+        // `e`加组合重音符号组合为`é`
+        let nfc = TextNormalizer::new().unicode(UnicodeForm::Nfc);
+        assert_eq!(nfc.normalize("e\u{301}"), "é");
+        let nfkc = TextNormalizer::new().unicode(UnicodeForm::Nfkc);
+        assert_eq!(nfkc.normalize("ﬁ１"), "fi1");
+    }
+}
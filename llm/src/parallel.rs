@@ -0,0 +1,387 @@
+use crate::error::ParallelError;
+use crate::loss::count_tokens;
+use crate::train::{accumulate_grads, apply_grads, count_total_tokens, StepOutput, TrainConfig};
+use data_loader::TrainData;
+use model::{AdamW, GPTModel, GradScaler, LrScheduler, Result};
+use std::any::Any;
+use std::thread;
+
+// 单机多线程的数据并行，相当于PyTorch的`DistributedDataParallel`：每个线程持有一份模型副本，
+// 对自己的分片做前向和反向传播，所有副本的梯度按token数加权汇总到主模型后由一个优化器更新。
+// 每一步开始时把主模型的参数复制到副本，保证副本与主模型一致
+#[derive(Debug, Clone)]
+pub struct DataParallel {
+    replicas: Vec<GPTModel>,
+}
+
+impl DataParallel {
+    pub fn new(model: &GPTModel, num_replicas: usize) -> Self {
+        assert!(
+            num_replicas > 0,
+            "Number of replicas must be greater than 0"
+        );
+        DataParallel {
+            replicas: vec![model.clone(); num_replicas],
+        }
+    }
+
+    pub fn num_replicas(&self) -> usize {
+        self.replicas.len()
+    }
+
+    // 每个副本的dropout使用不同的随机数
+    pub fn seed_dropout(&mut self, seed: u64) {
+        for (rank, replica) in self.replicas.iter_mut().enumerate() {
+            replica.seed_dropout(seed.wrapping_add((rank as u64) << 32));
+        }
+    }
+
+    // 与`train_step`相同，每个micro-batch按`shard_batch`平均分给所有副本
+    pub fn train_step<B: AsRef<[TrainData<usize>]>>(
+        &mut self,
+        model: &mut GPTModel,
+        optimizer: &mut AdamW,
+        scheduler: Option<&dyn LrScheduler>,
+        grad_scaler: Option<&mut GradScaler>,
+        micro_batches: &[B],
+        config: &TrainConfig,
+    ) -> Result<StepOutput, ParallelError> {
+        let mut shards = vec![vec![]; self.num_replicas()];
+        for batch in micro_batches {
+            for (rank, shard) in shard_batch(batch.as_ref(), shards.len())
+                .into_iter()
+                .enumerate()
+            {
+                shards[rank].push(shard);
+            }
+        }
+        self.train_step_sharded(model, optimizer, scheduler, grad_scaler, &shards, config)
+    }
+
+    // `shards[rank]`为第`rank`个副本的micro-batch，可以直接使用`DataLoaderBuilder::distributed(rank, world_size)`
+    // 构建的各个`DataLoader`的批次。所有分片的梯度按token数加权，结果与把所有分片拼在一起调用`train_step`相同
+    pub fn train_step_sharded<B: AsRef<[TrainData<usize>]> + Sync>(
+        &mut self,
+        model: &mut GPTModel,
+        optimizer: &mut AdamW,
+        scheduler: Option<&dyn LrScheduler>,
+        grad_scaler: Option<&mut GradScaler>,
+        shards: &[Vec<B>],
+        config: &TrainConfig,
+    ) -> Result<StepOutput, ParallelError> {
+        if shards.len() != self.num_replicas() {
+            return Err(ParallelError::Shards {
+                expected: self.num_replicas(),
+                actual: shards.len(),
+            });
+        }
+        let all = shards
+            .iter()
+            .flatten()
+            .map(AsRef::as_ref)
+            .collect::<Vec<_>>();
+        let total_tokens = count_total_tokens(&all, config.ignore_index);
+        if total_tokens == 0 {
+            return Err(ParallelError::NoTokens);
+        }
+        let scale = grad_scaler
+            .as_ref()
+            .map_or(1.0, |grad_scaler| grad_scaler.scale());
+
+        self.sync(model);
+        let losses = thread::scope(|scope| {
+            let handles = self
+                .replicas
+                .iter_mut()
+                .zip(shards)
+                .map(|(replica, shard)| {
+                    let batches = shard.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+                    scope.spawn(move || {
+                        replica.zero_grad();
+                        if batches
+                            .iter()
+                            .all(|batch| count_tokens(batch, config.ignore_index) == 0)
+                        {
+                            return Ok(0.0);
+                        }
+                        accumulate_grads(replica, &batches, total_tokens, scale, config)
+                    })
+                })
+                .collect::<Vec<_>>();
+            // 某个副本panic时返回错误，不让主线程跟着panic。先join所有线程，未join的线程panic时`scope`也会panic
+            let results = handles
+                .into_iter()
+                .map(|handle| handle.join())
+                .collect::<Vec<_>>();
+            results
+                .into_iter()
+                .enumerate()
+                .map(|(rank, result)| match result {
+                    Ok(loss) => Ok(loss?),
+                    Err(payload) => Err(ParallelError::WorkerPanicked {
+                        rank,
+                        message: panic_message(payload),
+                    }),
+                })
+                .collect::<Result<Vec<_>, ParallelError>>()
+        })?;
+
+        // 汇总梯度，每个副本的梯度已经按token数占比缩放，直接相加
+        model.zero_grad();
+        for replica in &self.replicas {
+            for ((_, param), (_, replica_param)) in model
+                .named_params_mut()
+                .into_iter()
+                .zip(replica.named_params())
+            {
+                if !param.requires_grad() {
+                    continue;
+                }
+                for (g, r) in param
+                    .grad_mut()
+                    .data_mut()
+                    .iter_mut()
+                    .zip(replica_param.grad().data())
+                {
+                    *g += r;
+                }
+            }
+        }
+
+        let updated = apply_grads(model, optimizer, scheduler, grad_scaler, config);
+        Ok(StepOutput {
+            loss: losses.iter().sum(),
+            updated,
+        })
+    }
+
+    // 复制主模型的参数。结构不同时（如之后加入了LoRA）重新克隆
+    fn sync(&mut self, model: &GPTModel) {
+        let names = model
+            .named_params()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        for replica in self.replicas.iter_mut() {
+            let same = replica
+                .named_params()
+                .into_iter()
+                .map(|(name, _)| name)
+                .eq(names.iter().cloned());
+            if !same {
+                *replica = model.clone();
+                continue;
+            }
+
+            for ((_, param), (_, replica_param)) in model
+                .named_params()
+                .into_iter()
+                .zip(replica.named_params_mut())
+            {
+                replica_param
+                    .value_mut()
+                    .data_mut()
+                    .copy_from_slice(param.value().data());
+                replica_param.set_requires_grad(param.requires_grad());
+            }
+            if model.is_training() {
+                replica.train();
+            } else {
+                replica.eval();
+            }
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>().map_or_else(
+            || "unknown panic".to_string(),
+            |message| message.to_string(),
+        ),
+    }
+}
+
+// 把批次按顺序平均分成`num_shards`份，前`len % num_shards`份多一个样本，样本数不足时后面的分片为空
+pub fn shard_batch<T>(batch: &[T], num_shards: usize) -> Vec<&[T]> {
+    assert!(num_shards > 0, "Number of shards must be greater than 0");
+    let (base, rem) = (batch.len() / num_shards, batch.len() % num_shards);
+    let mut start = 0;
+    (0..num_shards)
+        .map(|rank| {
+            let len = base + usize::from(rank < rem);
+            let shard = &batch[start..start + len];
+            start += len;
+            shard
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TestResult;
+    use crate::train::train_step;
+    use data_loader::{DataLoader, DataLoaderError, GPTDataset};
+    use model::{GptConfig, Precision};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn assert_close(a: &GPTModel, b: &GPTModel) {
+        for ((name, x), (_, y)) in a.named_params().into_iter().zip(b.named_params()) {
+            for (x, y) in x.value().data().iter().zip(y.value().data()) {
+                assert!((x - y).abs() < 1e-5, "{name}: {x} vs {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_data_parallel() -> TestResult {
+        let sizes = |shards: Vec<&[usize]>| shards.iter().map(|s| s.len()).collect::<Vec<_>>();
+        assert_eq!(sizes(shard_batch(&[0; 5], 3)), [2, 2, 1]);
+        assert_eq!(sizes(shard_batch(&[0; 2], 3)), [1, 1, 0]);
+
+        let config = GptConfig {
+            vocab_size: 16,
+            context_length: 8,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 1,
+            dropout: 0.0,
+            ..GptConfig::gpt2_small()
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let train_config = TrainConfig {
+            ignore_index: Some(15),
+            max_grad_norm: Some(1.0),
+            ..TrainConfig::default()
+        };
+        let batch = (0..5)
+            .map(|i| TrainData {
+                feature: (0..4).map(|j| (i * 3 + j) % 16).collect(),
+                label: (0..4)
+                    .map(|j| if i == 2 && j > 0 { 15 } else { j })
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+
+        // 两次更新后与单线程的结果相同，第二步使用了同步后的参数
+        let mut single = model.clone();
+        let mut optimizer = AdamW::new(1e-2, 0.1);
+        let mut parallel = model.clone();
+        let mut parallel_optimizer = AdamW::new(1e-2, 0.1);
+        let mut data_parallel = DataParallel::new(&parallel, 3);
+        for _ in 0..2 {
+            let loss = train_step(
+                &mut single,
+                &mut optimizer,
+                None,
+                None,
+                &[&batch],
+                &train_config,
+            )?
+            .loss;
+            let parallel_loss = data_parallel
+                .train_step(
+                    &mut parallel,
+                    &mut parallel_optimizer,
+                    None,
+                    None,
+                    &[&batch],
+                    &train_config,
+                )?
+                .loss;
+            println!("loss: {loss} {parallel_loss}");
+            assert!((loss - parallel_loss).abs() < 1e-5);
+        }
+        assert_eq!(parallel_optimizer.steps(), 2);
+        assert_close(&single, &parallel);
+
+        // 每个rank使用自己的分布式`DataLoader`
+        let token_ids = (0..80).map(|i| (i * 7) % 15).collect::<Vec<_>>();
+        let loaders = (0..2)
+            .map(|rank| {
+                DataLoader::builder()
+                    .batch_size(2)
+                    .shuffle(true)
+                    .seed(123)
+                    .distributed(rank, 2)
+                    .build(GPTDataset::new(token_ids.clone(), 4, 4))
+            })
+            .collect::<Vec<_>>();
+        let shards = loaders
+            .iter()
+            .map(|loader| Ok(vec![loader.iter().next().unwrap()?]))
+            .collect::<Result<Vec<_>, DataLoaderError>>()?;
+        let mut single = model.clone();
+        let mut optimizer = AdamW::new(1e-2, 0.1);
+        train_step(
+            &mut single,
+            &mut optimizer,
+            None,
+            None,
+            &[shards.concat().concat()],
+            &train_config,
+        )?;
+        let mut parallel = model.clone();
+        let mut parallel_optimizer = AdamW::new(1e-2, 0.1);
+        let mut data_parallel = DataParallel::new(&parallel, 2);
+        data_parallel.train_step_sharded(
+            &mut parallel,
+            &mut parallel_optimizer,
+            None,
+            None,
+            &shards,
+            &train_config,
+        )?;
+        assert_close(&single, &parallel);
+        assert!(data_parallel
+            .train_step_sharded(
+                &mut parallel,
+                &mut optimizer,
+                None,
+                None,
+                &shards[..1],
+                &train_config
+            )
+            .is_err());
+
+        // 与单线程相同，梯度溢出时跳过更新
+        let mut grad_scaler = GradScaler::new().with_init_scale(1e38);
+        let train_config = TrainConfig {
+            precision: Precision::F16,
+            ..train_config
+        };
+        let output = data_parallel.train_step_sharded(
+            &mut parallel,
+            &mut parallel_optimizer,
+            None,
+            Some(&mut grad_scaler),
+            &shards,
+            &train_config,
+        )?;
+        assert!(!output.updated);
+        assert_eq!(parallel_optimizer.steps(), 1);
+
+        // 量化后的模型不能反向传播，副本的panic转为错误
+        let mut quantized = model.clone();
+        quantized.quantize();
+        let err = DataParallel::new(&quantized, 2)
+            .train_step_sharded(
+                &mut quantized,
+                &mut parallel_optimizer,
+                None,
+                None,
+                &shards,
+                &train_config,
+            )
+            .unwrap_err();
+        println!("{err}");
+        assert!(matches!(
+            err,
+            ParallelError::WorkerPanicked { rank: 0, ref message } if message.contains("inference")
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,529 @@
+use crate::error::{invalid, Result};
+use crate::vocab::byte_token;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// `sentencepiece_model.proto`中piece的类型
+const NORMAL: u64 = 1;
+const UNKNOWN: u64 = 2;
+const CONTROL: u64 = 3;
+const USER_DEFINED: u64 = 4;
+const UNUSED: u64 = 5;
+const BYTE: u64 = 6;
+
+const UNIGRAM: u64 = 1;
+const BPE: u64 = 2;
+
+// SentencePiece用`▁`表示空格
+pub const SPACE: char = '▁';
+
+// 没有piece能覆盖的字符按unk切分时，分数比最低的piece再低这么多
+const UNKNOWN_PENALTY: f32 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PieceModelType {
+    #[default]
+    Unigram,
+    // Llama的`tokenizer.model`是BPE类型
+    Bpe,
+}
+
+// SentencePiece模型的切分参数，token本身保存在`Vocabulary`中
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PieceModel {
+    model_type: PieceModelType,
+    add_dummy_prefix: bool,
+    remove_extra_whitespaces: bool,
+    unknown_id: usize,
+    // 按id排列，unk、控制符和字节piece为None，不参与切分
+    scores: Vec<Option<f32>>,
+    #[serde(skip)]
+    pieces: HashMap<String, (usize, f32)>,
+    #[serde(skip)]
+    max_piece_chars: usize,
+    #[serde(skip)]
+    min_score: f32,
+}
+
+// 从`.model`文件解析出的内容，特殊token为unk、控制符和用户定义的piece
+pub(crate) struct SentencePieceFile {
+    pub tokens: Vec<String>,
+    pub special_tokens: Vec<String>,
+    pub byte_start: Option<usize>,
+    pub model: PieceModel,
+}
+
+impl PieceModel {
+    // 加载后根据token重建切分用的索引
+    pub(crate) fn index(&mut self, tokens: &[String]) {
+        self.pieces = tokens
+            .iter()
+            .zip(self.scores.iter())
+            .enumerate()
+            .filter_map(|(id, (token, score))| score.map(|score| (token.clone(), (id, score))))
+            .collect();
+        self.max_piece_chars = self
+            .pieces
+            .keys()
+            .map(|p| p.chars().count())
+            .max()
+            .unwrap_or(0);
+        self.min_score = self
+            .pieces
+            .values()
+            .map(|(_, score)| *score)
+            .fold(0.0, f32::min);
+    }
+
+    pub fn model_type(&self) -> PieceModelType {
+        self.model_type
+    }
+
+    pub fn unknown_id(&self) -> usize {
+        self.unknown_id
+    }
+
+    // `at_start`为true时按`add_dummy_prefix`在开头补一个空格
+    pub(crate) fn encode(
+        &self,
+        text: &str,
+        at_start: bool,
+        byte_start: Option<usize>,
+        token_ids: &mut Vec<usize>,
+    ) {
+        let text = self.normalize(text, at_start);
+        let pieces = match self.model_type {
+            PieceModelType::Unigram => self.segment_unigram(&text),
+            PieceModelType::Bpe => self.segment_bpe(&text),
+        };
+
+        for (id, piece) in pieces {
+            match (id, byte_start) {
+                (Some(id), _) => token_ids.push(id),
+                (None, Some(start)) => token_ids.extend(piece.bytes().map(|b| start + b as usize)),
+                (None, None) => token_ids.push(self.unknown_id),
+            }
+        }
+    }
+
+    // 把解码得到的`▁`还原为空格，并去掉补在开头的空格
+    pub(crate) fn restore_spaces(&self, text: &str) -> String {
+        let text = text.replace(SPACE, " ");
+        match text.strip_prefix(' ') {
+            Some(rest) if self.add_dummy_prefix => rest.to_string(),
+            _ => text,
+        }
+    }
+
+    fn normalize(&self, text: &str, at_start: bool) -> String {
+        let text = if self.remove_extra_whitespaces {
+            text.split(' ')
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        } else {
+            text.to_string()
+        };
+
+        let mut normalized = String::with_capacity(text.len() + SPACE.len_utf8());
+        if self.add_dummy_prefix && at_start && !text.is_empty() {
+            normalized.push(SPACE);
+        }
+        normalized.extend(text.chars().map(|c| if c == ' ' { SPACE } else { c }));
+        normalized
+    }
+
+    // Viterbi：选择piece分数之和最大的切分
+    fn segment_unigram<'a>(&self, text: &'a str) -> Vec<(Option<usize>, &'a str)> {
+        let bounds: Vec<usize> = text
+            .char_indices()
+            .map(|(pos, _)| pos)
+            .chain([text.len()])
+            .collect();
+        let n = bounds.len() - 1;
+
+        // best[i]: 切分到第i个字符时的(分数, 上一个切分点, piece id)
+        let mut best: Vec<Option<(f32, usize, Option<usize>)>> = vec![None; n + 1];
+        best[0] = Some((0.0, 0, None));
+
+        for start in 0..n {
+            let Some((score, _, _)) = best[start] else {
+                continue;
+            };
+
+            let mut update = |end: usize, total: f32, id: Option<usize>| {
+                if best[end].is_none_or(|(old, _, _)| total > old) {
+                    best[end] = Some((total, start, id));
+                }
+            };
+
+            let mut single = false;
+            for end in start + 1..=(start + self.max_piece_chars).min(n) {
+                if let Some(&(id, piece_score)) = self.pieces.get(&text[bounds[start]..bounds[end]])
+                {
+                    single |= end == start + 1;
+                    update(end, score + piece_score, Some(id));
+                }
+            }
+            if !single {
+                update(start + 1, score + self.min_score - UNKNOWN_PENALTY, None);
+            }
+        }
+
+        let mut pieces = vec![];
+        let mut end = n;
+        while end > 0 {
+            let (_, start, id) = best[end].unwrap();
+            pieces.push((id, &text[bounds[start]..bounds[end]]));
+            end = start;
+        }
+        pieces.reverse();
+        pieces
+    }
+
+    // 从单个字符开始，每次合并拼接后分数最高的相邻片段
+    fn segment_bpe<'a>(&self, text: &'a str) -> Vec<(Option<usize>, &'a str)> {
+        let mut symbols: Vec<(usize, usize)> = text
+            .char_indices()
+            .map(|(pos, c)| (pos, pos + c.len_utf8()))
+            .collect();
+
+        loop {
+            let best = symbols
+                .windows(2)
+                .enumerate()
+                .filter_map(|(i, pair)| {
+                    let (_, score) = self.pieces.get(&text[pair[0].0..pair[1].1])?;
+                    Some((i, *score))
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.cmp(&a.0)));
+
+            let Some((i, _)) = best else {
+                break;
+            };
+            symbols[i].1 = symbols[i + 1].1;
+            symbols.remove(i + 1);
+        }
+
+        symbols
+            .into_iter()
+            .map(|(start, end)| {
+                let piece = &text[start..end];
+                (self.pieces.get(piece).map(|(id, _)| *id), piece)
+            })
+            .collect()
+    }
+}
+
+// 解析SentencePiece的`.model`文件（protobuf格式的`ModelProto`），只读取切分需要的字段
+pub(crate) fn parse_model(bytes: &[u8]) -> Result<SentencePieceFile> {
+    let mut model = PieceModel {
+        add_dummy_prefix: true,
+        remove_extra_whitespaces: true,
+        ..Default::default()
+    };
+    let mut tokens = vec![];
+    let mut special_tokens = vec![];
+    let mut unknown_id = None;
+    let mut byte_ids = vec![];
+
+    let mut reader = ProtoReader::new(bytes);
+    while let Some((field, value)) = reader.next_field()? {
+        match (field, value) {
+            (1, ProtoValue::Bytes(bytes)) => {
+                let (piece, score, piece_type) = parse_piece(bytes)?;
+                let id = tokens.len();
+
+                model.scores.push(match piece_type {
+                    NORMAL => Some(score),
+                    UNKNOWN | CONTROL | USER_DEFINED | UNUSED | BYTE => None,
+                    _ => return Err(invalid!("Unknown piece type {piece_type}")),
+                });
+                match piece_type {
+                    UNKNOWN => {
+                        unknown_id = Some(id);
+                        special_tokens.push(piece.clone());
+                    }
+                    CONTROL | USER_DEFINED => special_tokens.push(piece.clone()),
+                    BYTE => byte_ids.push((piece.clone(), id)),
+                    _ => {}
+                }
+                tokens.push(piece);
+            }
+            (2, ProtoValue::Bytes(bytes)) => {
+                let mut reader = ProtoReader::new(bytes);
+                while let Some((field, value)) = reader.next_field()? {
+                    if let (3, ProtoValue::Varint(model_type)) = (field, value) {
+                        model.model_type = match model_type {
+                            UNIGRAM => PieceModelType::Unigram,
+                            BPE => PieceModelType::Bpe,
+                            _ => {
+                                return Err(invalid!(
+                                    "Unsupported SentencePiece model type {model_type}"
+                                ))
+                            }
+                        };
+                    }
+                }
+            }
+            (3, ProtoValue::Bytes(bytes)) => {
+                let mut reader = ProtoReader::new(bytes);
+                while let Some((field, value)) = reader.next_field()? {
+                    match (field, value) {
+                        (3, ProtoValue::Varint(v)) => model.add_dummy_prefix = v != 0,
+                        (4, ProtoValue::Varint(v)) => model.remove_extra_whitespaces = v != 0,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    model.unknown_id =
+        unknown_id.ok_or_else(|| invalid!("No unknown piece in SentencePiece model"))?;
+
+    // 字节piece需要是连续的`<0x00>`..`<0xFF>`，才能按字节回退
+    let byte_start = match byte_ids.first() {
+        None => None,
+        Some(&(_, start)) => {
+            let contiguous = byte_ids.len() == 256
+                && byte_ids
+                    .iter()
+                    .enumerate()
+                    .all(|(b, (piece, id))| *piece == byte_token(b as u8) && *id == start + b);
+            if !contiguous {
+                return Err(invalid!(
+                    "Byte pieces are not contiguous in SentencePiece model"
+                ));
+            }
+            Some(start)
+        }
+    };
+
+    model.index(&tokens);
+    Ok(SentencePieceFile {
+        tokens,
+        special_tokens,
+        byte_start,
+        model,
+    })
+}
+
+fn parse_piece(bytes: &[u8]) -> Result<(String, f32, u64)> {
+    let (mut piece, mut score, mut piece_type) = (String::new(), 0.0, NORMAL);
+
+    let mut reader = ProtoReader::new(bytes);
+    while let Some((field, value)) = reader.next_field()? {
+        match (field, value) {
+            (1, ProtoValue::Bytes(bytes)) => {
+                piece = String::from_utf8(bytes.to_vec())
+                    .map_err(|_| invalid!("Piece is not valid UTF-8"))?
+            }
+            (2, ProtoValue::Fixed32(bits)) => score = f32::from_bits(bits),
+            (3, ProtoValue::Varint(v)) => piece_type = v,
+            _ => {}
+        }
+    }
+
+    Ok((piece, score, piece_type))
+}
+
+enum ProtoValue<'a> {
+    Varint(u64),
+    Fixed64,
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+struct ProtoReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        ProtoReader { bytes, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid!("Truncated SentencePiece model"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid!("Invalid varint in SentencePiece model"))
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u64, ProtoValue<'a>)>> {
+        if self.pos >= self.bytes.len() {
+            return Ok(None);
+        }
+
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                ProtoValue::Fixed64
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                ProtoValue::Bytes(self.take(len)?)
+            }
+            5 => ProtoValue::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            wire_type => return Err(invalid!("Unsupported protobuf wire type {wire_type}")),
+        };
+
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vocab::{byte_token, SentenceType, Vocabulary};
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn message(field: u64, bytes: &[u8], out: &mut Vec<u8>) {
+        varint(field << 3 | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    // 按`ModelProto`格式生成一个`.model`文件
+    fn write_model(name: &str, model_type: u64, pieces: &[(&str, f32, u64)]) -> std::path::PathBuf {
+        let mut bytes = vec![];
+        for &(piece, score, piece_type) in pieces {
+            let mut item = vec![];
+            message(1, piece.as_bytes(), &mut item);
+            varint(2 << 3 | 5, &mut item);
+            item.extend_from_slice(&score.to_le_bytes());
+            varint(3 << 3, &mut item);
+            varint(piece_type, &mut item);
+            message(1, &item, &mut bytes);
+        }
+
+        let mut trainer_spec = vec![];
+        varint(3 << 3, &mut trainer_spec);
+        varint(model_type, &mut trainer_spec);
+        message(2, &trainer_spec, &mut bytes);
+
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn pieces(normal: &[(&'static str, f32)]) -> Vec<(String, f32, u64)> {
+        let mut pieces = vec![
+            ("<unk>".to_string(), 0.0, super::UNKNOWN),
+            ("<s>".to_string(), 0.0, super::CONTROL),
+            ("</s>".to_string(), 0.0, super::CONTROL),
+        ];
+        pieces.extend((0..=u8::MAX).map(|b| (byte_token(b), 0.0, super::BYTE)));
+        pieces.extend(
+            normal
+                .iter()
+                .map(|&(p, s)| (p.to_string(), s, super::NORMAL)),
+        );
+        pieces
+    }
+
+    fn load(name: &str, model_type: u64, normal: &[(&'static str, f32)]) -> Vocabulary {
+        let pieces = pieces(normal);
+        let pieces: Vec<(&str, f32, u64)> = pieces
+            .iter()
+            .map(|(p, s, t)| (p.as_str(), *s, *t))
+            .collect();
+        let path = write_model(name, model_type, &pieces);
+        let vocab = Vocabulary::from_sentencepiece(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        vocab
+    }
+
+    #[test]
+    fn test_sentencepiece_unigram() {
+        let normal = [
+            ("▁", -2.0),
+            ("▁hello", -3.0),
+            ("▁he", -4.0),
+            ("llo", -4.0),
+            ("▁world", -3.0),
+            ("h", -5.0),
+            ("e", -5.0),
+            ("l", -5.0),
+            ("o", -5.0),
+        ];
+        let vocab = load("test_sentencepiece_unigram.model", super::UNIGRAM, &normal);
+        assert_eq!(vocab.sentence_type(), &SentenceType::SentencePiece);
+        assert_eq!(vocab.len(), 3 + 256 + normal.len());
+
+        let offset = 3 + 256;
+        let token_ids = vocab.encode("<s>hello world").unwrap();
+        println!("{:?}", token_ids);
+        assert_eq!(token_ids, [1, offset + 1, offset + 4]);
+        assert_eq!(vocab.decode(&token_ids).unwrap(), "<s> hello world");
+        assert_eq!(
+            vocab.decode_skip_special(&token_ids).unwrap(),
+            "hello world"
+        );
+
+        // 没有对应piece的字符按字节回退
+        let text = "hello 世界";
+        let token_ids = vocab.encode(text).unwrap();
+        println!("{:?}", token_ids);
+        assert_eq!(token_ids.len(), 2 + "世界".len());
+        assert_eq!(vocab.decode(&token_ids).unwrap(), text);
+
+        let path = std::env::temp_dir().join("test_sentencepiece_unigram.json");
+        vocab.save(&path).unwrap();
+        let loaded = Vocabulary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.encode(text).unwrap(), token_ids);
+    }
+
+    #[test]
+    fn test_sentencepiece_bpe() {
+        let normal = [
+            ("▁h", -1.0),
+            ("▁hi", -2.0),
+            ("ll", -3.0),
+            ("▁hill", -4.0),
+            ("▁hil", -5.0),
+            ("▁", -6.0),
+            ("h", -7.0),
+            ("i", -8.0),
+            ("l", -9.0),
+        ];
+        let vocab = load("test_sentencepiece_bpe.model", super::BPE, &normal);
+
+        let offset = 3 + 256;
+        let token_ids = vocab.encode("hi hill").unwrap();
+        println!("{:?}", token_ids);
+        // 依次合并`▁h`、`▁hi`、`ll`和`▁hill`，分数更低的`▁hil`不会出现
+        assert_eq!(token_ids, [offset + 1, offset + 3]);
+        assert_eq!(vocab.decode(&token_ids).unwrap(), "hi hill");
+
+        let token_ids = vocab.encode("ih").unwrap();
+        assert_eq!(token_ids, [offset + 5, offset + 7, offset + 6]);
+        assert_eq!(vocab.decode(&token_ids).unwrap(), "ih");
+    }
+}
@@ -0,0 +1,187 @@
+use crate::error::{Result, TokenizerError};
+use fancy_regex::Regex;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::LazyLock;
+
+pub const SIMPLE_UNKNOWN_TOKEN: &str = "<|unk|>";
+pub const SIMPLE_EOF_TOKEN: &str = "<|endoftext|>";
+
+// 书中第2章的切分规则：标点、`--`和空白单独切开，去掉空白
+static SPLIT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"[,.:;?_!"()']|--|\s"#).unwrap());
+// 解码时去掉标点前面的空格
+static PUNCTUATION_V1: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\s+([,.?!"()'])"#).unwrap());
+static PUNCTUATION_V2: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\s+([,.:;?!"()'])"#).unwrap());
+
+fn split_words(text: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut start = 0;
+
+    for m in SPLIT.find_iter(text).flatten() {
+        words.push(&text[start..m.start()]);
+        words.push(m.as_str());
+        start = m.end();
+    }
+    words.push(&text[start..]);
+
+    words
+        .into_iter()
+        .map(|word| word.trim())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+fn build_vocab(text: &str, special_tokens: &[&str]) -> Vec<String> {
+    let words: BTreeSet<&str> = split_words(text).into_iter().collect();
+    let mut tokens: Vec<String> = words.into_iter().map(|word| word.to_string()).collect();
+    tokens.extend(special_tokens.iter().map(|token| token.to_string()));
+    tokens
+}
+
+fn token_to_id(tokens: &[String]) -> HashMap<String, usize> {
+    tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.clone(), id))
+        .collect()
+}
+
+fn join_tokens(tokens: &[String], token_ids: &[usize], punctuation: &Regex) -> Result<String> {
+    let words = token_ids
+        .iter()
+        .map(|&id| {
+            tokens
+                .get(id)
+                .map(|token| token.as_str())
+                .ok_or(TokenizerError::UnknownId(id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(punctuation.replace_all(&words.join(" "), "$1").into_owned())
+}
+
+// 书中的`SimpleTokenizerV1`：词表中没有的词编码时报错
+#[derive(Debug, Clone)]
+pub struct SimpleTokenizerV1 {
+    tokens: Vec<String>,
+    token_to_id: HashMap<String, usize>,
+}
+
+impl SimpleTokenizerV1 {
+    pub fn new(text: &str) -> Self {
+        let tokens = build_vocab(text, &[]);
+        SimpleTokenizerV1 {
+            token_to_id: token_to_id(&tokens),
+            tokens,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        split_words(text)
+            .into_iter()
+            .map(|word| match self.token_to_id.get(word) {
+                Some(&id) => Ok(id),
+                None => Err(TokenizerError::UnknownToken(word.to_string())),
+            })
+            .collect()
+    }
+
+    pub fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        join_tokens(&self.tokens, token_ids, &PUNCTUATION_V1)
+    }
+}
+
+// 书中的`SimpleTokenizerV2`：词表末尾加上`<|endoftext|>`和`<|unk|>`，未知的词编码为`<|unk|>`
+#[derive(Debug, Clone)]
+pub struct SimpleTokenizerV2 {
+    tokens: Vec<String>,
+    token_to_id: HashMap<String, usize>,
+}
+
+impl SimpleTokenizerV2 {
+    pub fn new(text: &str) -> Self {
+        let tokens = build_vocab(text, &[SIMPLE_EOF_TOKEN, SIMPLE_UNKNOWN_TOKEN]);
+        SimpleTokenizerV2 {
+            token_to_id: token_to_id(&tokens),
+            tokens,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn token_id(&self, token: &str) -> Option<usize> {
+        self.token_to_id.get(token).copied()
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<usize> {
+        let unknown = self.token_to_id[SIMPLE_UNKNOWN_TOKEN];
+        split_words(text)
+            .into_iter()
+            .map(|word| self.token_to_id.get(word).copied().unwrap_or(unknown))
+            .collect()
+    }
+
+    pub fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        join_tokens(&self.tokens, token_ids, &PUNCTUATION_V2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 与书中第2章的输出一致
+    #[test]
+    fn test_simple_tokenizer() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/the-verdict.txt");
+        let text = std::fs::read_to_string(path).unwrap();
+
+        let tokenizer = SimpleTokenizerV1::new(&text);
+        assert_eq!(tokenizer.len(), 1130);
+        let sample = "\"It's the last he painted, you know,\" \
+                      Mrs. Gisburn said with pardonable pride.";
+        let token_ids = tokenizer.encode(sample).unwrap();
+        println!("{:?}", token_ids);
+        assert_eq!(
+            token_ids,
+            [
+                1, 56, 2, 850, 988, 602, 533, 746, 5, 1126, 596, 5, 1, 67, 7, 38, 851, 1108, 754,
+                793, 7
+            ]
+        );
+        assert_eq!(
+            tokenizer.decode(&token_ids).unwrap(),
+            "\" It' s the last he painted, you know,\" Mrs. Gisburn said with pardonable pride."
+        );
+        assert!(tokenizer.encode("Hello, do you like tea?").is_err());
+
+        let tokenizer = SimpleTokenizerV2::new(&text);
+        assert_eq!(tokenizer.len(), 1132);
+        let sample = "Hello, do you like tea? <|endoftext|> In the sunlit terraces of the palace.";
+        let token_ids = tokenizer.encode(sample);
+        println!("{:?}", token_ids);
+        assert_eq!(
+            token_ids,
+            [1131, 5, 355, 1126, 628, 975, 10, 1130, 55, 988, 956, 984, 722, 988, 1131, 7]
+        );
+        assert_eq!(
+            tokenizer.decode(&token_ids).unwrap(),
+            "<|unk|>, do you like tea? <|endoftext|> In the sunlit terraces of the <|unk|>."
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// 单遍扫描语料时累计的统计信息，内存占用与语料大小无关
+#[derive(Debug, Default)]
+pub struct CorpusStats {
+    num_tokens: usize,
+    num_documents: usize,
+    doc_lengths: LengthSketch,
+    doc_hashes: BloomFilter,
+    duplicate_documents: usize,
+    zh_chars: usize,
+    en_chars: usize,
+    other_chars: usize,
+    languages: LanguageStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    Zh,
+    En,
+    Other,
+}
+
+impl Language {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "zh" => Some(Language::Zh),
+            "en" => Some(Language::En),
+            "other" => Some(Language::Other),
+            _ => None,
+        }
+    }
+}
+
+// 按字母统计判断文档的主要语言：中文字符占30%以上为中文，
+// 否则英文字母占一半以上为英文
+pub fn detect_language(text: &str) -> Language {
+    let (mut zh, mut en, mut other) = (0, 0, 0);
+
+    for c in text.chars() {
+        if is_cjk(c) {
+            zh += 1;
+        } else if c.is_ascii_alphabetic() {
+            en += 1;
+        } else if c.is_alphabetic() {
+            other += 1;
+        }
+    }
+
+    let letters = zh + en + other;
+    if letters == 0 {
+        Language::Other
+    } else if zh * 10 >= letters * 3 {
+        Language::Zh
+    } else if en * 2 >= letters {
+        Language::En
+    } else {
+        Language::Other
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LanguageCount {
+    pub documents: usize,
+    pub tokens: usize,
+    // 被语言过滤掉的文档数
+    pub filtered_documents: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LanguageStats {
+    pub zh: LanguageCount,
+    pub en: LanguageCount,
+    pub other: LanguageCount,
+}
+
+impl LanguageStats {
+    fn get_mut(&mut self, language: Language) -> &mut LanguageCount {
+        match language {
+            Language::Zh => &mut self.zh,
+            Language::En => &mut self.en,
+            Language::Other => &mut self.other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Quantiles {
+    pub p10: usize,
+    pub p50: usize,
+    pub p90: usize,
+    pub p99: usize,
+    pub max: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LanguageMix {
+    pub zh: f64,
+    pub en: f64,
+    pub other: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CorpusReport {
+    pub num_tokens: usize,
+    pub num_documents: usize,
+    pub doc_length_chars: Quantiles,
+    pub duplicate_documents: usize,
+    pub duplicate_ratio: f64,
+    pub language_mix: LanguageMix,
+    pub languages: LanguageStats,
+}
+
+impl CorpusStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_tokens(&mut self, count: usize) {
+        self.num_tokens += count;
+    }
+
+    // 按文档的语言累计文档数和token数，`tokens`不会计入`add_tokens`
+    pub fn add_language_document(&mut self, language: Language, tokens: usize) {
+        let count = self.languages.get_mut(language);
+        count.documents += 1;
+        count.tokens += tokens;
+    }
+
+    pub fn add_filtered_document(&mut self, language: Language) {
+        self.languages.get_mut(language).filtered_documents += 1;
+    }
+
+    pub fn add_document(&mut self, doc: &str) {
+        let doc = doc.trim();
+        if doc.is_empty() {
+            return;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        doc.hash(&mut hasher);
+        if !self.doc_hashes.insert(hasher.finish()) {
+            self.duplicate_documents += 1;
+        }
+        self.num_documents += 1;
+
+        let mut len = 0;
+        for c in doc.chars() {
+            len += 1;
+
+            if is_cjk(c) {
+                self.zh_chars += 1;
+            } else if c.is_ascii_alphabetic() {
+                self.en_chars += 1;
+            } else if c.is_alphabetic() {
+                self.other_chars += 1;
+            }
+        }
+
+        self.doc_lengths.add(len);
+    }
+
+    pub fn report(&self) -> CorpusReport {
+        let lengths = &self.doc_lengths;
+        let num_documents = self.num_documents;
+        let letters = (self.zh_chars + self.en_chars + self.other_chars).max(1) as f64;
+
+        CorpusReport {
+            num_tokens: self.num_tokens,
+            num_documents,
+            doc_length_chars: Quantiles {
+                p10: lengths.quantile(0.1),
+                p50: lengths.quantile(0.5),
+                p90: lengths.quantile(0.9),
+                p99: lengths.quantile(0.99),
+                max: lengths.max,
+            },
+            duplicate_documents: self.duplicate_documents,
+            duplicate_ratio: self.duplicate_documents as f64 / num_documents.max(1) as f64,
+            language_mix: LanguageMix {
+                zh: self.zh_chars as f64 / letters,
+                en: self.en_chars as f64 / letters,
+                other: self.other_chars as f64 / letters,
+            },
+            languages: self.languages.clone(),
+        }
+    }
+}
+
+// 文档长度的对数分桶直方图，桶数固定。小于`2 * SUB_BUCKETS`的长度精确计数，
+// 更长的长度保留最高的7位，分位数的相对误差不超过1/128
+const SUB_BUCKETS: usize = 64;
+const LENGTH_BUCKETS: usize = (usize::BITS as usize - 5) * SUB_BUCKETS;
+
+#[derive(Debug)]
+struct LengthSketch {
+    counts: Vec<usize>,
+    total: usize,
+    max: usize,
+}
+
+impl Default for LengthSketch {
+    fn default() -> Self {
+        LengthSketch {
+            counts: vec![0; LENGTH_BUCKETS],
+            total: 0,
+            max: 0,
+        }
+    }
+}
+
+impl LengthSketch {
+    // 返回桶的下标和桶内长度的位移
+    fn bucket(len: usize) -> (usize, u32) {
+        if len < 2 * SUB_BUCKETS {
+            return (len, 0);
+        }
+        let shift = len.ilog2() - SUB_BUCKETS.ilog2();
+        (shift as usize * SUB_BUCKETS + (len >> shift), shift)
+    }
+
+    fn add(&mut self, len: usize) {
+        self.counts[Self::bucket(len).0] += 1;
+        self.total += 1;
+        self.max = self.max.max(len);
+    }
+
+    // 与排序后取第`round((n - 1) * q)`个相同，落在桶内时返回桶的中点
+    fn quantile(&self, q: f64) -> usize {
+        if self.total == 0 {
+            return 0;
+        }
+        let rank = ((self.total - 1) as f64 * q).round() as usize;
+        if rank == self.total - 1 {
+            return self.max;
+        }
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen > rank {
+                if index < 2 * SUB_BUCKETS {
+                    return index;
+                }
+                let shift = index / SUB_BUCKETS - 1;
+                let start = (index - shift * SUB_BUCKETS) << shift;
+                return (start + (1 << shift) / 2).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+// 判断文档是否重复的Bloom过滤器，大小固定为8MB。
+// 有少量误判：不同的文档可能被记为重复，不会漏掉真正的重复
+const BLOOM_BITS: usize = 1 << 26;
+const BLOOM_HASHES: u64 = 7;
+
+#[derive(Debug)]
+struct BloomFilter {
+    words: Vec<u64>,
+}
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        BloomFilter {
+            words: vec![0; BLOOM_BITS / 64],
+        }
+    }
+}
+
+impl BloomFilter {
+    // 返回之前是否不存在。用双重哈希从一个64位哈希派生`BLOOM_HASHES`个位置
+    fn insert(&mut self, hash: u64) -> bool {
+        let (h1, h2) = (hash & 0xFFFF_FFFF, (hash >> 32) | 1);
+        let mut inserted = false;
+        for i in 0..BLOOM_HASHES {
+            let bit = (h1.wrapping_add(i.wrapping_mul(h2)) % BLOOM_BITS as u64) as usize;
+            let (word, mask) = (bit / 64, 1 << (bit % 64));
+            inserted |= self.words[word] & mask == 0;
+            self.words[word] |= mask;
+        }
+        inserted
+    }
+}
+
+pub fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x4E00..=0x9FFF | 0x3400..=0x4DBF | 0x20000..=0x2A6DF | 0xF900..=0xFAFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corpus_stats() {
+        let mut stats = CorpusStats::new();
+        stats.add_document("hello world");
+        stats.add_document("这是一个例子");
+        stats.add_document("hello world");
+        stats.add_document("\n\n");
+        stats.add_tokens(10);
+
+        let report = stats.report();
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+        assert_eq!(report.num_tokens, 10);
+        assert_eq!(report.num_documents, 3);
+        assert_eq!(report.duplicate_documents, 1);
+        assert_eq!(report.doc_length_chars.max, 11);
+        assert!((report.language_mix.zh - 6.0 / 26.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_length_sketch() {
+        let mut sketch = LengthSketch::default();
+        let mut lengths: Vec<usize> = (0..10000).map(|i| i * 37 % 5000 + i % 7).collect();
+        lengths.push(usize::MAX);
+        lengths.iter().for_each(|len| sketch.add(*len));
+        lengths.sort_unstable();
+
+        for q in [0.0, 0.1, 0.5, 0.9, 0.99, 1.0] {
+            let exact = lengths[((lengths.len() - 1) as f64 * q).round() as usize];
+            let approx = sketch.quantile(q);
+            println!("{q}: {exact} {approx}");
+            assert!(
+                exact.abs_diff(approx) <= exact / 128,
+                "{q}: {exact} {approx}"
+            );
+        }
+        assert_eq!(sketch.max, usize::MAX);
+
+        let mut sketch = LengthSketch::default();
+        (0..100).for_each(|len| sketch.add(len));
+        assert_eq!(sketch.quantile(0.5), 50);
+
+        let mut filter = BloomFilter::default();
+        assert!((0..1000u64).all(|i| filter.insert(i.wrapping_mul(0x9E37_79B9_7F4A_7C15))));
+        assert!((0..1000u64).all(|i| !filter.insert(i.wrapping_mul(0x9E37_79B9_7F4A_7C15))));
+    }
+
+    #[test]
+    fn test_detect_language() {
+        assert_eq!(
+            detect_language("这是一个简单的例子。This is an example."),
+            Language::Zh
+        );
+        assert_eq!(detect_language("This is an example, 例子"), Language::En);
+        assert_eq!(detect_language("Привет мир"), Language::Other);
+        assert_eq!(detect_language("1234 !!"), Language::Other);
+
+        let mut stats = CorpusStats::new();
+        stats.add_language_document(Language::Zh, 5);
+        stats.add_language_document(Language::Zh, 3);
+        stats.add_filtered_document(Language::Other);
+
+        let report = stats.report();
+        assert_eq!(
+            (report.languages.zh.documents, report.languages.zh.tokens),
+            (2, 8)
+        );
+        assert_eq!(report.languages.other.filtered_documents, 1);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::error::{file_error, MetricsError};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// TensorBoard的事件文件：每条记录为TFRecord格式（长度、长度的CRC、数据、数据的CRC），
+// 数据为protobuf编码的`Event`。只实现标量所需的字段，不依赖protobuf库
+pub struct EventWriter {
+    writer: BufWriter<File>,
+}
+
+impl EventWriter {
+    pub fn create(dir: impl AsRef<Path>) -> Result<Self, MetricsError> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir).map_err(file_error("create", dir))?;
+        let path = dir.join(format!("events.out.tfevents.{}.llm", wall_time() as u64));
+        let file = File::create(&path).map_err(file_error("create", &path))?;
+
+        let mut writer = EventWriter {
+            writer: BufWriter::new(file),
+        };
+        // 第一条记录声明文件版本
+        let mut event = vec![];
+        put_double(&mut event, 1, wall_time());
+        put_bytes(&mut event, 3, b"brain.Event:2");
+        writer.write_record(&event)?;
+        Ok(writer)
+    }
+
+    pub fn add_scalar(&mut self, tag: &str, value: f32, step: i64) -> io::Result<()> {
+        // Summary.Value { tag = 1, simple_value = 2 }
+        let mut summary_value = vec![];
+        put_bytes(&mut summary_value, 1, tag.as_bytes());
+        put_key(&mut summary_value, 2, 5);
+        summary_value.extend(value.to_le_bytes());
+        let mut summary = vec![];
+        put_bytes(&mut summary, 1, &summary_value);
+
+        // Event { wall_time = 1, step = 2, summary = 5 }
+        let mut event = vec![];
+        put_double(&mut event, 1, wall_time());
+        put_key(&mut event, 2, 0);
+        put_varint(&mut event, step as u64);
+        put_bytes(&mut event, 5, &summary);
+        self.write_record(&event)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn write_record(&mut self, data: &[u8]) -> io::Result<()> {
+        let len = (data.len() as u64).to_le_bytes();
+        self.writer.write_all(&len)?;
+        self.writer.write_all(&masked_crc32c(&len).to_le_bytes())?;
+        self.writer.write_all(data)?;
+        self.writer.write_all(&masked_crc32c(data).to_le_bytes())?;
+        Ok(())
+    }
+}
+
+fn wall_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |time| time.as_secs_f64())
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buf, (field << 3) | wire_type);
+}
+
+fn put_double(buf: &mut Vec<u8>, field: u64, value: f64) {
+    put_key(buf, field, 1);
+    buf.extend(value.to_le_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_key(buf, field, 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend(bytes);
+}
+
+// TFRecord使用Castagnoli多项式的CRC32，并做一次旋转和偏移
+fn masked_crc32c(data: &[u8]) -> u32 {
+    let crc = crc32c(data);
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0x82f6_3b78 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TestResult;
+    use std::fs;
+
+    #[test]
+    fn test_event_writer() -> TestResult {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+
+        let dir = std::env::temp_dir().join("test_tensorboard");
+        let _ = fs::remove_dir_all(&dir);
+        let mut writer = EventWriter::create(&dir)?;
+        writer.add_scalar("loss/train", 2.5, 1)?;
+        writer.add_scalar("loss/train", 2.0, 300)?;
+        writer.flush()?;
+
+        let path = fs::read_dir(&dir)?.next().unwrap()?.path();
+        let bytes = fs::read(&path)?;
+        let mut records = vec![];
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let len = u64::from_le_bytes(rest[..8].try_into()?) as usize;
+            assert_eq!(
+                u32::from_le_bytes(rest[8..12].try_into()?),
+                masked_crc32c(&rest[..8])
+            );
+            let data = &rest[12..12 + len];
+            let crc = u32::from_le_bytes(rest[12 + len..16 + len].try_into()?);
+            assert_eq!(crc, masked_crc32c(data));
+            records.push(data.to_vec());
+            rest = &rest[16 + len..];
+        }
+        println!("{path:?}: {} records", records.len());
+        assert_eq!(records.len(), 3);
+        assert!(records[0].ends_with(b"brain.Event:2"));
+        // step = 300的varint编码
+        assert!(records[2].windows(3).any(|w| w == [0x10, 0xac, 0x02]));
+        assert!(records[2].ends_with(&[0x15, 0, 0, 0, 0x40]));
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use crate::bpe::BpeTokenizer;
+use crate::char_tokenizer::CharTokenizer;
+use crate::error::Result;
+use crate::mmap_vocab::MmapVocabulary;
+use crate::simple_tokenizer::{SimpleTokenizerV1, SimpleTokenizerV2, SIMPLE_EOF_TOKEN};
+use crate::vocab::Vocabulary;
+use crate::wordpiece::WordPieceTokenizer;
+
+// 各种分词器的统一接口，数据集和训练代码只依赖这个trait，更换分词器时不需要修改流水线
+pub trait Tokenizer: Send + Sync {
+    fn encode(&self, text: &str) -> Result<Vec<usize>>;
+
+    fn decode(&self, token_ids: &[usize]) -> Result<String>;
+
+    // 所有id都小于该值，即嵌入矩阵的行数
+    fn vocab_size(&self) -> usize;
+
+    fn special_ids(&self) -> Vec<usize>;
+
+    fn eos_id(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl Tokenizer for Vocabulary {
+    fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        Vocabulary::encode(self, text)
+    }
+
+    fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        Vocabulary::decode(self, token_ids)
+    }
+
+    fn vocab_size(&self) -> usize {
+        Vocabulary::vocab_size(self)
+    }
+
+    fn special_ids(&self) -> Vec<usize> {
+        self.special_tokens().iter().map(|(_, id)| *id).collect()
+    }
+
+    fn eos_id(&self) -> Option<usize> {
+        Vocabulary::eos_id(self)
+    }
+}
+
+impl Tokenizer for MmapVocabulary {
+    fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        MmapVocabulary::encode(self, text)
+    }
+
+    fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        MmapVocabulary::decode(self, token_ids)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.len()
+    }
+
+    fn special_ids(&self) -> Vec<usize> {
+        self.special_tokens().iter().map(|(_, id)| *id).collect()
+    }
+}
+
+impl Tokenizer for BpeTokenizer {
+    fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        Ok(BpeTokenizer::encode(self, text))
+    }
+
+    fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        BpeTokenizer::decode(self, token_ids)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.len()
+    }
+
+    fn special_ids(&self) -> Vec<usize> {
+        self.special_tokens()
+            .iter()
+            .filter_map(|token| self.special_token_id(token))
+            .collect()
+    }
+}
+
+impl Tokenizer for WordPieceTokenizer {
+    fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        Ok(WordPieceTokenizer::encode(self, text))
+    }
+
+    fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        WordPieceTokenizer::decode(self, token_ids)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.len()
+    }
+
+    fn special_ids(&self) -> Vec<usize> {
+        self.special_tokens().iter().map(|(_, id)| *id).collect()
+    }
+}
+
+impl Tokenizer for CharTokenizer {
+    fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        CharTokenizer::encode(self, text)
+    }
+
+    fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        CharTokenizer::decode(self, token_ids)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.len()
+    }
+
+    fn special_ids(&self) -> Vec<usize> {
+        self.special_tokens().iter().map(|(_, id)| *id).collect()
+    }
+}
+
+impl Tokenizer for SimpleTokenizerV1 {
+    fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        SimpleTokenizerV1::encode(self, text)
+    }
+
+    fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        SimpleTokenizerV1::decode(self, token_ids)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.len()
+    }
+
+    fn special_ids(&self) -> Vec<usize> {
+        vec![]
+    }
+}
+
+// `<|endoftext|>`和`<|unk|>`是词表的最后两个token
+impl Tokenizer for SimpleTokenizerV2 {
+    fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        Ok(SimpleTokenizerV2::encode(self, text))
+    }
+
+    fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        SimpleTokenizerV2::decode(self, token_ids)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.len()
+    }
+
+    fn special_ids(&self) -> Vec<usize> {
+        vec![self.len() - 2, self.len() - 1]
+    }
+
+    fn eos_id(&self) -> Option<usize> {
+        self.token_id(SIMPLE_EOF_TOKEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bpe::BpeTrainer;
+    use crate::vocab::SentenceType;
+    use crate::wordpiece::WordPieceTrainer;
+
+    fn check_round_trip(tokenizer: &dyn Tokenizer, text: &str) {
+        let token_ids = tokenizer.encode(text).unwrap();
+        println!("{:?}", token_ids);
+        assert!(token_ids.iter().all(|id| *id < tokenizer.vocab_size()));
+        assert_eq!(tokenizer.decode(&token_ids).unwrap(), text);
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        let text = "这是一个例子。";
+        let vocab = Vocabulary::new("", SentenceType::English).unwrap();
+        check_round_trip(&vocab, "Hello world!");
+        assert_eq!(Tokenizer::eos_id(&vocab), Some(100257));
+
+        let vocab = Vocabulary::new(text, SentenceType::Chinese).unwrap();
+        check_round_trip(&vocab, text);
+        assert_eq!(vocab.special_ids(), [0, 1, 2]);
+
+        let bpe = BpeTrainer::new(300).special_tokens(&["<eof>"]).train(text);
+        check_round_trip(&bpe, "这是一个例子。<eof>");
+        assert_eq!(bpe.special_ids(), [256]);
+
+        let wordpiece = WordPieceTrainer::new(50).train(text);
+        check_round_trip(&wordpiece, "这是一个例子 。");
+        assert_eq!(wordpiece.special_ids(), [0]);
+    }
+}
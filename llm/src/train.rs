@@ -0,0 +1,1239 @@
+use crate::early_stopping::EarlyStopping;
+use crate::error::{file_error, TrainError};
+use crate::loss::{count_tokens, evaluate, split_batch, Evaluation};
+use crate::metrics::{Metrics, MetricsLogger};
+use crate::parallel::DataParallel;
+use crate::tokenizer::Tokenizer;
+use data_loader::{DataLoader, LoaderState, LoaderStats, TrainData};
+use model::{
+    clip_grad_norm, cross_entropy_with_grad, with_precision, AdamW, GPTModel, GradScaler,
+    LrScheduler, ModelError, Precision, SamplingConfig,
+};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{debug_span, info_span, instrument};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainConfig {
+    pub num_epochs: usize,
+    // 每次优化器更新累加多少个micro-batch的梯度，等效批次大小为`batch_size × accumulation_steps`
+    pub accumulation_steps: usize,
+    // 附录D中的梯度裁剪，`None`表示不裁剪
+    pub max_grad_norm: Option<f32>,
+    pub ignore_index: Option<usize>,
+    // 每隔多少次优化器更新评估一次，0表示不评估
+    pub eval_freq: usize,
+    // 评估时最多使用的验证批次数，`None`表示全部
+    pub eval_iter: Option<usize>,
+    // 每个epoch结束后从该prompt按`sampling`生成`sample_tokens`个token
+    pub start_context: Option<String>,
+    pub sample_tokens: usize,
+    // 每隔多少次优化器更新额外生成一次样例，0表示只在epoch结束后生成
+    pub sample_freq: usize,
+    // 默认贪心解码，随机采样时第`n`步的随机数由`seed + n`派生
+    pub sampling: SamplingConfig,
+    // 第`n`次优化器更新前用`seed + n`重新派生dropout的随机数，使从检查点继续的训练与不中断时相同
+    pub seed: u64,
+    // 设置了`Trainer::with_run_state`时每隔多少次优化器更新写一次运行状态，0表示只在epoch结束时写
+    pub run_state_freq: usize,
+    // 前向和反向传播中矩阵乘法的精度，反向传播保存的激活大部分存为16位，优化器仍然更新f32的参数（master weights）。
+    // `F16`时`Trainer`自动使用`GradScaler`
+    pub precision: Precision,
+    // 对模型打开`GPTModel::set_gradient_checkpointing`
+    pub gradient_checkpointing: bool,
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        TrainConfig {
+            num_epochs: 1,
+            accumulation_steps: 1,
+            max_grad_norm: None,
+            ignore_index: None,
+            eval_freq: 5,
+            eval_iter: Some(5),
+            start_context: None,
+            sample_tokens: 50,
+            sample_freq: 0,
+            sampling: SamplingConfig::greedy(),
+            seed: 123,
+            run_state_freq: 0,
+            precision: Precision::F32,
+            gradient_checkpointing: false,
+        }
+    }
+}
+
+// `train_model_simple`返回的曲线，`train_losses`、`val_losses`和`tokens_seen`一一对应
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrainHistory {
+    // 每次优化器更新的loss
+    pub step_losses: Vec<f32>,
+    // 两次评估之间`step_losses`的平均值
+    pub train_losses: Vec<f32>,
+    pub val_losses: Vec<f32>,
+    pub tokens_seen: Vec<usize>,
+    // 每个epoch结束后以及每`sample_freq`步生成的文本
+    pub samples: Vec<String>,
+}
+
+type Result<T, E = TrainError> = std::result::Result<T, E>;
+type SampleCallback = Box<dyn FnMut(&TrainSample)>;
+type RunStateCallback = Box<dyn Fn(&Path) -> Result<()>>;
+
+// 传给`Trainer::with_sample_callback`的样例
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainSample {
+    pub step: u64,
+    pub epoch: usize,
+    // 生成样例前最后一次更新的loss，epoch结束时为`None`
+    pub train_loss: Option<f32>,
+    // 包含prompt的完整文本
+    pub text: String,
+}
+
+// 书中第5章的`train_model_simple`：把模型、优化器和学习率调度组合在一起，
+// 按`TrainConfig`周期性评估并生成样例
+pub struct Trainer {
+    model: GPTModel,
+    optimizer: AdamW,
+    scheduler: Option<Box<dyn LrScheduler>>,
+    early_stopping: Option<EarlyStopping>,
+    metrics_logger: Option<MetricsLogger>,
+    sample_callback: Option<SampleCallback>,
+    grad_scaler: Option<GradScaler>,
+    data_parallel: Option<DataParallel>,
+    // 运行状态目录，以及写入目录前额外保存文件的回调
+    run_state: Option<(PathBuf, RunStateCallback)>,
+    config: TrainConfig,
+    epoch: usize,
+    tokens_seen: usize,
+    // 上次评估以来的loss
+    pending_losses: Vec<f32>,
+    history: TrainHistory,
+    // 最后一个epoch结束后`train_loader`的状态，从运行状态恢复时为epoch中间的位置
+    loader_state: Option<LoaderState>,
+}
+
+// 运行状态目录中的`run_state.json`，与`save_checkpoint`写入的文件一起用于从epoch中间继续训练
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunState {
+    // 当前epoch，还没有训练完
+    pub epoch: usize,
+    pub global_step: u64,
+    // `train_loader`的`LoaderState`：epoch、打乱顺序的种子和当前epoch已使用的样本数
+    pub loader_epoch: u64,
+    pub loader_seed: Option<u64>,
+    pub consumed: usize,
+    // dropout和生成样例的随机数由`seed + global_step`派生
+    pub seed: u64,
+}
+
+impl RunState {
+    // 读取`path`中的`run_state.json`。替换目录时被中断，`path`不存在时读取`<path>.old`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = run_state_dir(path.as_ref());
+        let state_path = path.join("run_state.json");
+        let state = fs::read_to_string(&state_path).map_err(file_error("read", &state_path))?;
+        serde_json::from_str(&state).map_err(|source| TrainError::InvalidState {
+            kind: "run state",
+            path: state_path,
+            source,
+        })
+    }
+
+    pub fn loader_state(&self) -> LoaderState {
+        LoaderState {
+            epoch: self.loader_epoch,
+            seed: self.loader_seed,
+            consumed: self.consumed,
+        }
+    }
+}
+
+// 运行状态实际所在的目录，见`replace_dir`
+pub fn run_state_dir(path: &Path) -> PathBuf {
+    let old = with_suffix(path, ".old");
+    if !path.exists() && old.exists() {
+        old
+    } else {
+        path.to_path_buf()
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// 先写到`<path>.tmp`，完成后把旧目录改名为`<path>.old`再换上新目录，
+// 任何时候中断（包括断电）都至少保留一份完整的状态
+fn replace_dir(path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    let old = with_suffix(path, ".old");
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp).map_err(file_error("remove", &tmp))?;
+    }
+    write(&tmp)?;
+    // 改名之前文件内容必须已经落盘
+    sync_files(&tmp)?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if path.exists() {
+        if old.exists() {
+            fs::remove_dir_all(&old).map_err(file_error("remove", &old))?;
+        }
+        fs::rename(path, &old).map_err(file_error("rename", path))?;
+        sync_dir(parent)?;
+    }
+    fs::rename(&tmp, path).map_err(file_error("rename", &tmp))?;
+    sync_dir(parent)?;
+    if old.exists() {
+        fs::remove_dir_all(&old).map_err(file_error("remove", &old))?;
+    }
+    Ok(())
+}
+
+// fsync目录中的每个文件以及目录本身
+fn sync_files(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir).map_err(file_error("read", dir))? {
+        let path = entry.map_err(file_error("read", dir))?.path();
+        if path.is_file() {
+            File::open(&path)
+                .and_then(|file| file.sync_all())
+                .map_err(file_error("sync", &path))?;
+        }
+    }
+    sync_dir(dir)
+}
+
+// 使目录中的创建和改名落盘。Windows不能打开目录，跳过
+fn sync_dir(dir: &Path) -> Result<()> {
+    if cfg!(unix) {
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(file_error("sync", dir))?;
+    }
+    Ok(())
+}
+
+// 检查点目录中`trainer.json`的内容，模型和优化器分别保存为`model.safetensors`和`optimizer.safetensors`，
+// 使用LoRA时适配器保存为`lora.safetensors`
+#[derive(Serialize, Deserialize)]
+struct TrainerState {
+    epoch: usize,
+    tokens_seen: usize,
+    pending_losses: Vec<f32>,
+    history: TrainHistory,
+    // `LoaderState`的(epoch, seed, consumed)
+    loader_state: Option<(u64, Option<u64>, usize)>,
+    #[serde(default)]
+    grad_scaler: Option<GradScaler>,
+}
+
+impl Trainer {
+    pub fn new(mut model: GPTModel, optimizer: AdamW, config: TrainConfig) -> Self {
+        assert!(
+            config.accumulation_steps > 0,
+            "Accumulation steps must be greater than 0"
+        );
+        if config.gradient_checkpointing {
+            model.set_gradient_checkpointing(true);
+        }
+        Trainer {
+            model,
+            optimizer,
+            scheduler: None,
+            early_stopping: None,
+            metrics_logger: None,
+            sample_callback: None,
+            grad_scaler: (config.precision == Precision::F16).then(GradScaler::new),
+            data_parallel: None,
+            run_state: None,
+            config,
+            epoch: 0,
+            tokens_seen: 0,
+            pending_losses: vec![],
+            history: TrainHistory::default(),
+            loader_state: None,
+        }
+    }
+
+    pub fn with_scheduler(mut self, scheduler: impl LrScheduler + 'static) -> Self {
+        self.scheduler = Some(Box::new(scheduler));
+        self
+    }
+
+    // 每次评估后检查验证loss，满足停止条件时`train`提前返回
+    pub fn with_early_stopping(mut self, early_stopping: EarlyStopping) -> Self {
+        self.early_stopping = Some(early_stopping);
+        self
+    }
+
+    // 每次优化器更新后记录一行指标
+    pub fn with_metrics_logger(mut self, metrics_logger: MetricsLogger) -> Self {
+        self.metrics_logger = Some(metrics_logger);
+        self
+    }
+
+    // 每次生成样例后调用，与书中相同用于观察训练过程中生成质量的变化
+    pub fn with_sample_callback(mut self, callback: impl FnMut(&TrainSample) + 'static) -> Self {
+        self.sample_callback = Some(Box::new(callback));
+        self
+    }
+
+    // 替换默认的`GradScaler`，其他精度下也可以使用loss scaling
+    pub fn with_grad_scaler(mut self, grad_scaler: GradScaler) -> Self {
+        self.grad_scaler = Some(grad_scaler);
+        self
+    }
+
+    // 每个批次平均分给`num_threads`个线程并行计算梯度，1表示不使用数据并行
+    pub fn with_data_parallel(mut self, num_threads: usize) -> Self {
+        self.data_parallel = (num_threads > 1).then(|| DataParallel::new(&self.model, num_threads));
+        self
+    }
+
+    // 每`run_state_freq`次优化器更新以及每个epoch结束时把运行状态写到`path`，见`save_run_state`。
+    // `on_save`在替换目录前调用，可以往临时目录中写入配置等其他文件
+    pub fn with_run_state(
+        mut self,
+        path: impl Into<PathBuf>,
+        on_save: impl Fn(&Path) -> Result<()> + 'static,
+    ) -> Self {
+        self.run_state = Some((path.into(), Box::new(on_save)));
+        self
+    }
+
+    pub fn grad_scaler(&self) -> Option<&GradScaler> {
+        self.grad_scaler.as_ref()
+    }
+
+    pub fn early_stopping(&self) -> Option<&EarlyStopping> {
+        self.early_stopping.as_ref()
+    }
+
+    pub fn stopped_early(&self) -> bool {
+        self.early_stopping
+            .as_ref()
+            .is_some_and(EarlyStopping::should_stop)
+    }
+
+    pub fn model(&self) -> &GPTModel {
+        &self.model
+    }
+
+    pub fn model_mut(&mut self) -> &mut GPTModel {
+        &mut self.model
+    }
+
+    pub fn into_model(self) -> GPTModel {
+        self.model
+    }
+
+    pub fn optimizer(&self) -> &AdamW {
+        &self.optimizer
+    }
+
+    pub fn config(&self) -> &TrainConfig {
+        &self.config
+    }
+
+    pub fn history(&self) -> &TrainHistory {
+        &self.history
+    }
+
+    // 已完成的优化器更新次数
+    pub fn global_step(&self) -> u64 {
+        self.optimizer.steps()
+    }
+
+    pub fn epoch(&self) -> usize {
+        self.epoch
+    }
+
+    pub fn tokens_seen(&self) -> usize {
+        self.tokens_seen
+    }
+
+    // 从检查点继续时传给`DataLoaderBuilder::resume`，使数据的顺序与不中断时相同
+    pub fn loader_state(&self) -> Option<LoaderState> {
+        self.loader_state
+    }
+
+    // 保存模型、优化器的矩和步数（学习率调度由步数决定）以及训练进度。
+    // dropout的随机数在每次更新前由`seed`派生，不需要单独保存
+    pub fn save_checkpoint(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.write_checkpoint(path.as_ref(), self.loader_state)?;
+        sync_files(path.as_ref())
+    }
+
+    // 在`save_checkpoint`的基础上记录`loader_state`（通常是`train_loader.state()`）和随机数种子，
+    // 写入`run_state.json`。整个目录（包括`on_save`写入的文件）fsync后原子地替换，见`replace_dir`
+    pub fn save_run_state(
+        &self,
+        path: impl AsRef<Path>,
+        loader_state: LoaderState,
+        on_save: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<()> {
+        let _span = debug_span!("run_state").entered();
+        let state = RunState {
+            epoch: self.epoch,
+            global_step: self.global_step(),
+            loader_epoch: loader_state.epoch,
+            loader_seed: loader_state.seed,
+            consumed: loader_state.consumed,
+            seed: self.config.seed,
+        };
+        replace_dir(path.as_ref(), |dir| {
+            self.write_checkpoint(dir, Some(loader_state))?;
+            let state_path = dir.join("run_state.json");
+            fs::write(&state_path, serde_json::to_string(&state)?)
+                .map_err(file_error("write", &state_path))?;
+            on_save(dir)
+        })?;
+        tracing::info!(
+            "Saved run state at step {} ({} samples into epoch {})",
+            state.global_step,
+            state.consumed,
+            state.epoch
+        );
+        Ok(())
+    }
+
+    fn write_checkpoint(&self, path: &Path, loader_state: Option<LoaderState>) -> Result<()> {
+        fs::create_dir_all(path).map_err(file_error("create", path))?;
+        self.model.save(path.join("model.safetensors"))?;
+        let lora_path = path.join("lora.safetensors");
+        if self.model.has_lora() {
+            self.model.save_lora(&lora_path)?;
+        } else if lora_path.exists() {
+            fs::remove_file(&lora_path).map_err(file_error("remove", &lora_path))?;
+        }
+        self.optimizer.save(path.join("optimizer.safetensors"))?;
+
+        let state = TrainerState {
+            epoch: self.epoch,
+            tokens_seen: self.tokens_seen,
+            pending_losses: self.pending_losses.clone(),
+            history: self.history.clone(),
+            loader_state: loader_state.map(|state| (state.epoch, state.seed, state.consumed)),
+            grad_scaler: self.grad_scaler.clone(),
+        };
+        let state_path = path.join("trainer.json");
+        fs::write(&state_path, serde_json::to_string(&state)?)
+            .map_err(file_error("write", &state_path))?;
+        Ok(())
+    }
+
+    // 用`save_checkpoint`保存的状态替换当前的模型、优化器和进度，`TrainConfig`和学习率调度保持不变。
+    // 从`save_run_state`的目录恢复时同时恢复随机数种子，`loader_state`为epoch中间的位置
+    pub fn resume_from(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = &run_state_dir(path.as_ref());
+        let state_path = path.join("trainer.json");
+        let state = fs::read_to_string(&state_path).map_err(file_error("read", &state_path))?;
+        let state: TrainerState =
+            serde_json::from_str(&state).map_err(|source| TrainError::InvalidState {
+                kind: "trainer state",
+                path: state_path,
+                source,
+            })?;
+
+        let mut model = GPTModel::load(path.join("model.safetensors"))?;
+        let lora_path = path.join("lora.safetensors");
+        if lora_path.exists() {
+            model.load_lora(lora_path)?;
+        }
+        model.to_device(self.model.device())?;
+        model.set_gradient_checkpointing(self.model.gradient_checkpointing());
+        self.model = model;
+        self.optimizer = AdamW::load(path.join("optimizer.safetensors"))?;
+        self.epoch = state.epoch;
+        self.tokens_seen = state.tokens_seen;
+        self.pending_losses = state.pending_losses;
+        self.history = state.history;
+        self.loader_state = state
+            .loader_state
+            .map(|(epoch, seed, consumed)| LoaderState {
+                epoch,
+                seed,
+                consumed,
+            });
+        if state.grad_scaler.is_some() {
+            self.grad_scaler = state.grad_scaler;
+        }
+        if path.join("run_state.json").exists() {
+            self.config.seed = RunState::load(path)?.seed;
+        }
+        Ok(())
+    }
+
+    // 训练到第`num_epochs`个epoch结束，从检查点继续时只训练剩余的epoch，
+    // 从运行状态继续时`train_loader`从epoch中间开始。
+    // 每个epoch调用一次`train_loader.iter()`，多个epoch时`train_loader`需要使用`persistent_workers`。
+    // 提前停止时当前epoch不计入`epoch`
+    pub fn train(
+        &mut self,
+        train_loader: &DataLoader<Vec<TrainData<usize>>>,
+        val_batches: &[Vec<TrainData<usize>>],
+        tokenizer: &impl Tokenizer,
+    ) -> Result<&TrainHistory> {
+        if self.config.eval_freq > 0 && val_batches.is_empty() {
+            return Err(TrainError::EmptyValidation);
+        }
+
+        while self.epoch < self.config.num_epochs && !self.stopped_early() {
+            let _span = info_span!("epoch", epoch = self.epoch).entered();
+            self.model.train();
+            let (mut micro_batches, mut num_batches) = (vec![], 0);
+            let run_state_freq = self.config.run_state_freq as u64;
+            for batch in train_loader.iter() {
+                micro_batches.push(batch?);
+                num_batches += 1;
+                if micro_batches.len() == self.config.accumulation_steps {
+                    let global_step = self.global_step();
+                    self.step(
+                        &micro_batches,
+                        &train_loader.stats(),
+                        val_batches,
+                        tokenizer,
+                    )?;
+                    micro_batches.clear();
+                    if self.stopped_early() {
+                        return Ok(&self.history);
+                    }
+                    // 只在两次更新之间写，不会有已取出但还没有训练的批次
+                    if run_state_freq > 0
+                        && self.global_step() > global_step
+                        && self.global_step().is_multiple_of(run_state_freq)
+                    {
+                        self.write_run_state(train_loader.state())?;
+                    }
+                }
+            }
+            // 最后不足`accumulation_steps`的micro-batch也更新一次
+            if !micro_batches.is_empty() {
+                self.step(
+                    &micro_batches,
+                    &train_loader.stats(),
+                    val_batches,
+                    tokenizer,
+                )?;
+                if self.stopped_early() {
+                    return Ok(&self.history);
+                }
+            }
+            if num_batches == 0 {
+                return Err(TrainError::NoBatches(self.epoch));
+            }
+
+            self.epoch += 1;
+            self.loader_state = Some(train_loader.state());
+            self.sample(tokenizer, None)?;
+            if let Some(metrics_logger) = self.metrics_logger.as_mut() {
+                metrics_logger.flush()?;
+            }
+            self.write_run_state(train_loader.state())?;
+        }
+        Ok(&self.history)
+    }
+
+    // 设置了`with_run_state`时写入运行状态
+    fn write_run_state(&self, loader_state: LoaderState) -> Result<()> {
+        match self.run_state.as_ref() {
+            Some((path, on_save)) => self.save_run_state(path, loader_state, on_save),
+            None => Ok(()),
+        }
+    }
+
+    // 训练循环中每`eval_freq`次更新调用一次，最多使用`eval_iter`个批次
+    #[instrument(name = "eval", level = "debug", skip_all)]
+    pub fn evaluate(&self, val_batches: &[Vec<TrainData<usize>>]) -> Result<Evaluation> {
+        evaluate(
+            &self.model,
+            val_batches,
+            self.config.eval_iter,
+            self.config.ignore_index,
+        )
+    }
+
+    // 从`start_context`生成样例，不使用dropout，不影响训练状态
+    fn sample(&mut self, tokenizer: &impl Tokenizer, train_loss: Option<f32>) -> Result<()> {
+        let Some(prompt) = self.config.start_context.as_deref() else {
+            return Ok(());
+        };
+        let _span = debug_span!("sample").entered();
+        let token_ids = tokenizer.encode(prompt)?;
+        let step = self.global_step();
+        let mut rng = StdRng::seed_from_u64(self.config.seed.wrapping_add(step));
+        let token_ids = self.model.generate(
+            &token_ids,
+            self.config.sample_tokens,
+            &self.config.sampling,
+            &mut rng,
+        )?;
+        let text = tokenizer.decode(&token_ids)?;
+
+        if let Some(callback) = self.sample_callback.as_mut() {
+            callback(&TrainSample {
+                step,
+                epoch: self.epoch,
+                train_loss,
+                text: text.clone(),
+            });
+        }
+        self.history.samples.push(text);
+        Ok(())
+    }
+
+    // `loader_stats`为训练集`DataLoader`当前epoch的统计，写入`Metrics`
+    fn step(
+        &mut self,
+        micro_batches: &[Vec<TrainData<usize>>],
+        loader_stats: &LoaderStats,
+        val_batches: &[Vec<TrainData<usize>>],
+        tokenizer: &impl Tokenizer,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let global_step = self.global_step();
+        let _span = debug_span!("step", step = global_step + 1).entered();
+        let seed = self.config.seed.wrapping_add(global_step);
+        self.model.seed_dropout(seed);
+        if let Some(data_parallel) = self.data_parallel.as_mut() {
+            data_parallel.seed_dropout(seed);
+        }
+        let output = match self.data_parallel.as_mut() {
+            Some(data_parallel) => data_parallel.train_step(
+                &mut self.model,
+                &mut self.optimizer,
+                self.scheduler.as_deref(),
+                self.grad_scaler.as_mut(),
+                micro_batches,
+                &self.config,
+            )?,
+            None => train_step(
+                &mut self.model,
+                &mut self.optimizer,
+                self.scheduler.as_deref(),
+                self.grad_scaler.as_mut(),
+                micro_batches,
+                &self.config,
+            )?,
+        };
+        // 梯度溢出，`GradScaler`跳过了这次更新
+        if !output.updated {
+            tracing::warn!("Gradient overflow, skipped the update");
+            return Ok(());
+        }
+        let loss = output.loss;
+        let tokens = micro_batches
+            .iter()
+            .flatten()
+            .map(|sample| sample.feature.len())
+            .sum::<usize>();
+        let tokens_per_sec = tokens as f64 / start.elapsed().as_secs_f64().max(f64::EPSILON);
+        tracing::debug!(
+            "loss {loss:.4}, lr {:.3e}, {tokens} tokens, {tokens_per_sec:.0} tokens/s",
+            self.optimizer.lr()
+        );
+        self.tokens_seen += tokens;
+        self.history.step_losses.push(loss);
+        self.pending_losses.push(loss);
+
+        // 与书中相同，第一次更新后就评估一次
+        let eval_freq = self.config.eval_freq as u64;
+        let mut val_loss = None;
+        if eval_freq > 0 && (self.global_step() - 1).is_multiple_of(eval_freq) {
+            let loss = self.evaluate(val_batches)?.loss;
+            tracing::debug!("val loss {loss:.4}");
+            val_loss = Some(loss);
+
+            let train_loss =
+                self.pending_losses.iter().sum::<f32>() / self.pending_losses.len() as f32;
+            self.pending_losses.clear();
+            self.history.train_losses.push(train_loss);
+            self.history.val_losses.push(loss);
+            self.history.tokens_seen.push(self.tokens_seen);
+
+            if let Some(early_stopping) = self.early_stopping.as_mut() {
+                early_stopping.update(loss, self.optimizer.steps(), &self.model)?;
+            }
+        }
+
+        if let Some(metrics_logger) = self.metrics_logger.as_mut() {
+            metrics_logger.log(&Metrics {
+                step: self.optimizer.steps(),
+                epoch: self.epoch,
+                train_loss: loss,
+                val_loss,
+                lr: self.optimizer.lr(),
+                tokens_per_sec,
+                padding_tokens: loader_stats.padding_tokens,
+                dropped_tokens: loader_stats.dropped_tokens,
+            })?;
+        }
+
+        let sample_freq = self.config.sample_freq as u64;
+        if sample_freq > 0 && self.global_step().is_multiple_of(sample_freq) {
+            self.sample(tokenizer, Some(loss))?;
+        }
+        Ok(())
+    }
+}
+
+// `train_step`的结果，`updated`为false时`GradScaler`检测到梯度溢出，跳过了这次更新
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepOutput {
+    pub loss: f32,
+    pub updated: bool,
+}
+
+// 一次优化器更新：清零梯度，依次对每个micro-batch反向传播，然后调度学习率、裁剪梯度并更新参数。
+// 每个micro-batch的梯度按其token数占比缩放，累加后与把所有micro-batch拼成一个批次的梯度相同。
+// 学习率按优化器更新次数调度，与micro-batch的数量无关。返回所有micro-batch的平均loss。
+// 使用`grad_scaler`时梯度出现inf/nan则不更新参数，`optimizer.steps()`不变
+pub fn train_step<B: AsRef<[TrainData<usize>]>>(
+    model: &mut GPTModel,
+    optimizer: &mut AdamW,
+    scheduler: Option<&dyn LrScheduler>,
+    grad_scaler: Option<&mut GradScaler>,
+    micro_batches: &[B],
+    config: &TrainConfig,
+) -> Result<StepOutput> {
+    let micro_batches = micro_batches.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+    let total_tokens = count_total_tokens(&micro_batches, config.ignore_index);
+    if total_tokens == 0 {
+        return Err(TrainError::NoTokens);
+    }
+
+    model.zero_grad();
+    let scale = grad_scaler
+        .as_ref()
+        .map_or(1.0, |grad_scaler| grad_scaler.scale());
+    let loss = accumulate_grads(model, &micro_batches, total_tokens, scale, config)?;
+    let updated = apply_grads(model, optimizer, scheduler, grad_scaler, config);
+    Ok(StepOutput { loss, updated })
+}
+
+pub(crate) fn count_total_tokens(
+    batches: &[&[TrainData<usize>]],
+    ignore_index: Option<usize>,
+) -> usize {
+    batches
+        .iter()
+        .map(|batch| count_tokens(batch, ignore_index))
+        .sum()
+}
+
+// 依次对`batches`反向传播，梯度按`token数 / total_tokens × scale`缩放后累加，返回加权的loss
+pub(crate) fn accumulate_grads(
+    model: &mut GPTModel,
+    batches: &[&[TrainData<usize>]],
+    total_tokens: usize,
+    scale: f32,
+    config: &TrainConfig,
+) -> Result<f32, ModelError> {
+    with_precision(config.precision, || {
+        let mut loss = 0.0;
+        for (i, batch) in batches.iter().enumerate() {
+            let tokens = count_tokens(batch, config.ignore_index);
+            if tokens == 0 {
+                continue;
+            }
+            // span关闭时记录前向和反向传播的耗时
+            let _span = debug_span!("batch", batch = i, tokens).entered();
+            let (inputs, targets) = split_batch(batch);
+            let (batch_loss, mut grad) = debug_span!("forward").in_scope(|| {
+                let logits = model.forward(&inputs)?;
+                cross_entropy_with_grad(&logits, &targets, config.ignore_index)
+            })?;
+
+            let weight = tokens as f32 / total_tokens as f32;
+            grad.data_mut()
+                .iter_mut()
+                .for_each(|g| *g *= weight * scale);
+            debug_span!("backward").in_scope(|| model.backward(&grad))?;
+            loss += batch_loss * weight;
+        }
+        Ok(loss)
+    })
+}
+
+// 梯度累加完成后：还原loss scaling，调度学习率，裁剪梯度并更新参数。梯度溢出时返回false
+pub(crate) fn apply_grads(
+    model: &mut GPTModel,
+    optimizer: &mut AdamW,
+    scheduler: Option<&dyn LrScheduler>,
+    grad_scaler: Option<&mut GradScaler>,
+    config: &TrainConfig,
+) -> bool {
+    if let Some(grad_scaler) = grad_scaler
+        && !grad_scaler.unscale(model.named_params_mut())
+    {
+        return false;
+    }
+    if let Some(scheduler) = scheduler {
+        scheduler.step(optimizer);
+    }
+    if let Some(max_norm) = config.max_grad_norm {
+        clip_grad_norm(model.named_params_mut(), max_norm);
+    }
+    optimizer.step(model.named_params_mut());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::char_tokenizer::CharTokenizer;
+    use crate::error::TestResult;
+    use crate::metrics::MetricsFormat;
+    use data_loader::{Dataset, GPTDataset};
+    use model::{GptConfig, WarmupCosine};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_gradient_accumulation() -> TestResult {
+        let config = GptConfig {
+            vocab_size: 16,
+            context_length: 8,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 1,
+            dropout: 0.0,
+            ..GptConfig::gpt2_small()
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let sample = |i: usize| TrainData {
+            feature: (0..4).map(|j| (i + j) % 16).collect(),
+            // 第二个样本部分位置被忽略，micro-batch的token数不同
+            label: (0..4)
+                .map(|j| {
+                    if i == 1 && j < 3 {
+                        15
+                    } else {
+                        (i * 3 + j) % 15
+                    }
+                })
+                .collect(),
+        };
+        let batch = [sample(0), sample(1), sample(2)];
+
+        // 一个大批次和三个micro-batch得到相同的梯度、loss和更新结果
+        let train_config = TrainConfig {
+            ignore_index: Some(15),
+            ..TrainConfig::default()
+        };
+        let scheduler = WarmupCosine::new(1e-2, 2, 10);
+        let mut full = model.clone();
+        let mut optimizer = AdamW::new(1e-2, 0.1);
+        let full_loss = train_step(
+            &mut full,
+            &mut optimizer,
+            Some(&scheduler),
+            None,
+            &[&batch[..]],
+            &train_config,
+        )?
+        .loss;
+
+        let mut accumulated = model.clone();
+        let mut accumulated_optimizer = AdamW::new(1e-2, 0.1);
+        let micro_batches = batch.chunks(1).collect::<Vec<_>>();
+        let train_config = TrainConfig {
+            accumulation_steps: 3,
+            ..train_config
+        };
+        let loss = train_step(
+            &mut accumulated,
+            &mut accumulated_optimizer,
+            Some(&scheduler),
+            None,
+            &micro_batches,
+            &train_config,
+        )?
+        .loss;
+        println!("loss: {full_loss} {loss}");
+        assert!((full_loss - loss).abs() < 1e-5);
+        assert_eq!(accumulated_optimizer.steps(), 1);
+        assert_eq!(optimizer.lr(), accumulated_optimizer.lr());
+
+        for ((name, a), (_, b)) in full
+            .named_params()
+            .into_iter()
+            .zip(accumulated.named_params())
+        {
+            for (x, y) in a.grad().data().iter().zip(b.grad().data()) {
+                assert!((x - y).abs() < 1e-5, "{name}: {x} vs {y}");
+            }
+            for (x, y) in a.value().data().iter().zip(b.value().data()) {
+                assert!((x - y).abs() < 1e-5, "{name}: {x} vs {y}");
+            }
+        }
+
+        let ignored = vec![TrainData {
+            feature: vec![0; 4],
+            label: vec![15; 4],
+        }];
+        assert!(train_step(
+            &mut full,
+            &mut optimizer,
+            None,
+            None,
+            &[ignored],
+            &train_config
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mixed_precision() -> TestResult {
+        let config = GptConfig {
+            vocab_size: 16,
+            context_length: 8,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 1,
+            dropout: 0.0,
+            ..GptConfig::gpt2_small()
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let batch = (0..2)
+            .map(|i| TrainData {
+                feature: (0..8).map(|j| (i * 5 + j) % 16).collect(),
+                label: (0..8).map(|j| (i * 5 + j + 1) % 16).collect(),
+            })
+            .collect::<Vec<_>>();
+
+        let mut full = model.clone();
+        let mut optimizer = AdamW::new(1e-3, 0.1);
+        let f32_config = TrainConfig::default();
+        let full_loss = train_step(
+            &mut full,
+            &mut optimizer,
+            None,
+            None,
+            &[&batch],
+            &f32_config,
+        )?
+        .loss;
+
+        let mut forward = model.clone();
+        forward.forward(&[batch[0].feature.clone()])?;
+        let full_bytes = forward.saved_bytes();
+
+        // 半精度的loss和更新后的参数与f32接近，但不完全相同
+        for precision in [Precision::Bf16, Precision::F16] {
+            let train_config = TrainConfig {
+                precision,
+                ..TrainConfig::default()
+            };
+            let mut half = model.clone();
+            let mut half_optimizer = AdamW::new(1e-3, 0.1);
+            let mut grad_scaler = GradScaler::new();
+            let loss = train_step(
+                &mut half,
+                &mut half_optimizer,
+                None,
+                (precision == Precision::F16).then_some(&mut grad_scaler),
+                &[&batch],
+                &train_config,
+            )?
+            .loss;
+            println!("{precision:?} loss: {full_loss} {loss}");
+            // 除了LayerNorm，保存的激活都是16位
+            with_precision(precision, || forward.forward(&[batch[0].feature.clone()]))?;
+            let half_bytes = forward.saved_bytes();
+            println!("{precision:?} saved: {half_bytes} of {full_bytes} bytes");
+            assert!(half_bytes * 10 < full_bytes * 6);
+            assert!(loss != full_loss && (loss - full_loss).abs() < 1e-2);
+            assert_eq!(half_optimizer.steps(), 1);
+            for ((name, a), (_, b)) in full.named_params().into_iter().zip(half.named_params()) {
+                for (x, y) in a.value().data().iter().zip(b.value().data()) {
+                    assert!((x - y).abs() < 1e-2, "{name}: {x} vs {y}");
+                }
+            }
+        }
+
+        // scale过大时梯度溢出，跳过这次更新并缩小scale
+        let train_config = TrainConfig {
+            precision: Precision::F16,
+            ..TrainConfig::default()
+        };
+        let mut trainer = Trainer::new(model.clone(), AdamW::new(1e-3, 0.1), train_config.clone());
+        assert_eq!(trainer.grad_scaler(), Some(&GradScaler::new()));
+        let mut overflow = model.clone();
+        let mut grad_scaler = GradScaler::new().with_init_scale(1e38);
+        let output = train_step(
+            &mut overflow,
+            &mut optimizer,
+            None,
+            Some(&mut grad_scaler),
+            &[&batch],
+            &train_config,
+        )?;
+        assert!(!output.updated);
+        assert_eq!(optimizer.steps(), 1);
+        assert_eq!(grad_scaler.scale(), 5e37);
+        assert_eq!(overflow.tok_emb().weight(), model.tok_emb().weight());
+
+        trainer = trainer.with_grad_scaler(GradScaler::new().with_init_scale(1e38));
+        let before = trainer.history().step_losses.len();
+        trainer.step(
+            &[batch],
+            &LoaderStats::default(),
+            &[],
+            &CharTokenizer::new("ab"),
+        )?;
+        assert_eq!(trainer.global_step(), 0);
+        assert_eq!(trainer.history().step_losses.len(), before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trainer() -> TestResult {
+        let text = "every effort moves you forward. ".repeat(8);
+        let tokenizer = CharTokenizer::new(&text);
+        let token_ids = tokenizer.encode(&text)?;
+        let (train_ids, val_ids) = token_ids.split_at(token_ids.len() * 9 / 10);
+
+        let train_loader = DataLoader::builder()
+            .batch_size(2)
+            .shuffle(true)
+            .seed(123)
+            .drop_last(true)
+            .persistent_workers(true)
+            .build(GPTDataset::new(train_ids.to_vec(), 8, 8));
+        let val_loader =
+            DataLoader::new(GPTDataset::new(val_ids.to_vec(), 8, 8), 2, false, 1, false);
+        let val_batches = val_loader.iter().collect::<Result<Vec<_>, _>>()?;
+
+        let config = GptConfig {
+            vocab_size: tokenizer.vocab_size(),
+            context_length: 8,
+            emb_dim: 32,
+            n_heads: 2,
+            n_layers: 1,
+            dropout: 0.0,
+            ..GptConfig::gpt2_small()
+        };
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let train_config = TrainConfig {
+            num_epochs: 4,
+            accumulation_steps: 2,
+            max_grad_norm: Some(1.0),
+            eval_freq: 2,
+            eval_iter: Some(1),
+            start_context: Some("every".to_string()),
+            sample_tokens: 4,
+            sample_freq: 10,
+            sampling: SamplingConfig {
+                top_k: Some(3),
+                ..SamplingConfig::default()
+            },
+            ..TrainConfig::default()
+        };
+        let metrics_path = std::env::temp_dir().join("test_trainer_metrics.jsonl");
+        let samples = Rc::new(RefCell::new(vec![]));
+        let recorded = Rc::clone(&samples);
+        let mut trainer = Trainer::new(model, AdamW::new(1e-2, 0.1), train_config)
+            .with_scheduler(WarmupCosine::new(1e-2, 2, 28))
+            .with_metrics_logger(MetricsLogger::create(&metrics_path, MetricsFormat::Jsonl)?)
+            .with_sample_callback(move |sample| recorded.borrow_mut().push(sample.clone()));
+        let history = trainer
+            .train(&train_loader, &val_batches, &tokenizer)?
+            .clone();
+        println!("{history:?}");
+
+        // 每次更新一行，评估的那一步有验证loss
+        let metrics = std::fs::read_to_string(&metrics_path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+        assert_eq!(metrics.len(), 28);
+        assert_eq!(metrics[2]["step"], 3);
+        let value = |i: usize, key: &str| metrics[i][key].as_f64().map(|x| x as f32);
+        assert_eq!(value(2, "val_loss"), Some(history.val_losses[1]));
+        assert!(metrics[3]["val_loss"].is_null());
+        assert_eq!(value(1, "lr"), Some(5e-3));
+        let dropped = GPTDataset::new(train_ids.to_vec(), 8, 8).dropped_tokens();
+        assert!(metrics.iter().all(|line| line["dropped_tokens"] == dropped));
+
+        // 每个epoch 14个批次，累加2个后更新一次，共4 × 7次更新
+        assert_eq!(trainer.global_step(), 28);
+        assert_eq!(trainer.epoch(), 4);
+        assert_eq!(trainer.tokens_seen(), 4 * 14 * 2 * 8);
+        assert_eq!(history.step_losses.len(), 28);
+        assert_eq!(history.val_losses.len(), 14);
+        assert_eq!(history.train_losses.len(), history.tokens_seen.len());
+        // 每个epoch结束后和第10、20步各生成一次
+        assert_eq!(history.samples.len(), 6);
+        assert!(history
+            .samples
+            .iter()
+            .all(|sample| sample.starts_with("every") && sample.len() == "every".len() + 4));
+        let samples = samples.borrow();
+        let steps = samples.iter().map(|sample| sample.step).collect::<Vec<_>>();
+        assert_eq!(steps, [7, 10, 14, 20, 21, 28]);
+        assert_eq!(samples[1].train_loss, Some(history.step_losses[9]));
+        assert_eq!(samples[2].train_loss, None);
+        assert_eq!(samples[5].text, history.samples[5]);
+        assert!(history.step_losses[27] < history.step_losses[0] - 1.0);
+        assert!(history.val_losses[13] < history.val_losses[0]);
+        let evaluation = evaluate(trainer.model(), &val_batches[..1], None, None)?;
+        assert_eq!(trainer.evaluate(&val_batches)?, evaluation);
+
+        assert!(trainer.train(&train_loader, &[], &tokenizer).is_err());
+
+        // 学习率过大时验证loss不再下降，训练提前停止并保留最好的模型
+        let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(123))?;
+        let train_config = TrainConfig {
+            num_epochs: 20,
+            eval_freq: 1,
+            start_context: None,
+            ..trainer.config().clone()
+        };
+        let mut trainer = Trainer::new(model, AdamW::new(1e-1, 0.1), train_config)
+            .with_early_stopping(EarlyStopping::new(3, 0.0));
+        let history = trainer
+            .train(&train_loader, &val_batches, &tokenizer)?
+            .clone();
+        let early_stopping = trainer.early_stopping().unwrap();
+        println!("{:?} {:?}", history.val_losses, early_stopping.best_step());
+        assert!(trainer.stopped_early() && trainer.epoch() < 20);
+        let best_step = early_stopping.best_step().unwrap();
+        assert_eq!(trainer.global_step(), best_step + 3);
+        assert_eq!(
+            history.val_losses[best_step as usize - 1],
+            early_stopping.best_loss()
+        );
+        let best = evaluate(
+            early_stopping.best_model().unwrap(),
+            &val_batches[..1],
+            None,
+            None,
+        )?;
+        assert_eq!(best.loss, early_stopping.best_loss());
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_resume() -> TestResult {
+        let tokenizer = CharTokenizer::new("abcdefgh");
+        let token_ids = (0..200).map(|i| (i * 5 + i / 7) % 8).collect::<Vec<_>>();
+        let builder = DataLoader::builder()
+            .batch_size(2)
+            .shuffle(true)
+            .seed(7)
+            .persistent_workers(true);
+        let val_batches = vec![vec![TrainData {
+            feature: token_ids[..8].to_vec(),
+            label: token_ids[1..9].to_vec(),
+        }]];
+
+        let config = GptConfig {
+            vocab_size: 8,
+            context_length: 8,
+            emb_dim: 16,
+            n_heads: 2,
+            n_layers: 1,
+            dropout: 0.1,
+            ..GptConfig::gpt2_small()
+        };
+        let train_config = TrainConfig {
+            num_epochs: 4,
+            accumulation_steps: 3,
+            eval_freq: 4,
+            ..TrainConfig::default()
+        };
+        let new_trainer = |seed| -> Result<Trainer> {
+            let model = GPTModel::new(&config, &mut StdRng::seed_from_u64(seed))?;
+            Ok(
+                Trainer::new(model, AdamW::new(4e-3, 0.1), train_config.clone())
+                    .with_scheduler(WarmupCosine::new(4e-3, 5, 32)),
+            )
+        };
+
+        let dataset = || GPTDataset::new(token_ids.clone(), 8, 8);
+        let mut full = new_trainer(123)?;
+        full.train(&builder.build(dataset()), &val_batches, &tokenizer)?;
+
+        // 训练2个epoch后保存，用另一个随机初始化的模型恢复后训练剩余的2个epoch
+        let mut first = new_trainer(123)?;
+        first.config.num_epochs = 2;
+        first.train(&builder.build(dataset()), &val_batches, &tokenizer)?;
+        let path = std::env::temp_dir().join("test_trainer_checkpoint");
+        first.save_checkpoint(&path)?;
+
+        let mut resumed = new_trainer(456)?;
+        resumed.resume_from(&path)?;
+        assert_eq!(resumed.epoch(), 2);
+        assert_eq!(resumed.global_step(), first.global_step());
+        let loader = builder
+            .clone()
+            .resume(resumed.loader_state().unwrap())
+            .build(dataset());
+        resumed.train(&loader, &val_batches, &tokenizer)?;
+        println!("{:?}", resumed.history().val_losses);
+
+        assert_eq!(resumed.global_step(), full.global_step());
+        assert_eq!(resumed.tokens_seen(), full.tokens_seen());
+        assert_eq!(resumed.history(), full.history());
+        assert_eq!(resumed.model().named_params(), full.model().named_params());
+        assert_eq!(resumed.optimizer().lr(), full.optimizer().lr());
+
+        assert!(resumed
+            .resume_from(std::env::temp_dir().join("missing_checkpoint"))
+            .is_err());
+
+        // 每3次更新写一次运行状态，第4次写入时失败模拟中断，目录中保留第6次更新后、第2个epoch中间的状态
+        let path = std::env::temp_dir().join("test_trainer_run_state");
+        let _ = fs::remove_dir_all(&path);
+        let saves = Rc::new(RefCell::new(0));
+        let mut interrupted = new_trainer(123)?.with_run_state(&path, {
+            let saves = saves.clone();
+            move |dir| {
+                *saves.borrow_mut() += 1;
+                if *saves.borrow() == 4 {
+                    return Err(TrainError::callback("Interrupted"));
+                }
+                fs::write(dir.join("config.json"), "{}")?;
+                Ok(())
+            }
+        });
+        interrupted.config.run_state_freq = 3;
+        assert!(interrupted
+            .train(&builder.build(dataset()), &val_batches, &tokenizer)
+            .is_err());
+        let state = RunState::load(&path)?;
+        println!("{state:?}");
+        assert_eq!((state.epoch, state.global_step), (1, 6));
+        assert_eq!(state.loader_state().consumed, 12);
+        assert!(path.join("config.json").exists() && !with_suffix(&path, ".old").exists());
+
+        let mut resumed = new_trainer(456)?;
+        resumed.config.seed = 0;
+        resumed.resume_from(&path)?;
+        assert_eq!(resumed.config().seed, 123);
+        assert_eq!(resumed.loader_state(), Some(state.loader_state()));
+        let loader = builder
+            .clone()
+            .resume(state.loader_state())
+            .build(dataset());
+        resumed.train(&loader, &val_batches, &tokenizer)?;
+        assert_eq!(resumed.global_step(), full.global_step());
+        assert_eq!(resumed.tokens_seen(), full.tokens_seen());
+        assert_eq!(resumed.history(), full.history());
+        assert_eq!(resumed.model().named_params(), full.model().named_params());
+        Ok(())
+    }
+}
@@ -1,16 +1,119 @@
-use anyhow::{Context, Result};
+#[cfg(not(feature = "jieba"))]
+use crate::char_segmenter::Jieba;
+use crate::error::{
+    invalid, invalid_file, read_error, unsupported, write_error, Result, TokenizerError,
+};
+use crate::hf_tokenizer::{parse_tokenizer, HfModel};
+use crate::normalize::TextNormalizer;
+use crate::sentencepiece::{parse_model, PieceModel};
+use crate::stats::is_cjk;
+#[cfg(feature = "jieba")]
 use jieba_rs::Jieba;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
-use tiktoken_rs::{cl100k_base, Rank};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use tiktoken_rs::{
+    cl100k_base_singleton, o200k_base_singleton, p50k_base_singleton, r50k_base_singleton, CoreBPE,
+    Rank,
+};
 
 pub const EOF_TOKEN: &str = "<eof>";
 pub const PADDING_TOKEN: &str = "<pad>";
 pub const UNKNOWN_TOKEN: &str = "<unk>";
 
-#[derive(Debug, Clone)]
+// `encode_file`每次至少读取并编码的字节数
+const FILE_CHUNK_SIZE: usize = 1 << 20;
+
+// 默认词典只加载一次
+static JIEBA: LazyLock<Jieba> = LazyLock::new(Jieba::new);
+
+// jieba的切分模式。`Full`只在构建词表时列出所有可能的词，
+// 编码时仍然使用精确模式，保证token拼接后与原文一致
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CutMode {
+    #[default]
+    Exact,
+    // 精确模式，并用HMM识别词典中没有的新词
+    Hmm,
+    Full,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SentenceType {
     English,
     Chinese,
+    // 从SentencePiece的`.model`文件加载，例如Llama系列的词表
+    SentencePiece,
+    // 从Hugging Face的`tokenizer.json`加载
+    HuggingFace,
+    // 中英文混合：中文片段用jieba切分并使用中文词表，其余片段使用tiktoken，
+    // tiktoken的id加上`bpe_offset`，两者共用一个id空间
+    Mixed,
+}
+
+// 英文使用的tiktoken编码。`Gpt2`与`R50kBase`的词表相同（50257个token），
+// 加载GPT-2预训练权重时需要使用
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Encoding {
+    Gpt2,
+    R50kBase,
+    P50kBase,
+    #[default]
+    Cl100kBase,
+    O200kBase,
+}
+
+impl Encoding {
+    // 包括编码自带的特殊token在内的id数，自定义的特殊token从这里开始编号
+    pub fn n_vocab(self) -> usize {
+        match self {
+            Encoding::Gpt2 | Encoding::R50kBase => 50257,
+            Encoding::P50kBase => 50281,
+            Encoding::Cl100kBase => 100277,
+            Encoding::O200kBase => 200019,
+        }
+    }
+
+    // 编码自带的`<|endoftext|>`的id
+    pub fn endoftext_id(self) -> usize {
+        match self {
+            Encoding::Gpt2 | Encoding::R50kBase | Encoding::P50kBase => 50256,
+            Encoding::Cl100kBase => 100257,
+            Encoding::O200kBase => 199999,
+        }
+    }
+
+    // 词表只在第一次使用时解析，之后所有调用共用同一个实例
+    pub(crate) fn bpe(self) -> &'static CoreBPE {
+        match self {
+            Encoding::Gpt2 | Encoding::R50kBase => r50k_base_singleton(),
+            Encoding::P50kBase => p50k_base_singleton(),
+            Encoding::Cl100kBase => cl100k_base_singleton(),
+            Encoding::O200kBase => o200k_base_singleton(),
+        }
+    }
+}
+
+// `Longest`按批次中最长的序列补齐，单个文本时与`DoNotPad`相同
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaddingStrategy {
+    #[default]
+    DoNotPad,
+    Longest,
+    MaxLength,
+}
+
+// `attention_mask`中真实token为1，`<pad>`为0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedText {
+    pub token_ids: Vec<usize>,
+    pub attention_mask: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
@@ -19,103 +122,916 @@ pub struct Vocabulary {
     id_to_tokens: Vec<String>,
     max_id: usize,
     sentence_type: SentenceType,
+    // 字节token`<0x00>`的id，`<0x00>`..`<0xFF>`的id连续
+    byte_start: Option<usize>,
+    encoding: Encoding,
+    // 编码时作为整体，不会被jieba或tiktoken切分
+    special_tokens: Vec<(String, usize)>,
+    sentencepiece: Option<PieceModel>,
+    hf_model: Option<HfModel>,
+    cut_mode: CutMode,
+    // 用户词典的内容，保存时一起写入词表文件
+    user_dict: String,
+    // 加载了用户词典的jieba，None时使用默认词典
+    jieba: Option<Arc<Jieba>>,
+    // 混合模式下tiktoken id的起始位置，即中文词表的大小
+    bpe_offset: Option<usize>,
+    normalizer: TextNormalizer,
+    // 构建时每个token在语料中出现的次数，按id排列，不保存到词表文件
+    frequencies: Vec<usize>,
+}
+
+// 保存到磁盘的格式，`tokens`按id排列
+#[derive(Serialize, Deserialize)]
+struct VocabularyFile {
+    sentence_type: SentenceType,
+    special_tokens: Vec<String>,
+    max_id: usize,
+    tokens: Vec<String>,
+    #[serde(default)]
+    byte_fallback: bool,
+    #[serde(default)]
+    encoding: Encoding,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sentencepiece: Option<PieceModel>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hf_model: Option<HfModel>,
+    #[serde(default)]
+    cut_mode: CutMode,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    user_dict: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bpe_offset: Option<usize>,
+    #[serde(default, skip_serializing_if = "TextNormalizer::is_identity")]
+    normalizer: TextNormalizer,
 }
 
 // 虽然`tiktoken_rs`支持中文分词。不过这里还是使用`jieba-rs`对中文分词。
 impl Vocabulary {
     pub fn new(text: &str, sentence_type: SentenceType) -> Result<Self> {
+        Self::new_with_normalizer(text, sentence_type, TextNormalizer::default())
+    }
+
+    // 构建词表和之后编码时都先对普通文本做规范化，特殊token不受影响
+    pub fn new_with_normalizer(
+        text: &str,
+        sentence_type: SentenceType,
+        normalizer: TextNormalizer,
+    ) -> Result<Self> {
+        let text = &*normalizer.normalize(text);
         let mut vocab = Vocabulary {
             tokens_to_id: HashMap::new(),
             id_to_tokens: Vec::new(),
             max_id: 0,
             sentence_type,
+            byte_start: None,
+            encoding: Encoding::default(),
+            special_tokens: vec![],
+            sentencepiece: None,
+            hf_model: None,
+            cut_mode: CutMode::default(),
+            user_dict: String::new(),
+            jieba: None,
+            bpe_offset: None,
+            frequencies: vec![],
+            normalizer,
         };
 
         match vocab.sentence_type {
-            SentenceType::English => {
-                // 在`encode_english`中设置`max_id`
-            }
+            SentenceType::English => vocab.max_id = vocab.encoding.n_vocab(),
             SentenceType::Chinese => {
-                vocab.add_token(UNKNOWN_TOKEN);
-                vocab.add_token(PADDING_TOKEN);
-                vocab.add_token(EOF_TOKEN);
+                vocab.add_special_token(UNKNOWN_TOKEN);
+                vocab.add_special_token(PADDING_TOKEN);
+                vocab.add_special_token(EOF_TOKEN);
 
-                let tokens = Vocabulary::tokenize_sentence(&text);
+                let tokens = vocab.cut_for_vocab(text);
                 vocab.add_tokens(tokens);
             }
+            SentenceType::SentencePiece => {
+                return Err(unsupported!(
+                    "Use Vocabulary::from_sentencepiece to load a SentencePiece model"
+                ))
+            }
+            SentenceType::HuggingFace => {
+                return Err(unsupported!(
+                    "Use Vocabulary::from_hf_tokenizer_file to load a tokenizer.json"
+                ))
+            }
+            SentenceType::Mixed => {
+                vocab.add_special_token(UNKNOWN_TOKEN);
+                vocab.add_special_token(PADDING_TOKEN);
+                vocab.add_special_token(EOF_TOKEN);
+
+                let tokens = script_runs(text)
+                    .into_iter()
+                    .filter(|(chinese, _)| *chinese)
+                    .flat_map(|(_, run)| vocab.cut_for_vocab(run))
+                    .collect();
+                vocab.add_tokens(tokens);
+
+                vocab.bpe_offset = Some(vocab.max_id);
+                vocab.max_id += vocab.encoding.n_vocab();
+            }
         }
 
         Ok(vocab)
     }
 
-    pub fn len(&self) -> usize {
-        self.max_id
+    // 使用jieba格式的用户词典（每行`词 [词频] [词性]`）构建中文词表，
+    // 适合医学、法律等默认词典切分效果不好的领域语料
+    pub fn new_chinese_with_dict(
+        text: &str,
+        dict_paths: &[impl AsRef<Path>],
+        cut_mode: CutMode,
+    ) -> Result<Self> {
+        let mut user_dict = String::new();
+        for path in dict_paths {
+            let path = path.as_ref();
+            let dict = fs::read_to_string(path).map_err(read_error(path))?;
+            user_dict.push_str(&dict);
+            if !user_dict.ends_with('\n') {
+                user_dict.push('\n');
+            }
+        }
+
+        let mut vocab = Vocabulary::new("", SentenceType::Chinese)?;
+        vocab.cut_mode = cut_mode;
+        vocab.jieba = load_jieba(&user_dict)?;
+        vocab.user_dict = user_dict;
+
+        let tokens = vocab.cut_for_vocab(text);
+        vocab.add_tokens(tokens);
+        Ok(vocab)
     }
 
-    pub fn encode(&mut self, sentence: &str) -> Result<Vec<usize>> {
-        match self.sentence_type {
-            SentenceType::Chinese => Ok(self.encode_chinese(sentence)),
-            SentenceType::English => self.encode_english(sentence),
+    pub fn normalizer(&self) -> &TextNormalizer {
+        &self.normalizer
+    }
+
+    pub fn cut_mode(&self) -> CutMode {
+        self.cut_mode
+    }
+
+    pub(crate) fn has_user_dict(&self) -> bool {
+        self.jieba.is_some()
+    }
+
+    // 加载SentencePiece的`.model`文件，unk、控制符（如`<s>`、`</s>`）
+    // 和用户定义的piece作为特殊token
+    pub fn from_sentencepiece(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(read_error(path))?;
+        let file = parse_model(&bytes).map_err(invalid_file("SentencePiece model", path))?;
+
+        let tokens_to_id: HashMap<String, usize> = file
+            .tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.clone(), id))
+            .collect();
+        let special_tokens = file
+            .special_tokens
+            .into_iter()
+            .map(|token| {
+                let id = tokens_to_id[&token];
+                (token, id)
+            })
+            .collect();
+
+        Ok(Vocabulary {
+            tokens_to_id,
+            max_id: file.tokens.len(),
+            id_to_tokens: file.tokens,
+            sentence_type: SentenceType::SentencePiece,
+            byte_start: file.byte_start,
+            encoding: Encoding::default(),
+            special_tokens,
+            sentencepiece: Some(file.model),
+            hf_model: None,
+            cut_mode: CutMode::default(),
+            user_dict: String::new(),
+            jieba: None,
+            bpe_offset: None,
+            frequencies: vec![],
+            normalizer: TextNormalizer::default(),
+        })
+    }
+
+    // 加载Hugging Face的`tokenizer.json`，`added_tokens`作为特殊token，
+    // 编码结果与上游的分词器相同（不包括`post_processor`添加的`<s>`等token）
+    pub fn from_hf_tokenizer_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(read_error(path))?;
+        let file = parse_tokenizer(&text).map_err(invalid_file("tokenizer file", path))?;
+
+        let tokens_to_id = file
+            .tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.clone(), id))
+            .collect();
+
+        Ok(Vocabulary {
+            tokens_to_id,
+            max_id: file.tokens.len(),
+            id_to_tokens: file.tokens,
+            sentence_type: SentenceType::HuggingFace,
+            byte_start: None,
+            encoding: Encoding::default(),
+            special_tokens: file.special_tokens,
+            sentencepiece: None,
+            hf_model: Some(file.model),
+            cut_mode: CutMode::default(),
+            user_dict: String::new(),
+            jieba: None,
+            bpe_offset: None,
+            frequencies: vec![],
+            normalizer: TextNormalizer::default(),
+        })
+    }
+
+    // 中文词表中不存在的词按UTF-8字节编码为256个保留的字节token，解码时可还原原文，
+    // 而不是全部变成`<unk>`。jieba把空白字符单独切分，词表中没有时同样按字节编码，
+    // 因此`decode(encode(text)) == text`对任意文本成立
+    pub fn with_byte_fallback(mut self) -> Self {
+        self.add_byte_tokens();
+        self
+    }
+
+    fn add_byte_tokens(&mut self) {
+        if let SentenceType::Chinese = self.sentence_type
+            && self.byte_start.is_none()
+        {
+            self.byte_start = Some(self.max_id);
+            for byte in 0..=u8::MAX {
+                let id = self.max_id;
+                self.tokens_to_id.insert(byte_token(byte), id);
+                self.id_to_tokens.push(byte_token(byte));
+                self.max_id += 1;
+            }
+        }
+    }
+
+    // 把另一个中文词表的token并入当前词表，已有的id保持不变，新的token排在最后。
+    // 返回`other`中每个id对应的新id，用于扩展已训练模型的词表时重排嵌入矩阵
+    pub fn merge(&mut self, other: &Vocabulary) -> Result<Vec<usize>> {
+        if self.sentence_type != SentenceType::Chinese
+            || other.sentence_type != SentenceType::Chinese
+        {
+            return Err(unsupported!("Only Chinese vocabularies can be merged"));
+        }
+        if self.normalizer != other.normalizer {
+            return Err(unsupported!(
+                "Cannot merge vocabularies with different text normalizers"
+            ));
+        }
+
+        if other.byte_start.is_some() {
+            self.add_byte_tokens();
+        }
+        if !self.frequencies.is_empty() {
+            self.frequencies.resize(self.id_to_tokens.len(), 0);
+        }
+
+        let mut ids = Vec::with_capacity(other.id_to_tokens.len());
+        for (id, token) in other.id_to_tokens.iter().enumerate() {
+            let new_id = if other.is_special(id) {
+                self.add_special_token(token)
+            } else {
+                self.add_token(token)
+            };
+
+            if !self.frequencies.is_empty() {
+                self.frequencies.resize(self.id_to_tokens.len(), 0);
+                self.frequencies[new_id] += other.frequencies.get(id).copied().unwrap_or(0);
+            }
+            ids.push(new_id);
+        }
+
+        Ok(ids)
+    }
+
+    // 丢弃构建时出现次数少于`min_frequency`的token，这些词之后编码为`<unk>`，
+    // 启用字节回退时编码为UTF-8字节。特殊token和字节token总是保留
+    pub fn with_min_frequency(self, min_frequency: usize) -> Result<Self> {
+        let frequencies = self.frequencies.clone();
+        self.retain_tokens(|id| frequencies.get(id).copied().unwrap_or(0) >= min_frequency)
+    }
+
+    // 只保留出现次数最多的token，使词表（混合模式下为中文部分）不超过`max_vocab_size`，
+    // 次数相同时保留id较小的
+    // 特殊token和字节token总是保留，`max_vocab_size`小于它们的个数时返回错误
+    pub fn with_max_vocab_size(self, max_vocab_size: usize) -> Result<Self> {
+        let mut candidates: Vec<usize> = (0..self.id_to_tokens.len())
+            .filter(|&id| !self.is_protected(id))
+            .collect();
+        let protected = self.id_to_tokens.len() - candidates.len();
+        if max_vocab_size < protected {
+            return Err(invalid!(
+                "max_vocab_size {max_vocab_size} is smaller than the {protected} special and byte tokens"
+            ));
         }
+        let budget = max_vocab_size - protected;
+
+        candidates.sort_by_key(|&id| (Reverse(self.frequencies.get(id).copied().unwrap_or(0)), id));
+        let keep: HashSet<usize> = candidates.into_iter().take(budget).collect();
+        self.retain_tokens(|id| keep.contains(&id))
     }
 
-    fn encode_english(&mut self, sentence: &str) -> Result<Vec<usize>> {
-        let tokenizer = cl100k_base()?;
-        let special: HashSet<&str> = [EOF_TOKEN].into_iter().collect();
-        let token_ids = tokenizer.encode(sentence, &special).0;
+    // 删除`keep`返回false的普通token，剩余的token按原来的顺序重新编号
+    fn retain_tokens(mut self, keep: impl Fn(usize) -> bool) -> Result<Self> {
+        if !matches!(
+            self.sentence_type,
+            SentenceType::Chinese | SentenceType::Mixed
+        ) || self.frequencies.is_empty()
+        {
+            return Err(unsupported!(
+                "Token frequencies are only counted when building a Chinese vocabulary from text"
+            ));
+        }
+        if let Some(offset) = self.bpe_offset
+            && self.max_id != offset + self.encoding.n_vocab()
+        {
+            return Err(unsupported!(
+                "Prune the vocabulary before adding special tokens"
+            ));
+        }
+
+        let protected: Vec<bool> = (0..self.id_to_tokens.len())
+            .map(|id| self.is_protected(id))
+            .collect();
+        let tokens = std::mem::take(&mut self.id_to_tokens);
+        let frequencies = std::mem::take(&mut self.frequencies);
+        let mut new_ids = vec![None; tokens.len()];
+        self.tokens_to_id.clear();
+
+        for (id, token) in tokens.into_iter().enumerate() {
+            if protected[id] || keep(id) {
+                let new_id = self.id_to_tokens.len();
+                new_ids[id] = Some(new_id);
+                self.tokens_to_id.insert(token.clone(), new_id);
+                self.id_to_tokens.push(token);
+                self.frequencies
+                    .push(frequencies.get(id).copied().unwrap_or(0));
+            }
+        }
 
-        self.max_id = *token_ids
+        for (_, id) in self.special_tokens.iter_mut() {
+            *id = new_ids[*id].expect("Special tokens are kept");
+        }
+        self.byte_start = self
+            .byte_start
+            .map(|start| new_ids[start].expect("Byte tokens are kept"));
+
+        self.max_id = self.id_to_tokens.len();
+        if self.bpe_offset.is_some() {
+            self.bpe_offset = Some(self.max_id);
+            self.max_id += self.encoding.n_vocab();
+        }
+        Ok(self)
+    }
+
+    fn is_protected(&self, id: usize) -> bool {
+        self.is_special(id)
+            || self
+                .byte_start
+                .is_some_and(|start| (start..start + 256).contains(&id))
+    }
+
+    // 英文词表使用的tiktoken编码，默认为`cl100k_base`。
+    // 需要在添加特殊token之前设置
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        let message = "Set the encoding before adding special tokens";
+        match (&self.sentence_type, self.bpe_offset) {
+            (SentenceType::English, _) => {
+                assert!(self.special_tokens.is_empty(), "{message}");
+                self.max_id = encoding.n_vocab();
+            }
+            (SentenceType::Mixed, Some(offset)) => {
+                assert_eq!(self.max_id, offset + self.encoding.n_vocab(), "{message}");
+                self.max_id = offset + encoding.n_vocab();
+            }
+            _ => {}
+        }
+        self.encoding = encoding;
+        self
+    }
+
+    // 已存在时返回原来的id。英文以外的特殊token加入词表，
+    // 英文的特殊token从`Encoding::n_vocab`开始编号，混合模式构建后添加的特殊token排在tiktoken之后
+    pub fn add_special_token(&mut self, token: &str) -> usize {
+        if let Some(id) = self.special_token_id(token) {
+            return id;
+        }
+
+        let id = match (&self.sentence_type, self.bpe_offset) {
+            (SentenceType::English, _) | (SentenceType::Mixed, Some(_)) => {
+                self.max_id += 1;
+                self.max_id - 1
+            }
+            _ => self.add_token(token),
+        };
+        self.special_tokens.push((token.to_string(), id));
+        id
+    }
+
+    pub fn special_token_id(&self, token: &str) -> Option<usize> {
+        self.special_tokens
+            .iter()
+            .find(|(special, _)| special == token)
+            .map(|(_, id)| *id)
+    }
+
+    pub fn special_tokens(&self) -> &[(String, usize)] {
+        &self.special_tokens
+    }
+
+    pub fn is_special(&self, id: usize) -> bool {
+        self.special_tokens
+            .iter()
+            .any(|(_, special)| *special == id)
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn byte_fallback(&self) -> bool {
+        self.byte_start.is_some()
+    }
+
+    // 以JSON保存，训练好的模型可以带上完全一致的词表
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let special_tokens = self
+            .special_tokens
+            .iter()
+            .map(|(token, _)| token.clone())
+            .collect();
+
+        let file = VocabularyFile {
+            sentence_type: self.sentence_type.clone(),
+            special_tokens,
+            max_id: self.max_id,
+            tokens: self.id_to_tokens.clone(),
+            byte_fallback: self.byte_fallback(),
+            encoding: self.encoding,
+            sentencepiece: self.sentencepiece.clone(),
+            hf_model: self.hf_model.clone(),
+            cut_mode: self.cut_mode,
+            user_dict: self.user_dict.clone(),
+            bpe_offset: self.bpe_offset,
+            normalizer: self.normalizer.clone(),
+        };
+
+        let path = path.as_ref();
+        fs::write(path, serde_json::to_string(&file)?).map_err(write_error(path))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(read_error(path))?;
+        Self::from_json(&text).map_err(invalid_file("vocabulary file", path))
+    }
+
+    // 从`save`写出的JSON构建，不需要读文件（如在浏览器中）
+    pub fn from_json(json: &str) -> Result<Self> {
+        let file: VocabularyFile = serde_json::from_str(json)?;
+
+        let tokens_to_id: HashMap<String, usize> = file
+            .tokens
             .iter()
-            .max()
-            .with_context(|| "No token in cl100k_base")? as usize;
+            .enumerate()
+            .map(|(id, token)| (token.clone(), id))
+            .collect();
+
+        if tokens_to_id.len() != file.tokens.len() {
+            return Err(invalid!("Duplicate tokens"));
+        }
+
+        if file.sentence_type == SentenceType::Mixed && file.bpe_offset.is_none() {
+            return Err(invalid!("BPE offset not in vocabulary"));
+        }
+
+        let mut special_tokens = vec![];
+        let mut extra_specials = 0;
+        for (i, token) in file.special_tokens.into_iter().enumerate() {
+            let id = match (&file.sentence_type, tokens_to_id.get(&token)) {
+                (SentenceType::English, _) => file.encoding.n_vocab() + i,
+                (_, Some(&id)) => id,
+                (SentenceType::Mixed, None) => {
+                    extra_specials += 1;
+                    file.bpe_offset.unwrap() + file.encoding.n_vocab() + extra_specials - 1
+                }
+                _ => return Err(invalid!("Special token {token} not in vocabulary")),
+            };
+            special_tokens.push((token, id));
+        }
+
+        if file.sentence_type == SentenceType::Chinese && !tokens_to_id.contains_key(UNKNOWN_TOKEN)
+        {
+            return Err(invalid!("Unknown token not in vocabulary"));
+        }
+
+        let byte_start = if file.byte_fallback {
+            let start = *tokens_to_id
+                .get(&byte_token(0))
+                .ok_or_else(|| invalid!("Byte tokens not in vocabulary"))?;
+
+            if (0..=u8::MAX)
+                .any(|b| tokens_to_id.get(&byte_token(b)) != Some(&(start + b as usize)))
+            {
+                return Err(invalid!("Byte tokens are not contiguous"));
+            }
+            Some(start)
+        } else {
+            None
+        };
+
+        let sentencepiece = match (&file.sentence_type, file.sentencepiece) {
+            (SentenceType::SentencePiece, None) => {
+                return Err(invalid!("SentencePiece model not in vocabulary"))
+            }
+            (_, mut model) => {
+                if let Some(model) = model.as_mut() {
+                    model.index(&file.tokens);
+                }
+                model
+            }
+        };
+
+        let hf_model = match (&file.sentence_type, file.hf_model) {
+            (SentenceType::HuggingFace, None) => {
+                return Err(invalid!("Tokenizer model not in vocabulary"))
+            }
+            (_, mut model) => {
+                if let Some(model) = model.as_mut() {
+                    model.compile(&tokens_to_id)?;
+                }
+                model
+            }
+        };
+
+        // 旧版本保存的英文词表`max_id`是最后一次编码时的最大id
+        let max_id = match file.sentence_type {
+            SentenceType::English => file.encoding.n_vocab() + special_tokens.len(),
+            _ => file.max_id,
+        };
+
+        Ok(Vocabulary {
+            tokens_to_id,
+            id_to_tokens: file.tokens,
+            max_id,
+            sentence_type: file.sentence_type,
+            byte_start,
+            encoding: file.encoding,
+            special_tokens,
+            sentencepiece,
+            hf_model,
+            cut_mode: file.cut_mode,
+            jieba: load_jieba(&file.user_dict)?,
+            user_dict: file.user_dict,
+            bpe_offset: file.bpe_offset,
+            normalizer: file.normalizer,
+            frequencies: vec![],
+        })
+    }
 
-        Ok(token_ids
+    // 所有id都小于该值，即嵌入矩阵的行数。英文为tiktoken编码的大小加上自定义的特殊token
+    pub fn vocab_size(&self) -> usize {
+        self.max_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.vocab_size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max_id == 0
+    }
+
+    // 依次查找`<eof>`、`<|endoftext|>`和`</s>`，英文词表没有添加时使用tiktoken自带的`<|endoftext|>`
+    pub fn eos_id(&self) -> Option<usize> {
+        [EOF_TOKEN, "<|endoftext|>", "</s>"]
             .into_iter()
-            .map(|item| item as usize)
-            .collect::<Vec<_>>())
+            .find_map(|token| self.special_token_id(token))
+            .or_else(|| {
+                (self.sentence_type == SentenceType::English).then(|| self.encoding.endoftext_id())
+            })
     }
 
-    fn encode_chinese(&self, sentence: &str) -> Vec<usize> {
-        let tokens = Vocabulary::tokenize_sentence(sentence);
-        let mut token_ids = Vec::with_capacity(tokens.len());
+    pub fn pad_id(&self) -> Option<usize> {
+        self.special_token_id(PADDING_TOKEN)
+    }
 
-        for token in tokens {
-            token_ids.push(self.get_id(&token));
+    pub fn encode(&self, sentence: &str) -> Result<Vec<usize>> {
+        self.encode_ids_from(sentence, true)
+    }
+
+    // 使用rayon并行编码多个文档，结果与逐个调用`encode`相同
+    pub fn encode_batch(&self, texts: &[&str]) -> Result<Vec<Vec<usize>>> {
+        texts.par_iter().map(|text| self.encode(text)).collect()
+    }
+
+    // 按行读取文件，逐块编码并依次返回token id，不需要把整个文件读入内存。
+    // 只在以非空白字符开头的行之前切分，结果与一次编码整个文件相同
+    pub fn encode_file(&self, path: impl AsRef<Path>) -> impl Iterator<Item = Result<usize>> + '_ {
+        FileTokens::new(self, path.as_ref(), FILE_CHUNK_SIZE)
+    }
+
+    // 在右侧截断到`max_length`并用`<pad>`补齐。不截断时超长的序列保持原样
+    pub fn encode_with_options(
+        &self,
+        text: &str,
+        max_length: usize,
+        padding: PaddingStrategy,
+        truncation: bool,
+    ) -> Result<EncodedText> {
+        let mut batch = self.encode_batch_with_options(&[text], max_length, padding, truncation)?;
+        Ok(batch.remove(0))
+    }
+
+    pub fn encode_batch_with_options(
+        &self,
+        texts: &[&str],
+        max_length: usize,
+        padding: PaddingStrategy,
+        truncation: bool,
+    ) -> Result<Vec<EncodedText>> {
+        let pad_id = match padding {
+            PaddingStrategy::DoNotPad => None,
+            _ => Some(self.pad_id().ok_or_else(|| {
+                invalid!("{PADDING_TOKEN} is not a special token, add it with add_special_token")
+            })?),
+        };
+
+        let mut batch = self.encode_batch(texts)?;
+        if truncation {
+            for token_ids in batch.iter_mut() {
+                token_ids.truncate(max_length);
+            }
+        }
+
+        let target = match padding {
+            PaddingStrategy::DoNotPad => 0,
+            PaddingStrategy::Longest => batch.iter().map(|ids| ids.len()).max().unwrap_or(0),
+            PaddingStrategy::MaxLength => max_length,
+        };
+
+        Ok(batch
+            .into_iter()
+            .map(|mut token_ids| {
+                let len = token_ids.len();
+                let mut attention_mask = vec![1; len];
+                if let Some(pad_id) = pad_id
+                    && len < target
+                {
+                    token_ids.resize(target, pad_id);
+                    attention_mask.resize(target, 0);
+                }
+                EncodedText {
+                    token_ids,
+                    attention_mask,
+                }
+            })
+            .collect())
+    }
+
+    // 返回每个token及其在原文中覆盖的字节范围，字符范围可以用`text[..start].chars().count()`得到。
+    // 字节回退或tiktoken把一个字符拆成多个token时，这些token各自覆盖其中的部分字节。
+    // SentencePiece和Hugging Face词表会先规范化文本，暂不支持
+    pub fn encode_with_offsets(&self, text: &str) -> Result<Vec<(usize, Range<usize>)>> {
+        if matches!(
+            self.sentence_type,
+            SentenceType::SentencePiece | SentenceType::HuggingFace
+        ) {
+            return Err(unsupported!(
+                "Offsets are not supported for {:?} vocabularies",
+                self.sentence_type
+            ));
+        }
+        if !self.normalizer.is_identity() {
+            return Err(unsupported!(
+                "Offsets are not supported with text normalization"
+            ));
         }
 
-        token_ids
+        let mut tokens = vec![];
+        let mut pos = 0;
+
+        for segment in split_special(text, &self.special_tokens) {
+            let segment = match segment {
+                Segment::Special(id) => {
+                    let len = self
+                        .special_tokens
+                        .iter()
+                        .find(|(_, s)| *s == id)
+                        .unwrap()
+                        .0
+                        .len();
+                    tokens.push((id, pos..pos + len));
+                    pos += len;
+                    continue;
+                }
+                Segment::Text(segment) => segment,
+            };
+
+            let mut token_ids = vec![];
+            let lengths = match self.sentence_type {
+                SentenceType::English => {
+                    token_ids = encode_tiktoken(self.encoding, segment)?;
+                    tiktoken_token_lengths(self.encoding, &token_ids)
+                }
+                SentenceType::Mixed => {
+                    let tokens = self.encode_mixed(segment)?;
+                    token_ids = tokens.iter().map(|(id, _)| *id).collect();
+                    tokens.into_iter().map(|(_, len)| len).collect()
+                }
+                _ => {
+                    let mut lengths = vec![];
+                    for word in self.cut(segment) {
+                        let start = token_ids.len();
+                        self.encode_word(word, &mut token_ids);
+                        // 按字节回退时每个字节token覆盖一个字节
+                        match token_ids.len() - start {
+                            1 => lengths.push(word.len()),
+                            n => lengths.extend(std::iter::repeat_n(1, n)),
+                        }
+                    }
+                    lengths
+                }
+            };
+
+            for (id, len) in token_ids.into_iter().zip(lengths) {
+                tokens.push((id, pos..pos + len));
+                pos += len;
+            }
+        }
+
+        Ok(tokens)
     }
 
-    pub fn decode(&self, token_ids: &[usize]) -> Result<String> {
-        match self.sentence_type {
-            SentenceType::Chinese => Ok(self.decode_chinese(token_ids)),
-            SentenceType::English => self.decode_engish(token_ids),
+    // `at_start`为false时表示接在之前的文本后面，用于分块编码
+    fn encode_ids_from(&self, sentence: &str, mut at_start: bool) -> Result<Vec<usize>> {
+        let mut token_ids = vec![];
+        // SentencePiece只在第一段普通文本前补空格，`<s>hello`与`<s>`加上`hello`的编码相同
+
+        for segment in split_special(sentence, &self.special_tokens) {
+            match segment {
+                Segment::Special(id) => token_ids.push(id),
+                Segment::Text(text) => {
+                    match (&self.sentence_type, &*self.normalizer.normalize(text)) {
+                        (SentenceType::Chinese, text) => self.encode_chinese(text, &mut token_ids),
+                        (SentenceType::English, text) => {
+                            token_ids.extend(encode_tiktoken(self.encoding, text)?)
+                        }
+                        (SentenceType::SentencePiece, text) => {
+                            let model = self.sentencepiece.as_ref().unwrap();
+                            model.encode(text, at_start, self.byte_start, &mut token_ids);
+                            at_start = false;
+                        }
+                        (SentenceType::HuggingFace, text) => {
+                            let model = self.hf_model.as_ref().unwrap();
+                            model.encode(text, at_start, &self.tokens_to_id, &mut token_ids)?;
+                            at_start = false;
+                        }
+                        (SentenceType::Mixed, text) => {
+                            token_ids.extend(self.encode_mixed(text)?.into_iter().map(|(id, _)| id))
+                        }
+                    }
+                }
+            }
         }
+
+        Ok(token_ids)
     }
 
-    fn decode_engish(&self, token_ids: &[usize]) -> Result<String> {
-        let tokenizer = cl100k_base()?;
-        let token_ids = token_ids.iter().map(|item| *item as Rank).collect();
-        tokenizer.decode(token_ids)
+    fn encode_chinese(&self, sentence: &str, token_ids: &mut Vec<usize>) {
+        for token in self.cut(sentence) {
+            self.encode_word(token, token_ids);
+        }
     }
 
-    fn decode_chinese(&self, token_ids: &[usize]) -> String {
-        let mut tokens = Vec::with_capacity(token_ids.len());
+    // 返回(id, 覆盖的字节数)。中文词表中没有的词也用tiktoken编码，不需要`<unk>`
+    fn encode_mixed(&self, text: &str) -> Result<Vec<(usize, usize)>> {
+        let offset = self.bpe_offset.unwrap();
+        let mut tokens = vec![];
+
+        let encode_bpe = |run: &str, tokens: &mut Vec<(usize, usize)>| -> Result<()> {
+            let token_ids = encode_tiktoken(self.encoding, run)?;
+            let lengths = tiktoken_token_lengths(self.encoding, &token_ids);
+            tokens.extend(token_ids.into_iter().map(|id| id + offset).zip(lengths));
+            Ok(())
+        };
+
+        for (chinese, run) in script_runs(text) {
+            if !chinese {
+                encode_bpe(run, &mut tokens)?;
+                continue;
+            }
 
-        for id in token_ids {
-            if let Some(token) = self.get_token(*id) {
-                tokens.push(token.to_string());
+            for word in self.cut(run) {
+                match self.tokens_to_id.get(word) {
+                    Some(&id) => tokens.push((id, word.len())),
+                    None => encode_bpe(word, &mut tokens)?,
+                }
             }
         }
 
-        tokens.into_iter().collect()
+        Ok(tokens)
     }
 
-    fn unique_tokens(tokens: Vec<String>) -> Vec<String> {
-        let tokens: HashSet<String> = tokens.into_iter().collect();
-        let mut tokens = tokens.into_iter().collect::<Vec<String>>();
-        tokens.sort();
-        tokens
+    fn decode_mixed(&self, token_ids: &[usize]) -> Result<String> {
+        let offset = self.bpe_offset.unwrap();
+        let mut text = String::new();
+
+        for run in token_ids.chunk_by(|a, b| (*a >= offset) == (*b >= offset)) {
+            if run[0] >= offset {
+                let run: Vec<usize> = run.iter().map(|id| id - offset).collect();
+                text.push_str(&decode_tiktoken(self.encoding, &run)?);
+            } else {
+                text.push_str(&decode_tokens(run, None, |id| self.get_token(id)));
+            }
+        }
+
+        Ok(text)
+    }
+
+    fn encode_word(&self, token: &str, token_ids: &mut Vec<usize>) {
+        match (self.tokens_to_id.get(token), self.byte_start) {
+            (Some(&id), _) => token_ids.push(id),
+            (None, Some(start)) => {
+                token_ids.extend(token.bytes().map(|b| start + b as usize));
+            }
+            (None, None) => token_ids.push(self.get_id(token)),
+        }
+    }
+
+    fn jieba(&self) -> &Jieba {
+        self.jieba.as_deref().unwrap_or(&JIEBA)
+    }
+
+    // 编码时的切分，拼接后与原文一致
+    fn cut<'a>(&self, sentence: &'a str) -> Vec<&'a str> {
+        self.jieba().cut(sentence, self.cut_mode == CutMode::Hmm)
+    }
+
+    fn cut_for_vocab(&self, text: &str) -> Vec<String> {
+        let tokens = match self.cut_mode {
+            CutMode::Full => self.jieba().cut_all(text),
+            _ => self.cut(text),
+        };
+        tokens.into_iter().map(|s| s.to_string()).collect()
+    }
+
+    pub fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        self.decode_with(token_ids, false)
+    }
+
+    // 去掉特殊token，例如生成结果中的`<eof>`和`<pad>`
+    pub fn decode_skip_special(&self, token_ids: &[usize]) -> Result<String> {
+        self.decode_with(token_ids, true)
+    }
+
+    fn decode_with(&self, token_ids: &[usize], skip_special: bool) -> Result<String> {
+        match self.sentence_type {
+            SentenceType::Chinese => Ok(self.decode_chinese(token_ids, skip_special)),
+            SentenceType::English => self.decode_engish(token_ids, skip_special),
+            SentenceType::SentencePiece => {
+                let text = self.decode_chinese(token_ids, skip_special);
+                Ok(self.sentencepiece.as_ref().unwrap().restore_spaces(&text))
+            }
+            SentenceType::Mixed => {
+                decode_with_special(token_ids, &self.special_tokens, skip_special, |ids| {
+                    self.decode_mixed(ids)
+                })
+            }
+            SentenceType::HuggingFace => {
+                let model = self.hf_model.as_ref().unwrap();
+                decode_with_special(token_ids, &self.special_tokens, skip_special, |ids| {
+                    let tokens = ids
+                        .iter()
+                        .filter_map(|&id| self.get_token(id))
+                        .map(|token| token.to_string())
+                        .collect();
+                    Ok(model.decode(tokens))
+                })
+            }
+        }
+    }
+
+    fn decode_engish(&self, token_ids: &[usize], skip_special: bool) -> Result<String> {
+        decode_with_special(token_ids, &self.special_tokens, skip_special, |ids| {
+            decode_tiktoken(self.encoding, ids)
+        })
+    }
+
+    fn decode_chinese(&self, token_ids: &[usize], skip_special: bool) -> String {
+        let token_ids: Vec<usize> = token_ids
+            .iter()
+            .copied()
+            .filter(|&id| !(skip_special && self.is_special(id)))
+            .collect();
+        decode_tokens(&token_ids, self.byte_start, |id| self.get_token(id))
     }
 
     fn add_token(&mut self, tokens: &str) -> usize {
@@ -130,11 +1046,23 @@ impl Vocabulary {
         }
     }
 
+    // 按字典序添加去重后的token，同时累计出现次数
     fn add_tokens(&mut self, tokens: Vec<String>) {
-        let tokens = Vocabulary::unique_tokens(tokens);
-        for tokens in tokens {
-            self.add_token(&tokens);
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for token in tokens {
+            *counts.entry(token).or_default() += 1;
         }
+        let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+        counts.sort();
+
+        for (token, count) in counts {
+            let id = self.add_token(&token);
+            if self.frequencies.len() <= id {
+                self.frequencies.resize(id + 1, 0);
+            }
+            self.frequencies[id] += count;
+        }
+        self.frequencies.resize(self.id_to_tokens.len(), 0);
     }
 
     fn get_id(&self, token: &str) -> usize {
@@ -149,14 +1077,267 @@ impl Vocabulary {
         self.id_to_tokens.get(id).map(|s| s.as_str())
     }
 
-    fn tokenize_sentence(sentence: &str) -> Vec<String> {
-        let jieba = Jieba::new();
-        jieba
-            .cut(sentence, false)
-            .into_iter()
-            .map(|s| s.to_string())
-            .collect()
+    pub fn sentence_type(&self) -> &SentenceType {
+        &self.sentence_type
+    }
+
+    // 按id排列的token，英文词表为空
+    pub fn tokens(&self) -> &[String] {
+        &self.id_to_tokens
+    }
+}
+
+pub(crate) enum Segment<'a> {
+    Text(&'a str),
+    Special(usize),
+}
+
+// 按特殊token切分文本，同一位置有多个匹配时选择最长的
+pub(crate) fn split_special<'a>(
+    text: &'a str,
+    special_tokens: &[(String, usize)],
+) -> Vec<Segment<'a>> {
+    let mut segments = vec![];
+    let mut rest = text;
+
+    while !rest.is_empty() {
+        let found = special_tokens
+            .iter()
+            .filter_map(|(token, id)| rest.find(token.as_str()).map(|pos| (pos, token, *id)))
+            .min_by_key(|(pos, token, _)| (*pos, std::cmp::Reverse(token.len())));
+
+        match found {
+            Some((pos, token, id)) => {
+                if pos > 0 {
+                    segments.push(Segment::Text(&rest[..pos]));
+                }
+                segments.push(Segment::Special(id));
+                rest = &rest[pos + token.len()..];
+            }
+            None => {
+                segments.push(Segment::Text(rest));
+                break;
+            }
+        }
+    }
+
+    segments
+}
+
+// 特殊token之间的普通token用`decode`解码
+pub(crate) fn decode_with_special(
+    token_ids: &[usize],
+    special_tokens: &[(String, usize)],
+    skip_special: bool,
+    decode: impl Fn(&[usize]) -> Result<String>,
+) -> Result<String> {
+    let mut text = String::new();
+    let mut start = 0;
+
+    for (i, &id) in token_ids.iter().enumerate() {
+        if let Some((token, _)) = special_tokens.iter().find(|(_, special)| *special == id) {
+            text.push_str(&decode(&token_ids[start..i])?);
+            if !skip_special {
+                text.push_str(token);
+            }
+            start = i + 1;
+        }
+    }
+
+    text.push_str(&decode(&token_ids[start..])?);
+    Ok(text)
+}
+
+pub(crate) fn byte_token(byte: u8) -> String {
+    format!("<0x{byte:02X}>")
+}
+
+// 连续的字节token先拼接为字节再按UTF-8解码
+pub(crate) fn decode_tokens<'a>(
+    token_ids: &[usize],
+    byte_start: Option<usize>,
+    get_token: impl Fn(usize) -> Option<&'a str>,
+) -> String {
+    let mut text = String::new();
+    let mut bytes = vec![];
+
+    for &id in token_ids {
+        if let Some(start) = byte_start
+            && (start..start + 256).contains(&id)
+        {
+            bytes.push((id - start) as u8);
+            continue;
+        }
+
+        if !bytes.is_empty() {
+            text.push_str(&String::from_utf8_lossy(&bytes));
+            bytes.clear();
+        }
+
+        if let Some(token) = get_token(id) {
+            text.push_str(token);
+        }
+    }
+
+    text.push_str(&String::from_utf8_lossy(&bytes));
+    text
+}
+
+pub(crate) fn encode_tiktoken(encoding: Encoding, sentence: &str) -> Result<Vec<usize>> {
+    let tokenizer = encoding.bpe();
+    let special: HashSet<&str> = [EOF_TOKEN].into_iter().collect();
+    let token_ids = tokenizer.encode(sentence, &special).0;
+
+    Ok(token_ids
+        .into_iter()
+        .map(|item| item as usize)
+        .collect::<Vec<_>>())
+}
+
+// 每个token解码后的字节数，拼接起来就是原文
+fn tiktoken_token_lengths(encoding: Encoding, token_ids: &[usize]) -> Vec<usize> {
+    let token_ids = token_ids.iter().map(|id| *id as Rank).collect();
+    encoding
+        .bpe()
+        ._decode_native_and_split(token_ids)
+        .map(|bytes| bytes.len())
+        .collect()
+}
+
+pub(crate) fn decode_tiktoken(encoding: Encoding, token_ids: &[usize]) -> Result<String> {
+    let tokenizer = encoding.bpe();
+    let token_ids = token_ids.iter().map(|item| *item as Rank).collect();
+    tokenizer
+        .decode(token_ids)
+        .map_err(|err| TokenizerError::Tiktoken(format!("{err:#}")))
+}
+
+// 按文字切分为连续的片段，true表示中文（汉字和全角标点）
+fn script_runs(text: &str) -> Vec<(bool, &str)> {
+    let is_chinese = |c: char| is_cjk(c) || matches!(c as u32, 0x3000..=0x303F | 0xFF00..=0xFFEF);
+
+    let mut runs = vec![];
+    let mut start = 0;
+    let mut current = None;
+    for (pos, c) in text.char_indices() {
+        let chinese = is_chinese(c);
+        if current.is_some_and(|current| current != chinese) {
+            runs.push((!chinese, &text[start..pos]));
+            start = pos;
+        }
+        current = Some(chinese);
+    }
+    if let Some(chinese) = current {
+        runs.push((chinese, &text[start..]));
+    }
+
+    runs
+}
+
+pub(crate) fn tokenize_chinese(sentence: &str) -> Vec<&str> {
+    JIEBA.cut(sentence, false)
+}
+
+struct FileTokens<'a> {
+    vocab: &'a Vocabulary,
+    path: PathBuf,
+    reader: Option<Result<BufReader<File>>>,
+    chunk_size: usize,
+    // 已读取但还未编码的行
+    pending: String,
+    token_ids: std::vec::IntoIter<usize>,
+    at_start: bool,
+}
+
+impl<'a> FileTokens<'a> {
+    fn new(vocab: &'a Vocabulary, path: &Path, chunk_size: usize) -> Self {
+        let reader = File::open(path)
+            .map(BufReader::new)
+            .map_err(read_error(path));
+
+        FileTokens {
+            vocab,
+            path: path.to_path_buf(),
+            reader: Some(reader),
+            chunk_size,
+            pending: String::new(),
+            token_ids: vec![].into_iter(),
+            at_start: true,
+        }
+    }
+
+    // 文件读完后返回剩余的内容，之后返回None
+    fn next_chunk(&mut self) -> Result<Option<String>> {
+        let reader = match self.reader.as_mut() {
+            Some(Ok(reader)) => reader,
+            Some(Err(_)) => return Err(self.reader.take().unwrap().unwrap_err()),
+            None => return Ok(None),
+        };
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let size = reader
+                .read_line(&mut line)
+                .map_err(read_error(&self.path))?;
+            if size == 0 {
+                self.reader = None;
+                let chunk = std::mem::take(&mut self.pending);
+                return Ok((!chunk.is_empty()).then_some(chunk));
+            }
+
+            // 空白处切开会改变tiktoken对连续空白和换行的合并，因此只在行首不是空白时切分
+            if self.pending.len() >= self.chunk_size
+                && line.starts_with(|c: char| !c.is_whitespace())
+            {
+                return Ok(Some(std::mem::replace(&mut self.pending, line)));
+            }
+            self.pending.push_str(&line);
+        }
+    }
+}
+
+impl Iterator for FileTokens<'_> {
+    type Item = Result<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(id) = self.token_ids.next() {
+                return Some(Ok(id));
+            }
+
+            let chunk = match self.next_chunk() {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => return None,
+                Err(e) => {
+                    self.reader = None;
+                    return Some(Err(e));
+                }
+            };
+
+            match self.vocab.encode_ids_from(&chunk, self.at_start) {
+                Ok(token_ids) => self.token_ids = token_ids.into_iter(),
+                Err(e) => {
+                    self.reader = None;
+                    return Some(Err(e));
+                }
+            }
+            self.at_start = false;
+        }
+    }
+}
+
+// 在默认词典的基础上加载用户词典，为空时返回None
+fn load_jieba(user_dict: &str) -> Result<Option<Arc<Jieba>>> {
+    if user_dict.is_empty() {
+        return Ok(None);
     }
+
+    let mut jieba = Jieba::new();
+    jieba
+        .load_dict(&mut user_dict.as_bytes())
+        .map_err(|err| invalid!("Invalid jieba user dictionary: {err}"))?;
+    Ok(Some(Arc::new(jieba)))
 }
 
 #[cfg(test)]
@@ -171,8 +1352,8 @@ mod tests {
         ];
 
         for item in texts {
-            let mut vocab = Vocabulary::new(&item.0, item.1).unwrap();
-            let token_ids = vocab.encode(&item.0).unwrap();
+            let vocab = Vocabulary::new(item.0, item.1).unwrap();
+            let token_ids = vocab.encode(item.0).unwrap();
 
             println!("\ntokens len: {}", vocab.len());
             println!("{:?}", token_ids);
@@ -185,4 +1366,403 @@ mod tests {
 
         println!();
     }
+
+    #[test]
+    fn test_vocab_save_load() {
+        let text = "这是一个例子。那是另一个例子。";
+        let vocab = Vocabulary::new(text, SentenceType::Chinese).unwrap();
+
+        let path = std::env::temp_dir().join("test_vocab_save_load.json");
+        vocab.save(&path).unwrap();
+        let loaded = Vocabulary::load(&path).unwrap();
+        let from_json = Vocabulary::from_json(&fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(from_json.tokens(), vocab.tokens());
+        assert!(Vocabulary::from_json("{}").is_err());
+
+        assert_eq!(loaded.len(), vocab.len());
+        assert_eq!(
+            loaded.encode(text).unwrap(),
+            vocab.clone().encode(text).unwrap()
+        );
+        assert_eq!(loaded.encode("未知").unwrap(), [0]);
+    }
+
+    #[test]
+    fn test_vocab_merge() {
+        let mut vocab = Vocabulary::new("我们学习语言模型。", SentenceType::Chinese).unwrap();
+        let tokens = vocab.tokens().to_vec();
+
+        let mut other = Vocabulary::new("医生检查病人的身体。", SentenceType::Chinese).unwrap();
+        other.add_special_token("<|im_end|>");
+        let other = other.with_byte_fallback();
+        let ids = vocab.merge(&other).unwrap();
+        println!("{} {:?}", vocab.len(), ids);
+
+        // 原有的id不变
+        assert_eq!(vocab.tokens()[..tokens.len()], tokens[..]);
+        assert_eq!(ids.len(), other.len());
+        assert_eq!(ids[0], 0);
+        assert!(vocab.byte_fallback());
+        assert!(vocab.special_token_id("<|im_end|>").is_some());
+
+        let text = "我们检查病人。";
+        let token_ids = vocab.encode(text).unwrap();
+        assert!(!token_ids.contains(&0));
+        let remapped: Vec<usize> = other
+            .encode(text)
+            .unwrap()
+            .into_iter()
+            .map(|id| ids[id])
+            .collect();
+        assert_eq!(vocab.decode(&remapped).unwrap(), text);
+
+        let english = Vocabulary::new("", SentenceType::English).unwrap();
+        assert!(vocab.merge(&english).is_err());
+    }
+
+    #[test]
+    fn test_vocab_byte_fallback() {
+        let vocab = Vocabulary::new("这是一个例子。", SentenceType::Chinese)
+            .unwrap()
+            .with_byte_fallback();
+
+        let text = "这是一个苹果。";
+        let token_ids = vocab.encode(text).unwrap();
+        println!("{:?}", token_ids);
+        assert!(!token_ids.contains(&0));
+        assert_eq!(vocab.decode(&token_ids).unwrap(), text);
+
+        let path = std::env::temp_dir().join("test_vocab_byte_fallback.json");
+        vocab.save(&path).unwrap();
+        let loaded = Vocabulary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.byte_fallback());
+        assert_eq!(loaded.encode(text).unwrap(), token_ids);
+
+        // 空白、词表外的词和`<unk>`原文都可以还原
+        for text in [
+            "这是 一个\n\n  例子\t。",
+            "这是Rust和机器学习的例子。<unk>",
+            "　全角空格\r\n",
+        ] {
+            let token_ids = vocab.encode(text).unwrap();
+            println!("{:?}", token_ids);
+            assert_eq!(vocab.decode(&token_ids).unwrap(), text);
+        }
+
+        // 字节token总是保留
+        let error = vocab.clone().with_max_vocab_size(100).unwrap_err();
+        println!("{error}");
+        assert!(matches!(error, TokenizerError::Invalid(_)));
+    }
+
+    #[test]
+    fn test_vocab_pruning() {
+        let text = "我们学习语言模型。我们学习语言模型。今天天气很好。";
+        let vocab = Vocabulary::new(text, SentenceType::Chinese).unwrap();
+        let pruned = vocab.clone().with_min_frequency(2).unwrap();
+        println!("{} -> {}", vocab.len(), pruned.len());
+        assert!(pruned.len() < vocab.len());
+        assert_eq!(pruned.special_token_id(UNKNOWN_TOKEN), Some(0));
+        assert_eq!(pruned.len(), pruned.tokens().len());
+
+        let token_ids = pruned.encode("我们学习。今天").unwrap();
+        assert_eq!(pruned.decode(&token_ids[..3]).unwrap(), "我们学习。");
+        assert_eq!(token_ids.last(), Some(&0));
+
+        // 字节回退时被丢弃的词编码为字节，可以还原
+        let pruned = vocab
+            .clone()
+            .with_byte_fallback()
+            .with_min_frequency(2)
+            .unwrap();
+        let token_ids = pruned.encode("今天学习").unwrap();
+        assert!(!token_ids.contains(&0));
+        assert_eq!(pruned.decode(&token_ids).unwrap(), "今天学习");
+
+        let capped = vocab.clone().with_max_vocab_size(5).unwrap();
+        assert_eq!(capped.len(), 5);
+        // `。`出现3次，其余的词最多2次
+        assert!(capped.tokens().contains(&"。".to_string()));
+
+        let mixed = Vocabulary::new(text, SentenceType::Mixed)
+            .unwrap()
+            .with_max_vocab_size(4)
+            .unwrap();
+        assert_eq!(mixed.len(), 4 + Encoding::default().n_vocab());
+    }
+
+    #[test]
+    fn test_vocab_encoding() {
+        let text = "Hello world! <eof>";
+
+        for encoding in [Encoding::Gpt2, Encoding::P50kBase, Encoding::O200kBase] {
+            let vocab = Vocabulary::new(text, SentenceType::English)
+                .unwrap()
+                .with_encoding(encoding);
+            let token_ids = vocab.encode(text).unwrap();
+            println!("{:?}: {:?}", encoding, token_ids);
+            assert_eq!(vocab.decode(&token_ids).unwrap(), text);
+        }
+
+        let vocab = Vocabulary::new(text, SentenceType::English)
+            .unwrap()
+            .with_encoding(Encoding::Gpt2);
+        assert_eq!(vocab.encode("Hello").unwrap(), [15496]);
+        assert_eq!(vocab.vocab_size(), 50257);
+        assert_eq!(vocab.eos_id(), Some(50256));
+        assert_eq!(vocab.pad_id(), None);
+
+        let mut vocab = Vocabulary::new("", SentenceType::English).unwrap();
+        assert_eq!(vocab.vocab_size(), 100277);
+        assert_eq!(vocab.add_special_token(PADDING_TOKEN), 100277);
+        assert_eq!(vocab.pad_id(), Some(100277));
+        assert_eq!(vocab.vocab_size(), 100278);
+
+        let vocab = Vocabulary::new("这是一个例子。", SentenceType::Chinese).unwrap();
+        assert_eq!(vocab.eos_id(), vocab.special_token_id(EOF_TOKEN));
+        assert_eq!(vocab.pad_id(), Some(1));
+    }
+
+    #[test]
+    fn test_vocab_special_tokens() {
+        let text = "<|im_start|>这是一个例子。<eof>";
+        let mut vocab = Vocabulary::new(text, SentenceType::Chinese).unwrap();
+        let im_start = vocab.add_special_token("<|im_start|>");
+        assert_eq!(vocab.add_special_token("<|im_start|>"), im_start);
+        assert_eq!(vocab.special_token_id(EOF_TOKEN), Some(2));
+
+        let token_ids = vocab.encode(text).unwrap();
+        println!("{:?}", token_ids);
+        assert_eq!(token_ids[0], im_start);
+        assert_eq!(token_ids.last(), Some(&2));
+        assert_eq!(vocab.decode(&token_ids).unwrap(), text);
+        assert_eq!(
+            vocab.decode_skip_special(&token_ids).unwrap(),
+            "这是一个例子。"
+        );
+
+        let mut vocab = Vocabulary::new("", SentenceType::English).unwrap();
+        let im_start = vocab.add_special_token("<|im_start|>");
+        assert_eq!(im_start, Encoding::Cl100kBase.n_vocab());
+
+        let text = "<|im_start|>Hello world";
+        let token_ids = vocab.encode(text).unwrap();
+        println!("{:?}", token_ids);
+        assert_eq!(token_ids[0], im_start);
+        assert_eq!(vocab.decode(&token_ids).unwrap(), text);
+        assert_eq!(
+            vocab.decode_skip_special(&token_ids).unwrap(),
+            "Hello world"
+        );
+
+        let path = std::env::temp_dir().join("test_vocab_special_tokens.json");
+        vocab.save(&path).unwrap();
+        let loaded = Vocabulary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.encode(text).unwrap(), token_ids);
+    }
+
+    #[test]
+    fn test_vocab_encode_batch() {
+        let texts = ["这是一个例子。", "那是另一个例子。", "例子"];
+        let vocab = Vocabulary::new(&texts.concat(), SentenceType::Chinese).unwrap();
+
+        let batch = vocab.encode_batch(&texts).unwrap();
+        println!("{:?}", batch);
+        for (text, token_ids) in texts.iter().zip(batch) {
+            assert_eq!(token_ids, vocab.encode(text).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_vocab_encode_file() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/the-verdict.txt");
+        let text = std::fs::read_to_string(path).unwrap();
+        let vocab = Vocabulary::new("", SentenceType::English).unwrap();
+
+        let expected = vocab.encode(&text).unwrap();
+        let token_ids = vocab.encode_file(path).collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(token_ids, expected);
+        // 用很小的块测试切分位置
+        let token_ids = FileTokens::new(&vocab, Path::new(path), 64)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(token_ids, expected);
+
+        let text = "我们训练模型。\n\n  Hello world!\n第二行<eof>和Rust\n\n\n最后一行";
+        let path = std::env::temp_dir().join("test_vocab_encode_file.txt");
+        std::fs::write(&path, text).unwrap();
+        let vocab = Vocabulary::new(text, SentenceType::Mixed).unwrap();
+        let token_ids = FileTokens::new(&vocab, &path, 1)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(token_ids, vocab.encode(text).unwrap());
+
+        let mut tokens = vocab.encode_file(&path);
+        assert!(tokens.next().unwrap().is_err());
+        assert!(tokens.next().is_none());
+    }
+
+    #[test]
+    fn test_vocab_encode_with_options() {
+        let texts = ["这是一个例子。", "例子"];
+        let vocab = Vocabulary::new(&texts.concat(), SentenceType::Chinese).unwrap();
+        let pad = vocab.special_token_id(PADDING_TOKEN).unwrap();
+
+        let encoded = vocab
+            .encode_with_options(texts[1], 4, PaddingStrategy::MaxLength, true)
+            .unwrap();
+        println!("{:?}", encoded);
+        assert_eq!(encoded.token_ids[1..], [pad, pad, pad]);
+        assert_eq!(encoded.attention_mask, [1, 0, 0, 0]);
+
+        let encoded = vocab
+            .encode_with_options(texts[0], 2, PaddingStrategy::MaxLength, true)
+            .unwrap();
+        assert_eq!(encoded.token_ids.len(), 2);
+        assert_eq!(encoded.attention_mask, [1, 1]);
+
+        let batch = vocab
+            .encode_batch_with_options(&texts, 2, PaddingStrategy::Longest, false)
+            .unwrap();
+        let longest = batch[0].token_ids.len();
+        assert!(longest > 2);
+        assert_eq!(batch[1].token_ids.len(), longest);
+        assert_eq!(batch[1].attention_mask.iter().sum::<u8>(), 1);
+
+        let english = Vocabulary::new("", SentenceType::English).unwrap();
+        assert!(english
+            .encode_with_options("Hello", 8, PaddingStrategy::MaxLength, true)
+            .is_err());
+        let encoded = english
+            .encode_with_options("Hello world", 1, PaddingStrategy::DoNotPad, true)
+            .unwrap();
+        assert_eq!(encoded.attention_mask, [1]);
+    }
+
+    #[test]
+    fn test_vocab_encode_with_offsets() {
+        let text = "这是一个例子。<eof>苹果";
+        let vocab = Vocabulary::new("这是一个例子。", SentenceType::Chinese)
+            .unwrap()
+            .with_byte_fallback();
+
+        let tokens = vocab.encode_with_offsets(text).unwrap();
+        println!("{:?}", tokens);
+        let token_ids: Vec<usize> = tokens.iter().map(|(id, _)| *id).collect();
+        assert_eq!(token_ids, vocab.encode(text).unwrap());
+        assert_eq!(tokens.last().unwrap().1.end, text.len());
+        assert!(tokens.windows(2).all(|w| w[0].1.end == w[1].1.start));
+
+        let (_, span) = &tokens[token_ids.iter().position(|id| *id == 2).unwrap()];
+        assert_eq!(&text[span.clone()], EOF_TOKEN);
+
+        let text = "Hello, world! 你好";
+        let vocab = Vocabulary::new(text, SentenceType::English).unwrap();
+        let tokens = vocab.encode_with_offsets(text).unwrap();
+        println!("{:?}", tokens);
+        let token_ids: Vec<usize> = tokens.iter().map(|(id, _)| *id).collect();
+        assert_eq!(token_ids, vocab.encode(text).unwrap());
+        assert_eq!(&text[tokens[0].1.clone()], "Hello");
+        assert_eq!(tokens.last().unwrap().1.end, text.len());
+    }
+
+    #[test]
+    fn test_vocab_user_dict() {
+        let text = "我们训练大语言模型。";
+        let default = Vocabulary::new(text, SentenceType::Chinese).unwrap();
+        assert!(!default.tokens().iter().any(|t| t == "大语言模型"));
+
+        let dict_path = std::env::temp_dir().join("test_vocab_user_dict.txt");
+        std::fs::write(&dict_path, "大语言模型 100 n\n").unwrap();
+        let vocab = Vocabulary::new_chinese_with_dict(text, &[&dict_path], CutMode::Exact).unwrap();
+        std::fs::remove_file(&dict_path).unwrap();
+
+        let token_ids = vocab.encode(text).unwrap();
+        println!("{:?}", token_ids);
+        let id = *vocab.tokens_to_id.get("大语言模型").unwrap();
+        assert!(token_ids.contains(&id));
+        assert_eq!(vocab.decode(&token_ids).unwrap(), text);
+
+        let path = std::env::temp_dir().join("test_vocab_user_dict.json");
+        vocab.save(&path).unwrap();
+        let loaded = Vocabulary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.encode(text).unwrap(), token_ids);
+        assert!(vocab.save_binary(&path).is_err());
+
+        let full = Vocabulary::new_chinese_with_dict(text, &[] as &[&Path], CutMode::Full).unwrap();
+        assert!(full.len() > default.len());
+        let token_ids = full.encode(text).unwrap();
+        assert_eq!(full.decode(&token_ids).unwrap(), text);
+    }
+
+    #[test]
+    fn test_vocab_mixed() {
+        let text = "我们用Rust训练语言模型。Hello world!";
+        let mut vocab = Vocabulary::new(text, SentenceType::Mixed).unwrap();
+        let offset = vocab.len() - Encoding::default().n_vocab();
+        assert_eq!(vocab.tokens().len(), offset);
+
+        let token_ids = vocab.encode(text).unwrap();
+        println!("{:?}", token_ids);
+        assert!(token_ids.iter().any(|id| *id < offset));
+        assert!(token_ids.iter().any(|id| *id >= offset));
+        assert_eq!(vocab.decode(&token_ids).unwrap(), text);
+
+        // 中文词表中没有的词使用tiktoken编码
+        let unknown = "苹果和Rust<eof>";
+        let token_ids = vocab.encode(unknown).unwrap();
+        assert!(!token_ids.contains(&0));
+        assert_eq!(vocab.decode(&token_ids).unwrap(), unknown);
+        let spans = vocab.encode_with_offsets(unknown).unwrap();
+        assert_eq!(spans.last().unwrap().1.end, unknown.len());
+
+        let im_end = vocab.add_special_token("<|im_end|>");
+        assert_eq!(im_end, offset + Encoding::default().n_vocab());
+        assert_eq!(vocab.len(), im_end + 1);
+
+        let path = std::env::temp_dir().join("test_vocab_mixed.json");
+        vocab.save(&path).unwrap();
+        let loaded = Vocabulary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let text = "我们训练模型<|im_end|>";
+        assert_eq!(loaded.encode(text).unwrap(), vocab.encode(text).unwrap());
+        assert_eq!(loaded.special_token_id("<|im_end|>"), Some(im_end));
+
+        let vocab = Vocabulary::new(text, SentenceType::Mixed)
+            .unwrap()
+            .with_encoding(Encoding::Gpt2);
+        assert_eq!(vocab.len(), vocab.tokens().len() + 50257);
+    }
+
+    #[test]
+    fn test_vocab_normalizer() {
+        let normalizer = TextNormalizer::new()
+            .full_width_to_half_width(true)
+            .lowercase(true);
+        let vocab =
+            Vocabulary::new_with_normalizer("ＧＰＴ模型", SentenceType::Chinese, normalizer)
+                .unwrap();
+        assert!(vocab.tokens().iter().any(|t| t == "gpt"));
+
+        let token_ids = vocab.encode("GPT模型<eof>").unwrap();
+        println!("{:?}", token_ids);
+        assert_eq!(token_ids, vocab.encode("ｇｐｔ模型<eof>").unwrap());
+        assert_eq!(vocab.decode(&token_ids).unwrap(), "gpt模型<eof>");
+        assert!(vocab.encode_with_offsets("GPT").is_err());
+
+        let path = std::env::temp_dir().join("test_vocab_normalizer.json");
+        vocab.save(&path).unwrap();
+        let loaded = Vocabulary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.normalizer(), vocab.normalizer());
+        assert_eq!(
+            loaded.encode("ＧＰＴ模型").unwrap(),
+            token_ids[..token_ids.len() - 1]
+        );
+    }
 }
@@ -0,0 +1,361 @@
+use crate::error::{invalid, invalid_file, read_error, write_error, Result, TokenizerError};
+use crate::stats::is_cjk;
+use crate::vocab::{split_special, Segment};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+pub const WORDPIECE_UNKNOWN_TOKEN: &str = "[UNK]";
+const CONTINUATION: &str = "##";
+
+// 超过该长度的单词直接编码为`[UNK]`
+const MAX_WORD_CHARS: usize = 100;
+
+// 学习BERT式的WordPiece词表：从单个字符开始，
+// 每次合并`freq(ab) / (freq(a) * freq(b))`最大的相邻片段
+#[derive(Debug, Clone)]
+pub struct WordPieceTrainer {
+    vocab_size: usize,
+    min_frequency: usize,
+    special_tokens: Vec<String>,
+}
+
+impl WordPieceTrainer {
+    pub fn new(vocab_size: usize) -> Self {
+        WordPieceTrainer {
+            vocab_size,
+            min_frequency: 2,
+            special_tokens: vec![],
+        }
+    }
+
+    pub fn min_frequency(mut self, min_frequency: usize) -> Self {
+        self.min_frequency = min_frequency.max(1);
+        self
+    }
+
+    // `[UNK]`总是id 0，其余特殊token（如`[CLS]`、`[SEP]`）依次排在后面
+    pub fn special_tokens(mut self, special_tokens: &[&str]) -> Self {
+        self.special_tokens = special_tokens.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn train(&self, text: &str) -> WordPieceTokenizer {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for word in split_words(text) {
+            *counts.entry(word).or_default() += 1;
+        }
+
+        let mut words: Vec<(Vec<String>, usize)> = counts
+            .into_iter()
+            .map(|(word, count)| (initial_pieces(word), count))
+            .collect();
+        words.sort();
+
+        let mut special_tokens = vec![WORDPIECE_UNKNOWN_TOKEN.to_string()];
+        for token in self.special_tokens.iter() {
+            if !special_tokens.contains(token) {
+                special_tokens.push(token.clone());
+            }
+        }
+
+        let alphabet: HashSet<&String> = words.iter().flat_map(|(pieces, _)| pieces).collect();
+        let mut alphabet: Vec<String> = alphabet.into_iter().cloned().collect();
+        alphabet.sort();
+
+        let mut tokens = special_tokens.clone();
+        tokens.extend(alphabet);
+
+        while tokens.len() < self.vocab_size {
+            let mut piece_counts: HashMap<&str, usize> = HashMap::new();
+            let mut pair_counts: HashMap<(&str, &str), usize> = HashMap::new();
+
+            for (pieces, count) in words.iter() {
+                for piece in pieces {
+                    *piece_counts.entry(piece).or_default() += count;
+                }
+                for pair in pieces.windows(2) {
+                    *pair_counts.entry((&pair[0], &pair[1])).or_default() += count;
+                }
+            }
+
+            // 分数相同时选择字典序较小的片段对，保证结果确定
+            let best = pair_counts
+                .into_iter()
+                .filter(|(_, count)| *count >= self.min_frequency)
+                .map(|(pair, count)| {
+                    let score = count as f64 / (piece_counts[pair.0] * piece_counts[pair.1]) as f64;
+                    (pair, score)
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.cmp(&a.0)))
+                .map(|((a, b), _)| (a.to_string(), b.to_string()));
+
+            let (a, b) = match best {
+                Some(best) => best,
+                None => break,
+            };
+
+            let merged = format!("{a}{}", b.trim_start_matches(CONTINUATION));
+            for (pieces, _) in words.iter_mut() {
+                merge_pieces(pieces, &a, &b, &merged);
+            }
+            tokens.push(merged);
+        }
+
+        WordPieceTokenizer::from_tokens(tokens, special_tokens)
+            .expect("Trained WordPiece tokens are unique")
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct WordPieceFile {
+    special_tokens: Vec<String>,
+    tokens: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WordPieceTokenizer {
+    tokens: Vec<String>,
+    token_to_id: HashMap<String, usize>,
+    special_tokens: Vec<(String, usize)>,
+}
+
+impl WordPieceTokenizer {
+    fn from_tokens(tokens: Vec<String>, special_tokens: Vec<String>) -> Result<Self> {
+        let token_to_id: HashMap<String, usize> = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.clone(), id))
+            .collect();
+
+        if token_to_id.len() != tokens.len() {
+            return Err(invalid!("Duplicate WordPiece tokens"));
+        }
+        if !token_to_id.contains_key(WORDPIECE_UNKNOWN_TOKEN) {
+            return Err(invalid!(
+                "{WORDPIECE_UNKNOWN_TOKEN} not in WordPiece tokens"
+            ));
+        }
+
+        let special_tokens = special_tokens
+            .into_iter()
+            .map(|token| {
+                let id = *token_to_id
+                    .get(&token)
+                    .ok_or_else(|| invalid!("Special token {token} not in WordPiece tokens"))?;
+                Ok((token, id))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(WordPieceTokenizer {
+            tokens,
+            token_to_id,
+            special_tokens,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn token_id(&self, token: &str) -> Option<usize> {
+        self.token_to_id.get(token).copied()
+    }
+
+    pub fn special_tokens(&self) -> &[(String, usize)] {
+        &self.special_tokens
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<usize> {
+        let mut token_ids = vec![];
+
+        for segment in split_special(text, &self.special_tokens) {
+            match segment {
+                Segment::Special(id) => token_ids.push(id),
+                Segment::Text(text) => {
+                    for word in split_words(text) {
+                        self.encode_word(word, &mut token_ids);
+                    }
+                }
+            }
+        }
+
+        token_ids
+    }
+
+    // 贪心地匹配最长的片段，无法完整切分的单词整体编码为`[UNK]`
+    fn encode_word(&self, word: &str, token_ids: &mut Vec<usize>) {
+        let unknown = self.token_to_id[WORDPIECE_UNKNOWN_TOKEN];
+        let chars: Vec<(usize, char)> = word.char_indices().collect();
+        if chars.len() > MAX_WORD_CHARS {
+            token_ids.push(unknown);
+            return;
+        }
+
+        let mut pieces = vec![];
+        let mut start = 0;
+
+        while start < chars.len() {
+            let found = (start + 1..=chars.len()).rev().find_map(|end| {
+                let from = chars[start].0;
+                let to = chars.get(end).map_or(word.len(), |(pos, _)| *pos);
+                let piece = if start == 0 {
+                    word[from..to].to_string()
+                } else {
+                    format!("{CONTINUATION}{}", &word[from..to])
+                };
+                self.token_to_id.get(&piece).map(|&id| (id, end))
+            });
+
+            match found {
+                Some((id, end)) => {
+                    pieces.push(id);
+                    start = end;
+                }
+                None => {
+                    token_ids.push(unknown);
+                    return;
+                }
+            }
+        }
+
+        token_ids.extend(pieces);
+    }
+
+    // 单词之间用空格连接，`##`片段接在前一个片段后面，中文字符之间不加空格
+    pub fn decode(&self, token_ids: &[usize]) -> Result<String> {
+        let mut text = String::new();
+        let mut prev_cjk = false;
+
+        for &id in token_ids {
+            let token = self.tokens.get(id).ok_or(TokenizerError::UnknownId(id))?;
+
+            if let Some(piece) = token.strip_prefix(CONTINUATION) {
+                text.push_str(piece);
+                continue;
+            }
+
+            let cjk = token.chars().all(is_cjk);
+            let separate = !(cjk && prev_cjk);
+            if separate && !text.is_empty() {
+                text.push(' ');
+            }
+            text.push_str(token);
+            prev_cjk = cjk;
+        }
+
+        Ok(text)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = WordPieceFile {
+            special_tokens: self.special_tokens.iter().map(|(t, _)| t.clone()).collect(),
+            tokens: self.tokens.clone(),
+        };
+
+        let path = path.as_ref();
+        fs::write(path, serde_json::to_string(&file)?).map_err(write_error(path))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(read_error(path))?;
+        let file: WordPieceFile =
+            serde_json::from_str(&text).map_err(invalid_file("WordPiece file", path))?;
+
+        Self::from_tokens(file.tokens, file.special_tokens)
+            .map_err(invalid_file("WordPiece file", path))
+    }
+}
+
+fn initial_pieces(word: &str) -> Vec<String> {
+    word.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if i == 0 {
+                c.to_string()
+            } else {
+                format!("{CONTINUATION}{c}")
+            }
+        })
+        .collect()
+}
+
+fn merge_pieces(pieces: &mut Vec<String>, a: &str, b: &str, merged: &str) {
+    let mut i = 0;
+    let mut result = Vec::with_capacity(pieces.len());
+
+    while i < pieces.len() {
+        if i + 1 < pieces.len() && pieces[i] == a && pieces[i + 1] == b {
+            result.push(merged.to_string());
+            i += 2;
+        } else {
+            result.push(std::mem::take(&mut pieces[i]));
+            i += 1;
+        }
+    }
+
+    *pieces = result;
+}
+
+// BERT的基本分词：按空白切分，标点和每个中文字符单独成词
+fn split_words(text: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut start = None;
+
+    for (pos, c) in text.char_indices() {
+        if !c.is_alphanumeric() || is_cjk(c) {
+            if let Some(start) = start.take() {
+                words.push(&text[start..pos]);
+            }
+            if !c.is_whitespace() {
+                words.push(&text[pos..pos + c.len_utf8()]);
+            }
+        } else if start.is_none() {
+            start = Some(pos);
+        }
+    }
+
+    if let Some(start) = start {
+        words.push(&text[start..]);
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wordpiece() {
+        let text = "playing played player plays replay 我们在学习。 \
+                    playing played player plays replay 我们在学习。";
+        let tokenizer = WordPieceTrainer::new(80)
+            .special_tokens(&["[CLS]", "[SEP]"])
+            .train(text);
+        println!("vocab size: {}", tokenizer.len());
+        assert!(tokenizer.token_id("##ing").is_some() || tokenizer.token_id("playing").is_some());
+
+        let token_ids = tokenizer.encode("[CLS] playing replay 学习。[SEP]");
+        println!("{:?}", token_ids);
+        assert_eq!(token_ids[0], 1);
+        assert_eq!(token_ids.last(), Some(&2));
+        assert_eq!(
+            tokenizer.decode(&token_ids).unwrap(),
+            "[CLS] playing replay 学习 。 [SEP]"
+        );
+        assert_eq!(tokenizer.encode("xyz"), [0]);
+
+        let path = std::env::temp_dir().join("test_wordpiece.json");
+        tokenizer.save(&path).unwrap();
+        let loaded = WordPieceTokenizer::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.encode(text), tokenizer.encode(text));
+    }
+}